@@ -0,0 +1,66 @@
+//! Redirects the traffic of a QEMU virtual machine to a SOCKS proxy without pcap.
+//!
+//! QEMU sends each Ethernet frame of the virtual machine in a UDP datagram with the socket
+//! network backend, and the frames are injected into a `Stack` here. Start QEMU with
+//!
+//! ```text
+//! qemu-system-x86_64 ... -netdev socket,id=n0,udp=127.0.0.1:10001,localaddr=127.0.0.1:10000 \
+//!     -device e1000,netdev=n0
+//! ```
+//!
+//! and run `cargo run --example qemu -- 127.0.0.1:1080`, then configure the virtual machine with
+//! the address 10.6.0.1/24 and the gateway 10.6.0.254.
+
+use std::env;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use pcap2socks::config::Config;
+use pcap2socks::stack::Stack;
+
+#[tokio::main]
+async fn main() {
+    let dst: SocketAddrV4 = env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("127.0.0.1:1080"))
+        .parse()
+        .expect("invalid destination");
+
+    let mut config = Config::new(dst);
+    config.src = Some("10.6.0.1/32".parse().unwrap());
+    config.publish = Some(Ipv4Addr::new(10, 6, 0, 254));
+    let mut stack = Stack::new(
+        &config,
+        "02:00:00:00:00:01".parse().unwrap(),
+        Ipv4Addr::new(10, 6, 0, 254),
+    );
+
+    let socket = UdpSocket::bind("127.0.0.1:10001").expect("cannot bind the socket");
+    socket
+        .connect("127.0.0.1:10000")
+        .expect("cannot connect the socket");
+
+    // Frames from the virtual machine
+    let device = stack.device().clone();
+    let socket_cloned = socket.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0u8; u16::MAX as usize];
+        while let Ok(size) = socket_cloned.recv(&mut buffer) {
+            device.inject(&buffer[..size]);
+        }
+    });
+
+    // Frames to the virtual machine
+    let device = stack.device().clone();
+    thread::spawn(move || loop {
+        match device.take() {
+            Some(frame) => {
+                let _ = socket.send(&frame);
+            }
+            None => thread::sleep(Duration::from_millis(1)),
+        }
+    });
+
+    stack.run(None).await.expect("cannot run the stack");
+}
@@ -0,0 +1,198 @@
+//! Support for routing flows to the faster of the proxy and the direct path.
+
+use log::{debug, info};
+use lru::LruCache;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+use crate::filter::BlockList;
+use crate::packet::layer::LayerKinds;
+use crate::proxy::{DirectTransport, ProxyConfig, ProxyTransport};
+
+/// Represents the interval of measuring a destination again.
+const ADAPTIVE_INTERVAL: u64 = 5 * 60 * 1000;
+
+/// Represents the number of connections over each path in a measurement, whose minimum time is
+/// taken.
+const ADAPTIVE_PROBES: usize = 3;
+
+/// Represents the timeout of a connection in a measurement.
+const ADAPTIVE_TIMEOUT: u64 = 3000;
+
+/// Represents the percentage the other path should be faster than the current path by before
+/// flows are switched to it.
+const ADAPTIVE_HYSTERESIS: u32 = 20;
+
+/// Represents the max number of destinations measured.
+const MAX_ADAPTIVE_DSTS: usize = 1024;
+
+/// Represents the measurement of a destination.
+#[derive(Clone, Copy, Debug)]
+struct Measurement {
+    is_direct: bool,
+    is_measuring: bool,
+    instant: Instant,
+}
+
+/// Represents a router which routes new TCP flows matching its rules to the direct path if it is
+/// faster than the proxy. Each destination is measured by connecting to it over both paths.
+pub struct AdaptiveRouter {
+    list: BlockList,
+    proxy: ProxyConfig,
+    direct: DirectTransport,
+    dsts: Arc<Mutex<LruCache<Ipv4Addr, Measurement>>>,
+}
+
+impl AdaptiveRouter {
+    /// Creates a new `AdaptiveRouter` with a list of the same syntax as the block list, which
+    /// opts destinations and sources in.
+    pub fn new(s: &str, proxy: ProxyConfig) -> AdaptiveRouter {
+        AdaptiveRouter {
+            list: BlockList::parse_list(s, "adaptive list"),
+            proxy,
+            direct: DirectTransport::new(),
+            dsts: Arc::new(Mutex::new(LruCache::new(MAX_ADAPTIVE_DSTS))),
+        }
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.list.set_utc_offset(offset);
+    }
+
+    /// Returns the number of rules in the adaptive list.
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Returns if the adaptive list contains no rule.
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Returns the direct path.
+    pub fn direct(&self) -> &DirectTransport {
+        &self.direct
+    }
+
+    /// Returns if a new TCP flow from the source to the destination should be routed to the
+    /// direct path. Destinations not measured yet or for a while are measured in the background,
+    /// and are routed to the proxy until they are known to be faster directly.
+    pub fn route(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        if !self
+            .list
+            .is_blocked(Some(LayerKinds::Tcp), Some(*src.ip()), dst)
+        {
+            return false;
+        }
+
+        let (is_direct, is_due) = {
+            let mut dsts = self.dsts.lock().unwrap();
+            match dsts.get_mut(dst.ip()) {
+                Some(measurement) => {
+                    let is_due = !measurement.is_measuring
+                        && measurement.instant.elapsed().as_millis() >= ADAPTIVE_INTERVAL as u128;
+                    if is_due {
+                        measurement.is_measuring = true;
+                    }
+
+                    (measurement.is_direct, is_due)
+                }
+                None => {
+                    dsts.put(
+                        *dst.ip(),
+                        Measurement {
+                            is_direct: false,
+                            is_measuring: true,
+                            instant: Instant::now(),
+                        },
+                    );
+
+                    (false, true)
+                }
+            }
+        };
+        if is_due {
+            self.measure(dst);
+        }
+
+        is_direct
+    }
+
+    fn measure(&self, dst: SocketAddrV4) {
+        let proxy = self.proxy.clone();
+        let direct = self.direct.clone();
+        let dsts = Arc::clone(&self.dsts);
+        tokio::spawn(async move {
+            let proxy_time = measure(&proxy, dst).await;
+            let direct_time = measure(&direct, dst).await;
+            debug!(
+                "measure {}: {:?} through the proxy, {:?} directly",
+                dst, proxy_time, direct_time
+            );
+
+            let mut dsts = dsts.lock().unwrap();
+            if let Some(measurement) = dsts.get_mut(dst.ip()) {
+                let is_direct = select(measurement.is_direct, proxy_time, direct_time);
+                if is_direct != measurement.is_direct {
+                    match is_direct {
+                        true => info!("Route {} directly, which is faster", dst.ip()),
+                        false => info!("Route {} through the proxy, which is faster", dst.ip()),
+                    }
+                }
+                measurement.is_direct = is_direct;
+                measurement.is_measuring = false;
+                measurement.instant = Instant::now();
+            }
+        });
+    }
+}
+
+/// Returns the minimum time of connecting to the destination through the transport, or `None`
+/// if it cannot be connected.
+async fn measure(transport: &dyn ProxyTransport, dst: SocketAddrV4) -> Option<Duration> {
+    let mut min_time = None;
+    for _ in 0..ADAPTIVE_PROBES {
+        let instant = Instant::now();
+        let result = time::timeout(
+            Duration::from_millis(ADAPTIVE_TIMEOUT),
+            transport.connect_tcp(dst),
+        )
+        .await;
+        if let Ok(Ok(_)) = result {
+            let time = instant.elapsed();
+            min_time = Some(min_time.map_or(time, |min_time: Duration| min_time.min(time)));
+        }
+    }
+
+    min_time
+}
+
+/// Returns if the direct path should be selected by the times of both paths. The path is only
+/// switched if the other one is faster by `ADAPTIVE_HYSTERESIS`, so flows will not flap between
+/// paths of similar latency.
+fn select(is_direct: bool, proxy_time: Option<Duration>, direct_time: Option<Duration>) -> bool {
+    match (proxy_time, direct_time) {
+        (Some(proxy_time), Some(direct_time)) => match is_direct {
+            true => proxy_time * 100 >= direct_time * (100 - ADAPTIVE_HYSTERESIS),
+            false => direct_time * 100 < proxy_time * (100 - ADAPTIVE_HYSTERESIS),
+        },
+        (None, Some(_)) => true,
+        (Some(_), None) => false,
+        (None, None) => is_direct,
+    }
+}
+
+#[test]
+fn adaptive_select() {
+    let ms = Duration::from_millis;
+    assert!(!select(false, Some(ms(100)), Some(ms(90))));
+    assert!(select(false, Some(ms(100)), Some(ms(70))));
+    assert!(select(true, Some(ms(90)), Some(ms(100))));
+    assert!(!select(true, Some(ms(70)), Some(ms(100))));
+    assert!(select(false, None, Some(ms(100))));
+    assert!(!select(true, Some(ms(100)), None));
+    assert!(select(true, None, None));
+}
@@ -0,0 +1,112 @@
+//! Support for pinning threads to CPU cores.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+/// Represents the max number of CPU cores, which is the size of `cpu_set_t` in Linux.
+const MAX_CORES: usize = 1024;
+
+/// Represents a set of CPU cores, like `0-1,3`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CoreSet {
+    cores: Vec<usize>,
+}
+
+impl CoreSet {
+    /// Returns the CPU cores in ascending order.
+    pub fn cores(&self) -> &[usize] {
+        &self.cores
+    }
+}
+
+impl Display for CoreSet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let cores = self
+            .cores
+            .iter()
+            .map(|core| core.to_string())
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", cores.join(","))
+    }
+}
+
+impl FromStr for CoreSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |core: &str| {
+            let core = core
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid core {}: {}", core, e))?;
+            if core >= MAX_CORES {
+                return Err(format!("core {} out of range", core));
+            }
+
+            Ok(core)
+        };
+
+        let mut cores = Vec::new();
+        for range in s.split(',') {
+            match range.find('-') {
+                Some(i) => {
+                    let first = parse(&range[..i])?;
+                    let last = parse(&range[i + 1..])?;
+                    if first > last {
+                        return Err(format!("invalid range {}", range));
+                    }
+                    cores.extend(first..=last);
+                }
+                None => cores.push(parse(range)?),
+            }
+        }
+        cores.sort_unstable();
+        cores.dedup();
+
+        Ok(CoreSet { cores })
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sched_setaffinity(pid: i32, cpusetsize: usize, mask: *const u64) -> i32;
+}
+
+/// Pins the current thread to the CPU cores. Pinning is only supported in Linux.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(set: &CoreSet) -> io::Result<()> {
+    let mut mask = [0u64; MAX_CORES / 64];
+    for &core in set.cores.iter() {
+        mask[core / 64] |= 1 << (core % 64);
+    }
+
+    // The PID 0 represents the calling thread
+    let r = unsafe { sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Pins the current thread to the CPU cores. Pinning is only supported in Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_: &CoreSet) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "pinning threads is not supported in this platform",
+    ))
+}
+
+#[test]
+fn core_set_from_str() {
+    let set = "3,0-1,1".parse::<CoreSet>().unwrap();
+    assert_eq!(set.cores(), &[0, 1, 3]);
+    assert_eq!(set.to_string(), "0,1,3");
+
+    assert!("1-0".parse::<CoreSet>().is_err());
+    assert!("1024".parse::<CoreSet>().is_err());
+    assert!("a".parse::<CoreSet>().is_err());
+}
@@ -0,0 +1,289 @@
+//! Support for notifying alerts on error conditions through hooks.
+
+use log::{debug, warn};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Represents the number of consecutive connection errors after which the upstream is down.
+pub const UPSTREAM_DOWN_ERRORS: usize = 5;
+
+/// Represents the timeout of connecting, writing and reading of webhooks.
+const WEBHOOK_TIMEOUT: u64 = 5000;
+
+/// Represents the default port of webhooks.
+const DEFAULT_WEBHOOK_PORT: u16 = 80;
+
+/// Represents an error condition to alert.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AlertEvent {
+    /// Connections to the proxy failed consecutively.
+    UpstreamDown(usize),
+    /// A connection to the proxy succeeded after the upstream was down.
+    UpstreamUp,
+    /// Frames were dropped as malformed over the threshold in a minute.
+    FramesDropped(usize),
+    /// Bytes buffered in TCP connections exceeded the threshold.
+    BufferFull(usize),
+}
+
+impl AlertEvent {
+    /// Returns the name of the event, like `upstream_down`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertEvent::UpstreamDown(_) => "upstream_down",
+            AlertEvent::UpstreamUp => "upstream_up",
+            AlertEvent::FramesDropped(_) => "frames_dropped",
+            AlertEvent::BufferFull(_) => "buffer_full",
+        }
+    }
+}
+
+impl Display for AlertEvent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AlertEvent::UpstreamDown(errors) => {
+                write!(f, "The proxy cannot be connected after {} attempts", errors)
+            }
+            AlertEvent::UpstreamUp => write!(f, "The proxy is connected again"),
+            AlertEvent::FramesDropped(count) => {
+                write!(f, "Dropped {} malformed frames in a minute", count)
+            }
+            AlertEvent::BufferFull(size) => write!(f, "Buffered {} Bytes in TCP connections", size),
+        }
+    }
+}
+
+/// Represents a hook notified of alerts.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum AlertHook {
+    /// Posts the alert in JSON to the HTTP URL, like `http://192.168.1.2:8080/alert`.
+    Webhook {
+        host: String,
+        port: u16,
+        path: String,
+    },
+    /// Executes the command in the shell, with the environment variables
+    /// `PCAP2SOCKS_ALERT_EVENT` and `PCAP2SOCKS_ALERT_MESSAGE`.
+    Exec(String),
+}
+
+impl AlertHook {
+    fn notify(&self, event: &AlertEvent, time: u128) -> io::Result<()> {
+        match self {
+            AlertHook::Webhook { host, port, path } => {
+                let body = format!(
+                    "{{\"time\":{},\"event\":\"{}\",\"message\":\"{}\"}}",
+                    time,
+                    event.name(),
+                    escape(&event.to_string())
+                );
+                let request = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path,
+                    host,
+                    body.len(),
+                    body
+                );
+
+                let timeout = Duration::from_millis(WEBHOOK_TIMEOUT);
+                let addr = (host.as_str(), *port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                stream.write_all(request.as_bytes())?;
+
+                // Status line, like HTTP/1.1 204 No Content
+                let mut buffer = [0u8; 12];
+                stream.read_exact(&mut buffer)?;
+                match buffer[9] {
+                    b'2' => Ok(()),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("replied {}", String::from_utf8_lossy(&buffer[9..])),
+                    )),
+                }
+            }
+            AlertHook::Exec(command) => {
+                let mut shell = match cfg!(windows) {
+                    true => {
+                        let mut shell = Command::new("cmd");
+                        shell.arg("/C");
+                        shell
+                    }
+                    false => {
+                        let mut shell = Command::new("sh");
+                        shell.arg("-c");
+                        shell
+                    }
+                };
+                let status = shell
+                    .arg(command)
+                    .env("PCAP2SOCKS_ALERT_EVENT", event.name())
+                    .env("PCAP2SOCKS_ALERT_MESSAGE", event.to_string())
+                    .status()?;
+                match status.success() {
+                    true => Ok(()),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("exited with {}", status),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl Display for AlertHook {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AlertHook::Webhook { host, port, path } => {
+                write!(f, "http://{}:{}{}", host, port, path)
+            }
+            AlertHook::Exec(command) => write!(f, "exec:{}", command),
+        }
+    }
+}
+
+impl FromStr for AlertHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(command) = s.strip_prefix("exec:") {
+            if command.is_empty() {
+                return Err(String::from("empty command"));
+            }
+
+            return Ok(AlertHook::Exec(command.to_string()));
+        }
+
+        let s = match s.strip_prefix("http://") {
+            Some(s) => s,
+            None => match s.starts_with("https://") {
+                true => return Err(String::from("TLS is not supported, please use http")),
+                false => return Err(format!("invalid hook {}, please use http:// or exec:", s)),
+            },
+        };
+        let (authority, path) = match s.find('/') {
+            Some(i) => (&s[..i], &s[i..]),
+            None => (s, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|e| format!("invalid port in {}: {}", authority, e))?,
+            ),
+            None => (authority, DEFAULT_WEBHOOK_PORT),
+        };
+        if host.is_empty() {
+            return Err(format!("invalid URL {}", s));
+        }
+
+        Ok(AlertHook::Webhook {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Represents the hooks and the thresholds of alerts.
+#[derive(Clone, Debug, Default)]
+pub struct Alerter {
+    hooks: Vec<AlertHook>,
+    drop_threshold: Option<usize>,
+    buffer_threshold: Option<usize>,
+}
+
+impl Alerter {
+    /// Creates a new `Alerter` without hooks.
+    pub fn new() -> Alerter {
+        Alerter::default()
+    }
+
+    /// Appends a hook.
+    pub fn push(&mut self, hook: AlertHook) {
+        self.hooks.push(hook);
+    }
+
+    /// Sets the threshold of malformed frames in a minute.
+    pub fn set_drop_threshold(&mut self, threshold: usize) {
+        self.drop_threshold = Some(threshold);
+    }
+
+    /// Returns the threshold of malformed frames in a minute.
+    pub fn drop_threshold(&self) -> Option<usize> {
+        self.drop_threshold
+    }
+
+    /// Sets the threshold of bytes buffered in TCP connections.
+    pub fn set_buffer_threshold(&mut self, threshold: usize) {
+        self.buffer_threshold = Some(threshold);
+    }
+
+    /// Returns the threshold of bytes buffered in TCP connections.
+    pub fn buffer_threshold(&self) -> Option<usize> {
+        self.buffer_threshold
+    }
+
+    /// Notifies all the hooks of the event in the background, so the caller is never blocked by a
+    /// slow hook.
+    pub fn alert(&self, event: AlertEvent) {
+        warn!("Alert: {}", event);
+        if self.hooks.is_empty() {
+            return;
+        }
+
+        let hooks = self.hooks.clone();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        thread::spawn(move || {
+            for hook in hooks.iter() {
+                match hook.notify(&event, time) {
+                    Ok(_) => debug!("notify alert {} to {}", event.name(), hook),
+                    Err(ref e) => warn!("notify alert {} to {}: {}", event.name(), hook, e),
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn alert_hook_from_str() {
+    assert_eq!(
+        "http://192.168.1.2:8080/alert".parse::<AlertHook>(),
+        Ok(AlertHook::Webhook {
+            host: "192.168.1.2".to_string(),
+            port: 8080,
+            path: "/alert".to_string()
+        })
+    );
+    assert_eq!(
+        "http://example.com"
+            .parse::<AlertHook>()
+            .unwrap()
+            .to_string(),
+        "http://example.com:80/"
+    );
+    assert_eq!(
+        "exec:logger pcap2socks".parse::<AlertHook>(),
+        Ok(AlertHook::Exec("logger pcap2socks".to_string()))
+    );
+    assert!("https://example.com".parse::<AlertHook>().is_err());
+    assert!("example.com".parse::<AlertHook>().is_err());
+}
@@ -0,0 +1,203 @@
+//! Support for capturing the traffic of certain sources and destinations on demand.
+
+use log::{debug, warn};
+use lru::LruCache;
+use pnet::datalink::{self, DataLinkSender};
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+
+use crate::filter::BlockList;
+use crate::packet::layer::LayerKind;
+use crate::packet::Indicator;
+use crate::pcap::{RotatingDump, Sender, Timestamp};
+
+/// Represents the max number of flows captured by their server names.
+const MAX_NAMED_FLOWS: usize = 1024;
+
+/// Represents a capture which dumps only the frames of flows matching its rules, from and to
+/// sources, to a rotating dump.
+pub struct Capture {
+    list: BlockList,
+    dump: Mutex<RotatingDump>,
+    named_flows: Mutex<LruCache<(SocketAddrV4, SocketAddrV4), ()>>,
+}
+
+impl Capture {
+    /// Creates a new `Capture` with a capture list. Each line contains a rule like
+    /// `capture * from 192.168.1.10` or `capture game.example.com`, where the part after `capture`
+    /// follows the syntax of the block list, and `#` starts a comment.
+    pub fn new(s: &str, dump: RotatingDump) -> Capture {
+        let rules = s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut v = line.splitn(2, char::is_whitespace);
+                match (v.next(), v.next()) {
+                    (Some("capture"), Some(rule)) => Some(rule.trim()),
+                    _ => {
+                        warn!("Ignore invalid capture list line: {}", line);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Capture {
+            list: BlockList::parse_list(&rules.join("\n"), "capture list"),
+            dump: Mutex::new(dump),
+            named_flows: Mutex::new(LruCache::new(MAX_NAMED_FLOWS)),
+        }
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.list.set_utc_offset(offset);
+    }
+
+    /// Returns the number of rules in the capture list.
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Returns if the capture list contains no rule.
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Captures the rest of a flow from the source to the destination if the server name it is
+    /// heading for matches, and returns if it matches.
+    pub fn capture_name(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        name: &str,
+    ) -> bool {
+        if !self.list.is_name_blocked(kind, name) {
+            return false;
+        }
+
+        if self
+            .named_flows
+            .lock()
+            .unwrap()
+            .put((src, dst), ())
+            .is_none()
+        {
+            debug!("capture {} -> {} of {}", src, dst, name);
+        }
+
+        true
+    }
+
+    /// Writes a frame from a source captured at the given timestamp to the dump if it matches.
+    pub fn write_from_source(&self, frame: &[u8], timestamp: Timestamp) {
+        self.write(frame, timestamp, true);
+    }
+
+    /// Writes a frame sent to a source at the given timestamp to the dump if it matches.
+    pub fn write_to_source(&self, frame: &[u8], timestamp: Timestamp) {
+        self.write(frame, timestamp, false);
+    }
+
+    fn write(&self, frame: &[u8], timestamp: Timestamp, is_from_source: bool) {
+        let indicator = match Indicator::from(frame) {
+            Some(indicator) => indicator,
+            None => return,
+        };
+        let ipv4 = match indicator.ipv4() {
+            Some(ipv4) => ipv4,
+            None => return,
+        };
+        let (src_port, dst_port) = match (indicator.tcp(), indicator.udp()) {
+            (Some(tcp), _) => (tcp.src(), tcp.dst()),
+            (_, Some(udp)) => (udp.src(), udp.dst()),
+            _ => (0, 0),
+        };
+        let (src, dst) = (
+            SocketAddrV4::new(ipv4.src(), src_port),
+            SocketAddrV4::new(ipv4.dst(), dst_port),
+        );
+        // Flows are always seen from sources
+        let (src, dst) = match is_from_source {
+            true => (src, dst),
+            false => (dst, src),
+        };
+
+        let is_captured = self
+            .list
+            .is_blocked(ipv4.next_level_layer_kind(), Some(*src.ip()), dst)
+            || self.named_flows.lock().unwrap().get(&(src, dst)).is_some();
+        if is_captured {
+            if let Err(ref e) = self.dump.lock().unwrap().write_at(frame, timestamp) {
+                warn!("capture frame: {}", e);
+            }
+        }
+    }
+}
+
+/// Represents a send half which copies the sent frames matching a capture to its dump.
+pub struct CaptureSender {
+    tx: Sender,
+    capture: Arc<Capture>,
+}
+
+impl CaptureSender {
+    /// Creates a new `CaptureSender`.
+    pub fn new(tx: Sender, capture: Arc<Capture>) -> CaptureSender {
+        CaptureSender { tx, capture }
+    }
+}
+
+impl DataLinkSender for CaptureSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let capture = &self.capture;
+        self.tx
+            .build_and_send(num_packets, packet_size, &mut |buffer| {
+                func(buffer);
+                capture.write_to_source(buffer, Timestamp::now());
+            })
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        self.capture.write_to_source(packet, Timestamp::now());
+
+        self.tx.send_to(packet, dst)
+    }
+}
+
+#[test]
+fn capture_new() {
+    let path = std::env::temp_dir().join("pcap2socks_capture_new.pcap");
+    let capture = Capture::new(
+        "# Game\ncapture udp * from 192.168.1.10\n10.0.0.1\ncapture game.example.com\n",
+        RotatingDump::new(path.to_str().unwrap()),
+    );
+    assert_eq!(capture.len(), 2);
+
+    let src = "192.168.1.10:3074".parse().unwrap();
+    let dst = "10.0.0.2:3074".parse().unwrap();
+    assert!(capture.capture_name(
+        crate::packet::layer::LayerKinds::Udp,
+        src,
+        dst,
+        "lobby.game.example.com"
+    ));
+    assert!(capture
+        .named_flows
+        .lock()
+        .unwrap()
+        .get(&(src, dst))
+        .is_some());
+}
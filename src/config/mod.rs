@@ -0,0 +1,93 @@
+//! Support for configuring the core of pcap2socks without the command line.
+
+use ipnetwork::Ipv4Network;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use crate::proxy::ProxyConfig;
+
+/// Represents the configuration of the interface, the route and the proxy. Each field maps onto an
+/// option of the command line with the same name, so library consumers can configure pcap2socks
+/// as the command line does without depending on clap.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Config {
+    /// Interface for listening, or the default one if it is not set.
+    pub interface: Option<String>,
+    /// MTU, or the MTU of the interface if it is not set.
+    pub mtu: Option<usize>,
+    /// Source, or only discovered devices if it is not set.
+    pub src: Option<Ipv4Network>,
+    /// ARP publishing address.
+    pub publish: Option<Ipv4Addr>,
+    /// Destination.
+    pub dst: SocketAddrV4,
+    /// Addresses of the destination raced in connecting, which are never raced if there is less
+    /// than 2 addresses.
+    pub dst_addrs: Vec<SocketAddr>,
+    /// Username and password of the destination.
+    pub auth: Option<(String, String)>,
+    /// Force associating with the destination address in UDP ASSOCIATE.
+    pub force_associate_dst: bool,
+    /// Force associating with the bind address in UDP ASSOCIATE.
+    pub force_associate_bind_addr: bool,
+    /// UDP relay tunneled over TCP if UDP ASSOCIATE is not supported.
+    pub udp_relay: Option<SocketAddrV4>,
+}
+
+impl Config {
+    /// Creates a new `Config` with the given destination.
+    pub fn new(dst: SocketAddrV4) -> Config {
+        Config {
+            interface: None,
+            mtu: None,
+            src: None,
+            publish: None,
+            dst,
+            dst_addrs: Vec::new(),
+            auth: None,
+            force_associate_dst: false,
+            force_associate_bind_addr: false,
+            udp_relay: None,
+        }
+    }
+
+    /// Returns the `ProxyConfig` of the destination.
+    pub fn proxy(&self) -> ProxyConfig {
+        let mut proxy = ProxyConfig::new_socks(
+            self.dst,
+            self.force_associate_dst,
+            self.force_associate_bind_addr,
+            self.auth.clone(),
+        );
+        if self.dst_addrs.len() > 1 {
+            proxy.set_addrs(self.dst_addrs.clone());
+        }
+        if let Some(udp_relay) = self.udp_relay {
+            proxy.set_udp_relay(udp_relay);
+        }
+
+        proxy
+    }
+}
+
+#[test]
+fn config_proxy() {
+    let mut config = Config::new("127.0.0.1:1080".parse().unwrap());
+    assert!(config.interface.is_none());
+    assert!(config.auth.is_none());
+    assert_eq!(config.proxy().upstream_addrs(), vec![Ipv4Addr::LOCALHOST]);
+
+    // A single address is never raced
+    config.dst_addrs = vec!["10.0.0.1:1080".parse().unwrap()];
+    assert_eq!(config.proxy().upstream_addrs(), vec![Ipv4Addr::LOCALHOST]);
+
+    config.dst_addrs.push("[::1]:1080".parse().unwrap());
+    config.udp_relay = Some("10.0.0.2:53".parse().unwrap());
+    assert_eq!(
+        config.proxy().upstream_addrs(),
+        vec![
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2)
+        ]
+    );
+}
@@ -0,0 +1,232 @@
+//! Support for diagnosing conditions of the network segment passively from its traffic.
+
+use log::{trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the number of distinct remote addresses behind a hardware address before the host of
+/// the hardware address is considered as a router.
+const ROUTER_THRESHOLD: usize = 4;
+
+/// Represents a condition of the network segment which breaks the redirection.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Finding {
+    /// Represents another host answering ARP for the published address.
+    Conflict {
+        ip_addr: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    },
+    /// Represents a source still sending traffic to another router, like the real gateway.
+    Bypass {
+        src: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    },
+    /// Represents traffic to a source delivered by another router, so the path of the source is
+    /// asymmetric.
+    Asymmetric {
+        dst: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    },
+}
+
+impl Finding {
+    /// Returns the kind of the finding.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Finding::Conflict { .. } => "conflict",
+            Finding::Bypass { .. } => "bypass",
+            Finding::Asymmetric { .. } => "asymmetric",
+        }
+    }
+
+    /// Returns the IP address concerned, which is the published address in a conflict, or the
+    /// source otherwise.
+    pub fn ip_addr(&self) -> Ipv4Addr {
+        match *self {
+            Finding::Conflict { ip_addr, .. } => ip_addr,
+            Finding::Bypass { src, .. } => src,
+            Finding::Asymmetric { dst, .. } => dst,
+        }
+    }
+
+    /// Returns the hardware address of the other host.
+    pub fn hardware_addr(&self) -> HardwareAddr {
+        match *self {
+            Finding::Conflict { hardware_addr, .. } => hardware_addr,
+            Finding::Bypass { hardware_addr, .. } => hardware_addr,
+            Finding::Asymmetric { hardware_addr, .. } => hardware_addr,
+        }
+    }
+
+    /// Returns the advice on the finding.
+    pub fn advice(&self) -> &'static str {
+        match self {
+            Finding::Conflict { .. } => "use an unused address for publishing",
+            Finding::Bypass { .. } => "set the gateway of the source to the published address manually, or renew its DHCP lease",
+            Finding::Asymmetric { .. } => "make sure the source has no other route to the Internet, like another interface or a static route",
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Finding::Conflict {
+                ip_addr,
+                hardware_addr,
+            } => write!(
+                f,
+                "the published address {} is also answered by {}",
+                ip_addr, hardware_addr
+            ),
+            Finding::Bypass { src, hardware_addr } => write!(
+                f,
+                "the source {} still sends traffic to the router {}",
+                src, hardware_addr
+            ),
+            Finding::Asymmetric { dst, hardware_addr } => write!(
+                f,
+                "traffic to the source {} is delivered by the router {}",
+                dst, hardware_addr
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DiagnosticsState {
+    findings: Vec<Finding>,
+    outbound: HashMap<(Ipv4Addr, HardwareAddr), HashSet<Ipv4Addr>>,
+    inbound: HashMap<(Ipv4Addr, HardwareAddr), HashSet<Ipv4Addr>>,
+}
+
+/// Represents the findings on the network segment. A host is considered as a router once traffic
+/// of several remote addresses goes through its hardware address.
+#[derive(Debug)]
+pub struct Diagnostics {
+    local_hardware_addr: HardwareAddr,
+    state: Mutex<DiagnosticsState>,
+}
+
+impl Diagnostics {
+    /// Creates a new `Diagnostics` on the interface of the given hardware address.
+    pub fn new(local_hardware_addr: HardwareAddr) -> Diagnostics {
+        Diagnostics {
+            local_hardware_addr,
+            state: Mutex::new(DiagnosticsState::default()),
+        }
+    }
+
+    /// Records another host answering ARP for the published address. The conflict is logged by
+    /// the redirector.
+    pub fn observe_conflict(&self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
+        let finding = Finding::Conflict {
+            ip_addr,
+            hardware_addr,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !state.findings.contains(&finding) {
+            trace!("find {}", finding);
+            state.findings.push(finding);
+        }
+    }
+
+    /// Records a frame from the source to the remote address through the given hardware address.
+    pub fn observe_outbound(&self, src: Ipv4Addr, dst: Ipv4Addr, hardware_addr: HardwareAddr) {
+        if !self.is_remote(dst, hardware_addr) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if is_router(&mut state.outbound, src, dst, hardware_addr) {
+            report(&mut state, Finding::Bypass { src, hardware_addr });
+        }
+    }
+
+    /// Records a frame from the remote address to the source through the given hardware address.
+    pub fn observe_inbound(&self, src: Ipv4Addr, dst: Ipv4Addr, hardware_addr: HardwareAddr) {
+        if !self.is_remote(src, hardware_addr) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if is_router(&mut state.inbound, dst, src, hardware_addr) {
+            report(&mut state, Finding::Asymmetric { dst, hardware_addr });
+        }
+    }
+
+    fn is_remote(&self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> bool {
+        // Group addresses
+        hardware_addr != self.local_hardware_addr
+            && hardware_addr.0 & 1 == 0
+            && !ip_addr.is_broadcast()
+            && !ip_addr.is_multicast()
+            && !ip_addr.is_unspecified()
+    }
+
+    /// Returns all the findings, in the order they were found.
+    pub fn findings(&self) -> Vec<Finding> {
+        self.state.lock().unwrap().findings.clone()
+    }
+}
+
+/// Adds the remote address to the ones of the source behind the hardware address, and returns if
+/// the hardware address just became a router.
+fn is_router(
+    routes: &mut HashMap<(Ipv4Addr, HardwareAddr), HashSet<Ipv4Addr>>,
+    ip_addr: Ipv4Addr,
+    remote: Ipv4Addr,
+    hardware_addr: HardwareAddr,
+) -> bool {
+    let remotes = routes.entry((ip_addr, hardware_addr)).or_default();
+    if remotes.len() >= ROUTER_THRESHOLD {
+        return false;
+    }
+    remotes.insert(remote);
+
+    remotes.len() >= ROUTER_THRESHOLD
+}
+
+fn report(state: &mut DiagnosticsState, finding: Finding) {
+    if !state.findings.contains(&finding) {
+        warn!(
+            "Diagnose the segment: {}. Please {}",
+            finding,
+            finding.advice()
+        );
+        state.findings.push(finding);
+    }
+}
+
+#[test]
+fn diagnostics_observe() {
+    let local = HardwareAddr::new(0x00, 0xd9, 0xd1, 1, 2, 3);
+    let router = HardwareAddr::new(0x98, 0xb6, 0xe9, 1, 2, 3);
+    let src = Ipv4Addr::new(10, 6, 0, 1);
+    let diagnostics = Diagnostics::new(local);
+
+    // Traffic to pcap2socks and to a peer in the segment
+    for i in 0..8 {
+        diagnostics.observe_outbound(src, Ipv4Addr::new(1, 1, 1, i), local);
+    }
+    diagnostics.observe_outbound(src, Ipv4Addr::new(10, 6, 0, 2), router);
+    assert!(diagnostics.findings().is_empty());
+
+    for i in 0..8 {
+        diagnostics.observe_outbound(src, Ipv4Addr::new(1, 1, 1, i), router);
+        diagnostics.observe_inbound(Ipv4Addr::new(8, 8, 8, i), src, router);
+    }
+    diagnostics.observe_conflict(Ipv4Addr::new(10, 6, 0, 254), router);
+    diagnostics.observe_conflict(Ipv4Addr::new(10, 6, 0, 254), router);
+    let findings = diagnostics.findings();
+    assert_eq!(findings.len(), 3);
+    assert_eq!(findings[0].kind(), "bypass");
+    assert_eq!(findings[1].kind(), "asymmetric");
+    assert_eq!(findings[2].kind(), "conflict");
+    assert_eq!(findings[1].ip_addr(), src);
+}
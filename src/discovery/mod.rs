@@ -0,0 +1,268 @@
+//! Support for discovering devices from their ARP and DHCP traffic.
+
+use log::{info, trace};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::oui;
+use crate::pcap::HardwareAddr;
+
+/// Represents the port of DHCP servers.
+pub const DHCP_SERVER_PORT: u16 = 67;
+/// Represents the port of DHCP clients.
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTP_SIZE: usize = 236;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTION_PAD: u8 = 0;
+const DHCP_OPTION_HOSTNAME: u8 = 12;
+const DHCP_OPTION_REQUESTED_IP_ADDR: u8 = 50;
+const DHCP_OPTION_END: u8 = 255;
+
+/// Represents a device seen on the interface.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Candidate {
+    hardware_addr: HardwareAddr,
+    ip_addr: Option<Ipv4Addr>,
+    hostname: Option<String>,
+}
+
+impl Candidate {
+    /// Returns the hardware address of the device.
+    pub fn hardware_addr(&self) -> HardwareAddr {
+        self.hardware_addr
+    }
+
+    /// Returns the vendor of the device.
+    pub fn vendor(&self) -> Option<&'static str> {
+        oui::vendor(self.hardware_addr)
+    }
+
+    /// Returns the last IP address of the device, which may be unknown if the device has only
+    /// discovered a DHCP server.
+    pub fn ip_addr(&self) -> Option<Ipv4Addr> {
+        self.ip_addr
+    }
+
+    /// Returns the hostname the device sent in DHCP.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+}
+
+impl Display for Candidate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.hardware_addr)?;
+        if let Some(vendor) = self.vendor() {
+            write!(f, " ({})", vendor)?;
+        }
+        if let Some(ref hostname) = self.hostname {
+            write!(f, " {}", hostname)?;
+        }
+        match self.ip_addr {
+            Some(ip_addr) => write!(f, " at {}", ip_addr),
+            None => write!(f, " without an address"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DiscoveryState {
+    candidates: HashMap<HardwareAddr, Candidate>,
+    approved: HashSet<Ipv4Addr>,
+}
+
+/// Represents the devices learned from the traffic on the interface, and the ones approved to be
+/// proxied as sources.
+#[derive(Debug, Default)]
+pub struct Discovery {
+    state: Mutex<DiscoveryState>,
+}
+
+impl Discovery {
+    /// Creates a new `Discovery`.
+    pub fn new() -> Discovery {
+        Discovery::default()
+    }
+
+    /// Records a device seen with the given addresses. A known address is not overwritten by an
+    /// unknown one.
+    pub fn observe(
+        &self,
+        hardware_addr: HardwareAddr,
+        ip_addr: Option<Ipv4Addr>,
+        hostname: Option<String>,
+    ) {
+        let ip_addr = ip_addr.filter(|ip_addr| !ip_addr.is_unspecified());
+
+        let mut state = self.state.lock().unwrap();
+        match state.candidates.get_mut(&hardware_addr) {
+            Some(candidate) => {
+                if ip_addr.is_some() && ip_addr != candidate.ip_addr {
+                    candidate.ip_addr = ip_addr;
+                    info!("Device {} moved", candidate);
+                }
+                if hostname.is_some() {
+                    candidate.hostname = hostname;
+                }
+            }
+            None => {
+                let candidate = Candidate {
+                    hardware_addr,
+                    ip_addr,
+                    hostname,
+                };
+                info!("Found device {}", candidate);
+                state.candidates.insert(hardware_addr, candidate);
+            }
+        }
+    }
+
+    /// Returns all the devices seen, ordered by their hardware addresses.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let state = self.state.lock().unwrap();
+        let mut candidates = state.candidates.values().cloned().collect::<Vec<_>>();
+        candidates.sort_by_key(|candidate| {
+            let addr = candidate.hardware_addr;
+            [addr.0, addr.1, addr.2, addr.3, addr.4, addr.5]
+        });
+
+        candidates
+    }
+
+    /// Approves the IP address to be proxied as a source. Returns if it was not approved yet.
+    pub fn approve(&self, ip_addr: Ipv4Addr) -> bool {
+        let is_new = self.state.lock().unwrap().approved.insert(ip_addr);
+        if is_new {
+            trace!("approve source {}", ip_addr);
+        }
+
+        is_new
+    }
+
+    /// Returns if the IP address is approved to be proxied.
+    pub fn is_approved(&self, ip_addr: Ipv4Addr) -> bool {
+        self.state.lock().unwrap().approved.contains(&ip_addr)
+    }
+
+    /// Returns the approved IP addresses.
+    pub fn approved(&self) -> Vec<Ipv4Addr> {
+        let mut approved = self
+            .state
+            .lock()
+            .unwrap()
+            .approved
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        approved.sort();
+
+        approved
+    }
+}
+
+/// Parses a DHCP message, and returns the hardware address of the client, its IP address if any,
+/// and its hostname if any. The IP address is the client address, the assigned address of a
+/// reply, or the requested address, in order.
+pub fn parse_dhcp(payload: &[u8]) -> Option<(HardwareAddr, Option<Ipv4Addr>, Option<String>)> {
+    if payload.len() < BOOTP_SIZE + DHCP_MAGIC_COOKIE.len()
+        || payload[BOOTP_SIZE..BOOTP_SIZE + 4] != DHCP_MAGIC_COOKIE
+    {
+        return None;
+    }
+    // Ethernet
+    if payload[1] != 1 || payload[2] != 6 {
+        return None;
+    }
+
+    let ciaddr = Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]);
+    let yiaddr = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+    let hardware_addr = HardwareAddr::new(
+        payload[28],
+        payload[29],
+        payload[30],
+        payload[31],
+        payload[32],
+        payload[33],
+    );
+
+    // Options
+    let mut requested = None;
+    let mut hostname = None;
+    let mut i = BOOTP_SIZE + 4;
+    while i < payload.len() {
+        match payload[i] {
+            DHCP_OPTION_PAD => {
+                i += 1;
+                continue;
+            }
+            DHCP_OPTION_END => break,
+            _ => {}
+        }
+        if i + 2 > payload.len() {
+            break;
+        }
+        let option = payload[i];
+        let end = i + 2 + payload[i + 1] as usize;
+        if end > payload.len() {
+            break;
+        }
+        let value = &payload[i + 2..end];
+        match option {
+            DHCP_OPTION_REQUESTED_IP_ADDR if value.len() == 4 => {
+                requested = Some(Ipv4Addr::new(value[0], value[1], value[2], value[3]));
+            }
+            DHCP_OPTION_HOSTNAME => {
+                let s = String::from_utf8_lossy(value);
+                let s = s.trim_matches(char::from(0)).trim();
+                if !s.is_empty() {
+                    hostname = Some(s.to_string());
+                }
+            }
+            _ => {}
+        }
+        i = end;
+    }
+
+    let ip_addr = [Some(ciaddr), Some(yiaddr), requested]
+        .iter()
+        .flatten()
+        .copied()
+        .find(|ip_addr| !ip_addr.is_unspecified());
+
+    Some((hardware_addr, ip_addr, hostname))
+}
+
+#[test]
+fn discovery_parse_dhcp() {
+    let mut payload = vec![0u8; BOOTP_SIZE];
+    payload[0] = 1;
+    payload[1] = 1;
+    payload[2] = 6;
+    payload[28..34].copy_from_slice(&[0x98, 0xb6, 0xe9, 1, 2, 3]);
+    payload.extend_from_slice(&DHCP_MAGIC_COOKIE);
+    // DHCP request with a requested address and a hostname
+    payload.extend_from_slice(&[53, 1, 3, 50, 4, 192, 168, 1, 9, 12, 6]);
+    payload.extend_from_slice(b"Switch");
+    payload.push(DHCP_OPTION_END);
+
+    let (hardware_addr, ip_addr, hostname) = parse_dhcp(&payload).unwrap();
+    assert_eq!(hardware_addr, HardwareAddr::new(0x98, 0xb6, 0xe9, 1, 2, 3));
+    assert_eq!(ip_addr, Some(Ipv4Addr::new(192, 168, 1, 9)));
+    assert_eq!(hostname.as_deref(), Some("Switch"));
+
+    let discovery = Discovery::new();
+    discovery.observe(hardware_addr, None, hostname);
+    discovery.observe(hardware_addr, ip_addr, None);
+    let candidates = discovery.candidates();
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].ip_addr(), ip_addr);
+    assert_eq!(candidates[0].hostname(), Some("Switch"));
+    #[cfg(feature = "oui")]
+    assert_eq!(candidates[0].vendor(), Some("Nintendo"));
+
+    assert!(discovery.approve(Ipv4Addr::new(192, 168, 1, 9)));
+    assert!(discovery.is_approved(Ipv4Addr::new(192, 168, 1, 9)));
+}
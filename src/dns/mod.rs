@@ -0,0 +1,807 @@
+//! Support for caching DNS responses.
+
+use log::{trace, warn};
+#[cfg(feature = "dns")]
+use lru::LruCache;
+#[cfg(feature = "dns")]
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+#[cfg(feature = "dns")]
+use std::net::SocketAddrV4;
+#[cfg(feature = "dns")]
+use std::time::Instant;
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+mod upstream;
+#[cfg(all(feature = "dns", feature = "tls"))]
+pub use upstream::{DnsUpstream, DNS_OVER_HTTPS_PORT, DNS_OVER_TLS_PORT};
+
+/// Represents the port of DNS.
+pub const DNS_PORT: u16 = 53;
+
+/// Represents the max number of entries in the DNS cache.
+#[cfg(feature = "dns")]
+const MAX_CACHE: usize = 1024;
+
+/// Represents the max TTL of negative answers in the DNS cache.
+#[cfg(feature = "dns")]
+const MAX_NEGATIVE_TTL: u32 = 60;
+
+/// Represents the TTL of the first server failure in the DNS cache, which doubles for each
+/// consecutive failure of the same question.
+#[cfg(feature = "dns")]
+const SERVFAIL_TTL: u32 = 5;
+
+/// Represents the max TTL of server failures in the DNS cache.
+#[cfg(feature = "dns")]
+const MAX_SERVFAIL_TTL: u32 = 300;
+
+/// Represents the max number of sources in the DNS redirection.
+#[cfg(feature = "dns")]
+const MAX_REDIRECT: usize = 1024;
+
+/// Represents the TTL of answers from static hostname mappings.
+const HOSTS_TTL: u32 = 60;
+
+/// Represents the max UDP payload size of DNS messages without EDNS.
+#[cfg(feature = "dns")]
+pub const MAX_UDP_PAYLOAD_SIZE: u16 = 512;
+/// Represents the UDP payload size advertised in DNS queries with EDNS, which avoids IP
+/// fragmentation in most networks.
+#[cfg(feature = "dns")]
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+const HEADER_SIZE: usize = 12;
+const MAX_POINTERS: usize = 16;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+#[cfg(feature = "dns")]
+const RCODE_NOERROR: u8 = 0;
+#[cfg(feature = "dns")]
+const RCODE_SERVFAIL: u8 = 2;
+#[cfg(feature = "dns")]
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Represents a DNS question.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl Question {
+    /// Returns the name of the question, in lowercase.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Returns the type of the question.
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+
+    /// Returns the class of the question.
+    pub fn qclass(&self) -> u16 {
+        self.qclass
+    }
+}
+
+impl Display for Question {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} (type {})", self.name, self.qtype)
+    }
+}
+
+/// Represents a DNS message.
+#[derive(Clone, Debug)]
+pub struct Message {
+    id: u16,
+    flags: u16,
+    question: Option<Question>,
+    question_end: usize,
+    ancount: u16,
+    ttls: Vec<(usize, u32)>,
+    addrs: Vec<Ipv4Addr>,
+    negative_ttl: Option<u32>,
+    /// Represents the start, the end and the UDP payload size of the OPT record.
+    opt: Option<(usize, usize, u16)>,
+}
+
+impl Message {
+    /// Parses a DNS message from the given buffer.
+    pub fn parse(buffer: &[u8]) -> Option<Message> {
+        let id = read_u16(buffer, 0)?;
+        let flags = read_u16(buffer, 2)?;
+        let qdcount = read_u16(buffer, 4)?;
+        let ancount = read_u16(buffer, 6)?;
+        let nscount = read_u16(buffer, 8)?;
+        let arcount = read_u16(buffer, 10)?;
+
+        // Questions
+        let mut pos = HEADER_SIZE;
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let (name, next) = read_name(buffer, pos)?;
+            let qtype = read_u16(buffer, next)?;
+            let qclass = read_u16(buffer, next + 2)?;
+            questions.push(Question {
+                name,
+                qtype,
+                qclass,
+            });
+            pos = next + 4;
+        }
+        let question = match questions.len() {
+            1 => questions.pop(),
+            _ => None,
+        };
+        let question_end = pos;
+
+        // Resource records
+        let mut ttls = Vec::new();
+        let mut addrs = Vec::new();
+        let mut negative_ttl = None;
+        let mut opt = None;
+        let count = ancount as usize + nscount as usize + arcount as usize;
+        for i in 0..count {
+            let (_, next) = read_name(buffer, pos)?;
+            let rtype = read_u16(buffer, next)?;
+            let rclass = read_u16(buffer, next + 2)?;
+            let ttl = read_u32(buffer, next + 4)?;
+            let rdlength = read_u16(buffer, next + 8)? as usize;
+            let start = pos;
+            pos = next + 10 + rdlength;
+            if pos > buffer.len() {
+                return None;
+            }
+            if rtype != TYPE_OPT {
+                ttls.push((next + 4, ttl));
+            } else if i >= ancount as usize + nscount as usize {
+                opt = Some((start, pos, rclass));
+            }
+            // Addresses in answers
+            if i < ancount as usize && rtype == TYPE_A && rclass == CLASS_IN && rdlength == 4 {
+                let b = &buffer[next + 10..pos];
+                addrs.push(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            }
+            // SOA in authorities, whose MINIMUM limits the TTL of negative answers (RFC 2308)
+            if i >= ancount as usize && i < ancount as usize + nscount as usize && rtype == TYPE_SOA
+            {
+                let (_, rname) = read_name(buffer, next + 10)?;
+                let (_, serial) = read_name(buffer, rname)?;
+                let minimum = read_u32(buffer, serial + 16).filter(|_| serial + 20 <= pos)?;
+                negative_ttl = Some(ttl.min(minimum));
+            }
+        }
+
+        Some(Message {
+            id,
+            flags,
+            question,
+            question_end,
+            ancount,
+            ttls,
+            addrs,
+            negative_ttl,
+            opt,
+        })
+    }
+
+    /// Returns the identifier of the message.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns if the message is a response.
+    pub fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    /// Returns the opcode of the message.
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0xF) as u8
+    }
+
+    /// Returns if the message is truncated.
+    pub fn is_truncated(&self) -> bool {
+        self.flags & 0x0200 != 0
+    }
+
+    /// Returns the response code of the message.
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xF) as u8
+    }
+
+    /// Returns the question of the message if the message contains exactly one question.
+    pub fn question(&self) -> Option<&Question> {
+        self.question.as_ref()
+    }
+
+    /// Returns if recursion is desired in the message.
+    pub fn is_recursion_desired(&self) -> bool {
+        self.flags & 0x0100 != 0
+    }
+
+    /// Returns the number of answers of the message.
+    pub fn ancount(&self) -> u16 {
+        self.ancount
+    }
+
+    /// Returns the IPv4 addresses in the A records of the answers, which may belong to the aliases
+    /// of the name in the question.
+    pub fn addrs(&self) -> &[Ipv4Addr] {
+        &self.addrs
+    }
+
+    /// Returns the minimum TTL of all the resource records of the message.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.ttls.iter().map(|(_, ttl)| *ttl).min()
+    }
+
+    /// Returns the TTL of the negative answer of the message, which is known by the SOA record in
+    /// the authorities.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.negative_ttl
+    }
+
+    /// Returns the UDP payload size in the EDNS OPT record of the message.
+    pub fn udp_payload_size(&self) -> Option<u16> {
+        self.opt.map(|(_, _, size)| size)
+    }
+}
+
+/// Returns the DNS query with the UDP payload size in its OPT record set to the given size. An
+/// OPT record will be added if the query has none.
+#[cfg(feature = "dns")]
+pub fn set_udp_payload_size(query: &[u8], message: &Message, size: u16) -> Vec<u8> {
+    let mut query = query.to_vec();
+    match message.opt {
+        Some((start, _, _)) => {
+            // The name of the OPT record is always the root
+            query[start + 3..start + 5].copy_from_slice(&size.to_be_bytes());
+        }
+        None => {
+            let arcount = read_u16(&query, 10).unwrap_or(0).saturating_add(1);
+            query[10..12].copy_from_slice(&arcount.to_be_bytes());
+            query.extend_from_slice(&[0, 0, TYPE_OPT as u8]);
+            query.extend_from_slice(&size.to_be_bytes());
+            query.extend_from_slice(&[0; 6]);
+        }
+    }
+
+    query
+}
+
+/// Returns the DNS response fit for a source of the UDP payload size, and if the source supports
+/// EDNS. The OPT record will be removed if the source does not support EDNS, and the response
+/// will be truncated to the question with the TC bit set if it is still too large, so the source
+/// can retry over TCP.
+#[cfg(feature = "dns")]
+pub fn fit_response(response: &[u8], message: &Message, size: u16, is_edns: bool) -> Vec<u8> {
+    let mut response = response.to_vec();
+    if !is_edns {
+        // Only the OPT record at the end can be removed without breaking compression
+        if let Some((start, end, _)) = message.opt.filter(|opt| opt.1 == response.len()) {
+            response.truncate(start);
+            let arcount = read_u16(&response, 10).unwrap_or(1).saturating_sub(1);
+            response[10..12].copy_from_slice(&arcount.to_be_bytes());
+            trace!("remove DNS OPT record of {} Bytes", end - start);
+        }
+    }
+
+    let size = max(size, MAX_UDP_PAYLOAD_SIZE) as usize;
+    if response.len() > size && message.question_end <= size {
+        trace!(
+            "truncate DNS response of {} Bytes to {}",
+            response.len(),
+            size
+        );
+        response.truncate(message.question_end);
+        response[2] |= 0x02;
+        response[6..12].copy_from_slice(&[0; 6]);
+    }
+
+    response
+}
+
+/// Returns the DNS query of the DNS response, which is used to retry a truncated response over
+/// TCP.
+#[cfg(feature = "dns")]
+pub fn query_of(response: &[u8], message: &Message) -> Option<Vec<u8>> {
+    message.question()?;
+    let mut query = response.get(..message.question_end)?.to_vec();
+
+    // Keep the opcode, RD and CD bits
+    query[2..4].copy_from_slice(&(message.flags & 0x7910).to_be_bytes());
+    query[6..12].copy_from_slice(&[0; 6]);
+
+    Some(query)
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> Option<u16> {
+    let b = buffer.get(pos..pos + 2)?;
+
+    Some(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(buffer: &[u8], pos: usize) -> Option<u32> {
+    let b = buffer.get(pos..pos + 4)?;
+
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_name(buffer: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = pos;
+    let mut next = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buffer.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            // Compression
+            let low = *buffer.get(pos + 1)? as usize;
+            if next.is_none() {
+                next = Some(pos + 2);
+            }
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            pos = ((len & 0x3F) << 8) | low;
+        } else if len & 0xC0 != 0 {
+            return None;
+        } else if len == 0 {
+            if next.is_none() {
+                next = Some(pos + 1);
+            }
+            break;
+        } else {
+            let label = buffer.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += 1 + len;
+        }
+    }
+
+    Some((labels.join("."), next.unwrap()))
+}
+
+/// Represents a cached DNS response.
+#[cfg(feature = "dns")]
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    payload: Vec<u8>,
+    ttls: Vec<(usize, u32)>,
+    instant: Instant,
+    ttl: u32,
+}
+
+/// Represents a cache of DNS responses.
+#[cfg(feature = "dns")]
+pub struct DnsCache {
+    min_ttl: u32,
+    max_ttl: u32,
+    entries: LruCache<Question, CacheEntry>,
+    /// Represents the LRU mapping a question to the number of its consecutive server failures.
+    failures: LruCache<Question, u32>,
+}
+
+#[cfg(feature = "dns")]
+impl DnsCache {
+    /// Creates a new `DnsCache`, the TTLs of responses will be clamped to the given range.
+    pub fn new(min_ttl: u32, max_ttl: u32) -> DnsCache {
+        DnsCache {
+            min_ttl,
+            max_ttl,
+            entries: LruCache::new(MAX_CACHE),
+            failures: LruCache::new(MAX_CACHE),
+        }
+    }
+
+    /// Inserts a DNS response into the cache. Successful responses with answers will be cached,
+    /// so will negative answers with SOA records for a short time (RFC 2308), and server failures
+    /// for a time backing off exponentially.
+    pub fn insert(&mut self, response: &[u8]) {
+        let message = match Message::parse(response) {
+            Some(message) => message,
+            None => return,
+        };
+        if !message.is_response() || message.opcode() != 0 || message.is_truncated() {
+            return;
+        }
+        let question = match message.question() {
+            Some(question) => question.clone(),
+            None => return,
+        };
+        let ttl = match message.rcode() {
+            RCODE_NOERROR if message.ancount() > 0 => {
+                self.failures.pop(&question);
+                match message.min_ttl() {
+                    Some(ttl) => min(self.max_ttl, max(self.min_ttl, ttl)),
+                    None => return,
+                }
+            }
+            // Negative answers, NXDOMAIN or NODATA
+            RCODE_NOERROR | RCODE_NXDOMAIN => {
+                self.failures.pop(&question);
+                match message.negative_ttl() {
+                    Some(ttl) => min(self.max_ttl, min(MAX_NEGATIVE_TTL, ttl)),
+                    None => return,
+                }
+            }
+            RCODE_SERVFAIL => {
+                let failures = self
+                    .failures
+                    .get(&question)
+                    .copied()
+                    .unwrap_or(0)
+                    .saturating_add(1);
+                self.failures.put(question.clone(), failures);
+                trace!("back off DNS {} after {} failures", question, failures);
+
+                min(MAX_SERVFAIL_TTL, SERVFAIL_TTL << min(failures - 1, 8))
+            }
+            _ => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        trace!("insert DNS cache of {} for {} s", question, ttl);
+        self.entries.put(
+            question,
+            CacheEntry {
+                payload: response.to_vec(),
+                ttls: message.ttls,
+                instant: Instant::now(),
+                ttl,
+            },
+        );
+    }
+
+    /// Returns the cached DNS response of the given DNS query. The identifier and TTLs in the
+    /// response will be updated.
+    pub fn get(&mut self, query: &[u8]) -> Option<Vec<u8>> {
+        let message = Message::parse(query)?;
+        if message.is_response() || message.opcode() != 0 {
+            return None;
+        }
+        let question = message.question()?;
+
+        let (ttl, elapsed) = {
+            let entry = self.entries.get(question)?;
+            (entry.ttl, entry.instant.elapsed().as_secs())
+        };
+        if elapsed >= ttl as u64 {
+            self.entries.pop(question);
+            trace!("expire DNS cache of {}", question);
+
+            return None;
+        }
+        let remaining = ttl - elapsed as u32;
+
+        let entry = self.entries.peek(question)?;
+        let mut payload = entry.payload.clone();
+        payload[..2].copy_from_slice(&message.id().to_be_bytes());
+        for &(offset, ttl) in &entry.ttls {
+            payload[offset..offset + 4].copy_from_slice(&min(ttl, remaining).to_be_bytes());
+        }
+        trace!("hit DNS cache of {} with TTL {} s", question, remaining);
+
+        Some(payload)
+    }
+}
+
+/// Represents a redirection of DNS queries to a designated DNS server.
+#[cfg(feature = "dns")]
+pub struct DnsRedirect {
+    server: SocketAddrV4,
+    map: LruCache<SocketAddrV4, SocketAddrV4>,
+}
+
+#[cfg(feature = "dns")]
+impl DnsRedirect {
+    /// Creates a new `DnsRedirect`.
+    pub fn new(server: SocketAddrV4) -> DnsRedirect {
+        DnsRedirect {
+            server,
+            map: LruCache::new(MAX_REDIRECT),
+        }
+    }
+
+    /// Redirects a DNS query from the source to the DNS server, and returns the DNS server.
+    pub fn redirect(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> SocketAddrV4 {
+        if dst != self.server {
+            self.map.put(src, dst);
+            trace!("redirect DNS {} -> {} to {}", src, dst, self.server);
+        }
+
+        self.server
+    }
+
+    /// Restores the original destination of a DNS response from the DNS server to the source.
+    pub fn restore(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> SocketAddrV4 {
+        if dst == self.server {
+            if let Some(&original) = self.map.get(&src) {
+                return original;
+            }
+        }
+
+        dst
+    }
+
+    /// Returns the DNS server.
+    pub fn server(&self) -> SocketAddrV4 {
+        self.server
+    }
+}
+
+/// Represents static hostname mappings.
+#[derive(Clone, Debug, Default)]
+pub struct Hosts {
+    map: HashMap<String, Ipv4Addr>,
+}
+
+impl Hosts {
+    /// Parses static hostname mappings in the hosts file format. Lines which cannot be parsed or
+    /// with IPv6 addresses will be ignored.
+    pub fn parse(s: &str) -> Hosts {
+        let mut map = HashMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let ip_addr = match fields.next() {
+                Some(field) => match field.parse::<Ipv4Addr>() {
+                    Ok(ip_addr) => ip_addr,
+                    Err(_) => {
+                        if field.parse::<std::net::Ipv6Addr>().is_err() {
+                            warn!("Ignore line {} in the hosts: {}", i + 1, line.trim());
+                        }
+                        continue;
+                    }
+                },
+                None => continue,
+            };
+            for name in fields {
+                let name = name.trim_end_matches('.').to_lowercase();
+                trace!("set hosts {} to {}", name, ip_addr);
+                map.insert(name, ip_addr);
+            }
+        }
+
+        Hosts { map }
+    }
+
+    /// Returns the IP address of the given name.
+    pub fn get(&self, name: &str) -> Option<Ipv4Addr> {
+        self.map.get(name).copied()
+    }
+
+    /// Returns the number of names in the mappings.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns if the mappings are empty.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the DNS response of the given DNS query if the name of the query is in the
+    /// mappings.
+    pub fn reply(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let message = Message::parse(query)?;
+        let mut flags: u16 = 0x8080;
+        if message.is_recursion_desired() {
+            flags |= 0x0100;
+        }
+
+        self.reply_with_flags(query, &message, flags)
+    }
+
+    /// Returns the LLMNR response of the given LLMNR query if the name of the query is in the
+    /// mappings. LLMNR shares the message format of DNS, but not the meaning of the flags.
+    pub fn reply_llmnr(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let message = Message::parse(query)?;
+
+        self.reply_with_flags(query, &message, 0x8000)
+    }
+
+    fn reply_with_flags(&self, query: &[u8], message: &Message, flags: u16) -> Option<Vec<u8>> {
+        if message.is_response() || message.opcode() != 0 {
+            return None;
+        }
+        let question = message.question()?;
+        if question.qclass() != CLASS_IN {
+            return None;
+        }
+        let ip_addr = self.get(question.name())?;
+        let answers = match question.qtype() {
+            TYPE_A => vec![ip_addr],
+            // Reply an empty answer so the query will not fall back to the upstream
+            TYPE_AAAA => vec![],
+            _ => return None,
+        };
+
+        // Header
+        let mut response = Vec::with_capacity(message.question_end + 16 * answers.len());
+        response.extend_from_slice(&message.id().to_be_bytes());
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        // Question
+        response.extend_from_slice(&query[HEADER_SIZE..message.question_end]);
+        // Answers
+        for ip_addr in &answers {
+            response.extend_from_slice(&[0xc0, HEADER_SIZE as u8]);
+            response.extend_from_slice(&TYPE_A.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&HOSTS_TTL.to_be_bytes());
+            response.extend_from_slice(&4u16.to_be_bytes());
+            response.extend_from_slice(&ip_addr.octets());
+        }
+        trace!("reply hosts of {} with {}", question, ip_addr);
+
+        Some(response)
+    }
+}
+
+#[test]
+fn message_parse() {
+    let response = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, b'E', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0,
+        0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+    let message = Message::parse(&response).unwrap();
+    assert!(message.is_response());
+    assert_eq!(message.question().unwrap().name(), "example.com");
+    assert_eq!(message.ancount(), 1);
+    assert_eq!(message.min_ttl(), Some(300));
+    assert_eq!(message.addrs(), &[Ipv4Addr::new(93, 184, 216, 34)]);
+
+    assert!(Message::parse(&response[..40]).is_none());
+}
+
+#[cfg(feature = "dns")]
+#[test]
+fn dns_cache_get() {
+    let query = [
+        0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+    let response = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, b'E', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0,
+        0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+
+    let mut cache = DnsCache::new(0, 60);
+    assert!(cache.get(&query).is_none());
+
+    cache.insert(&response);
+    let cached = cache.get(&query).unwrap();
+    assert_eq!(&cached[..2], &[0xab, 0xcd]);
+    assert_eq!(&cached[35..39], &60u32.to_be_bytes());
+}
+
+#[cfg(feature = "dns")]
+#[test]
+fn dns_cache_negative() {
+    let query = [
+        0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+    let mut cache = DnsCache::new(0, 3600);
+
+    // NXDOMAIN with an SOA record of TTL 3600 s and MINIMUM 30 s
+    let mut response = query.to_vec();
+    response[2..4].copy_from_slice(&[0x81, 0x83]);
+    response[8..10].copy_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10]);
+    response.extend_from_slice(&[0x00, 0x1a, 0x02, b'n', b's', 0xc0, 0x0c, 0x00]);
+    response.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 30]);
+    let message = Message::parse(&response).unwrap();
+    assert_eq!(message.negative_ttl(), Some(30));
+
+    cache.insert(&response);
+    let cached = cache.get(&query).unwrap();
+    assert_eq!(cached[3] & 0x0f, RCODE_NXDOMAIN);
+    assert_eq!(&cached[35..39], &30u32.to_be_bytes());
+
+    // SERVFAIL, backing off exponentially
+    let mut response = query.to_vec();
+    response[2..4].copy_from_slice(&[0x81, 0x82]);
+    let question = Message::parse(&response)
+        .unwrap()
+        .question()
+        .unwrap()
+        .clone();
+    for ttl in &[5, 10, 20, 40] {
+        cache.insert(&response);
+        assert_eq!(cache.entries.peek(&question).unwrap().ttl, *ttl);
+    }
+}
+
+#[cfg(feature = "dns")]
+#[test]
+fn edns_fit_response() {
+    let query = [
+        0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+    let edns_query = set_udp_payload_size(&query, &Message::parse(&query).unwrap(), 1232);
+    let message = Message::parse(&edns_query).unwrap();
+    assert_eq!(message.udp_payload_size(), Some(1232));
+    let edns_query = set_udp_payload_size(&edns_query, &message, 4096);
+    assert_eq!(
+        Message::parse(&edns_query).unwrap().udp_payload_size(),
+        Some(4096)
+    );
+
+    // A response of 40 answers and an OPT record
+    let mut response = query.to_vec();
+    response[2..4].copy_from_slice(&[0x81, 0x80]);
+    response[6..8].copy_from_slice(&40u16.to_be_bytes());
+    response[10..12].copy_from_slice(&1u16.to_be_bytes());
+    for i in 0..40 {
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c]);
+        response.extend_from_slice(&[0x00, 0x04, 10, 0, 0, i]);
+    }
+    response.extend_from_slice(&[0, 0, TYPE_OPT as u8, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+    let message = Message::parse(&response).unwrap();
+
+    let fitted = fit_response(&response, &message, 1232, true);
+    assert_eq!(fitted, response);
+    let fitted = fit_response(&response, &message, 512, false);
+    let truncated = Message::parse(&fitted).unwrap();
+    assert!(truncated.is_truncated());
+    assert_eq!(truncated.ancount(), 0);
+    assert_eq!(fitted.len(), query.len());
+    let fitted = fit_response(&response, &message, 1232, false);
+    assert_eq!(fitted.len(), response.len() - 11);
+    assert_eq!(Message::parse(&fitted).unwrap().udp_payload_size(), None);
+
+    assert_eq!(query_of(&response, &message).unwrap(), query.to_vec());
+}
+
+#[test]
+fn hosts_reply() {
+    let query = [
+        0xab, 0xcd, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    let hosts = Hosts::parse("# Comment\n10.0.0.1 Example.com. www.example.com\n::1 localhost\n");
+    assert_eq!(hosts.len(), 2);
+    assert_eq!(hosts.get("example.com"), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    let response = hosts.reply(&query).unwrap();
+    let message = Message::parse(&response).unwrap();
+    assert!(message.is_response());
+    assert_eq!(message.id(), 0xabcd);
+    assert_eq!(message.ancount(), 1);
+    assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 1]);
+}
+
+#[cfg(feature = "dns")]
+#[test]
+fn dns_redirect_restore() {
+    let server = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+    let original = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 53);
+    let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 2), 50000);
+
+    let mut redirect = DnsRedirect::new(server);
+    assert_eq!(redirect.redirect(src, original), server);
+    assert_eq!(redirect.restore(server, src), original);
+    assert_eq!(redirect.restore(original, src), original);
+}
@@ -0,0 +1,252 @@
+//! Support for resolving DNS queries with encrypted DNS servers.
+
+use log::debug;
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{self, AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{self, TcpStream};
+use tokio::time;
+
+use crate::proxy::{self, ProxyStream, ProxyTransport};
+use crate::tls::{CertPin, TlsConfig};
+
+/// Represents the default port of DNS over TLS.
+pub const DNS_OVER_TLS_PORT: u16 = 853;
+/// Represents the default port of DNS over HTTPS.
+pub const DNS_OVER_HTTPS_PORT: u16 = 443;
+
+/// Represents the max size of the head of a response of DNS over HTTPS.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Represents an encrypted DNS server, which is either DNS over TLS (RFC 7858) or DNS over HTTPS
+/// (RFC 8484). The host is resolved in each connection, and verified in the certificate of the
+/// server.
+#[derive(Clone, Debug)]
+pub struct DnsUpstream {
+    host: String,
+    port: u16,
+    path: Option<String>,
+    is_direct: bool,
+    tls: TlsConfig,
+}
+
+impl DnsUpstream {
+    /// Creates a new `DnsUpstream` of DNS over TLS.
+    pub fn new_tls(host: &str, port: u16) -> io::Result<DnsUpstream> {
+        Ok(DnsUpstream {
+            host: String::from(host),
+            port,
+            path: None,
+            is_direct: false,
+            tls: TlsConfig::new(host)?,
+        })
+    }
+
+    /// Creates a new `DnsUpstream` of DNS over HTTPS, which posts queries to the path.
+    pub fn new_https(host: &str, port: u16, path: &str) -> io::Result<DnsUpstream> {
+        Ok(DnsUpstream {
+            host: String::from(host),
+            port,
+            path: Some(String::from(path)),
+            is_direct: false,
+            tls: TlsConfig::new(host)?,
+        })
+    }
+
+    /// Sets if the server should be connected directly instead of through the proxy.
+    pub fn set_direct(&mut self, is_direct: bool) {
+        self.is_direct = is_direct;
+    }
+
+    /// Sets the pins of the certificate of the server, which replace the verification by the root
+    /// certificates.
+    pub fn set_pins(&mut self, pins: Vec<CertPin>) {
+        self.tls.set_pins(pins);
+    }
+
+    /// Resolves a DNS query with the server, and returns the response.
+    pub async fn resolve(&self, proxy: &dyn ProxyTransport, query: &[u8]) -> io::Result<Vec<u8>> {
+        let fut = async {
+            let addr = self.lookup().await?;
+            let stream = match self.is_direct {
+                true => ProxyStream::Tcp(TcpStream::connect(addr).await?),
+                false => proxy.connect_tcp(addr).await?,
+            };
+            let mut stream = BufStream::new(self.tls.connect(stream).await?);
+
+            match self.path {
+                Some(ref path) => exchange_https(&mut stream, &self.authority(), path, query).await,
+                None => proxy::exchange_tcp(&mut stream, addr, query).await,
+            }
+        };
+
+        match time::timeout(Duration::from_millis(proxy::DNS_TIMEOUT), fut).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+
+    /// Resolves the host to an IPv4 address.
+    async fn lookup(&self) -> io::Result<SocketAddrV4> {
+        net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no IPv4 address of {}", self.host),
+                )
+            })
+    }
+
+    /// Returns the host with the port if it is not the default one of HTTPS.
+    fn authority(&self) -> String {
+        match self.port {
+            DNS_OVER_HTTPS_PORT => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+impl Display for DnsUpstream {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => write!(f, "https://{}:{}{}", self.host, self.port, path),
+            None => write!(f, "tls://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Exchanges a DNS query with the server over the stream in an HTTP/1.1 POST request.
+async fn exchange_https<S>(
+    stream: &mut S,
+    authority: &str,
+    path: &str,
+    query: &[u8],
+) -> io::Result<Vec<u8>>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    // Request
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+        path,
+        authority,
+        query.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+    debug!(
+        "send to DNS over HTTPS: {}{} ({} Bytes)",
+        authority,
+        path,
+        query.len()
+    );
+
+    // Response
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response head too large",
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let size = content_length(&String::from_utf8_lossy(&head))?;
+    let mut response = vec![0u8; size];
+    stream.read_exact(&mut response).await?;
+    debug!(
+        "receive from DNS over HTTPS: {}{} ({} Bytes)",
+        authority, path, size
+    );
+
+    Ok(response)
+}
+
+/// Returns the length of the DNS message in the body of the response of the head.
+fn content_length(head: &str) -> io::Result<usize> {
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("unexpected status {}", status),
+        ));
+    }
+
+    lines
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].trim(), line[i + 1..].trim()))
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .filter(|size| *size <= u16::MAX as usize)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid content length"))
+}
+
+#[test]
+fn dns_upstream_content_length() {
+    assert_eq!(
+        content_length("HTTP/1.1 200 OK\r\ncontent-length: 45\r\n\r\n").unwrap(),
+        45
+    );
+    assert!(content_length("HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").is_err());
+    assert!(content_length("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+    assert!(content_length("HTTP/1.1 200 OK\r\nContent-Length: 70000\r\n\r\n").is_err());
+
+    let upstream = DnsUpstream::new_https("dns.google", 443, "/dns-query").unwrap();
+    assert_eq!(upstream.authority(), "dns.google");
+    assert_eq!(upstream.to_string(), "https://dns.google:443/dns-query");
+    let upstream = DnsUpstream::new_tls("dns.google", DNS_OVER_TLS_PORT).unwrap();
+    assert_eq!(upstream.to_string(), "tls://dns.google:853");
+}
+
+#[tokio::test]
+async fn dns_upstream_exchange() {
+    let query = [0x12, 0x34, 0x01, 0x00];
+    let answer = [0x12, 0x34, 0x81, 0x80, 0x00];
+
+    // DNS over HTTPS
+    let (client, mut server) = io::duplex(1024);
+    tokio::spawn(async move {
+        let mut request = vec![0u8; 1024];
+        let n = server.read(&mut request).await.unwrap();
+        let request = String::from_utf8_lossy(&request[..n]).to_string();
+        assert!(request.starts_with("POST /dns-query HTTP/1.1\r\nHost: dns.google\r\n"));
+        assert!(request.contains("Content-Length: 4\r\n"));
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: 5\r\n\r\n")
+            .await
+            .unwrap();
+        server.write_all(&answer).await.unwrap();
+    });
+    let mut client = BufStream::new(client);
+    let response = exchange_https(&mut client, "dns.google", "/dns-query", &query)
+        .await
+        .unwrap();
+    assert_eq!(response, answer);
+
+    // DNS over TLS
+    let (client, mut server) = io::duplex(1024);
+    tokio::spawn(async move {
+        let size = server.read_u16().await.unwrap() as usize;
+        let mut request = vec![0u8; size];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, query);
+        server.write_u16(answer.len() as u16).await.unwrap();
+        server.write_all(&answer).await.unwrap();
+    });
+    let mut client = BufStream::new(client);
+    let response = proxy::exchange_tcp(&mut client, "8.8.8.8:853".parse().unwrap(), &query)
+        .await
+        .unwrap();
+    assert_eq!(response, answer);
+}
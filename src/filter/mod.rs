@@ -0,0 +1,870 @@
+//! Support for filtering and rewriting packets.
+
+use log::warn;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::packet::layer::{LayerKind, LayerKinds};
+use crate::packet::Indicator;
+
+mod script;
+pub use script::ScriptFilter;
+
+/// Represents the verdict of a packet filter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Verdict {
+    /// Accepts the packet.
+    Accept,
+    /// Drops the packet silently.
+    Drop,
+    /// Redirects the flow of the packet to another destination. Only TCP and UDP packets can be
+    /// redirected, and the source will still see the original destination.
+    Redirect(SocketAddrV4),
+}
+
+/// Represents a filter which inspects each parsed packet from sources before it is redirected.
+///
+/// A TCP flow is redirected if its SYN is redirected, and a UDP datagram is redirected on its
+/// own. Non-first IPv4 fragments carry no transport layer and are seen by the filter as is.
+pub trait PacketFilter: Send + Sync {
+    /// Returns the verdict of the packet.
+    fn filter(&self, indicator: &Indicator) -> Verdict;
+
+    /// Returns the verdict of a flow to the destination by the server name it is heading for,
+    /// like the SNI of a QUIC initial packet. The verdict overrides the one of the packet, and a
+    /// redirection applies to the rest of the flow.
+    fn filter_name(&self, _kind: LayerKind, _dst: SocketAddrV4, _name: &str) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Returns the verdict of an established flow from the source to the destination. It is used
+    /// to re-evaluate long-lived flows, and only a `Drop` has effect which closes the flow.
+    fn filter_flow(&self, _kind: LayerKind, _src: SocketAddrV4, _dst: SocketAddrV4) -> Verdict {
+        Verdict::Accept
+    }
+}
+
+/// Represents a list of filters applied in order. The first verdict other than `Accept` wins.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn PacketFilter>>,
+}
+
+impl FilterChain {
+    /// Creates a new empty `FilterChain`.
+    pub fn new() -> FilterChain {
+        FilterChain::default()
+    }
+
+    /// Appends a filter to the end of the chain.
+    pub fn push(&mut self, filter: Box<dyn PacketFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Returns the number of filters in the chain.
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Returns if the chain contains no filter.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl PacketFilter for FilterChain {
+    fn filter(&self, indicator: &Indicator) -> Verdict {
+        for filter in &self.filters {
+            let verdict = filter.filter(indicator);
+            if verdict != Verdict::Accept {
+                return verdict;
+            }
+        }
+
+        Verdict::Accept
+    }
+
+    fn filter_name(&self, kind: LayerKind, dst: SocketAddrV4, name: &str) -> Verdict {
+        for filter in &self.filters {
+            let verdict = filter.filter_name(kind, dst, name);
+            if verdict != Verdict::Accept {
+                return verdict;
+            }
+        }
+
+        Verdict::Accept
+    }
+
+    fn filter_flow(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) -> Verdict {
+        for filter in &self.filters {
+            let verdict = filter.filter_flow(kind, src, dst);
+            if verdict != Verdict::Accept {
+                return verdict;
+            }
+        }
+
+        Verdict::Accept
+    }
+}
+
+/// Represents the abbreviations of days of the week, starting from Monday.
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+/// Represents the number of minutes in a day.
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Represents a weekly schedule in the local time, like `18:00-23:00` or `mon-fri/00:00-06:00`.
+/// A schedule whose end is earlier than its start wraps midnight, and the part after midnight
+/// belongs to the day it starts.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Schedule {
+    days: u8,
+    start: u16,
+    end: u16,
+}
+
+impl Schedule {
+    /// Returns if the schedule is active at the day of the week, where 0 stands for Monday, and
+    /// the minute of the day.
+    pub fn is_active_at(&self, day: u8, minute: u16) -> bool {
+        let is_on = |day: u8| self.days & (1 << (day % 7)) != 0;
+        if self.start < self.end {
+            is_on(day) && minute >= self.start && minute < self.end
+        } else if self.start > self.end {
+            (is_on(day) && minute >= self.start) || (is_on(day + 6) && minute < self.end)
+        } else {
+            is_on(day)
+        }
+    }
+
+    /// Returns if the schedule is active now, with the offset of the local time to UTC in
+    /// minutes.
+    pub fn is_active(&self, offset: i32) -> bool {
+        let (day, minute) = local_time(offset);
+        self.is_active_at(day, minute)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.days != 0x7f {
+            let days = DAYS
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| self.days & (1 << i) != 0)
+                .map(|(_, day)| *day)
+                .collect::<Vec<_>>();
+            write!(f, "{}/", days.join(","))?;
+        }
+
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_day = |day: &str| {
+            DAYS.iter()
+                .position(|d| day.eq_ignore_ascii_case(d))
+                .ok_or(format!("invalid day {}", day))
+        };
+        let parse_time = |time: &str| {
+            let i = time.find(':').ok_or(format!("invalid time {}", time))?;
+            let hour = time[..i]
+                .parse::<u16>()
+                .map_err(|e| format!("invalid time {}: {}", time, e))?;
+            let minute = time[i + 1..]
+                .parse::<u16>()
+                .map_err(|e| format!("invalid time {}: {}", time, e))?;
+            // Check the hour before multiplying, which would overflow
+            if hour > 24 || minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+                return Err(format!("time {} out of range", time));
+            }
+
+            Ok(hour * 60 + minute)
+        };
+
+        let (days, times) = match s.find('/') {
+            Some(i) => {
+                let mut days = 0u8;
+                for range in s[..i].split(',') {
+                    match range.find('-') {
+                        Some(j) => {
+                            // Ranges like `sat-mon` wrap the week
+                            let first = parse_day(&range[..j])?;
+                            let last = parse_day(&range[j + 1..])?;
+                            let mut day = first;
+                            loop {
+                                days |= 1 << day;
+                                if day == last {
+                                    break;
+                                }
+                                day = (day + 1) % 7;
+                            }
+                        }
+                        None => days |= 1 << parse_day(range)?,
+                    }
+                }
+
+                (days, &s[i + 1..])
+            }
+            None => (0x7f, s),
+        };
+
+        let i = times.find('-').ok_or(format!("invalid schedule {}", s))?;
+        let start = parse_time(&times[..i])?;
+        let end = parse_time(&times[i + 1..])?;
+
+        Ok(Schedule { days, start, end })
+    }
+}
+
+/// Returns the day of the week, where 0 stands for Monday, and the minute of the day of now in
+/// the local time, with the offset of the local time to UTC in minutes.
+fn local_time(offset: i32) -> (u8, u16) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+    let minutes = secs / 60 + offset as i64;
+    let days = minutes.div_euclid(MINUTES_PER_DAY as i64);
+
+    // 1970-01-01 is a Thursday
+    (
+        (days + 3).rem_euclid(7) as u8,
+        minutes.rem_euclid(MINUTES_PER_DAY as i64) as u16,
+    )
+}
+
+/// Represents the conditions of an entry on the source and the time.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Condition {
+    src: Option<Ipv4Addr>,
+    schedule: Option<Schedule>,
+}
+
+impl Condition {
+    fn is_met(&self, src: Option<Ipv4Addr>, offset: i32) -> bool {
+        self.src.map_or(true, |ip_addr| Some(ip_addr) == src)
+            && self
+                .schedule
+                .map_or(true, |schedule| schedule.is_active(offset))
+    }
+}
+
+/// Parses and removes the trailing conditions like `from 192.168.1.10` and
+/// `during 18:00-23:00` of a line.
+fn parse_condition(v: &mut Vec<&str>) -> Option<Condition> {
+    let mut condition = Condition::default();
+    while v.len() >= 2 {
+        let n = v.len();
+        match v[n - 2].to_ascii_lowercase().as_str() {
+            "from" => condition.src = Some(v[n - 1].parse().ok()?),
+            "during" => condition.schedule = Some(v[n - 1].parse().ok()?),
+            _ => break,
+        }
+        v.truncate(n - 2);
+    }
+
+    Some(condition)
+}
+
+/// Represents a filter which drops packets to the given destination IP addresses and ports, and
+/// flows heading for the given server names.
+pub struct BlockList {
+    dsts: Vec<(Option<LayerKind>, Ipv4Addr, Option<u16>, Condition)>,
+    names: Vec<(Option<LayerKind>, String, Condition)>,
+    offset: i32,
+}
+
+impl BlockList {
+    /// Parses a block list. Each line contains an IP address and optionally a port like
+    /// `10.0.0.1` or `10.0.0.1:443`, `*` for any destination, or a server name like
+    /// `telemetry.example.com` which also matches its subdomains. A line can be prefixed by a
+    /// protocol of `tcp`, `udp` or `icmp` like `tcp 10.0.0.1`, suffixed by a source like
+    /// `from 192.168.1.10` except for server names, and a schedule like `during 18:00-23:00`,
+    /// and `#` starts a comment.
+    pub fn parse(s: &str) -> BlockList {
+        BlockList::parse_list(s, "block list")
+    }
+
+    /// Parses a list of the same syntax as the block list, where the name of the list is used in
+    /// warnings.
+    pub(crate) fn parse_list(s: &str, list: &str) -> BlockList {
+        let mut dsts = Vec::new();
+        let mut names = Vec::new();
+        for line in s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+        {
+            match parse_block(line) {
+                Some(Block::Dst(t, ip_addr, port, condition)) => {
+                    dsts.push((t, ip_addr, port, condition))
+                }
+                Some(Block::Name(t, name, condition)) => names.push((t, name, condition)),
+                None => warn!("Ignore invalid {} line: {}", list, line),
+            }
+        }
+
+        BlockList {
+            dsts,
+            names,
+            offset: 0,
+        }
+    }
+
+    /// Checks a block list, and returns the invalid lines located by their line numbers.
+    pub fn check(s: &str) -> Vec<String> {
+        check_lines(s, |line| parse_block(line).is_some())
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.offset = offset;
+    }
+
+    /// Returns the number of entries in the block list.
+    pub fn len(&self) -> usize {
+        self.dsts.len() + self.names.len()
+    }
+
+    /// Returns if the block list contains no entry.
+    pub fn is_empty(&self) -> bool {
+        self.dsts.is_empty() && self.names.is_empty()
+    }
+
+    /// Returns if the block list contains server names.
+    pub(crate) fn has_names(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    pub(crate) fn is_blocked(
+        &self,
+        t: Option<LayerKind>,
+        src: Option<Ipv4Addr>,
+        dst: SocketAddrV4,
+    ) -> bool {
+        self.dsts.iter().any(|&(kind, ip_addr, port, condition)| {
+            kind.map_or(true, |kind| Some(kind) == t)
+                && (ip_addr.is_unspecified() || ip_addr == *dst.ip())
+                && port.map_or(true, |p| p == dst.port())
+                && condition.is_met(src, self.offset)
+        })
+    }
+
+    pub(crate) fn is_name_blocked(&self, t: LayerKind, name: &str) -> bool {
+        self.names.iter().any(|(kind, blocked, condition)| {
+            kind.map_or(true, |kind| kind == t)
+                && (name == blocked.as_str()
+                    || (name.ends_with(blocked.as_str())
+                        && name[..name.len() - blocked.len()].ends_with('.')))
+                && condition.is_met(None, self.offset)
+        })
+    }
+}
+
+/// Represents an entry of a block list.
+enum Block {
+    Dst(Option<LayerKind>, Ipv4Addr, Option<u16>, Condition),
+    Name(Option<LayerKind>, String, Condition),
+}
+
+/// Parses an entry of a block list.
+fn parse_block(line: &str) -> Option<Block> {
+    let mut v = line.split_whitespace().collect::<Vec<_>>();
+    let condition = parse_condition(&mut v)?;
+    let (t, dst) = match v.len() {
+        1 => (None, v[0]),
+        2 => match v[0].to_ascii_lowercase().as_str() {
+            "tcp" => (Some(LayerKinds::Tcp), v[1]),
+            "udp" => (Some(LayerKinds::Udp), v[1]),
+            "icmp" => (Some(LayerKinds::Icmpv4), v[1]),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    if dst == "*" {
+        Some(Block::Dst(t, Ipv4Addr::UNSPECIFIED, None, condition))
+    } else if let Ok(addr) = dst.parse::<SocketAddrV4>() {
+        Some(Block::Dst(t, *addr.ip(), Some(addr.port()), condition))
+    } else if let Ok(ip_addr) = dst.parse() {
+        Some(Block::Dst(t, ip_addr, None, condition))
+    } else if is_name(dst) && condition.src.is_none() {
+        Some(Block::Name(
+            t,
+            dst.trim_end_matches('.').to_ascii_lowercase(),
+            condition,
+        ))
+    } else {
+        None
+    }
+}
+
+/// Checks the lines of a list by the given parser of a line, and returns the invalid lines
+/// located by their line numbers. Comments and empty lines are skipped.
+fn check_lines<F: Fn(&str) -> bool>(s: &str, is_valid: F) -> Vec<String> {
+    s.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty() && !is_valid(line))
+        .map(|(i, line)| format!("line {}: {}", i + 1, line))
+        .collect()
+}
+
+/// Returns if the string is a server name of at least 2 labels.
+fn is_name(s: &str) -> bool {
+    let s = s.trim_end_matches('.');
+    s.contains('.')
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+impl PacketFilter for BlockList {
+    fn filter(&self, indicator: &Indicator) -> Verdict {
+        let ipv4 = match indicator.ipv4() {
+            Some(ipv4) => ipv4,
+            None => return Verdict::Accept,
+        };
+        let port = match (indicator.tcp(), indicator.udp()) {
+            (Some(tcp), _) => tcp.dst(),
+            (_, Some(udp)) => udp.dst(),
+            _ => 0,
+        };
+
+        if self.is_blocked(
+            ipv4.next_level_layer_kind(),
+            Some(ipv4.src()),
+            SocketAddrV4::new(ipv4.dst(), port),
+        ) {
+            Verdict::Drop
+        } else {
+            Verdict::Accept
+        }
+    }
+
+    fn filter_name(&self, kind: LayerKind, _dst: SocketAddrV4, name: &str) -> Verdict {
+        if self.is_name_blocked(kind, name) {
+            Verdict::Drop
+        } else {
+            Verdict::Accept
+        }
+    }
+
+    fn filter_flow(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) -> Verdict {
+        if self.is_blocked(Some(kind), Some(*src.ip()), dst) {
+            Verdict::Drop
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+/// Represents a filter which redirects TCP and UDP flows to the given destination ports to other
+/// ports, and optionally to other IP addresses.
+pub struct RewriteList {
+    rules: Vec<(LayerKind, u16, SocketAddrV4, Condition)>,
+    offset: i32,
+}
+
+impl RewriteList {
+    /// Parses a rewrite list. Each line contains a rule like `rewrite tcp dst 80 -> 8080` or
+    /// `rewrite udp 53 -> 5353@10.0.0.2`, where the address of the target is the original
+    /// destination if omitted. A rule can be suffixed by a source like `from 192.168.1.10` and
+    /// a schedule like `during 18:00-23:00`, and `#` starts a comment.
+    pub fn parse(s: &str) -> RewriteList {
+        let rules = s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match parse_rewrite(line) {
+                Some(rule) => Some(rule),
+                None => {
+                    warn!("Ignore invalid rewrite list line: {}", line);
+                    None
+                }
+            })
+            .collect();
+
+        RewriteList { rules, offset: 0 }
+    }
+
+    /// Checks a rewrite list, and returns the invalid lines located by their line numbers.
+    pub fn check(s: &str) -> Vec<String> {
+        check_lines(s, |line| parse_rewrite(line).is_some())
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.offset = offset;
+    }
+
+    /// Returns the number of rules in the rewrite list.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns if the rewrite list contains no rule.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn rewrite(
+        &self,
+        t: LayerKind,
+        src: Option<Ipv4Addr>,
+        dst: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
+        self.rules
+            .iter()
+            .find(|&&(kind, port, _, condition)| {
+                kind == t && port == dst.port() && condition.is_met(src, self.offset)
+            })
+            .map(|&(_, _, target, _)| match target.ip().is_unspecified() {
+                true => SocketAddrV4::new(*dst.ip(), target.port()),
+                false => target,
+            })
+    }
+}
+
+/// Parses a rewrite rule. The unspecified address in the target stands for the original
+/// destination.
+fn parse_rewrite(line: &str) -> Option<(LayerKind, u16, SocketAddrV4, Condition)> {
+    let mut v = line.split_whitespace().collect::<Vec<_>>();
+    if v.first() != Some(&"rewrite") {
+        return None;
+    }
+    v.remove(0);
+    let condition = parse_condition(&mut v)?;
+    if v.get(1) == Some(&"dst") {
+        v.remove(1);
+    }
+    if v.len() != 4 || v[2] != "->" {
+        return None;
+    }
+
+    let t = match v[0].to_ascii_lowercase().as_str() {
+        "tcp" => LayerKinds::Tcp,
+        "udp" => LayerKinds::Udp,
+        _ => return None,
+    };
+    let port = v[1].parse().ok()?;
+    let target = match v[3].find('@') {
+        Some(i) => SocketAddrV4::new(v[3][i + 1..].parse().ok()?, v[3][..i].parse().ok()?),
+        None => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, v[3].parse().ok()?),
+    };
+
+    Some((t, port, target, condition))
+}
+
+impl PacketFilter for RewriteList {
+    fn filter(&self, indicator: &Indicator) -> Verdict {
+        let ipv4 = match indicator.ipv4() {
+            Some(ipv4) => ipv4,
+            None => return Verdict::Accept,
+        };
+        let (t, port) = match (indicator.tcp(), indicator.udp()) {
+            (Some(tcp), _) => (LayerKinds::Tcp, tcp.dst()),
+            (_, Some(udp)) => (LayerKinds::Udp, udp.dst()),
+            _ => return Verdict::Accept,
+        };
+
+        match self.rewrite(t, Some(ipv4.src()), SocketAddrV4::new(ipv4.dst(), port)) {
+            Some(target) => Verdict::Redirect(target),
+            None => Verdict::Accept,
+        }
+    }
+}
+
+/// Represents the duration in milliseconds of traffic at the rate a source may send in a burst.
+const RATE_LIMIT_BURST: u64 = 200;
+
+/// Represents the minimum size of a burst, which admits at least 2 full-sized frames.
+const RATE_LIMIT_MIN_BURST: u64 = 2 * 1514;
+
+/// Represents a token bucket of a source.
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: u64,
+    instant: Instant,
+}
+
+/// Represents a rate limiter which limits the traffic of each source to the rate of the first
+/// matched rule with token buckets. Packets beyond the rate are dropped, and TCP will slow down
+/// on its own.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rules: Vec<(u64, Condition)>,
+    buckets: HashMap<Ipv4Addr, TokenBucket>,
+    offset: i32,
+}
+
+impl RateLimiter {
+    /// Parses a rate limit list. Each line contains a rule like `limit 2mbps` with a rate in bits
+    /// per second suffixed by `bps`, `kbps`, `mbps` or `gbps`. A rule can be suffixed by a source
+    /// like `from 192.168.1.10` and a schedule like `during 18:00-23:00`, and `#` starts a
+    /// comment. Each source has its own bucket even if a rule matches many sources.
+    pub fn parse(s: &str) -> RateLimiter {
+        let rules = s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match parse_limit(line) {
+                Some(rule) => Some(rule),
+                None => {
+                    warn!("Ignore invalid rate limit list line: {}", line);
+                    None
+                }
+            })
+            .collect();
+
+        RateLimiter {
+            rules,
+            buckets: HashMap::new(),
+            offset: 0,
+        }
+    }
+
+    /// Checks a rate limit list, and returns the invalid lines located by their line numbers.
+    pub fn check(s: &str) -> Vec<String> {
+        check_lines(s, |line| parse_limit(line).is_some())
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.offset = offset;
+    }
+
+    /// Returns the number of rules in the rate limit list.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns if the rate limit list contains no rule.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the rate in bytes per second of the source now, or `None` if it is not limited.
+    pub fn rate(&self, src: Ipv4Addr) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|(_, condition)| condition.is_met(Some(src), self.offset))
+            .map(|&(rate, _)| rate)
+    }
+
+    /// Admits a packet of the given size of the source at the given instant, and returns if it is
+    /// within the rate.
+    pub fn admit(&mut self, src: Ipv4Addr, size: usize, instant: Instant) -> bool {
+        let rate = match self.rate(src) {
+            Some(rate) => rate,
+            None => return true,
+        };
+        let burst = (rate * RATE_LIMIT_BURST / 1000).max(RATE_LIMIT_MIN_BURST);
+
+        let bucket = self.buckets.entry(src).or_insert(TokenBucket {
+            tokens: burst,
+            instant,
+        });
+        // Refill, where the instant is kept until at least a token is refilled so slow refills are
+        // not lost
+        let elapsed = instant.saturating_duration_since(bucket.instant);
+        let refill = (rate as u128 * elapsed.as_micros() / 1_000_000) as u64;
+        if refill > 0 {
+            bucket.tokens = bucket.tokens.saturating_add(refill);
+            bucket.instant = instant;
+        }
+        bucket.tokens = min(bucket.tokens, burst);
+
+        if bucket.tokens >= size as u64 {
+            bucket.tokens -= size as u64;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Parses a rate limit rule, where the rate is in bytes per second.
+fn parse_limit(line: &str) -> Option<(u64, Condition)> {
+    let mut v = line.split_whitespace().collect::<Vec<_>>();
+    if v.first() != Some(&"limit") {
+        return None;
+    }
+    v.remove(0);
+    let condition = parse_condition(&mut v)?;
+    if v.len() != 1 {
+        return None;
+    }
+
+    let rate = parse_rate(v[0])?;
+
+    Some((rate, condition))
+}
+
+/// Parses a rate in bits per second like `2mbps` or `512kbps` into bytes per second.
+fn parse_rate(s: &str) -> Option<u64> {
+    let s = s.to_ascii_lowercase();
+    let (n, unit) = if let Some(n) = s.strip_suffix("gbps") {
+        (n, 1_000_000_000.0)
+    } else if let Some(n) = s.strip_suffix("mbps") {
+        (n, 1_000_000.0)
+    } else if let Some(n) = s.strip_suffix("kbps") {
+        (n, 1_000.0)
+    } else if let Some(n) = s.strip_suffix("bps") {
+        (n, 1.0)
+    } else {
+        return None;
+    };
+    let n = n.parse::<f64>().ok()?;
+    if !n.is_finite() || n <= 0.0 {
+        return None;
+    }
+
+    match (n * unit / 8.0) as u64 {
+        0 => None,
+        rate => Some(rate),
+    }
+}
+
+#[test]
+fn block_list_is_blocked() {
+    let block_list = BlockList::parse(
+        "# Telemetry\n10.0.0.1\n10.0.0.2:443 # HTTPS\ninvalid\ntcp 10.0.0.3\nsctp 10.0.0.4\n",
+    );
+    let tcp = Some(LayerKinds::Tcp);
+    let udp = Some(LayerKinds::Udp);
+    assert_eq!(block_list.len(), 3);
+    assert!(block_list.is_blocked(tcp, None, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80)));
+    assert!(block_list.is_blocked(
+        tcp,
+        None,
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 443)
+    ));
+    assert!(!block_list.is_blocked(tcp, None, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80)));
+    assert!(block_list.is_blocked(tcp, None, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 80)));
+    assert!(!block_list.is_blocked(udp, None, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 53)));
+
+    let block_list = BlockList::parse("example.com\ntcp telemetry.example.net\n");
+    assert_eq!(block_list.len(), 2);
+    assert!(block_list.is_name_blocked(LayerKinds::Udp, "example.com"));
+    assert!(block_list.is_name_blocked(LayerKinds::Udp, "cdn.example.com"));
+    assert!(!block_list.is_name_blocked(LayerKinds::Udp, "badexample.com"));
+    assert!(!block_list.is_name_blocked(LayerKinds::Udp, "telemetry.example.net"));
+
+    let block_list = BlockList::parse("* from 192.168.1.10\nexample.com from 192.168.1.10\n");
+    let src = Some(Ipv4Addr::new(192, 168, 1, 10));
+    assert_eq!(block_list.len(), 1);
+    assert!(block_list.is_blocked(tcp, src, SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80)));
+    assert!(!block_list.is_blocked(tcp, None, SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80)));
+}
+
+#[test]
+fn list_check() {
+    assert_eq!(
+        BlockList::check("# Telemetry\n10.0.0.1\n\ntcp 10.0.0.2:443 during 25:00-26:00\nexample.com from 192.168.1.10\n"),
+        vec![
+            String::from("line 4: tcp 10.0.0.2:443 during 25:00-26:00"),
+            String::from("line 5: example.com from 192.168.1.10")
+        ]
+    );
+    assert_eq!(
+        RewriteList::check("rewrite tcp 80 -> 8080\nrewrite icmp 1 -> 2 # ICMP\n"),
+        vec![String::from("line 2: rewrite icmp 1 -> 2")]
+    );
+    assert_eq!(
+        RateLimiter::check("limit 2mbps\nlimit 2 mbps\n"),
+        vec![String::from("line 2: limit 2 mbps")]
+    );
+}
+
+#[test]
+fn schedule_is_active_at() {
+    let schedule = "18:00-23:00".parse::<Schedule>().unwrap();
+    assert!(schedule.is_active_at(0, 18 * 60));
+    assert!(!schedule.is_active_at(6, 23 * 60));
+    assert_eq!(schedule.to_string(), "18:00-23:00");
+
+    // Wraps midnight
+    let schedule = "fri-sun/23:00-06:00".parse::<Schedule>().unwrap();
+    assert!(schedule.is_active_at(4, 23 * 60 + 30));
+    assert!(schedule.is_active_at(0, 5 * 60));
+    assert!(!schedule.is_active_at(4, 5 * 60));
+    assert_eq!(schedule.to_string(), "fri,sat,sun/23:00-06:00");
+
+    assert!("mon/18:00".parse::<Schedule>().is_err());
+    assert!("18:00-24:30".parse::<Schedule>().is_err());
+    assert!("9999:00-23:00".parse::<Schedule>().is_err());
+    assert!("18:00-23:99".parse::<Schedule>().is_err());
+    assert_eq!(
+        "00:00-24:00".parse::<Schedule>().unwrap().to_string(),
+        "00:00-24:00"
+    );
+    assert!("someday/18:00-23:00".parse::<Schedule>().is_err());
+}
+
+#[test]
+fn rewrite_list_rewrite() {
+    let rewrite_list = RewriteList::parse(
+        "rewrite tcp dst 80 -> 8080\nrewrite udp 53 -> 5353@10.0.0.2 # DNS\nrewrite icmp 1 -> 2\n",
+    );
+    let dst = Ipv4Addr::new(1, 1, 1, 1);
+    assert_eq!(rewrite_list.len(), 2);
+    assert_eq!(
+        rewrite_list.rewrite(LayerKinds::Tcp, None, SocketAddrV4::new(dst, 80)),
+        Some(SocketAddrV4::new(dst, 8080))
+    );
+    assert_eq!(
+        rewrite_list.rewrite(LayerKinds::Udp, None, SocketAddrV4::new(dst, 53)),
+        Some("10.0.0.2:5353".parse().unwrap())
+    );
+    assert_eq!(
+        rewrite_list.rewrite(LayerKinds::Udp, None, SocketAddrV4::new(dst, 80)),
+        None
+    );
+}
+
+#[test]
+fn rate_limiter_admit() {
+    use std::time::Duration;
+
+    let mut rate_limiter = RateLimiter::parse(
+        "limit 1mbps from 192.168.1.10\nlimit 80kbps during 00:00-24:00 # All\nlimit 1 mbps\n",
+    );
+    assert_eq!(rate_limiter.len(), 2);
+    let src = Ipv4Addr::new(192, 168, 1, 10);
+    let other = Ipv4Addr::new(192, 168, 1, 20);
+    assert_eq!(rate_limiter.rate(src), Some(125_000));
+    assert_eq!(rate_limiter.rate(other), Some(10_000));
+
+    // A burst of at least 2 full-sized frames
+    let instant = Instant::now();
+    assert!(rate_limiter.admit(other, 1514, instant));
+    assert!(rate_limiter.admit(other, 1514, instant));
+    assert!(!rate_limiter.admit(other, 1514, instant));
+    // Sources are limited independently
+    assert!(rate_limiter.admit(src, 1514, instant));
+    // 10000 bytes per second refills 1514 bytes in about 152 ms
+    assert!(!rate_limiter.admit(other, 1514, instant + Duration::from_millis(100)));
+    assert!(rate_limiter.admit(other, 1514, instant + Duration::from_millis(160)));
+
+    assert!(RateLimiter::parse("limit 0kbps\nlimit fast\n").is_empty());
+}
@@ -0,0 +1,314 @@
+//! Support for filtering packets with an external script.
+
+use log::warn;
+use lru::LruCache;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddrV4;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{PacketFilter, Verdict};
+use crate::packet::layer::{LayerKind, LayerKinds};
+use crate::packet::Indicator;
+
+/// Represents the max time in milliseconds a script can take to answer a query. Packets of
+/// unanswered queries are accepted.
+const SCRIPT_TIMEOUT: u64 = 100;
+
+/// Represents the number of verdicts cached by a script filter.
+const SCRIPT_CACHE_SIZE: usize = 4096;
+
+/// Represents a query to a script, which is a flow from the source to the destination, or a flow
+/// to the destination heading for the server name.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ScriptQuery {
+    kind: LayerKind,
+    src: Option<SocketAddrV4>,
+    dst: SocketAddrV4,
+    name: Option<String>,
+}
+
+impl ScriptQuery {
+    /// Returns the query in a JSON line with the given ID.
+    fn to_json(&self, id: u64) -> String {
+        let mut s = format!(
+            "{{\"id\":{},\"kind\":\"{}\"",
+            id,
+            self.kind.to_string().to_lowercase()
+        );
+        if let Some(src) = self.src {
+            s.push_str(&format!(",\"src\":\"{}\"", src));
+        }
+        s.push_str(&format!(",\"dst\":\"{}\"", self.dst));
+        if let Some(ref name) = self.name {
+            s.push_str(&format!(
+                ",\"name\":\"{}\"",
+                name.replace('\\', "\\\\").replace('"', "\\\"")
+            ));
+        }
+        s.push('}');
+
+        s
+    }
+}
+
+/// Parses a reply of a script like `1 accept`, `2 drop` or `3 redirect 10.0.0.1:8080`.
+fn parse_reply(s: &str) -> Option<(u64, Verdict)> {
+    let mut v = s.split_whitespace();
+    let id = v.next()?.parse().ok()?;
+    let verdict = match (v.next()?, v.next()) {
+        ("accept", None) => Verdict::Accept,
+        ("drop", None) => Verdict::Drop,
+        ("redirect", Some(target)) => Verdict::Redirect(target.parse().ok()?),
+        _ => return None,
+    };
+    if v.next().is_some() {
+        return None;
+    }
+
+    Some((id, verdict))
+}
+
+struct ScriptState {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    rx: mpsc::Receiver<String>,
+    id: u64,
+    cache: LruCache<ScriptQuery, Verdict>,
+}
+
+/// Represents a filter which asks an external script for the verdicts of flows.
+///
+/// The script is started once in the shell, and runs in its own process, so it can be written in
+/// any language and sandboxed by the OS, like running as an unprivileged user. Each query is a
+/// JSON line written to its standard input, like
+/// `{"id":1,"kind":"tcp","src":"10.6.0.1:50000","dst":"1.1.1.1:443"}`, where queries by server
+/// names carry a `name` but no `src`, and the script answers with a line to its standard output
+/// like `1 accept`, `1 drop` or `1 redirect 10.0.0.1:443`. Verdicts are cached per flow, so a
+/// flow is only asked once.
+pub struct ScriptFilter {
+    command: String,
+    state: Mutex<ScriptState>,
+}
+
+impl ScriptFilter {
+    /// Starts the script of the command in the shell.
+    pub fn spawn(command: &str) -> io::Result<ScriptFilter> {
+        let mut shell = match cfg!(windows) {
+            true => {
+                let mut shell = Command::new("cmd");
+                shell.arg("/C");
+                shell
+            }
+            false => {
+                let mut shell = Command::new("sh");
+                shell.arg("-c");
+                shell
+            }
+        };
+        let mut child = shell
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(ScriptFilter {
+            command: String::from(command),
+            state: Mutex::new(ScriptState {
+                child,
+                stdin,
+                rx,
+                id: 0,
+                cache: LruCache::new(SCRIPT_CACHE_SIZE),
+            }),
+        })
+    }
+
+    /// Returns the verdict of the query from the cache or the script.
+    fn query(&self, query: ScriptQuery) -> Verdict {
+        let mut state = self.state.lock().unwrap();
+        if let Some(verdict) = state.cache.get(&query) {
+            return *verdict;
+        }
+
+        // Ask
+        state.id += 1;
+        let id = state.id;
+        let is_written = match state.stdin {
+            Some(ref mut stdin) => {
+                match writeln!(stdin, "{}", query.to_json(id)).and_then(|_| stdin.flush()) {
+                    Ok(_) => true,
+                    Err(ref e) => {
+                        warn!(
+                            "Cannot write to the filter script {}: {}, accept all packets",
+                            self.command, e
+                        );
+                        false
+                    }
+                }
+            }
+            None => return Verdict::Accept,
+        };
+        if !is_written {
+            state.stdin = None;
+            return Verdict::Accept;
+        }
+
+        // Wait for the answer, skipping the late ones of queries timed out
+        let deadline = Instant::now() + Duration::from_millis(SCRIPT_TIMEOUT);
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match state.rx.recv_timeout(timeout) {
+                Ok(line) => match parse_reply(&line) {
+                    Some((reply_id, verdict)) => {
+                        if reply_id == id {
+                            state.cache.put(query, verdict);
+
+                            return verdict;
+                        }
+                    }
+                    None => warn!(
+                        "Ignore invalid reply of the filter script {}: {}",
+                        self.command, line
+                    ),
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "The filter script {} does not answer in {} ms, accept the packet",
+                        self.command, SCRIPT_TIMEOUT
+                    );
+
+                    return Verdict::Accept;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!(
+                        "The filter script {} exited, accept all packets",
+                        self.command
+                    );
+                    state.stdin = None;
+
+                    return Verdict::Accept;
+                }
+            }
+        }
+    }
+}
+
+impl PacketFilter for ScriptFilter {
+    fn filter(&self, indicator: &Indicator) -> Verdict {
+        let ipv4 = match indicator.ipv4() {
+            Some(ipv4) => ipv4,
+            None => return Verdict::Accept,
+        };
+        let (kind, src_port, dst_port) = match (indicator.tcp(), indicator.udp()) {
+            (Some(tcp), _) => (LayerKinds::Tcp, tcp.src(), tcp.dst()),
+            (_, Some(udp)) => (LayerKinds::Udp, udp.src(), udp.dst()),
+            _ => return Verdict::Accept,
+        };
+
+        self.query(ScriptQuery {
+            kind,
+            src: Some(SocketAddrV4::new(ipv4.src(), src_port)),
+            dst: SocketAddrV4::new(ipv4.dst(), dst_port),
+            name: None,
+        })
+    }
+
+    fn filter_name(&self, kind: LayerKind, dst: SocketAddrV4, name: &str) -> Verdict {
+        self.query(ScriptQuery {
+            kind,
+            src: None,
+            dst,
+            name: Some(String::from(name)),
+        })
+    }
+}
+
+impl Drop for ScriptFilter {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        state.stdin = None;
+        let _ = state.child.kill();
+        let _ = state.child.wait();
+    }
+}
+
+#[test]
+fn script_reply_parse() {
+    assert_eq!(parse_reply("1 accept"), Some((1, Verdict::Accept)));
+    assert_eq!(parse_reply("2 drop"), Some((2, Verdict::Drop)));
+    assert_eq!(
+        parse_reply("3 redirect 10.0.0.1:8080"),
+        Some((3, Verdict::Redirect("10.0.0.1:8080".parse().unwrap())))
+    );
+    assert_eq!(parse_reply("drop"), None);
+    assert_eq!(parse_reply("4 redirect"), None);
+    assert_eq!(parse_reply("5 drop now"), None);
+
+    let query = ScriptQuery {
+        kind: LayerKinds::Udp,
+        src: None,
+        dst: "1.1.1.1:443".parse().unwrap(),
+        name: Some(String::from("a\"b")),
+    };
+    assert_eq!(
+        query.to_json(6),
+        "{\"id\":6,\"kind\":\"udp\",\"dst\":\"1.1.1.1:443\",\"name\":\"a\\\"b\"}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn script_filter_query() {
+    let filter = ScriptFilter::spawn(
+        "while read -r line; do \
+             id=${line#*\\\"id\\\":}; id=${id%%,*}; \
+             case \"$line\" in \
+                 *'\"dst\":\"10.0.0.1:443\"'*) echo \"$id drop\" ;; \
+                 *'\"name\":\"example.com\"'*) echo \"$id redirect 10.0.0.3:443\" ;; \
+                 *) echo \"$id accept\" ;; \
+             esac; \
+         done",
+    )
+    .unwrap();
+    let query = |dst: &str, name: Option<&str>| ScriptQuery {
+        kind: LayerKinds::Tcp,
+        src: Some("10.6.0.1:50000".parse().unwrap()),
+        dst: dst.parse().unwrap(),
+        name: name.map(String::from),
+    };
+
+    assert_eq!(filter.query(query("10.0.0.1:443", None)), Verdict::Drop);
+    assert_eq!(filter.query(query("10.0.0.2:443", None)), Verdict::Accept);
+    assert_eq!(
+        filter.filter_name(
+            LayerKinds::Udp,
+            "10.0.0.2:443".parse().unwrap(),
+            "example.com"
+        ),
+        Verdict::Redirect("10.0.0.3:443".parse().unwrap())
+    );
+    // Cached
+    assert_eq!(filter.state.lock().unwrap().cache.len(), 3);
+    assert_eq!(filter.query(query("10.0.0.1:443", None)), Verdict::Drop);
+    assert_eq!(filter.state.lock().unwrap().id, 3);
+}
@@ -0,0 +1,332 @@
+//! Support for handing over UDP associations and TCP connections to a new process over a Unix
+//! domain socket.
+
+use log::trace;
+#[cfg(target_os = "linux")]
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+#[cfg(target_os = "linux")]
+use nix::sys::uio::IoVec;
+#[cfg(target_os = "linux")]
+use nix::unistd;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the interval in milliseconds of accepting a new process on the handover socket.
+pub const ACCEPT_INTERVAL: u64 = 1000;
+
+/// Represents the timeout in milliseconds of sending or receiving in the handover.
+const TIMEOUT: u64 = 5000;
+
+/// Represents the size of the length of a record.
+const LENGTH_SIZE: usize = 2;
+
+/// Represents a UDP association handed over, with the file descriptors of the TCP connection the
+/// association was requested on and its UDP socket connected to the proxy. Received descriptors
+/// are owned by the receiver.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Association {
+    pub src: SocketAddrV4,
+    pub hardware_addr: HardwareAddr,
+    /// Represents if the source has sent STUN binding requests.
+    pub is_stun: bool,
+    /// Represents if the source is an encrypted tunnel.
+    pub is_tunnel: bool,
+    pub control: RawFd,
+    pub socket: RawFd,
+}
+
+/// Represents the state handed over to a new process. TCP connections are handed over with the
+/// sequence number and the hardware address of their sources only, so they can be reset.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Handover {
+    pub associations: Vec<Association>,
+    pub connections: Vec<(SocketAddrV4, SocketAddrV4, u32, HardwareAddr)>,
+}
+
+/// Listens on the handover socket, which replaces the socket file left by a previous process.
+pub fn listen(path: &str) -> io::Result<UnixListener> {
+    match fs::remove_file(path) {
+        Ok(_) => {}
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    trace!("listen handover on {}", path);
+
+    Ok(listener)
+}
+
+/// Takes over the state from the process listening on the handover socket, which stops
+/// redirecting once it is connected. Returns `None` if no process is listening.
+pub fn take_over(path: &str) -> io::Result<Option<Handover>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            return match e.kind() {
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => Ok(None),
+                _ => Err(e),
+            }
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_millis(TIMEOUT)))?;
+
+    receive(&stream).map(Some)
+}
+
+/// Sends the state over the stream. The descriptors are duplicated into the receiver, so the
+/// sockets stay open after the sender closes its ones.
+pub fn send(stream: &UnixStream, handover: &Handover) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(Duration::from_millis(TIMEOUT)))?;
+
+    for association in handover.associations.iter() {
+        let record = format!(
+            "udp {} {} {} {}",
+            association.src, association.hardware_addr, association.is_stun, association.is_tunnel
+        );
+        send_record(
+            stream,
+            record.as_bytes(),
+            &[association.control, association.socket],
+        )?;
+    }
+    for (src, dst, sequence, hardware_addr) in handover.connections.iter() {
+        let record = format!("tcp {} {} {} {}", src, dst, sequence, hardware_addr);
+        send_record(stream, record.as_bytes(), &[])?;
+    }
+
+    Ok(())
+}
+
+/// Receives the state over the stream until the sender closes it.
+pub fn receive(stream: &UnixStream) -> io::Result<Handover> {
+    let mut handover = Handover::default();
+    let mut stream = stream;
+    while let Some((record, fds)) = recv_record(&mut stream)? {
+        let record = String::from_utf8_lossy(&record);
+        let v = record.split_whitespace().collect::<Vec<_>>();
+        match (v.first(), v.len(), fds.as_slice()) {
+            (Some(&"udp"), 5, &[control, socket]) => {
+                match (v[1].parse(), v[2].parse(), v[3].parse(), v[4].parse()) {
+                    (Ok(src), Ok(hardware_addr), Ok(is_stun), Ok(is_tunnel)) => {
+                        handover.associations.push(Association {
+                            src,
+                            hardware_addr,
+                            is_stun,
+                            is_tunnel,
+                            control,
+                            socket,
+                        })
+                    }
+                    _ => close_fds(&fds),
+                }
+            }
+            (Some(&"tcp"), 5, &[]) => {
+                if let (Ok(src), Ok(dst), Ok(sequence), Ok(hardware_addr)) =
+                    (v[1].parse(), v[2].parse(), v[3].parse(), v[4].parse())
+                {
+                    handover
+                        .connections
+                        .push((src, dst, sequence, hardware_addr));
+                }
+            }
+            _ => {
+                trace!("skip handover record {}", record);
+                close_fds(&fds);
+            }
+        }
+    }
+
+    Ok(handover)
+}
+
+/// Sends a record of the length and the data, with the descriptors attached to it.
+fn send_record(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(LENGTH_SIZE + data.len());
+    record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    record.extend_from_slice(data);
+
+    let size = send_fds(stream.as_raw_fd(), &record, fds)?;
+    let mut stream = stream;
+    stream.write_all(&record[size..])
+}
+
+/// Receives a record, or `None` if the stream is closed.
+fn recv_record(stream: &mut &UnixStream) -> io::Result<Option<(Vec<u8>, Vec<RawFd>)>> {
+    // The descriptors come with the first byte of the record
+    let mut length = [0u8; LENGTH_SIZE];
+    let (size, fds) = recv_fds(stream.as_raw_fd(), &mut length)?;
+    if size == 0 {
+        return Ok(None);
+    }
+    let r = stream.read_exact(&mut length[size..]).and_then(|_| {
+        let mut data = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut data)?;
+
+        Ok(data)
+    });
+    match r {
+        Ok(data) => Ok(Some((data, fds))),
+        Err(e) => {
+            close_fds(&fds);
+            Err(e)
+        }
+    }
+}
+
+/// Represents the max number of descriptors in a record.
+#[cfg(target_os = "linux")]
+const MAX_FDS: usize = 2;
+
+/// Sends the data with the descriptors in `SCM_RIGHTS`, and returns the size of the data sent.
+/// Passing descriptors is only supported in Linux.
+#[cfg(target_os = "linux")]
+fn send_fds(fd: RawFd, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot pass more than {} descriptors in a record", MAX_FDS),
+        ));
+    }
+
+    let iov = [IoVec::from_slice(data)];
+    let rights = [ControlMessage::ScmRights(fds)];
+    let cmsgs: &[ControlMessage] = match fds.is_empty() {
+        true => &[],
+        false => &rights,
+    };
+    loop {
+        match socket::sendmsg(fd, &iov, cmsgs, MsgFlags::MSG_NOSIGNAL, None) {
+            Ok(size) => return Ok(size),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Receives data with the descriptors in `SCM_RIGHTS`, and returns the size of the data received
+/// and the descriptors. Passing descriptors is only supported in Linux.
+#[cfg(target_os = "linux")]
+fn recv_fds(fd: RawFd, buffer: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    let iov = [IoVec::from_mut_slice(buffer)];
+    let mut control = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let msg = loop {
+        match socket::recvmsg(fd, &iov, Some(&mut control), MsgFlags::MSG_CMSG_CLOEXEC) {
+            Ok(msg) => break msg,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(rights) = cmsg {
+            fds.extend(rights);
+        }
+    }
+    if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+        close_fds(&fds);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many descriptors",
+        ));
+    }
+
+    Ok((msg.bytes, fds))
+}
+
+#[cfg(target_os = "linux")]
+fn close_fds(fds: &[RawFd]) {
+    for fd in fds.iter() {
+        let _ = unistd::close(*fd);
+    }
+}
+
+/// Sends the data with the descriptors in `SCM_RIGHTS`, and returns the size of the data sent.
+/// Passing descriptors is only supported in Linux.
+#[cfg(not(target_os = "linux"))]
+fn send_fds(_: RawFd, _: &[u8], _: &[RawFd]) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "passing sockets is not supported in this platform",
+    ))
+}
+
+/// Receives data with the descriptors in `SCM_RIGHTS`, and returns the size of the data received
+/// and the descriptors. Passing descriptors is only supported in Linux.
+#[cfg(not(target_os = "linux"))]
+fn recv_fds(_: RawFd, _: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "passing sockets is not supported in this platform",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn close_fds(_: &[RawFd]) {}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn handover_exchange() {
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let control = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let local_addr = socket.local_addr().unwrap();
+
+    let hardware_addr = "00:11:22:33:44:55".parse().unwrap();
+    let handover = Handover {
+        associations: vec![Association {
+            src: "192.168.1.100:3074".parse().unwrap(),
+            hardware_addr,
+            is_stun: true,
+            is_tunnel: false,
+            control: control.as_raw_fd(),
+            socket: socket.as_raw_fd(),
+        }],
+        connections: vec![(
+            "192.168.1.100:50000".parse().unwrap(),
+            "1.1.1.1:443".parse().unwrap(),
+            1234,
+            hardware_addr,
+        )],
+    };
+
+    let (tx, rx) = UnixStream::pair().unwrap();
+    let sent = handover.clone();
+    let sender = thread::spawn(move || send(&tx, &sent).unwrap());
+    let received = receive(&rx).unwrap();
+    sender.join().unwrap();
+    assert_eq!(received.connections, handover.connections);
+    assert_eq!(received.associations.len(), 1);
+    let association = received.associations[0];
+    assert_eq!(association.src, handover.associations[0].src);
+    assert!(association.is_stun && !association.is_tunnel);
+
+    // The sockets keep working after the sender closes its descriptors
+    drop(control);
+    drop(socket);
+    let mut control = unsafe { TcpStream::from_raw_fd(association.control) };
+    let socket = unsafe { UdpSocket::from_raw_fd(association.socket) };
+    assert_eq!(socket.local_addr().unwrap(), local_addr);
+    control.write_all(b"ping").unwrap();
+    let mut buffer = [0u8; 4];
+    peer.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"ping");
+}
@@ -0,0 +1,677 @@
+//! Support for recording closed flows into a history file and querying them.
+
+use log::warn;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::dns::Message;
+use crate::observer::{CloseReason, FlowObserver};
+use crate::packet::layer::{LayerKind, LayerKinds};
+#[cfg(feature = "quic")]
+use crate::quic::Initial;
+
+/// Represents the max number of names of destinations remembered for naming flows.
+const MAX_NAMES: usize = 4096;
+
+/// Represents the number of fields of a record.
+const FIELDS: usize = 10;
+
+/// Represents the header of SQLite databases.
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Represents the format of a history.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum HistoryFormat {
+    /// Lines of tab-separated records.
+    Text,
+    /// An SQLite database with a row in the `flows` table for each record, which requires the
+    /// `sqlite` feature.
+    Sqlite,
+}
+
+impl Display for HistoryFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            HistoryFormat::Text => write!(f, "text"),
+            HistoryFormat::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+impl FromStr for HistoryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(HistoryFormat::Text),
+            "sqlite" => Ok(HistoryFormat::Sqlite),
+            _ => Err(format!("invalid format {}, please use text or sqlite", s)),
+        }
+    }
+}
+
+/// Represents a closed flow in the history.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    time: u64,
+    protocol: String,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    domain: Option<String>,
+    upstream: Option<SocketAddr>,
+    duration: u64,
+    sent: usize,
+    received: usize,
+    reason: String,
+}
+
+impl Record {
+    /// Returns the time the flow was closed in milliseconds since the Unix epoch.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Returns the protocol of the flow.
+    pub fn protocol(&self) -> &str {
+        self.protocol.as_str()
+    }
+
+    /// Returns the source of the flow.
+    pub fn src(&self) -> SocketAddrV4 {
+        self.src
+    }
+
+    /// Returns the destination of the flow.
+    pub fn dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Returns the domain of the destination, from the SNI of QUIC or the DNS response the source
+    /// resolved the destination from.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Returns the address of the proxy the flow went through.
+    pub fn upstream(&self) -> Option<SocketAddr> {
+        self.upstream
+    }
+
+    /// Returns the duration of the flow in milliseconds.
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    /// Returns the bytes sent from the source.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the bytes received by the source.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Returns the reason why the flow was closed.
+    pub fn reason(&self) -> &str {
+        self.reason.as_str()
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let upstream = self.upstream.map(|upstream| upstream.to_string());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.time,
+            self.protocol,
+            self.src,
+            self.dst,
+            self.domain.as_deref().unwrap_or("-"),
+            upstream.as_deref().unwrap_or("-"),
+            self.duration,
+            self.sent,
+            self.received,
+            self.reason
+        )
+    }
+}
+
+impl FromStr for Record {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split('\t').collect::<Vec<_>>();
+        if fields.len() != FIELDS {
+            return Err(format!("invalid record: {}", s));
+        }
+        let invalid = |field: &str| format!("invalid {} in record: {}", field, s);
+        let optional = |value: &str| match value {
+            "-" => None,
+            _ => Some(value.to_string()),
+        };
+
+        Ok(Record {
+            time: fields[0].parse().map_err(|_| invalid("time"))?,
+            protocol: fields[1].to_string(),
+            src: fields[2].parse().map_err(|_| invalid("source"))?,
+            dst: fields[3].parse().map_err(|_| invalid("destination"))?,
+            domain: optional(fields[4]),
+            upstream: match optional(fields[5]) {
+                Some(upstream) => Some(upstream.parse().map_err(|_| invalid("upstream"))?),
+                None => None,
+            },
+            duration: fields[6].parse().map_err(|_| invalid("duration"))?,
+            sent: fields[7].parse().map_err(|_| invalid("sent"))?,
+            received: fields[8].parse().map_err(|_| invalid("received"))?,
+            reason: fields[9].to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct HistoryEntry {
+    instant: Instant,
+    domain: Option<String>,
+    upstream: Option<SocketAddr>,
+    sent: usize,
+    received: usize,
+}
+
+/// Represents the destination records of a history are written to.
+enum HistoryWriter {
+    Text(BufWriter<File>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Connection),
+}
+
+impl HistoryWriter {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self {
+            HistoryWriter::Text(file) => writeln!(file, "{}", record).and_then(|_| file.flush()),
+            #[cfg(feature = "sqlite")]
+            HistoryWriter::Sqlite(connection) => connection
+                .execute(
+                    "INSERT INTO flows (time, protocol, src, dst, domain, upstream, duration, \
+                     sent, received, reason) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    rusqlite::params![
+                        record.time as i64,
+                        record.protocol,
+                        record.src.to_string(),
+                        record.dst.to_string(),
+                        record.domain,
+                        record.upstream.map(|upstream| upstream.to_string()),
+                        record.duration as i64,
+                        record.sent as i64,
+                        record.received as i64,
+                        record.reason
+                    ],
+                )
+                .map(|_| ())
+                .map_err(sqlite_error),
+        }
+    }
+}
+
+/// Opens the SQLite database of the given path for writing, creating the `flows` table if it does
+/// not exist.
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &str) -> io::Result<HistoryWriter> {
+    let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS flows (time INTEGER NOT NULL, protocol TEXT NOT NULL, \
+             src TEXT NOT NULL, dst TEXT NOT NULL, domain TEXT, upstream TEXT, \
+             duration INTEGER NOT NULL, sent INTEGER NOT NULL, received INTEGER NOT NULL, \
+             reason TEXT NOT NULL);
+             CREATE INDEX IF NOT EXISTS flows_time ON flows (time);",
+        )
+        .map_err(sqlite_error)?;
+
+    Ok(HistoryWriter::Sqlite(connection))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_path: &str) -> io::Result<HistoryWriter> {
+    Err(sqlite_unsupported())
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "SQLite is not supported, please build with the sqlite feature",
+    )
+}
+
+/// Represents an observer which appends a record to the history for each closed flow. The domain
+/// of a flow is named by the SNI of QUIC, or the last DNS response in which the source resolved
+/// the destination.
+pub struct FlowHistory {
+    file: Mutex<HistoryWriter>,
+    entries: Mutex<HashMap<(LayerKind, SocketAddrV4, SocketAddrV4), HistoryEntry>>,
+    names: Mutex<LruCache<(Ipv4Addr, Ipv4Addr), String>>,
+}
+
+impl FlowHistory {
+    /// Opens a `FlowHistory` which appends to the file of the given path in the format.
+    pub fn open(path: &str, format: HistoryFormat) -> io::Result<FlowHistory> {
+        let file = match format {
+            HistoryFormat::Text => HistoryWriter::Text(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            HistoryFormat::Sqlite => open_sqlite(path)?,
+        };
+
+        Ok(FlowHistory {
+            file: Mutex::new(file),
+            entries: Mutex::new(HashMap::new()),
+            names: Mutex::new(LruCache::new(MAX_NAMES)),
+        })
+    }
+
+    fn add(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        sent: usize,
+        received: usize,
+    ) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&(kind, src, dst)) {
+            entry.sent = entry.sent.checked_add(sent).unwrap_or(usize::MAX);
+            entry.received = entry.received.checked_add(received).unwrap_or(usize::MAX);
+        }
+    }
+}
+
+impl FlowObserver for FlowHistory {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        let domain = self
+            .names
+            .lock()
+            .unwrap()
+            .get(&(*src.ip(), *dst.ip()))
+            .cloned();
+        self.entries.lock().unwrap().insert(
+            (kind, src, dst),
+            HistoryEntry {
+                instant: Instant::now(),
+                domain,
+                upstream: None,
+                sent: 0,
+                received: 0,
+            },
+        );
+    }
+
+    fn on_flow_connected(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        upstream: SocketAddr,
+    ) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&(kind, src, dst)) {
+            entry.upstream = Some(upstream);
+        }
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        // Flows failed to connect are closed without being created
+        let entry = self.entries.lock().unwrap().remove(&(kind, src, dst));
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let record = match entry {
+            Some(entry) => Record {
+                time,
+                protocol: kind.to_string(),
+                src,
+                dst,
+                domain: entry.domain,
+                upstream: entry.upstream,
+                duration: entry.instant.elapsed().as_millis() as u64,
+                sent: entry.sent,
+                received: entry.received,
+                reason: reason.to_string(),
+            },
+            None => Record {
+                time,
+                protocol: kind.to_string(),
+                src,
+                dst,
+                domain: None,
+                upstream: None,
+                duration: 0,
+                sent: 0,
+                received: 0,
+                reason: reason.to_string(),
+            },
+        };
+
+        if let Err(ref e) = self.file.lock().unwrap().write(&record) {
+            warn!("write history: {}", e);
+        }
+    }
+
+    fn on_bytes_sent(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, n, 0);
+    }
+
+    fn on_bytes_received(&self, kind: LayerKind, dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, 0, n);
+    }
+
+    fn on_dns_response(&self, _dst: SocketAddrV4, src: SocketAddrV4, message: &Message) {
+        let question = match message.question() {
+            Some(question) => question,
+            None => return,
+        };
+
+        let mut names = self.names.lock().unwrap();
+        for addr in message.addrs() {
+            names.put((*src.ip(), *addr), question.name().to_string());
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    fn on_quic_initial(&self, src: SocketAddrV4, dst: SocketAddrV4, initial: &Initial) {
+        let sni = match initial.sni() {
+            Some(sni) => sni.to_string(),
+            None => return,
+        };
+
+        // The flow is created after its first initial
+        if let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&(LayerKinds::Udp, src, dst))
+        {
+            entry.domain = Some(sni.clone());
+        }
+        self.names.lock().unwrap().put((*src.ip(), *dst.ip()), sni);
+    }
+}
+
+/// Represents the filters of querying the history.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryQuery {
+    src: Option<Ipv4Addr>,
+    domain: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl HistoryQuery {
+    /// Creates a `HistoryQuery` matching all the records.
+    pub fn new() -> HistoryQuery {
+        HistoryQuery::default()
+    }
+
+    /// Sets the IP address of the source.
+    pub fn set_src(&mut self, src: Ipv4Addr) {
+        self.src = Some(src);
+    }
+
+    /// Sets the domain, which also matches its subdomains.
+    pub fn set_domain(&mut self, domain: &str) {
+        self.domain = Some(domain.trim_end_matches('.').to_lowercase());
+    }
+
+    /// Sets the earliest time in milliseconds since the Unix epoch.
+    pub fn set_since(&mut self, since: u64) {
+        self.since = Some(since);
+    }
+
+    /// Sets the latest time in milliseconds since the Unix epoch.
+    pub fn set_until(&mut self, until: u64) {
+        self.until = Some(until);
+    }
+
+    /// Returns if the record matches the query. A flow matches a time range if it was active in
+    /// the range.
+    pub fn matches(&self, record: &Record) -> bool {
+        if let Some(src) = self.src {
+            if *record.src.ip() != src {
+                return false;
+            }
+        }
+        if let Some(ref domain) = self.domain {
+            match record.domain {
+                Some(ref name) => {
+                    if name != domain && !name.ends_with(&format!(".{}", domain)) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        if let Some(since) = self.since {
+            if record.time < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if record.time.saturating_sub(record.duration) > until {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Reads the records matching the query from the history file of the given path, in the order
+/// they were closed. The format of the history is told by its header. Malformed lines, like a
+/// line cut by a crash, are skipped.
+pub fn read(path: &str, query: &HistoryQuery) -> io::Result<Vec<Record>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 16];
+    let is_sqlite = match file.read_exact(&mut header) {
+        Ok(_) => &header == SQLITE_HEADER,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    if is_sqlite {
+        return read_sqlite(path, query);
+    }
+    let file = File::open(path)?;
+
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match line.parse::<Record>() {
+            Ok(record) => {
+                if query.matches(&record) {
+                    records.push(record);
+                }
+            }
+            Err(ref e) => warn!("read history: {}", e),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Reads the records matching the query from the SQLite database of the given path.
+#[cfg(feature = "sqlite")]
+fn read_sqlite(path: &str, query: &HistoryQuery) -> io::Result<Vec<Record>> {
+    use rusqlite::{Connection, OpenFlags, Row};
+
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(sqlite_error)?;
+    let mut statement = connection
+        .prepare(
+            "SELECT time, protocol, src, dst, domain, upstream, duration, sent, received, \
+             reason FROM flows WHERE time >= ?1 ORDER BY rowid",
+        )
+        .map_err(sqlite_error)?;
+
+    let parse = |row: &Row| -> rusqlite::Result<Result<Record, String>> {
+        let src: String = row.get(2)?;
+        let dst: String = row.get(3)?;
+        let upstream: Option<String> = row.get(5)?;
+        let invalid = |field: &str| format!("invalid {} in record: {} -> {}", field, src, dst);
+
+        Ok(Ok(Record {
+            time: row.get::<_, i64>(0)? as u64,
+            protocol: row.get(1)?,
+            src: match src.parse() {
+                Ok(src) => src,
+                Err(_) => return Ok(Err(invalid("source"))),
+            },
+            dst: match dst.parse() {
+                Ok(dst) => dst,
+                Err(_) => return Ok(Err(invalid("destination"))),
+            },
+            domain: row.get(4)?,
+            upstream: match upstream.map(|upstream| upstream.parse()) {
+                Some(Ok(upstream)) => Some(upstream),
+                Some(Err(_)) => return Ok(Err(invalid("upstream"))),
+                None => None,
+            },
+            duration: row.get::<_, i64>(6)? as u64,
+            sent: row.get::<_, i64>(7)? as usize,
+            received: row.get::<_, i64>(8)? as usize,
+            reason: row.get(9)?,
+        }))
+    };
+
+    let mut records = Vec::new();
+    let rows = statement
+        .query_map(rusqlite::params![query.since.unwrap_or(0) as i64], parse)
+        .map_err(sqlite_error)?;
+    for row in rows {
+        match row.map_err(sqlite_error)? {
+            Ok(record) => {
+                if query.matches(&record) {
+                    records.push(record);
+                }
+            }
+            Err(ref e) => warn!("read history: {}", e),
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn read_sqlite(_path: &str, _query: &HistoryQuery) -> io::Result<Vec<Record>> {
+    Err(sqlite_unsupported())
+}
+
+/// Formats the time in milliseconds since the Unix epoch in UTC, like `2021-01-01 00:00:00`.
+pub fn format_time(time: u64) -> String {
+    let secs = time / 1000;
+    let (year, month, day) = civil_from_days(secs / 86400);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Returns the year, the month and the day of the given days since the Unix epoch.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[test]
+fn history_record() {
+    let record = Record {
+        time: 1609459200000,
+        protocol: "TCP".to_string(),
+        src: "192.168.1.9:50000".parse().unwrap(),
+        dst: "93.184.216.34:443".parse().unwrap(),
+        domain: Some("www.example.com".to_string()),
+        upstream: Some("10.0.0.1:1080".parse().unwrap()),
+        duration: 1500,
+        sent: 1024,
+        received: 65536,
+        reason: "source_fin".to_string(),
+    };
+    assert_eq!(record.to_string().parse::<Record>().unwrap(), record);
+    assert_eq!(format_time(record.time()), "2021-01-01 00:00:00");
+
+    let mut query = HistoryQuery::new();
+    query.set_src(Ipv4Addr::new(192, 168, 1, 9));
+    query.set_domain("Example.com");
+    query.set_until(1609459199000);
+    assert!(query.matches(&record));
+    query.set_since(1609459200001);
+    assert!(!query.matches(&record));
+
+    assert!("1\tTCP\t192.168.1.9:50000".parse::<Record>().is_err());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn history_sqlite() {
+    let path = std::env::temp_dir().join("pcap2socks_history_sqlite.db");
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    let src: SocketAddrV4 = "192.168.1.9:50000".parse().unwrap();
+    let dst: SocketAddrV4 = "93.184.216.34:443".parse().unwrap();
+    {
+        let history = FlowHistory::open(path, HistoryFormat::Sqlite).unwrap();
+        history.on_flow_created(LayerKinds::Tcp, src, dst);
+        history.on_bytes_sent(LayerKinds::Tcp, src, dst, 1024);
+        history.on_flow_closed(LayerKinds::Tcp, src, dst, CloseReason::SourceFin);
+        history.on_flow_closed(
+            LayerKinds::Udp,
+            "192.168.1.10:50000".parse().unwrap(),
+            dst,
+            CloseReason::SourceFin,
+        );
+    }
+
+    // The format is told by the header
+    let records = read(path, &HistoryQuery::new()).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].protocol(), "TCP");
+    assert_eq!(records[0].src(), src);
+    assert_eq!(records[0].sent(), 1024);
+    let mut query = HistoryQuery::new();
+    query.set_src(Ipv4Addr::new(192, 168, 1, 10));
+    assert_eq!(read(path, &query).unwrap().len(), 1);
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,1027 @@
+//! Support for controlling from frontends like pcap2socks-gui over IPC.
+//!
+//! Frontends talk to the IPC server in lines of JSON. Each request is like
+//! `{"version":1,"command":"stats"}`, and each response or event is a line with the version and a
+//! `type`. The commands are `start`, `stop`, `stats`, `flows`, `subscribe`, `devices`, `approve`,
+//! `wake`, `credentials` and `diagnostics`, where `approve` carries the IP address of the device in the `address` field, and
+//! `credentials` carries the new credentials of the proxy in the `username` and the `password`
+//! fields. If a token is set, each request should also carry it in the `token` field.
+//!
+//! With the `noise` feature, the IPC can also be served over TCP for remote frontends, where
+//! connections are encrypted and authenticated by a pre-shared key in Noise.
+#![cfg_attr(not(any(unix, windows, feature = "noise")), allow(dead_code))]
+
+#[cfg(feature = "noise")]
+mod noise;
+#[cfg(feature = "noise")]
+pub use noise::{connect, IpcKey, NoiseStream};
+
+use log::{debug, info, trace, warn};
+use std::future::{self, Future};
+#[cfg(feature = "noise")]
+use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::CharIndices;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+use crate::diagnostics::Diagnostics;
+use crate::discovery::Discovery;
+use crate::dns::Message;
+use crate::observer::{self, CloseReason, DnsRoute, FlowObserver, FlowTable};
+use crate::packet::layer::LayerKind;
+use crate::pcap::HardwareAddr;
+use crate::{verify_token, Forwarder, ProxyConfig};
+
+/// Represents the version of the IPC schema. The version will be increased on incompatible
+/// changes of the schema.
+pub const IPC_VERSION: u32 = 1;
+/// Represents the number of flow events buffered for each subscriber.
+const MAX_EVENTS: usize = 1024;
+
+/// Represents an observer which publishes flow events to IPC subscribers.
+#[derive(Debug)]
+pub struct IpcEvents {
+    tx: Sender<String>,
+}
+
+impl IpcEvents {
+    /// Creates a new `IpcEvents`.
+    pub fn new() -> IpcEvents {
+        let (tx, _) = broadcast::channel(MAX_EVENTS);
+
+        IpcEvents { tx }
+    }
+
+    fn publish(&self, event: String) {
+        // Events are dropped if there is no subscriber
+        let _ = self.tx.send(event);
+    }
+}
+
+impl Default for IpcEvents {
+    fn default() -> IpcEvents {
+        IpcEvents::new()
+    }
+}
+
+impl FlowObserver for IpcEvents {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.publish(format!(
+            "{{\"version\":{},\"type\":\"flow_created\",\"time\":{},\"protocol\":\"{}\",\"src\":\"{}\",\"dst\":\"{}\"}}",
+            IPC_VERSION,
+            timestamp(),
+            kind,
+            src,
+            dst
+        ));
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        self.publish(format!(
+            "{{\"version\":{},\"type\":\"flow_closed\",\"time\":{},\"protocol\":\"{}\",\"src\":\"{}\",\"dst\":\"{}\",\"reason\":\"{}\"}}",
+            IPC_VERSION,
+            timestamp(),
+            kind,
+            src,
+            dst,
+            reason
+        ));
+    }
+
+    fn on_dns_message(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+        route: Option<DnsRoute>,
+    ) {
+        let (message, route) = match (Message::parse(payload), route) {
+            (Some(message), Some(route)) => (message, route),
+            _ => return,
+        };
+        self.publish(format!(
+            "{{\"version\":{},\"type\":\"dns\",\"time\":{},{}}}",
+            IPC_VERSION,
+            timestamp(),
+            observer::dns_fields(src, dst, &message, route)
+        ));
+    }
+}
+
+fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Represents a command from a frontend.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Command {
+    /// Resumes redirecting traffic.
+    Start,
+    /// Pauses redirecting traffic. Frames are dropped until the redirection is started again.
+    Stop,
+    /// Queries the statistics of sources.
+    Stats,
+    /// Queries the active flows and the last closed flows.
+    Flows,
+    /// Subscribes flow events and DNS events.
+    Subscribe,
+    /// Queries the devices discovered on the interface.
+    Devices,
+    /// Approves the device of the IP address to be redirected as a source.
+    Approve(Ipv4Addr),
+    /// Wakes the device of the hardware address with a Wake-on-LAN magic packet.
+    Wake(HardwareAddr),
+    /// Rotates the username and the password of the proxy, or removes them if `None`, for new
+    /// flows.
+    Credentials(Option<(String, String)>),
+    /// Queries the findings of the diagnostics on the network segment.
+    Diagnostics,
+}
+
+/// Parses a request line, and returns the command or the message of the error.
+pub fn parse_request(line: &str) -> Result<Command, String> {
+    let fields = fields(line).ok_or_else(|| String::from("malformed request"))?;
+    let value = |key| field(&fields, key);
+
+    match value("version") {
+        Some(version) => {
+            if version != IPC_VERSION.to_string() {
+                return Err(format!("unsupported version {}", version));
+            }
+        }
+        None => return Err(String::from("missing version")),
+    }
+
+    match value("command") {
+        Some("start") => Ok(Command::Start),
+        Some("stop") => Ok(Command::Stop),
+        Some("stats") => Ok(Command::Stats),
+        Some("flows") => Ok(Command::Flows),
+        Some("subscribe") => Ok(Command::Subscribe),
+        Some("devices") => Ok(Command::Devices),
+        Some("approve") => match value("address") {
+            Some(address) => address
+                .parse()
+                .map(Command::Approve)
+                .map_err(|e| format!("invalid address {}: {}", address, e)),
+            None => Err(String::from("missing address")),
+        },
+        Some("wake") => match value("address") {
+            Some(address) => address
+                .parse()
+                .map(Command::Wake)
+                .map_err(|_| format!("invalid hardware address {}", address)),
+            None => Err(String::from("missing address")),
+        },
+        Some("credentials") => match (value("username"), value("password")) {
+            (Some(username), Some(password)) => Ok(Command::Credentials(Some((
+                username.to_string(),
+                password.to_string(),
+            )))),
+            (None, None) => Ok(Command::Credentials(None)),
+            (Some(_), None) => Err(String::from("missing password")),
+            (None, Some(_)) => Err(String::from("missing username")),
+        },
+        Some("diagnostics") => Ok(Command::Diagnostics),
+        Some(command) => Err(format!("unknown command {}", command)),
+        None => Err(String::from("missing command")),
+    }
+}
+
+/// Parses a flat JSON object, and returns its fields as the keys and the values, where strings are
+/// unescaped without their quotes, and other values are kept as they are. Returns `None` if the
+/// object is malformed, has nested objects or arrays, or has duplicate keys.
+fn fields(line: &str) -> Option<Vec<(String, String)>> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut rest = line.trim().strip_prefix('{')?.trim_start();
+    if let Some(rest) = rest.strip_prefix('}') {
+        return match rest.trim().is_empty() {
+            true => Some(fields),
+            false => None,
+        };
+    }
+
+    loop {
+        let (key, next) = string(rest)?;
+        if fields.iter().any(|(k, _)| *k == key) {
+            return None;
+        }
+        rest = next.trim_start().strip_prefix(':')?.trim_start();
+
+        let (value, next) = match rest.starts_with('"') {
+            true => string(rest)?,
+            false => {
+                let end = rest.find(|c| c == ',' || c == '}').unwrap_or(rest.len());
+                let value = rest[..end].trim();
+                if !is_literal(value) {
+                    return None;
+                }
+
+                (value.to_string(), &rest[end..])
+            }
+        };
+        fields.push((key, value));
+
+        rest = next.trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next.trim_start();
+        } else {
+            return match rest.strip_prefix('}')?.trim().is_empty() {
+                true => Some(fields),
+                false => None,
+            };
+        }
+    }
+}
+
+/// Returns the unescaped content of the JSON string at the start, and the rest after its closing
+/// quote. Returns `None` if the string is not closed, or has invalid escapes or unescaped control
+/// characters.
+fn string(s: &str) -> Option<(String, &str)> {
+    let s = s.strip_prefix('"')?;
+    let mut content = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((content, &s[i + 1..])),
+            '\\' => {
+                let c = match chars.next()?.1 {
+                    '"' => '"',
+                    '\\' => '\\',
+                    '/' => '/',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => {
+                        let unit = utf16(&mut chars)?;
+                        match unit {
+                            // Surrogate pair
+                            0xd800..=0xdbff => {
+                                if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                                    return None;
+                                }
+                                let low = utf16(&mut chars)?;
+                                std::char::decode_utf16([unit, low].iter().cloned())
+                                    .next()?
+                                    .ok()?
+                            }
+                            _ => std::char::from_u32(unit as u32)?,
+                        }
+                    }
+                    _ => return None,
+                };
+                content.push(c);
+            }
+            // Control characters must be escaped
+            c if c < ' ' => return None,
+            c => content.push(c),
+        }
+    }
+
+    None
+}
+
+/// Returns the UTF-16 code unit of the 4 hexadecimal digits in a JSON `\u` escape.
+fn utf16(chars: &mut CharIndices) -> Option<u16> {
+    let mut unit = 0;
+    for _ in 0..4 {
+        unit = unit * 16 + chars.next()?.1.to_digit(16)? as u16;
+    }
+
+    Some(unit)
+}
+
+/// Returns if the value is a JSON number, `true`, `false` or `null`.
+fn is_literal(value: &str) -> bool {
+    match value {
+        "true" | "false" | "null" => true,
+        _ => {
+            value
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+                && value.parse::<f64>().is_ok()
+        }
+    }
+}
+
+/// Returns the value of the field in the fields of a flat JSON object.
+fn field<'a>(fields: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Represents the optional components managed by frontends.
+#[derive(Clone, Default)]
+struct Managed {
+    discovery: Option<Arc<Discovery>>,
+    diagnostics: Option<Arc<Diagnostics>>,
+    flows: Option<Arc<FlowTable>>,
+    proxy: Option<ProxyConfig>,
+}
+
+/// Represents the endpoint an `IpcServer` listens on.
+enum Listener {
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+    #[cfg(windows)]
+    Pipe(String, tokio::net::windows::named_pipe::NamedPipeServer),
+    #[cfg(feature = "noise")]
+    Tcp(tokio::net::TcpListener, Arc<IpcKey>),
+}
+
+/// Represents an IPC server which serves frontends on a Unix domain socket, or a named pipe on
+/// Windows, or on TCP encrypted in Noise.
+pub struct IpcServer {
+    tx: Arc<Mutex<Forwarder>>,
+    paused: Arc<AtomicBool>,
+    events: Sender<String>,
+    token: Option<Arc<str>>,
+    managed: Managed,
+    listener: Listener,
+}
+
+impl IpcServer {
+    /// Binds an `IpcServer` on the given path. The statistics are queried from the `Forwarder`,
+    /// and the redirection is paused or resumed by the given flag.
+    #[cfg(unix)]
+    pub fn bind(
+        path: &str,
+        tx: Arc<Mutex<Forwarder>>,
+        paused: Arc<AtomicBool>,
+        events: &IpcEvents,
+    ) -> io::Result<IpcServer> {
+        // Remove the stale socket left by a previous run
+        if let Ok(metadata) = std::fs::metadata(path) {
+            use std::os::unix::fs::FileTypeExt;
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        // Only the owner can connect
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        trace!("bind IPC {}", path);
+
+        Ok(IpcServer {
+            tx,
+            paused,
+            events: events.tx.clone(),
+            token: None,
+            managed: Managed::default(),
+            listener: Listener::Unix(listener),
+        })
+    }
+
+    /// Sets the token which authenticates requests.
+    pub fn set_token(&mut self, token: String) {
+        self.token = Some(Arc::from(token));
+        trace!("set IPC token");
+    }
+
+    /// Sets the discovery which devices are queried from and approved to.
+    pub fn set_discovery(&mut self, discovery: Arc<Discovery>) {
+        self.managed.discovery = Some(discovery);
+        trace!("set IPC discovery");
+    }
+
+    /// Sets the diagnostics which findings are queried from.
+    pub fn set_diagnostics(&mut self, diagnostics: Arc<Diagnostics>) {
+        self.managed.diagnostics = Some(diagnostics);
+        trace!("set IPC diagnostics");
+    }
+
+    /// Sets the flow table which flows are queried from.
+    pub fn set_flows(&mut self, flows: Arc<FlowTable>) {
+        self.managed.flows = Some(flows);
+        trace!("set IPC flows");
+    }
+
+    /// Sets the proxy whose credentials are rotated.
+    pub fn set_proxy(&mut self, proxy: ProxyConfig) {
+        self.managed.proxy = Some(proxy);
+        trace!("set IPC proxy");
+    }
+
+    /// Binds an `IpcServer` on the given path of a named pipe, like `\\.\pipe\pcap2socks`. The
+    /// pipe is created with the default security descriptor, which only grants writing to the
+    /// owner, administrators and the system, and remote clients are rejected.
+    #[cfg(windows)]
+    pub fn bind(
+        path: &str,
+        tx: Arc<Mutex<Forwarder>>,
+        paused: Arc<AtomicBool>,
+        events: &IpcEvents,
+    ) -> io::Result<IpcServer> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        // Fail if another process owns the pipe
+        let pipe = ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create(path)?;
+        trace!("bind IPC {}", path);
+
+        Ok(IpcServer {
+            tx,
+            paused,
+            events: events.tx.clone(),
+            token: None,
+            managed: Managed::default(),
+            listener: Listener::Pipe(String::from(path), pipe),
+        })
+    }
+
+    /// Binds an `IpcServer` on the given path. IPC is not supported on this platform, so this
+    /// always returns an error.
+    #[cfg(not(any(unix, windows)))]
+    pub fn bind(
+        _path: &str,
+        _tx: Arc<Mutex<Forwarder>>,
+        _paused: Arc<AtomicBool>,
+        _events: &IpcEvents,
+    ) -> io::Result<IpcServer> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "IPC is not supported on this platform",
+        ))
+    }
+
+    /// Binds an `IpcServer` on the given TCP address. Each connection has to complete the Noise
+    /// handshake with the key before any request.
+    #[cfg(feature = "noise")]
+    pub async fn bind_tcp(
+        addr: SocketAddr,
+        key: IpcKey,
+        tx: Arc<Mutex<Forwarder>>,
+        paused: Arc<AtomicBool>,
+        events: &IpcEvents,
+    ) -> io::Result<IpcServer> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        trace!("bind IPC {}", addr);
+
+        Ok(IpcServer {
+            tx,
+            paused,
+            events: events.tx.clone(),
+            token: None,
+            managed: Managed::default(),
+            listener: Listener::Tcp(listener, Arc::new(key)),
+        })
+    }
+
+    /// Serves frontends. Each frontend is handled in its own task.
+    pub async fn serve(self) {
+        #[cfg_attr(not(any(unix, windows, feature = "noise")), allow(unused_variables))]
+        let handler = Handler {
+            tx: self.tx,
+            paused: self.paused,
+            events: self.events,
+            token: self.token,
+            managed: self.managed,
+        };
+        match self.listener {
+            #[cfg(unix)]
+            Listener::Unix(listener) => loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(ref e) => {
+                        warn!("accept IPC: {}", e);
+                        continue;
+                    }
+                };
+                debug!("accept IPC");
+
+                handler.spawn(future::ready(Ok(stream)));
+            },
+            // A new instance of the pipe is created for the next frontend once one is connected
+            #[cfg(windows)]
+            Listener::Pipe(path, mut pipe) => loop {
+                use tokio::net::windows::named_pipe::ServerOptions;
+
+                let is_connected = match pipe.connect().await {
+                    Ok(_) => true,
+                    Err(ref e) => {
+                        warn!("accept IPC: {}", e);
+                        false
+                    }
+                };
+
+                // An instance failed in connecting is replaced as well
+                let next = match ServerOptions::new()
+                    .reject_remote_clients(true)
+                    .create(&path)
+                {
+                    Ok(pipe) => pipe,
+                    Err(ref e) => {
+                        warn!("Cannot create the IPC pipe {}: {}", path, e);
+                        return;
+                    }
+                };
+                let stream = std::mem::replace(&mut pipe, next);
+                if is_connected {
+                    debug!("accept IPC");
+                    handler.spawn(future::ready(Ok(stream)));
+                }
+            },
+            // The handshake is performed in the task of the frontend, so a slow frontend does
+            // not block others
+            #[cfg(feature = "noise")]
+            Listener::Tcp(listener, key) => loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(ref e) => {
+                        warn!("accept IPC: {}", e);
+                        continue;
+                    }
+                };
+                debug!("accept IPC from {}", addr);
+
+                let key = Arc::clone(&key);
+                handler.spawn(async move { noise::accept(stream, &key).await });
+            },
+        }
+    }
+}
+
+/// Represents the states shared by the frontends of an `IpcServer`.
+struct Handler {
+    tx: Arc<Mutex<Forwarder>>,
+    paused: Arc<AtomicBool>,
+    events: Sender<String>,
+    token: Option<Arc<str>>,
+    managed: Managed,
+}
+
+impl Handler {
+    /// Handles a frontend in a new task once it is connected.
+    fn spawn<F, S>(&self, stream: F)
+    where
+        F: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let tx = Arc::clone(&self.tx);
+        let paused = Arc::clone(&self.paused);
+        let events = self.events.clone();
+        let token = self.token.clone();
+        let managed = self.managed.clone();
+        tokio::spawn(async move {
+            let result = match stream.await {
+                Ok(stream) => handle(stream, tx, paused, events, token, managed).await,
+                Err(e) => Err(e),
+            };
+            if let Err(ref e) = result {
+                warn!("handle IPC: {}", e);
+            }
+            trace!("close IPC");
+        });
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    tx: Arc<Mutex<Forwarder>>,
+    paused: Arc<AtomicBool>,
+    events: Sender<String>,
+    token: Option<Arc<str>>,
+    managed: Managed,
+) -> io::Result<()> {
+    let (stream_rx, mut stream_tx) = io::split(stream);
+    let mut lines = BufReader::new(stream_rx).lines();
+    let mut events_rx = None;
+    loop {
+        let line;
+        let event;
+
+        // Select
+        {
+            let lines_fut = lines.next_line();
+            let events_fut = recv_event(&mut events_rx);
+
+            tokio::pin!(lines_fut, events_fut);
+
+            tokio::select! {
+                l = lines_fut => {
+                    line = l?;
+                    event = None;
+                }
+                e = events_fut => {
+                    line = None;
+                    event = e;
+                }
+            }
+        }
+
+        let response = match (line, event) {
+            (Some(line), _) => {
+                match authorize(&line, token.as_deref()).and_then(|_| parse_request(&line)) {
+                    Ok(command) => {
+                        match command {
+                            // Never log the password
+                            Command::Credentials(_) => trace!("IPC command credentials"),
+                            _ => trace!("IPC command {:?}", command),
+                        }
+                        match command {
+                            Command::Start => {
+                                paused.store(false, Ordering::Relaxed);
+                                status(&paused)
+                            }
+                            Command::Stop => {
+                                paused.store(true, Ordering::Relaxed);
+                                status(&paused)
+                            }
+                            Command::Stats => stats(&tx, &paused),
+                            Command::Flows => match managed.flows {
+                                Some(ref table) => flows(table),
+                                None => error("flows not enabled"),
+                            },
+                            Command::Subscribe => {
+                                events_rx = Some(events.subscribe());
+                                status(&paused)
+                            }
+                            Command::Devices => match managed.discovery {
+                                Some(ref discovery) => devices(discovery),
+                                None => error("discovery not enabled"),
+                            },
+                            Command::Approve(ip_addr) => match managed.discovery {
+                                Some(ref discovery) => {
+                                    if discovery.approve(ip_addr) {
+                                        info!("Proxy device at {}", ip_addr);
+                                    }
+                                    devices(discovery)
+                                }
+                                None => error("discovery not enabled"),
+                            },
+                            Command::Wake(hardware_addr) => {
+                                match tx.lock().unwrap().send_wake(hardware_addr) {
+                                    Ok(_) => {
+                                        info!("Wake {}", hardware_addr);
+                                        status(&paused)
+                                    }
+                                    Err(ref e) => error(&e.to_string()),
+                                }
+                            }
+                            Command::Credentials(auth) => match managed.proxy {
+                                Some(ref proxy) => {
+                                    match auth {
+                                        Some((ref username, _)) => {
+                                            info!(
+                                                "Rotate the credentials of the proxy to {}",
+                                                username
+                                            )
+                                        }
+                                        None => info!("Remove the credentials of the proxy"),
+                                    }
+                                    proxy.set_auth(auth);
+                                    status(&paused)
+                                }
+                                None => error("proxy not managed"),
+                            },
+                            Command::Diagnostics => match managed.diagnostics {
+                                Some(ref diagnostics) => findings(diagnostics),
+                                None => error("diagnostics not enabled"),
+                            },
+                        }
+                    }
+                    Err(e) => error(&e),
+                }
+            }
+            (None, Some(event)) => event,
+            // The frontend closed the connection
+            (None, None) => return Ok(()),
+        };
+
+        stream_tx.write_all(response.as_bytes()).await?;
+        stream_tx.write_all(b"\n").await?;
+        stream_tx.flush().await?;
+    }
+}
+
+/// Checks the token of a request line if a token is set.
+fn authorize(line: &str, token: Option<&str>) -> Result<(), String> {
+    match token {
+        Some(token) => {
+            let fields = fields(line).unwrap_or_default();
+            match field(&fields, "token") {
+                Some(t) if verify_token(t, token) => Ok(()),
+                _ => Err(String::from("unauthorized")),
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+async fn recv_event(events_rx: &mut Option<Receiver<String>>) -> Option<String> {
+    match events_rx {
+        Some(events_rx) => loop {
+            match events_rx.recv().await {
+                Ok(event) => return Some(event),
+                // Slow subscribers miss events
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        },
+        None => future::pending().await,
+    }
+}
+
+fn error(message: &str) -> String {
+    format!(
+        "{{\"version\":{},\"type\":\"error\",\"message\":\"{}\"}}",
+        IPC_VERSION,
+        escape(message)
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            // Control characters, which would also break the lines of messages
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn devices(discovery: &Discovery) -> String {
+    let approved = discovery.approved();
+    let devices = discovery
+        .candidates()
+        .iter()
+        .map(|candidate| {
+            format!(
+                "{{\"mac\":\"{}\",\"vendor\":{},\"hostname\":{},\"ip\":{},\"approved\":{}}}",
+                candidate.hardware_addr(),
+                match candidate.vendor() {
+                    Some(vendor) => format!("\"{}\"", vendor),
+                    None => String::from("null"),
+                },
+                match candidate.hostname() {
+                    Some(hostname) => format!("\"{}\"", escape(hostname)),
+                    None => String::from("null"),
+                },
+                match candidate.ip_addr() {
+                    Some(ip_addr) => format!("\"{}\"", ip_addr),
+                    None => String::from("null"),
+                },
+                candidate
+                    .ip_addr()
+                    .map_or(false, |ip_addr| approved.contains(&ip_addr))
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"version\":{},\"type\":\"devices\",\"devices\":[{}]}}",
+        IPC_VERSION,
+        devices.join(",")
+    )
+}
+
+fn findings(diagnostics: &Diagnostics) -> String {
+    let findings = diagnostics
+        .findings()
+        .iter()
+        .map(|finding| {
+            format!(
+                "{{\"kind\":\"{}\",\"ip\":\"{}\",\"mac\":\"{}\",\"message\":\"{}\",\"advice\":\"{}\"}}",
+                finding.kind(),
+                finding.ip_addr(),
+                finding.hardware_addr(),
+                finding,
+                finding.advice()
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"version\":{},\"type\":\"diagnostics\",\"findings\":[{}]}}",
+        IPC_VERSION,
+        findings.join(",")
+    )
+}
+
+fn flows(table: &FlowTable) -> String {
+    let flows = table
+        .flows()
+        .iter()
+        .map(|flow| {
+            format!(
+                "{{\"protocol\":\"{}\",\"src\":\"{}\",\"dst\":\"{}\",\"proxy\":{},\"duration\":{},\"sent\":{},\"received\":{},\"reason\":{}}}",
+                flow.kind(),
+                flow.src(),
+                flow.dst(),
+                match flow.upstream() {
+                    Some(upstream) => format!("\"{}\"", upstream),
+                    None => String::from("null"),
+                },
+                flow.duration().as_millis(),
+                flow.sent(),
+                flow.received(),
+                match flow.reason() {
+                    Some(reason) => format!("\"{}\"", reason),
+                    None => String::from("null"),
+                }
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"version\":{},\"type\":\"flows\",\"flows\":[{}]}}",
+        IPC_VERSION,
+        flows.join(",")
+    )
+}
+
+fn status(paused: &AtomicBool) -> String {
+    format!(
+        "{{\"version\":{},\"type\":\"status\",\"running\":{}}}",
+        IPC_VERSION,
+        !paused.load(Ordering::Relaxed)
+    )
+}
+
+fn stats(tx: &Mutex<Forwarder>, paused: &AtomicBool) -> String {
+    let client_stats = tx.lock().unwrap().client_stats();
+    let clients = client_stats
+        .iter()
+        .map(|stats| {
+            format!(
+                "{{\"ip\":\"{}\",\"mac\":{},\"vendor\":{},\"flows\":{},\"active\":{},\"srtt\":{},\"sent\":{},\"retransmitted\":{}}}",
+                stats.ip_addr(),
+                match stats.hardware_addr() {
+                    Some(hardware_addr) => format!("\"{}\"", hardware_addr),
+                    None => String::from("null"),
+                },
+                match stats.vendor() {
+                    Some(vendor) => format!("\"{}\"", vendor),
+                    None => String::from("null"),
+                },
+                stats.flows(),
+                stats.active(),
+                match stats.srtt() {
+                    Some(srtt) => format!("{:.3}", srtt * 1000.0),
+                    None => String::from("null"),
+                },
+                stats.sent(),
+                stats.retrans()
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"version\":{},\"type\":\"stats\",\"running\":{},\"clients\":[{}]}}",
+        IPC_VERSION,
+        !paused.load(Ordering::Relaxed),
+        clients.join(",")
+    )
+}
+
+#[test]
+fn ipc_parse_request() {
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"stats\"}"),
+        Ok(Command::Stats)
+    );
+    assert_eq!(
+        parse_request("{ \"command\" : \"stop\", \"version\" : 1 }"),
+        Ok(Command::Stop)
+    );
+    assert!(parse_request("{\"version\":2,\"command\":\"stats\"}").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":\"restart\"}").is_err());
+    assert!(parse_request("{\"command\":\"start\"}").is_err());
+    // Keys are only matched as keys
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"credentials\",\"username\":\"password\",\"password\":\"pass\"}"),
+        Ok(Command::Credentials(Some((
+            String::from("password"),
+            String::from("pass")
+        ))))
+    );
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"approve\",\"note\":\"\\\"address\\\":1\",\"address\":\"10.6.0.1\"}"),
+        Ok(Command::Approve(Ipv4Addr::new(10, 6, 0, 1)))
+    );
+    assert!(parse_request("{\"version\":1,\"note\":\"\\\"command\\\":\\\"stop\\\"\"}").is_err());
+    // Escapes
+    assert_eq!(
+        parse_request(
+            "{\"version\":1,\"command\":\"credentials\",\"username\":\"us\\u00e9r\",\"password\":\"p\\\"a\\\\s\\/s\"}"
+        ),
+        Ok(Command::Credentials(Some((
+            String::from("us\u{e9}r"),
+            String::from("p\"a\\s/s")
+        ))))
+    );
+    assert_eq!(
+        parse_request(
+            "{\"version\":1,\"command\":\"credentials\",\"username\":\"\\ud83d\\ude00\",\"password\":\"\\\\\"}"
+        ),
+        Ok(Command::Credentials(Some((
+            String::from("\u{1f600}"),
+            String::from("\\")
+        ))))
+    );
+    assert!(parse_request("{\"version\":1,\"command\":\"st\\xats\"}").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":\"stats\\ud83d\"}").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":\"stats\t\"}").is_err());
+    assert!(parse_request("{\"version\":01x,\"command\":\"stats\"}").is_err());
+    // Malformed
+    assert!(parse_request("{\"version\":1,\"command\":\"stats\"").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":\"stats\"}}").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":[\"stats\"]}").is_err());
+    assert!(parse_request("{\"version\":1,\"command\":\"stats\",\"command\":\"stop\"}").is_err());
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"approve\",\"address\":\"10.6.0.1\"}"),
+        Ok(Command::Approve(Ipv4Addr::new(10, 6, 0, 1)))
+    );
+    assert!(parse_request("{\"version\":1,\"command\":\"approve\"}").is_err());
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"wake\",\"address\":\"00:d9:d1:01:02:03\"}"),
+        Ok(Command::Wake(HardwareAddr::new(0x00, 0xd9, 0xd1, 1, 2, 3)))
+    );
+    assert!(
+        parse_request("{\"version\":1,\"command\":\"wake\",\"address\":\"10.6.0.1\"}").is_err()
+    );
+    assert_eq!(
+        parse_request(
+            "{\"version\":1,\"command\":\"credentials\",\"username\":\"user\",\"password\":\"pass\"}"
+        ),
+        Ok(Command::Credentials(Some((
+            String::from("user"),
+            String::from("pass")
+        ))))
+    );
+    assert!(
+        parse_request("{\"version\":1,\"command\":\"credentials\",\"username\":\"user\"}").is_err()
+    );
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"diagnostics\"}"),
+        Ok(Command::Diagnostics)
+    );
+    assert_eq!(
+        parse_request("{\"version\":1,\"command\":\"flows\"}"),
+        Ok(Command::Flows)
+    );
+}
+
+#[test]
+fn ipc_flows() {
+    use crate::packet::layer::LayerKinds;
+
+    let table = FlowTable::new();
+    let src: SocketAddrV4 = "10.6.0.1:50000".parse().unwrap();
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    assert_eq!(
+        flows(&table),
+        "{\"version\":1,\"type\":\"flows\",\"flows\":[]}"
+    );
+
+    table.on_flow_closed(LayerKinds::Tcp, src, dst, CloseReason::SocksError);
+    let response = flows(&table);
+    assert!(response.starts_with("{\"version\":1,\"type\":\"flows\",\"flows\":[{\"protocol\":\"TCP\",\"src\":\"10.6.0.1:50000\",\"dst\":\"1.1.1.1:80\",\"proxy\":null,"));
+    assert!(response.ends_with("\"sent\":0,\"received\":0,\"reason\":\"socks_error\"}]}"));
+}
+
+#[test]
+fn ipc_authorize() {
+    let line = "{\"version\":1,\"command\":\"stop\",\"token\":\"secret\"}";
+    assert_eq!(authorize(line, Some("secret")), Ok(()));
+    assert!(authorize(line, Some("secreT")).is_err());
+    assert!(authorize("{\"version\":1,\"command\":\"stop\"}", Some("secret")).is_err());
+    assert_eq!(authorize(line, None), Ok(()));
+}
+
+#[test]
+fn ipc_escape() {
+    let s = "p\"a\\s\ns\u{1}";
+    assert_eq!(escape(s), "p\\\"a\\\\s\\u000as\\u0001");
+    assert_eq!(
+        string(&format!("\"{}\"", escape(s))),
+        Some((s.to_string(), ""))
+    );
+}
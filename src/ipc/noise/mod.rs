@@ -0,0 +1,381 @@
+//! Support for encrypting IPC connections over TCP with the Noise Protocol Framework.
+//!
+//! Connections are encrypted in `Noise_NNpsk0_25519_ChaChaPoly_SHA256` with an empty prologue,
+//! where both sides prove the pre-shared key in the handshake, and agree on ephemeral X25519 keys,
+//! so recorded sessions cannot be decrypted even if the key leaks later. Each Noise message is
+//! prefixed with its length in a big-endian 16-bit integer, and each write is carried in its own
+//! transport message.
+
+use snow::{Builder, HandshakeState, TransportState};
+use std::cmp::min;
+use std::fmt::{self, Debug, Formatter};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Represents the name of the Noise protocol.
+const PROTOCOL_NAME: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+/// Represents the size of keys.
+const KEY_SIZE: usize = 32;
+/// Represents the size of the authentication tag of ChaCha20-Poly1305.
+const TAG_SIZE: usize = 16;
+/// Represents the max size of a Noise message.
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Represents a pre-shared key of IPC connections.
+#[derive(Clone, Eq, PartialEq)]
+pub struct IpcKey([u8; KEY_SIZE]);
+
+impl IpcKey {
+    /// Creates a new `IpcKey` of the bytes.
+    pub fn new(key: [u8; KEY_SIZE]) -> IpcKey {
+        IpcKey(key)
+    }
+}
+
+impl Debug for IpcKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // Never print the key
+        f.debug_tuple("IpcKey").finish()
+    }
+}
+
+impl FromStr for IpcKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid key {}, please use 64 hexadecimal digits, like the output of openssl rand -hex 32",
+                s
+            )
+        };
+
+        if s.len() != KEY_SIZE * 2 || !s.is_ascii() {
+            return Err(err());
+        }
+        let mut key = [0u8; KEY_SIZE];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| err())?;
+        }
+
+        Ok(IpcKey(key))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Returns the IO error of an error of Noise in the handshake.
+fn handshake_error(e: snow::Error) -> io::Error {
+    match e {
+        // The pre-shared key is mixed into the key of the payload
+        snow::Error::Decrypt => invalid_data("key mismatch"),
+        e => invalid_data(&format!("invalid Noise handshake: {}", e)),
+    }
+}
+
+/// Returns the initial handshake state of the pre-shared key for the initiator or the responder.
+fn handshake(key: &IpcKey, is_initiator: bool) -> io::Result<HandshakeState> {
+    let params = PROTOCOL_NAME.parse().map_err(handshake_error)?;
+    let builder = Builder::new(params).psk(0, &key.0);
+    let state = match is_initiator {
+        true => builder.build_initiator(),
+        false => builder.build_responder(),
+    };
+
+    state.map_err(handshake_error)
+}
+
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let size = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; size];
+    stream.read_exact(&mut message).await?;
+
+    Ok(message)
+}
+
+async fn write_message<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+
+    stream.flush().await
+}
+
+/// Performs the handshake as the responder over the stream, and returns the encrypted stream. The
+/// handshake fails if the initiator does not have the same key.
+pub async fn accept<S>(mut stream: S, key: &IpcKey) -> io::Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = handshake(key, false)?;
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+    // -> psk, e
+    let message = read_message(&mut stream).await?;
+    state
+        .read_message(&message, &mut buffer)
+        .map_err(handshake_error)?;
+
+    // <- e, ee
+    let size = state
+        .write_message(&[], &mut buffer)
+        .map_err(handshake_error)?;
+    write_message(&mut stream, &buffer[..size]).await?;
+
+    let transport = state.into_transport_mode().map_err(handshake_error)?;
+
+    Ok(NoiseStream::new(stream, transport))
+}
+
+/// Performs the handshake as the initiator over the stream, and returns the encrypted stream.
+pub async fn connect<S>(mut stream: S, key: &IpcKey) -> io::Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = handshake(key, true)?;
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+    // -> psk, e
+    let size = state
+        .write_message(&[], &mut buffer)
+        .map_err(handshake_error)?;
+    write_message(&mut stream, &buffer[..size]).await?;
+
+    // <- e, ee
+    let message = read_message(&mut stream).await?;
+    state
+        .read_message(&message, &mut buffer)
+        .map_err(handshake_error)?;
+
+    let transport = state.into_transport_mode().map_err(handshake_error)?;
+
+    Ok(NoiseStream::new(stream, transport))
+}
+
+/// Represents a stream encrypted in Noise transport messages. Reads return the payloads of the
+/// messages regardless of their boundaries. Each write is sent in a message, which is written
+/// completely before the write returns, so callers have to retry with the same data after
+/// `Poll::Pending`, like `write_all` does.
+pub struct NoiseStream<S> {
+    stream: S,
+    transport: TransportState,
+    read_buffer: Vec<u8>,
+    payload: Vec<u8>,
+    payload_offset: usize,
+    write_buffer: Vec<u8>,
+    write_offset: usize,
+    accepted: usize,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(stream: S, transport: TransportState) -> NoiseStream<S> {
+        NoiseStream {
+            stream,
+            transport,
+            read_buffer: Vec::new(),
+            payload: Vec::new(),
+            payload_offset: 0,
+            write_buffer: Vec::new(),
+            write_offset: 0,
+            accepted: 0,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    /// Writes the buffered message to the underlying stream.
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_offset < self.write_buffer.len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer[self.write_offset..])
+            {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)))
+                }
+                Poll::Ready(Ok(n)) => self.write_offset += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.write_buffer.clear();
+        self.write_offset = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> Debug for NoiseStream<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("NoiseStream")
+            .field("tx", &self.transport.sending_nonce())
+            .field("rx", &self.transport.receiving_nonce())
+            .finish()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        loop {
+            // Payload
+            if stream.payload_offset < stream.payload.len() {
+                let size = min(
+                    stream.payload.len() - stream.payload_offset,
+                    buf.remaining(),
+                );
+                buf.put_slice(&stream.payload[stream.payload_offset..stream.payload_offset + size]);
+                stream.payload_offset += size;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            // Message
+            if stream.read_buffer.len() >= 2 {
+                let size =
+                    u16::from_be_bytes([stream.read_buffer[0], stream.read_buffer[1]]) as usize;
+                if stream.read_buffer.len() >= 2 + size {
+                    let message = stream
+                        .read_buffer
+                        .drain(..2 + size)
+                        .skip(2)
+                        .collect::<Vec<_>>();
+                    let mut payload = vec![0u8; message.len()];
+                    let size = stream
+                        .transport
+                        .read_message(&message, &mut payload)
+                        .map_err(|_| invalid_data("cannot decrypt the Noise message"))?;
+                    payload.truncate(size);
+                    stream.payload = payload;
+                    stream.payload_offset = 0;
+                    continue;
+                }
+            }
+
+            let mut data = [0u8; 4096];
+            let mut data_buf = ReadBuf::new(&mut data);
+            match Pin::new(&mut stream.stream).poll_read(cx, &mut data_buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if data_buf.filled().is_empty() {
+                // Closed between messages
+                if stream.read_buffer.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            stream.read_buffer.extend_from_slice(data_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if stream.write_buffer.is_empty() {
+            let size = min(buf.len(), MAX_MESSAGE_SIZE - TAG_SIZE);
+            // Fails rather than reusing a nonce once the counter is exhausted
+            let mut message = vec![0u8; size + TAG_SIZE];
+            if let Err(ref e) = stream.transport.write_message(&buf[..size], &mut message) {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("cannot encrypt the Noise message: {}", e),
+                )));
+            }
+            stream
+                .write_buffer
+                .extend_from_slice(&(message.len() as u16).to_be_bytes());
+            stream.write_buffer.extend(message);
+            stream.accepted = size;
+        }
+
+        match stream.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(min(stream.accepted, buf.len()))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        match stream.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut stream.stream).poll_flush(cx),
+            poll => poll,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        match stream.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut stream.stream).poll_shutdown(cx),
+            poll => poll,
+        }
+    }
+}
+
+#[test]
+fn ipc_key_parse() {
+    let key: IpcKey = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+        .parse()
+        .unwrap();
+    assert_eq!(key.0[31], 0x1f);
+    assert_eq!(format!("{:?}", key), "IpcKey");
+    assert!("0001".parse::<IpcKey>().is_err());
+    assert!(
+        "zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            .parse::<IpcKey>()
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn noise_stream_exchange() {
+    use tokio::io::AsyncBufReadExt;
+
+    let key = IpcKey::new([7u8; KEY_SIZE]);
+
+    let (client, server) = io::duplex(1 << 20);
+    let server_key = key.clone();
+    let server = tokio::spawn(async move {
+        let mut stream = accept(server, &server_key).await.unwrap();
+        let mut line = String::new();
+        io::BufReader::new(&mut stream)
+            .read_line(&mut line)
+            .await
+            .unwrap();
+        assert_eq!(line, "{\"version\":1,\"command\":\"stats\"}\n");
+        // Larger than a message
+        stream.write_all(&vec![0x2a; 100000]).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+    let mut stream = connect(client, &key).await.unwrap();
+    stream
+        .write_all(b"{\"version\":1,\"command\":\"stats\"}\n")
+        .await
+        .unwrap();
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, vec![0x2a; 100000]);
+    server.await.unwrap();
+
+    // Another key
+    let (client, server) = io::duplex(1024);
+    let server = tokio::spawn(async move { accept(server, &key).await.map(|_| ()) });
+    let result = connect(client, &IpcKey::new([8u8; KEY_SIZE])).await;
+    assert!(server.await.unwrap().is_err());
+    assert!(result.is_err());
+}
@@ -0,0 +1,168 @@
+//! Support for probing the liveness of designated long-lived TCP connections.
+
+use log::warn;
+use std::collections::HashSet;
+use std::net::{SocketAddr, SocketAddrV4};
+
+use crate::filter::BlockList;
+use crate::packet::layer::LayerKinds;
+
+/// Represents a list of TCP connections which are probed periodically in both directions, so a
+/// stalled relay can be told from a disconnect by the server.
+pub struct KeepaliveList {
+    list: BlockList,
+}
+
+impl KeepaliveList {
+    /// Parses a keep-alive list. Each line contains a rule like `keepalive 10.0.0.1:3074` or
+    /// `keepalive * from 192.168.1.10`, where the part after `keepalive` follows the syntax of the
+    /// block list except for server names, and `#` starts a comment.
+    pub fn parse(s: &str) -> KeepaliveList {
+        let rules = s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut v = line.splitn(2, char::is_whitespace);
+                match (v.next(), v.next()) {
+                    (Some("keepalive"), Some(rule)) => Some(rule.trim()),
+                    _ => {
+                        warn!("Ignore invalid keep-alive list line: {}", line);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let list = BlockList::parse_list(&rules.join("\n"), "keep-alive list");
+        if list.has_names() {
+            warn!("Ignore server names in the keep-alive list: TCP connections are matched by their destinations");
+        }
+
+        KeepaliveList { list }
+    }
+
+    /// Sets the offset of the local time to UTC in minutes, which applies to schedules.
+    pub fn set_utc_offset(&mut self, offset: i32) {
+        self.list.set_utc_offset(offset);
+    }
+
+    /// Returns the number of rules in the keep-alive list.
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Returns if the keep-alive list contains no rule.
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Returns if the TCP connection from the source to the destination should be probed.
+    pub fn is_designated(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        self.list
+            .is_blocked(Some(LayerKinds::Tcp), Some(*src.ip()), dst)
+    }
+}
+
+/// Returns the distinct proxies in order, so each proxy is probed once however many connections
+/// in the keep-alive list go through it.
+pub fn distinct_upstreams<I: IntoIterator<Item = SocketAddr>>(upstreams: I) -> Vec<SocketAddr> {
+    let mut set = HashSet::new();
+
+    upstreams
+        .into_iter()
+        .filter(|upstream| set.insert(*upstream))
+        .collect()
+}
+
+/// Represents the liveness of one side of a connection. A side is considered as not responding
+/// after the given number of probes in a row are missed, until it answers again.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Liveness {
+    is_pending: bool,
+    missed: usize,
+    is_down: bool,
+}
+
+impl Liveness {
+    /// Creates a new `Liveness`.
+    pub fn new() -> Liveness {
+        Liveness::default()
+    }
+
+    /// Records a probe about to be sent, where the previous probe is missed if it has not been
+    /// answered yet. Returns if the side has just stopped responding.
+    pub fn probe(&mut self, max: usize) -> bool {
+        let is_down = self.is_pending && self.miss(max);
+        self.is_pending = true;
+
+        is_down
+    }
+
+    /// Records a missed probe, and returns if the side has just stopped responding.
+    pub fn miss(&mut self, max: usize) -> bool {
+        self.missed = self.missed.saturating_add(1);
+        if self.missed >= max && !self.is_down {
+            self.is_down = true;
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Records an answer of the side, and returns if the side has just resumed responding.
+    pub fn answer(&mut self) -> bool {
+        self.is_pending = false;
+        self.missed = 0;
+
+        std::mem::replace(&mut self.is_down, false)
+    }
+
+    /// Returns if the side is not responding.
+    pub fn is_down(&self) -> bool {
+        self.is_down
+    }
+}
+
+#[test]
+fn keepalive_list_parse() {
+    let list = KeepaliveList::parse(
+        "keepalive 10.0.0.1:3074 # Xbox Live\nkeepalive * from 192.168.1.10\nblock 10.0.0.2",
+    );
+    assert_eq!(list.len(), 2);
+    let src = "192.168.1.20:50000".parse().unwrap();
+    assert!(list.is_designated(src, "10.0.0.1:3074".parse().unwrap()));
+    assert!(!list.is_designated(src, "10.0.0.1:443".parse().unwrap()));
+    assert!(list.is_designated(
+        "192.168.1.10:50000".parse().unwrap(),
+        "10.0.0.3:443".parse().unwrap()
+    ));
+}
+
+#[test]
+fn liveness_probe() {
+    let mut liveness = Liveness::new();
+    assert!(!liveness.probe(2));
+    assert!(!liveness.answer());
+
+    // 2 probes missed in a row
+    assert!(!liveness.probe(2));
+    assert!(!liveness.probe(2));
+    assert!(liveness.probe(2));
+    assert!(!liveness.probe(2));
+    assert!(liveness.is_down());
+    assert!(liveness.answer());
+    assert!(!liveness.is_down());
+
+    assert!(!liveness.miss(2));
+    assert!(liveness.miss(2));
+}
+
+#[test]
+fn distinct_upstreams_dedup() {
+    let a: SocketAddr = "10.0.0.1:1080".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1080".parse().unwrap();
+    assert_eq!(distinct_upstreams(vec![a, b, a, a, b]), vec![a, b]);
+    assert!(distinct_upstreams(Vec::new()).is_empty());
+}
@@ -5,40 +5,121 @@
 use ipnetwork::Ipv4Network;
 use log::{debug, info, trace, warn};
 use lru::LruCache;
+use pnet::packet::ip::IpNextHeaderProtocols;
 use rand::{self, Rng};
 use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::fs;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+#[cfg(feature = "dns")]
+use std::sync::Weak;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io;
 
+pub mod adaptive;
+pub mod affinity;
+#[cfg(feature = "alert")]
+pub mod alert;
+#[cfg(feature = "capture")]
+pub mod capture;
+pub mod config;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+pub mod dns;
+pub mod filter;
+#[cfg(unix)]
+pub mod handover;
+#[cfg(feature = "history")]
+pub mod history;
+#[cfg(feature = "ipc")]
+pub mod ipc;
+pub mod keepalive;
+#[cfg(feature = "multicast")]
+pub mod multicast;
+pub mod names;
+pub mod observer;
+pub mod oui;
 pub mod packet;
 pub mod pcap;
+pub mod pool;
 pub mod proxy;
+pub mod qos;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod quota;
+pub mod stack;
+pub mod stats;
+pub mod stun;
 pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tunnel;
+#[cfg(feature = "wol")]
+pub mod wol;
 
+use self::proxy::probe;
 pub use self::proxy::ProxyConfig;
-use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, StreamWorker};
+use self::proxy::{
+    Batching, DatagramWorker, DirectTransport, ForwardDatagram, ForwardStream, ProxyTransport,
+    StreamWorker, UdpPortStrategy,
+};
+use adaptive::AdaptiveRouter;
+#[cfg(feature = "alert")]
+use alert::{AlertEvent, Alerter};
+#[cfg(feature = "capture")]
+use capture::{Capture, CaptureSender};
+#[cfg(feature = "diagnostics")]
+use diagnostics::Diagnostics;
+#[cfg(feature = "discovery")]
+use discovery::Discovery;
+#[cfg(all(feature = "dns", feature = "tls"))]
+use dns::DnsUpstream;
+#[cfg(feature = "dns")]
+use dns::{DnsCache, DnsRedirect};
+use dns::{Hosts, Message};
+use filter::{PacketFilter, RateLimiter, Verdict};
+#[cfg(unix)]
+use handover::{Association, Handover};
+use keepalive::{KeepaliveList, Liveness};
+#[cfg(feature = "multicast")]
+use multicast::MulticastWorker;
+use names::{NamePolicy, NameService};
+use observer::{CloseReason, DnsRoute, FlowObserver};
+use packet::builder::{EthernetBuilder, TcpBuilder};
 use packet::layer::arp::Arp;
-use packet::layer::ethernet::Ethernet;
 use packet::layer::icmpv4::Icmpv4;
-use packet::layer::ipv4::Ipv4;
+use packet::layer::ipv4::{self, Ipv4, TtlPolicy};
 use packet::layer::tcp::Tcp;
 use packet::layer::udp::Udp;
-use packet::layer::{Layer, LayerKinds, Layers};
-use packet::{Defraggler, Indicator};
+use packet::layer::{Layer, LayerKind, LayerKinds, Layers};
+use packet::template::UdpTemplate;
+use packet::{Defraggler, Indicator, Parsers};
 use pcap::Interface;
-use pcap::{HardwareAddr, Receiver, Sender};
-use tcp::{TcpRxState, TcpTxState};
+#[cfg(feature = "capture")]
+use pcap::{BlackHole, Mirror, MirrorSender};
+use pcap::{Dump, HardwareAddr, Receiver, Sender, Timestamp};
+use pool::{BufferPool, PooledBuffer};
+use qos::{Classifier, DeficitQueue, FlowClass};
+#[cfg(feature = "quic")]
+use quic::Initial;
+use quota::{QuotaPolicy, QuotaTracker};
+use stats::{ClientStats, FlowStats, LatencyStats, Stage, StageStats};
+use tcp::{TcpRxState, TcpTxState, Timer};
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
     pcap::interfaces()
         .into_iter()
-        .filter(|inter| inter.is_up() && !inter.is_loopback())
+        .filter(|inter| inter.is_up() && !inter.is_loopback() && !inter.is_point_to_point())
         .collect()
 }
 
@@ -61,6 +142,69 @@ pub fn interface(name: Option<String>) -> Option<Interface> {
     }
 }
 
+/// Represents the total memory below which the machine is of low memory, like home routers.
+const LOW_MEMORY_THRESHOLD: u64 = 256 * 1024 * 1024;
+
+/// Represents if the machine is of low memory, 0 for unknown, 1 for low memory and 2 for not.
+static LOW_MEMORY: AtomicU8 = AtomicU8::new(0);
+
+/// Returns the total memory of the machine in Bytes. Only Linux is supported.
+pub fn total_memory() -> Option<u64> {
+    // Read the procfs rather than calling sysinfo, which works in static builds against musl too
+    let s = fs::read_to_string("/proc/meminfo").ok()?;
+
+    parse_meminfo(&s)
+}
+
+fn parse_meminfo(s: &str) -> Option<u64> {
+    let line = s.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let size = line["MemTotal:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(size * 1024)
+}
+
+/// Returns if the machine is of low memory, where smaller buffers will be used.
+pub fn is_low_memory() -> bool {
+    match LOW_MEMORY.load(Ordering::Relaxed) {
+        0 => {
+            let is_low_memory = match total_memory() {
+                Some(size) => size < LOW_MEMORY_THRESHOLD,
+                None => false,
+            };
+            LOW_MEMORY.store(
+                match is_low_memory {
+                    true => 1,
+                    false => 2,
+                },
+                Ordering::Relaxed,
+            );
+
+            is_low_memory
+        }
+        1 => true,
+        _ => false,
+    }
+}
+
+/// Returns if the token matches the expected one. The comparison takes the same time wherever the
+/// tokens differ, so the expected token cannot be guessed byte by byte.
+pub fn verify_token(token: &str, expected: &str) -> bool {
+    if token.len() != expected.len() {
+        return false;
+    }
+
+    token
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
 /// Represents the max distance of `u32` values between packets in an `u32` window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
@@ -72,27 +216,115 @@ const ENABLE_RECV_SWS_AVOID: bool = true;
 /// Represents if the send-side silly window syndrome avoidance, Clark's algorithm, is enabled.
 const ENABLE_SEND_SWS_AVOID: bool = true;
 
-/// Represents if the delayed ACK is enabled.
+/// Represents if the delayed ACK is enabled by default.
 const ENABLE_DELAYED_ACK: bool = true;
 
 /// Represents if the TCP MSS option is enabled.
 const ENABLE_MSS: bool = true;
 
+/// Represents if the path MTU blackhole detection is enabled.
+const ENABLE_BLACKHOLE_DETECTION: bool = true;
+/// Represents the number of times a write of full-sized segments to the proxy is reported as
+/// stalled before a path MTU blackhole is detected.
+const BLACKHOLE_STALLS: usize = 3;
+/// Represents the plateaus of MTU which the advertised MTU will be reduced to in turn when a path
+/// MTU blackhole is detected.
+const BLACKHOLE_MTU_PLATEAUS: [usize; 5] = [1452, 1400, 1280, 1006, 576];
+/// Represents the time after which a reduced MTU is raised to the previous plateau, so the MTU
+/// recovers once the blackhole is gone.
+const BLACKHOLE_REPROBE_INTERVAL: u64 = 600000;
+
+/// Represents if small UDP packets are serialized from cached headers.
+const ENABLE_UDP_FAST_PATH: bool = true;
+/// Represents the max size of the payload of UDP packets in the fast path.
+const UDP_FAST_PATH_SIZE: usize = 256;
+/// Represents the max number of UDP flows whose headers are cached.
+const MAX_UDP_TEMPLATES: usize = 1024;
+
 /// Represents the minimum frame size.
 /// Because all traffic is in Ethernet, and the 802.3 specifies the minimum is 64 Bytes.
 /// Exclude the 4 bytes used in FCS, the minimum frame size in pcap2socks is 60 Bytes.
 const MINIMUM_FRAME_SIZE: usize = 60;
 
+/// Represents the max number of redirected UDP flows.
+const MAX_UDP_REDIRECT: usize = 1024;
+
+/// Represents the max number of pending DNS queries whose responses are fit for the sources.
+#[cfg(feature = "dns")]
+const MAX_PENDING_DNS_QUERIES: usize = 1024;
+
+/// Represents the max number of QUIC connection IDs of servers tracked.
+#[cfg(feature = "quic")]
+const MAX_QUIC_CIDS: usize = 1024;
+
+/// Represents the minimum timeout in milliseconds of idle UDP ports of encrypted tunnels.
+const TUNNEL_TIMEOUT: u64 = 10 * 60 * 1000;
+
+/// Represents the number of keep-alive probes missed in a row before a side of a TCP connection is
+/// considered as not responding.
+const KEEPALIVE_PROBES: usize = 3;
+
+/// Represents the time after an interactive frame in which bulk frames are throttled.
+const INTERACTIVE_WINDOW: u64 = 100;
+/// Represents the max number of bulk frames sent after each frame in throttling.
+const BULK_BURST: usize = 4;
+/// Represents the max number of queued bulk frames.
+const MAX_BULK_QUEUE: usize = 1024;
+
 /// Represents a channel forward traffic to the source in pcap.
 pub struct Forwarder {
     tx: Sender,
     src_mtu_map: HashMap<Ipv4Addr, usize>,
+    blackhole_mtu_map: HashMap<Ipv4Addr, (usize, Timer)>,
     local_mtu: usize,
     src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
     local_hardware_addr: HardwareAddr,
     local_ip_addr: Ipv4Addr,
     ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
     states: HashMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    client_stats: HashMap<Ipv4Addr, ClientStats>,
+    #[cfg(feature = "dns")]
+    dns_cache: Option<DnsCache>,
+    #[cfg(feature = "dns")]
+    dns_redirect: Option<DnsRedirect>,
+    /// Represents the LRU mapping a DNS query to the UDP payload size of the source, if the source
+    /// supports EDNS, and if the query has been resolved over TCP.
+    #[cfg(feature = "dns")]
+    dns_queries: LruCache<(SocketAddrV4, SocketAddrV4, u16), (u16, bool, bool)>,
+    /// Represents the proxy and the forwarder itself, which truncated DNS responses will be
+    /// retried over TCP through.
+    #[cfg(feature = "dns")]
+    dns_fallback: Option<(ProxyConfig, Weak<Mutex<Forwarder>>)>,
+    /// Represents the LRU mapping a redirected target and a source to the original destination.
+    udp_redirects: LruCache<(SocketAddrV4, SocketAddrV4), SocketAddrV4>,
+    /// Represents the LRU mapping a QUIC connection ID chosen by a server to the source.
+    #[cfg(feature = "quic")]
+    quic_cids: LruCache<Vec<u8>, SocketAddrV4>,
+    #[cfg(feature = "quic")]
+    quic_cid_lens: HashSet<usize>,
+    ttl: u8,
+    /// Represents the LRU mapping a UDP flow to its cached headers.
+    udp_templates: LruCache<(SocketAddrV4, SocketAddrV4), UdpTemplate>,
+    /// Represents the sources of encrypted tunnels, whose replies are not inspected.
+    tunnel_srcs: HashSet<SocketAddrV4>,
+    stages: Option<Arc<Mutex<StageStats>>>,
+    is_udp_activity: bool,
+    /// Represents the map mapping a UDP source to the instant of the last datagram sent to it.
+    udp_activities: HashMap<SocketAddrV4, Instant>,
+    /// Represents the map mapping a source to its external mapping learned from STUN.
+    external_addrs: HashMap<SocketAddrV4, SocketAddrV4>,
+    /// Represents the map mapping an external mapping learned from STUN to its source.
+    external_srcs: HashMap<SocketAddrV4, SocketAddrV4>,
+    observer: Option<Arc<dyn FlowObserver>>,
+    is_qos: bool,
+    /// Represents the rate limiter of the traffic to sources.
+    rate_limiter: Option<RateLimiter>,
+    classifier: Classifier,
+    bulk_queue: DeficitQueue<Ipv4Addr, (SocketAddrV4, SocketAddrV4), PooledBuffer>,
+    pool: BufferPool,
+    interactive_timer: Option<Timer>,
+    is_delayed_ack: bool,
+    is_delayed_ack_bulk_only: bool,
     traffic: Option<Arc<AtomicUsize>>,
     count: Option<Arc<AtomicUsize>>,
 }
@@ -120,12 +352,44 @@ impl Forwarder {
         Forwarder {
             tx,
             src_mtu_map: HashMap::new(),
+            blackhole_mtu_map: HashMap::new(),
             local_mtu: mtu,
             src_hardware_addr_map: HashMap::new(),
             local_hardware_addr,
             local_ip_addr,
             ipv4_identification_map: HashMap::new(),
             states: HashMap::new(),
+            client_stats: HashMap::new(),
+            #[cfg(feature = "dns")]
+            dns_cache: None,
+            #[cfg(feature = "dns")]
+            dns_redirect: None,
+            #[cfg(feature = "dns")]
+            dns_queries: LruCache::new(MAX_PENDING_DNS_QUERIES),
+            #[cfg(feature = "dns")]
+            dns_fallback: None,
+            udp_redirects: LruCache::new(MAX_UDP_REDIRECT),
+            #[cfg(feature = "quic")]
+            quic_cids: LruCache::new(MAX_QUIC_CIDS),
+            #[cfg(feature = "quic")]
+            quic_cid_lens: HashSet::new(),
+            ttl: ipv4::TTL,
+            udp_templates: LruCache::new(MAX_UDP_TEMPLATES),
+            tunnel_srcs: HashSet::new(),
+            stages: None,
+            is_udp_activity: false,
+            udp_activities: HashMap::new(),
+            external_addrs: HashMap::new(),
+            external_srcs: HashMap::new(),
+            observer: None,
+            is_qos: false,
+            rate_limiter: None,
+            classifier: Classifier::new(),
+            bulk_queue: DeficitQueue::new(),
+            pool: BufferPool::new(),
+            interactive_timer: None,
+            is_delayed_ack: ENABLE_DELAYED_ACK,
+            is_delayed_ack_bulk_only: false,
             traffic,
             count,
         }
@@ -153,11 +417,30 @@ impl Forwarder {
     pub fn set_src_hardware_addr(&mut self, src_ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
         self.src_hardware_addr_map
             .insert(src_ip_addr, hardware_addr);
-        trace!(
-            "set source hardware address of {} to {}",
-            src_ip_addr,
-            hardware_addr
-        );
+        match oui::vendor(hardware_addr) {
+            Some(vendor) => trace!(
+                "set source hardware address of {} to {} ({})",
+                src_ip_addr,
+                hardware_addr,
+                vendor
+            ),
+            None => trace!(
+                "set source hardware address of {} to {}",
+                src_ip_addr,
+                hardware_addr
+            ),
+        }
+    }
+
+    /// Sets the latencies of stages, which will record the time injecting each frame.
+    pub fn set_stages(&mut self, stages: Arc<Mutex<StageStats>>) {
+        self.stages = Some(stages);
+        trace!("set stages");
+    }
+
+    /// Returns the hardware address of the source.
+    pub fn src_hardware_addr(&self, src_ip_addr: Ipv4Addr) -> Option<HardwareAddr> {
+        self.src_hardware_addr_map.get(&src_ip_addr).copied()
     }
 
     /// Sets the local IP address.
@@ -166,6 +449,92 @@ impl Forwarder {
         trace!("set local IP address to {}", ip_addr);
     }
 
+    /// Sets the DNS cache.
+    #[cfg(feature = "dns")]
+    pub fn set_dns_cache(&mut self, cache: DnsCache) {
+        self.dns_cache = Some(cache);
+        trace!("set DNS cache");
+    }
+
+    /// Sets the DNS server which DNS queries will be redirected to.
+    #[cfg(feature = "dns")]
+    pub fn set_dns_server(&mut self, server: SocketAddrV4) {
+        self.dns_redirect = Some(DnsRedirect::new(server));
+        trace!("set DNS server to {}", server);
+    }
+
+    /// Sets the proxy and the forwarder itself, which enables EDNS in DNS queries and retries
+    /// truncated DNS responses over TCP through the proxy.
+    #[cfg(feature = "dns")]
+    pub fn set_dns_fallback(&mut self, proxy: ProxyConfig, tx: Weak<Mutex<Forwarder>>) {
+        self.dns_fallback = Some((proxy, tx));
+        trace!("set DNS fallback");
+    }
+
+    /// Sets the observer of flows.
+    pub fn set_observer(&mut self, observer: Arc<dyn FlowObserver>) {
+        self.observer = Some(observer);
+        trace!("set observer");
+    }
+
+    /// Sets the mirror which all the frames sent to sources will be copied to.
+    #[cfg(feature = "capture")]
+    pub fn set_mirror(&mut self, mirror: Arc<Mutex<Mirror>>) {
+        let tx = std::mem::replace(&mut self.tx, Box::new(BlackHole::new()));
+        self.tx = Box::new(MirrorSender::new(tx, mirror));
+        trace!("set mirror");
+    }
+
+    /// Sets the capture which the frames sent to sources in its flows will be copied to.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(&mut self, capture: Arc<Capture>) {
+        let tx = std::mem::replace(&mut self.tx, Box::new(BlackHole::new()));
+        self.tx = Box::new(CaptureSender::new(tx, capture));
+        trace!("set capture");
+    }
+
+    /// Sets if frames should be scheduled for QoS. Frames with payload of bulk TCP connections
+    /// will be queued and sent in turn, and throttled while interactive frames are being sent.
+    pub fn set_qos(&mut self, is_qos: bool) {
+        self.is_qos = is_qos;
+        trace!("set QoS to {}", is_qos);
+    }
+
+    /// Sets the rate limiter of the traffic to sources. Frames with payload beyond the rate are
+    /// dropped, and TCP segments are retransmitted later.
+    pub fn set_rate_limit(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Some(rate_limiter);
+        trace!("set rate limit");
+    }
+
+    /// Sets if TCP ACKs without payload to sources are delayed, so every two segments from a
+    /// source are acknowledged by one ACK, or by the next segment sent to the source.
+    pub fn set_delayed_ack(&mut self, is_delayed_ack: bool) {
+        self.is_delayed_ack = is_delayed_ack;
+        trace!("set delayed ACK to {}", is_delayed_ack);
+    }
+
+    /// Sets if TCP ACKs are delayed only in bulk TCP connections, so interactive ones are
+    /// acknowledged immediately.
+    pub fn set_delayed_ack_bulk_only(&mut self, is_bulk_only: bool) {
+        self.is_delayed_ack_bulk_only = is_bulk_only;
+        trace!("set delayed ACK bulk only to {}", is_bulk_only);
+    }
+
+    /// Sets the TTL of the packets synthesized toward sources.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+        self.udp_templates.clear();
+        trace!("set TTL to {}", ttl);
+    }
+
+    /// Sets the weight of the client in scheduling queued bulk frames. Clients are scheduled in
+    /// deficit round-robin, and are of weight 1 by default.
+    pub fn set_client_weight(&mut self, ip_addr: Ipv4Addr, weight: usize) {
+        self.bulk_queue.set_weight(ip_addr, weight);
+        trace!("set client {} weight to {}", ip_addr, weight);
+    }
+
     fn increase_ipv4_identification(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) {
         let entry = self
             .ipv4_identification_map
@@ -191,7 +560,80 @@ impl Forwarder {
     pub fn clean_up(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
         let key = (src, dst);
 
-        self.states.remove(&key);
+        self.classifier.remove(dst, src);
+
+        if let Some(state) = self.states.remove(&key) {
+            // Statistics
+            self.client_stats
+                .entry(*src.ip())
+                .or_insert_with(|| ClientStats::new(*src.ip()))
+                .add_flow(&state.stats(), false);
+        }
+    }
+
+    /// Reduces the MTU advertised to the source to the next plateau because of a path MTU
+    /// blackhole on the way to the proxy. The reduced MTU is advertised in the MSS option of new
+    /// TCP connections from the source, and will be raised again after a while.
+    fn reduce_advertised_mtu(&mut self, src_ip_addr: Ipv4Addr) {
+        let mtu = self.get_advertised_mtu(src_ip_addr);
+        let next_mtu = match BLACKHOLE_MTU_PLATEAUS
+            .iter()
+            .find(|plateau| **plateau < mtu)
+        {
+            Some(plateau) => *plateau,
+            None => return,
+        };
+
+        self.blackhole_mtu_map.insert(
+            src_ip_addr,
+            (next_mtu, Timer::new(BLACKHOLE_REPROBE_INTERVAL)),
+        );
+        info!(
+            "Detect a path MTU blackhole from {}, reduce MTU from {} to {}",
+            src_ip_addr, mtu, next_mtu
+        );
+    }
+
+    /// Returns the MTU advertised to the source. A MTU reduced because of a path MTU blackhole is
+    /// raised to the previous plateau once in an interval to probe if the blackhole is gone, like
+    /// the packetization layer path MTU discovery (RFC 4821).
+    fn get_advertised_mtu(&mut self, src_ip_addr: Ipv4Addr) -> usize {
+        let (mtu, timer) = match self.blackhole_mtu_map.get(&src_ip_addr) {
+            Some(entry) => entry,
+            None => return self.local_mtu,
+        };
+        if !timer.is_timedout() {
+            return *mtu;
+        }
+
+        let mtu = *mtu;
+        match BLACKHOLE_MTU_PLATEAUS
+            .iter()
+            .rev()
+            .find(|plateau| **plateau > mtu && **plateau < self.local_mtu)
+        {
+            Some(plateau) => {
+                self.blackhole_mtu_map.insert(
+                    src_ip_addr,
+                    (*plateau, Timer::new(BLACKHOLE_REPROBE_INTERVAL)),
+                );
+                debug!(
+                    "probe path MTU from {}: raise MTU from {} to {}",
+                    src_ip_addr, mtu, plateau
+                );
+
+                *plateau
+            }
+            None => {
+                self.blackhole_mtu_map.remove(&src_ip_addr);
+                debug!(
+                    "probe path MTU from {}: restore MTU from {} to {}",
+                    src_ip_addr, mtu, self.local_mtu
+                );
+
+                self.local_mtu
+            }
+        }
     }
 
     /// Returns the source MTU.
@@ -220,6 +662,261 @@ impl Forwarder {
         self.states.get_mut(&key)
     }
 
+    /// Returns the cached DNS response of a DNS query.
+    #[cfg(feature = "dns")]
+    pub fn get_dns_cache(&mut self, query: &[u8]) -> Option<Vec<u8>> {
+        let response = match &mut self.dns_cache {
+            Some(cache) => cache.get(query)?,
+            None => return None,
+        };
+
+        // Cached responses may be received with EDNS
+        if self.dns_fallback.is_some() {
+            if let (Some(query), Some(message)) = (Message::parse(query), Message::parse(&response))
+            {
+                let size = query
+                    .udp_payload_size()
+                    .unwrap_or(dns::MAX_UDP_PAYLOAD_SIZE);
+                let is_edns = query.udp_payload_size().is_some();
+
+                return Some(dns::fit_response(&response, &message, size, is_edns));
+            }
+        }
+
+        Some(response)
+    }
+
+    /// Sets a DNS query from the source to the destination pending, whose response will be fit for
+    /// the source, and returns the query advertising EDNS to be sent instead if it is not resolved
+    /// over TCP.
+    #[cfg(feature = "dns")]
+    pub fn set_dns_query(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        query: &[u8],
+        is_tcp: bool,
+    ) -> Option<Vec<u8>> {
+        self.dns_fallback.as_ref()?;
+        let message = Message::parse(query).filter(|message| !message.is_response())?;
+        if message.opcode() != 0 {
+            return None;
+        }
+
+        let size = message.udp_payload_size();
+        self.dns_queries.put(
+            (src, dst, message.id()),
+            (
+                size.unwrap_or(dns::MAX_UDP_PAYLOAD_SIZE),
+                size.is_some(),
+                is_tcp,
+            ),
+        );
+        trace!("set DNS query {} -> {} ({})", src, dst, message.id());
+
+        match is_tcp {
+            true => None,
+            false => Some(dns::set_udp_payload_size(
+                query,
+                &message,
+                dns::EDNS_PAYLOAD_SIZE,
+            )),
+        }
+    }
+
+    /// Fits a DNS response from the server for the source of the pending query, and returns the
+    /// response to be sent, or `None` if the query is retried over TCP because the response is
+    /// truncated.
+    #[cfg(feature = "dns")]
+    fn fit_dns_response(
+        &mut self,
+        server: SocketAddrV4,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        response: &[u8],
+    ) -> Option<Vec<u8>> {
+        let message = match Message::parse(response).filter(|message| message.is_response()) {
+            Some(message) => message,
+            None => return Some(response.to_vec()),
+        };
+        let (size, is_edns, is_retried) = match self.dns_queries.pop(&(src, dst, message.id())) {
+            Some(query) => query,
+            None => return Some(response.to_vec()),
+        };
+
+        // TCP fallback
+        if message.is_truncated() && !is_retried {
+            if let (Some((proxy, tx)), Some(query), Ok(handle)) = (
+                &self.dns_fallback,
+                dns::query_of(response, &message),
+                tokio::runtime::Handle::try_current(),
+            ) {
+                debug!("retry DNS over TCP: {} -> {}", src, server);
+                let (proxy, tx) = (proxy.clone(), tx.clone());
+                let (id, response) = (message.id(), response.to_vec());
+                handle.spawn(async move {
+                    let response = match proxy::resolve_tcp(&proxy, server, &query).await {
+                        Ok(response) => response,
+                        Err(ref e) => {
+                            warn!("handle send: {}: {} -> {}: {}", "DNS", src, server, e);
+                            response
+                        }
+                    };
+                    if let Some(tx) = tx.upgrade() {
+                        let mut tx_locked = tx.lock().unwrap();
+                        tx_locked
+                            .dns_queries
+                            .put((src, dst, id), (size, is_edns, true));
+                        if let Err(ref e) =
+                            ForwardDatagram::forward(&mut *tx_locked, server, src, &response)
+                        {
+                            warn!("handle receive: {}: {} -> {}: {}", "DNS", server, src, e);
+                        }
+                    }
+                });
+
+                return None;
+            }
+        }
+
+        Some(dns::fit_response(response, &message, size, is_edns))
+    }
+
+    /// Redirects a DNS query to the DNS server, and returns the actual destination of the query.
+    #[cfg(feature = "dns")]
+    pub fn redirect_dns(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> SocketAddrV4 {
+        match &mut self.dns_redirect {
+            Some(redirect) => redirect.redirect(src, dst),
+            None => dst,
+        }
+    }
+
+    /// Redirects a UDP flow to the target, and replies from the target will be restored to the
+    /// original destination.
+    pub fn redirect_udp(&mut self, dst: SocketAddrV4, src: SocketAddrV4, target: SocketAddrV4) {
+        self.udp_redirects.put((target, src), dst);
+        trace!("redirect UDP {} -> {} to {}", src, dst, target);
+    }
+
+    /// Returns the source of the QUIC connection of the packet in the short header, which is known
+    /// by the connection IDs in the long headers from servers.
+    #[cfg(feature = "quic")]
+    pub fn quic_src(&mut self, payload: &[u8]) -> Option<SocketAddrV4> {
+        let mut lens = self.quic_cid_lens.iter().copied().collect::<Vec<_>>();
+        lens.sort_unstable();
+        lens.into_iter()
+            .filter(|len| payload.len() > 1 + len)
+            .find_map(|len| self.quic_cids.get(&payload[1..1 + len].to_vec()).copied())
+    }
+
+    /// Moves the QUIC connections of a source to another source.
+    #[cfg(feature = "quic")]
+    pub fn migrate_quic(&mut self, prev_src: SocketAddrV4, src: SocketAddrV4) {
+        for (_, cid_src) in self.quic_cids.iter_mut() {
+            if *cid_src == prev_src {
+                *cid_src = src;
+            }
+        }
+    }
+
+    /// Sets if the source is of an encrypted tunnel, whose replies will be relayed without
+    /// inspection.
+    pub fn set_tunnel_src(&mut self, src: SocketAddrV4, is_tunnel: bool) {
+        let is_changed = match is_tunnel {
+            true => self.tunnel_srcs.insert(src),
+            false => self.tunnel_srcs.remove(&src),
+        };
+        if is_changed {
+            trace!("set tunnel of {} to {}", src, is_tunnel);
+        }
+    }
+
+    /// Sets the external mapping of a source, which answers the hairpinned traffic from other
+    /// sources.
+    pub fn set_external_addr(&mut self, src: SocketAddrV4, external: SocketAddrV4) {
+        if let Some(prev_external) = self.external_addrs.insert(src, external) {
+            if prev_external == external {
+                return;
+            }
+            self.external_srcs.remove(&prev_external);
+        }
+        if let Some(prev_src) = self.external_srcs.insert(external, src) {
+            self.external_addrs.remove(&prev_src);
+        }
+        trace!("set external address of {} to {}", src, external);
+    }
+
+    /// Removes the external mapping of a source.
+    pub fn remove_external_addr(&mut self, src: SocketAddrV4) {
+        if let Some(external) = self.external_addrs.remove(&src) {
+            self.external_srcs.remove(&external);
+            trace!("remove external address {} of {}", external, src);
+        }
+    }
+
+    /// Sets if the instant of the last datagram sent to each UDP source is recorded, which keeps
+    /// idle UDP ports of sources receiving datagrams from being released.
+    pub fn set_udp_activity(&mut self, is_udp_activity: bool) {
+        self.is_udp_activity = is_udp_activity;
+        trace!("set UDP activity to {}", is_udp_activity);
+    }
+
+    /// Returns the instant of the last datagram sent to the UDP source.
+    pub fn udp_activity(&self, src: SocketAddrV4) -> Option<Instant> {
+        self.udp_activities.get(&src).copied()
+    }
+
+    /// Removes the instant of the last datagram sent to the UDP source.
+    pub fn remove_udp_activity(&mut self, src: SocketAddrV4) {
+        self.udp_activities.remove(&src);
+    }
+
+    /// Returns the external mapping of a source.
+    pub fn external_addr(&self, src: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.external_addrs.get(&src).copied()
+    }
+
+    /// Returns the source of an external mapping.
+    pub fn external_src(&self, external: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.external_srcs.get(&external).copied()
+    }
+
+    /// Returns the size of data buffered in all the TCP connections.
+    pub fn buffer_size(&self) -> usize {
+        self.states
+            .values()
+            .map(|state| state.cache().len() + state.queue().len())
+            .sum()
+    }
+
+    /// Returns the statistics of all the TCP connections.
+    pub fn flow_stats(&self) -> Vec<FlowStats> {
+        self.states.values().map(|state| state.stats()).collect()
+    }
+
+    /// Returns the statistics of all the sources, including the closed TCP connections.
+    pub fn client_stats(&self) -> Vec<ClientStats> {
+        let mut client_stats = self.client_stats.clone();
+        for state in self.states.values() {
+            let stats = state.stats();
+            client_stats
+                .entry(*stats.src().ip())
+                .or_insert_with(|| ClientStats::new(*stats.src().ip()))
+                .add_flow(&stats, true);
+        }
+
+        for (ip_addr, stats) in client_stats.iter_mut() {
+            if let Some(hardware_addr) = self.src_hardware_addr_map.get(ip_addr) {
+                stats.set_hardware_addr(*hardware_addr);
+            }
+        }
+
+        let mut client_stats = client_stats.values().copied().collect::<Vec<_>>();
+        client_stats.sort_by_key(|stats| stats.ip_addr());
+
+        client_stats
+    }
+
     fn get_tcp_window(&self, dst: SocketAddrV4, src: SocketAddrV4) -> u16 {
         let key = (src, dst);
 
@@ -274,16 +971,38 @@ impl Forwarder {
         self.send_ethernet(pcap::HARDWARE_ADDR_BROADCAST, Layers::Arp(arp), None, None)
     }
 
-    /// Sends an ICMPv4 echo reply packet.
+    /// Sends an ARP probe packet of the local IP address.
+    pub fn send_arp_probe(&mut self) -> io::Result<()> {
+        // ARP
+        let arp = Arp::new_probe(self.local_hardware_addr, self.local_ip_addr);
+
+        // Send
+        self.send_ethernet(pcap::HARDWARE_ADDR_BROADCAST, Layers::Arp(arp), None, None)
+    }
+
+    /// Sends a Wake-on-LAN magic packet of the hardware address in a UDP broadcast.
+    #[cfg(feature = "wol")]
+    pub fn send_wake(&mut self, hardware_addr: HardwareAddr) -> io::Result<()> {
+        let payload = wol::magic_packet(hardware_addr);
+
+        self.send_udp(
+            SocketAddrV4::new(self.local_ip_addr, wol::WOL_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, wol::WOL_PORT),
+            &payload,
+        )
+    }
+
+    /// Sends an ICMPv4 echo reply packet carrying the data of the echo request.
     pub fn send_icmpv4_echo_reply(
         &mut self,
         dst_ip_addr: Ipv4Addr,
         src_ip_addr: Ipv4Addr,
         identifier: u16,
         sequence_number: u16,
+        data: &[u8],
     ) -> io::Result<()> {
         // ICMPv4
-        let icmpv4 = Icmpv4::new_echo_reply(identifier, sequence_number);
+        let icmpv4 = Icmpv4::new_echo_reply_with_data(identifier, sequence_number, data);
 
         self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
     }
@@ -345,7 +1064,7 @@ impl Forwarder {
 
         // Congestion control
         if let Some(cc) = &mut state.cc_mut() {
-            cc.fast_retransmission();
+            cc.fast_retransmission(recv_next);
         }
 
         // Find all disjointed ranges
@@ -402,6 +1121,11 @@ impl Forwarder {
                     // Send
                     self.send_tcp_ack(dst, src, range.0, payload.as_slice(), false)?;
                 }
+
+                // Statistics
+                self.get_state_mut(dst, src)
+                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                    .add_retrans(payload.len());
             }
         }
 
@@ -420,6 +1144,52 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Retransmits the first unacknowledged TCP segment from the cache. This method is used for
+    /// partial acknowledgements in fast recovery.
+    pub fn retransmit_tcp_first(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        let mss = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu)
+            - (Ipv4::minimum_len() + Tcp::minimum_len());
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let sequence = state.cache().sequence();
+        let recv_next = state.cache().recv_next();
+
+        let size = min(mss, state.cache().len());
+        let payload = state.cache().get(sequence, size)?;
+        if payload.len() > 0 {
+            let is_fin = sequence
+                .checked_add(payload.len() as u32)
+                .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence))
+                == recv_next
+                && state.cache_fin().is_some();
+            trace!(
+                "retransmit TCP ACK{} ({} Bytes) {} -> {} from {}",
+                if is_fin { "/FIN" } else { "" },
+                payload.len(),
+                dst,
+                src,
+                sequence
+            );
+
+            // Send
+            self.send_tcp_ack(dst, src, sequence, payload.as_slice(), is_fin)?;
+
+            // Statistics
+            self.get_state_mut(dst, src)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                .add_retrans(payload.len());
+        } else if state.cache_fin().is_some() {
+            // FIN
+            trace!("retransmit TCP FIN {} -> {}", dst, src);
+
+            // Send
+            self.send_tcp_fin(dst, src)?;
+        }
+
+        Ok(())
+    }
+
     /// Retransmits timed out TCP packets from the cache. This method is used for transmitting
     /// timed out data.
     pub fn retransmit_tcp_timedout(
@@ -445,6 +1215,9 @@ impl Forwarder {
                     cc.timedout();
                 }
 
+                // Statistics
+                state.add_retrans(payload.len());
+
                 // If all the cache is get, the FIN should also be sent
                 if size == payload.len() && state.cache_fin().is_some() {
                     // ACK/FIN
@@ -488,6 +1261,20 @@ impl Forwarder {
             }
         }
 
+        // Zero window probe
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if let Some(timer) = state.persist() {
+            if timer.is_timedout() {
+                state.update_persist_timer();
+                trace!("probe TCP zero window {} -> {}", dst, src);
+
+                // Send
+                self.send_tcp_window_probe(dst, src)?;
+            }
+        }
+
         // Delayed ACK0
         let state = self
             .get_state(dst, src)
@@ -531,6 +1318,7 @@ impl Forwarder {
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
                 let payload = state.append_cache(size)?;
+                state.add_sent(payload.len());
 
                 // If the queue is empty and a FIN is in the queue, pop it
                 if state.queue().is_empty() && state.queue_fin() {
@@ -552,6 +1340,12 @@ impl Forwarder {
                     self.send_tcp_ack(dst, src, sequence, &payload, false)?;
                 }
             }
+        } else if !state.queue().is_empty() && state.cache().is_empty() && state.persist().is_none()
+        {
+            // Start persisting if nothing is in flight to be retransmitted
+            self.get_state_mut(dst, src)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                .update_persist_timer();
         }
 
         // If the queue is empty and a FIN is in the queue, pop it
@@ -654,12 +1448,17 @@ impl Forwarder {
 
     /// Sends an TCP delayed ACK packet without payload.
     pub fn send_tcp_delay_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        if ENABLE_DELAYED_ACK {
+        let is_bulk_only = self.is_delayed_ack_bulk_only;
+        if self.is_delayed_ack {
             let state = self
                 .get_state_mut(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-            if state.delayed_ack() {
+            // Acknowledge interactive TCP connections immediately, which are classified by the
+            // bytes received from the source since the ACKs answer its segments
+            if state.delayed_ack()
+                || (is_bulk_only && qos::classify(state.received()) == FlowClass::Interactive)
+            {
                 self.send_tcp_ack_0(dst, src)?;
             } else {
                 state.set_delayed_ack();
@@ -699,10 +1498,32 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Sends an TCP keep-alive probe, which elicits an ACK from the source.
+    pub fn send_tcp_keepalive(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        trace!("send TCP keep-alive {} -> {}", dst, src);
+        self.send_tcp_window_probe(dst, src)
+    }
+
+    fn send_tcp_window_probe(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        // TCP, the sequence before the send next elicits an ACK with the current window
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let tcp = TcpBuilder::new(dst.port(), src.port())
+            .sequence(state.sequence().wrapping_sub(1))
+            .ack(state.acknowledgement())
+            .window(self.get_tcp_window(dst, src))
+            .build();
+
+        // Send
+        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+    }
+
     fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         let mss = match ENABLE_MSS {
             true => {
-                let mss = self.local_mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+                let mtu = self.get_advertised_mtu(*src.ip());
+                let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
                 let mss = if mss > u16::MAX as usize {
                     u16::MAX
                 } else {
@@ -783,6 +1604,23 @@ impl Forwarder {
         self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
     }
 
+    /// Sends an TCP ACK/RST packet refusing a TCP SYN without a state.
+    pub fn send_tcp_ack_rst_refused(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        // TCP
+        let tcp = TcpBuilder::new(dst.port(), src.port())
+            .ack(acknowledgement)
+            .rst()
+            .build();
+
+        // Send
+        self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+    }
+
     /// Sends an TCP RST packet.
     pub fn send_tcp_rst(
         &mut self,
@@ -822,38 +1660,167 @@ impl Forwarder {
         src: SocketAddrV4,
         payload: &[u8],
     ) -> io::Result<()> {
+        self.send_udp_with_ttl(dst, src, payload, self.ttl)
+    }
+
+    /// Sends UDP packets with the given TTL instead of the one of synthesized packets.
+    pub fn send_udp_with_ttl(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &[u8],
+        ttl: u8,
+    ) -> io::Result<()> {
+        if self.is_udp_activity {
+            self.udp_activities.insert(src, Instant::now());
+        }
+
+        if ENABLE_UDP_FAST_PATH && payload.len() < UDP_FAST_PATH_SIZE {
+            return self.send_udp_fast(dst, src, payload, ttl);
+        }
+
         // UDP
         let udp = Udp::new(dst.port(), src.port());
 
-        self.send_ipv4(
+        self.send_ipv4_with_ttl(
             dst.ip().clone(),
             src.ip().clone(),
             Layers::Udp(udp),
             Some(payload),
+            ttl,
         )
     }
 
-    fn send_ipv4(
+    /// Sends a small UDP packet from the cached headers of the flow, which are rebuilt if the
+    /// hardware address of the source or the TTL changes.
+    fn send_udp_fast(
         &mut self,
-        dst_ip_addr: Ipv4Addr,
-        src_ip_addr: Ipv4Addr,
-        mut transport: Layers,
-        payload: Option<&[u8]>,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &[u8],
+        ttl: u8,
     ) -> io::Result<()> {
-        // Fragmentation
-        let size = &transport.len()
-            + match payload {
-                Some(payload) => payload.len(),
-                None => 0,
-            };
-        let mss = *self
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("inject", %src, %dst, size = payload.len()).entered();
+        let instant = self.stages.as_ref().map(|_| Instant::now());
+
+        // Template
+        let hardware_addr = self.get_hardware_addr(*src.ip());
+        let template = self
+            .udp_templates
+            .get(&(dst, src))
+            .copied()
+            .filter(|template| template.dst() == hardware_addr && template.ttl() == ttl);
+        let template = match template {
+            Some(template) => template,
+            None => {
+                let mut ipv4 = Ipv4::new(0, LayerKinds::Udp, *dst.ip(), *src.ip()).unwrap();
+                ipv4.set_ttl(ttl);
+                let indicator = EthernetBuilder::new(self.local_hardware_addr, hardware_addr)
+                    .network(Layers::Ipv4(ipv4))
+                    .transport(Layers::Udp(Udp::new(dst.port(), src.port())))
+                    .build()
+                    .unwrap();
+                let template = UdpTemplate::new(&indicator)?;
+                trace!("cache UDP template {} -> {}", dst, src);
+                self.udp_templates.put((dst, src), template);
+
+                template
+            }
+        };
+
+        // Rate limit
+        if !payload.is_empty()
+            && self.is_rate_limited(*src.ip(), template.header_len() + payload.len())
+        {
+            trace!("drop UDP {} -> {}: rate limited", dst, src);
+
+            return Ok(());
+        }
+
+        let identification = *self
+            .ipv4_identification_map
+            .get(&(*src.ip(), *dst.ip()))
+            .unwrap_or(&0);
+
+        // Serialize and send
+        let size = template.header_len() + payload.len();
+        let buffer_size = max(size, MINIMUM_FRAME_SIZE);
+        let mut result = None;
+        self.tx
+            .build_and_send(1, buffer_size, &mut |buffer| {
+                if let Err(e) = template.serialize(&mut buffer[..size], identification, payload) {
+                    result = Some(e);
+                }
+            })
+            .unwrap_or(Ok(()))?;
+        match result {
+            Some(e) => return Err(e),
+            None => debug!(
+                "send to pcap: {} -> {} in the fast path ({} + {} Bytes)",
+                dst,
+                src,
+                template.header_len(),
+                payload.len()
+            ),
+        }
+        if let (Some(stages), Some(instant)) = (&self.stages, instant) {
+            stages
+                .lock()
+                .unwrap()
+                .record(Stage::Inject, instant.elapsed());
+        }
+
+        // Update IPv4 identification
+        self.increase_ipv4_identification(*dst.ip(), *src.ip());
+
+        // Monitor
+        if let Some(download) = &self.traffic {
+            download.fetch_add(buffer_size, Ordering::Relaxed);
+        }
+        if let Some(count) = &self.count {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if self.is_qos {
+            self.flush_bulk()?;
+        }
+
+        Ok(())
+    }
+
+    fn send_ipv4(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        transport: Layers,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        self.send_ipv4_with_ttl(dst_ip_addr, src_ip_addr, transport, payload, self.ttl)
+    }
+
+    fn send_ipv4_with_ttl(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        mut transport: Layers,
+        payload: Option<&[u8]>,
+        ttl: u8,
+    ) -> io::Result<()> {
+        // Fragmentation
+        let size = &transport.len()
+            + match payload {
+                Some(payload) => payload.len(),
+                None => 0,
+            };
+        let mss = *self
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu)
             - Ipv4::minimum_len();
         if size <= mss {
             // IPv4
-            let ipv4 = Ipv4::new(
+            let mut ipv4 = Ipv4::new(
                 *self
                     .ipv4_identification_map
                     .get(&(src_ip_addr, dst_ip_addr))
@@ -863,20 +1830,11 @@ impl Forwarder {
                 src_ip_addr,
             )
             .unwrap();
-
-            // Set IPv4 layer for checksum
-            match transport {
-                Layers::Tcp(ref mut tcp) => tcp.set_ipv4_layer(&ipv4),
-                Layers::Udp(ref mut udp) => udp.set_ipv4_layer(&ipv4),
-                _ => {}
-            }
+            ipv4.set_ttl(ttl);
 
             // Send
             self.send_ethernet(
-                *self
-                    .src_hardware_addr_map
-                    .get(&src_ip_addr)
-                    .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                self.get_hardware_addr(src_ip_addr),
                 Layers::Ipv4(ipv4),
                 Some(transport),
                 payload,
@@ -893,14 +1851,14 @@ impl Forwarder {
             }
 
             // Payload
-            let mut buffer = vec![0u8; size];
+            let mut buffer = self.pool.get(size);
             match payload {
                 Some(payload) => transport.serialize_with_payload(
-                    buffer.as_mut_slice(),
+                    &mut buffer,
                     payload,
                     transport.len() + payload.len(),
                 )?,
-                None => transport.serialize(buffer.as_mut_slice(), transport.len())?,
+                None => transport.serialize(&mut buffer, transport.len())?,
             };
 
             let mut n = 0;
@@ -920,7 +1878,7 @@ impl Forwarder {
                 }
 
                 // IPv4
-                let ipv4 = if remain > 0 {
+                let mut ipv4 = if remain > 0 {
                     Ipv4::new_more_fragment(
                         *self
                             .ipv4_identification_map
@@ -945,13 +1903,11 @@ impl Forwarder {
                     )
                     .unwrap()
                 };
+                ipv4.set_ttl(ttl);
 
                 // Send
                 self.send_ethernet(
-                    *self
-                        .src_hardware_addr_map
-                        .get(&src_ip_addr)
-                        .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                    self.get_hardware_addr(src_ip_addr),
                     Layers::Ipv4(ipv4),
                     None,
                     Some(&buffer[n..n + length]),
@@ -967,6 +1923,22 @@ impl Forwarder {
         Ok(())
     }
 
+    fn get_hardware_addr(&self, src_ip_addr: Ipv4Addr) -> HardwareAddr {
+        if src_ip_addr.is_broadcast() {
+            return pcap::HARDWARE_ADDR_BROADCAST;
+        }
+        if src_ip_addr.is_multicast() {
+            // Map the lower 23 bits of the group (RFC 1112)
+            let octets = src_ip_addr.octets();
+            return HardwareAddr::new(0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]);
+        }
+
+        *self
+            .src_hardware_addr_map
+            .get(&src_ip_addr)
+            .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED)
+    }
+
     fn send_ethernet(
         &mut self,
         src_hardware_addr: HardwareAddr,
@@ -974,18 +1946,31 @@ impl Forwarder {
         transport: Option<Layers>,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
-        // Ethernet
-        let ethernet =
-            Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("inject", dst = %src_hardware_addr).entered();
+        let instant = self.stages.as_ref().map(|_| Instant::now());
 
         // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+        let mut builder =
+            EthernetBuilder::new(self.local_hardware_addr, src_hardware_addr).network(network);
+        if let Some(transport) = transport {
+            builder = builder.transport(transport);
+        }
+        let indicator = builder.build().unwrap();
 
         // Send
-        match payload {
+        let result = match payload {
             Some(payload) => self.send_with_payload(&indicator, payload),
             None => self.send(&indicator),
+        };
+        if let (Some(stages), Some(instant)) = (&self.stages, instant) {
+            stages
+                .lock()
+                .unwrap()
+                .record(Stage::Inject, instant.elapsed());
         }
+
+        result
     }
 
     fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
@@ -1014,10 +1999,54 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Returns if a frame of the given size with payload to the source is beyond its rate limit.
+    fn is_rate_limited(&mut self, src_ip_addr: Ipv4Addr, size: usize) -> bool {
+        match &mut self.rate_limiter {
+            Some(rate_limiter) => !rate_limiter.admit(src_ip_addr, size, Instant::now()),
+            None => false,
+        }
+    }
+
     fn send_with_payload(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
-        // Serialize and send
         let size = indicator.len();
         let buffer_size = max(size + payload.len(), MINIMUM_FRAME_SIZE);
+
+        // Rate limit
+        if !payload.is_empty() {
+            if let Some(ipv4) = indicator.ipv4() {
+                if self.is_rate_limited(ipv4.dst(), size + payload.len()) {
+                    trace!("drop {}: rate limited", indicator.brief());
+
+                    return Ok(());
+                }
+            }
+        }
+
+        // QoS
+        if self.is_qos {
+            if let (Some(ipv4), Some(tcp)) = (indicator.ipv4(), indicator.tcp()) {
+                let dst = SocketAddrV4::new(ipv4.src(), tcp.src());
+                let src = SocketAddrV4::new(ipv4.dst(), tcp.dst());
+                if self.classifier.add(dst, src, payload.len()) == FlowClass::Bulk {
+                    let mut frame = self.pool.get(buffer_size);
+                    indicator
+                        .serialize_with_payload(&mut frame[..size + payload.len()], payload)?;
+                    trace!(
+                        "queue bulk {} ({} + {} Bytes)",
+                        indicator.brief(),
+                        size,
+                        payload.len()
+                    );
+                    self.bulk_queue
+                        .push(*src.ip(), (src, dst), frame, buffer_size);
+
+                    return self.flush_bulk();
+                }
+            }
+            self.interactive_timer = Some(Timer::new(INTERACTIVE_WINDOW));
+        }
+
+        // Serialize and send
         let mut result = None;
         self.tx
             .build_and_send(1, buffer_size, &mut |buffer| {
@@ -1046,6 +2075,37 @@ impl Forwarder {
             count.fetch_add(1, Ordering::Relaxed);
         }
 
+        if self.is_qos {
+            self.flush_bulk()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_bulk(&mut self) -> io::Result<()> {
+        let is_interactive = match &self.interactive_timer {
+            Some(timer) => !timer.is_timedout(),
+            None => false,
+        };
+        let n = match is_interactive && self.bulk_queue.len() <= MAX_BULK_QUEUE {
+            true => min(BULK_BURST, self.bulk_queue.len()),
+            false => self.bulk_queue.len(),
+        };
+
+        for _ in 0..n {
+            let frame = self.bulk_queue.pop().unwrap();
+            self.tx.send_to(&frame, None).unwrap_or(Ok(()))?;
+            debug!("send to pcap: bulk ({} Bytes)", frame.len());
+
+            // Monitor
+            if let Some(download) = &self.traffic {
+                download.fetch_add(frame.len(), Ordering::Relaxed);
+            }
+            if let Some(count) = &self.count {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
         Ok(())
     }
 }
@@ -1070,11 +2130,19 @@ impl ForwardStream for Forwarder {
             return Err(io::Error::from(io::ErrorKind::InvalidData));
         }
 
+        // Observer
+        if let Some(observer) = &self.observer {
+            observer.on_bytes_received(LayerKinds::Tcp, dst, src, payload.len());
+        }
+
         self.queue_tcp(dst, src, payload)
     }
 
     fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        self.retransmit_tcp_timedout(dst, src)
+        self.retransmit_tcp_timedout(dst, src)?;
+
+        // Flush bulk frames left in throttling
+        self.flush_bulk()
     }
 
     fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
@@ -1087,6 +2155,36 @@ impl ForwardStream for Forwarder {
         self.send_tcp(dst, src)
     }
 
+    fn reset(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        if self.get_state(dst, src).is_none() {
+            return Ok(());
+        }
+
+        self.send_tcp_ack_rst(dst, src)
+    }
+
+    fn stall(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        size: usize,
+        n: usize,
+    ) -> io::Result<()> {
+        debug!(
+            "stall TCP {} -> {} ({} Bytes) for {} times",
+            src, dst, size, n
+        );
+
+        // Path MTU blackhole, where full-sized segments from the source are stuck on the way to
+        // the proxy
+        let mss = self.get_advertised_mtu(*src.ip()) - (Ipv4::minimum_len() + Tcp::minimum_len());
+        if ENABLE_BLACKHOLE_DETECTION && n == BLACKHOLE_STALLS && size >= mss {
+            self.reduce_advertised_mtu(*src.ip());
+        }
+
+        Ok(())
+    }
+
     fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize> {
         let state = self
             .get_state(dst, src)
@@ -1097,6 +2195,86 @@ impl ForwardStream for Forwarder {
 
 impl ForwardDatagram for Forwarder {
     fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "dns")]
+        let server = dst;
+
+        // DNS redirection
+        #[cfg(feature = "dns")]
+        let dst = match &mut self.dns_redirect {
+            Some(redirect) => redirect.restore(dst, src),
+            None => dst,
+        };
+
+        // UDP redirection
+        let dst = match self.udp_redirects.get(&(dst, src)) {
+            Some(&original) => original,
+            None => dst,
+        };
+
+        // DNS cache
+        if dst.port() == dns::DNS_PORT {
+            #[cfg(feature = "dns")]
+            {
+                if let Some(cache) = &mut self.dns_cache {
+                    cache.insert(payload);
+                }
+            }
+        }
+
+        // EDNS
+        #[cfg(feature = "dns")]
+        let fitted;
+        #[cfg(feature = "dns")]
+        let payload = if dst.port() == dns::DNS_PORT && self.dns_fallback.is_some() {
+            match self.fit_dns_response(server, dst, src, payload) {
+                Some(response) => {
+                    fitted = response;
+                    fitted.as_slice()
+                }
+                None => return Ok(()),
+            }
+        } else {
+            payload
+        };
+
+        if dst.port() == dns::DNS_PORT {
+            if let Some(observer) = &self.observer {
+                if let Some(message) = Message::parse(payload) {
+                    if message.is_response() {
+                        observer.on_dns_response(dst, src, &message);
+                    }
+                }
+                observer.on_dns_message(src, dst, payload, Some(DnsRoute::Proxy));
+            }
+        }
+
+        // Encrypted tunnels, which are relayed without inspecting the payload
+        if !self.tunnel_srcs.contains(&src) {
+            // STUN
+            if let Some(message) = stun::Message::parse(payload) {
+                if message.is_binding_response() {
+                    debug!("STUN binding response {} -> {}", dst, src);
+                    if let Some(external) = message.mapped_addr() {
+                        self.set_external_addr(src, external);
+                    }
+                }
+            }
+
+            // QUIC
+            #[cfg(feature = "quic")]
+            if let Some((_, _, scid)) = quic::long_header(payload) {
+                if !scid.is_empty() && self.quic_cids.put(scid.to_vec(), src).is_none() {
+                    self.quic_cid_lens.insert(scid.len());
+                    trace!("set QUIC connection {:02x?} of {}", scid, src);
+                }
+            }
+        }
+
+        // Observer
+        if let Some(observer) = &self.observer {
+            observer.on_bytes_received(LayerKinds::Udp, dst, src, payload.len());
+        }
+
         self.send_udp(dst, src, payload)
     }
 }
@@ -1168,6 +2346,34 @@ const MAX_RECV_WSCALE: u8 = 8;
 /// Represents the max limit of UDP port for binding in local.
 const MAX_UDP_PORT: usize = 256;
 
+/// Represents the number of ARP probes before publishing.
+const ARP_PROBE_NUM: usize = 3;
+/// Represents the interval between ARP probes.
+const ARP_PROBE_INTERVAL: u64 = 200;
+/// Represents the wait time for conflicting ARP packets after the last ARP probe.
+const ARP_PROBE_WAIT: u64 = 1000;
+/// Represents the min interval between ARP replies to the same requester.
+const ARP_REPLY_INTERVAL: u64 = 200;
+/// Represents the min interval between defending the published address with gratuitous ARP.
+const ARP_DEFEND_INTERVAL: u64 = 10000;
+/// Represents the time of not replying ARP requests after backing off from a conflict.
+const ARP_BACKOFF_TIME: u64 = 60000;
+
+/// Represents the interval of reaping idle UDP ports.
+const REAP_INTERVAL: u64 = 1000;
+
+/// Represents the interval of logging the statistics summary.
+const STATS_INTERVAL: u64 = 60000;
+
+/// Represents the interval of re-evaluating the filter on TCP connections.
+const REEVALUATE_INTERVAL: u64 = 60000;
+
+/// Represents the interval of saving the TCP flow state.
+const FLOW_STATE_INTERVAL: u64 = 10000;
+
+/// Represents the timeout of a TCP connection through the proxy measuring an ICMPv4 echo request.
+const PING_TIMEOUT: u64 = 3000;
+
 /// Represents a channel redirect traffic to the proxy or loopback to the source in pcap.
 pub struct Redirector {
     tx: Arc<Mutex<Forwarder>>,
@@ -1183,7 +2389,126 @@ pub struct Redirector {
     datagram_map: HashMap<SocketAddrV4, u16>,
     /// Represents the LRU mapping a local port to a source port.
     udp_lru: LruCache<u16, SocketAddrV4>,
+    udp_timeout: Option<u64>,
+    udp_keepalive: Option<u64>,
+    udp_port_timeouts: HashMap<u16, u64>,
+    udp_port_strategy: UdpPortStrategy,
+    tcp_port_batchings: HashMap<u16, Batching>,
+    /// Represents the map mapping a source port to its idle timer.
+    udp_timers: HashMap<SocketAddrV4, Timer>,
+    udp_mapping_path: Option<String>,
+    /// Represents the map mapping a source port to its restored local port which is not bound yet.
+    udp_restored: HashMap<SocketAddrV4, u16>,
+    is_udp_mapping_dirty: bool,
+    flow_state_path: Option<String>,
+    /// Represents the TCP connections restored from the flow state, with the sequence number and
+    /// the hardware address of their sources, which are reset once redirecting.
+    tcp_restored: Vec<(SocketAddrV4, SocketAddrV4, u32, HardwareAddr)>,
+    /// Represents the listener of the handover socket, which a new process connects to for taking
+    /// over the UDP associations.
+    #[cfg(unix)]
+    handover_listener: Option<UnixListener>,
+    /// Represents the source ports which have sent STUN binding requests. Their local ports are
+    /// kept from being reused by other sources, so the same SOCKS UDP association, hence the same
+    /// external mapping, answers to all the remote peers.
+    stun_srcs: HashSet<SocketAddrV4>,
+    /// Represents the source ports of encrypted tunnels. Like the ones of STUN, their local ports
+    /// are kept from being reused, and they are relayed without inspection, so the external
+    /// mapping under the tunnel will not change in the middle of a session.
+    tunnel_srcs: HashSet<SocketAddrV4>,
+    /// Represents the sources which have sent IPv6 in IPv4 packets, which are warned once.
+    ipv6_tunnel_srcs: HashSet<Ipv4Addr>,
+    /// Represents the addresses which are never redirected as sources, including the ones of the
+    /// proxy, so traffic of pcap2socks itself never loops back through the redirection.
+    excluded: HashSet<Ipv4Addr>,
+    stages: Option<Arc<Mutex<StageStats>>>,
+    is_hairpin: bool,
+    ttl_policy: TtlPolicy,
+    /// Represents the TTL of the frame being handled.
+    ttl: u8,
+    is_arp_probe: bool,
+    is_force_publish: bool,
+    arp_probe_timer: Option<Timer>,
+    /// Represents the hardware addresses of other hosts owning the published address.
+    arp_conflicts: HashSet<HardwareAddr>,
+    arp_reply_interval: u64,
+    /// Represents the map mapping a requester to the timer since the last ARP reply to it.
+    arp_reply_timers: HashMap<Ipv4Addr, Timer>,
+    is_arp_backoff: bool,
+    arp_backoff_timer: Option<Timer>,
+    arp_defend_timer: Option<Timer>,
+    is_qos: bool,
+    /// Represents the rate limiter of the traffic from sources.
+    rate_limiter: Option<RateLimiter>,
+    #[cfg(feature = "multicast")]
+    multicast_groups: Vec<SocketAddrV4>,
+    /// Represents the map mapping a multicast group to sources joining it.
+    #[cfg(feature = "multicast")]
+    multicast_members: HashMap<Ipv4Addr, HashSet<Ipv4Addr>>,
+    #[cfg(feature = "multicast")]
+    multicast_workers: HashMap<SocketAddrV4, MulticastWorker>,
     defrag: Defraggler,
+    hosts: Option<Hosts>,
+    #[cfg(feature = "dns")]
+    dns_tcp: bool,
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    dns_upstream: Option<Arc<DnsUpstream>>,
+    name_policy: NamePolicy,
+    client_name_policies: HashMap<Ipv4Addr, NamePolicy>,
+    /// Represents the sources whose queries of local name resolution have been blocked, which are
+    /// reported once.
+    name_blocked: HashSet<(Ipv4Addr, NameService)>,
+    parsers: Option<Parsers>,
+    is_strict: bool,
+    malformed: usize,
+    malformed_reported: usize,
+    malformed_dump: Option<Dump>,
+    #[cfg(feature = "capture")]
+    mirror: Option<Arc<Mutex<Mirror>>>,
+    #[cfg(feature = "capture")]
+    capture: Option<Arc<Capture>>,
+    adaptive: Option<AdaptiveRouter>,
+    keepalive: Option<KeepaliveList>,
+    keepalive_interval: u64,
+    /// Represents the liveness of the sources of TCP connections in the keep-alive list.
+    keepalive_flows: HashMap<(SocketAddrV4, SocketAddrV4), Liveness>,
+    /// Represents the liveness of the proxies the TCP connections in the keep-alive list are
+    /// connected to, which are updated by the probing tasks.
+    keepalive_upstreams: Arc<Mutex<HashMap<SocketAddr, Liveness>>>,
+    timestamp: Timestamp,
+    socks_errors: usize,
+    #[cfg(feature = "alert")]
+    alerter: Option<Alerter>,
+    /// Represents the number of consecutive errors connecting to the proxy.
+    #[cfg(feature = "alert")]
+    connect_errors: usize,
+    #[cfg(feature = "alert")]
+    is_upstream_down: bool,
+    #[cfg(feature = "alert")]
+    malformed_alerted: usize,
+    #[cfg(feature = "alert")]
+    is_buffer_full: bool,
+    snapshot: Arc<AtomicBool>,
+    ready: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    filter: Option<Box<dyn PacketFilter>>,
+    is_reevaluate: bool,
+    disabled: HashSet<LayerKind>,
+    observer: Option<Arc<dyn FlowObserver>>,
+    quota: Option<Arc<QuotaTracker>>,
+    quota_policy: QuotaPolicy,
+    direct: DirectTransport,
+    #[cfg(feature = "discovery")]
+    discovery: Option<Arc<Discovery>>,
+    #[cfg(feature = "diagnostics")]
+    diagnostics: Option<Arc<Diagnostics>>,
+    /// Represents the map mapping a source to destinations of UDP flows.
+    udp_flows: HashMap<SocketAddrV4, HashSet<SocketAddrV4>>,
+    /// Represents the map mapping a UDP flow to its target redirected by its server name.
+    udp_name_targets: HashMap<(SocketAddrV4, SocketAddrV4), SocketAddrV4>,
+    ping_port: Option<u16>,
+    /// Represents the sources and the destinations of ICMPv4 echo requests being measured.
+    pings: Arc<Mutex<HashSet<(Ipv4Addr, Ipv4Addr)>>>,
 }
 
 impl Redirector {
@@ -1207,7 +2532,97 @@ impl Redirector {
             datagrams: HashMap::new(),
             datagram_map: HashMap::new(),
             udp_lru: LruCache::new(MAX_UDP_PORT),
+            udp_timeout: None,
+            udp_keepalive: None,
+            udp_port_timeouts: HashMap::new(),
+            udp_port_strategy: UdpPortStrategy::Ephemeral,
+            tcp_port_batchings: HashMap::new(),
+            udp_timers: HashMap::new(),
+            udp_mapping_path: None,
+            udp_restored: HashMap::new(),
+            is_udp_mapping_dirty: false,
+            flow_state_path: None,
+            tcp_restored: Vec::new(),
+            #[cfg(unix)]
+            handover_listener: None,
+            stun_srcs: HashSet::new(),
+            tunnel_srcs: HashSet::new(),
+            ipv6_tunnel_srcs: HashSet::new(),
+            excluded: HashSet::new(),
+            stages: None,
+            is_hairpin: true,
+            ttl_policy: TtlPolicy::Fixed,
+            ttl: ipv4::TTL,
+            is_arp_probe: false,
+            is_force_publish: false,
+            arp_probe_timer: None,
+            arp_conflicts: HashSet::new(),
+            arp_reply_interval: ARP_REPLY_INTERVAL,
+            arp_reply_timers: HashMap::new(),
+            is_arp_backoff: false,
+            arp_backoff_timer: None,
+            arp_defend_timer: None,
+            is_qos: false,
+            rate_limiter: None,
+            #[cfg(feature = "multicast")]
+            multicast_groups: Vec::new(),
+            #[cfg(feature = "multicast")]
+            multicast_members: HashMap::new(),
+            #[cfg(feature = "multicast")]
+            multicast_workers: HashMap::new(),
             defrag: Defraggler::new(),
+            hosts: None,
+            #[cfg(feature = "dns")]
+            dns_tcp: false,
+            #[cfg(all(feature = "dns", feature = "tls"))]
+            dns_upstream: None,
+            name_policy: NamePolicy::Block,
+            client_name_policies: HashMap::new(),
+            name_blocked: HashSet::new(),
+            parsers: None,
+            is_strict: false,
+            malformed: 0,
+            malformed_reported: 0,
+            malformed_dump: None,
+            #[cfg(feature = "capture")]
+            mirror: None,
+            #[cfg(feature = "capture")]
+            capture: None,
+            adaptive: None,
+            keepalive: None,
+            keepalive_interval: 0,
+            keepalive_flows: HashMap::new(),
+            keepalive_upstreams: Arc::new(Mutex::new(HashMap::new())),
+            timestamp: Timestamp::now(),
+            socks_errors: 0,
+            #[cfg(feature = "alert")]
+            alerter: None,
+            #[cfg(feature = "alert")]
+            connect_errors: 0,
+            #[cfg(feature = "alert")]
+            is_upstream_down: false,
+            #[cfg(feature = "alert")]
+            malformed_alerted: 0,
+            #[cfg(feature = "alert")]
+            is_buffer_full: false,
+            snapshot: Arc::new(AtomicBool::new(false)),
+            ready: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            filter: None,
+            is_reevaluate: false,
+            disabled: HashSet::new(),
+            observer: None,
+            quota: None,
+            quota_policy: QuotaPolicy::Reject,
+            direct: DirectTransport::new(),
+            #[cfg(feature = "discovery")]
+            discovery: None,
+            #[cfg(feature = "diagnostics")]
+            diagnostics: None,
+            udp_flows: HashMap::new(),
+            udp_name_targets: HashMap::new(),
+            ping_port: None,
+            pings: Arc::new(Mutex::new(HashSet::new())),
         };
         if let Some(gw_ip_addr) = gw_ip_addr {
             redirector.tx.lock().unwrap().set_local_ip_addr(gw_ip_addr);
@@ -1216,125 +2631,989 @@ impl Redirector {
         redirector
     }
 
-    /// Opens an `Interface` for redirection.
-    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
-        self.open_monitored(rx, None, None, None).await
+    /// Sets the static hostname mappings.
+    pub fn set_hosts(&mut self, hosts: Hosts) {
+        self.hosts = Some(hosts);
+        trace!("set hosts");
     }
 
-    /// Opens an `Interface` for redirection and monitoring.
-    pub async fn open_monitored(
-        &mut self,
-        rx: &mut Receiver,
-        is_running: Option<Arc<AtomicBool>>,
-        traffic: Option<Arc<AtomicUsize>>,
-        count: Option<Arc<AtomicUsize>>,
-    ) -> io::Result<()> {
-        // Send gratuitous ARP
-        if self.gw_ip_addr.is_some() {
-            self.tx.lock().unwrap().send_gratuitous_arp()?;
-        }
-
-        loop {
-            // Monitor
-            if let Some(is_running) = &is_running {
-                if !is_running.load(Ordering::Relaxed) {
-                    return Ok(());
-                }
-            }
-            match rx.next() {
-                Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
-                        if let Some(t) = indicator.network_kind() {
-                            let traffic = match &traffic {
-                                Some(traffic) => Some(Arc::clone(traffic)),
-                                None => None,
-                            };
-                            let count = match &count {
-                                Some(count) => Some(Arc::clone(count)),
-                                None => None,
-                            };
-                            match t {
-                                LayerKinds::Arp => {
-                                    if let Err(ref e) = self.handle_arp(indicator, traffic, count) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                LayerKinds::Ipv4 => {
-                                    if let Err(ref e) =
-                                        self.handle_ipv4(indicator, frame, traffic, count).await
-                                    {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                    };
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                        continue;
-                    }
-                    return Err(e);
-                }
-            };
-        }
+    /// Sets the policy of handling NetBIOS-NS and LLMNR queries from sources, which are blocked by
+    /// default.
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.name_policy = policy;
+        trace!("set name policy to {}", policy);
     }
 
-    fn handle_arp(
-        &mut self,
-        indicator: &Indicator,
-        traffic: Option<Arc<AtomicUsize>>,
-        count: Option<Arc<AtomicUsize>>,
-    ) -> io::Result<()> {
-        if let Some(gw_ip_addr) = self.gw_ip_addr {
-            if let Some(arp) = indicator.arp() {
-                let src = arp.src();
-                if src != self.local_ip_addr
-                    && self.src_ip_addr.contains(src)
-                    && arp.dst() == gw_ip_addr
-                {
-                    let src = arp.src();
-                    debug!(
-                        "receive from pcap: {} ({} Bytes)",
-                        indicator.brief(),
-                        indicator.len()
-                    );
+    /// Sets the policy of handling NetBIOS-NS and LLMNR queries from the source, which overrides
+    /// the one of all sources.
+    pub fn set_client_name_policy(&mut self, ip_addr: Ipv4Addr, policy: NamePolicy) {
+        self.client_name_policies.insert(ip_addr, policy);
+        trace!("set client {} name policy to {}", ip_addr, policy);
+    }
 
-                    // Set forwarder's hardware address
-                    self.set_tx_hardware_addr(src, arp.src_hardware_addr());
+    /// Sets if DNS queries should be resolved over TCP through the proxy.
+    #[cfg(feature = "dns")]
+    pub fn set_dns_tcp(&mut self, dns_tcp: bool) {
+        self.dns_tcp = dns_tcp;
+        trace!("set DNS over TCP to {}", dns_tcp);
+    }
 
-                    // Send
-                    self.tx.lock().unwrap().send_arp_reply(src)?;
+    /// Sets the encrypted DNS server, which resolves all the DNS queries from sources instead of
+    /// the servers they are sent to.
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    pub fn set_dns_upstream(&mut self, upstream: DnsUpstream) {
+        trace!("set DNS upstream to {}", upstream);
+        self.dns_upstream = Some(Arc::new(upstream));
+    }
 
-                    // Monitor
-                    if let Some(traffic) = traffic {
-                        traffic.fetch_add(indicator.content_len(), Ordering::Relaxed);
-                    }
-                    if let Some(count) = count {
-                        count.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-            }
+    /// Returns if DNS queries are resolved in streams, where responses are not limited in size.
+    #[cfg(feature = "dns")]
+    fn is_dns_stream(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.dns_upstream.is_some() {
+            return true;
         }
 
-        Ok(())
+        self.dns_tcp
+    }
+
+    /// Sets if DNS queries should advertise EDNS, and truncated DNS responses should be retried
+    /// over TCP through the proxy.
+    #[cfg(feature = "dns")]
+    pub fn set_edns(&mut self, edns: bool) {
+        if edns {
+            let tx = Arc::downgrade(&self.tx);
+            self.tx
+                .lock()
+                .unwrap()
+                .set_dns_fallback(self.proxy.clone(), tx);
+        }
+        trace!("set EDNS to {}", edns);
+    }
+
+    /// Sets the parsers of custom layers.
+    pub fn set_parsers(&mut self, parsers: Parsers) {
+        self.parsers = Some(parsers);
+        trace!("set parsers");
+    }
+
+    /// Sets if frames should be parsed in the strict mode. In the strict mode, truncated frames
+    /// and frames with unparsable transport layers will also be dropped as malformed.
+    pub fn set_strict(&mut self, is_strict: bool) {
+        self.is_strict = is_strict;
+        trace!("set strict to {}", is_strict);
+    }
+
+    /// Sets the dump which malformed frames will be written to.
+    pub fn set_malformed_dump(&mut self, dump: Dump) {
+        self.malformed_dump = Some(dump);
+        trace!("set malformed dump");
+    }
+
+    /// Sets the mirror which all the relayed frames, from and to sources, will be copied to.
+    #[cfg(feature = "capture")]
+    pub fn set_mirror(&mut self, mirror: Mirror) {
+        let mirror = Arc::new(Mutex::new(mirror));
+        self.tx.lock().unwrap().set_mirror(Arc::clone(&mirror));
+        self.mirror = Some(mirror);
+        trace!("set mirror");
+    }
+
+    /// Sets the capture which the relayed frames, from and to sources, in its flows will be
+    /// copied to.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(&mut self, capture: Capture) {
+        let capture = Arc::new(capture);
+        self.tx.lock().unwrap().set_capture(Arc::clone(&capture));
+        self.capture = Some(capture);
+        trace!("set capture");
+    }
+
+    /// Sets the adaptive router, which routes new TCP flows to the direct path if it is faster.
+    pub fn set_adaptive(&mut self, adaptive: AdaptiveRouter) {
+        self.adaptive = Some(adaptive);
+        trace!("set adaptive router");
+    }
+
+    /// Sets the keep-alive list and the interval of its probes in milliseconds. TCP connections in
+    /// the list are probed toward both the source and the proxy, and a side missing
+    /// `KEEPALIVE_PROBES` probes in a row is logged as not responding.
+    pub fn set_keepalive(&mut self, keepalive: KeepaliveList, interval: u64) {
+        self.keepalive = Some(keepalive);
+        self.keepalive_interval = interval;
+        trace!("set keep-alive to {}", interval);
+    }
+
+    /// Returns the number of malformed frames.
+    pub fn malformed(&self) -> usize {
+        self.malformed
+    }
+
+    /// Returns the number of TCP connections which failed because of the proxy.
+    pub fn socks_errors(&self) -> usize {
+        self.socks_errors
+    }
+
+    /// Returns the flag which requests logging a snapshot of statistics once it is set.
+    pub fn snapshot_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.snapshot)
+    }
+
+    /// Returns the flag which indicates if the redirector has started redirecting.
+    pub fn ready_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.ready)
+    }
+
+    /// Returns the flag which pauses the redirection once it is set. Frames are dropped while the
+    /// redirection is paused.
+    pub fn paused_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.paused)
+    }
+
+    /// the source has neither sent nor received any datagram in the timeout.
+    /// the source has not sent any datagram in the timeout.
+    pub fn set_udp_timeout(&mut self, timeout: u64) {
+        self.udp_timeout = Some(timeout);
+        self.tx.lock().unwrap().set_udp_activity(true);
+        trace!("set UDP timeout to {}", timeout);
+    }
+
+    /// Sets the interval of keep-alive datagrams of UDP ports. An empty datagram will be sent on
+    /// the association of a local port if nothing has been sent on it during the interval, until
+    /// the port is released.
+    pub fn set_udp_keepalive(&mut self, interval: u64) {
+        self.udp_keepalive = Some(interval);
+        trace!("set UDP keep-alive to {}", interval);
+    }
+
+    /// Sets the strategy to choose the local port bound for the UDP association of a source. Some
+    /// P2P games behave better when the external port matches their source port, which is more
+    /// likely if the local port matches it in the first place.
+    pub fn set_udp_port_strategy(&mut self, strategy: UdpPortStrategy) {
+        self.udp_port_strategy = strategy;
+        trace!("set UDP port strategy to {}", strategy);
+    }
+
+    /// Sets if UDP datagrams from a source to the external mapping of another source learned from
+    /// STUN are delivered locally instead of through the proxy.
+    pub fn set_hairpin(&mut self, is_hairpin: bool) {
+        self.is_hairpin = is_hairpin;
+        trace!("set hairpin to {}", is_hairpin);
+    }
+
+    /// Sets the policy of the TTL of packets hairpinned between sources. The TTL of the packets is
+    /// the one of synthesized packets, the one of the original packets, or the one decremented
+    /// like a router.
+    pub fn set_ttl_policy(&mut self, policy: TtlPolicy) {
+        self.ttl_policy = policy;
+        trace!("set TTL policy to {}", policy);
+    }
+
+    /// Sets the port which ICMPv4 echo requests from sources are measured on. Each echo request will
+    /// be relayed as a TCP connection to the port of the destination through the proxy, and be
+    /// replied once the connection is established.
+    pub fn set_ping_port(&mut self, port: u16) {
+        self.ping_port = Some(port);
+        trace!("set ping port to {}", port);
+    }
+
+    /// Sets the idle timeout of UDP ports overriding the default one for datagrams from or to the
+    /// given port.
+    pub fn set_udp_port_timeout(&mut self, port: u16, timeout: u64) {
+        self.udp_port_timeouts.insert(port, timeout);
+        self.tx.lock().unwrap().set_udp_activity(true);
+        trace!("set UDP timeout of port {} to {}", port, timeout);
+    }
+
+    /// Sets how writes of TCP connections from or to the given port are batched to the proxy,
+    /// overriding the classification for QoS.
+    pub fn set_tcp_port_batching(&mut self, port: u16, batching: Batching) {
+        self.tcp_port_batchings.insert(port, batching);
+        trace!("set TCP batching of port {} to {}", port, batching);
+    }
+
+    /// Sets the file of UDP port mappings and restores mappings from it. The local port bound for
+    /// a source will be saved to the file, and will be bound again for the source if available
+    /// after a restart. Returns the number of restored mappings.
+    pub fn set_udp_mapping_file(&mut self, path: &str) -> io::Result<usize> {
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                for line in s.lines() {
+                    let v = line.split_whitespace().collect::<Vec<_>>();
+                    if v.len() != 2 {
+                        continue;
+                    }
+                    if let (Ok(src), Ok(local_port)) = (v[0].parse(), v[1].parse()) {
+                        self.udp_restored.insert(src, local_port);
+                    }
+                }
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        self.udp_mapping_path = Some(path.to_string());
+        trace!("set UDP mapping file to {}", path);
+
+        Ok(self.udp_restored.len())
+    }
+
+    /// Sets the file of the flow state and restores TCP connections from it. TCP connections
+    /// through the proxy cannot survive a restart, so the restored ones are reset to their
+    /// sources once redirecting, instead of being left until the sources time out. Returns the
+    /// number of restored connections.
+    pub fn set_flow_state_file(&mut self, path: &str) -> io::Result<usize> {
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                for line in s.lines() {
+                    let v = line.split_whitespace().collect::<Vec<_>>();
+                    if v.len() != 5 || v[0] != "tcp" {
+                        continue;
+                    }
+                    if let (Ok(src), Ok(dst), Ok(sequence), Ok(hardware_addr)) =
+                        (v[1].parse(), v[2].parse(), v[3].parse(), v[4].parse())
+                    {
+                        self.tcp_restored.push((src, dst, sequence, hardware_addr));
+                    }
+                }
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        self.flow_state_path = Some(path.to_string());
+        trace!("set flow state file to {}", path);
+
+        Ok(self.tcp_restored.len())
+    }
+
+    /// Restores the UDP associations and the TCP connections taken over from a previous process.
+    /// The associations keep relaying through the same connections to the proxy, so their
+    /// external mappings are kept, while the TCP connections are reset to their sources once
+    /// redirecting. Returns the number of restored associations.
+    #[cfg(unix)]
+    pub fn restore_handover(&mut self, handover: Handover) -> usize {
+        self.tcp_restored.extend(handover.connections);
+
+        let mut n = 0;
+        for association in handover.associations {
+            let src = association.src;
+            // The sockets are owned from now on, and closed if not restored
+            let (stream, socket) = unsafe {
+                (
+                    std::net::TcpStream::from_raw_fd(association.control),
+                    std::net::UdpSocket::from_raw_fd(association.socket),
+                )
+            };
+            if self.datagram_map.contains_key(&src) || self.udp_lru.len() >= self.udp_lru.cap() {
+                continue;
+            }
+
+            let (mut worker, port) =
+                match DatagramWorker::from_std(self.get_tx(), src, stream, socket) {
+                    Ok(pair) => pair,
+                    Err(ref e) => {
+                        warn!("restore UDP {}: {}", src, e);
+                        continue;
+                    }
+                };
+            if let Some(interval) = self.udp_keepalive {
+                worker.set_keepalive(interval);
+            }
+            self.datagrams.insert(port, worker);
+
+            // Update map and LRU
+            self.udp_restored.remove(&src);
+            self.datagram_map.insert(src, port);
+            self.udp_lru.put(port, src);
+            self.is_udp_mapping_dirty = true;
+
+            // Idle timer, which is extended by the next datagram of the source
+            let timeout = self
+                .udp_port_timeouts
+                .get(&src.port())
+                .copied()
+                .or(self.udp_timeout)
+                .map(|timeout| match association.is_tunnel {
+                    true => max(timeout, TUNNEL_TIMEOUT),
+                    false => timeout,
+                });
+            if let Some(timeout) = timeout {
+                self.udp_timers.insert(src, Timer::new(timeout));
+            }
+
+            if association.is_stun {
+                self.stun_srcs.insert(src);
+            }
+            if association.is_tunnel {
+                self.tunnel_srcs.insert(src);
+            }
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                if tx_locked.src_hardware_addr(*src.ip()).is_none() {
+                    tx_locked.set_src_hardware_addr(*src.ip(), association.hardware_addr);
+                }
+                if association.is_tunnel {
+                    tx_locked.set_tunnel_src(src, true);
+                }
+            }
+            trace!("restore UDP port {} = {}", port, src);
+
+            n += 1;
+        }
+
+        n
+    }
+
+    /// Sets the listener of the handover socket. Once a new process connects to it, the
+    /// redirection stops, and the UDP associations through the proxy and the TCP connections are
+    /// handed over to the new process.
+    #[cfg(unix)]
+    pub fn set_handover_listener(&mut self, listener: UnixListener) {
+        self.handover_listener = Some(listener);
+        trace!("set handover listener");
+    }
+
+    /// Sets if the published address should be probed with ARP before publishing. If another host
+    /// is found owning the address in probing, the redirection will fail unless it is forced.
+    pub fn set_arp_probe(&mut self, is_arp_probe: bool) {
+        self.is_arp_probe = is_arp_probe;
+        trace!("set ARP probe to {}", is_arp_probe);
+    }
+
+    /// Sets if the address should be published even if another host owns it.
+    pub fn set_force_publish(&mut self, is_force_publish: bool) {
+        self.is_force_publish = is_force_publish;
+        trace!("set force publish to {}", is_force_publish);
+    }
+
+    /// Sets the min interval between ARP replies to the same requester. Requests from the
+    /// requester within the interval will be ignored.
+    pub fn set_arp_reply_interval(&mut self, interval: u64) {
+        self.arp_reply_interval = interval;
+        trace!("set ARP reply interval to {}", interval);
+    }
+
+    /// Sets if publishing should back off when another host announces the published address.
+    /// ARP requests will not be replied for a while after a conflict, otherwise the published
+    /// address will be defended with a gratuitous ARP at most once in an interval, and publishing
+    /// will back off only if the conflict persists within the interval.
+    pub fn set_arp_backoff(&mut self, is_arp_backoff: bool) {
+        self.is_arp_backoff = is_arp_backoff;
+        trace!("set ARP backoff to {}", is_arp_backoff);
+    }
+
+    /// Sets the multicast groups with ports to relay. Once a source joins a group with IGMP,
+    /// pcap2socks will join the group on the host side, and relay datagrams to the port of the
+    /// group to sources until all of them leave the group.
+    #[cfg(feature = "multicast")]
+    pub fn set_multicast_groups(&mut self, groups: Vec<SocketAddrV4>) {
+        trace!("set multicast groups to {:?}", groups);
+        self.multicast_groups = groups;
+    }
+
+    /// Sets if flows should be scheduled for QoS. Interactive flows will be prioritized over bulk
+    /// flows in both sending to sources and sending to the proxy.
+    pub fn set_qos(&mut self, is_qos: bool) {
+        self.is_qos = is_qos;
+        self.tx.lock().unwrap().set_qos(is_qos);
+        trace!("set QoS to {}", is_qos);
+    }
+
+    /// Sets the rate limiter, which limits the traffic from and to each source independently in
+    /// each direction.
+    pub fn set_rate_limit(&mut self, rate_limiter: RateLimiter) {
+        self.tx.lock().unwrap().set_rate_limit(rate_limiter.clone());
+        self.rate_limiter = Some(rate_limiter);
+        trace!("set rate limit");
+    }
+
+    /// Sets if TCP ACKs to sources are delayed and coalesced, and if only in bulk TCP connections.
+    pub fn set_delayed_ack(&mut self, is_delayed_ack: bool, is_bulk_only: bool) {
+        let mut tx_locked = self.tx.lock().unwrap();
+        tx_locked.set_delayed_ack(is_delayed_ack);
+        tx_locked.set_delayed_ack_bulk_only(is_bulk_only);
+    }
+
+    /// Sets the weight of the client in scheduling with QoS. One client saturating the link will
+    /// not starve others, and a client of weight 2 can send twice as many bytes in its turn.
+    pub fn set_client_weight(&mut self, ip_addr: Ipv4Addr, weight: usize) {
+        self.tx.lock().unwrap().set_client_weight(ip_addr, weight);
+    }
+
+    /// Returns the latencies of upstream proxies, which can be read while redirecting.
+    pub fn latency(&self) -> Arc<Mutex<LatencyStats>> {
+        self.proxy.latency()
+    }
+
+    /// Sets the latencies of stages in the hot path, which will record the time parsing,
+    /// filtering, dispatching and injecting each frame.
+    pub fn set_stages(&mut self, stages: Arc<Mutex<StageStats>>) {
+        self.tx.lock().unwrap().set_stages(Arc::clone(&stages));
+        self.stages = Some(stages);
+        trace!("set stages");
+    }
+
+    /// Disables the redirection of the given transport protocol. Packets of the protocol from
+    /// sources will be left untouched, as if pcap2socks is not running.
+    pub fn disable(&mut self, t: LayerKind) {
+        self.disabled.insert(t);
+        trace!("disable {}", t);
+    }
+
+    /// Sets the filter of packets.
+    pub fn set_filter(&mut self, filter: Box<dyn PacketFilter>) {
+        self.filter = Some(filter);
+        trace!("set filter");
+    }
+
+    /// Sets if the filter re-evaluates established TCP connections periodically, which closes
+    /// the ones dropped by the filter like the ones out of their schedules.
+    pub fn set_reevaluate(&mut self, reevaluate: bool) {
+        self.is_reevaluate = reevaluate;
+        trace!("set reevaluate to {}", reevaluate);
+    }
+
+    /// Sets the observer of flows.
+    pub fn set_observer(&mut self, observer: Arc<dyn FlowObserver>) {
+        self.tx.lock().unwrap().set_observer(Arc::clone(&observer));
+        self.observer = Some(observer);
+        trace!("set observer");
+    }
+
+    /// Sets the quota tracker. New flows of sources which have used up their quotas will be
+    /// handled by the quota policy. The tracker counts bytes as an observer of flows, and should
+    /// also be set in the observer.
+    pub fn set_quota(&mut self, quota: Arc<QuotaTracker>) {
+        self.quota = Some(quota);
+        trace!("set quota");
+    }
+
+    /// Sets the policy of new flows of sources which have used up their quotas. New TCP
+    /// connections are reset, or connected directly from the host without the proxy.
+    pub fn set_quota_policy(&mut self, policy: QuotaPolicy) {
+        self.quota_policy = policy;
+        trace!("set quota policy to {}", policy);
+    }
+
+    /// Sets the alerter notified when the proxy is down, too many frames are malformed, or too many
+    /// bytes are buffered.
+    #[cfg(feature = "alert")]
+    pub fn set_alerter(&mut self, alerter: Alerter) {
+        self.alerter = Some(alerter);
+        trace!("set alerter");
+    }
+
+    /// Sets the discovery of devices. Devices are learned from their ARP and DHCP traffic, and the
+    /// ones approved in the discovery are also redirected as sources.
+    #[cfg(feature = "discovery")]
+    pub fn set_discovery(&mut self, discovery: Arc<Discovery>) {
+        self.discovery = Some(discovery);
+        trace!("set discovery");
+    }
+
+    /// Sets the diagnostics of the network segment. Conflicts of the published address, sources
+    /// still sending traffic to other routers, and traffic to sources delivered by other routers
+    /// are found from the traffic.
+    #[cfg(feature = "diagnostics")]
+    pub fn set_diagnostics(&mut self, diagnostics: Arc<Diagnostics>) {
+        self.diagnostics = Some(diagnostics);
+        trace!("set diagnostics");
+    }
+
+    /// Adds an address which is never redirected as a source even if it is in the source, like a
+    /// host of management or upstream services on the same network. The local address and the
+    /// addresses of the proxy are always excluded.
+    pub fn add_excluded(&mut self, ip_addr: Ipv4Addr) {
+        self.excluded.insert(ip_addr);
+        trace!("add excluded {}", ip_addr);
+    }
+
+    fn is_src(&self, ip_addr: Ipv4Addr) -> bool {
+        if self.excluded.contains(&ip_addr) {
+            return false;
+        }
+
+        self.src_ip_addr.contains(ip_addr) || self.is_approved(ip_addr)
+    }
+
+    #[cfg(feature = "discovery")]
+    fn is_approved(&self, ip_addr: Ipv4Addr) -> bool {
+        match &self.discovery {
+            Some(discovery) => discovery.is_approved(ip_addr),
+            None => false,
+        }
+    }
+
+    #[cfg(not(feature = "discovery"))]
+    fn is_approved(&self, _ip_addr: Ipv4Addr) -> bool {
+        false
+    }
+
+    fn exclude_upstreams(&mut self) {
+        for ip_addr in self.proxy.upstream_addrs() {
+            if self.src_ip_addr.contains(ip_addr) && !self.excluded.contains(&ip_addr) {
+                warn!("exclude {} in the source: upstream of the proxy", ip_addr);
+            }
+            self.excluded.insert(ip_addr);
+        }
+    }
+
+    /// Opens an `Interface` for redirection.
+    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        self.open_monitored(rx, None, None, None).await
+    }
+
+    /// Opens an `Interface` for redirection and monitoring.
+    pub async fn open_monitored(
+        &mut self,
+        rx: &mut Receiver,
+        is_running: Option<Arc<AtomicBool>>,
+        traffic: Option<Arc<AtomicUsize>>,
+        count: Option<Arc<AtomicUsize>>,
+    ) -> io::Result<()> {
+        if self.gw_ip_addr.is_some() {
+            if self.is_arp_probe {
+                // Send ARP probes
+                for i in 0..ARP_PROBE_NUM {
+                    if i > 0 {
+                        tokio::time::sleep(Duration::from_millis(ARP_PROBE_INTERVAL)).await;
+                    }
+                    self.tx.lock().unwrap().send_arp_probe()?;
+                }
+                self.arp_probe_timer = Some(Timer::new(ARP_PROBE_WAIT));
+            } else {
+                // Send gratuitous ARP
+                self.tx.lock().unwrap().send_gratuitous_arp()?;
+            }
+        }
+
+        self.exclude_upstreams();
+        self.reset_restored_tcp_streams();
+        self.ready.store(true, Ordering::Relaxed);
+
+        let mut stats_timer = Timer::new(STATS_INTERVAL);
+        let mut reap_timer = Timer::new(REAP_INTERVAL);
+        let mut reevaluate_timer = Timer::new(REEVALUATE_INTERVAL);
+        let mut flow_state_timer = Timer::new(FLOW_STATE_INTERVAL);
+        #[cfg(unix)]
+        let mut handover_timer = Timer::new(handover::ACCEPT_INTERVAL);
+        let mut keepalive_timer = Timer::new(self.keepalive_interval);
+        loop {
+            // Monitor
+            if let Some(is_running) = &is_running {
+                if !is_running.load(Ordering::Relaxed) {
+                    self.ready.store(false, Ordering::Relaxed);
+                    self.save_udp_mappings();
+                    self.save_flow_state();
+                    if let Some(quota) = &self.quota {
+                        quota.save();
+                    }
+                    return Ok(());
+                }
+            }
+
+            // Statistics
+            if stats_timer.is_timedout() {
+                #[cfg(feature = "alert")]
+                self.alert_malformed();
+                self.log_stats();
+                stats_timer = Timer::new(STATS_INTERVAL);
+            }
+            if self.snapshot.swap(false, Ordering::Relaxed) {
+                self.log_snapshot();
+            }
+
+            // Publish after probing
+            if let Some(ref timer) = self.arp_probe_timer {
+                if timer.is_timedout() {
+                    self.arp_probe_timer = None;
+                    debug!("probe ARP: no conflict");
+                    self.tx.lock().unwrap().send_gratuitous_arp()?;
+                }
+            }
+
+            // Reap
+            if reap_timer.is_timedout() {
+                #[cfg(feature = "alert")]
+                self.alert_buffer();
+                self.reap_tcp_streams();
+                self.reap_udp_ports();
+                self.save_udp_mappings();
+                if let Some(quota) = &self.quota {
+                    quota.save();
+                }
+                reap_timer = Timer::new(REAP_INTERVAL);
+            }
+            if reevaluate_timer.is_timedout() {
+                self.reevaluate_tcp_streams();
+                reevaluate_timer = Timer::new(REEVALUATE_INTERVAL);
+            }
+            if flow_state_timer.is_timedout() {
+                self.save_flow_state();
+                flow_state_timer = Timer::new(FLOW_STATE_INTERVAL);
+            }
+            #[cfg(unix)]
+            if handover_timer.is_timedout() {
+                if let Some(stream) = self.accept_handover() {
+                    self.ready.store(false, Ordering::Relaxed);
+                    self.save_udp_mappings();
+                    if let Some(quota) = &self.quota {
+                        quota.save();
+                    }
+                    self.hand_over(stream);
+                    return Ok(());
+                }
+                handover_timer = Timer::new(handover::ACCEPT_INTERVAL);
+            }
+            if self.keepalive.is_some() && keepalive_timer.is_timedout() {
+                self.probe_tcp_streams();
+                keepalive_timer = Timer::new(self.keepalive_interval);
+            }
+
+            let frame = {
+                #[cfg(feature = "tracing")]
+                let _span = tracing::trace_span!("capture").entered();
+                rx.next()
+            };
+            match frame {
+                Ok(frame) => {
+                    // Stamp at the capture boundary before any processing
+                    self.timestamp = Timestamp::now();
+                    if self.paused.load(Ordering::Relaxed) {
+                        continue;
+                    }
+
+                    let is_strict = self.is_strict;
+                    let instant = self.stage_instant();
+                    let indicator = {
+                        #[cfg(feature = "tracing")]
+                        let _span = tracing::trace_span!("parse", size = frame.len()).entered();
+                        Indicator::from_with(frame, self.parsers.as_ref())
+                            .filter(|indicator| !indicator.is_malformed(frame, is_strict))
+                    };
+                    self.record_stage(Stage::Parse, instant);
+                    if let Some(ref indicator) = indicator {
+                        // Filter
+                        let instant = self.stage_instant();
+                        let verdict = {
+                            #[cfg(feature = "tracing")]
+                            let _span = tracing::trace_span!("filter").entered();
+                            match &self.filter {
+                                Some(filter) => filter.filter(indicator),
+                                None => Verdict::Accept,
+                            }
+                        };
+                        self.record_stage(Stage::Filter, instant);
+                        let target = match verdict {
+                            Verdict::Accept => None,
+                            Verdict::Drop => {
+                                trace!("drop {}", indicator.brief());
+                                continue;
+                            }
+                            Verdict::Redirect(target) => Some(target),
+                        };
+
+                        if let Some(t) = indicator.network_kind() {
+                            let traffic = match &traffic {
+                                Some(traffic) => Some(Arc::clone(traffic)),
+                                None => None,
+                            };
+                            let count = match &count {
+                                Some(count) => Some(Arc::clone(count)),
+                                None => None,
+                            };
+                            let instant = self.stage_instant();
+                            #[cfg(feature = "tracing")]
+                            let span = tracing::trace_span!("dispatch", kind = %t);
+                            match t {
+                                LayerKinds::Arp => {
+                                    #[cfg(feature = "tracing")]
+                                    let _span = span.enter();
+                                    if let Err(e) = self.handle_arp(indicator, traffic, count) {
+                                        // Refuse to publish an address owned by another host
+                                        if e.kind() == io::ErrorKind::AddrInUse {
+                                            return Err(e);
+                                        }
+                                        warn!("handle {}: {}", indicator.brief(), e);
+                                    }
+                                }
+                                LayerKinds::Ipv4 => {
+                                    let handle =
+                                        self.handle_ipv4(indicator, frame, target, traffic, count);
+                                    // The span is entered in each poll of the handling, since it
+                                    // may await the proxy
+                                    #[cfg(feature = "tracing")]
+                                    let handle = tracing::Instrument::instrument(handle, span);
+                                    if let Err(ref e) = handle.await {
+                                        warn!("handle {}: {}", indicator.brief(), e);
+                                    }
+                                }
+                                // Custom layers are left to filters and observers
+                                LayerKinds::Custom => {}
+                                _ => debug!("drop {}: unhandled layer {}", indicator.brief(), t),
+                            }
+                            self.record_stage(Stage::Dispatch, instant);
+                        }
+                    } else {
+                        self.handle_malformed(frame);
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+        }
+    }
+
+    fn stage_instant(&self) -> Option<Instant> {
+        self.stages.as_ref().map(|_| Instant::now())
+    }
+
+    fn record_stage(&self, stage: Stage, instant: Option<Instant>) {
+        if let (Some(stages), Some(instant)) = (&self.stages, instant) {
+            stages.lock().unwrap().record(stage, instant.elapsed());
+        }
+    }
+
+    fn handle_malformed(&mut self, frame: &[u8]) {
+        self.malformed = self.malformed.checked_add(1).unwrap_or(usize::MAX);
+        trace!("drop malformed frame ({} Bytes)", frame.len());
+
+        // Observer
+        if let Some(observer) = &self.observer {
+            observer.on_parse_error(frame);
+        }
+
+        // Dump
+        if let Some(dump) = &mut self.malformed_dump {
+            if let Err(ref e) = dump.write_at(frame, self.timestamp) {
+                warn!("dump malformed frame: {}", e);
+            }
+        }
+    }
+
+    fn handle_arp(
+        &mut self,
+        indicator: &Indicator,
+        traffic: Option<Arc<AtomicUsize>>,
+        count: Option<Arc<AtomicUsize>>,
+    ) -> io::Result<()> {
+        // Discovery
+        #[cfg(feature = "discovery")]
+        if let Some(discovery) = &self.discovery {
+            if let Some(arp) = indicator.arp() {
+                let local_hardware_addr = self.tx.lock().unwrap().local_hardware_addr;
+                if arp.src_hardware_addr() != local_hardware_addr && arp.src() != self.local_ip_addr
+                {
+                    discovery.observe(arp.src_hardware_addr(), Some(arp.src()), None);
+                }
+            }
+        }
+
+        if let Some(gw_ip_addr) = self.gw_ip_addr {
+            if let Some(arp) = indicator.arp() {
+                // Conflict
+                let local_hardware_addr = self.tx.lock().unwrap().local_hardware_addr;
+                if arp.src_hardware_addr() != local_hardware_addr
+                    && (arp.src() == gw_ip_addr
+                        || (self.arp_probe_timer.is_some()
+                            && arp.src() == Ipv4Addr::UNSPECIFIED
+                            && arp.dst() == gw_ip_addr))
+                {
+                    return self.handle_arp_conflict(gw_ip_addr, arp.src_hardware_addr());
+                }
+                if self.arp_probe_timer.is_some() {
+                    return Ok(());
+                }
+                // Back off
+                if let Some(ref timer) = self.arp_backoff_timer {
+                    if !timer.is_timedout() {
+                        return Ok(());
+                    }
+                    self.arp_backoff_timer = None;
+                    info!("Resume publishing for {}", gw_ip_addr);
+                    self.tx.lock().unwrap().send_gratuitous_arp()?;
+                }
+
+                let src = arp.src();
+                if src != self.local_ip_addr && self.is_src(src) && arp.dst() == gw_ip_addr {
+                    let src = arp.src();
+                    debug!(
+                        "receive from pcap at {}: {} ({} Bytes)",
+                        self.timestamp,
+                        indicator.brief(),
+                        indicator.len()
+                    );
+
+                    // Set forwarder's hardware address
+                    self.set_tx_hardware_addr(src, arp.src_hardware_addr());
+
+                    // Rate limit
+                    if let Some(timer) = self.arp_reply_timers.get(&src) {
+                        if timer.elapsed() < Duration::from_millis(self.arp_reply_interval) {
+                            trace!("ignore ARP request from {} due to rate limit", src);
+                            return Ok(());
+                        }
+                    }
+                    self.arp_reply_timers
+                        .insert(src, Timer::new(self.arp_reply_interval));
+
+                    // Send
+                    self.tx.lock().unwrap().send_arp_reply(src)?;
+
+                    // Monitor
+                    if let Some(traffic) = traffic {
+                        traffic.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                    }
+                    if let Some(count) = count {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_arp_conflict(
+        &mut self,
+        gw_ip_addr: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    ) -> io::Result<()> {
+        let is_new = self.arp_conflicts.insert(hardware_addr);
+        #[cfg(feature = "diagnostics")]
+        if let Some(diagnostics) = &self.diagnostics {
+            diagnostics.observe_conflict(gw_ip_addr, hardware_addr);
+        }
+        if self.arp_probe_timer.is_some() && !self.is_force_publish {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!(
+                    "the published address {} is already owned by {}",
+                    gw_ip_addr, hardware_addr
+                ),
+            ));
+        }
+
+        if is_new {
+            warn!("The published address {} is also owned by {}, which may be the real gateway replying the same ARP requests, and the sources may send traffic to it instead. Please use an unused address for publishing", gw_ip_addr, hardware_addr);
+        } else {
+            debug!(
+                "receive conflicting ARP from {} for {}",
+                hardware_addr, gw_ip_addr
+            );
+        }
+
+        if self.is_arp_backoff {
+            // Back off
+            if self.arp_backoff_timer.is_none() {
+                info!(
+                    "Back off from publishing for {} for {} seconds due to the conflict with {}",
+                    gw_ip_addr,
+                    ARP_BACKOFF_TIME / 1000,
+                    hardware_addr
+                );
+            }
+            self.arp_backoff_timer = Some(Timer::new(ARP_BACKOFF_TIME));
+        } else if self
+            .arp_backoff_timer
+            .as_ref()
+            .map_or(false, |timer| !timer.is_timedout())
+        {
+            trace!(
+                "ignore conflicting ARP from {} for {} due to backoff",
+                hardware_addr,
+                gw_ip_addr
+            );
+        } else {
+            // Defend at most once in an interval, and back off if the conflict persists
+            // (RFC 5227)
+            match self.arp_defend_timer {
+                Some(ref timer) if !timer.is_timedout() => {
+                    warn!(
+                        "Back off from publishing for {} for {} seconds since {} keeps conflicting after the defense",
+                        gw_ip_addr,
+                        ARP_BACKOFF_TIME / 1000,
+                        hardware_addr
+                    );
+                    self.arp_defend_timer = None;
+                    self.arp_backoff_timer = Some(Timer::new(ARP_BACKOFF_TIME));
+                }
+                _ => {
+                    debug!("defend {} against {}", gw_ip_addr, hardware_addr);
+                    self.tx.lock().unwrap().send_gratuitous_arp()?;
+                    self.arp_defend_timer = Some(Timer::new(ARP_DEFEND_INTERVAL));
+                }
+            }
+        }
+
+        Ok(())
     }
 
     async fn handle_ipv4(
         &mut self,
         indicator: &Indicator,
         frame: &[u8],
+        target: Option<SocketAddrV4>,
         traffic: Option<Arc<AtomicUsize>>,
         count: Option<Arc<AtomicUsize>>,
     ) -> io::Result<()> {
         if let Some(ipv4) = indicator.ipv4() {
+            // Discovery
+            #[cfg(feature = "discovery")]
+            if let (Some(discovery), Some(udp)) = (&self.discovery, indicator.udp()) {
+                if (udp.src() == discovery::DHCP_CLIENT_PORT
+                    && udp.dst() == discovery::DHCP_SERVER_PORT)
+                    || (udp.src() == discovery::DHCP_SERVER_PORT
+                        && udp.dst() == discovery::DHCP_CLIENT_PORT)
+                {
+                    let payload = &frame[min(indicator.len(), frame.len())
+                        ..min(indicator.content_len(), frame.len())];
+                    if let Some((hardware_addr, ip_addr, hostname)) = discovery::parse_dhcp(payload)
+                    {
+                        discovery.observe(hardware_addr, ip_addr, hostname);
+                    }
+                }
+            }
+
             let src = ipv4.src();
-            if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
+            if src != self.local_ip_addr && self.is_src(src) {
+                // Disabled protocols, which also covers non-first fragments
+                if let Some(t) = ipv4.next_level_layer_kind() {
+                    if self.disabled.contains(&t) {
+                        trace!("bypass {}", indicator.brief());
+                        return Ok(());
+                    }
+                }
+
+                // Rate limit, where frames without payload like TCP ACKs always pass
+                if indicator.content_len() > indicator.len() {
+                    if let Some(rate_limiter) = &mut self.rate_limiter {
+                        if !rate_limiter.admit(
+                            src,
+                            indicator.content_len(),
+                            self.timestamp.instant(),
+                        ) {
+                            trace!("drop {}: rate limited", indicator.brief());
+                            return Ok(());
+                        }
+                    }
+                }
+
                 let src = ipv4.src();
+                self.ttl = ipv4.ttl();
                 debug!(
-                    "receive from pcap: {} ({} + {} Bytes)",
+                    "receive from pcap at {}: {} ({} + {} Bytes)",
+                    self.timestamp,
                     indicator.brief(),
                     indicator.len(),
                     indicator.content_len() - indicator.len()
@@ -1342,7 +3621,35 @@ impl Redirector {
                 // Set forwarder's hardware address
                 self.set_tx_hardware_addr(src, indicator.ethernet().unwrap().src());
 
-                let frame_without_padding = &frame[..indicator.content_len()];
+                // Diagnostics
+                #[cfg(feature = "diagnostics")]
+                if let Some(diagnostics) = &self.diagnostics {
+                    diagnostics.observe_outbound(
+                        src,
+                        ipv4.dst(),
+                        indicator.ethernet().unwrap().dst(),
+                    );
+                }
+
+                let frame_without_padding = &frame[..min(indicator.content_len(), frame.len())];
+
+                // Mirror
+                #[cfg(feature = "capture")]
+                if let Some(mirror) = &self.mirror {
+                    let timestamp = self.timestamp;
+                    if let Err(ref e) = mirror
+                        .lock()
+                        .unwrap()
+                        .write_at(frame_without_padding, timestamp)
+                    {
+                        warn!("mirror frame: {}", e);
+                    }
+                }
+                #[cfg(feature = "capture")]
+                if let Some(capture) = &self.capture {
+                    capture.write_from_source(frame_without_padding, self.timestamp);
+                }
+
                 if ipv4.is_fragment() {
                     // Fragmentation
                     let frag = match self.defrag.add(indicator, frame_without_padding) {
@@ -1353,26 +3660,54 @@ impl Redirector {
 
                     if let Some(transport) = transport {
                         match transport {
-                            Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, &payload).await?,
-                            Layers::Udp(ref udp) => self.handle_udp(udp, &payload).await?,
-                            _ => unreachable!(),
+                            Layers::Icmpv4(ref icmpv4) => {
+                                self.handle_icmpv4(src, ipv4.dst(), icmpv4)?
+                            }
+                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, &payload, target).await?,
+                            Layers::Udp(ref udp) => self.handle_udp(udp, &payload, target).await?,
+                            ref layer => debug!(
+                                "drop {}: unhandled fragmented layer {}",
+                                indicator.brief(),
+                                layer.kind()
+                            ),
                         }
                     }
                 } else {
                     if let Some(transport) = indicator.transport() {
                         match transport {
-                            Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
+                            Layers::Icmpv4(icmpv4) => {
+                                self.handle_icmpv4(src, ipv4.dst(), icmpv4)?
+                            }
                             Layers::Tcp(tcp) => {
-                                self.handle_tcp(tcp, &frame_without_padding[indicator.len()..])
-                                    .await?
+                                self.handle_tcp(
+                                    tcp,
+                                    &frame_without_padding[indicator.len()..],
+                                    target,
+                                )
+                                .await?
                             }
                             Layers::Udp(udp) => {
-                                self.handle_udp(udp, &frame_without_padding[indicator.len()..])
-                                    .await?
+                                self.handle_udp(
+                                    udp,
+                                    &frame_without_padding[indicator.len()..],
+                                    target,
+                                )
+                                .await?
                             }
-                            _ => unreachable!(),
+                            // Custom layers are left to filters and observers
+                            Layers::Custom(_) => {}
+                            layer => debug!(
+                                "drop {}: unhandled layer {}",
+                                indicator.brief(),
+                                layer.kind()
+                            ),
                         }
+                    } else if ipv4.next_level_protocol() == IpNextHeaderProtocols::Igmp {
+                        #[cfg(feature = "multicast")]
+                        self.handle_igmp(src, &frame_without_padding[indicator.len()..])
+                            .await?;
+                    } else if ipv4.next_level_protocol() == IpNextHeaderProtocols::Ipv6 {
+                        self.handle_ipv6_in_ipv4(src, ipv4.dst());
                     }
                 }
 
@@ -1383,13 +3718,83 @@ impl Redirector {
                 if let Some(count) = count {
                     count.fetch_add(1, Ordering::Relaxed);
                 }
+            } else {
+                // Traffic to sources delivered by other routers
+                #[cfg(feature = "diagnostics")]
+                if let Some(diagnostics) = &self.diagnostics {
+                    if self.is_src(ipv4.dst()) {
+                        diagnostics.observe_inbound(
+                            src,
+                            ipv4.dst(),
+                            indicator.ethernet().unwrap().src(),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_ipv6_in_ipv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr) {
+        // IPv6 in IPv4 of 6in4 and 6to4, which is neither TCP nor UDP
+        if self.ipv6_tunnel_srcs.insert(src) {
+            warn!(
+                "drop IPv6 in IPv4 {} -> {}: protocol 41 cannot be proxied, use Teredo on the source or exclude it",
+                src, dst
+            );
+        } else {
+            trace!("drop IPv6 in IPv4 {} -> {}", src, dst);
+        }
+    }
+
+    #[cfg(feature = "multicast")]
+    async fn handle_igmp(&mut self, src: Ipv4Addr, payload: &[u8]) -> io::Result<()> {
+        for (group, is_join) in multicast::parse_igmp(payload) {
+            let addrs = self
+                .multicast_groups
+                .iter()
+                .filter(|addr| *addr.ip() == group)
+                .cloned()
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                continue;
+            }
+
+            if is_join {
+                if self.multicast_members.entry(group).or_default().insert(src) {
+                    debug!("join multicast {} = {}", group, src);
+                }
+                for addr in addrs {
+                    if !self.multicast_workers.contains_key(&addr) {
+                        // Join on the default interface of the host
+                        let worker = MulticastWorker::join(
+                            self.get_tx(),
+                            addr,
+                            Ipv4Addr::UNSPECIFIED,
+                            self.src_ip_addr,
+                        )
+                        .await?;
+                        self.multicast_workers.insert(addr, worker);
+                    }
+                }
+            } else if let Some(members) = self.multicast_members.get_mut(&group) {
+                if members.remove(&src) {
+                    debug!("leave multicast {} = {}", group, src);
+                }
+                if members.is_empty() {
+                    self.multicast_members.remove(&group);
+                    for addr in addrs {
+                        self.multicast_workers.remove(&addr);
+                    }
+                }
             }
         }
 
         Ok(())
     }
 
-    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
+    fn handle_icmpv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr, icmpv4: &Icmpv4) -> io::Result<()> {
         if icmpv4.is_destination_port_unreachable() {
             // Destination port unreachable
             let kind = match icmpv4.next_level_layer_kind() {
@@ -1398,40 +3803,124 @@ impl Redirector {
             };
             match kind {
                 LayerKinds::Udp => {
-                    let dst = icmpv4.dst().unwrap();
-                    self.unbind_local_udp_port(dst);
+                    if let Some(dst) = icmpv4.dst() {
+                        self.unbind_local_udp_port(dst, CloseReason::Unreachable);
+                    }
                 }
                 _ => {}
             }
         } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
             // Fragmentation required, and DF flag set
-            let mtu = icmpv4.next_hop_mtu().unwrap();
+            let (mtu, dst_ip_addr) = match (icmpv4.next_hop_mtu(), icmpv4.dst_ip_addr()) {
+                (Some(mtu), Some(dst_ip_addr)) => (mtu, dst_ip_addr),
+                _ => return Ok(()),
+            };
             if self
                 .tx
                 .lock()
                 .unwrap()
-                .set_src_mtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
+                .set_src_mtu(dst_ip_addr, mtu as usize)
             {
-                info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
+                info!("Update MTU of {} to {}", dst_ip_addr, mtu);
+            }
+        } else if icmpv4.is_echo_request() {
+            // Echo request, measured by a TCP connection through the proxy
+            let (port, identifier, sequence_number, data) = match (
+                self.ping_port,
+                icmpv4.identifier(),
+                icmpv4.sequence_number(),
+                icmpv4.data(),
+            ) {
+                (Some(port), Some(identifier), Some(sequence_number), Some(data)) => {
+                    (port, identifier, sequence_number, data.to_vec())
+                }
+                _ => return Ok(()),
+            };
+            if dst == self.local_ip_addr || dst.is_broadcast() || dst.is_multicast() {
+                return Ok(());
+            }
+            // Only one echo request of a destination is measured at a time
+            if !self.pings.lock().unwrap().insert((src, dst)) {
+                trace!("drop ICMPv4 echo request {} -> {}: pending", src, dst);
+
+                return Ok(());
             }
+
+            let tx = self.get_tx();
+            let proxy = self.proxy.clone();
+            let pings = Arc::clone(&self.pings);
+            tokio::spawn(async move {
+                let instant = Instant::now();
+                let result = tokio::time::timeout(
+                    Duration::from_millis(PING_TIMEOUT),
+                    proxy.connect_tcp(SocketAddrV4::new(dst, port)),
+                )
+                .await;
+                pings.lock().unwrap().remove(&(src, dst));
+                match result {
+                    Ok(Ok(_)) => {
+                        debug!(
+                            "measure ICMPv4 echo request {} -> {} in {} ms",
+                            src,
+                            dst,
+                            instant.elapsed().as_millis()
+                        );
+                        if let Err(ref e) = tx.lock().unwrap().send_icmpv4_echo_reply(
+                            dst,
+                            src,
+                            identifier,
+                            sequence_number,
+                            &data,
+                        ) {
+                            warn!("handle receive: {}: {} -> {}: {}", "ICMPv4", dst, src, e);
+                        }
+                    }
+                    Ok(Err(ref e)) => {
+                        debug!("measure ICMPv4 echo request {} -> {}: {}", src, dst, e)
+                    }
+                    Err(_) => debug!("measure ICMPv4 echo request {} -> {}: timed out", src, dst),
+                }
+            });
         }
 
         Ok(())
     }
 
-    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+    async fn handle_tcp(
+        &mut self,
+        tcp: &Tcp,
+        payload: &[u8],
+        target: Option<SocketAddrV4>,
+    ) -> io::Result<()> {
+        // Keep-alive, where any segment from the source answers the probes
+        let key = (
+            SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+            SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+        );
+        if let Some(liveness) = self.keepalive_flows.get_mut(&key) {
+            if liveness.answer() {
+                info!(
+                    "Resume TCP {} -> {}: the source responds again",
+                    key.0, key.1
+                );
+            }
+        }
+
         if tcp.is_rst() {
             self.handle_tcp_rst(tcp);
+        } else if tcp.is_syn() && tcp.is_ack() {
+            // TCP ACK/SYN
+            self.handle_tcp_ack_syn(tcp)?;
         } else if tcp.is_ack() {
             self.handle_tcp_ack(tcp, payload)?;
         } else if tcp.is_syn() {
             // Pure TCP SYN
-            self.handle_tcp_syn(tcp).await?;
+            self.handle_tcp_syn(tcp, payload, target).await?;
         } else if tcp.is_fin() {
             // Pure TCP FIN
             self.handle_tcp_fin(tcp, payload)?;
         } else {
-            unreachable!();
+            debug!("drop TCP {} -> {}: no SYN, ACK, FIN or RST", key.0, key.1);
         }
 
         Ok(())
@@ -1467,15 +3956,46 @@ impl Redirector {
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-                tx_state.acknowledge(tcp.acknowledgement());
+                let is_partial =
+                    tx_state.acknowledge_at(tcp.acknowledgement(), self.timestamp.instant());
                 tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
+
+                // Partial acknowledgement in fast recovery
+                if is_partial {
+                    tx_locked.retransmit_tcp_first(dst, src)?;
+                }
             }
 
             if payload.len() > 0 {
                 // ACK
                 if is_writable {
-                    // Append to cache
-                    let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+                    // Discard bytes delivered, and append to cache
+                    let cont_payload = match state.trim_delivered(tcp.sequence(), payload) {
+                        Some((sequence, payload)) => match state.append_cache(sequence, payload) {
+                            Ok(cont_payload) => cont_payload,
+                            Err(_) => {
+                                // Zero window probe, or bytes beyond the window
+                                trace!(
+                                    "TCP beyond window of {} -> {} at {}",
+                                    src,
+                                    dst,
+                                    tcp.sequence()
+                                );
+
+                                None
+                            }
+                        },
+                        None => {
+                            trace!(
+                                "TCP retransmission of {} -> {} at {}",
+                                src,
+                                dst,
+                                tcp.sequence()
+                            );
+
+                            None
+                        }
+                    };
 
                     // SACK
                     if state.sack_perm() {
@@ -1498,6 +4018,11 @@ impl Redirector {
                             let size = payload.len();
                             match stream.send(payload) {
                                 Ok(_) => {
+                                    // Observer
+                                    if let Some(observer) = &self.observer {
+                                        observer.on_bytes_sent(LayerKinds::Tcp, src, dst, size);
+                                    }
+
                                     let cache_remaining_size = (state.cache().remaining()
                                         >> state.wscale() as usize)
                                         as u16;
@@ -1514,6 +4039,7 @@ impl Redirector {
 
                                     // Update TCP acknowledgement
                                     tx_state.add_acknowledgement(size as u32);
+                                    tx_state.add_received(size);
 
                                     // Send delayed ACK0
                                     // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
@@ -1524,7 +4050,7 @@ impl Redirector {
                                     self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
 
                                     // Clean up
-                                    self.clean_up(src, dst);
+                                    self.clean_up(src, dst, CloseReason::ProxyError);
 
                                     return Err(e);
                                 }
@@ -1552,7 +4078,7 @@ impl Redirector {
                     self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
 
                     // Clean up
-                    self.clean_up(src, dst);
+                    self.clean_up(src, dst, CloseReason::Aborted);
 
                     return Ok(());
                 }
@@ -1562,10 +4088,16 @@ impl Redirector {
                     if self.tx.lock().unwrap().get_cache_size(dst, src) == 0 {
                         // LAST_ACK
                         // Clean up
-                        self.clean_up(src, dst);
+                        self.clean_up(src, dst, CloseReason::SourceFin);
 
                         return Ok(());
                     }
+                } else if tcp.sequence() == state.recv_next().wrapping_sub(1) {
+                    // Zero window probe or keep-alive
+                    trace!("TCP probe of {} -> {}", src, dst);
+
+                    // Send ACK0
+                    self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
                 } else {
                     // Duplicate ACK
                     state.admit(tcp.acknowledgement());
@@ -1621,84 +4153,264 @@ impl Redirector {
         Ok(())
     }
 
-    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
+    fn handle_tcp_ack_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
         let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
         let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
         let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
-
-        // Connect if not connected, drop if established
-        if !is_exist {
-            // Clean up
-            self.clean_up(src, dst);
-
-            // Admit SYN
-            let wscale = match ENABLE_WSCALE {
-                true => tcp.wscale(),
-                false => None,
-            };
-            let recv_wscale = match wscale {
-                Some(wscale) => Some(min(wscale, MAX_RECV_WSCALE)),
-                None => None,
-            };
-            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
-            let state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
 
-            {
+        match self.states.get(&key) {
+            Some(state) => {
                 let mut tx_locked = self.tx.lock().unwrap();
+                if tcp.sequence() == state.syn_sequence() {
+                    // Simultaneous open, the source answered the TCP ACK/SYN with its own SYN
+                    trace!("TCP simultaneous open of {} -> {}", src, dst);
+                    let tx_state = tx_locked
+                        .get_state_mut(dst, src)
+                        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-                let mut rng = rand::thread_rng();
-                let sequence = rng.gen::<u32>();
-                let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
-                if let Some(mss) = tcp.mss() {
-                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
-                    if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
-                        info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
-                    }
+                    tx_state.acknowledge_at(tcp.acknowledgement(), self.timestamp.instant());
+                    tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
                 }
 
-                let tx_state = TcpTxState::new(
-                    src,
+                // Send ACK0
+                tx_locked.send_tcp_ack_0(dst, src)?;
+            }
+            None => {
+                // Send ACK/RST
+                self.tx.lock().unwrap().send_tcp_ack_rst_untracked(
                     dst,
-                    sequence,
-                    acknowledgement,
-                    tcp.window(),
-                    recv_wscale,
-                    sack_perm,
-                    wscale,
-                    tx_locked.get_src_mtu(tcp.src_ip_addr())
-                        - (Ipv4::minimum_len() + Tcp::minimum_len()),
-                );
-                tx_locked.set_state(dst, src, tx_state);
+                    src,
+                    tcp.acknowledgement(),
+                )?;
             }
+        }
 
-            // Connect
-            let stream = StreamWorker::connect(self.get_tx(), src, dst, &self.proxy).await;
+        Ok(())
+    }
 
-            let stream = match stream {
-                Ok(stream) => stream,
-                Err(e) => {
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked
-                            .get_state_mut(dst, src)
-                            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+    async fn handle_tcp_syn(
+        &mut self,
+        tcp: &Tcp,
+        payload: &[u8],
+        target: Option<SocketAddrV4>,
+    ) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let is_exist = self.streams.get(&key).is_some();
+
+        // Answer a retransmitted SYN if connected, connect again if the port is reused
+        if is_exist {
+            let syn_sequence = self
+                .states
+                .get(&key)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                .syn_sequence();
+            if tcp.sequence() == syn_sequence {
+                let mut tx_locked = self.tx.lock().unwrap();
+                let is_syn_acked = tx_locked
+                    .get_state(dst, src)
+                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                    .cache_syn()
+                    .is_none();
+                if is_syn_acked {
+                    // Send ACK0
+                    tx_locked.send_tcp_ack_0(dst, src)?;
+                } else {
+                    // The source may have lost the TCP ACK/SYN
+                    trace!(
+                        "retransmit TCP ACK/SYN {} -> {} due to duplicate SYN",
+                        dst,
+                        src
+                    );
+                    tx_locked.send_tcp_ack_syn(dst, src)?;
+                }
 
-                        tx_state.add_acknowledgement(1);
+                return Ok(());
+            }
 
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, src)?;
-                    }
+            debug!("reconnect TCP {} -> {}: new SYN", src, dst);
+        }
 
-                    // Clean up
-                    self.clean_up(src, dst);
+        // Clean up
+        self.clean_up(src, dst, CloseReason::Reset);
+
+        // Quota
+        let is_exceeded = self
+            .quota
+            .as_ref()
+            .map_or(false, |quota| quota.is_exceeded(*src.ip()));
+        if is_exceeded && self.quota_policy == QuotaPolicy::Reject {
+            debug!("reject TCP {} -> {}: quota exceeded", src, dst);
+
+            return self.tx.lock().unwrap().send_tcp_ack_rst_refused(
+                dst,
+                src,
+                tcp.sequence().checked_add(1).unwrap_or(0),
+            );
+        }
 
-                    return Err(e);
+        // Admit SYN
+        let wscale = match ENABLE_WSCALE {
+            true => tcp.wscale(),
+            false => None,
+        };
+        let recv_wscale = match wscale {
+            Some(wscale) => Some(min(wscale, MAX_RECV_WSCALE)),
+            None => None,
+        };
+        let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
+        let mut state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+        // SYN with data, like TCP Fast Open, whose data is acknowledged in the TCP ACK/SYN
+        if payload.len() > 0 {
+            state.add_recv_next(payload.len() as u32);
+        }
+
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+
+            let mut rng = rand::thread_rng();
+            let sequence = rng.gen::<u32>();
+            let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
+            if let Some(mss) = tcp.mss() {
+                let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
+                if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
+                    info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
                 }
-            };
+            }
+
+            let mut tx_state = TcpTxState::new(
+                src,
+                dst,
+                sequence,
+                acknowledgement,
+                tcp.window(),
+                recv_wscale,
+                sack_perm,
+                wscale,
+                tx_locked.get_src_mtu(tcp.src_ip_addr())
+                    - (Ipv4::minimum_len() + Tcp::minimum_len()),
+            );
+            if payload.len() > 0 {
+                tx_state.add_acknowledgement(payload.len() as u32);
+                tx_state.add_received(payload.len());
+            }
+            tx_locked.set_state(dst, src, tx_state);
+        }
+
+        // Connect
+        let target = target.unwrap_or(dst);
+        if target != dst {
+            debug!("redirect TCP {} -> {} to {}", src, dst, target);
+        }
+        let direct = match is_exceeded {
+            true => {
+                debug!("route TCP {} -> {} directly: quota exceeded", src, dst);
+                Some(&self.direct)
+            }
+            false => self
+                .adaptive
+                .as_ref()
+                .filter(|adaptive| adaptive.route(src, target))
+                .map(|adaptive| adaptive.direct()),
+        };
+        #[cfg(feature = "alert")]
+        let is_direct = direct.is_some();
+        let is_keepalive = self
+            .keepalive
+            .as_ref()
+            .map_or(false, |keepalive| keepalive.is_designated(src, dst));
+        let keepalive = match is_keepalive {
+            true => Some(Duration::from_millis(self.keepalive_interval)),
+            false => None,
+        };
+        let stream = match direct {
+            Some(direct) => {
+                debug!("connect TCP {} -> {} directly", src, target);
+                StreamWorker::connect_to(self.get_tx(), src, dst, target, direct, keepalive).await
+            }
+            None => {
+                StreamWorker::connect_to(self.get_tx(), src, dst, target, &self.proxy, keepalive)
+                    .await
+            }
+        };
+
+        // Failures of the direct path are not the ones of the proxy
+        #[cfg(feature = "alert")]
+        if !is_direct {
+            self.alert_upstream(stream.is_ok());
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    let tx_state = tx_locked
+                        .get_state_mut(dst, src)
+                        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+                    tx_state.add_acknowledgement(1);
+
+                    // Send ACK/RST
+                    tx_locked.send_tcp_ack_rst(dst, src)?;
+                }
+
+                // Clean up
+                let reason = match proxy::is_socks_error(&e) {
+                    true => CloseReason::SocksError,
+                    false => CloseReason::ConnectError,
+                };
+                self.clean_up(src, dst, reason);
+
+                return Err(e);
+            }
+        };
+
+        self.states.insert(key, state);
+        self.streams.insert(key, stream);
+        if is_keepalive {
+            debug!("keep alive TCP {} -> {}", src, dst);
+            self.keepalive_flows.insert(key, Liveness::new());
+        }
+        if self.is_qos {
+            self.streams.get_mut(&key).unwrap().set_qos(true);
+        }
+        if let Some(&batching) = self
+            .tcp_port_batchings
+            .get(&dst.port())
+            .or_else(|| self.tcp_port_batchings.get(&src.port()))
+        {
+            self.streams.get_mut(&key).unwrap().set_batching(batching);
+        }
+
+        // Observer
+        if let Some(observer) = &self.observer {
+            observer.on_flow_created(LayerKinds::Tcp, src, dst);
+            if let Some(upstream) = self.streams.get(&key).unwrap().upstream() {
+                observer.on_flow_connected(LayerKinds::Tcp, src, dst, upstream);
+            }
+        }
+
+        // SYN with data
+        if payload.len() > 0 {
+            let stream = self
+                .streams
+                .get_mut(&key)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            if let Err(e) = stream.send(payload.to_vec()) {
+                // Send ACK/RST
+                self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                // Clean up
+                self.clean_up(src, dst, CloseReason::ProxyError);
 
-            self.states.insert(key, state);
-            self.streams.insert(key, stream);
+                return Err(e);
+            }
+
+            // Observer
+            if let Some(observer) = &self.observer {
+                observer.on_bytes_sent(LayerKinds::Tcp, src, dst, payload.len());
+            }
         }
 
         Ok(())
@@ -1716,17 +4428,17 @@ impl Redirector {
                     if tcp.sequence() == state.recv_next() {
                         // Admit RST
                         // Clean up
-                        self.clean_up(src, dst);
+                        self.clean_up(src, dst, CloseReason::Reset);
                     }
                 }
                 None => {
                     // Clean up
-                    self.clean_up(src, dst);
+                    self.clean_up(src, dst, CloseReason::Reset);
                 }
             }
         } else {
             // Clean up
-            self.clean_up(src, dst);
+            self.clean_up(src, dst, CloseReason::Reset);
         }
     }
 
@@ -1783,7 +4495,7 @@ impl Redirector {
                         } else {
                             // Close by remote
                             // Clean up
-                            self.clean_up(src, dst);
+                            self.clean_up(src, dst, CloseReason::RemoteFin);
                         }
                     } else {
                         trace!(
@@ -1812,20 +4524,433 @@ impl Redirector {
                 .send_tcp_ack_rst_untracked(dst, src, tcp.acknowledgement())?;
         }
 
-        Ok(())
-    }
+        Ok(())
+    }
+
+    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4, reason: CloseReason) {
+        let key = (src, dst);
+
+        let is_exist = self.streams.remove(&key).is_some();
+        self.states.remove(&key);
+
+        // Keep-alive, telling a stalled relay from a disconnect of either side
+        if let Some(liveness) = self.keepalive_flows.remove(&key) {
+            let cause = match reason {
+                CloseReason::Reset | CloseReason::SourceFin | CloseReason::Aborted => {
+                    "closed by the source"
+                }
+                CloseReason::RemoteFin => "closed by the remote",
+                CloseReason::ProxyError => "reset by the proxy",
+                _ => "closed by pcap2socks",
+            };
+            match liveness.is_down() {
+                true => info!(
+                    "Close TCP {} -> {}: {}, after the source stopped responding",
+                    src, dst, cause
+                ),
+                false => info!("Close TCP {} -> {}: {}", src, dst, cause),
+            }
+        }
+
+        self.tx.lock().unwrap().clean_up(dst, src);
+
+        if reason == CloseReason::ConnectError
+            || reason == CloseReason::SocksError
+            || reason == CloseReason::ProxyError
+        {
+            self.socks_errors = self.socks_errors.checked_add(1).unwrap_or(usize::MAX);
+        }
+
+        // Observer
+        // Flows failed to connect are closed without being created
+        if is_exist || reason == CloseReason::ConnectError || reason == CloseReason::SocksError {
+            debug!("close TCP {} -> {}: {}", src, dst, reason);
+            if let Some(observer) = &self.observer {
+                observer.on_flow_closed(LayerKinds::Tcp, src, dst, reason);
+            }
+        }
+    }
+
+    fn probe_tcp_streams(&mut self) {
+        let mut upstreams = Vec::new();
+        let keys = self.keepalive_flows.keys().cloned().collect::<Vec<_>>();
+        for (src, dst) in keys {
+            // Source
+            if let Some(liveness) = self.keepalive_flows.get_mut(&(src, dst)) {
+                if liveness.probe(KEEPALIVE_PROBES) {
+                    warn!(
+                        "probe TCP {} -> {}: the source stopped responding to {} keep-alive probes",
+                        src, dst, KEEPALIVE_PROBES
+                    );
+                }
+            }
+            if let Err(ref e) = self.tx.lock().unwrap().send_tcp_keepalive(dst, src) {
+                warn!("handle keep-alive: {}: {} -> {}: {}", "TCP", dst, src, e);
+            }
+
+            if let Some(upstream) = self.streams.get(&(src, dst)).and_then(|s| s.upstream()) {
+                upstreams.push(upstream);
+            }
+        }
+
+        // Proxies, whose connections are kept alive by TCP keep-alive, and are probed by new
+        // connections so a proxy not responding is logged
+        for upstream in keepalive::distinct_upstreams(upstreams) {
+            let upstreams = Arc::clone(&self.keepalive_upstreams);
+            tokio::spawn(async move {
+                let result = probe::probe_reachable(upstream).await;
+                let mut upstreams_locked = upstreams.lock().unwrap();
+                let liveness = upstreams_locked.entry(upstream).or_default();
+                match result {
+                    Ok(_) => {
+                        if liveness.answer() {
+                            info!("Resume the proxy {}: the proxy responds again", upstream);
+                        }
+                    }
+                    Err(ref e) => {
+                        if liveness.miss(KEEPALIVE_PROBES) {
+                            warn!(
+                                "probe the proxy {}: stopped responding to {} keep-alive probes: {}",
+                                upstream, KEEPALIVE_PROBES, e
+                            );
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    fn reap_tcp_streams(&mut self) {
+        let keys = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.is_reset())
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (src, dst) in keys {
+            debug!("reap reset TCP {} -> {}", src, dst);
+            self.clean_up(src, dst, CloseReason::ProxyError);
+        }
+    }
+
+    fn reevaluate_tcp_streams(&mut self) {
+        let filter = match &self.filter {
+            Some(filter) if self.is_reevaluate => filter,
+            _ => return,
+        };
+        let keys = self
+            .streams
+            .keys()
+            .filter(|(src, dst)| filter.filter_flow(LayerKinds::Tcp, *src, *dst) == Verdict::Drop)
+            .copied()
+            .collect::<Vec<_>>();
+        for (src, dst) in keys {
+            debug!("drop TCP {} -> {}: filtered", src, dst);
+
+            // Send ACK/RST
+            if let Err(ref e) = self.tx.lock().unwrap().send_tcp_ack_rst(dst, src) {
+                warn!("handle {}: {} -> {}: {}", "TCP", src, dst, e);
+            }
+
+            // Clean up
+            self.clean_up(src, dst, CloseReason::Filtered);
+        }
+    }
+
+    async fn handle_udp(
+        &mut self,
+        udp: &Udp,
+        payload: &[u8],
+        target: Option<SocketAddrV4>,
+    ) -> io::Result<()> {
+        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let dst = SocketAddrV4::new(udp.dst_ip_addr(), udp.dst());
+
+        // Encrypted tunnels, which are relayed without inspecting the payload
+        let is_tunnel = self.tunnel_srcs.contains(&src)
+            || match tunnel::recognize(dst.port(), payload) {
+                Some(kind) => {
+                    debug!("{} {} -> {}: pin the UDP association", kind, src, dst);
+                    self.tunnel_srcs.insert(src);
+                    self.tx.lock().unwrap().set_tunnel_src(src, true);
+
+                    true
+                }
+                None => false,
+            };
+
+        // Observer
+        if let Some(observer) = &self.observer {
+            if dst.port() == dns::DNS_PORT {
+                if let Some(message) = Message::parse(payload) {
+                    if let Some(question) = message.question() {
+                        if !message.is_response() {
+                            observer.on_dns_query(src, dst, question);
+                        }
+                    }
+                }
+                observer.on_dns_message(src, dst, payload, None);
+            }
+        }
+
+        // Wake-on-LAN, which is broadcast on the interface instead of being proxied, because the
+        // device to wake is not reachable through the proxy
+        #[cfg(feature = "wol")]
+        if let Some(hardware_addr) = wol::parse_magic_packet(payload).filter(|_| !is_tunnel) {
+            if dst.ip().is_broadcast() {
+                // Already received by every device on the interface
+                trace!("wake {} from {}", hardware_addr, src);
+
+                return Ok(());
+            }
+            debug!("wake {} from {}", hardware_addr, src);
+
+            return self.tx.lock().unwrap().send_wake(hardware_addr);
+        }
+
+        // Local name resolution
+        if let Some(service) = NameService::from_port(dst.port()) {
+            let policy = *self
+                .client_name_policies
+                .get(src.ip())
+                .unwrap_or(&self.name_policy);
+            match policy {
+                NamePolicy::Block => {
+                    if self.name_blocked.insert((*src.ip(), service)) {
+                        info!("Block {} queries from {}", service, src.ip());
+                    }
+                    trace!("block {}: {} -> {}", service, src, dst);
+
+                    return Ok(());
+                }
+                NamePolicy::Respond => {
+                    let response = self.hosts.as_ref().and_then(|hosts| match service {
+                        NameService::NetBios => names::reply_nbns(hosts, payload),
+                        NameService::Llmnr => hosts.reply_llmnr(payload),
+                    });
+                    let response = match response {
+                        Some(response) => response,
+                        None => {
+                            trace!("ignore {}: {} -> {}", service, src, dst);
+
+                            return Ok(());
+                        }
+                    };
+                    // Responders never reply from a multicast or broadcast address
+                    let responder = if dst.ip().is_multicast()
+                        || dst.ip().is_broadcast()
+                        || *dst.ip() == self.src_ip_addr.broadcast()
+                    {
+                        self.gw_ip_addr.unwrap_or(self.local_ip_addr)
+                    } else {
+                        *dst.ip()
+                    };
+                    let responder = SocketAddrV4::new(responder, dst.port());
+                    debug!("reply {} from hosts: {} -> {}", service, responder, src);
+
+                    return self.tx.lock().unwrap().send_udp(responder, src, &response);
+                }
+                NamePolicy::Relay => {}
+            }
+        }
+
+        // Static hostname mappings
+        if dst.port() == dns::DNS_PORT {
+            if let Some(hosts) = &self.hosts {
+                if let Some(response) = hosts.reply(payload) {
+                    debug!("reply from hosts: {} -> {}", dst, src);
+                    if let Some(observer) = &self.observer {
+                        observer.on_dns_message(src, dst, &response, Some(DnsRoute::Hosts));
+                    }
+
+                    return self.tx.lock().unwrap().send_udp(dst, src, &response);
+                }
+            }
+        }
+
+        // DNS cache
+        #[cfg(feature = "dns")]
+        if dst.port() == dns::DNS_PORT {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Some(response) = tx_locked.get_dns_cache(payload) {
+                debug!("reply from DNS cache: {} -> {}", dst, src);
+                if let Some(observer) = &self.observer {
+                    observer.on_dns_message(src, dst, &response, Some(DnsRoute::Cache));
+                }
+
+                return tx_locked.send_udp(dst, src, &response);
+            }
+        }
+
+        // EDNS
+        #[cfg(feature = "dns")]
+        let query = match dst.port() == dns::DNS_PORT {
+            true => self
+                .tx
+                .lock()
+                .unwrap()
+                .set_dns_query(dst, src, payload, self.is_dns_stream()),
+            false => None,
+        };
+        #[cfg(feature = "dns")]
+        let payload = query.as_deref().unwrap_or(payload);
+
+        // QUIC
+        #[cfg_attr(not(feature = "quic"), allow(unused_mut))]
+        let mut target = self.udp_name_targets.get(&(src, dst)).copied().or(target);
+        #[cfg(feature = "quic")]
+        if let Some(initial) = Some(payload)
+            .filter(|_| !is_tunnel)
+            .and_then(Initial::parse)
+        {
+            debug!("receive {}: {} -> {}", initial, src, dst);
+            if let Some(observer) = &self.observer {
+                observer.on_quic_initial(src, dst, &initial);
+            }
+            #[cfg(feature = "capture")]
+            if let (Some(capture), Some(name)) = (&self.capture, initial.sni()) {
+                capture.capture_name(LayerKinds::Udp, src, dst, name);
+            }
+            if let (Some(filter), Some(name)) = (&self.filter, initial.sni()) {
+                match filter.filter_name(LayerKinds::Udp, dst, name) {
+                    Verdict::Accept => {}
+                    Verdict::Drop => {
+                        debug!("drop QUIC {} -> {} of {}", src, dst, name);
+
+                        return Ok(());
+                    }
+                    Verdict::Redirect(name_target) => {
+                        self.udp_name_targets.insert((src, dst), name_target);
+                        target = Some(name_target);
+                    }
+                }
+            }
+        }
+
+        // Filter
+        let dst = match target {
+            Some(target) if target != dst => {
+                self.tx.lock().unwrap().redirect_udp(dst, src, target);
+                target
+            }
+            _ => dst,
+        };
+
+        // Encrypted DNS
+        #[cfg(all(feature = "dns", feature = "tls"))]
+        if let (true, Some(upstream)) = (dst.port() == dns::DNS_PORT, &self.dns_upstream) {
+            let tx = self.get_tx();
+            let proxy = self.proxy.clone();
+            let upstream = Arc::clone(upstream);
+            let query = payload.to_vec();
+            tokio::spawn(async move {
+                match upstream.resolve(&proxy, &query).await {
+                    Ok(response) => {
+                        let mut tx_locked = tx.lock().unwrap();
+                        if let Err(ref e) =
+                            ForwardDatagram::forward(&mut *tx_locked, dst, src, &response)
+                        {
+                            warn!("handle receive: {}: {} -> {}: {}", "DNS", dst, src, e);
+                        }
+                    }
+                    Err(ref e) => warn!("handle send: {}: {} -> {}: {}", "DNS", src, upstream, e),
+                }
+            });
+
+            return Ok(());
+        }
+
+        // DNS over TCP
+        #[cfg(feature = "dns")]
+        if dst.port() == dns::DNS_PORT && self.dns_tcp {
+            let tx = self.get_tx();
+            let proxy = self.proxy.clone();
+            let query = payload.to_vec();
+            let server = self.tx.lock().unwrap().redirect_dns(dst, src);
+            tokio::spawn(async move {
+                match proxy::resolve_tcp(&proxy, server, &query).await {
+                    Ok(response) => {
+                        let mut tx_locked = tx.lock().unwrap();
+                        if let Err(ref e) =
+                            ForwardDatagram::forward(&mut *tx_locked, dst, src, &response)
+                        {
+                            warn!("handle receive: {}: {} -> {}: {}", "DNS", dst, src, e);
+                        }
+                    }
+                    Err(ref e) => warn!("handle send: {}: {} -> {}: {}", "DNS", src, dst, e),
+                }
+            });
+
+            return Ok(());
+        }
+
+        // DNS redirection
+        #[cfg(feature = "dns")]
+        let dst = if dst.port() == dns::DNS_PORT {
+            self.tx.lock().unwrap().redirect_dns(dst, src)
+        } else {
+            dst
+        };
 
-    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
-        let key = (src, dst);
+        // Quota
+        if !self.datagram_map.contains_key(&src) {
+            if let Some(quota) = &self.quota {
+                if quota.is_exceeded(*src.ip()) {
+                    debug!("drop UDP {} -> {}: quota exceeded", src, dst);
 
-        self.streams.remove(&key);
-        self.states.remove(&key);
+                    return Ok(());
+                }
+            }
+        }
 
-        self.tx.lock().unwrap().clean_up(dst, src);
-    }
+        // STUN
+        if let Some(message) = Some(payload)
+            .filter(|_| !is_tunnel)
+            .and_then(stun::Message::parse)
+        {
+            if message.is_binding_request() && self.stun_srcs.insert(src) {
+                debug!("STUN binding {} -> {}: pin the UDP association", src, dst);
+            }
+        }
 
-    async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        // Hairpinning
+        if self.is_hairpin {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Some(peer) = tx_locked.external_src(dst).filter(|peer| *peer != src) {
+                // Present the source as its external mapping, so the peer sees the same address
+                // as it would through the proxy
+                if let Some(external) = tx_locked.external_addr(src) {
+                    trace!("hairpin UDP {} -> {} to {}", src, dst, peer);
+                    return match self.ttl_policy {
+                        TtlPolicy::Fixed => tx_locked.send_udp(external, peer, payload),
+                        TtlPolicy::Preserve => {
+                            tx_locked.send_udp_with_ttl(external, peer, payload, self.ttl)
+                        }
+                        TtlPolicy::Decrement => match self.ttl {
+                            0 | 1 => {
+                                trace!("drop hairpin UDP {} -> {}: TTL exceeded", src, peer);
+                                Ok(())
+                            }
+                            ttl => tx_locked.send_udp_with_ttl(external, peer, payload, ttl - 1),
+                        },
+                    };
+                }
+            }
+        }
+
+        // QUIC connection migration, which is only followed in the same source
+        #[cfg(feature = "quic")]
+        if !is_tunnel && !self.datagram_map.contains_key(&src) && quic::is_short_header(payload) {
+            let prev_src = self.tx.lock().unwrap().quic_src(payload);
+            if let Some(prev_src) = prev_src {
+                if prev_src != src
+                    && prev_src.ip() == src.ip()
+                    && self.datagram_map.contains_key(&prev_src)
+                {
+                    self.migrate_udp_port(prev_src, src);
+                }
+            }
+        }
 
         // Bind
         let port = self.bind_local_udp_port(src).await?;
@@ -1834,10 +4959,35 @@ impl Redirector {
         self.datagrams
             .get_mut(&port)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?
-            .send_to(
-                payload.to_vec(),
-                SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
-            )?;
+            .send_to(payload.to_vec(), dst)?;
+
+        // Idle timer
+        let timeout = self
+            .udp_port_timeouts
+            .get(&src.port())
+            .or_else(|| self.udp_port_timeouts.get(&dst.port()))
+            .copied()
+            .or(self.udp_timeout)
+            .map(|timeout| match is_tunnel {
+                true => max(timeout, TUNNEL_TIMEOUT),
+                false => timeout,
+            });
+        if let Some(timeout) = timeout {
+            let timeout = match self.udp_timers.get(&src) {
+                Some(timer) => max(timer.timeout().as_millis() as u64, timeout),
+                None => timeout,
+            };
+            self.udp_timers.insert(src, Timer::new(timeout));
+        }
+
+        // Observer
+        if let Some(observer) = &self.observer {
+            let is_new = self.udp_flows.entry(src).or_default().insert(dst);
+            if is_new {
+                observer.on_flow_created(LayerKinds::Udp, src, dst);
+            }
+            observer.on_bytes_sent(LayerKinds::Udp, src, dst, payload.len());
+        }
 
         Ok(())
     }
@@ -1846,6 +4996,15 @@ impl Redirector {
         let local_port = self.datagram_map.get(&src);
         match local_port {
             Some(&local_port) => {
+                // Migrate from a dead association
+                if self
+                    .datagrams
+                    .get(&local_port)
+                    .map_or(false, |worker| worker.is_closed())
+                {
+                    return self.rebind_local_udp_port(src, local_port).await;
+                }
+
                 // Update LRU
                 self.udp_lru.get(&local_port);
 
@@ -1853,13 +5012,27 @@ impl Redirector {
             }
             None => {
                 let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
-                    match DatagramWorker::bind(self.get_tx(), src, &self.proxy).await {
-                        Ok((worker, port)) => {
+                    let restored = self.udp_restored.remove(&src);
+                    let port = match self.udp_port_strategy {
+                        UdpPortStrategy::Ephemeral => restored.unwrap_or(0),
+                        UdpPortStrategy::Preserve => src.port(),
+                    };
+                    match DatagramWorker::bind_to(self.get_tx(), src, port, &self.proxy).await {
+                        Ok((mut worker, port)) => {
+                            if self.udp_port_strategy == UdpPortStrategy::Preserve
+                                && port != src.port()
+                            {
+                                debug!("cannot preserve UDP port {}, bind {}", src, port);
+                            }
+                            if let Some(interval) = self.udp_keepalive {
+                                worker.set_keepalive(interval);
+                            }
                             self.datagrams.insert(port, worker);
 
                             // Update map and LRU
                             self.datagram_map.insert(src, port);
                             self.udp_lru.put(port, src);
+                            self.is_udp_mapping_dirty = true;
 
                             trace!("bind UDP port {} = {}", port, src);
 
@@ -1877,15 +5050,44 @@ impl Redirector {
                         if self.udp_lru.is_empty() {
                             Err(e)
                         } else {
-                            let pair = self.udp_lru.pop_lru().unwrap();
+                            // Prefer the least recently used port not pinned by STUN or tunnels
+                            let stun_srcs = &self.stun_srcs;
+                            let tunnel_srcs = &self.tunnel_srcs;
+                            let unpinned = self
+                                .udp_lru
+                                .iter()
+                                .rev()
+                                .find(|(_, prev_src)| {
+                                    !stun_srcs.contains(*prev_src)
+                                        && !tunnel_srcs.contains(*prev_src)
+                                })
+                                .map(|(&port, _)| port);
+                            let pair = match unpinned {
+                                Some(port) => {
+                                    let prev_src = self.udp_lru.pop(&port).unwrap();
+                                    (port, prev_src)
+                                }
+                                None => self.udp_lru.pop_lru().unwrap(),
+                            };
                             let port = pair.0;
                             let prev_src = pair.1;
 
                             // Reuse
                             self.datagram_map.remove(&prev_src);
+                            self.udp_timers.remove(&prev_src);
+                            self.stun_srcs.remove(&prev_src);
+                            self.tunnel_srcs.remove(&prev_src);
+                            {
+                                let mut tx_locked = self.tx.lock().unwrap();
+                                tx_locked.remove_external_addr(prev_src);
+                                tx_locked.set_tunnel_src(prev_src, false);
+                                tx_locked.remove_udp_activity(prev_src);
+                            }
+                            self.close_udp_flows(prev_src, CloseReason::Evicted);
                             trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
                             self.datagram_map.insert(src.clone(), port);
                             self.datagrams.get_mut(&port).unwrap().set_src(&src);
+                            self.is_udp_mapping_dirty = true;
 
                             // Update LRU
                             self.udp_lru.put(port, src.clone());
@@ -1898,13 +5100,65 @@ impl Redirector {
         }
     }
 
-    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
+    async fn rebind_local_udp_port(
+        &mut self,
+        src: SocketAddrV4,
+        local_port: u16,
+    ) -> io::Result<u16> {
+        // The mapping of the proxy changed with the association
+        self.datagrams.remove(&local_port);
+        self.tx.lock().unwrap().remove_external_addr(src);
+
+        // Prefer the same local port, so the mapping of the source stays stable
+        match DatagramWorker::bind_to(self.get_tx(), src, local_port, &self.proxy).await {
+            Ok((mut worker, port)) => {
+                if let Some(interval) = self.udp_keepalive {
+                    worker.set_keepalive(interval);
+                }
+                self.datagrams.insert(port, worker);
+
+                // Update map and LRU
+                if port != local_port {
+                    self.udp_lru.pop(&local_port);
+                    self.datagram_map.insert(src, port);
+                    self.is_udp_mapping_dirty = true;
+                }
+                self.udp_lru.put(port, src);
+
+                debug!(
+                    "migrate UDP {} from a dead association {} to {}",
+                    src, local_port, port
+                );
+
+                Ok(port)
+            }
+            Err(e) => {
+                self.unbind_local_udp_port(src, CloseReason::ProxyError);
+
+                Err(e)
+            }
+        }
+    }
+
+    fn unbind_local_udp_port(&mut self, src: SocketAddrV4, reason: CloseReason) {
+        self.udp_timers.remove(&src);
+        self.stun_srcs.remove(&src);
+        self.tunnel_srcs.remove(&src);
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            tx_locked.remove_external_addr(src);
+            tx_locked.set_tunnel_src(src, false);
+            tx_locked.remove_udp_activity(src);
+        }
+
         let local_port = self.datagram_map.get(&src);
         match local_port {
             Some(&local_port) => {
                 self.datagrams.remove(&local_port);
                 self.udp_lru.pop(&local_port);
                 self.datagram_map.remove(&src);
+                self.is_udp_mapping_dirty = true;
+                self.close_udp_flows(src, reason);
 
                 trace!("unbind UDP port {} = {}", local_port, src);
             }
@@ -1912,6 +5166,341 @@ impl Redirector {
         }
     }
 
+    fn reap_udp_ports(&mut self) {
+        let srcs = self
+            .udp_timers
+            .iter()
+            .filter(|(_, timer)| timer.is_timedout())
+            .map(|(src, _)| *src)
+            .collect::<Vec<_>>();
+        for src in srcs {
+            // Datagrams sent to the source also keep the port alive
+            let timer = self.udp_timers[&src];
+            if let Some(instant) = self.tx.lock().unwrap().udp_activity(src) {
+                let timer = Timer::new_at(instant, timer.timeout().as_millis() as u64);
+                if !timer.is_timedout() {
+                    self.udp_timers.insert(src, timer);
+                    continue;
+                }
+            }
+
+            debug!("reap idle UDP {}", src);
+            self.unbind_local_udp_port(src, CloseReason::Idle);
+        }
+    }
+
+    fn save_udp_mappings(&mut self) {
+        if !self.is_udp_mapping_dirty {
+            return;
+        }
+        let path = match &self.udp_mapping_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut s = String::new();
+        for (src, local_port) in self.datagram_map.iter().chain(self.udp_restored.iter()) {
+            s.push_str(&format!("{} {}\n", src, local_port));
+        }
+        // Write to a temporary file and rename it to avoid a partial file
+        let tmp_path = format!("{}.tmp", path);
+        match fs::write(&tmp_path, s).and_then(|_| fs::rename(&tmp_path, path)) {
+            Ok(_) => self.is_udp_mapping_dirty = false,
+            Err(ref e) => warn!("save UDP mappings: {}", e),
+        }
+    }
+
+    fn save_flow_state(&self) {
+        let path = match &self.flow_state_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut s = String::new();
+        for (src, dst, sequence, hardware_addr) in self.tcp_flows() {
+            s.push_str(&format!(
+                "tcp {} {} {} {}\n",
+                src, dst, sequence, hardware_addr
+            ));
+        }
+        // Write to a temporary file and rename it to avoid a partial file
+        let tmp_path = format!("{}.tmp", path);
+        if let Err(ref e) = fs::write(&tmp_path, s).and_then(|_| fs::rename(&tmp_path, path)) {
+            warn!("save flow state: {}", e);
+        }
+    }
+
+    /// Returns the TCP connections with the sequence number and the hardware address of their
+    /// sources.
+    fn tcp_flows(&self) -> Vec<(SocketAddrV4, SocketAddrV4, u32, HardwareAddr)> {
+        let tx_locked = self.tx.lock().unwrap();
+        self.streams
+            .keys()
+            .filter_map(|(src, dst)| {
+                let hardware_addr = tx_locked.src_hardware_addr(*src.ip())?;
+                let state = tx_locked.get_state(*dst, *src)?;
+
+                Some((*src, *dst, state.sequence(), hardware_addr))
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn accept_handover(&self) -> Option<UnixStream> {
+        let listener = self.handover_listener.as_ref()?;
+        match listener.accept() {
+            Ok((stream, _)) => Some(stream),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    warn!("accept handover: {}", e);
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Hands over the UDP associations through the proxy and the TCP connections to the new
+    /// process. Associations tunneled over TCP and of sources of unknown hardware addresses are
+    /// left to be closed.
+    #[cfg(unix)]
+    fn hand_over(&mut self, stream: UnixStream) {
+        let mut handover = Handover::default();
+        {
+            let tx_locked = self.tx.lock().unwrap();
+            for worker in self.datagrams.values() {
+                if worker.is_closed() {
+                    continue;
+                }
+                let (control, socket) = match worker.raw_fds() {
+                    Some(fds) => fds,
+                    None => continue,
+                };
+                let src = worker.src();
+                let hardware_addr = match tx_locked.src_hardware_addr(*src.ip()) {
+                    Some(hardware_addr) => hardware_addr,
+                    None => continue,
+                };
+                handover.associations.push(Association {
+                    src,
+                    hardware_addr,
+                    is_stun: self.stun_srcs.contains(&src),
+                    is_tunnel: self.tunnel_srcs.contains(&src),
+                    control,
+                    socket,
+                });
+            }
+        }
+        handover.connections = self.tcp_flows();
+
+        match handover::send(&stream, &handover) {
+            Ok(_) => info!(
+                "Hand over {} UDP associations and {} TCP connections to the new process",
+                handover.associations.len(),
+                handover.connections.len()
+            ),
+            Err(ref e) => warn!("hand over: {}", e),
+        }
+    }
+
+    fn reset_restored_tcp_streams(&mut self) {
+        let restored = std::mem::take(&mut self.tcp_restored);
+        for (src, dst, sequence, hardware_addr) in restored {
+            debug!("reset restored TCP {} -> {}", src, dst);
+
+            // Send ACK/RST, a source with a later sequence number answers with an ACK, which is
+            // reset again as an untracked connection
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tx_locked.src_hardware_addr(*src.ip()).is_none() {
+                tx_locked.set_src_hardware_addr(*src.ip(), hardware_addr);
+            }
+            if let Err(ref e) = tx_locked.send_tcp_ack_rst_untracked(dst, src, sequence) {
+                warn!("handle {}: {} -> {}: {}", "TCP", src, dst, e);
+            }
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    fn migrate_udp_port(&mut self, prev_src: SocketAddrV4, src: SocketAddrV4) {
+        let port = match self.datagram_map.remove(&prev_src) {
+            Some(port) => port,
+            None => return,
+        };
+        self.datagram_map.insert(src, port);
+        self.udp_lru.put(port, src);
+        if let Some(worker) = self.datagrams.get_mut(&port) {
+            worker.set_src(&src);
+        }
+        if let Some(timer) = self.udp_timers.remove(&prev_src) {
+            self.udp_timers.insert(src, timer);
+        }
+        if self.stun_srcs.remove(&prev_src) {
+            self.stun_srcs.insert(src);
+        }
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Some(external) = tx_locked.external_addr(prev_src) {
+                tx_locked.remove_external_addr(prev_src);
+                tx_locked.set_external_addr(src, external);
+            }
+            tx_locked.migrate_quic(prev_src, src);
+        }
+        let name_targets = self
+            .udp_name_targets
+            .iter()
+            .filter(|((name_src, _), _)| *name_src == prev_src)
+            .map(|((_, dst), target)| (*dst, *target))
+            .collect::<Vec<_>>();
+        for (dst, target) in name_targets {
+            self.udp_name_targets.insert((src, dst), target);
+        }
+        self.close_udp_flows(prev_src, CloseReason::Migrated);
+        self.is_udp_mapping_dirty = true;
+
+        debug!("migrate UDP port {} = {} to {}", port, prev_src, src);
+    }
+
+    fn close_udp_flows(&mut self, src: SocketAddrV4, reason: CloseReason) {
+        self.udp_name_targets
+            .retain(|(name_src, _), _| *name_src != src);
+        if let Some(dsts) = self.udp_flows.remove(&src) {
+            if let Some(observer) = &self.observer {
+                for dst in dsts {
+                    observer.on_flow_closed(LayerKinds::Udp, src, dst, reason);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "alert")]
+    fn alert_upstream(&mut self, is_connected: bool) {
+        let alerter = match &self.alerter {
+            Some(alerter) => alerter,
+            None => return,
+        };
+
+        if is_connected {
+            self.connect_errors = 0;
+            if self.is_upstream_down {
+                self.is_upstream_down = false;
+                alerter.alert(AlertEvent::UpstreamUp);
+            }
+        } else {
+            self.connect_errors = self.connect_errors.checked_add(1).unwrap_or(usize::MAX);
+            if !self.is_upstream_down && self.connect_errors >= alert::UPSTREAM_DOWN_ERRORS {
+                self.is_upstream_down = true;
+                alerter.alert(AlertEvent::UpstreamDown(self.connect_errors));
+            }
+        }
+    }
+
+    #[cfg(feature = "alert")]
+    fn alert_malformed(&mut self) {
+        let threshold = match self
+            .alerter
+            .as_ref()
+            .and_then(|alerter| alerter.drop_threshold())
+        {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        let count = self.malformed - self.malformed_alerted;
+        self.malformed_alerted = self.malformed;
+        if count > threshold {
+            self.alerter
+                .as_ref()
+                .unwrap()
+                .alert(AlertEvent::FramesDropped(count));
+        }
+    }
+
+    #[cfg(feature = "alert")]
+    fn alert_buffer(&mut self) {
+        let threshold = match self
+            .alerter
+            .as_ref()
+            .and_then(|alerter| alerter.buffer_threshold())
+        {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        let tx_buffer_size = self.tx.lock().unwrap().buffer_size();
+        let rx_buffer_size: usize = self.states.values().map(|state| state.cache().len()).sum();
+        let size = tx_buffer_size + rx_buffer_size;
+        // Alert once until the buffer drains below the threshold
+        if size > threshold {
+            if !self.is_buffer_full {
+                self.is_buffer_full = true;
+                self.alerter
+                    .as_ref()
+                    .unwrap()
+                    .alert(AlertEvent::BufferFull(size));
+            }
+        } else {
+            self.is_buffer_full = false;
+        }
+    }
+
+    fn log_stats(&mut self) {
+        let client_stats = self.tx.lock().unwrap().client_stats();
+        for stats in client_stats.iter().filter(|stats| stats.active() > 0) {
+            info!("Statistics of {}", stats);
+        }
+        let upstreams = self.proxy.latency().lock().unwrap().upstreams();
+        for stats in upstreams.iter() {
+            info!("Latency of upstream {}", stats);
+        }
+        if let Some(stages) = &self.stages {
+            info!("Latency of stages: {}", stages.lock().unwrap());
+        }
+        self.log_quota();
+        if self.malformed > self.malformed_reported {
+            info!(
+                "Dropped {} malformed frames",
+                self.malformed - self.malformed_reported
+            );
+            self.malformed_reported = self.malformed;
+        }
+    }
+
+    fn log_snapshot(&self) {
+        let (client_stats, flow_stats, tx_buffer_size) = {
+            let tx_locked = self.tx.lock().unwrap();
+            (
+                tx_locked.client_stats(),
+                tx_locked.flow_stats(),
+                tx_locked.buffer_size(),
+            )
+        };
+        let rx_buffer_size: usize = self.states.values().map(|state| state.cache().len()).sum();
+
+        info!(
+            "Snapshot: {} TCP connections, {} UDP ports, {} Bytes buffered, {} malformed frames, {} SOCKS errors",
+            self.streams.len(),
+            self.datagrams.len(),
+            tx_buffer_size + rx_buffer_size,
+            self.malformed,
+            self.socks_errors
+        );
+        for stats in client_stats.iter() {
+            info!("Statistics of {}", stats);
+        }
+        for stats in flow_stats.iter() {
+            info!("Statistics of {}", stats);
+        }
+        self.log_quota();
+    }
+
+    fn log_quota(&self) {
+        if let Some(quota) = &self.quota {
+            for (ip_addr, usage) in quota.usages() {
+                info!("Quota usage of {}: {} of {}", ip_addr, usage, quota.quota());
+            }
+        }
+    }
+
     fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
         Arc::clone(&self.tx)
     }
@@ -1927,3 +5516,1109 @@ impl Redirector {
         }
     }
 }
+
+#[test]
+fn forwarder_observer() {
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl FlowObserver for Recorder {
+        fn on_bytes_received(
+            &self,
+            _: packet::layer::LayerKind,
+            dst: SocketAddrV4,
+            src: SocketAddrV4,
+            n: usize,
+        ) {
+            let event = format!("receive {} -> {} {}", dst, src, n);
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    let mut forwarder = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+    forwarder.set_src_hardware_addr(
+        "10.6.0.1".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    );
+    let recorder = Arc::new(Recorder::default());
+    forwarder.set_observer(recorder.clone());
+
+    // A datagram from the destination to the source
+    let dst = "1.1.1.1:5000".parse().unwrap();
+    let src = "10.6.0.1:50000".parse().unwrap();
+    ForwardDatagram::forward(&mut forwarder, dst, src, &[1, 2, 3]).unwrap();
+
+    // A segment of a TCP connection which does not exist is not relayed
+    assert!(ForwardStream::forward(&mut forwarder, dst, src, &[1, 2, 3]).is_err());
+
+    let events = recorder.events.lock().unwrap().clone();
+    assert_eq!(
+        events,
+        vec![String::from("receive 1.1.1.1:5000 -> 10.6.0.1:50000 3")]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_arp_reply() {
+    use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations};
+    use pnet::packet::ethernet::EtherTypes;
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let gw_ip_addr: Ipv4Addr = "10.6.0.254".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some(gw_ip_addr),
+        ProxyConfig::new_socks("127.0.0.1:1080".parse().unwrap(), false, false, None),
+    );
+
+    // ARP request from the source to the gateway
+    let arp = Arp::from(arp::Arp {
+        hardware_type: ArpHardwareTypes::Ethernet,
+        protocol_type: EtherTypes::Ipv4,
+        hw_addr_len: 6,
+        proto_addr_len: 4,
+        operation: ArpOperations::Request,
+        sender_hw_addr: src_hardware_addr,
+        sender_proto_addr: src_ip_addr,
+        target_hw_addr: pcap::HARDWARE_ADDR_UNSPECIFIED,
+        target_proto_addr: gw_ip_addr,
+        payload: vec![],
+    });
+    let indicator = EthernetBuilder::new(src_hardware_addr, pcap::HARDWARE_ADDR_BROADCAST)
+        .arp(arp)
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    loopback.inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // Gratuitous ARP and ARP reply
+    let frames = loopback.take_all();
+    assert_eq!(frames.len(), 2);
+    let indicator = Indicator::from(&frames[1]).unwrap();
+    let arp = indicator.arp().unwrap();
+    assert!(arp.is_reply());
+    assert_eq!(arp.src(), gw_ip_addr);
+    assert_eq!(arp.src_hardware_addr(), local_hardware_addr);
+    assert_eq!(arp.dst_hardware_addr(), src_hardware_addr);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn forwarder_buffer_size() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+    assert_eq!(forwarder.buffer_size(), 0);
+
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    for port in [1000, 1001].iter() {
+        let src = SocketAddrV4::new("10.6.0.1".parse().unwrap(), *port);
+        let mut state = TcpTxState::new(src, dst, 1000, 2000, 65535, None, false, None, 1460);
+        state.append_queue(&[0u8; 100]);
+        forwarder.set_state(dst, src, state);
+    }
+    assert_eq!(forwarder.buffer_size(), 200);
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_snapshot() {
+    use packet::builder::Ipv4Builder;
+    use tokio::net::TcpListener;
+
+    // The proxy is unreachable
+    let proxy = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    };
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks(proxy, false, false, None),
+    );
+
+    // SYN from the source and a malformed frame
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, "1.1.1.1".parse().unwrap()))
+        .tcp(TcpBuilder::new(50000, 80).sequence(1000).syn())
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    loopback.inject(&frame);
+    loopback.inject(&[0u8; 10]);
+
+    // Request a snapshot
+    let snapshot = redirector.snapshot_flag();
+    snapshot.store(true, Ordering::Relaxed);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    assert!(!snapshot.load(Ordering::Relaxed));
+    assert_eq!(redirector.socks_errors(), 1);
+    assert_eq!(redirector.malformed(), 1);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn redirector_udp_mapping_file() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        "10.6.0.0/24".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks("127.0.0.1:1080".parse().unwrap(), false, false, None),
+    );
+
+    let path = std::env::temp_dir().join("pcap2socks_udp_mapping_file");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    assert_eq!(redirector.set_udp_mapping_file(path).unwrap(), 0);
+
+    fs::write(path, "10.6.0.1:5000 40000\nmalformed\n10.6.0.2:5000 port\n").unwrap();
+    assert_eq!(redirector.set_udp_mapping_file(path).unwrap(), 1);
+    assert_eq!(
+        redirector
+            .udp_restored
+            .get(&"10.6.0.1:5000".parse().unwrap()),
+        Some(&40000)
+    );
+
+    // Mappings not bound yet are saved again
+    redirector.is_udp_mapping_dirty = true;
+    redirector.save_udp_mappings();
+    assert_eq!(fs::read_to_string(path).unwrap(), "10.6.0.1:5000 40000\n");
+    assert!(!redirector.is_udp_mapping_dirty);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn forwarder_tcp_zero_window() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+
+    // The source advertises a zero window
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    let src: SocketAddrV4 = "10.6.0.1:1000".parse().unwrap();
+    let mut state = TcpTxState::new(src, dst, 1000, 2000, 0, None, false, None, 1460);
+    state.append_queue(&[0u8; 100]);
+    forwarder.set_state(dst, src, state);
+    forwarder.send_tcp(dst, src).unwrap();
+    assert!(loopback.take().is_none());
+    assert!(forwarder.get_state(dst, src).unwrap().persist().is_some());
+
+    // Probe after the persist timer times out
+    thread::sleep(
+        forwarder
+            .get_state(dst, src)
+            .unwrap()
+            .persist()
+            .unwrap()
+            .timeout(),
+    );
+    thread::sleep(Duration::from_millis(10));
+    forwarder.retransmit_tcp_timedout(dst, src).unwrap();
+    let frame = loopback.take().unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    let tcp = indicator.tcp().unwrap();
+    assert_eq!(tcp.sequence(), 999);
+    assert_eq!(tcp.acknowledgement(), 2000);
+    assert_eq!(indicator.content_len(), indicator.len());
+    assert!(loopback.take().is_none());
+
+    // Stop probing once the window opens
+    let state = forwarder.get_state_mut(dst, src).unwrap();
+    state.set_src_window(65535);
+    assert!(state.persist().is_none());
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_observer() {
+    use packet::builder::Ipv4Builder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl FlowObserver for Recorder {
+        fn on_flow_created(&self, _: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+            let event = format!("create {} -> {}", src, dst);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_flow_connected(
+            &self,
+            _: LayerKind,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            upstream: SocketAddr,
+        ) {
+            let event = format!("connect {}", upstream);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_flow_closed(
+            &self,
+            _: LayerKind,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            reason: CloseReason,
+        ) {
+            let event = format!("close {}", reason);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_bytes_sent(&self, _: LayerKind, _: SocketAddrV4, _: SocketAddrV4, n: usize) {
+            let event = format!("send {}", n);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_parse_error(&self, frame: &[u8]) {
+            let event = format!("malformed {}", frame.len());
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    // A SOCKS5 server which accepts the connection
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).await.unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut payload = Vec::new();
+        let _ = stream.read_to_end(&mut payload).await;
+    });
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let dst_ip_addr: Ipv4Addr = "1.1.1.1".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks(proxy, false, false, None),
+    );
+    let recorder = Arc::new(Recorder::default());
+    redirector.set_observer(recorder.clone());
+
+    // SYN with data, RST and a malformed frame from the source
+    let payload = [1u8, 2, 3, 4, 5];
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, dst_ip_addr))
+        .tcp(TcpBuilder::new(50000, 80).sequence(1000).syn())
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut frame, &payload)
+        .unwrap();
+    loopback.inject(&frame);
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, dst_ip_addr))
+        .tcp(TcpBuilder::new(50000, 80).sequence(1006).rst())
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    loopback.inject(&frame);
+    loopback.inject(&[0u8; 10]);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    let events = recorder.events.lock().unwrap().clone();
+    assert_eq!(
+        events,
+        vec![
+            String::from("create 10.6.0.1:50000 -> 1.1.1.1:80"),
+            format!("connect {}", proxy),
+            String::from("send 5"),
+            format!("close {}", CloseReason::Reset),
+            String::from("malformed 10"),
+        ]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_self_exclusion() {
+    use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations};
+    use pnet::packet::ethernet::EtherTypes;
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let proxy_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let proxy_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let gw_ip_addr: Ipv4Addr = "10.6.0.254".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    // The proxy lives in the source
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new("10.6.0.0".parse().unwrap(), 24).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some(gw_ip_addr),
+        ProxyConfig::new_socks(SocketAddrV4::new(proxy_ip_addr, 1080), false, false, None),
+    );
+
+    // ARP request from the proxy to the gateway
+    let arp = Arp::from(arp::Arp {
+        hardware_type: ArpHardwareTypes::Ethernet,
+        protocol_type: EtherTypes::Ipv4,
+        hw_addr_len: 6,
+        proto_addr_len: 4,
+        operation: ArpOperations::Request,
+        sender_hw_addr: proxy_hardware_addr,
+        sender_proto_addr: proxy_ip_addr,
+        target_hw_addr: pcap::HARDWARE_ADDR_UNSPECIFIED,
+        target_proto_addr: gw_ip_addr,
+        payload: vec![],
+    });
+    let indicator = EthernetBuilder::new(proxy_hardware_addr, pcap::HARDWARE_ADDR_BROADCAST)
+        .arp(arp)
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    loopback.inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // Gratuitous ARP only
+    let frames = loopback.take_all();
+    assert_eq!(frames.len(), 1);
+    let indicator = Indicator::from(&frames[0]).unwrap();
+    let arp = indicator.arp().unwrap();
+    assert!(!arp.is_reply());
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_arp_defend() {
+    use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperation, ArpOperations};
+    use pnet::packet::ethernet::EtherTypes;
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let conflict_hardware_addr: HardwareAddr = "33:33:33:33:33:33".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let gw_ip_addr: Ipv4Addr = "10.6.0.254".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some(gw_ip_addr),
+        ProxyConfig::new_socks("127.0.0.1:1080".parse().unwrap(), false, false, None),
+    );
+
+    let inject = |operation: ArpOperation,
+                  hardware_addr: HardwareAddr,
+                  ip_addr: Ipv4Addr,
+                  target: HardwareAddr| {
+        let arp = Arp::from(arp::Arp {
+            hardware_type: ArpHardwareTypes::Ethernet,
+            protocol_type: EtherTypes::Ipv4,
+            hw_addr_len: 6,
+            proto_addr_len: 4,
+            operation,
+            sender_hw_addr: hardware_addr,
+            sender_proto_addr: ip_addr,
+            target_hw_addr: target,
+            target_proto_addr: gw_ip_addr,
+            payload: vec![],
+        });
+        let indicator = EthernetBuilder::new(hardware_addr, pcap::HARDWARE_ADDR_BROADCAST)
+            .arp(arp)
+            .build()
+            .unwrap();
+        let mut frame = vec![0u8; indicator.len()];
+        indicator.serialize(&mut frame).unwrap();
+        loopback.inject(&frame);
+    };
+    // Another host announces the published address 3 times in a row, where the first one is
+    // defended, and the second one backs off
+    for _ in 0..3 {
+        inject(
+            ArpOperations::Reply,
+            conflict_hardware_addr,
+            gw_ip_addr,
+            pcap::HARDWARE_ADDR_BROADCAST,
+        );
+    }
+    // ARP request from the source to the gateway in the backoff
+    inject(
+        ArpOperations::Request,
+        src_hardware_addr,
+        src_ip_addr,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+    );
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // Gratuitous ARP and a single defense
+    let frames = loopback.take_all();
+    assert_eq!(frames.len(), 2);
+    for frame in frames {
+        let indicator = Indicator::from(&frame).unwrap();
+        let arp = indicator.arp().unwrap();
+        assert_eq!(arp.src(), gw_ip_addr);
+        assert_eq!(arp.src_hardware_addr(), local_hardware_addr);
+    }
+}
+
+#[test]
+fn meminfo_parse() {
+    let s = "MemTotal:         124136 kB\nMemFree:           49988 kB\n";
+    assert_eq!(parse_meminfo(s), Some(124136 * 1024));
+    assert_eq!(parse_meminfo("MemFree: 1 kB\n"), None);
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_ping() {
+    use packet::builder::Ipv4Builder;
+    use pnet::packet::icmp::{echo_request, Icmp, IcmpTypes};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A SOCKS5 server which returns the target of the connection
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).await.unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        SocketAddrV4::new(
+            Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]),
+            u16::from_be_bytes([buffer[8], buffer[9]]),
+        )
+    });
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let dst_ip_addr: Ipv4Addr = "1.1.1.1".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks(proxy, false, false, None),
+    );
+    redirector.set_ping_port(443);
+
+    // ICMPv4 echo request from the source
+    let mut payload = vec![0u8; 4];
+    payload[..2].copy_from_slice(&1u16.to_ne_bytes());
+    payload[2..].copy_from_slice(&2u16.to_ne_bytes());
+    payload.extend_from_slice(b"ping");
+    let icmpv4 = Icmpv4::from(Icmp {
+        icmp_type: IcmpTypes::EchoRequest,
+        icmp_code: echo_request::IcmpCodes::NoCode,
+        checksum: 0,
+        payload,
+    });
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, dst_ip_addr))
+        .transport(Layers::Icmpv4(icmpv4))
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    loopback.inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // Measured on the port, and replied with the data
+    assert_eq!(server.await.unwrap(), SocketAddrV4::new(dst_ip_addr, 443));
+    let replies = loopback
+        .take_all()
+        .into_iter()
+        .filter_map(|frame| {
+            let indicator = Indicator::from(&frame)?;
+            let ipv4 = indicator.ipv4()?;
+            let icmpv4 = indicator.icmpv4()?;
+            match icmpv4.is_echo_reply() {
+                true => Some((
+                    ipv4.src(),
+                    ipv4.dst(),
+                    icmpv4.identifier()?,
+                    icmpv4.sequence_number()?,
+                    icmpv4.data()?.to_vec(),
+                )),
+                false => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        replies,
+        vec![(dst_ip_addr, src_ip_addr, 1, 2, b"ping".to_vec())]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn forwarder_quick_ack_interactive() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+    forwarder.set_delayed_ack(true);
+    forwarder.set_delayed_ack_bulk_only(true);
+
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    let src: SocketAddrV4 = "10.6.0.1:1000".parse().unwrap();
+    let mut state = TcpTxState::new(src, dst, 0, 0, 65535, None, false, None, 1460);
+    // A download is bulk toward the source but interactive from it
+    state.add_sent(qos::BULK_THRESHOLD);
+    forwarder.set_state(dst, src, state);
+
+    forwarder.send_tcp_delay_ack_0(dst, src).unwrap();
+    assert_eq!(loopback.take_all().len(), 1);
+
+    // An upload is bulk from the source, whose ACKs are delayed
+    forwarder
+        .get_state_mut(dst, src)
+        .unwrap()
+        .add_received(qos::BULK_THRESHOLD);
+    forwarder.send_tcp_delay_ack_0(dst, src).unwrap();
+    assert!(loopback.take_all().is_empty());
+    assert!(forwarder.get_state(dst, src).unwrap().delayed_ack());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn forwarder_tcp_keepalive() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+
+    let dst: SocketAddrV4 = "1.1.1.1:3074".parse().unwrap();
+    let src: SocketAddrV4 = "10.6.0.1:1000".parse().unwrap();
+    assert!(forwarder.send_tcp_keepalive(dst, src).is_err());
+    forwarder.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 1000, 2000, 65535, None, false, None, 1460),
+    );
+
+    // The sequence before the send next without payload elicits an ACK
+    forwarder.send_tcp_keepalive(dst, src).unwrap();
+    let frames = loopback.take_all();
+    assert_eq!(frames.len(), 1);
+    let indicator = Indicator::from(&frames[0]).unwrap();
+    let tcp = indicator.tcp().unwrap();
+    assert_eq!(tcp.sequence(), 999);
+    assert_eq!(tcp.acknowledgement(), 2000);
+    assert_eq!(indicator.content_len(), indicator.len());
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_tcp_syn_with_data() {
+    use packet::builder::Ipv4Builder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A SOCKS5 server which returns the first payload of the connection
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).await.unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut payload = [0u8; 5];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        payload
+    });
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let dst_ip_addr: Ipv4Addr = "1.1.1.1".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks(proxy, false, false, None),
+    );
+
+    // SYN with data from the source
+    let payload = [1u8, 2, 3, 4, 5];
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, dst_ip_addr))
+        .tcp(TcpBuilder::new(50000, 80).sequence(1000).syn())
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut frame, &payload)
+        .unwrap();
+    loopback.inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // A single TCP ACK/SYN acknowledging the data
+    let ack_syns = loopback
+        .take_all()
+        .into_iter()
+        .filter_map(|frame| {
+            let indicator = Indicator::from(&frame)?;
+            let tcp = indicator.tcp()?;
+            match tcp.is_syn() && tcp.is_ack() {
+                true => Some(tcp.acknowledgement()),
+                false => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ack_syns, vec![1000 + 1 + payload.len() as u32]);
+    assert_eq!(server.await.unwrap(), payload);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn forwarder_blackhole_reprobe() {
+    let loopback = pcap::Loopback::new();
+    let (tx, _) = loopback.open();
+    let mut forwarder = Forwarder::new(
+        tx,
+        1500,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "192.168.1.2".parse().unwrap(),
+    );
+
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    let src: SocketAddrV4 = "10.6.0.1:1000".parse().unwrap();
+    forwarder.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 1000, 2000, 65535, None, false, None, 1460),
+    );
+    let advertised_mss = |forwarder: &mut Forwarder| {
+        forwarder.send_tcp_ack_syn(dst, src).unwrap();
+        let frame = loopback.take().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+
+        indicator.tcp().unwrap().mss()
+    };
+    assert_eq!(advertised_mss(&mut forwarder), Some(1460));
+
+    // Small writes are not stuck in blackholes
+    for n in 1..=BLACKHOLE_STALLS {
+        forwarder.stall(dst, src, 100, n).unwrap();
+    }
+    assert_eq!(advertised_mss(&mut forwarder), Some(1460));
+
+    // Full-sized writes stalled
+    for n in 1..=BLACKHOLE_STALLS + 1 {
+        forwarder.stall(dst, src, 1460, n).unwrap();
+    }
+    assert_eq!(advertised_mss(&mut forwarder), Some(1412));
+
+    // Probe the larger MTU after a while
+    forwarder
+        .blackhole_mtu_map
+        .insert(*src.ip(), (1400, Timer::new_at(Instant::now(), 0)));
+    thread::sleep(Duration::from_millis(1));
+    assert_eq!(advertised_mss(&mut forwarder), Some(1412));
+    forwarder
+        .blackhole_mtu_map
+        .insert(*src.ip(), (1452, Timer::new_at(Instant::now(), 0)));
+    thread::sleep(Duration::from_millis(1));
+    assert_eq!(advertised_mss(&mut forwarder), Some(1460));
+    assert!(forwarder.blackhole_mtu_map.is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_quota_direct() {
+    use packet::builder::Ipv4Builder;
+    use quota::{Quota, QuotaPeriod};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // The proxy is unreachable, while the destination is connected directly
+    let proxy = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut payload = [0u8; 5];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        payload
+    });
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+
+    let loopback = pcap::Loopback::new();
+    let (tx, mut rx) = loopback.open();
+    let forwarder = Forwarder::new(
+        tx,
+        1500,
+        local_hardware_addr,
+        "192.168.1.2".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(src_ip_addr, 32).unwrap(),
+        "192.168.1.2".parse().unwrap(),
+        Some("10.6.0.254".parse().unwrap()),
+        ProxyConfig::new_socks(proxy, false, false, None),
+    );
+    // The source has used up its quota
+    redirector.set_quota(Arc::new(QuotaTracker::new(Quota::new(0, QuotaPeriod::Day))));
+    redirector.set_quota_policy(QuotaPolicy::Direct);
+
+    let payload = [1u8, 2, 3, 4, 5];
+    let indicator = EthernetBuilder::new(src_hardware_addr, local_hardware_addr)
+        .ipv4(Ipv4Builder::new(src_ip_addr, *dst.ip()))
+        .tcp(TcpBuilder::new(50000, dst.port()).sequence(1000).syn())
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut frame, &payload)
+        .unwrap();
+    loopback.inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    redirector
+        .open_monitored(&mut rx, Some(is_running), None, None)
+        .await
+        .unwrap();
+
+    // Accepted rather than reset
+    let is_accepted = loopback.take_all().into_iter().any(|frame| {
+        Indicator::from(&frame)
+            .and_then(|indicator| indicator.tcp().map(|tcp| tcp.is_syn() && tcp.is_ack()))
+            .unwrap_or(false)
+    });
+    assert!(is_accepted);
+    assert_eq!(server.await.unwrap(), payload);
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn redirector_arp_probe() {
+    use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations};
+    use pnet::packet::ethernet::EtherTypes;
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let conflict_hardware_addr: HardwareAddr = "33:33:33:33:33:33".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let gw_ip_addr: Ipv4Addr = "10.6.0.254".parse().unwrap();
+
+    let open = |is_conflict: bool| {
+        let loopback = pcap::Loopback::new();
+        let (tx, mut rx) = loopback.open();
+        let forwarder = Forwarder::new(
+            tx,
+            1500,
+            local_hardware_addr,
+            "192.168.1.2".parse().unwrap(),
+        );
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            Ipv4Network::new(src_ip_addr, 32).unwrap(),
+            "192.168.1.2".parse().unwrap(),
+            Some(gw_ip_addr),
+            ProxyConfig::new_socks("127.0.0.1:1080".parse().unwrap(), false, false, None),
+        );
+        redirector.set_arp_probe(true);
+
+        // Another host replies the probes
+        if is_conflict {
+            let arp = Arp::from(arp::Arp {
+                hardware_type: ArpHardwareTypes::Ethernet,
+                protocol_type: EtherTypes::Ipv4,
+                hw_addr_len: 6,
+                proto_addr_len: 4,
+                operation: ArpOperations::Reply,
+                sender_hw_addr: conflict_hardware_addr,
+                sender_proto_addr: gw_ip_addr,
+                target_hw_addr: local_hardware_addr,
+                target_proto_addr: Ipv4Addr::UNSPECIFIED,
+                payload: vec![],
+            });
+            let indicator = EthernetBuilder::new(conflict_hardware_addr, local_hardware_addr)
+                .arp(arp)
+                .build()
+                .unwrap();
+            let mut frame = vec![0u8; indicator.len()];
+            indicator.serialize(&mut frame).unwrap();
+            loopback.inject(&frame);
+        }
+
+        async move {
+            let is_running = Arc::new(AtomicBool::new(true));
+            let is_running_cloned = Arc::clone(&is_running);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(
+                    (ARP_PROBE_NUM as u64) * ARP_PROBE_INTERVAL + ARP_PROBE_WAIT + 200,
+                ));
+                is_running_cloned.store(false, Ordering::Relaxed);
+            });
+            let result = redirector
+                .open_monitored(&mut rx, Some(is_running), None, None)
+                .await;
+
+            (result, loopback.take_all())
+        }
+    };
+
+    // Refuse to publish
+    let (result, _) = open(true).await;
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+    // Publish after probes
+    let (result, frames) = open(false).await;
+    result.unwrap();
+    let arps = frames
+        .iter()
+        .map(|frame| {
+            let indicator = Indicator::from(frame).unwrap();
+            let arp = indicator.arp().unwrap();
+
+            (arp.src(), arp.dst())
+        })
+        .collect::<Vec<_>>();
+    let mut expected = vec![(Ipv4Addr::UNSPECIFIED, gw_ip_addr); ARP_PROBE_NUM];
+    expected.push((gw_ip_addr, gw_ip_addr));
+    assert_eq!(arps, expected);
+}
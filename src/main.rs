@@ -1,36 +1,231 @@
 use env_logger::fmt::{Color, Formatter, Target};
 use ipnetwork::Ipv4Network;
-use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
 use std::clone::Clone;
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::io::{self, Write};
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::clap::ErrorKind;
 use structopt::StructOpt;
+#[cfg(feature = "api")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "api")]
+use tokio::net::TcpListener;
+use tokio::runtime;
+#[cfg(unix)]
+use tokio::signal;
 
+use pcap2socks::adaptive::AdaptiveRouter;
+use pcap2socks::affinity::{self, CoreSet};
+#[cfg(feature = "alert")]
+use pcap2socks::alert::{AlertHook, Alerter};
+#[cfg(feature = "capture")]
+use pcap2socks::capture::Capture;
+use pcap2socks::config::Config;
+#[cfg(feature = "diagnostics")]
+use pcap2socks::diagnostics::Diagnostics;
+#[cfg(feature = "discovery")]
+use pcap2socks::discovery::Discovery;
+#[cfg(feature = "dns")]
+use pcap2socks::dns::DnsCache;
+use pcap2socks::dns::Hosts;
+#[cfg(all(feature = "dns", feature = "tls"))]
+use pcap2socks::dns::{self, DnsUpstream};
+use pcap2socks::filter::{BlockList, FilterChain, RateLimiter, RewriteList, ScriptFilter};
+#[cfg(unix)]
+use pcap2socks::handover;
+#[cfg(feature = "history")]
+use pcap2socks::history::{self, FlowHistory, HistoryFormat, HistoryQuery};
+#[cfg(feature = "noise")]
+use pcap2socks::ipc::IpcKey;
+#[cfg(feature = "ipc")]
+use pcap2socks::ipc::{IpcEvents, IpcServer};
+use pcap2socks::keepalive::KeepaliveList;
+use pcap2socks::names::NamePolicy;
+#[cfg(feature = "ipc")]
+use pcap2socks::observer::FlowTable;
+#[cfg(feature = "ipfix")]
+use pcap2socks::observer::IpfixExporter;
+use pcap2socks::observer::{AuditLog, DnsLog, DnsLogFormat, ObserverGroup};
+use pcap2socks::packet::layer::ipv4::{self, TtlPolicy};
+use pcap2socks::packet::layer::LayerKinds;
+#[cfg(feature = "wol")]
+use pcap2socks::pcap::HardwareAddr;
+use pcap2socks::pcap::{BlackHole, Dump, Interface, Receiver, Replay, Sender};
+#[cfg(feature = "capture")]
+use pcap2socks::pcap::{Mirror, RotatingDump};
+use pcap2socks::proxy::{
+    probe, Batching, UdpPortStrategy, WebSocketConfig, SECURE_WEBSOCKET_PORT, WEBSOCKET_PORT,
+};
+use pcap2socks::quota::{Quota, QuotaPolicy, QuotaTracker};
+use pcap2socks::stats::StageStats;
+#[cfg(feature = "tls")]
+use pcap2socks::tls::{CertPin, TlsConfig};
 use pcap2socks::{self as lib, Forwarder, ProxyConfig, Redirector};
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Config, whose arguments are inserted before the ones in the command line, which override
+    // them
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let config = match config_path() {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(s) => Ok(config_args(&s)),
+            Err(e) => Err((path, e)),
+        },
+        None => Ok(Vec::new()),
+    };
+
     // Parse arguments
-    let flags = Flags::from_args();
+    if let (Ok(ref config_args), false) = (&config, args.is_empty()) {
+        args.splice(1..1, config_args.iter().cloned());
+    }
+    let mut flags = match Flags::from_iter_safe(&args) {
+        Ok(flags) => flags,
+        Err(e) => match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                let message = e.message.lines().next().unwrap_or("");
+                // The flag is looked up in the raw arguments since they cannot be parsed
+                let error_json = env_flag("PCAP2SOCKS_ERROR_JSON")
+                    || args.iter().any(|arg| arg == "--error-json");
+                exit(
+                    Fatal::Args,
+                    error_json,
+                    message.trim_start_matches("error: "),
+                );
+            }
+        },
+    };
+    flags.force_associate_dst |= env_flag("PCAP2SOCKS_FORCE_ASSOCIATE_DESTINATION");
+    flags.force_associate_bind_addr |= env_flag("PCAP2SOCKS_FORCE_ASSOCIATE_BIND_ADDRESS");
+    #[cfg(feature = "dns")]
+    {
+        flags.dns_cache |= env_flag("PCAP2SOCKS_DNS_CACHE");
+        flags.dns_tcp |= env_flag("PCAP2SOCKS_DNS_TCP");
+        flags.edns |= env_flag("PCAP2SOCKS_EDNS");
+    }
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    {
+        flags.dns_upstream_direct |= env_flag("PCAP2SOCKS_DNS_UPSTREAM_DIRECT");
+    }
+    flags.strict |= env_flag("PCAP2SOCKS_STRICT");
+    flags.force_publish |= env_flag("PCAP2SOCKS_FORCE_PUBLISH");
+    flags.qos |= env_flag("PCAP2SOCKS_QOS");
+    flags.arp_backoff |= env_flag("PCAP2SOCKS_ARP_BACKOFF");
+    flags.privacy |= env_flag("PCAP2SOCKS_PRIVACY");
+    flags.no_delayed_ack |= env_flag("PCAP2SOCKS_NO_DELAYED_ACK");
+    flags.quick_ack_interactive |= env_flag("PCAP2SOCKS_QUICK_ACK_INTERACTIVE");
+    flags.no_tcp |= env_flag("PCAP2SOCKS_NO_TCP");
+    flags.no_udp |= env_flag("PCAP2SOCKS_NO_UDP");
+    flags.no_icmp |= env_flag("PCAP2SOCKS_NO_ICMP");
+    flags.no_hairpin |= env_flag("PCAP2SOCKS_NO_HAIRPIN");
+    #[cfg(feature = "discovery")]
+    {
+        flags.auto_source |= env_flag("PCAP2SOCKS_AUTO_SOURCE");
+    }
+    flags.reevaluate |= env_flag("PCAP2SOCKS_REEVALUATE");
+    flags.self_test |= env_flag("PCAP2SOCKS_SELF_TEST");
+    flags.profile |= env_flag("PCAP2SOCKS_PROFILE");
+    #[cfg(feature = "diagnostics")]
+    {
+        flags.diagnose |= env_flag("PCAP2SOCKS_DIAGNOSE");
+    }
+    flags.error_json |= env_flag("PCAP2SOCKS_ERROR_JSON");
+    let error_json = flags.error_json;
 
     // Log
-    set_logger(flags.verbose);
+    let last_error = set_logger(flags.verbose);
+    if let Err((path, ref e)) = config {
+        error!("Cannot open the config {}: {}", path, e);
+        exit(Fatal::Args, error_json, &last_error.lock().unwrap());
+    }
+    if let Some(ref config) = flags.config {
+        info!("Load the config {}", config);
+    }
+
+    // Runtime
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(workers) = flags.workers {
+        if workers == 0 {
+            error!("Cannot run with 0 worker threads");
+            exit(Fatal::Args, error_json, &last_error.lock().unwrap());
+        }
+        builder.worker_threads(workers);
+        info!("Run with {} worker threads", workers);
+    }
+    if let Some(ref cores) = flags.worker_cores {
+        let cores = cores.clone();
+        builder.on_thread_start(move || {
+            if let Err(ref e) = affinity::pin_current_thread(&cores) {
+                warn!("pin worker thread to cores {}: {}", cores, e);
+            }
+        });
+        info!("Pin worker threads to cores {}", cores);
+    }
+    let runtime = match builder.build() {
+        Ok(runtime) => runtime,
+        Err(ref e) => {
+            error!("Cannot create the runtime: {}", e);
+            exit(Fatal::Runtime, error_json, &last_error.lock().unwrap());
+        }
+    };
+
+    if let Err(fatal) = runtime.block_on(run(flags)) {
+        exit(fatal, error_json, &last_error.lock().unwrap());
+    }
+}
+
+async fn run(flags: Flags) -> Result<(), Fatal> {
+    // Test
+    if let Some(Command::Test(ref test_flags)) = flags.cmd {
+        return test(test_flags).await;
+    }
+
+    // History
+    #[cfg(feature = "history")]
+    if let Some(Command::History(ref history_flags)) = flags.cmd {
+        return show_history(history_flags);
+    }
+
+    // Config
+    if let Some(Command::Config(ConfigCommand::Check(ref config_flags))) = flags.cmd {
+        return check(config_flags);
+    }
+
+    // Privacy
+    if flags.privacy {
+        #[cfg(feature = "dns")]
+        if flags.dns_cache {
+            error!("Cannot cache DNS responses in the privacy mode");
+            return Err(Fatal::Args);
+        }
+        info!("Run in the privacy mode. Only headers of frames will be dumped or mirrored");
+    }
 
     // Interface
-    let inter = match lib::interface(flags.inter) {
+    let inter = match lib::interface(flags.inter.clone()) {
         Some(inter) => inter,
         None => {
             error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
             for inter in lib::interfaces().iter() {
                 info!("    {}", inter);
             }
-            return;
+            return Err(Fatal::Interface);
         }
     };
     info!("Listen on {}", inter);
+    if lib::is_low_memory() {
+        info!("Use small buffers for the low memory");
+    }
 
     // MTU
     let mtu = match flags.mtu {
@@ -38,7 +233,7 @@ async fn main() {
         None => {
             if inter.mtu() <= 0 {
                 error!("Cannot obtain the MTU. Please use --mtu <VALUE> to set");
-                return;
+                return Err(Fatal::Interface);
             }
 
             inter.mtu()
@@ -47,6 +242,231 @@ async fn main() {
     info!("Use MTU {}", mtu);
 
     // Route
+    let (src, publish) = route(&flags, &inter)?;
+    let is_discovered_only = flags.preset.is_none() && flags.src.is_none();
+
+    // Publish
+    if let Some(publish) = publish {
+        info!("Publish for {}", publish);
+    }
+
+    // Gateway
+    let gw = publish.unwrap_or(inter.ip_addr().unwrap());
+    if src.size() == 1 && src.network() == gw {
+        error!("The source cannot be the same with the gateway (publish)");
+        return Err(Fatal::Args);
+    }
+
+    // Destination
+    let dst = match destination(flags.dst.clone()) {
+        Some(dst) => dst,
+        None => return Err(Fatal::Args),
+    };
+    let config = config(&flags, &dst);
+
+    // DNS cache
+    #[cfg(feature = "dns")]
+    if flags.dns_min_ttl > flags.dns_max_ttl {
+        error!("The minimum TTL of the DNS cache cannot be greater than the maximum TTL");
+        return Err(Fatal::Args);
+    }
+
+    // UDP keep-alive
+    if flags.udp_keepalive == Some(0) {
+        error!("The interval of UDP keep-alive datagrams cannot be 0");
+        return Err(Fatal::Args);
+    }
+
+    // TCP keep-alive
+    if flags.keepalive_interval == 0 {
+        error!("The interval of keep-alive probes cannot be 0");
+        return Err(Fatal::Args);
+    }
+
+    // Replay
+    let replay_speed = flags.replay_speed.unwrap_or(1.0);
+    if !(replay_speed > 0.0) || !replay_speed.is_finite() {
+        error!("The speed of the replay must be greater than 0");
+        return Err(Fatal::Args);
+    }
+
+    // Lists, dumps and observers, which are opened before taking over, so the previous process
+    // keeps redirecting if any of them cannot be opened
+    let hosts = open_hosts(&flags)?;
+    let filters = open_filters(&flags)?;
+    let malformed_dump = open_malformed_dump(&flags)?;
+    #[cfg(feature = "capture")]
+    let mirror = open_mirror(&flags)?;
+    #[cfg(feature = "capture")]
+    let capture = open_capture(&flags)?;
+    let keepalive = open_keepalive(&flags)?;
+    let rate_limiter = open_rate_limiter(&flags)?;
+    let quota = open_quota(&flags)?;
+    let mut observers = open_observers(&flags)?;
+
+    // Instructions
+    if is_discovered_only {
+        info!("Discover devices on the interface. Type the address of a device to proxy it");
+    } else {
+        show_info(src, gw, mtu);
+    }
+
+    // Take over from the previous process, which stops redirecting
+    #[cfg(unix)]
+    let taken_over = match flags.handover {
+        Some(ref path) => match handover::take_over(path) {
+            Ok(taken_over) => taken_over,
+            Err(ref e) => {
+                error!("Cannot take over from the handover socket {}: {}", path, e);
+                return Err(Fatal::Runtime);
+            }
+        },
+        None => None,
+    };
+
+    // Proxy
+    let (tx, mut rx) = open_datalink(&flags, &inter, replay_speed)?;
+    let forwarder = Arc::new(Mutex::new(open_forwarder(&flags, tx, mtu, &inter)?));
+    let proxy = setup_proxy(&flags, &config, &dst).await?;
+    let mut redirector = Redirector::new(Arc::clone(&forwarder), src, gw, publish, proxy.clone());
+    configure(&flags, publish, &proxy, &mut redirector)?;
+    if let Some(hosts) = hosts {
+        redirector.set_hosts(hosts);
+    }
+    if !filters.is_empty() {
+        redirector.set_filter(Box::new(filters));
+        if flags.reevaluate {
+            redirector.set_reevaluate(true);
+            info!("Re-evaluate filters on TCP connections every minute");
+        }
+    }
+    if let Some(dump) = malformed_dump {
+        redirector.set_malformed_dump(dump);
+    }
+    #[cfg(feature = "capture")]
+    if let Some(mirror) = mirror {
+        redirector.set_mirror(mirror);
+    }
+    #[cfg(feature = "capture")]
+    if let Some(capture) = capture {
+        redirector.set_capture(capture);
+    }
+    if let Some(keepalive) = keepalive {
+        redirector.set_keepalive(
+            keepalive,
+            flags
+                .keepalive_interval
+                .checked_mul(1000)
+                .unwrap_or(u64::MAX),
+        );
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        redirector.set_rate_limit(rate_limiter);
+    }
+    #[cfg(feature = "discovery")]
+    let discovery = setup_discovery(&flags, &mut redirector);
+    #[cfg(feature = "diagnostics")]
+    let diagnostics = setup_diagnostics(&flags, &inter, &mut redirector);
+    // Stages are only read by the metrics besides the statistics summary
+    #[cfg_attr(not(feature = "api"), allow(unused_variables))]
+    let stages = match flags.profile {
+        true => {
+            let stages = Arc::new(Mutex::new(StageStats::new()));
+            redirector.set_stages(Arc::clone(&stages));
+            info!("Record latencies of each stage in the hot path");
+
+            Some(stages)
+        }
+        false => None,
+    };
+    #[cfg(feature = "ipc")]
+    let ipc_observers = ipc_observers(&flags, &mut observers);
+    if let Some(quota) = quota {
+        observers.push(quota.clone());
+        redirector.set_quota(quota);
+        if flags.quota_policy == QuotaPolicy::Direct {
+            redirector.set_quota_policy(flags.quota_policy);
+            info!("Connect TCP of sources beyond their quotas directly");
+        }
+    }
+    if !observers.is_empty() {
+        redirector.set_observer(Arc::new(observers));
+    }
+    #[cfg(feature = "alert")]
+    setup_alerts(&flags, &mut redirector);
+    #[cfg(unix)]
+    setup_handover(&flags, taken_over, &mut redirector)?;
+    #[cfg(feature = "multicast")]
+    setup_multicast(&flags, &mut redirector);
+    #[cfg(feature = "dns")]
+    setup_dns(&flags, &mut redirector)?;
+
+    // Summary
+    let summary = summarize(&flags, &inter, src, publish, mtu, &dst);
+    info!("Run with the effective configuration:");
+    for (key, value) in summary.iter() {
+        info!("    {}: {}", key, value);
+    }
+
+    let src_str = match is_discovered_only {
+        true => String::from("approved devices"),
+        false => src.to_string(),
+    };
+    match flags.username {
+        Some(ref username) => info!("Proxy {} to {}@{}", src_str, username, dst),
+        None => info!("Proxy {} to {}", src_str, dst),
+    }
+
+    // Console and signals
+    let snapshot = redirector.snapshot_flag();
+    spawn_console(
+        Arc::clone(&snapshot),
+        #[cfg(feature = "wol")]
+        Arc::clone(&forwarder),
+        #[cfg(feature = "discovery")]
+        discovery.clone(),
+    );
+    #[cfg(unix)]
+    spawn_signals(snapshot, proxy.clone());
+
+    // Management endpoints
+    #[cfg(feature = "api")]
+    serve_api(&flags, &redirector, stages, &summary).await?;
+
+    // IPC
+    #[cfg(feature = "ipc")]
+    if let Some(ref ipc_observers) = ipc_observers {
+        serve_ipc(
+            &flags,
+            &forwarder,
+            &redirector,
+            ipc_observers,
+            &discovery,
+            &diagnostics,
+            &proxy,
+        )
+        .await?;
+    }
+
+    // Capture runs in the current thread
+    if let Some(ref cores) = flags.capture_cores {
+        if let Err(ref e) = affinity::pin_current_thread(cores) {
+            error!("Cannot pin the capture thread to cores {}: {}", cores, e);
+            return Err(Fatal::Runtime);
+        }
+        info!("Pin the capture thread to cores {}", cores);
+    }
+
+    if let Err(ref e) = redirector.open(&mut rx).await {
+        error!("{}", e);
+        return Err(Fatal::Runtime);
+    }
+
+    Ok(())
+}
+
+/// Returns the source and the published address of the route, which may be given by the preset.
+fn route(flags: &Flags, inter: &Interface) -> Result<(Ipv4Network, Option<Ipv4Addr>), Fatal> {
     let src = match flags.preset {
         Some(ref preset) => match preset.as_str() {
             "t" | "tencent" => Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap(),
@@ -60,10 +480,14 @@ async fn main() {
             }
             _ => {
                 error!("The preset {} is not available", preset);
-                return;
+                return Err(Fatal::Args);
             }
         },
-        None => flags.src.unwrap(),
+        None => match flags.src {
+            Some(src) => src,
+            // Only discovered devices, and the broadcast address is never a source
+            None => Ipv4Network::new(Ipv4Addr::BROADCAST, 32).unwrap(),
+        },
     };
     let publish = match flags.preset {
         Some(ref preset) => match preset.as_str() {
@@ -77,184 +501,2862 @@ async fn main() {
             }
             _ => {
                 error!("The preset {} is not available", preset);
-                return;
+                return Err(Fatal::Args);
             }
         },
         None => flags.publish,
     };
 
-    // Publish
-    if let Some(publish) = publish {
-        info!("Publish for {}", publish);
-    }
+    Ok((src, publish))
+}
 
-    // Gateway
-    let gw = publish.unwrap_or(inter.ip_addr().unwrap());
-    if src.size() == 1 && src.network() == gw {
-        error!("The source cannot be the same with the gateway (publish)");
-        return;
+/// Opens the datalink channel on the interface, or replays the dump, and runs the self-test on it.
+fn open_datalink(
+    flags: &Flags,
+    inter: &Interface,
+    replay_speed: f64,
+) -> Result<(Sender, Receiver), Fatal> {
+    let (mut tx, rx): (Sender, Receiver) = match flags.replay {
+        Some(ref path) => match Replay::open(path) {
+            Ok(mut replay) => {
+                replay.set_speed(replay_speed);
+                replay.set_hardware_addr(inter.hardware_addr());
+                for rewrite in flags.replay_rewrites.iter() {
+                    replay.add_ip_addr_rewrite(rewrite.from, rewrite.to);
+                }
+                // Frames to sources are discarded, which are only kept in the mirror
+                info!("Replay {} at {}x speed", path, replay_speed);
+
+                (Box::new(BlackHole::new()), Box::new(replay))
+            }
+            Err(ref e) => {
+                error!("Cannot open the replay {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        },
+        None => match open_interface(inter, flags) {
+            Ok((tx, rx)) => (tx, rx),
+            Err(ref e) => {
+                error!("{}", e);
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    error!("Cannot capture on the interface. Please run as root or grant the capabilities CAP_NET_RAW and CAP_NET_ADMIN, e.g. with --cap-add=NET_RAW --cap-add=NET_ADMIN for a container");
+                }
+                return Err(Fatal::Pcap);
+            }
+        },
+    };
+
+    // Self-test
+    let rx = match flags.self_test {
+        true => match inter.self_test(&mut tx, rx) {
+            Ok(rx) => {
+                info!(
+                    "Pass the self-test of sending and receiving on {}",
+                    inter.name()
+                );
+
+                rx
+            }
+            Err(ref e) => {
+                error!("Cannot pass the self-test on the interface: {}", e);
+                return Err(Fatal::Pcap);
+            }
+        },
+        false => rx,
+    };
+
+    Ok((tx, rx))
+}
+
+/// Returns the forwarder sending to sources, which may cache and redirect DNS queries.
+fn open_forwarder(
+    flags: &Flags,
+    tx: Sender,
+    mtu: usize,
+    inter: &Interface,
+) -> Result<Forwarder, Fatal> {
+    let mut forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
+    #[cfg(feature = "dns")]
+    if flags.dns_cache {
+        forwarder.set_dns_cache(DnsCache::new(flags.dns_min_ttl, flags.dns_max_ttl));
+        info!(
+            "Cache DNS responses with TTL between {} and {} s",
+            flags.dns_min_ttl, flags.dns_max_ttl
+        );
+    }
+    #[cfg(feature = "dns")]
+    if let Some(dns) = flags.dns {
+        forwarder.set_dns_server(dns);
+        info!("Redirect DNS queries to {}", dns);
+    }
+    if let Some(ttl) = flags.ttl {
+        if ttl == 0 {
+            error!("The TTL cannot be 0");
+            return Err(Fatal::Args);
+        }
+        forwarder.set_ttl(ttl);
+        info!("Send packets to sources with TTL {}", ttl);
     }
 
-    // Instructions
-    show_info(src, gw, mtu);
+    Ok(forwarder)
+}
 
-    // Proxy
-    let (tx, mut rx) = match inter.open() {
-        Ok((tx, rx)) => (tx, rx),
+/// Checks the config of the flags without running.
+fn check(flags: &ConfigCheckFlags) -> Result<(), Fatal> {
+    let s = match fs::read_to_string(&flags.config) {
+        Ok(s) => s,
         Err(ref e) => {
-            error!("{}", e);
-            return;
+            error!("Cannot open the config {}: {}", flags.config, e);
+            return Err(Fatal::Args);
         }
     };
-    let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
-    let auth = match flags.username {
-        Some(ref username) => Some((username.clone(), flags.password.unwrap())),
-        None => None,
+    let errors = check_config(&s);
+    if !errors.is_empty() {
+        for e in errors.iter() {
+            error!("{}", e);
+        }
+        error!(
+            "Found {} errors in the config {}",
+            errors.len(),
+            flags.config
+        );
+        return Err(Fatal::Args);
+    }
+    info!("The config {} is valid", flags.config);
+
+    Ok(())
+}
+
+/// Opens the static hostname mappings.
+fn open_hosts(flags: &Flags) -> Result<Option<Hosts>, Fatal> {
+    let path = match flags.hosts {
+        Some(ref path) => path,
+        None => return Ok(None),
     };
-    let mut redirector = Redirector::new(
-        Arc::new(Mutex::new(forwarder)),
-        src,
-        gw,
-        publish,
-        ProxyConfig::new_socks(
-            flags.dst.addr(),
-            flags.force_associate_dst,
-            flags.force_associate_bind_addr,
-            auth,
-        ),
-    );
-    match flags.username {
-        Some(username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
-        None => info!("Proxy {} to {}", src, flags.dst),
+
+    match fs::read_to_string(path) {
+        Ok(s) => {
+            let hosts = Hosts::parse(&s);
+            info!("Use {} static hostname mappings", hosts.len());
+
+            Ok(Some(hosts))
+        }
+        Err(ref e) => {
+            error!("Cannot open the hosts {}: {}", path, e);
+            Err(Fatal::Args)
+        }
     }
-    if let Err(ref e) = redirector.open(&mut rx).await {
-        error!("{}", e);
+}
+
+/// Opens the block list and the rewrite list, and starts the filter script, in the order they
+/// are chained.
+fn open_filters(flags: &Flags) -> Result<FilterChain, Fatal> {
+    let mut filters = FilterChain::new();
+    if let Some(ref path) = flags.block {
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                let mut block_list = BlockList::parse(&s);
+                if let Some(offset) = flags.utc_offset {
+                    block_list.set_utc_offset(offset.0);
+                }
+                info!("Block {} destinations", block_list.len());
+                filters.push(Box::new(block_list));
+            }
+            Err(ref e) => {
+                error!("Cannot open the block list {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+    if let Some(ref path) = flags.rewrite {
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                let mut rewrite_list = RewriteList::parse(&s);
+                if let Some(offset) = flags.utc_offset {
+                    rewrite_list.set_utc_offset(offset.0);
+                }
+                info!("Rewrite with {} rules", rewrite_list.len());
+                filters.push(Box::new(rewrite_list));
+            }
+            Err(ref e) => {
+                error!("Cannot open the rewrite list {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+    if let Some(ref command) = flags.filter_script {
+        match ScriptFilter::spawn(command) {
+            Ok(script) => {
+                info!("Ask the filter script {} for verdicts of flows", command);
+                filters.push(Box::new(script));
+            }
+            Err(ref e) => {
+                error!("Cannot start the filter script {}: {}", command, e);
+                return Err(Fatal::Args);
+            }
+        }
     }
+
+    Ok(filters)
 }
 
-fn show_info(src: Ipv4Network, gw: Ipv4Addr, mtu: usize) {
-    macro_rules! max {
-        ($x: expr) => ($x);
-        ($x: expr, $($z: expr),+) => (::std::cmp::max($x, max!($($z),*)));
+/// Creates the dump of malformed frames.
+fn open_malformed_dump(flags: &Flags) -> Result<Option<Dump>, Fatal> {
+    let path = match flags.malformed_dump {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+
+    match Dump::create(path) {
+        Ok(mut dump) => {
+            dump.set_privacy(flags.privacy);
+            info!("Dump malformed frames to {}", path);
+
+            Ok(Some(dump))
+        }
+        Err(ref e) => {
+            error!("Cannot create the malformed dump {}: {}", path, e);
+            Err(Fatal::Args)
+        }
     }
+}
 
-    let src_str = match src.size() {
-        1 => src.network().to_string(),
-        _ => format!("{} - {}", src.network(), src.nth(src.size() - 1).unwrap()),
+/// Creates the mirror of relayed frames.
+#[cfg(feature = "capture")]
+fn open_mirror(flags: &Flags) -> Result<Option<Mirror>, Fatal> {
+    let target = match flags.mirror {
+        Some(ref target) => target,
+        None => return Ok(None),
     };
-    let mtu_str = format!("<={}", mtu);
-    let src_octets = src.network().octets();
-    let mask_octets = src.mask().octets();
-    let gw_octets = gw.octets();
 
-    // Mask, align to 8 bytes
-    let mut mask_octets = [
-        !(src_octets[0] ^ gw_octets[0]) & mask_octets[0],
-        !(src_octets[1] ^ gw_octets[1]) & mask_octets[1],
-        !(src_octets[2] ^ gw_octets[2]) & mask_octets[2],
-        !(src_octets[3] ^ gw_octets[3]) & mask_octets[3],
-    ];
-    let mut is_zero = false;
-    mask_octets.iter_mut().for_each(|b| {
-        if is_zero || *b != u8::MAX {
-            *b = 0;
-            is_zero = true;
+    match Mirror::create(target) {
+        Ok(mut mirror) => {
+            if let Some(snaplen) = flags.mirror_snaplen {
+                mirror.set_snaplen(snaplen);
+            }
+            if let Some(sample) = flags.mirror_sample {
+                mirror.set_sample(sample);
+            }
+            mirror.set_privacy(flags.privacy);
+            info!("Mirror relayed frames to {}", target);
+
+            Ok(Some(mirror))
         }
-    });
-    let mask_value = u32::from_be_bytes(mask_octets);
-    let mask = Ipv4Addr::from(mask_value);
+        Err(ref e) => {
+            error!("Cannot create the mirror {}: {}", target, e);
+            Err(Fatal::Runtime)
+        }
+    }
+}
 
-    let width = max!(
-        src_str.len(),
-        mask.to_string().len(),
-        gw.to_string().len(),
-        mtu_str.len()
-    );
-    info!("Please set the network of your device which is going to be proxied with the following parameters:");
-    info!("    ┌─────────────{:─>w$}─┐", "", w = width);
-    info!("    │ IP Address  {:>w$} │", src_str, w = width);
-    info!("    │ Mask        {:>w$} │", mask, w = width);
-    info!("    │ Gateway     {:>w$} │", gw, w = width);
-    info!("    │─────────────{:─>w$}─│", "", w = width);
-    info!("    │ MTU         {:>w$} │", mtu_str, w = width);
-    info!("    └─────────────{:─>w$}─┘", "", w = width);
-    if mask == Ipv4Addr::UNSPECIFIED {
-        warn!("The mask is all zeros, which may cause potential problems");
+/// Opens the capture list, whose frames are written to the rotating dump.
+#[cfg(feature = "capture")]
+fn open_capture(flags: &Flags) -> Result<Option<Capture>, Fatal> {
+    let path = match flags.capture {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+
+    match fs::read_to_string(path) {
+        Ok(s) => {
+            let mut dump = RotatingDump::new(flags.capture_dump.as_ref().unwrap());
+            dump.set_privacy(flags.privacy);
+            let mut capture = Capture::new(&s, dump);
+            if let Some(offset) = flags.utc_offset {
+                capture.set_utc_offset(offset.0);
+            }
+            info!(
+                "Capture with {} rules to {}",
+                capture.len(),
+                flags.capture_dump.as_ref().unwrap()
+            );
+
+            Ok(Some(capture))
+        }
+        Err(ref e) => {
+            error!("Cannot open the capture list {}: {}", path, e);
+            Err(Fatal::Args)
+        }
     }
 }
 
-#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
-#[structopt(about)]
-struct Flags {
-    #[structopt(
-        long,
-        short,
-        help = "Prints verbose information (-vv for vverbose)",
-        parse(from_occurrences)
-    )]
-    pub verbose: usize,
-    #[structopt(
-        long = "interface",
-        short,
-        help = "Interface for listening",
-        value_name = "INTERFACE",
-        display_order(0)
-    )]
+/// Opens the keep-alive list.
+fn open_keepalive(flags: &Flags) -> Result<Option<KeepaliveList>, Fatal> {
+    let path = match flags.keepalive {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+
+    match fs::read_to_string(path) {
+        Ok(s) => {
+            let mut keepalive = KeepaliveList::parse(&s);
+            if let Some(offset) = flags.utc_offset {
+                keepalive.set_utc_offset(offset.0);
+            }
+            info!(
+                "Keep alive TCP connections with {} rules every {} s",
+                keepalive.len(),
+                flags.keepalive_interval
+            );
+
+            Ok(Some(keepalive))
+        }
+        Err(ref e) => {
+            error!("Cannot open the keep-alive list {}: {}", path, e);
+            Err(Fatal::Args)
+        }
+    }
+}
+
+/// Opens the rate limit list.
+fn open_rate_limiter(flags: &Flags) -> Result<Option<RateLimiter>, Fatal> {
+    let path = match flags.rate_limit {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+
+    match fs::read_to_string(path) {
+        Ok(s) => {
+            let mut rate_limiter = RateLimiter::parse(&s);
+            if let Some(offset) = flags.utc_offset {
+                rate_limiter.set_utc_offset(offset.0);
+            }
+            info!("Limit rates with {} rules", rate_limiter.len());
+
+            Ok(Some(rate_limiter))
+        }
+        Err(ref e) => {
+            error!("Cannot open the rate limit list {}: {}", path, e);
+            Err(Fatal::Args)
+        }
+    }
+}
+
+/// Opens the quota of each source, which is restored from the quota file if any.
+fn open_quota(flags: &Flags) -> Result<Option<Arc<QuotaTracker>>, Fatal> {
+    let quota = match flags.quota {
+        Some(quota) => quota,
+        None => return Ok(None),
+    };
+
+    let tracker = match flags.quota_file {
+        Some(ref path) => match QuotaTracker::open(quota, path) {
+            Ok(tracker) => tracker,
+            Err(ref e) => {
+                error!("Cannot open the quota file {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        },
+        None => QuotaTracker::new(quota),
+    };
+    info!("Limit each source to {}", quota);
+
+    Ok(Some(Arc::new(tracker)))
+}
+
+/// Opens the observers of closed flows and DNS queries.
+fn open_observers(flags: &Flags) -> Result<ObserverGroup, Fatal> {
+    let mut observers = ObserverGroup::new();
+
+    // Audit log
+    if let Some(ref path) = flags.audit_log {
+        match AuditLog::open(path) {
+            Ok(audit_log) => {
+                observers.push(Arc::new(audit_log));
+                info!("Log closed flows to {}", path);
+            }
+            Err(ref e) => {
+                error!("Cannot open the audit log {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+
+    // DNS log
+    if let Some(ref path) = flags.dns_log {
+        match DnsLog::open(path, flags.dns_log_format) {
+            Ok(mut dns_log) => {
+                dns_log.set_privacy(flags.privacy);
+                observers.push(Arc::new(dns_log));
+                info!("Log DNS queries in {} to {}", flags.dns_log_format, path);
+            }
+            Err(ref e) => {
+                error!("Cannot open the DNS log {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+
+    // History
+    #[cfg(feature = "history")]
+    if let Some(history) = open_history(flags)? {
+        observers.push(Arc::new(history));
+    }
+
+    // IPFIX
+    #[cfg(feature = "ipfix")]
+    if let Some(ipfix) = open_ipfix(flags)? {
+        observers.push(Arc::new(ipfix));
+    }
+
+    Ok(observers)
+}
+
+/// Opens the history of closed flows.
+#[cfg(feature = "history")]
+fn open_history(flags: &Flags) -> Result<Option<FlowHistory>, Fatal> {
+    let path = match flags.history {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+
+    match FlowHistory::open(path, flags.history_format) {
+        Ok(history) => {
+            info!("Record closed flows in the history {}", path);
+
+            Ok(Some(history))
+        }
+        Err(ref e) => {
+            error!("Cannot open the history {}: {}", path, e);
+            Err(Fatal::Args)
+        }
+    }
+}
+
+/// Connects to the IPFIX collector.
+#[cfg(feature = "ipfix")]
+fn open_ipfix(flags: &Flags) -> Result<Option<IpfixExporter>, Fatal> {
+    let collector = match flags.ipfix {
+        Some(collector) => collector,
+        None => return Ok(None),
+    };
+
+    match IpfixExporter::connect(collector) {
+        Ok(mut ipfix) => {
+            if let Some(sample) = flags.ipfix_sample {
+                ipfix.set_sample(sample);
+            }
+            info!("Export closed flows in IPFIX to {}", collector);
+
+            Ok(Some(ipfix))
+        }
+        Err(ref e) => {
+            error!("Cannot connect to the IPFIX collector {}: {}", collector, e);
+            Err(Fatal::Runtime)
+        }
+    }
+}
+
+/// Returns the proxy of the destination, which may be tunneled in WebSocket, and reaches it in the
+/// self-test.
+async fn setup_proxy(
+    flags: &Flags,
+    config: &Config,
+    dst: &ResolvableSocketAddrV4,
+) -> Result<ProxyConfig, Fatal> {
+    let mut proxy = config.proxy();
+    if config.dst_addrs.len() > 1 {
+        info!(
+            "Race {} addresses of the destination in connecting",
+            config.dst_addrs.len()
+        );
+    }
+    if let Some(udp_relay) = config.udp_relay {
+        info!("Tunnel UDP over TCP to {} if UDP ASSOCIATE is not supported, which may increase latency", udp_relay);
+    }
+    if let Some(ref websocket) = flags.websocket {
+        #[cfg(feature = "tls")]
+        if !flags.websocket_pins.is_empty() && !websocket.is_tls {
+            error!(
+                "Cannot pin the certificate of the WebSocket server {}: it is not over TLS",
+                websocket
+            );
+            return Err(Fatal::Args);
+        }
+        match websocket.websocket() {
+            #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+            Ok(mut websocket) => {
+                #[cfg(feature = "tls")]
+                websocket.set_pins(flags.websocket_pins.clone());
+                proxy.set_websocket(websocket)
+            }
+            Err(ref e) => {
+                error!("Cannot use the WebSocket server {}: {}", websocket, e);
+                return Err(Fatal::Args);
+            }
+        }
+        #[cfg(feature = "tls")]
+        if !flags.websocket_pins.is_empty() {
+            info!(
+                "Pin the certificate of the WebSocket server to {}",
+                flags
+                    .websocket_pins
+                    .iter()
+                    .map(|pin| pin.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        info!(
+            "Tunnel connections to the destination in WebSocket to {}",
+            websocket
+        );
+        if flags.udp_relay.is_none() {
+            warn!("UDP ASSOCIATE is not supported over WebSocket, UDP will not be proxied without a UDP relay");
+        }
+    }
+    if flags.self_test {
+        let upstream = match flags.websocket {
+            Some(ref websocket) => match websocket.lookup().await {
+                Ok(addr) => addr,
+                Err(ref e) => {
+                    error!("Cannot resolve the WebSocket server {}: {}", websocket, e);
+                    return Err(Fatal::Proxy);
+                }
+            },
+            None => dst.addr().into(),
+        };
+        match probe::probe_reachable(upstream).await {
+            Ok(duration) => info!(
+                "Pass the self-test of reaching the proxy {} in {:.0} ms",
+                upstream,
+                duration.as_secs_f64() * 1000.0
+            ),
+            Err(ref e) => {
+                error!("Cannot reach the proxy {}: {}", upstream, e);
+                return Err(Fatal::Proxy);
+            }
+        }
+    }
+
+    Ok(proxy)
+}
+
+/// Configures the redirector with the options of publishing, local name resolution, the
+/// transport of each protocol and the adaptive routing.
+fn configure(
+    flags: &Flags,
+    publish: Option<Ipv4Addr>,
+    proxy: &ProxyConfig,
+    redirector: &mut Redirector,
+) -> Result<(), Fatal> {
+    // Publish
+    if let Some(publish) = publish {
+        redirector.set_arp_probe(true);
+        if let Some(interval) = flags.arp_reply_interval {
+            redirector.set_arp_reply_interval(interval);
+            info!(
+                "Reply ARP requests from a source at most once in {} ms",
+                interval
+            );
+        }
+        if flags.arp_backoff {
+            redirector.set_arp_backoff(true);
+            info!(
+                "Back off from publishing for {} if it is announced by another host",
+                publish
+            );
+        }
+        if flags.force_publish {
+            redirector.set_force_publish(true);
+            info!(
+                "Publish for {} even if it is owned by another host",
+                publish
+            );
+        }
+    }
+
+    // Local name resolution
+    for name_policy in &flags.name_policies {
+        match name_policy.ip_addr {
+            Some(ip_addr) => {
+                redirector.set_client_name_policy(ip_addr, name_policy.policy);
+                info!(
+                    "Handle NetBIOS-NS and LLMNR queries from {} in the {} policy",
+                    ip_addr, name_policy.policy
+                );
+            }
+            None => {
+                redirector.set_name_policy(name_policy.policy);
+                info!(
+                    "Handle NetBIOS-NS and LLMNR queries in the {} policy",
+                    name_policy.policy
+                );
+            }
+        }
+    }
+
+    // Sources
+    if flags.no_hairpin {
+        redirector.set_hairpin(false);
+        info!("Send traffic between sources through the proxy");
+    }
+    for ip_addr in flags.exclude.iter() {
+        redirector.add_excluded(*ip_addr);
+    }
+    if !flags.exclude.is_empty() {
+        info!("Exclude {} addresses from the source", flags.exclude.len());
+    }
+
+    // Protocols
+    for (is_disabled, t) in [
+        (flags.no_tcp, LayerKinds::Tcp),
+        (flags.no_udp, LayerKinds::Udp),
+        (flags.no_icmp, LayerKinds::Icmpv4),
+    ]
+    .iter()
+    {
+        if *is_disabled {
+            redirector.disable(*t);
+            info!("Leave {} untouched", t);
+        }
+    }
+    if let Some(port) = flags.ping {
+        redirector.set_ping_port(port);
+        info!(
+            "Reply ICMP echo requests by TCP connections to port {} through the proxy",
+            port
+        );
+    }
+    if flags.strict {
+        redirector.set_strict(true);
+        info!("Parse frames in the strict mode");
+    }
+
+    // TCP
+    if flags.qos || !flags.client_weights.is_empty() {
+        redirector.set_qos(true);
+        info!("Prioritize interactive flows over bulk flows");
+    }
+    for port_batching in &flags.tcp_port_batchings {
+        redirector.set_tcp_port_batching(port_batching.port, port_batching.batching);
+        info!(
+            "Batch writes of TCP connections of port {} in the {} mode",
+            port_batching.port, port_batching.batching
+        );
+    }
+    if flags.no_delayed_ack {
+        redirector.set_delayed_ack(false, false);
+        info!("Acknowledge TCP segments immediately");
+    } else if flags.quick_ack_interactive {
+        redirector.set_delayed_ack(true, true);
+        info!("Acknowledge interactive flows immediately");
+    }
+    for client_weight in &flags.client_weights {
+        redirector.set_client_weight(client_weight.ip_addr, client_weight.weight);
+        info!(
+            "Schedule client {} with weight {}",
+            client_weight.ip_addr, client_weight.weight
+        );
+    }
+    if let Some(ref path) = flags.adaptive {
+        let mut adaptive = match fs::read_to_string(path) {
+            Ok(s) => AdaptiveRouter::new(&s, proxy.clone()),
+            Err(ref e) => {
+                error!("Cannot open the adaptive list {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        };
+        if let Some(offset) = flags.utc_offset {
+            adaptive.set_utc_offset(offset.0);
+        }
+        info!(
+            "Route TCP flows matching {} rules to the faster of the proxy and the direct path",
+            adaptive.len()
+        );
+        redirector.set_adaptive(adaptive);
+    }
+
+    // UDP
+    if let Some(timeout) = flags.udp_timeout {
+        redirector.set_udp_timeout(timeout.checked_mul(1000).unwrap_or(u64::MAX));
+        info!("Release UDP ports idle for {} seconds", timeout);
+    }
+    if flags.ttl_policy != TtlPolicy::Fixed {
+        redirector.set_ttl_policy(flags.ttl_policy);
+        info!(
+            "Hairpin packets between sources with the {} TTL",
+            match flags.ttl_policy {
+                TtlPolicy::Preserve => "preserved",
+                _ => "decremented",
+            }
+        );
+    }
+    if flags.udp_port_strategy == UdpPortStrategy::Preserve {
+        redirector.set_udp_port_strategy(flags.udp_port_strategy);
+        info!("Bind the same local UDP ports as the source ports if available");
+    }
+    if let Some(interval) = flags.udp_keepalive {
+        redirector.set_udp_keepalive(interval.checked_mul(1000).unwrap_or(u64::MAX));
+        info!("Keep UDP associations alive every {} seconds", interval);
+    }
+    for port_timeout in &flags.udp_port_timeouts {
+        redirector.set_udp_port_timeout(
+            port_timeout.port,
+            port_timeout.timeout.checked_mul(1000).unwrap_or(u64::MAX),
+        );
+        info!(
+            "Release UDP ports of port {} idle for {} seconds",
+            port_timeout.port, port_timeout.timeout
+        );
+    }
+    if let Some(ref path) = flags.udp_mapping {
+        match redirector.set_udp_mapping_file(path) {
+            Ok(n) => info!("Restore {} UDP port mappings from {}", n, path),
+            Err(ref e) => {
+                error!("Cannot open the UDP port mappings {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+    if let Some(ref path) = flags.flow_state {
+        match redirector.set_flow_state_file(path) {
+            Ok(n) => info!("Reset {} TCP connections restored from {}", n, path),
+            Err(ref e) => {
+                error!("Cannot open the flow state {}: {}", path, e);
+                return Err(Fatal::Args);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the discovery of devices to the redirector, and returns it for approving devices.
+#[cfg(feature = "discovery")]
+fn setup_discovery(flags: &Flags, redirector: &mut Redirector) -> Option<Arc<Discovery>> {
+    if !flags.auto_source {
+        return None;
+    }
+
+    let discovery = Arc::new(Discovery::new());
+    redirector.set_discovery(Arc::clone(&discovery));
+    info!("Discover devices from their ARP and DHCP traffic");
+
+    Some(discovery)
+}
+
+/// Sets the diagnostics of the network segment to the redirector, and returns it for the IPC.
+#[cfg(feature = "diagnostics")]
+fn setup_diagnostics(
+    flags: &Flags,
+    inter: &Interface,
+    redirector: &mut Redirector,
+) -> Option<Arc<Diagnostics>> {
+    if !flags.diagnose {
+        return None;
+    }
+
+    let diagnostics = Arc::new(Diagnostics::new(inter.hardware_addr()));
+    redirector.set_diagnostics(Arc::clone(&diagnostics));
+    info!("Diagnose the network segment from its traffic");
+
+    Some(diagnostics)
+}
+
+/// Returns the observers of the IPC, which are pushed to the observers, if the IPC is served.
+#[cfg(feature = "ipc")]
+fn ipc_observers(
+    flags: &Flags,
+    observers: &mut ObserverGroup,
+) -> Option<(Arc<IpcEvents>, Arc<FlowTable>)> {
+    #[cfg(feature = "noise")]
+    let is_ipc = flags.ipc.is_some() || flags.ipc_tcp.is_some();
+    #[cfg(not(feature = "noise"))]
+    let is_ipc = flags.ipc.is_some();
+    if !is_ipc {
+        return None;
+    }
+
+    let ipc_events = Arc::new(IpcEvents::new());
+    observers.push(ipc_events.clone());
+    let flow_table = Arc::new(FlowTable::new());
+    observers.push(flow_table.clone());
+
+    Some((ipc_events, flow_table))
+}
+
+/// Sets the alerter of the hooks and the thresholds to the redirector.
+#[cfg(feature = "alert")]
+fn setup_alerts(flags: &Flags, redirector: &mut Redirector) {
+    if flags.alerts.is_empty() && flags.alert_drops.is_none() && flags.alert_buffer.is_none() {
+        return;
+    }
+
+    let mut alerter = Alerter::new();
+    for hook in &flags.alerts {
+        alerter.push(hook.clone());
+        info!("Notify alerts to {}", hook);
+    }
+    if let Some(threshold) = flags.alert_drops {
+        alerter.set_drop_threshold(threshold);
+        info!("Alert more than {} malformed frames in a minute", threshold);
+    }
+    if let Some(threshold) = flags.alert_buffer {
+        alerter.set_buffer_threshold(threshold);
+        info!("Alert more than {} Bytes buffered", threshold);
+    }
+    redirector.set_alerter(alerter);
+}
+
+/// Restores the flows taken over from the previous process, and listens on the handover socket
+/// for the next one.
+#[cfg(unix)]
+fn setup_handover(
+    flags: &Flags,
+    taken_over: Option<handover::Handover>,
+    redirector: &mut Redirector,
+) -> Result<(), Fatal> {
+    let path = match flags.handover {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+
+    if let Some(taken_over) = taken_over {
+        let connections = taken_over.connections.len();
+        let n = redirector.restore_handover(taken_over);
+        info!(
+            "Take over {} UDP associations and reset {} TCP connections from the previous process",
+            n, connections
+        );
+    }
+    match handover::listen(path) {
+        Ok(listener) => {
+            redirector.set_handover_listener(listener);
+            info!("Listen for a new process on the handover socket {}", path);
+
+            Ok(())
+        }
+        Err(ref e) => {
+            error!("Cannot listen on the handover socket {}: {}", path, e);
+            Err(Fatal::Runtime)
+        }
+    }
+}
+
+/// Sets the multicast groups to relay to the redirector.
+#[cfg(feature = "multicast")]
+fn setup_multicast(flags: &Flags, redirector: &mut Redirector) {
+    if !flags.multicast.is_empty() {
+        info!("Relay {} multicast groups", flags.multicast.len());
+        redirector.set_multicast_groups(flags.multicast.clone());
+    }
+}
+
+/// Configures the redirector with the transport and the upstream of DNS queries.
+#[cfg(feature = "dns")]
+fn setup_dns(flags: &Flags, redirector: &mut Redirector) -> Result<(), Fatal> {
+    if flags.dns_tcp {
+        redirector.set_dns_tcp(true);
+        info!("Resolve DNS queries over TCP through the proxy");
+    }
+    #[cfg(feature = "tls")]
+    if let Some(ref url) = flags.dns_upstream {
+        let mut upstream = match url.upstream() {
+            Ok(upstream) => upstream,
+            Err(ref e) => {
+                error!("Cannot use the DNS upstream {}: {}", url, e);
+                return Err(Fatal::Args);
+            }
+        };
+        upstream.set_direct(flags.dns_upstream_direct);
+        if !flags.dns_upstream_pins.is_empty() {
+            upstream.set_pins(flags.dns_upstream_pins.clone());
+            info!(
+                "Pin the certificate of the DNS upstream to {}",
+                flags
+                    .dns_upstream_pins
+                    .iter()
+                    .map(|pin| pin.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        match flags.dns_upstream_direct {
+            true => info!("Resolve DNS queries with {} directly", upstream),
+            false => info!("Resolve DNS queries with {} through the proxy", upstream),
+        }
+        redirector.set_dns_upstream(upstream);
+    }
+    if flags.edns {
+        redirector.set_edns(true);
+        info!("Advertise EDNS and retry truncated DNS responses over TCP");
+    }
+
+    Ok(())
+}
+
+/// Spawns the thread reading commands from the stdin, which takes a snapshot by `s`, wakes a
+/// device by `wake <HARDWARE_ADDRESS>`, and approves a discovered device by its address.
+fn spawn_console(
+    snapshot: Arc<AtomicBool>,
+    #[cfg(feature = "wol")] forwarder: Arc<Mutex<Forwarder>>,
+    #[cfg(feature = "discovery")] discovery: Option<Arc<Discovery>>,
+) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match line.trim() {
+                "s" => snapshot.store(true, Ordering::Relaxed),
+                "" => {}
+                // Wake a device on the interface
+                #[cfg(feature = "wol")]
+                line if line.starts_with("wake ") => {
+                    let hardware_addr = line.trim_start_matches("wake ").trim();
+                    match hardware_addr.parse::<HardwareAddr>() {
+                        Ok(hardware_addr) => {
+                            match forwarder.lock().unwrap().send_wake(hardware_addr) {
+                                Ok(_) => info!("Wake {}", hardware_addr),
+                                Err(ref e) => warn!("Cannot wake {}: {}", hardware_addr, e),
+                            }
+                        }
+                        Err(_) => warn!("Cannot parse the hardware address {}", hardware_addr),
+                    }
+                }
+                // Approve a discovered device
+                #[cfg(feature = "discovery")]
+                line => {
+                    if let Some(ref discovery) = discovery {
+                        match line.parse() {
+                            Ok(ip_addr) => {
+                                if discovery.approve(ip_addr) {
+                                    info!("Proxy device at {}", ip_addr);
+                                }
+                            }
+                            Err(ref e) => warn!("Cannot parse the address {}: {}", line, e),
+                        }
+                    }
+                }
+                #[cfg(not(feature = "discovery"))]
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Spawns the tasks handling signals, which take a snapshot by SIGUSR1, and reload the
+/// credentials of the proxy from the config by SIGHUP.
+#[cfg(unix)]
+fn spawn_signals(snapshot: Arc<AtomicBool>, proxy: ProxyConfig) {
+    tokio::spawn(async move {
+        let mut sigusr1 = match signal::unix::signal(signal::unix::SignalKind::user_defined1()) {
+            Ok(sigusr1) => sigusr1,
+            Err(ref e) => {
+                warn!("Cannot listen to SIGUSR1: {}", e);
+                return;
+            }
+        };
+        while sigusr1.recv().await.is_some() {
+            snapshot.store(true, Ordering::Relaxed);
+        }
+    });
+    if let Some(path) = config_path() {
+        tokio::spawn(async move {
+            let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(ref e) => {
+                    warn!("Cannot listen to SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while sighup.recv().await.is_some() {
+                let credentials = fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| config_credentials(&s));
+                match credentials {
+                    Ok(Some(auth)) => {
+                        info!("Rotate the credentials of the proxy to {}", auth.0);
+                        proxy.set_auth(Some(auth));
+                    }
+                    // Credentials from the command line or the environment are kept
+                    Ok(None) => info!("Keep the credentials of the proxy: not set in the config"),
+                    Err(ref e) => warn!("Cannot reload the config {}: {}", path, e),
+                }
+            }
+        });
+    }
+}
+
+/// Serves the management endpoints, the health check and the Prometheus metrics.
+#[cfg(feature = "api")]
+async fn serve_api(
+    flags: &Flags,
+    redirector: &Redirector,
+    stages: Option<Arc<Mutex<StageStats>>>,
+    summary: &[(&str, String)],
+) -> Result<(), Fatal> {
+    let api_allow = Arc::new(flags.api_allow.clone());
+    if !api_allow.is_empty() {
+        info!(
+            "Allow {} networks to the management endpoints",
+            api_allow.len()
+        );
+    }
+
+    // Health check
+    if let Some(health) = flags.health {
+        let ready = redirector.ready_flag();
+        let api_allow = Arc::clone(&api_allow);
+        let listener = match TcpListener::bind(health).await {
+            Ok(listener) => listener,
+            Err(ref e) => {
+                error!(
+                    "Cannot listen on the health check address {}: {}",
+                    health, e
+                );
+                return Err(Fatal::Runtime);
+            }
+        };
+        info!("Serve the health check on {}", health);
+        tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        if !is_api_allowed(&api_allow, addr) {
+                            debug!("deny health check from {}", addr);
+                            continue;
+                        }
+
+                        stream
+                    }
+                    Err(ref e) => {
+                        warn!("accept health check: {}", e);
+                        continue;
+                    }
+                };
+                // The health check is not authenticated by the token, so probes of
+                // containers work without it, and replies nothing but the status
+                let response = match ready.load(Ordering::Relaxed) {
+                    true => HEALTH_OK,
+                    false => HEALTH_UNAVAILABLE,
+                };
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    let _ = stream.read(&mut buffer).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+    }
+
+    // Metrics
+    if let Some(metrics) = flags.metrics {
+        let latency = redirector.latency();
+        let summary: Arc<str> = Arc::from(summary_to_json(summary));
+        let api_allow = Arc::clone(&api_allow);
+        let api_token: Option<Arc<str>> = flags.api_token.clone().map(Arc::from);
+        let listener = match TcpListener::bind(metrics).await {
+            Ok(listener) => listener,
+            Err(ref e) => {
+                error!("Cannot listen on the metrics address {}: {}", metrics, e);
+                return Err(Fatal::Runtime);
+            }
+        };
+        info!("Serve the Prometheus metrics on {}", metrics);
+        tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        if !is_api_allowed(&api_allow, addr) {
+                            debug!("deny metrics from {}", addr);
+                            continue;
+                        }
+
+                        stream
+                    }
+                    Err(ref e) => {
+                        warn!("accept metrics: {}", e);
+                        continue;
+                    }
+                };
+                let mut body = latency.lock().unwrap().to_prometheus();
+                if let Some(ref stages) = stages {
+                    body.push_str(&stages.lock().unwrap().to_prometheus());
+                }
+                let api_token = api_token.clone();
+                let summary = Arc::clone(&summary);
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    let size = stream.read(&mut buffer).await.unwrap_or(0);
+                    let request = &buffer[..size];
+                    let response = match (is_authorized(request, api_token.as_deref()), request_path(request) == Some("/config")) {
+                        (true, true) => format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", summary.len(), summary),
+                        (true, false) => format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+                        (false, _) => String::from(HTTP_UNAUTHORIZED),
+                    };
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Serves the IPC on the path, and over TCP encrypted in Noise.
+#[cfg(feature = "ipc")]
+async fn serve_ipc(
+    flags: &Flags,
+    forwarder: &Arc<Mutex<Forwarder>>,
+    redirector: &Redirector,
+    ipc_observers: &(Arc<IpcEvents>, Arc<FlowTable>),
+    discovery: &Option<Arc<Discovery>>,
+    diagnostics: &Option<Arc<Diagnostics>>,
+    proxy: &ProxyConfig,
+) -> Result<(), Fatal> {
+    let (ipc_events, flow_table) = ipc_observers;
+    let mut ipc_servers = Vec::new();
+    if let Some(ref path) = flags.ipc {
+        match IpcServer::bind(
+            path,
+            Arc::clone(forwarder),
+            redirector.paused_flag(),
+            ipc_events,
+        ) {
+            Ok(server) => ipc_servers.push((path.clone(), server)),
+            Err(ref e) => {
+                error!("Cannot listen on the IPC path {}: {}", path, e);
+                return Err(Fatal::Runtime);
+            }
+        }
+    }
+    #[cfg(feature = "noise")]
+    if let Some(addr) = flags.ipc_tcp {
+        match IpcServer::bind_tcp(
+            addr,
+            flags.ipc_key.clone().unwrap(),
+            Arc::clone(forwarder),
+            redirector.paused_flag(),
+            ipc_events,
+        )
+        .await
+        {
+            Ok(server) => ipc_servers.push((format!("{} encrypted in Noise", addr), server)),
+            Err(ref e) => {
+                error!("Cannot listen on the IPC address {}: {}", addr, e);
+                return Err(Fatal::Runtime);
+            }
+        }
+    }
+    for (name, mut server) in ipc_servers {
+        if let Some(ref token) = flags.api_token {
+            server.set_token(token.clone());
+        }
+        if let Some(ref discovery) = discovery {
+            server.set_discovery(Arc::clone(discovery));
+        }
+        if let Some(ref diagnostics) = diagnostics {
+            server.set_diagnostics(Arc::clone(diagnostics));
+        }
+        server.set_flows(Arc::clone(flow_table));
+        server.set_proxy(proxy.clone());
+        info!("Serve the IPC on {}", name);
+        tokio::spawn(server.serve());
+    }
+
+    Ok(())
+}
+
+/// Represents the category of a fatal error, which decides the exit code of the process.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Fatal {
+    /// Represents the error in running after the start.
+    Runtime,
+    /// Represents the error in the arguments or the config.
+    Args,
+    /// Represents the interface cannot be determined.
+    Interface,
+    /// Represents the interface cannot be opened for capturing.
+    Pcap,
+    /// Represents the proxy cannot be reached.
+    Proxy,
+}
+
+impl Fatal {
+    /// Returns the exit code of the process.
+    fn code(&self) -> i32 {
+        match self {
+            Fatal::Runtime => 1,
+            Fatal::Args => 2,
+            Fatal::Interface => 3,
+            Fatal::Pcap => 4,
+            Fatal::Proxy => 5,
+        }
+    }
+}
+
+impl Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fatal::Runtime => write!(f, "runtime"),
+            Fatal::Args => write!(f, "args"),
+            Fatal::Interface => write!(f, "interface"),
+            Fatal::Pcap => write!(f, "pcap"),
+            Fatal::Proxy => write!(f, "proxy"),
+        }
+    }
+}
+
+/// Exits the process with the code of the fatal error, and writes the error as a JSON object in
+/// the final line of the stderr if required.
+fn exit(fatal: Fatal, error_json: bool, message: &str) -> ! {
+    if error_json {
+        eprintln!("{}", fatal_to_json(fatal, message));
+    }
+
+    std::process::exit(fatal.code())
+}
+
+/// Returns the fatal error as a JSON object in a line.
+fn fatal_to_json(fatal: Fatal, message: &str) -> String {
+    format!(
+        "{{\"error\":\"{}\",\"code\":{},\"message\":\"{}\"}}",
+        fatal,
+        fatal.code(),
+        message
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Returns the destination in the arguments or the proxy environment variables, or the default
+/// one.
+fn destination(dst: Option<ResolvableSocketAddrV4>) -> Option<ResolvableSocketAddrV4> {
+    match dst {
+        Some(dst) => Some(dst),
+        None => match proxy_from_env() {
+            Some((key, value)) => match parse_proxy(&value) {
+                Ok(dst) => Some(dst),
+                Err(ref e) => {
+                    error!("Cannot parse the destination {} in {}: {}", value, key, e);
+                    None
+                }
+            },
+            None => Some(ResolvableSocketAddrV4 {
+                addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, DEFAULT_DST_PORT),
+                addrs: Vec::new(),
+                alias: None,
+            }),
+        },
+    }
+}
+
+/// Returns the `Config` of the arguments with the given destination.
+fn config(flags: &Flags, dst: &ResolvableSocketAddrV4) -> Config {
+    Config {
+        interface: flags.inter.clone(),
+        mtu: flags.mtu,
+        src: flags.src,
+        publish: flags.publish,
+        dst: dst.addr(),
+        dst_addrs: dst.addrs().to_vec(),
+        auth: match flags.username {
+            Some(ref username) => Some((username.clone(), flags.password.clone().unwrap())),
+            None => None,
+        },
+        force_associate_dst: flags.force_associate_dst,
+        force_associate_bind_addr: flags.force_associate_bind_addr,
+        udp_relay: flags.udp_relay,
+    }
+}
+
+/// Shows the flows in the history filtered by the source, the domain and the time, where only the
+/// latest flows up to the limit are shown.
+#[cfg(feature = "history")]
+fn show_history(flags: &HistoryFlags) -> Result<(), Fatal> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut query = HistoryQuery::new();
+    if let Some(src) = flags.src {
+        query.set_src(src);
+    }
+    if let Some(ref domain) = flags.domain {
+        query.set_domain(domain);
+    }
+    if let Some(since) = flags.since {
+        query.set_since(now.saturating_sub(since.0));
+    }
+    if let Some(until) = flags.until {
+        query.set_until(now.saturating_sub(until.0));
+    }
+
+    let records = match history::read(&flags.history, &query) {
+        Ok(records) => records,
+        Err(ref e) => {
+            error!("Cannot read the history {}: {}", flags.history, e);
+            return Err(Fatal::Args);
+        }
+    };
+    let skip = match flags.limit {
+        Some(limit) => records.len().saturating_sub(limit),
+        None => 0,
+    };
+    info!("Found {} flows in the history", records.len());
+    for record in records.iter().skip(skip) {
+        let domain = match record.domain() {
+            Some(domain) => format!(" ({})", domain),
+            None => String::new(),
+        };
+        let upstream = match record.upstream() {
+            Some(upstream) => format!(" via {}", upstream),
+            None => String::new(),
+        };
+        info!(
+            "    {} {} {} -> {}{}{}: {} ms, {} Bytes sent, {} Bytes received, {}",
+            history::format_time(record.time()),
+            record.protocol(),
+            record.src(),
+            record.dst(),
+            domain,
+            upstream,
+            record.duration(),
+            record.sent(),
+            record.received(),
+            record.reason()
+        );
+    }
+
+    Ok(())
+}
+
+/// Tests the destination without capturing, and reports the SOCKS handshake time, the TCP
+/// throughput and the UDP round-trip time.
+async fn test(flags: &TestFlags) -> Result<(), Fatal> {
+    let dst = match destination(flags.dst.clone()) {
+        Some(dst) => dst,
+        None => return Err(Fatal::Args),
+    };
+    let auth = match flags.username {
+        Some(ref username) => Some((username.clone(), flags.password.clone().unwrap())),
+        None => None,
+    };
+    let mut proxy = ProxyConfig::new_socks(dst.addr(), false, false, auth);
+    if dst.addrs().len() > 1 {
+        proxy.set_addrs(dst.addrs().to_vec());
+    }
+    let udp_target = flags.udp_target.as_ref().unwrap_or(&flags.target);
+    info!(
+        "Test the destination {} with the test endpoint {}",
+        dst, flags.target
+    );
+
+    // TCP
+    let tcp = match probe::probe_tcp(&proxy, flags.target.addr(), flags.size).await {
+        Ok(report) => {
+            info!("TCP: {}", report);
+
+            Ok(())
+        }
+        Err(ref e) => {
+            error!("Cannot test TCP to {}: {}", flags.target, e);
+
+            Err(Fatal::Proxy)
+        }
+    };
+
+    // UDP
+    match probe::probe_udp(&proxy, udp_target.addr(), flags.count).await {
+        Ok(report) => {
+            info!("UDP: {}", report);
+            if report.lost() == report.rtts().len() && !report.rtts().is_empty() {
+                warn!(
+                    "No datagram was echoed by {}, please make sure it is a UDP echo server",
+                    udp_target
+                );
+            }
+        }
+        Err(ref e) => error!("Cannot test UDP to {}: {}", udp_target, e),
+    }
+
+    tcp
+}
+
+/// Opens the interface. With the handover socket, receiving times out on an idle network, so a
+/// new process connecting to the socket is still accepted.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn open_interface(inter: &Interface, flags: &Flags) -> io::Result<(Sender, Receiver)> {
+    #[cfg(unix)]
+    if flags.handover.is_some() {
+        return inter.open_timeout(Duration::from_millis(handover::ACCEPT_INTERVAL));
+    }
+
+    inter.open()
+}
+
+fn show_info(src: Ipv4Network, gw: Ipv4Addr, mtu: usize) {
+    macro_rules! max {
+        ($x: expr) => ($x);
+        ($x: expr, $($z: expr),+) => (::std::cmp::max($x, max!($($z),*)));
+    }
+
+    let src_str = match src.size() {
+        1 => src.network().to_string(),
+        _ => format!("{} - {}", src.network(), src.nth(src.size() - 1).unwrap()),
+    };
+    let mtu_str = format!("<={}", mtu);
+    let src_octets = src.network().octets();
+    let mask_octets = src.mask().octets();
+    let gw_octets = gw.octets();
+
+    // Mask, align to 8 bytes
+    let mut mask_octets = [
+        !(src_octets[0] ^ gw_octets[0]) & mask_octets[0],
+        !(src_octets[1] ^ gw_octets[1]) & mask_octets[1],
+        !(src_octets[2] ^ gw_octets[2]) & mask_octets[2],
+        !(src_octets[3] ^ gw_octets[3]) & mask_octets[3],
+    ];
+    let mut is_zero = false;
+    mask_octets.iter_mut().for_each(|b| {
+        if is_zero || *b != u8::MAX {
+            *b = 0;
+            is_zero = true;
+        }
+    });
+    let mask_value = u32::from_be_bytes(mask_octets);
+    let mask = Ipv4Addr::from(mask_value);
+
+    let width = max!(
+        src_str.len(),
+        mask.to_string().len(),
+        gw.to_string().len(),
+        mtu_str.len()
+    );
+    info!("Please set the network of your device which is going to be proxied with the following parameters:");
+    info!("    ┌─────────────{:─>w$}─┐", "", w = width);
+    info!("    │ IP Address  {:>w$} │", src_str, w = width);
+    info!("    │ Mask        {:>w$} │", mask, w = width);
+    info!("    │ Gateway     {:>w$} │", gw, w = width);
+    info!("    │─────────────{:─>w$}─│", "", w = width);
+    info!("    │ MTU         {:>w$} │", mtu_str, w = width);
+    info!("    └─────────────{:─>w$}─┘", "", w = width);
+    if mask == Ipv4Addr::UNSPECIFIED {
+        warn!("The mask is all zeros, which may cause potential problems");
+    }
+}
+
+/// Returns the effective configuration after validation as pairs of names and values, including
+/// the interface, the route, the upstreams and the enabled subsystems.
+fn summarize(
+    flags: &Flags,
+    inter: &Interface,
+    src: Ipv4Network,
+    publish: Option<Ipv4Addr>,
+    mtu: usize,
+    dst: &ResolvableSocketAddrV4,
+) -> Vec<(&'static str, String)> {
+    let mut summary = Vec::new();
+    summary.push(("version", String::from(env!("CARGO_PKG_VERSION"))));
+    summary.push(("interface", inter.name().clone()));
+    summary.push(("hardware_addr", inter.hardware_addr().to_string()));
+    if let Some(ip_addr) = inter.ip_addr() {
+        summary.push(("ip_addr", ip_addr.to_string()));
+    }
+    summary.push((
+        "src",
+        match flags.preset.is_none() && flags.src.is_none() {
+            true => String::from("approved devices"),
+            false => src.to_string(),
+        },
+    ));
+    if let Some(publish) = publish {
+        summary.push(("publish", publish.to_string()));
+    }
+    summary.push(("mtu", mtu.to_string()));
+    summary.push(("mss", mtu.saturating_sub(40).to_string()));
+    summary.push(("ttl", flags.ttl.unwrap_or(ipv4::TTL).to_string()));
+    summary.push(("ttl_policy", flags.ttl_policy.to_string()));
+    summary.push(("dst", dst.to_string()));
+    if !dst.addrs.is_empty() {
+        let addrs = dst
+            .addrs
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        summary.push(("dst_addrs", addrs.join(", ")));
+    }
+    if let Some(ref username) = flags.username {
+        summary.push(("username", username.clone()));
+    }
+    if let Some(ref websocket) = flags.websocket {
+        summary.push(("websocket", websocket.to_string()));
+    }
+    if let Some(udp_relay) = flags.udp_relay {
+        summary.push(("udp_relay", udp_relay.to_string()));
+    }
+    summary.push(("udp_port_strategy", flags.udp_port_strategy.to_string()));
+    #[cfg(feature = "dns")]
+    if let Some(dns) = flags.dns {
+        summary.push(("dns", dns.to_string()));
+    }
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    if let Some(ref dns_upstream) = flags.dns_upstream {
+        summary.push(("dns_upstream", dns_upstream.to_string()));
+    }
+
+    // Subsystems, by their flags or options
+    let mut subsystems = Vec::new();
+    let mut enable = |name: &'static str, is_enabled: bool| {
+        if is_enabled {
+            subsystems.push(name);
+        }
+    };
+    #[cfg(feature = "dns")]
+    {
+        enable("dns-cache", flags.dns_cache);
+        enable("dns-tcp", flags.dns_tcp);
+        enable("edns", flags.edns);
+    }
+    enable("hosts", flags.hosts.is_some());
+    enable("block", flags.block.is_some());
+    enable("rewrite", flags.rewrite.is_some());
+    enable("filter-script", flags.filter_script.is_some());
+    enable("adaptive", flags.adaptive.is_some());
+    enable("ping", flags.ping.is_some());
+    enable("qos", flags.qos);
+    enable("quota", flags.quota.is_some());
+    #[cfg(feature = "multicast")]
+    enable("multicast", !flags.multicast.is_empty());
+    #[cfg(feature = "discovery")]
+    enable("auto-source", flags.auto_source);
+    enable("reevaluate", flags.reevaluate);
+    enable("privacy", flags.privacy);
+    enable("self-test", flags.self_test);
+    enable("profile", flags.profile);
+    #[cfg(feature = "diagnostics")]
+    enable("diagnose", flags.diagnose);
+    enable("strict", flags.strict);
+    #[cfg(feature = "capture")]
+    enable("mirror", flags.mirror.is_some());
+    #[cfg(feature = "capture")]
+    enable("capture", flags.capture.is_some());
+    enable("keepalive", flags.keepalive.is_some());
+    enable("rate-limit", flags.rate_limit.is_some());
+    #[cfg(feature = "ipfix")]
+    enable("ipfix", flags.ipfix.is_some());
+    enable("audit-log", flags.audit_log.is_some());
+    enable("dns-log", flags.dns_log.is_some());
+    #[cfg(feature = "history")]
+    enable("history", flags.history.is_some());
+    enable("flow-state", flags.flow_state.is_some());
+    #[cfg(unix)]
+    enable("handover", flags.handover.is_some());
+    #[cfg(feature = "alert")]
+    enable("alert", !flags.alerts.is_empty());
+    #[cfg(feature = "api")]
+    {
+        enable("health", flags.health.is_some());
+        enable("metrics", flags.metrics.is_some());
+    }
+    #[cfg(feature = "ipc")]
+    enable("ipc", flags.ipc.is_some());
+    #[cfg(feature = "noise")]
+    enable("ipc-tcp", flags.ipc_tcp.is_some());
+    enable("no-tcp", flags.no_tcp);
+    enable("no-udp", flags.no_udp);
+    enable("no-icmp", flags.no_icmp);
+    enable("no-hairpin", flags.no_hairpin);
+    summary.push(("subsystems", subsystems.join(", ")));
+
+    summary
+}
+
+/// Returns the summary of the configuration as a flat JSON object of strings.
+#[cfg(feature = "api")]
+fn summary_to_json(summary: &[(&str, String)]) -> String {
+    let fields = summary
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "\"{}\":\"{}\"",
+                key,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}\n", fields.join(","))
+}
+
+const DEFAULT_DST_PORT: u16 = 1080;
+#[cfg(all(feature = "dns", feature = "tls"))]
+const DEFAULT_DNS_OVER_HTTPS_PATH: &str = "/dns-query";
+
+#[cfg(feature = "api")]
+const HEALTH_OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nOK\n";
+#[cfg(feature = "api")]
+const HEALTH_UNAVAILABLE: &str =
+    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 12\r\nConnection: close\r\n\r\nUnavailable\n";
+#[cfg(feature = "api")]
+const HTTP_UNAUTHORIZED: &str =
+    "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 13\r\nConnection: close\r\n\r\nUnauthorized\n";
+
+/// Returns if the peer is allowed to the management endpoints. All the peers are allowed if no
+/// network is given.
+#[cfg(feature = "api")]
+fn is_api_allowed(allow: &[Ipv4Network], addr: SocketAddr) -> bool {
+    if allow.is_empty() {
+        return true;
+    }
+
+    match addr.ip() {
+        IpAddr::V4(ip_addr) => allow.iter().any(|network| network.contains(ip_addr)),
+        IpAddr::V6(ip_addr) => match ip_addr.to_ipv4() {
+            // IPv4-mapped addresses of dual-stack listeners
+            Some(mapped) if ip_addr.segments()[5] == 0xffff => {
+                allow.iter().any(|network| network.contains(mapped))
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Returns the path in the request line of the HTTP request, without the query.
+#[cfg(feature = "api")]
+fn request_path(request: &[u8]) -> Option<&str> {
+    let line = request.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let target = std::str::from_utf8(line).ok()?.split_whitespace().nth(1)?;
+
+    target.split('?').next()
+}
+
+/// Returns if the HTTP request carries the token in the `Authorization` header as a bearer token.
+/// All the requests are authorized if no token is set.
+#[cfg(feature = "api")]
+fn is_authorized(request: &[u8], token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+
+    String::from_utf8_lossy(request)
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?;
+            let value = parts.next()?.trim();
+            match name.trim().eq_ignore_ascii_case("authorization") {
+                true => value.strip_prefix("Bearer "),
+                false => None,
+            }
+        })
+        .any(|t| lib::verify_token(t.trim(), token))
+}
+
+/// Returns the path of the config in the arguments or the environment variable.
+fn config_path() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+
+    std::env::var("PCAP2SOCKS_CONFIG").ok()
+}
+
+/// Returns the arguments of a config, which are inserted before the arguments in the command
+/// line. Each line of the config contains an option or a flag and optionally its value like
+/// `destination = 127.0.0.1:1080` or `dns-cache`, and `#` starts a comment. Options set by
+/// environment variables are skipped, and the arguments in the command line override the ones in
+/// the config since they are parsed later, so the config takes precedence over nothing but the
+/// defaults. UCI configs of OpenWrt are also accepted, like `option destination '127.0.0.1:1080'`
+/// and `list multicast '239.255.255.250:1900'`, where the lists are added to the ones in the
+/// command line.
+fn config_args(s: &str) -> Vec<String> {
+    let mut config_args = Vec::new();
+    for line in s.lines() {
+        match parse_config_line(line) {
+            Some(ConfigEntry::Option(key, value)) => {
+                if std::env::var_os(&key).is_some() {
+                    continue;
+                }
+                if let (_, Some(arg)) = config_arg(&key, &value) {
+                    config_args.push(arg);
+                }
+            }
+            Some(ConfigEntry::List(arg)) => config_args.push(arg),
+            None => {}
+        }
+    }
+
+    config_args
+}
+
+/// Flags which take no value, which are set in a config by truthy values and unset by falsy ones.
+const CONFIG_FLAGS: [&str; 24] = [
+    "verbose",
+    "force-associate-destination",
+    "force-associate-bind-address",
+    "dns-cache",
+    "dns-tcp",
+    "strict",
+    "force-publish",
+    "qos",
+    "arp-backoff",
+    "privacy",
+    "no-delayed-ack",
+    "quick-ack-interactive",
+    "no-tcp",
+    "no-udp",
+    "no-icmp",
+    "no-hairpin",
+    "auto-source",
+    "reevaluate",
+    "edns",
+    "self-test",
+    "profile",
+    "error-json",
+    "diagnose",
+    "dns-upstream-direct",
+];
+
+/// Returns the name and the argument of an option or a flag in a config, or `None` as the
+/// argument if it is a flag unset by a falsy value.
+fn config_arg(key: &str, value: &str) -> (String, Option<String>) {
+    let name = key
+        .trim_start_matches("PCAP2SOCKS_")
+        .replace('_', "-")
+        .to_ascii_lowercase();
+    if !CONFIG_FLAGS.contains(&name.as_str()) {
+        let arg = format!("--{}={}", name, value);
+
+        return (name, Some(arg));
+    }
+
+    let arg = match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(format!("--{}", name)),
+        "0" | "false" | "no" | "off" => None,
+        // Rejected in parsing as a flag with a value
+        _ => Some(format!("--{}={}", name, value)),
+    };
+
+    (name, arg)
+}
+
+/// Parses the arguments without running, and returns the kind and the message of the error.
+fn parse_args(args: &[String]) -> Result<(), (ErrorKind, String)> {
+    Flags::clap()
+        .setting(structopt::clap::AppSettings::ColorNever)
+        .get_matches_from_safe(args)
+        .map(|_| ())
+        .map_err(|e| {
+            let message = e.message.lines().next().unwrap_or("");
+            (e.kind, message.trim_start_matches("error: ").to_string())
+        })
+}
+
+/// Parses a config, and returns the options as the names and the values of their environment
+/// variables, and the lists as arguments.
+fn parse_config(s: &str) -> (Vec<(String, String)>, Vec<String>) {
+    let mut options = Vec::new();
+    let mut lists = Vec::new();
+    for line in s.lines() {
+        match parse_config_line(line) {
+            Some(ConfigEntry::Option(key, value)) => options.push((key, value)),
+            Some(ConfigEntry::List(arg)) => lists.push(arg),
+            None => {}
+        }
+    }
+
+    (options, lists)
+}
+
+/// Represents an entry in a line of a config.
+#[derive(Debug, Eq, PartialEq)]
+enum ConfigEntry {
+    /// Represents an option or a flag, as the name and the value of its environment variable.
+    Option(String, String),
+    /// Represents an option which can be repeated, as an argument.
+    List(String),
+}
+
+/// Parses a line of a config, and returns `None` if the line has no entry.
+fn parse_config_line(line: &str) -> Option<ConfigEntry> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    // UCI
+    let mut parts = line.splitn(3, char::is_whitespace);
+    match parts.next() {
+        Some("config") | Some("package") => return None,
+        Some(kind @ "option") | Some(kind @ "list") => {
+            let key = parts.next()?.trim_matches(|c| c == '\'' || c == '"');
+            let value = parts
+                .next()
+                .unwrap_or("")
+                .trim()
+                .trim_matches(|c| c == '\'' || c == '"');
+            if kind == "list" {
+                return Some(ConfigEntry::List(format!(
+                    "--{}={}",
+                    key.replace('_', "-"),
+                    value
+                )));
+            }
+            let key = format!("PCAP2SOCKS_{}", key.to_ascii_uppercase());
+            return Some(ConfigEntry::Option(key, value.to_string()));
+        }
+        _ => {}
+    }
+
+    let (key, value) = match line.find('=') {
+        Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+        None => (line, "true"),
+    };
+    let key = format!(
+        "PCAP2SOCKS_{}",
+        key.trim_start_matches("--")
+            .replace('-', "_")
+            .to_ascii_uppercase()
+    );
+
+    Some(ConfigEntry::Option(key, value.to_string()))
+}
+
+/// Checks a config without running, and returns the errors located by their line numbers. Each
+/// line is parsed alone as its equivalent argument, and the whole config is parsed together at
+/// last for conflicts between lines.
+fn check_config(s: &str) -> Vec<String> {
+    // Environment variables also apply in the check, and are checked first so their errors are
+    // not located in lines
+    let program = String::from("pcap2socks");
+    if let Err((kind, e)) = parse_args(&[program.clone(), String::from("--preset=t")]) {
+        if kind != ErrorKind::MissingRequiredArgument {
+            return vec![format!("environment: {}", e)];
+        }
+    }
+
+    let mut errors = Vec::new();
+    let mut args = vec![program];
+    for (i, line) in s.lines().enumerate() {
+        let (name, arg) = match parse_config_line(line) {
+            Some(ConfigEntry::Option(key, value)) => config_arg(&key, &value),
+            Some(ConfigEntry::List(arg)) => {
+                let name = arg
+                    .trim_start_matches("--")
+                    .split('=')
+                    .next()
+                    .unwrap_or("")
+                    .to_string();
+
+                (name, Some(arg))
+            }
+            None => continue,
+        };
+        let arg = match arg {
+            Some(arg) => arg,
+            None => continue,
+        };
+
+        // Satisfy the source, or values are not validated at all
+        let mut line_args = vec![args[0].clone(), arg.clone()];
+        if name != "preset" {
+            line_args.push(String::from("--preset=t"));
+        }
+        match parse_args(&line_args) {
+            // Required arguments may be in other lines
+            Ok(_) | Err((ErrorKind::MissingRequiredArgument, _)) => args.push(arg),
+            Err((_, e)) => errors.push(format!("line {}: {}: {}", i + 1, name, e)),
+        }
+    }
+
+    // Rule lists in the options are checked once the config is valid
+    if errors.is_empty() {
+        match Flags::from_iter_safe(&args) {
+            Ok(flags) => errors.extend(check_lists(&flags)),
+            Err(e) => {
+                let message = e.message.lines().next().unwrap_or("");
+                errors.push(message.trim_start_matches("error: ").to_string());
+            }
+        }
+    }
+
+    errors
+}
+
+/// Checks the rule lists in the options, and returns the errors located by the lists and their
+/// line numbers.
+fn check_lists(flags: &Flags) -> Vec<String> {
+    let lists: [(&str, &Option<String>, fn(&str) -> Vec<String>); 3] = [
+        ("block", &flags.block, BlockList::check),
+        ("rewrite", &flags.rewrite, RewriteList::check),
+        ("rate-limit", &flags.rate_limit, RateLimiter::check),
+    ];
+
+    let mut errors = Vec::new();
+    for (name, path, check) in lists.iter() {
+        if let Some(path) = path {
+            match fs::read_to_string(path) {
+                Ok(s) => errors.extend(
+                    check(&s)
+                        .into_iter()
+                        .map(|e| format!("{} {}: {}", name, path, e)),
+                ),
+                Err(ref e) => errors.push(format!("{} {}: {}", name, path, e)),
+            }
+        }
+    }
+
+    errors
+}
+
+/// Returns the credentials of the proxy in a config, or `None` if the config has no username, in
+/// which case the current credentials should be kept.
+fn config_credentials(s: &str) -> Result<Option<(String, String)>, String> {
+    let (options, _) = parse_config(s);
+    let value = |key: &str| {
+        options
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    };
+
+    match (value("PCAP2SOCKS_USERNAME"), value("PCAP2SOCKS_PASSWORD")) {
+        (Some(username), Some(password)) => Ok(Some((username, password))),
+        (None, _) => Ok(None),
+        (Some(_), None) => Err(String::from("missing password")),
+    }
+}
+
+/// Returns if the environment variable of a flag is set to true.
+fn env_flag(key: &str) -> bool {
+    match std::env::var(key) {
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Returns the name and the value of the first set environment variable of the proxy.
+fn proxy_from_env() -> Option<(&'static str, String)> {
+    for key in &["SOCKS_PROXY", "socks_proxy", "ALL_PROXY", "all_proxy"] {
+        if let Ok(value) = std::env::var(key) {
+            if !value.is_empty() {
+                return Some((*key, value));
+            }
+        }
+    }
+
+    None
+}
+
+/// Parses a proxy like `socks5://127.0.0.1:1080`. Only the SOCKS schemes are supported.
+fn parse_proxy(s: &str) -> Result<ResolvableSocketAddrV4, ResolvableAddrParseError> {
+    let s = match s.find("://") {
+        Some(i) => match &s[..i] {
+            "socks" | "socks5" | "socks5h" => &s[i + 3..],
+            scheme => {
+                return Err(ResolvableAddrParseError::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported scheme {}", scheme),
+                )))
+            }
+        },
+        None => s,
+    };
+    let s = s.trim_end_matches('/');
+    let s = match s.rfind('@') {
+        Some(i) => {
+            warn!(
+                "Ignore the credentials in the proxy, please use --username and --password instead"
+            );
+            &s[i + 1..]
+        }
+        None => s,
+    };
+
+    if s.contains(':') {
+        s.parse()
+    } else {
+        format!("{}:{}", s, DEFAULT_DST_PORT).parse()
+    }
+}
+
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+#[structopt(
+    about,
+    setting = structopt::clap::AppSettings::SubcommandsNegateReqs,
+    setting = structopt::clap::AppSettings::AllArgsOverrideSelf
+)]
+struct Flags {
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+    #[structopt(
+        long,
+        short,
+        help = "Prints verbose information (-vv for vverbose)",
+        parse(from_occurrences)
+    )]
+    pub verbose: usize,
+    #[structopt(
+        long = "interface",
+        short,
+        help = "Interface for listening",
+        value_name = "INTERFACE",
+        env = "PCAP2SOCKS_INTERFACE",
+        display_order(0)
+    )]
     pub inter: Option<String>,
-    #[structopt(long, help = "MTU", value_name = "VALUE", display_order(1))]
+    #[structopt(
+        long,
+        help = "MTU",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_MTU",
+        display_order(1)
+    )]
     pub mtu: Option<usize>,
     #[structopt(
         long,
-        short = "P",
-        help = "Preset",
-        value_name = "PRESET",
-        display_order(2)
+        short = "P",
+        help = "Preset",
+        value_name = "PRESET",
+        env = "PCAP2SOCKS_PRESET",
+        display_order(2)
+    )]
+    pub preset: Option<String>,
+    #[structopt(
+        long = "source",
+        short,
+        help = "Source",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_SOURCE",
+        display_order(3)
+    )]
+    #[cfg_attr(
+        feature = "discovery",
+        structopt(required_unless_one(&["preset", "auto_source"]))
+    )]
+    #[cfg_attr(not(feature = "discovery"), structopt(required_unless("preset")))]
+    pub src: Option<Ipv4Network>,
+    #[structopt(
+        long,
+        short,
+        help = "ARP publishing address",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_PUBLISH",
+        display_order(4)
+    )]
+    pub publish: Option<Ipv4Addr>,
+    #[structopt(
+        long = "destination",
+        short,
+        help = "Destination",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_DESTINATION",
+        display_order(5)
+    )]
+    pub dst: Option<ResolvableSocketAddrV4>,
+    #[cfg(feature = "dns")]
+    #[structopt(
+        long = "dns-min-ttl",
+        help = "Minimum TTL of the DNS cache",
+        value_name = "VALUE",
+        default_value = "0",
+        env = "PCAP2SOCKS_DNS_MIN_TTL",
+        display_order(6)
+    )]
+    pub dns_min_ttl: u32,
+    #[cfg(feature = "dns")]
+    #[structopt(
+        long = "dns-max-ttl",
+        help = "Maximum TTL of the DNS cache",
+        value_name = "VALUE",
+        default_value = "86400",
+        env = "PCAP2SOCKS_DNS_MAX_TTL",
+        display_order(7)
+    )]
+    pub dns_max_ttl: u32,
+    #[structopt(
+        long,
+        help = "Static hostname mappings",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_HOSTS",
+        display_order(8)
+    )]
+    pub hosts: Option<String>,
+    #[cfg(feature = "dns")]
+    #[structopt(
+        long,
+        help = "DNS server",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_DNS",
+        display_order(9)
+    )]
+    pub dns: Option<SocketAddrV4>,
+    #[structopt(
+        long,
+        help = "Block list",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_BLOCK",
+        display_order(10)
+    )]
+    pub block: Option<String>,
+    #[structopt(
+        long = "malformed-dump",
+        help = "Dump of malformed frames",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_MALFORMED_DUMP",
+        display_order(11)
+    )]
+    pub malformed_dump: Option<String>,
+    #[structopt(
+        long = "audit-log",
+        help = "Audit log",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_AUDIT_LOG",
+        display_order(12)
+    )]
+    pub audit_log: Option<String>,
+    #[structopt(
+        long = "udp-timeout",
+        help = "Timeout of idle UDP ports",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_UDP_TIMEOUT",
+        display_order(13)
+    )]
+    pub udp_timeout: Option<u64>,
+    #[structopt(
+        long = "udp-port-timeout",
+        help = "Timeout of idle UDP ports of a port",
+        value_name = "PORT:VALUE",
+        number_of_values = 1,
+        display_order(14)
+    )]
+    pub udp_port_timeouts: Vec<PortTimeout>,
+    #[structopt(
+        long,
+        help = "Config",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_CONFIG",
+        display_order(15)
+    )]
+    pub config: Option<String>,
+    #[cfg(feature = "api")]
+    #[structopt(
+        long,
+        help = "Health check address, unauthenticated with a bare status",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_HEALTH",
+        display_order(16)
+    )]
+    pub health: Option<SocketAddr>,
+    #[structopt(
+        long = "udp-relay",
+        help = "UDP relay",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_UDP_RELAY",
+        display_order(17)
+    )]
+    pub udp_relay: Option<SocketAddrV4>,
+    #[structopt(
+        long = "udp-mapping",
+        help = "UDP port mappings",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_UDP_MAPPING",
+        display_order(18)
+    )]
+    pub udp_mapping: Option<String>,
+    #[cfg(feature = "multicast")]
+    #[structopt(
+        long,
+        help = "Multicast group",
+        value_name = "ADDRESS",
+        number_of_values = 1,
+        display_order(19)
+    )]
+    pub multicast: Vec<SocketAddrV4>,
+    #[structopt(
+        long = "client-weight",
+        help = "Weight of a client in scheduling",
+        value_name = "ADDRESS:VALUE",
+        number_of_values = 1,
+        display_order(20)
+    )]
+    pub client_weights: Vec<ClientWeight>,
+    #[cfg(feature = "api")]
+    #[structopt(
+        long,
+        help = "Prometheus metrics address",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_METRICS",
+        display_order(21)
+    )]
+    pub metrics: Option<SocketAddr>,
+    #[cfg(feature = "capture")]
+    #[structopt(
+        long,
+        help = "Mirror of relayed frames",
+        value_name = "TARGET",
+        env = "PCAP2SOCKS_MIRROR",
+        display_order(22)
+    )]
+    pub mirror: Option<String>,
+    #[cfg(feature = "capture")]
+    #[structopt(
+        long = "mirror-snaplen",
+        help = "Snapshot length of the mirror",
+        value_name = "VALUE",
+        requires("mirror"),
+        env = "PCAP2SOCKS_MIRROR_SNAPLEN",
+        display_order(23)
+    )]
+    pub mirror_snaplen: Option<usize>,
+    #[cfg(feature = "capture")]
+    #[structopt(
+        long = "mirror-sample",
+        help = "Sampling rate of the mirror",
+        value_name = "VALUE",
+        requires("mirror"),
+        env = "PCAP2SOCKS_MIRROR_SAMPLE",
+        display_order(24)
+    )]
+    pub mirror_sample: Option<usize>,
+    #[cfg(feature = "ipfix")]
+    #[structopt(
+        long,
+        help = "IPFIX collector",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_IPFIX",
+        display_order(25)
+    )]
+    pub ipfix: Option<SocketAddr>,
+    #[cfg(feature = "ipfix")]
+    #[structopt(
+        long = "ipfix-sample",
+        help = "Sampling rate of IPFIX",
+        value_name = "VALUE",
+        requires("ipfix"),
+        env = "PCAP2SOCKS_IPFIX_SAMPLE",
+        display_order(26)
+    )]
+    pub ipfix_sample: Option<usize>,
+    #[structopt(
+        long = "arp-reply-interval",
+        help = "Interval between ARP replies to a source",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_ARP_REPLY_INTERVAL",
+        display_order(27)
+    )]
+    pub arp_reply_interval: Option<u64>,
+    #[cfg(feature = "ipc")]
+    #[structopt(
+        long,
+        help = "IPC path for frontends",
+        value_name = "PATH",
+        env = "PCAP2SOCKS_IPC",
+        display_order(28)
+    )]
+    pub ipc: Option<String>,
+    #[cfg(any(feature = "api", feature = "ipc"))]
+    #[structopt(
+        long = "api-token",
+        help = "Token of the management endpoints except the health check",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_API_TOKEN",
+        hide_env_values = true,
+        display_order(29)
+    )]
+    pub api_token: Option<String>,
+    #[cfg(feature = "api")]
+    #[structopt(
+        long = "api-allow",
+        help = "Network allowed to the management endpoints",
+        value_name = "CIDR",
+        number_of_values = 1,
+        display_order(30)
+    )]
+    pub api_allow: Vec<Ipv4Network>,
+    #[structopt(
+        long,
+        help = "Quota of each source",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_QUOTA",
+        display_order(31)
+    )]
+    pub quota: Option<Quota>,
+    #[structopt(
+        long = "quota-file",
+        help = "File of quota usages",
+        value_name = "FILE",
+        requires("quota"),
+        env = "PCAP2SOCKS_QUOTA_FILE",
+        display_order(32)
+    )]
+    pub quota_file: Option<String>,
+    #[structopt(
+        long = "quota-policy",
+        help = "Policy of sources beyond their quotas",
+        value_name = "POLICY",
+        default_value = "reject",
+        env = "PCAP2SOCKS_QUOTA_POLICY",
+        display_order(32)
+    )]
+    pub quota_policy: QuotaPolicy,
+    #[structopt(
+        long = "udp-keepalive",
+        help = "Interval of UDP keep-alive datagrams",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_UDP_KEEPALIVE",
+        display_order(33)
+    )]
+    pub udp_keepalive: Option<u64>,
+    #[structopt(
+        long = "tcp-port-batching",
+        help = "Batching of writes of TCP connections of a port",
+        value_name = "PORT:MODE",
+        number_of_values = 1,
+        display_order(34)
+    )]
+    pub tcp_port_batchings: Vec<PortBatching>,
+    #[structopt(
+        long,
+        help = "Replay of a pcap file instead of capturing",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_REPLAY",
+        display_order(35)
+    )]
+    pub replay: Option<String>,
+    #[structopt(
+        long = "replay-speed",
+        help = "Speed multiplier of the replay",
+        value_name = "VALUE",
+        requires("replay"),
+        env = "PCAP2SOCKS_REPLAY_SPEED",
+        display_order(36)
+    )]
+    pub replay_speed: Option<f64>,
+    #[structopt(
+        long = "replay-rewrite",
+        help = "Rewrite of an address in the replay",
+        value_name = "FROM=TO",
+        number_of_values = 1,
+        requires("replay"),
+        display_order(37)
+    )]
+    pub replay_rewrites: Vec<AddrRewrite>,
+    #[structopt(
+        long,
+        help = "Rewrite list",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_REWRITE",
+        display_order(38)
+    )]
+    pub rewrite: Option<String>,
+    #[structopt(
+        long = "filter-script",
+        help = "Script deciding verdicts of flows",
+        value_name = "COMMAND",
+        env = "PCAP2SOCKS_FILTER_SCRIPT",
+        display_order(38)
+    )]
+    pub filter_script: Option<String>,
+    #[structopt(
+        long = "name-policy",
+        help = "Policy of NetBIOS-NS and LLMNR queries",
+        value_name = "[ADDRESS:]POLICY",
+        number_of_values = 1,
+        display_order(39)
+    )]
+    pub name_policies: Vec<ClientNamePolicy>,
+    #[structopt(
+        long,
+        help = "WebSocket server tunneling to the destination",
+        value_name = "URL",
+        env = "PCAP2SOCKS_WEBSOCKET",
+        display_order(40)
+    )]
+    pub websocket: Option<WebSocketUrl>,
+    #[cfg(feature = "history")]
+    #[structopt(
+        long,
+        help = "History of closed flows",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_HISTORY",
+        display_order(41)
+    )]
+    pub history: Option<String>,
+    #[cfg(feature = "history")]
+    #[structopt(
+        long = "history-format",
+        help = "Format of the history",
+        value_name = "FORMAT",
+        default_value = "text",
+        env = "PCAP2SOCKS_HISTORY_FORMAT",
+        display_order(41)
+    )]
+    pub history_format: HistoryFormat,
+    #[cfg(feature = "alert")]
+    #[structopt(
+        long = "alert",
+        help = "Hook of alerts",
+        value_name = "HOOK",
+        number_of_values = 1,
+        display_order(42)
+    )]
+    pub alerts: Vec<AlertHook>,
+    #[cfg(feature = "alert")]
+    #[structopt(
+        long = "alert-drops",
+        help = "Threshold of malformed frames in a minute to alert",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_ALERT_DROPS",
+        display_order(43)
+    )]
+    pub alert_drops: Option<usize>,
+    #[cfg(feature = "alert")]
+    #[structopt(
+        long = "alert-buffer",
+        help = "Threshold of bytes buffered to alert",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_ALERT_BUFFER",
+        display_order(44)
+    )]
+    pub alert_buffer: Option<usize>,
+    #[structopt(
+        long,
+        help = "Number of worker threads",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_WORKERS",
+        display_order(45)
+    )]
+    pub workers: Option<usize>,
+    #[structopt(
+        long = "capture-cores",
+        help = "CPU cores to pin the capture thread",
+        value_name = "CORES",
+        env = "PCAP2SOCKS_CAPTURE_CORES",
+        display_order(46)
+    )]
+    pub capture_cores: Option<CoreSet>,
+    #[structopt(
+        long = "worker-cores",
+        help = "CPU cores to pin worker threads",
+        value_name = "CORES",
+        env = "PCAP2SOCKS_WORKER_CORES",
+        display_order(47)
+    )]
+    pub worker_cores: Option<CoreSet>,
+    #[structopt(
+        long = "utc-offset",
+        help = "Offset of the local time to UTC for schedules",
+        value_name = "OFFSET",
+        allow_hyphen_values = true,
+        env = "PCAP2SOCKS_UTC_OFFSET",
+        display_order(48)
+    )]
+    pub utc_offset: Option<UtcOffset>,
+    #[cfg(feature = "capture")]
+    #[structopt(
+        long,
+        help = "Capture list",
+        value_name = "FILE",
+        requires("capture_dump"),
+        env = "PCAP2SOCKS_CAPTURE",
+        display_order(49)
+    )]
+    pub capture: Option<String>,
+    #[cfg(feature = "capture")]
+    #[structopt(
+        long = "capture-dump",
+        help = "Rotating dump of captured frames",
+        value_name = "FILE",
+        requires("capture"),
+        env = "PCAP2SOCKS_CAPTURE_DUMP",
+        display_order(50)
+    )]
+    pub capture_dump: Option<String>,
+    #[structopt(
+        long = "dns-log",
+        help = "DNS log",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_DNS_LOG",
+        display_order(51)
+    )]
+    pub dns_log: Option<String>,
+    #[structopt(
+        long = "dns-log-format",
+        help = "Format of the DNS log",
+        value_name = "FORMAT",
+        default_value = "json",
+        env = "PCAP2SOCKS_DNS_LOG_FORMAT",
+        display_order(52)
+    )]
+    pub dns_log_format: DnsLogFormat,
+    #[structopt(
+        long,
+        help = "Port measuring ICMP echo requests through the proxy",
+        value_name = "PORT",
+        conflicts_with("no_icmp"),
+        env = "PCAP2SOCKS_PING",
+        display_order(53)
+    )]
+    pub ping: Option<u16>,
+    #[structopt(
+        long,
+        help = "Adaptive list of flows routed to the faster path",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_ADAPTIVE",
+        display_order(54)
+    )]
+    pub adaptive: Option<String>,
+    #[structopt(
+        long = "flow-state",
+        help = "Flow state kept across restarts",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_FLOW_STATE",
+        display_order(55)
+    )]
+    pub flow_state: Option<String>,
+    #[structopt(
+        long = "udp-port-strategy",
+        help = "Strategy of local UDP ports",
+        value_name = "STRATEGY",
+        default_value = "ephemeral",
+        env = "PCAP2SOCKS_UDP_PORT_STRATEGY",
+        display_order(56)
+    )]
+    pub udp_port_strategy: UdpPortStrategy,
+    #[structopt(
+        long,
+        help = "Address never redirected as a source",
+        value_name = "ADDRESS",
+        number_of_values = 1,
+        display_order(57)
+    )]
+    pub exclude: Vec<Ipv4Addr>,
+    #[structopt(
+        long,
+        help = "TTL of packets to sources",
+        value_name = "VALUE",
+        env = "PCAP2SOCKS_TTL",
+        display_order(58)
+    )]
+    pub ttl: Option<u8>,
+    #[structopt(
+        long = "ttl-policy",
+        help = "Policy of the TTL of packets between sources",
+        value_name = "POLICY",
+        default_value = "fixed",
+        env = "PCAP2SOCKS_TTL_POLICY",
+        display_order(59)
+    )]
+    pub ttl_policy: TtlPolicy,
+    #[structopt(
+        long,
+        help = "Keep-alive list",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_KEEPALIVE",
+        display_order(60)
+    )]
+    pub keepalive: Option<String>,
+    #[structopt(
+        long = "keepalive-interval",
+        help = "Interval of keep-alive probes",
+        value_name = "VALUE",
+        default_value = "30",
+        env = "PCAP2SOCKS_KEEPALIVE_INTERVAL",
+        display_order(61)
+    )]
+    pub keepalive_interval: u64,
+    #[structopt(
+        long = "rate-limit",
+        help = "Rate limit list",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_RATE_LIMIT",
+        display_order(62)
+    )]
+    pub rate_limit: Option<String>,
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    #[structopt(
+        long = "dns-upstream",
+        help = "Encrypted DNS server resolving DNS queries",
+        value_name = "URL",
+        env = "PCAP2SOCKS_DNS_UPSTREAM",
+        display_order(63)
+    )]
+    pub dns_upstream: Option<DnsUpstreamUrl>,
+    #[cfg(feature = "tls")]
+    #[structopt(
+        long = "websocket-pin",
+        help = "Pin of the certificate of the WebSocket server over TLS",
+        value_name = "PIN",
+        number_of_values = 1,
+        requires("websocket"),
+        display_order(64)
+    )]
+    pub websocket_pins: Vec<CertPin>,
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    #[structopt(
+        long = "dns-upstream-pin",
+        help = "Pin of the certificate of the encrypted DNS server",
+        value_name = "PIN",
+        number_of_values = 1,
+        requires("dns_upstream"),
+        display_order(65)
+    )]
+    pub dns_upstream_pins: Vec<CertPin>,
+    #[cfg(feature = "noise")]
+    #[structopt(
+        long = "ipc-tcp",
+        help = "IPC address for remote frontends, encrypted in Noise",
+        value_name = "ADDRESS",
+        env = "PCAP2SOCKS_IPC_TCP",
+        requires("ipc_key"),
+        display_order(66)
+    )]
+    pub ipc_tcp: Option<SocketAddr>,
+    #[cfg(feature = "noise")]
+    #[structopt(
+        long = "ipc-key",
+        help = "Pre-shared key of the IPC over TCP",
+        value_name = "KEY",
+        env = "PCAP2SOCKS_IPC_KEY",
+        hide_env_values = true,
+        requires("ipc_tcp"),
+        display_order(67)
+    )]
+    pub ipc_key: Option<IpcKey>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "Handover socket for upgrades keeping UDP associations",
+        value_name = "SOCKET",
+        env = "PCAP2SOCKS_HANDOVER",
+        display_order(68)
+    )]
+    pub handover: Option<String>,
+    #[structopt(
+        long = "force-associate-destination",
+        help = "Force to associate with the destination",
+        conflicts_with("force_associate_bind_addr"),
+        display_order(1000)
+    )]
+    pub force_associate_dst: bool,
+    #[structopt(
+        long = "force-associate-bind-address",
+        help = "Force to associate with the replied bind address",
+        display_order(1001)
+    )]
+    pub force_associate_bind_addr: bool,
+    #[cfg(feature = "dns")]
+    #[structopt(long = "dns-cache", help = "Cache DNS responses", display_order(1002))]
+    pub dns_cache: bool,
+    #[cfg(feature = "dns")]
+    #[structopt(
+        long = "dns-tcp",
+        help = "Resolve DNS queries over TCP through the proxy",
+        display_order(1003)
+    )]
+    pub dns_tcp: bool,
+    #[structopt(long, help = "Parse frames in the strict mode", display_order(1004))]
+    pub strict: bool,
+    #[structopt(
+        long = "force-publish",
+        help = "Force to publish even if the address is owned by another host",
+        display_order(1005)
+    )]
+    pub force_publish: bool,
+    #[structopt(
+        long,
+        help = "Prioritize interactive flows over bulk flows",
+        display_order(1006)
+    )]
+    pub qos: bool,
+    #[structopt(
+        long = "arp-backoff",
+        help = "Back off from publishing when the address is announced by another host",
+        display_order(1007)
+    )]
+    pub arp_backoff: bool,
+    #[structopt(long, help = "Never record payload of traffic", display_order(1008))]
+    pub privacy: bool,
+    #[structopt(
+        long = "no-delayed-ack",
+        help = "Acknowledge every TCP segment from sources immediately",
+        display_order(1009)
+    )]
+    pub no_delayed_ack: bool,
+    #[structopt(
+        long = "quick-ack-interactive",
+        help = "Acknowledge interactive flows immediately",
+        conflicts_with("no_delayed_ack"),
+        display_order(1010)
+    )]
+    pub quick_ack_interactive: bool,
+    #[structopt(long = "no-tcp", help = "Leave TCP untouched", display_order(1011))]
+    pub no_tcp: bool,
+    #[structopt(long = "no-udp", help = "Leave UDP untouched", display_order(1012))]
+    pub no_udp: bool,
+    #[structopt(long = "no-icmp", help = "Leave ICMP untouched", display_order(1013))]
+    pub no_icmp: bool,
+    #[structopt(
+        long = "no-hairpin",
+        help = "Send traffic between sources through the proxy",
+        display_order(1014)
+    )]
+    pub no_hairpin: bool,
+    #[cfg(feature = "discovery")]
+    #[structopt(
+        long = "auto-source",
+        help = "Discover sources from traffic on the interface",
+        display_order(1015)
+    )]
+    pub auto_source: bool,
+    #[structopt(
+        long,
+        help = "Re-evaluate filters on established TCP connections",
+        display_order(1016)
+    )]
+    pub reevaluate: bool,
+    #[cfg(feature = "dns")]
+    #[structopt(
+        long,
+        help = "Advertise EDNS and retry truncated DNS responses over TCP",
+        display_order(1017)
+    )]
+    pub edns: bool,
+    #[structopt(
+        long = "self-test",
+        help = "Test sending and receiving on the interface before redirecting",
+        conflicts_with("replay"),
+        display_order(1018)
+    )]
+    pub self_test: bool,
+    #[structopt(
+        long,
+        help = "Record latencies of each stage in the hot path",
+        display_order(1019)
+    )]
+    pub profile: bool,
+    #[structopt(
+        long = "error-json",
+        help = "Write the fatal error as a JSON object in the final line of the stderr",
+        display_order(1020)
+    )]
+    pub error_json: bool,
+    #[cfg(feature = "diagnostics")]
+    #[structopt(
+        long,
+        help = "Diagnose conditions of the network segment which break the redirection",
+        display_order(1021)
+    )]
+    pub diagnose: bool,
+    #[cfg(all(feature = "dns", feature = "tls"))]
+    #[structopt(
+        long = "dns-upstream-direct",
+        help = "Connect to the encrypted DNS server directly instead of through the proxy",
+        display_order(1022)
+    )]
+    pub dns_upstream_direct: bool,
+    #[structopt(
+        long,
+        help = "Username",
+        value_name = "VALUE",
+        requires("password"),
+        env = "PCAP2SOCKS_USERNAME",
+        display_order(1000)
+    )]
+    pub username: Option<String>,
+    #[structopt(
+        long,
+        help = "Password",
+        value_name = "VALUE",
+        requires("username"),
+        env = "PCAP2SOCKS_PASSWORD",
+        hide_env_values = true,
+        display_order(1001)
     )]
-    pub preset: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+enum Command {
+    #[structopt(about = "Tests the destination without capturing")]
+    Test(TestFlags),
+    #[cfg(feature = "history")]
+    #[structopt(about = "Queries the history of closed flows")]
+    History(HistoryFlags),
+    #[structopt(about = "Manages configs")]
+    Config(ConfigCommand),
+}
+
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+enum ConfigCommand {
+    #[structopt(about = "Checks a config without running")]
+    Check(ConfigCheckFlags),
+}
+
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+struct ConfigCheckFlags {
+    #[structopt(help = "Config", value_name = "FILE")]
+    pub config: String,
+}
+
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+struct TestFlags {
     #[structopt(
-        long = "source",
+        long = "destination",
         short,
-        help = "Source",
+        help = "Destination",
         value_name = "ADDRESS",
-        required_unless("preset"),
-        display_order(3)
+        env = "PCAP2SOCKS_DESTINATION",
+        display_order(0)
     )]
-    pub src: Option<Ipv4Network>,
+    pub dst: Option<ResolvableSocketAddrV4>,
     #[structopt(
         long,
         short,
-        help = "ARP publishing address",
+        help = "Test endpoint",
         value_name = "ADDRESS",
-        display_order(4)
+        display_order(1)
     )]
-    pub publish: Option<Ipv4Addr>,
+    pub target: ResolvableSocketAddrV4,
     #[structopt(
-        long = "destination",
-        short,
-        help = "Destination",
+        long = "udp-target",
+        help = "UDP test endpoint",
         value_name = "ADDRESS",
-        default_value = "127.0.0.1:1080",
-        display_order(5)
+        display_order(2)
     )]
-    pub dst: ResolvableSocketAddrV4,
+    pub udp_target: Option<ResolvableSocketAddrV4>,
     #[structopt(
-        long = "force-associate-destination",
-        help = "Force to associate with the destination",
-        conflicts_with("force_associate_bind_addr"),
-        display_order(1000)
+        long,
+        help = "Size of data in testing TCP",
+        value_name = "VALUE",
+        default_value = "1048576",
+        display_order(3)
     )]
-    pub force_associate_dst: bool,
+    pub size: usize,
     #[structopt(
-        long = "force-associate-bind-address",
-        help = "Force to associate with the replied bind address",
-        display_order(1001)
+        long,
+        help = "Number of datagrams in testing UDP",
+        value_name = "VALUE",
+        default_value = "4",
+        display_order(4)
     )]
-    pub force_associate_bind_addr: bool,
+    pub count: usize,
     #[structopt(
         long,
         help = "Username",
         value_name = "VALUE",
         requires("password"),
+        env = "PCAP2SOCKS_USERNAME",
         display_order(1000)
     )]
     pub username: Option<String>,
@@ -263,20 +3365,89 @@ struct Flags {
         help = "Password",
         value_name = "VALUE",
         requires("username"),
+        env = "PCAP2SOCKS_PASSWORD",
+        hide_env_values = true,
         display_order(1001)
     )]
     pub password: Option<String>,
 }
 
+#[cfg(feature = "history")]
+#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+struct HistoryFlags {
+    #[structopt(
+        long,
+        help = "History of closed flows",
+        value_name = "FILE",
+        env = "PCAP2SOCKS_HISTORY",
+        display_order(0)
+    )]
+    pub history: String,
+    #[structopt(
+        long = "source",
+        short,
+        help = "Source",
+        value_name = "ADDRESS",
+        display_order(1)
+    )]
+    pub src: Option<Ipv4Addr>,
+    #[structopt(
+        long,
+        short,
+        help = "Domain and its subdomains",
+        value_name = "NAME",
+        display_order(2)
+    )]
+    pub domain: Option<String>,
+    #[structopt(
+        long,
+        help = "Flows active since the time ago",
+        value_name = "DURATION",
+        display_order(3)
+    )]
+    pub since: Option<TimeAgo>,
+    #[structopt(
+        long,
+        help = "Flows active until the time ago",
+        value_name = "DURATION",
+        display_order(4)
+    )]
+    pub until: Option<TimeAgo>,
+    #[structopt(
+        long,
+        short = "n",
+        help = "Number of the last flows",
+        value_name = "VALUE",
+        display_order(5)
+    )]
+    pub limit: Option<usize>,
+}
+
+/// Represents the interval of summarizing repeated warnings.
+const LOG_THROTTLE_INTERVAL: u64 = 10000;
+/// Represents the max number of warnings of the same place logged in an interval.
+const LOG_THROTTLE_BURST: usize = 5;
+/// Represents the target of summaries of repeated warnings, which are never throttled.
+const LOG_THROTTLE_TARGET: &str = "throttle";
+
+/// Represents the warnings of the same place logged in an interval.
+#[derive(Debug, Default)]
+struct Throttled {
+    count: usize,
+    last: String,
+}
+
 /// Represents a logger.
 struct Logger {
     stderr_logger: env_logger::Logger,
     stdout_logger: env_logger::Logger,
+    throttled: Arc<Mutex<HashMap<(String, u32), Throttled>>>,
+    last_error: Arc<Mutex<String>>,
 }
 
 impl Logger {
-    /// Initializes the global logger.
-    pub fn init(level: LevelFilter) {
+    /// Initializes the global logger, and returns the last error logged.
+    pub fn init(level: LevelFilter) -> Arc<Mutex<String>> {
         let fmt = |buf: &mut Formatter, record: &Record| {
             let mut style = buf.style();
 
@@ -303,16 +3474,67 @@ impl Logger {
             .format(fmt)
             .build();
 
+        let throttled = Arc::new(Mutex::new(HashMap::new()));
+        let throttled_cloned = Arc::clone(&throttled);
+        let last_error = Arc::new(Mutex::new(String::new()));
         let logger = Logger {
             stderr_logger,
             stdout_logger,
+            throttled,
+            last_error: Arc::clone(&last_error),
         };
 
+        // Summarize repeated warnings
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(LOG_THROTTLE_INTERVAL));
+            let summaries = throttled_cloned
+                .lock()
+                .unwrap()
+                .drain()
+                .filter(|(_, throttled)| throttled.count > LOG_THROTTLE_BURST)
+                .map(|(_, throttled)| (throttled.count - LOG_THROTTLE_BURST, throttled.last))
+                .collect::<Vec<_>>();
+            for (count, last) in summaries {
+                warn!(
+                    target: LOG_THROTTLE_TARGET,
+                    "Suppressed {} repeated warnings in {} seconds, the last one: {}",
+                    count,
+                    LOG_THROTTLE_INTERVAL / 1000,
+                    last
+                );
+            }
+        });
+
         // Set the logger
         let r = log::set_boxed_logger(Box::new(logger));
         if r.is_ok() {
             log::set_max_level(level);
         }
+
+        last_error
+    }
+
+    /// Counts the warning in the place it was logged, and returns if it should be logged. Warnings
+    /// of the same place over the burst in an interval are summarized at the end of the interval.
+    ///
+    /// Warnings are throttled by their places rather than their formatted messages, since a storm
+    /// like failing to send to the pcap repeats the same warning with different addresses in it,
+    /// so different warnings of the same place are throttled together.
+    fn throttle(&self, record: &Record) -> bool {
+        let key = (
+            record.file().unwrap_or_default().to_string(),
+            record.line().unwrap_or_default(),
+        );
+
+        let mut throttled = self.throttled.lock().unwrap();
+        let entry = throttled.entry(key).or_default();
+        entry.count = entry.count.checked_add(1).unwrap_or(usize::MAX);
+        if entry.count <= LOG_THROTTLE_BURST {
+            return true;
+        }
+        entry.last = record.args().to_string();
+
+        false
     }
 }
 
@@ -325,8 +3547,18 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record) {
+        if record.level() == Level::Warn
+            && record.target() != LOG_THROTTLE_TARGET
+            && !self.throttle(record)
+        {
+            return;
+        }
+
         match record.metadata().level() {
-            Level::Error => self.stderr_logger.log(record),
+            Level::Error => {
+                *self.last_error.lock().unwrap() = record.args().to_string();
+                self.stderr_logger.log(record)
+            }
             _ => self.stdout_logger.log(record),
         }
     }
@@ -334,13 +3566,13 @@ impl Log for Logger {
     fn flush(&self) {}
 }
 
-fn set_logger(verbose: usize) {
+fn set_logger(verbose: usize) -> Arc<Mutex<String>> {
     let level = match verbose {
         0 => LevelFilter::Info,
         1 => LevelFilter::Debug,
         _ => LevelFilter::Trace,
     };
-    Logger::init(level);
+    Logger::init(level)
 }
 
 #[derive(Debug)]
@@ -373,6 +3605,7 @@ impl From<io::Error> for ResolvableAddrParseError {
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 struct ResolvableSocketAddrV4 {
     addr: SocketAddrV4,
+    addrs: Vec<SocketAddr>,
     alias: Option<String>,
 }
 
@@ -380,6 +3613,10 @@ impl ResolvableSocketAddrV4 {
     fn addr(&self) -> SocketAddrV4 {
         self.addr
     }
+
+    fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
 }
 
 impl Display for ResolvableSocketAddrV4 {
@@ -395,6 +3632,7 @@ impl FromStr for ResolvableSocketAddrV4 {
     type Err = ResolvableAddrParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let has_alias;
+        let mut addrs = Vec::new();
         let addr = match s.parse() {
             Ok(addr) => {
                 has_alias = false;
@@ -414,14 +3652,16 @@ impl FromStr for ResolvableSocketAddrV4 {
                     Err(_) => return Err(ResolvableAddrParseError::from(e)),
                 };
                 let ip = match dns_lookup::lookup_host(v[0]) {
-                    Ok(addrs) => {
+                    Ok(ips) => {
                         let mut ip = None;
 
-                        for addr in addrs {
+                        for addr in ips {
                             if let IpAddr::V4(addr) = addr {
-                                ip = Some(addr);
-                                break;
+                                if ip.is_none() {
+                                    ip = Some(addr);
+                                }
                             }
+                            addrs.push(SocketAddr::new(addr, port));
                         }
 
                         match ip {
@@ -440,6 +3680,631 @@ impl FromStr for ResolvableSocketAddrV4 {
             true => Some(String::from_str(s).unwrap()),
             false => None,
         };
-        Ok(ResolvableSocketAddrV4 { addr, alias })
+        Ok(ResolvableSocketAddrV4 { addr, addrs, alias })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct PortTimeout {
+    port: u16,
+    timeout: u64,
+}
+
+impl FromStr for PortTimeout {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split(':').collect::<Vec<_>>();
+        if v.len() != 2 {
+            return Err(format!("invalid port timeout {}", s));
+        }
+
+        let port = v[0].parse().map_err(|e| format!("invalid port: {}", e))?;
+        let timeout = v[1]
+            .parse()
+            .map_err(|e| format!("invalid timeout: {}", e))?;
+
+        Ok(PortTimeout { port, timeout })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct PortBatching {
+    port: u16,
+    batching: Batching,
+}
+
+impl FromStr for PortBatching {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split(':').collect::<Vec<_>>();
+        if v.len() != 2 {
+            return Err(format!("invalid port batching {}", s));
+        }
+
+        let port = v[0].parse().map_err(|e| format!("invalid port: {}", e))?;
+        let batching = v[1].parse()?;
+
+        Ok(PortBatching { port, batching })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ClientWeight {
+    ip_addr: Ipv4Addr,
+    weight: usize,
+}
+
+impl FromStr for ClientWeight {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split(':').collect::<Vec<_>>();
+        if v.len() != 2 {
+            return Err(format!("invalid client weight {}", s));
+        }
+
+        let ip_addr = v[0]
+            .parse()
+            .map_err(|e| format!("invalid address: {}", e))?;
+        let weight = v[1].parse().map_err(|e| format!("invalid weight: {}", e))?;
+        if weight == 0 {
+            return Err(String::from("invalid weight: zero"));
+        }
+
+        Ok(ClientWeight { ip_addr, weight })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct AddrRewrite {
+    from: Ipv4Addr,
+    to: Ipv4Addr,
+}
+
+impl FromStr for AddrRewrite {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split('=').collect::<Vec<_>>();
+        if v.len() != 2 {
+            return Err(format!("invalid address rewrite {}", s));
+        }
+
+        let from = v[0]
+            .parse()
+            .map_err(|e| format!("invalid address: {}", e))?;
+        let to = v[1]
+            .parse()
+            .map_err(|e| format!("invalid address: {}", e))?;
+
+        Ok(AddrRewrite { from, to })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ClientNamePolicy {
+    ip_addr: Option<Ipv4Addr>,
+    policy: NamePolicy,
+}
+
+impl FromStr for ClientNamePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split(':').collect::<Vec<_>>();
+        match v.len() {
+            1 => Ok(ClientNamePolicy {
+                ip_addr: None,
+                policy: v[0].parse()?,
+            }),
+            2 => {
+                let ip_addr = v[0]
+                    .parse()
+                    .map_err(|e| format!("invalid address: {}", e))?;
+                let policy = v[1].parse()?;
+
+                Ok(ClientNamePolicy {
+                    ip_addr: Some(ip_addr),
+                    policy,
+                })
+            }
+            _ => Err(format!("invalid name policy {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct WebSocketUrl {
+    is_tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebSocketUrl {
+    fn websocket(&self) -> io::Result<WebSocketConfig> {
+        #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+        let mut websocket = WebSocketConfig::new(self.host.clone(), self.port, self.path.clone());
+        #[cfg(feature = "tls")]
+        if self.is_tls {
+            websocket.set_tls(TlsConfig::new(&self.host)?);
+        }
+
+        Ok(websocket)
+    }
+
+    /// Resolves the host to an address, like the one connected to in each connection.
+    async fn lookup(&self) -> io::Result<SocketAddr> {
+        tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+impl Display for WebSocketUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}://{}:{}{}",
+            match self.is_tls {
+                true => "wss",
+                false => "ws",
+            },
+            self.host,
+            self.port,
+            self.path
+        )
+    }
+}
+
+impl FromStr for WebSocketUrl {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (is_tls, rest) = match s.find("://") {
+            Some(i) => match &s[..i] {
+                "ws" => (false, &s[i + 3..]),
+                #[cfg(feature = "tls")]
+                "wss" => (true, &s[i + 3..]),
+                #[cfg(not(feature = "tls"))]
+                "wss" => {
+                    return Err(String::from(
+                        "TLS is not supported, please build with the tls feature",
+                    ))
+                }
+                scheme => return Err(format!("unsupported scheme {}", scheme)),
+            },
+            None => (false, s),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|e| format!("invalid port {}: {}", &authority[i + 1..], e))?,
+            ),
+            None => match is_tls {
+                true => (authority, SECURE_WEBSOCKET_PORT),
+                false => (authority, WEBSOCKET_PORT),
+            },
+        };
+        if host.is_empty() {
+            return Err(format!("invalid URL {}", s));
+        }
+
+        Ok(WebSocketUrl {
+            is_tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct DnsUpstreamUrl {
+    host: String,
+    port: u16,
+    path: Option<String>,
+}
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+impl DnsUpstreamUrl {
+    fn upstream(&self) -> io::Result<DnsUpstream> {
+        match self.path {
+            Some(ref path) => DnsUpstream::new_https(&self.host, self.port, path),
+            None => DnsUpstream::new_tls(&self.host, self.port),
+        }
+    }
+}
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+impl Display for DnsUpstreamUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.path {
+            Some(ref path) => write!(f, "https://{}:{}{}", self.host, self.port, path),
+            None => write!(f, "tls://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+impl FromStr for DnsUpstreamUrl {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (is_https, rest) = match s.find("://") {
+            Some(i) => match &s[..i] {
+                "tls" => (false, &s[i + 3..]),
+                "https" => (true, &s[i + 3..]),
+                scheme => {
+                    return Err(format!(
+                        "unsupported scheme {}, please use tls or https",
+                        scheme
+                    ))
+                }
+            },
+            None => return Err(format!("invalid URL {}", s)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(&rest[i..])),
+            None => (rest, None),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|e| format!("invalid port {}: {}", &authority[i + 1..], e))?,
+            ),
+            None => match is_https {
+                true => (authority, dns::DNS_OVER_HTTPS_PORT),
+                false => (authority, dns::DNS_OVER_TLS_PORT),
+            },
+        };
+        if host.is_empty() || (!is_https && path.is_some()) {
+            return Err(format!("invalid URL {}", s));
+        }
+
+        Ok(DnsUpstreamUrl {
+            host: host.to_string(),
+            port,
+            path: match is_https {
+                true => Some(path.unwrap_or(DEFAULT_DNS_OVER_HTTPS_PATH).to_string()),
+                false => None,
+            },
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct TimeAgo(u64);
+
+impl FromStr for TimeAgo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => (&s[..i], &s[i..]),
+            None => (s, "s"),
+        };
+        let value: u64 = value
+            .parse()
+            .map_err(|e| format!("invalid duration {}: {}", s, e))?;
+        let unit = match unit {
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            _ => return Err(format!("invalid unit {}, please use s, m, h or d", unit)),
+        };
+
+        Ok(TimeAgo(value.checked_mul(unit).unwrap_or(u64::MAX)))
+    }
+}
+
+/// Represents the offset of the local time to UTC in minutes, like `+08:00` or `-05:30`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct UtcOffset(i32);
+
+impl FromStr for UtcOffset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sign, value) = match s.chars().next() {
+            Some('+') => (1, &s[1..]),
+            Some('-') => (-1, &s[1..]),
+            _ => (1, s),
+        };
+        let (hours, minutes) = match value.find(':') {
+            Some(i) => (&value[..i], &value[i + 1..]),
+            None => (value, "0"),
+        };
+        let hours: u16 = hours
+            .parse()
+            .map_err(|e| format!("invalid offset {}: {}", s, e))?;
+        let minutes: u16 = minutes
+            .parse()
+            .map_err(|e| format!("invalid offset {}: {}", s, e))?;
+        if hours > 14 || minutes >= 60 {
+            return Err(format!("offset {} out of range", s));
+        }
+
+        Ok(UtcOffset(sign * i32::from(hours * 60 + minutes)))
+    }
+}
+
+#[test]
+fn config_parse() {
+    let (options, lists) = parse_config(
+        "# pcap2socks\nconfig pcap2socks 'main'\n  option destination '127.0.0.1:1080'\n  list exclude '192.168.1.5'\n--interface=eth0\nqos\n",
+    );
+    assert_eq!(
+        options,
+        vec![
+            (
+                String::from("PCAP2SOCKS_DESTINATION"),
+                String::from("127.0.0.1:1080")
+            ),
+            (String::from("PCAP2SOCKS_INTERFACE"), String::from("eth0")),
+            (String::from("PCAP2SOCKS_QOS"), String::from("true")),
+        ]
+    );
+    assert_eq!(lists, vec![String::from("--exclude=192.168.1.5")]);
+}
+
+#[test]
+fn config_args_parse() {
+    assert_eq!(
+        config_args(
+            "destination = 127.0.0.1:1080\nqos\nstrict = off\nttl = 64\nlist exclude '10.6.0.5'\n"
+        ),
+        vec![
+            String::from("--destination=127.0.0.1:1080"),
+            String::from("--qos"),
+            String::from("--ttl=64"),
+            String::from("--exclude=10.6.0.5")
+        ]
+    );
+}
+
+#[test]
+fn config_args_merge() {
+    let mut args = vec![String::from("pcap2socks")];
+    args.extend(config_args(
+        "destination = 127.0.0.1:1080\nqos\nverbose\nttl = 64\nlist exclude '10.6.0.5'\n",
+    ));
+    args.extend(
+        [
+            "-d",
+            "127.0.0.1:1081",
+            "--preset=t",
+            "--qos",
+            "-v",
+            "--exclude",
+            "10.6.0.6",
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    let flags = Flags::from_iter_safe(&args).unwrap();
+
+    // Options in the command line override the config
+    assert_eq!(
+        flags.dst.unwrap().addr(),
+        SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1081)
+    );
+    assert_eq!(flags.ttl, Some(64));
+    // Flags set in both are set once
+    assert!(flags.qos);
+    // Repeated options and flags are added
+    assert_eq!(flags.verbose, 2);
+    assert_eq!(
+        flags.exclude,
+        vec![Ipv4Addr::new(10, 6, 0, 5), Ipv4Addr::new(10, 6, 0, 6)]
+    );
+}
+
+#[test]
+fn config_flags_take_no_value() {
+    for name in CONFIG_FLAGS.iter() {
+        let args = [
+            String::from("pcap2socks"),
+            String::from("--preset=t"),
+            format!("--{}", name),
+        ];
+        // Flags of disabled features are unknown
+        assert!(
+            !matches!(parse_args(&args), Err((ErrorKind::EmptyValue, _))),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn config_line_parse() {
+    assert_eq!(
+        parse_config_line("  option dns_cache '1' # Cache"),
+        Some(ConfigEntry::Option(
+            String::from("PCAP2SOCKS_DNS_CACHE"),
+            String::from("1")
+        ))
+    );
+    assert_eq!(
+        parse_config_line("list multicast \"239.255.255.250:1900\""),
+        Some(ConfigEntry::List(String::from(
+            "--multicast=239.255.255.250:1900"
+        )))
+    );
+    assert_eq!(
+        parse_config_line("--udp-timeout = 30"),
+        Some(ConfigEntry::Option(
+            String::from("PCAP2SOCKS_UDP_TIMEOUT"),
+            String::from("30")
+        ))
+    );
+    assert_eq!(parse_config_line("config pcap2socks 'main'"), None);
+    assert_eq!(parse_config_line("  # Comment"), None);
+}
+
+#[test]
+fn config_from_flags() {
+    let flags = Flags::from_iter_safe(&[
+        "pcap2socks",
+        "-i",
+        "eth0",
+        "--mtu",
+        "1400",
+        "-s",
+        "10.6.0.1/32",
+        "-p",
+        "10.6.0.2",
+        "-d",
+        "127.0.0.1:1080",
+        "--username",
+        "user",
+        "--password",
+        "pass",
+        "--force-associate-destination",
+        "--udp-relay",
+        "127.0.0.1:1081",
+    ])
+    .unwrap();
+    let dst = flags.dst.clone().unwrap();
+    let config = config(&flags, &dst);
+    assert_eq!(config.interface, Some(String::from("eth0")));
+    assert_eq!(config.mtu, Some(1400));
+    assert_eq!(config.src, Some("10.6.0.1/32".parse().unwrap()));
+    assert_eq!(config.publish, Some(Ipv4Addr::new(10, 6, 0, 2)));
+    assert_eq!(config.dst, "127.0.0.1:1080".parse().unwrap());
+    assert!(config.dst_addrs.is_empty());
+    assert_eq!(
+        config.auth,
+        Some((String::from("user"), String::from("pass")))
+    );
+    assert!(config.force_associate_dst);
+    assert!(!config.force_associate_bind_addr);
+    assert_eq!(config.udp_relay, Some("127.0.0.1:1081".parse().unwrap()));
+}
+
+#[test]
+fn config_check() {
+    assert!(check_config("destination = 127.0.0.1:1080\npreset = t\nqos\n").is_empty());
+
+    let errors = check_config("destination = 127.0.0.1:1080\nttl = 300\npreset = t\n");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("line 2: ttl: "));
+
+    // Repeated options and flags, which override or add to the previous ones
+    assert!(check_config(
+        "destination = 127.0.0.1:1080\npreset = t\nqos\nqos = on\nverbose\nverbose\n\
+         list exclude '10.6.0.5'\nlist exclude '10.6.0.6'\nttl = 64\nttl = 32\n"
+    )
+    .is_empty());
+
+    // Flags with values other than truthy or falsy ones
+    let errors = check_config("destination = 127.0.0.1:1080\npreset = t\nqos = maybe\n");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("line 3: qos: "));
+
+    // Rule lists
+    let path = std::env::temp_dir().join("pcap2socks_config_check_block");
+    fs::write(&path, "10.0.0.1\n10.0.0.256:443\n").unwrap();
+    let path = path.to_str().unwrap();
+    assert_eq!(
+        check_config(&format!(
+            "destination = 127.0.0.1:1080\npreset = t\nblock = {}\n",
+            path
+        )),
+        vec![format!("block {}: line 2: 10.0.0.256:443", path)]
+    );
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn config_credentials_parse() {
+    assert_eq!(
+        config_credentials("username=alice\npassword=secret"),
+        Ok(Some((String::from("alice"), String::from("secret"))))
+    );
+    // Credentials not in the config are kept
+    assert_eq!(config_credentials("destination=127.0.0.1:1080"), Ok(None));
+    assert_eq!(config_credentials("password=secret"), Ok(None));
+    assert!(config_credentials("username=alice").is_err());
+}
+
+#[test]
+fn logger_throttle() {
+    let logger = Logger {
+        stderr_logger: env_logger::builder().build(),
+        stdout_logger: env_logger::builder().build(),
+        throttled: Arc::new(Mutex::new(HashMap::new())),
+        last_error: Arc::new(Mutex::new(String::new())),
+    };
+
+    // Warnings of the same place are throttled together regardless of their messages
+    for i in 0..LOG_THROTTLE_BURST + 2 {
+        let is_logged = logger.throttle(
+            &Record::builder()
+                .level(Level::Warn)
+                .file(Some("src/lib.rs"))
+                .line(Some(1))
+                .args(format_args!("send to pcap: 10.6.0.{}", i))
+                .build(),
+        );
+        assert_eq!(is_logged, i < LOG_THROTTLE_BURST);
+    }
+    assert!(logger.throttle(
+        &Record::builder()
+            .level(Level::Warn)
+            .file(Some("src/lib.rs"))
+            .line(Some(2))
+            .args(format_args!("send to pcap: 10.6.0.1"))
+            .build()
+    ));
+
+    let throttled = logger.throttled.lock().unwrap();
+    let throttled = &throttled[&(String::from("src/lib.rs"), 1)];
+    assert_eq!(throttled.count, LOG_THROTTLE_BURST + 2);
+    assert_eq!(throttled.last, "send to pcap: 10.6.0.6");
+}
+
+#[cfg(all(feature = "dns", feature = "tls"))]
+#[test]
+fn dns_upstream_url_parse() {
+    let url: DnsUpstreamUrl = "https://dns.google".parse().unwrap();
+    assert_eq!(url.to_string(), "https://dns.google:443/dns-query");
+    let url: DnsUpstreamUrl = "https://doh.example.com:8443/resolve".parse().unwrap();
+    assert_eq!(url.to_string(), "https://doh.example.com:8443/resolve");
+    let url: DnsUpstreamUrl = "tls://one.one.one.one".parse().unwrap();
+    assert_eq!(url.to_string(), "tls://one.one.one.one:853");
+    assert!(url.upstream().is_ok());
+
+    assert!("dns.google".parse::<DnsUpstreamUrl>().is_err());
+    assert!("udp://dns.google".parse::<DnsUpstreamUrl>().is_err());
+    assert!("tls://dns.google/dns-query"
+        .parse::<DnsUpstreamUrl>()
+        .is_err());
+    assert!("tls://dns.google:dot".parse::<DnsUpstreamUrl>().is_err());
+}
+
+#[test]
+fn websocket_url_parse() {
+    let url: WebSocketUrl = "ws://example.com/socks".parse().unwrap();
+    assert_eq!(url.to_string(), "ws://example.com:80/socks");
+    let url: WebSocketUrl = "10.0.0.1:8080".parse().unwrap();
+    assert_eq!(url.to_string(), "ws://10.0.0.1:8080/");
+    assert!("http://example.com".parse::<WebSocketUrl>().is_err());
+    assert!("ws://example.com:http/".parse::<WebSocketUrl>().is_err());
+
+    #[cfg(feature = "tls")]
+    {
+        let url: WebSocketUrl = "wss://cdn.example.com/socks".parse().unwrap();
+        assert_eq!(url.to_string(), "wss://cdn.example.com:443/socks");
+        assert!(url.websocket().unwrap().is_tls());
     }
+    #[cfg(not(feature = "tls"))]
+    assert!("wss://cdn.example.com/socks"
+        .parse::<WebSocketUrl>()
+        .is_err());
 }
@@ -0,0 +1,200 @@
+//! Support for relaying multicast groups joined by sources.
+
+use ipnetwork::Ipv4Network;
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::proxy::ForwardDatagram;
+
+/// Represents the IGMPv1 membership report.
+const IGMP_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+/// Represents the IGMPv2 membership report.
+const IGMP_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+/// Represents the IGMPv2 leave group.
+const IGMP_V2_LEAVE_GROUP: u8 = 0x17;
+/// Represents the IGMPv3 membership report.
+const IGMP_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+const ALLOW_NEW_SOURCES: u8 = 5;
+
+const IGMP_HEADER_SIZE: usize = 8;
+const GROUP_RECORD_HEADER_SIZE: usize = 8;
+
+/// Parses an IGMP message from a source, and returns the multicast groups it joins or leaves.
+/// Each item is a group and if the group is joined.
+pub fn parse_igmp(payload: &[u8]) -> Vec<(Ipv4Addr, bool)> {
+    if payload.len() < IGMP_HEADER_SIZE {
+        return Vec::new();
+    }
+
+    match payload[0] {
+        IGMP_V1_MEMBERSHIP_REPORT | IGMP_V2_MEMBERSHIP_REPORT => {
+            vec![(parse_group(&payload[4..]), true)]
+        }
+        IGMP_V2_LEAVE_GROUP => vec![(parse_group(&payload[4..]), false)],
+        IGMP_V3_MEMBERSHIP_REPORT => {
+            let mut groups = Vec::new();
+
+            let n = payload[6] as usize * 256 + payload[7] as usize;
+            let mut i = IGMP_HEADER_SIZE;
+            for _ in 0..n {
+                if payload.len() < i + GROUP_RECORD_HEADER_SIZE {
+                    break;
+                }
+                let record_type = payload[i];
+                let aux_len = payload[i + 1] as usize;
+                let sources = payload[i + 2] as usize * 256 + payload[i + 3] as usize;
+                let group = parse_group(&payload[i + 4..]);
+                match record_type {
+                    MODE_IS_EXCLUDE | CHANGE_TO_EXCLUDE_MODE => groups.push((group, true)),
+                    MODE_IS_INCLUDE | CHANGE_TO_INCLUDE_MODE => {
+                        // Including no source means leaving the group
+                        groups.push((group, sources > 0));
+                    }
+                    ALLOW_NEW_SOURCES => {
+                        if sources > 0 {
+                            groups.push((group, true));
+                        }
+                    }
+                    _ => {}
+                }
+
+                i += GROUP_RECORD_HEADER_SIZE + sources * 4 + aux_len * 4;
+            }
+
+            groups
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn parse_group(buffer: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3])
+}
+
+/// Represents a worker which joins a multicast group on the host side and relays datagrams of
+/// the group to sources.
+pub struct MulticastWorker {
+    group: SocketAddrV4,
+    close_tx: Sender<()>,
+}
+
+impl MulticastWorker {
+    /// Joins the multicast group on the interface of the given address. Datagrams from sources
+    /// themselves will not be relayed.
+    pub async fn join(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        group: SocketAddrV4,
+        local_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Network,
+    ) -> io::Result<MulticastWorker> {
+        // Share the port with other listeners of the group on the host, like SSDP
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+        let socket = UdpSocket::from_std(socket.into_udp_socket())?;
+        socket.join_multicast_v4(*group.ip(), local_ip_addr)?;
+
+        let (close_tx, mut close_rx) = mpsc::channel(1);
+
+        // Receive
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; u16::MAX as usize];
+            loop {
+                let result;
+
+                // Select
+                {
+                    let socket_fut = socket.recv_from(&mut buffer);
+                    let close_rx_fut = close_rx.recv();
+
+                    tokio::pin!(socket_fut, close_rx_fut);
+
+                    tokio::select! {
+                        r = socket_fut => result = Some(r),
+                        _ = close_rx_fut => result = None
+                    }
+                }
+
+                let (size, addr) = match result {
+                    Some(Ok((size, SocketAddr::V4(addr)))) => (size, addr),
+                    Some(Ok(_)) => continue,
+                    Some(Err(ref e)) => {
+                        warn!("receive from multicast: {}: {}", group, e);
+                        break;
+                    }
+                    None => break,
+                };
+                if src_ip_addr.contains(*addr.ip()) {
+                    continue;
+                }
+                debug!(
+                    "receive from multicast: {} -> {} ({} Bytes)",
+                    addr, group, size
+                );
+
+                // Send
+                if let Err(ref e) = tx.lock().unwrap().forward(addr, group, &buffer[..size]) {
+                    warn!("handle multicast: {}: {} -> {}: {}", "UDP", addr, group, e);
+                }
+            }
+            trace!("close multicast {}", group);
+        });
+
+        trace!("join multicast {}", group);
+
+        Ok(MulticastWorker { group, close_tx })
+    }
+}
+
+impl Drop for MulticastWorker {
+    fn drop(&mut self) {
+        let _ = self.close_tx.try_send(());
+        trace!("drop multicast {}", self.group);
+    }
+}
+
+#[test]
+fn parse_igmp_v2_v3() {
+    let group = Ipv4Addr::new(239, 255, 255, 250);
+    let report = [0x16, 0, 0, 0, 239, 255, 255, 250];
+    assert_eq!(parse_igmp(&report), vec![(group, true)]);
+    let leave = [0x17, 0, 0, 0, 239, 255, 255, 250];
+    assert_eq!(parse_igmp(&leave), vec![(group, false)]);
+
+    // IGMPv3 report with a CHANGE_TO_EXCLUDE_MODE and a CHANGE_TO_INCLUDE_MODE without sources
+    let report = [
+        0x22, 0, 0, 0, 0, 0, 0, 2, 4, 0, 0, 0, 239, 255, 255, 250, 3, 0, 0, 0, 224, 0, 0, 251,
+    ];
+    assert_eq!(
+        parse_igmp(&report),
+        vec![(group, true), (Ipv4Addr::new(224, 0, 0, 251), false)]
+    );
+}
+
+#[test]
+fn parse_igmp_v1_and_truncated() {
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let report = [0x12, 0, 0, 0, 224, 0, 0, 251];
+    assert_eq!(parse_igmp(&report), vec![(group, true)]);
+    assert!(parse_igmp(&report[..4]).is_empty());
+    // Membership queries are not from members
+    assert!(parse_igmp(&[0x11, 0, 0, 0, 0, 0, 0, 0]).is_empty());
+
+    // IGMPv3 report with an ALLOW_NEW_SOURCES of 1 source and 1 word of auxiliary data, followed
+    // by a truncated record
+    let report = [
+        0x22, 0, 0, 0, 0, 0, 0, 2, 5, 1, 0, 1, 224, 0, 0, 251, 10, 0, 0, 1, 0, 0, 0, 0, 4, 0, 0,
+    ];
+    assert_eq!(parse_igmp(&report), vec![(group, true)]);
+}
@@ -0,0 +1,189 @@
+//! Support for handling local name resolution of NetBIOS and LLMNR.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::dns::Hosts;
+
+/// Represents the port of the NetBIOS name service.
+pub const NBNS_PORT: u16 = 137;
+/// Represents the port of LLMNR.
+pub const LLMNR_PORT: u16 = 5355;
+
+const NBNS_HEADER_SIZE: usize = 12;
+/// Represents the size of an encoded NetBIOS name without scopes, including the length and the
+/// terminating zero.
+const NBNS_NAME_SIZE: usize = 34;
+const NBNS_TYPE_NB: u16 = 0x0020;
+const NBNS_CLASS_IN: u16 = 0x0001;
+const NBNS_TTL: u32 = 60;
+/// Represents the suffix of the workstation service.
+const NBNS_SUFFIX_WORKSTATION: u8 = 0x00;
+/// Represents the suffix of the file server service.
+const NBNS_SUFFIX_SERVER: u8 = 0x20;
+
+/// Represents a service of local name resolution.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NameService {
+    /// Represents the NetBIOS name service.
+    NetBios,
+    /// Represents the Link-Local Multicast Name Resolution.
+    Llmnr,
+}
+
+impl NameService {
+    /// Returns the service of the destination port, or `None` if the port is of neither of them.
+    pub fn from_port(port: u16) -> Option<NameService> {
+        match port {
+            NBNS_PORT => Some(NameService::NetBios),
+            LLMNR_PORT => Some(NameService::Llmnr),
+            _ => None,
+        }
+    }
+}
+
+impl Display for NameService {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            NameService::NetBios => "NetBIOS-NS",
+            NameService::Llmnr => "LLMNR",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Represents the policy of handling queries of local name resolution from sources.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NamePolicy {
+    /// Drops the queries, which lets the source fall back to DNS.
+    Block,
+    /// Answers the queries from the static hostname mappings, and drops the others.
+    Respond,
+    /// Sends the queries through the proxy like other UDP datagrams.
+    Relay,
+}
+
+impl Display for NamePolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            NamePolicy::Block => "block",
+            NamePolicy::Respond => "respond",
+            NamePolicy::Relay => "relay",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for NamePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(NamePolicy::Block),
+            "respond" => Ok(NamePolicy::Respond),
+            "relay" => Ok(NamePolicy::Relay),
+            _ => Err(format!("invalid name policy {}", s)),
+        }
+    }
+}
+
+/// Decodes the first-level encoding of a NetBIOS name, and returns the name without the padding
+/// and its suffix.
+fn decode_name(encoded: &[u8]) -> Option<(String, u8)> {
+    if encoded.len() != 32 {
+        return None;
+    }
+
+    let mut decoded = [0u8; 16];
+    for (i, pair) in encoded.chunks(2).enumerate() {
+        if !(b'A'..=b'P').contains(&pair[0]) || !(b'A'..=b'P').contains(&pair[1]) {
+            return None;
+        }
+        decoded[i] = ((pair[0] - b'A') << 4) | (pair[1] - b'A');
+    }
+
+    let name = String::from_utf8_lossy(&decoded[..15])
+        .trim_end_matches(' ')
+        .to_lowercase();
+
+    Some((name, decoded[15]))
+}
+
+/// Returns the NetBIOS name query response of the given NetBIOS name query if the name of the
+/// query is in the static hostname mappings. Names with scopes and services other than the
+/// workstation and the file server are not answered.
+pub fn reply_nbns(hosts: &Hosts, query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < NBNS_HEADER_SIZE + NBNS_NAME_SIZE + 4 {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    // A request of the name query
+    if flags & 0x8000 != 0 || (flags >> 11) & 0x0f != 0 || qdcount != 1 {
+        return None;
+    }
+
+    // Question
+    let name = &query[NBNS_HEADER_SIZE..NBNS_HEADER_SIZE + NBNS_NAME_SIZE];
+    if name[0] != 32 || name[NBNS_NAME_SIZE - 1] != 0 {
+        return None;
+    }
+    let (decoded, suffix) = decode_name(&name[1..NBNS_NAME_SIZE - 1])?;
+    if suffix != NBNS_SUFFIX_WORKSTATION && suffix != NBNS_SUFFIX_SERVER {
+        return None;
+    }
+    let i = NBNS_HEADER_SIZE + NBNS_NAME_SIZE;
+    let qtype = u16::from_be_bytes([query[i], query[i + 1]]);
+    let qclass = u16::from_be_bytes([query[i + 2], query[i + 3]]);
+    if qtype != NBNS_TYPE_NB || qclass != NBNS_CLASS_IN {
+        return None;
+    }
+    let ip_addr = hosts.get(&decoded)?;
+
+    // Header of an authoritative positive response
+    let mut response = Vec::with_capacity(NBNS_HEADER_SIZE + NBNS_NAME_SIZE + 16);
+    response.extend_from_slice(&query[..2]);
+    response.extend_from_slice(&0x8500u16.to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    // Answer
+    response.extend_from_slice(name);
+    response.extend_from_slice(&NBNS_TYPE_NB.to_be_bytes());
+    response.extend_from_slice(&NBNS_CLASS_IN.to_be_bytes());
+    response.extend_from_slice(&NBNS_TTL.to_be_bytes());
+    response.extend_from_slice(&6u16.to_be_bytes());
+    // A unique name of a B node
+    response.extend_from_slice(&[0, 0]);
+    response.extend_from_slice(&ip_addr.octets());
+
+    Some(response)
+}
+
+#[test]
+fn names_reply_nbns() {
+    let hosts = Hosts::parse("192.168.1.2 nas\n");
+
+    // Name query of "NAS" with the file server suffix
+    let mut query = vec![0x12, 0x34, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 0, 32];
+    let mut name = *b"NAS             ";
+    name[15] = NBNS_SUFFIX_SERVER;
+    for b in name.iter() {
+        query.push(b'A' + (b >> 4));
+        query.push(b'A' + (b & 0x0f));
+    }
+    query.extend_from_slice(&[0, 0x00, 0x20, 0x00, 0x01]);
+
+    let response = reply_nbns(&hosts, &query).unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+    assert_eq!(&response[12..46], &query[12..46]);
+    assert_eq!(&response[response.len() - 4..], &[192, 168, 1, 2]);
+
+    // Unknown name
+    let hosts = Hosts::parse("192.168.1.2 printer\n");
+    assert!(reply_nbns(&hosts, &query).is_none());
+
+    assert_eq!("Respond".parse(), Ok(NamePolicy::Respond));
+    assert!("answer".parse::<NamePolicy>().is_err());
+}
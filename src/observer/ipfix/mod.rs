@@ -0,0 +1,257 @@
+//! Support for exporting flows in IPFIX.
+
+use log::{trace, warn};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{CloseReason, FlowObserver};
+use crate::packet::layer::{LayerKind, LayerKinds};
+
+/// Represents the version of IPFIX.
+const IPFIX_VERSION: u16 = 10;
+/// Represents the ID of the template of IPFIX data records.
+const IPFIX_TEMPLATE_ID: u16 = 256;
+/// Represents the number of IPFIX messages between 2 template sets.
+const IPFIX_TEMPLATE_INTERVAL: u32 = 16;
+/// Represents the fields in the template of IPFIX data records, as information element IDs and
+/// lengths.
+const IPFIX_FIELDS: [(u16, u16); 10] = [
+    // sourceIPv4Address
+    (8, 4),
+    // destinationIPv4Address
+    (12, 4),
+    // sourceTransportPort
+    (7, 2),
+    // destinationTransportPort
+    (11, 2),
+    // protocolIdentifier
+    (4, 1),
+    // octetDeltaCount
+    (1, 8),
+    // packetDeltaCount
+    (2, 8),
+    // flowStartMilliseconds
+    (152, 8),
+    // flowEndMilliseconds
+    (153, 8),
+    // flowEndReason
+    (136, 1),
+];
+
+#[derive(Debug)]
+struct IpfixEntry {
+    start: SystemTime,
+    sent: usize,
+    sent_packets: usize,
+    received: usize,
+    received_packets: usize,
+}
+
+#[derive(Debug)]
+struct IpfixSocket {
+    socket: UdpSocket,
+    sequence: u32,
+    messages: u32,
+    closed: usize,
+}
+
+/// Represents an observer which exports a pair of IPFIX (RFC 7011) data records to a collector
+/// for each closed flow, one in each direction, with the endpoints, the bytes and the number of
+/// payloads relayed, the duration and the reason why it was closed.
+#[derive(Debug)]
+pub struct IpfixExporter {
+    socket: Mutex<IpfixSocket>,
+    entries: Mutex<HashMap<(LayerKind, SocketAddrV4, SocketAddrV4), IpfixEntry>>,
+    sample: usize,
+}
+
+impl IpfixExporter {
+    /// Creates an `IpfixExporter` which exports to the collector of the given address.
+    pub fn connect(collector: SocketAddr) -> io::Result<IpfixExporter> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(collector)?;
+
+        Ok(IpfixExporter {
+            socket: Mutex::new(IpfixSocket {
+                socket,
+                sequence: 0,
+                messages: 0,
+                closed: 0,
+            }),
+            entries: Mutex::new(HashMap::new()),
+            sample: 1,
+        })
+    }
+
+    /// Sets the sampling rate. Only 1 flow will be exported in every given number of closed
+    /// flows.
+    pub fn set_sample(&mut self, sample: usize) {
+        self.sample = max(sample, 1);
+        trace!("set IPFIX sampling rate to {}", self.sample);
+    }
+
+    fn add(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        sent: usize,
+        received: usize,
+    ) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&(kind, src, dst)) {
+            if sent > 0 {
+                entry.sent = entry.sent.checked_add(sent).unwrap_or(usize::MAX);
+                entry.sent_packets = entry.sent_packets.checked_add(1).unwrap_or(usize::MAX);
+            }
+            if received > 0 {
+                entry.received = entry.received.checked_add(received).unwrap_or(usize::MAX);
+                entry.received_packets =
+                    entry.received_packets.checked_add(1).unwrap_or(usize::MAX);
+            }
+        }
+    }
+}
+
+impl FlowObserver for IpfixExporter {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.entries.lock().unwrap().insert(
+            (kind, src, dst),
+            IpfixEntry {
+                start: SystemTime::now(),
+                sent: 0,
+                sent_packets: 0,
+                received: 0,
+                received_packets: 0,
+            },
+        );
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        let now = SystemTime::now();
+        // Flows failed to connect are closed without being created
+        let entry = self
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&(kind, src, dst))
+            .unwrap_or(IpfixEntry {
+                start: now,
+                sent: 0,
+                sent_packets: 0,
+                received: 0,
+                received_packets: 0,
+            });
+
+        let mut socket = self.socket.lock().unwrap();
+        // Sample
+        socket.closed = socket.closed.checked_add(1).unwrap_or(0);
+        if socket.closed % self.sample != 0 {
+            return;
+        }
+
+        let protocol = match kind {
+            LayerKinds::Tcp => 6,
+            LayerKinds::Udp => 17,
+            _ => 0,
+        };
+        let reason = ipfix_end_reason(reason);
+        let start = ipfix_millis(entry.start);
+        let end = ipfix_millis(now);
+
+        // Data set
+        let mut data = Vec::new();
+        for (src, dst, octets, packets) in &[
+            (src, dst, entry.sent, entry.sent_packets),
+            (dst, src, entry.received, entry.received_packets),
+        ] {
+            data.extend_from_slice(&src.ip().octets());
+            data.extend_from_slice(&dst.ip().octets());
+            data.extend_from_slice(&src.port().to_be_bytes());
+            data.extend_from_slice(&dst.port().to_be_bytes());
+            data.push(protocol);
+            data.extend_from_slice(&(*octets as u64).to_be_bytes());
+            data.extend_from_slice(&(*packets as u64).to_be_bytes());
+            data.extend_from_slice(&start.to_be_bytes());
+            data.extend_from_slice(&end.to_be_bytes());
+            data.push(reason);
+        }
+
+        // Template set
+        let mut sets = Vec::new();
+        if socket.messages % IPFIX_TEMPLATE_INTERVAL == 0 {
+            sets.extend_from_slice(&2u16.to_be_bytes());
+            sets.extend_from_slice(&(8 + IPFIX_FIELDS.len() as u16 * 4).to_be_bytes());
+            sets.extend_from_slice(&IPFIX_TEMPLATE_ID.to_be_bytes());
+            sets.extend_from_slice(&(IPFIX_FIELDS.len() as u16).to_be_bytes());
+            for (id, length) in IPFIX_FIELDS.iter() {
+                sets.extend_from_slice(&id.to_be_bytes());
+                sets.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+        sets.extend_from_slice(&IPFIX_TEMPLATE_ID.to_be_bytes());
+        sets.extend_from_slice(&(4 + data.len() as u16).to_be_bytes());
+        sets.extend_from_slice(&data);
+
+        // Message header
+        let mut message = Vec::with_capacity(16 + sets.len());
+        message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+        message.extend_from_slice(&(16 + sets.len() as u16).to_be_bytes());
+        message.extend_from_slice(&((end / 1000) as u32).to_be_bytes());
+        message.extend_from_slice(&socket.sequence.to_be_bytes());
+        // Observation domain ID
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&sets);
+
+        if let Err(ref e) = socket.socket.send(message.as_slice()) {
+            warn!("export IPFIX: {}", e);
+        }
+        socket.sequence = socket.sequence.wrapping_add(2);
+        socket.messages = socket.messages.wrapping_add(1);
+    }
+
+    fn on_bytes_sent(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, n, 0);
+    }
+
+    fn on_bytes_received(&self, kind: LayerKind, dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, 0, n);
+    }
+}
+
+fn ipfix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Returns the IPFIX flowEndReason of the close reason.
+fn ipfix_end_reason(reason: CloseReason) -> u8 {
+    match reason {
+        // Idle timeout
+        CloseReason::Idle => 0x01,
+        // End of flow detected
+        CloseReason::Reset
+        | CloseReason::SourceFin
+        | CloseReason::RemoteFin
+        | CloseReason::Aborted
+        | CloseReason::Unreachable => 0x03,
+        // Forced end
+        CloseReason::ConnectError
+        | CloseReason::SocksError
+        | CloseReason::ProxyError
+        | CloseReason::Migrated
+        | CloseReason::Filtered => 0x04,
+        // Lack of resources
+        CloseReason::Evicted => 0x05,
+    }
+}
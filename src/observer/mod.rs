@@ -0,0 +1,950 @@
+//! Support for observing events of flows.
+
+use log::warn;
+use lru::LruCache;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::dns::{Message, Question};
+use crate::packet::layer::{LayerKind, LayerKinds};
+#[cfg(feature = "quic")]
+use crate::quic::Initial;
+
+#[cfg(feature = "ipfix")]
+mod ipfix;
+#[cfg(feature = "ipfix")]
+pub use ipfix::IpfixExporter;
+
+/// Represents the reason why a flow was closed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CloseReason {
+    /// The source reset the TCP connection.
+    Reset,
+    /// The source closed the TCP connection first.
+    SourceFin,
+    /// The remote closed the TCP connection first.
+    RemoteFin,
+    /// The source sent data after the TCP connection was closed.
+    Aborted,
+    /// The proxy could not be connected.
+    ConnectError,
+    /// The SOCKS server replied an error, like a refused connection to the destination.
+    SocksError,
+    /// Data could not be sent to the proxy.
+    ProxyError,
+    /// The source replied an ICMP destination port unreachable.
+    Unreachable,
+    /// The local port bound for the source was reused by another source.
+    Evicted,
+    /// The source moved its QUIC connections to another port.
+    Migrated,
+    /// The source has been idle for a while.
+    Idle,
+    /// The flow was dropped by re-evaluating the filter.
+    Filtered,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            CloseReason::Reset => "reset",
+            CloseReason::SourceFin => "source_fin",
+            CloseReason::RemoteFin => "remote_fin",
+            CloseReason::Aborted => "aborted",
+            CloseReason::ConnectError => "connect_error",
+            CloseReason::SocksError => "socks_error",
+            CloseReason::ProxyError => "proxy_error",
+            CloseReason::Unreachable => "unreachable",
+            CloseReason::Evicted => "evicted",
+            CloseReason::Migrated => "migrated",
+            CloseReason::Idle => "idle",
+            CloseReason::Filtered => "filtered",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Represents the route which answers a DNS query.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DnsRoute {
+    /// The static hostname mappings answered the query.
+    Hosts,
+    /// The DNS cache answered the query.
+    Cache,
+    /// The DNS server answered the query through the proxy.
+    Proxy,
+}
+
+impl Display for DnsRoute {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            DnsRoute::Hosts => "hosts",
+            DnsRoute::Cache => "cache",
+            DnsRoute::Proxy => "proxy",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Represents an observer which receives events of flows.
+///
+/// A TCP flow lives from the handshake to the clean up of the connection. A UDP flow lives from
+/// the first datagram from the source to the destination to the release of the local port bound
+/// for the source. All the methods are called in the redirecting loop or the proxy workers, so
+/// implementations should return quickly.
+pub trait FlowObserver: Send + Sync {
+    /// Called when a flow from the source to the destination is created.
+    fn on_flow_created(&self, _kind: LayerKind, _src: SocketAddrV4, _dst: SocketAddrV4) {}
+
+    /// Called when a flow from the source to the destination is connected through the upstream,
+    /// which is the address of the proxy.
+    fn on_flow_connected(
+        &self,
+        _kind: LayerKind,
+        _src: SocketAddrV4,
+        _dst: SocketAddrV4,
+        _upstream: SocketAddr,
+    ) {
+    }
+
+    /// Called when a flow from the source to the destination is closed.
+    fn on_flow_closed(
+        &self,
+        _kind: LayerKind,
+        _src: SocketAddrV4,
+        _dst: SocketAddrV4,
+        _reason: CloseReason,
+    ) {
+    }
+
+    /// Called when bytes are relayed from the source to the destination through the proxy.
+    fn on_bytes_sent(&self, _kind: LayerKind, _src: SocketAddrV4, _dst: SocketAddrV4, _n: usize) {}
+
+    /// Called when bytes are relayed from the destination to the source through the proxy.
+    fn on_bytes_received(
+        &self,
+        _kind: LayerKind,
+        _dst: SocketAddrV4,
+        _src: SocketAddrV4,
+        _n: usize,
+    ) {
+    }
+
+    /// Called when a frame cannot be parsed or is malformed.
+    fn on_parse_error(&self, _frame: &[u8]) {}
+
+    /// Called when a DNS query is received from the source to the destination.
+    fn on_dns_query(&self, _src: SocketAddrV4, _dst: SocketAddrV4, _question: &Question) {}
+
+    /// Called when a DNS response is received from the destination to the source.
+    fn on_dns_response(&self, _dst: SocketAddrV4, _src: SocketAddrV4, _message: &Message) {}
+
+    /// Called when a QUIC initial packet is received from the source to the destination.
+    #[cfg(feature = "quic")]
+    fn on_quic_initial(&self, _src: SocketAddrV4, _dst: SocketAddrV4, _initial: &Initial) {}
+
+    /// Called when a DNS message in the payload is exchanged between the source and the
+    /// destination, which is a query from the source if the route is `None`, or a response to
+    /// the source answered by the route. Unlike `on_dns_response`, it is also called for the
+    /// responses from the hosts and the DNS cache.
+    fn on_dns_message(
+        &self,
+        _src: SocketAddrV4,
+        _dst: SocketAddrV4,
+        _payload: &[u8],
+        _route: Option<DnsRoute>,
+    ) {
+    }
+}
+
+#[derive(Debug)]
+struct AuditEntry {
+    instant: Instant,
+    sent: usize,
+    received: usize,
+}
+
+/// Represents an observer which appends a line in JSON to a file for each closed flow, with its
+/// duration in milliseconds, the bytes relayed and the reason why it was closed.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<BufWriter<File>>,
+    entries: Mutex<HashMap<(LayerKind, SocketAddrV4, SocketAddrV4), AuditEntry>>,
+}
+
+impl AuditLog {
+    /// Opens an `AuditLog` which appends to the file of the given path.
+    pub fn open(path: &str) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog {
+            file: Mutex::new(BufWriter::new(file)),
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn add(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        sent: usize,
+        received: usize,
+    ) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&(kind, src, dst)) {
+            entry.sent = entry.sent.checked_add(sent).unwrap_or(usize::MAX);
+            entry.received = entry.received.checked_add(received).unwrap_or(usize::MAX);
+        }
+    }
+}
+
+impl FlowObserver for AuditLog {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.entries.lock().unwrap().insert(
+            (kind, src, dst),
+            AuditEntry {
+                instant: Instant::now(),
+                sent: 0,
+                received: 0,
+            },
+        );
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        // Flows failed to connect are closed without being created
+        let (duration, sent, received) =
+            match self.entries.lock().unwrap().remove(&(kind, src, dst)) {
+                Some(entry) => (
+                    entry.instant.elapsed().as_millis(),
+                    entry.sent,
+                    entry.received,
+                ),
+                None => (0, 0, 0),
+            };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{{\"time\":{},\"protocol\":\"{}\",\"src\":\"{}\",\"dst\":\"{}\",\"duration\":{},\"sent\":{},\"received\":{},\"reason\":\"{}\"}}",
+            timestamp.as_millis(),
+            kind,
+            src,
+            dst,
+            duration,
+            sent,
+            received,
+            reason
+        );
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(ref e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            warn!("write audit log: {}", e);
+        }
+    }
+
+    fn on_bytes_sent(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, n, 0);
+    }
+
+    fn on_bytes_received(&self, kind: LayerKind, dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, 0, n);
+    }
+}
+
+/// Represents the max number of closed flows kept in the flow table.
+const MAX_CLOSED_FLOWS: usize = 256;
+
+/// Represents a flow in the flow table.
+#[derive(Clone, Debug)]
+pub struct FlowEntry {
+    kind: LayerKind,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    upstream: Option<SocketAddr>,
+    instant: Instant,
+    duration: Option<Duration>,
+    sent: usize,
+    received: usize,
+    reason: Option<CloseReason>,
+}
+
+impl FlowEntry {
+    fn new(kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) -> FlowEntry {
+        FlowEntry {
+            kind,
+            src,
+            dst,
+            upstream: None,
+            instant: Instant::now(),
+            duration: None,
+            sent: 0,
+            received: 0,
+            reason: None,
+        }
+    }
+
+    /// Returns the protocol of the flow.
+    pub fn kind(&self) -> LayerKind {
+        self.kind
+    }
+
+    /// Returns the source of the flow.
+    pub fn src(&self) -> SocketAddrV4 {
+        self.src
+    }
+
+    /// Returns the destination of the flow.
+    pub fn dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Returns the proxy which the flow is connected through.
+    pub fn upstream(&self) -> Option<SocketAddr> {
+        self.upstream
+    }
+
+    /// Returns the duration of the flow, which is still growing if the flow is active.
+    pub fn duration(&self) -> Duration {
+        self.duration.unwrap_or_else(|| self.instant.elapsed())
+    }
+
+    /// Returns the bytes relayed from the source.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the bytes relayed to the source.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Returns the reason why the flow was closed, or `None` if the flow is active.
+    pub fn reason(&self) -> Option<CloseReason> {
+        self.reason
+    }
+}
+
+/// Represents an observer which lists the active flows and the last closed flows with the reasons
+/// why they were closed.
+#[derive(Debug, Default)]
+pub struct FlowTable {
+    active: Mutex<HashMap<(LayerKind, SocketAddrV4, SocketAddrV4), FlowEntry>>,
+    closed: Mutex<VecDeque<FlowEntry>>,
+}
+
+impl FlowTable {
+    /// Creates a new `FlowTable`.
+    pub fn new() -> FlowTable {
+        FlowTable::default()
+    }
+
+    /// Returns the active flows from the oldest, followed by the closed flows from the latest.
+    pub fn flows(&self) -> Vec<FlowEntry> {
+        let mut flows = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        flows.sort_by_key(|flow| flow.instant);
+        flows.extend(self.closed.lock().unwrap().iter().rev().cloned());
+
+        flows
+    }
+
+    fn add(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        sent: usize,
+        received: usize,
+    ) {
+        if let Some(flow) = self.active.lock().unwrap().get_mut(&(kind, src, dst)) {
+            flow.sent = flow.sent.checked_add(sent).unwrap_or(usize::MAX);
+            flow.received = flow.received.checked_add(received).unwrap_or(usize::MAX);
+        }
+    }
+}
+
+impl FlowObserver for FlowTable {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.active
+            .lock()
+            .unwrap()
+            .insert((kind, src, dst), FlowEntry::new(kind, src, dst));
+    }
+
+    fn on_flow_connected(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        upstream: SocketAddr,
+    ) {
+        if let Some(flow) = self.active.lock().unwrap().get_mut(&(kind, src, dst)) {
+            flow.upstream = Some(upstream);
+        }
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        // Flows failed to connect are closed without being created
+        let mut flow = self
+            .active
+            .lock()
+            .unwrap()
+            .remove(&(kind, src, dst))
+            .unwrap_or_else(|| FlowEntry::new(kind, src, dst));
+        flow.duration = Some(flow.instant.elapsed());
+        flow.reason = Some(reason);
+
+        let mut closed = self.closed.lock().unwrap();
+        if closed.len() >= MAX_CLOSED_FLOWS {
+            closed.pop_front();
+        }
+        closed.push_back(flow);
+    }
+
+    fn on_bytes_sent(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, n, 0);
+    }
+
+    fn on_bytes_received(&self, kind: LayerKind, dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        self.add(kind, src, dst, 0, n);
+    }
+}
+
+/// Represents an observer which dispatches events to all of its observers in order.
+#[derive(Default)]
+pub struct ObserverGroup {
+    observers: Vec<Arc<dyn FlowObserver>>,
+}
+
+impl ObserverGroup {
+    /// Creates a new `ObserverGroup`.
+    pub fn new() -> ObserverGroup {
+        ObserverGroup::default()
+    }
+
+    /// Appends an observer.
+    pub fn push(&mut self, observer: Arc<dyn FlowObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Returns the number of observers.
+    pub fn len(&self) -> usize {
+        self.observers.len()
+    }
+
+    /// Returns if the group contains no observer.
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+}
+
+impl FlowObserver for ObserverGroup {
+    fn on_flow_created(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4) {
+        for observer in self.observers.iter() {
+            observer.on_flow_created(kind, src, dst);
+        }
+    }
+
+    fn on_flow_connected(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        upstream: SocketAddr,
+    ) {
+        for observer in self.observers.iter() {
+            observer.on_flow_connected(kind, src, dst, upstream);
+        }
+    }
+
+    fn on_flow_closed(
+        &self,
+        kind: LayerKind,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    ) {
+        for observer in self.observers.iter() {
+            observer.on_flow_closed(kind, src, dst, reason);
+        }
+    }
+
+    fn on_bytes_sent(&self, kind: LayerKind, src: SocketAddrV4, dst: SocketAddrV4, n: usize) {
+        for observer in self.observers.iter() {
+            observer.on_bytes_sent(kind, src, dst, n);
+        }
+    }
+
+    fn on_bytes_received(&self, kind: LayerKind, dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        for observer in self.observers.iter() {
+            observer.on_bytes_received(kind, dst, src, n);
+        }
+    }
+
+    fn on_parse_error(&self, frame: &[u8]) {
+        for observer in self.observers.iter() {
+            observer.on_parse_error(frame);
+        }
+    }
+
+    fn on_dns_query(&self, src: SocketAddrV4, dst: SocketAddrV4, question: &Question) {
+        for observer in self.observers.iter() {
+            observer.on_dns_query(src, dst, question);
+        }
+    }
+
+    fn on_dns_response(&self, dst: SocketAddrV4, src: SocketAddrV4, message: &Message) {
+        for observer in self.observers.iter() {
+            observer.on_dns_response(dst, src, message);
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    fn on_quic_initial(&self, src: SocketAddrV4, dst: SocketAddrV4, initial: &Initial) {
+        for observer in self.observers.iter() {
+            observer.on_quic_initial(src, dst, initial);
+        }
+    }
+
+    fn on_dns_message(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+        route: Option<DnsRoute>,
+    ) {
+        for observer in self.observers.iter() {
+            observer.on_dns_message(src, dst, payload, route);
+        }
+    }
+}
+
+/// Represents the max number of DNS queries waiting for their responses in a DNS log.
+const MAX_DNS_QUERIES: usize = 1024;
+/// Represents the content type of dnstap in Frame Streams.
+const DNSTAP_CONTENT_TYPE: &str = "protobuf:dnstap.Dnstap";
+/// Represents the type of dnstap messages of queries from clients.
+const DNSTAP_CLIENT_QUERY: u64 = 5;
+/// Represents the type of dnstap messages of responses to clients.
+const DNSTAP_CLIENT_RESPONSE: u64 = 6;
+/// Represents the type of control frames starting a Frame Stream.
+const FSTRM_CONTROL_START: u32 = 2;
+/// Represents the type of control frames stopping a Frame Stream.
+const FSTRM_CONTROL_STOP: u32 = 3;
+
+/// Represents the format of a DNS log.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DnsLogFormat {
+    /// Lines of JSON, one for each response.
+    Json,
+    /// dnstap in a Frame Streams file, one message for each query and each response.
+    Dnstap,
+}
+
+impl Display for DnsLogFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DnsLogFormat::Json => write!(f, "json"),
+            DnsLogFormat::Dnstap => write!(f, "dnstap"),
+        }
+    }
+}
+
+impl FromStr for DnsLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(DnsLogFormat::Json),
+            "dnstap" => Ok(DnsLogFormat::Dnstap),
+            _ => Err(format!("invalid format {}, please use json or dnstap", s)),
+        }
+    }
+}
+
+/// Represents an observer which logs DNS queries of sources and their responses, with the
+/// answers and the routes answering them, in lines of JSON or in dnstap.
+#[derive(Debug)]
+pub struct DnsLog {
+    file: Mutex<BufWriter<File>>,
+    format: DnsLogFormat,
+    queries: Mutex<LruCache<(SocketAddrV4, SocketAddrV4, u16), SystemTime>>,
+    is_privacy: bool,
+}
+
+impl DnsLog {
+    /// Opens a `DnsLog` which writes to the file of the given path. A log in JSON is appended,
+    /// and a log in dnstap is truncated, since a Frame Streams file holds only one stream.
+    pub fn open(path: &str, format: DnsLogFormat) -> io::Result<DnsLog> {
+        let file = match format {
+            DnsLogFormat::Json => OpenOptions::new().create(true).append(true).open(path)?,
+            DnsLogFormat::Dnstap => File::create(path)?,
+        };
+        let mut file = BufWriter::new(file);
+        if format == DnsLogFormat::Dnstap {
+            file.write_all(&fstrm_control(
+                FSTRM_CONTROL_START,
+                Some(DNSTAP_CONTENT_TYPE),
+            ))?;
+            file.flush()?;
+        }
+
+        Ok(DnsLog {
+            file: Mutex::new(file),
+            format,
+            queries: Mutex::new(LruCache::new(MAX_DNS_QUERIES)),
+            is_privacy: false,
+        })
+    }
+
+    /// Sets if the log is in the privacy mode. The names and the answers are not written in JSON,
+    /// and the DNS messages are not written in dnstap in the privacy mode.
+    pub fn set_privacy(&mut self, is_privacy: bool) {
+        self.is_privacy = is_privacy;
+    }
+
+    fn write(&self, data: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(ref e) = file.write_all(data).and_then(|_| file.flush()) {
+            warn!("write DNS log: {}", e);
+        }
+    }
+}
+
+impl FlowObserver for DnsLog {
+    fn on_dns_message(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+        route: Option<DnsRoute>,
+    ) {
+        let message = match Message::parse(payload) {
+            Some(message) => message,
+            None => return,
+        };
+        let now = SystemTime::now();
+
+        match route {
+            None => {
+                if message.is_response() {
+                    return;
+                }
+                self.queries
+                    .lock()
+                    .unwrap()
+                    .put((src, dst, message.id()), now);
+                if self.format == DnsLogFormat::Dnstap {
+                    self.write(&dnstap_frame(
+                        DNSTAP_CLIENT_QUERY,
+                        src,
+                        dst,
+                        Some(now),
+                        None,
+                        Some(payload).filter(|_| !self.is_privacy),
+                    ));
+                }
+            }
+            Some(route) => {
+                let query_time = self.queries.lock().unwrap().pop(&(src, dst, message.id()));
+                match self.format {
+                    DnsLogFormat::Json => {
+                        let latency = match query_time
+                            .and_then(|query_time| now.duration_since(query_time).ok())
+                        {
+                            Some(latency) => latency.as_millis().to_string(),
+                            None => String::from("null"),
+                        };
+                        let fields = match self.is_privacy {
+                            true => dns_fields_redacted(src, dst, &message, route),
+                            false => dns_fields(src, dst, &message, route),
+                        };
+                        let line = format!(
+                            "{{\"time\":{},{},\"latency\":{}}}\n",
+                            now.duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_millis(),
+                            fields,
+                            latency
+                        );
+                        self.write(line.as_bytes());
+                    }
+                    DnsLogFormat::Dnstap => self.write(&dnstap_frame(
+                        DNSTAP_CLIENT_RESPONSE,
+                        src,
+                        dst,
+                        query_time,
+                        Some(now),
+                        Some(payload).filter(|_| !self.is_privacy),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DnsLog {
+    fn drop(&mut self) {
+        if self.format == DnsLogFormat::Dnstap {
+            self.write(&fstrm_control(FSTRM_CONTROL_STOP, None));
+        }
+    }
+}
+
+/// Returns the fields in JSON of a DNS response from the destination to the source, including
+/// the client, the server, the question, the answers and the route, without braces.
+pub(crate) fn dns_fields(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    message: &Message,
+    route: DnsRoute,
+) -> String {
+    let (name, qtype) = match message.question() {
+        Some(question) => (
+            question.name().replace('\\', "\\\\").replace('"', "\\\""),
+            question.qtype(),
+        ),
+        None => (String::new(), 0),
+    };
+    let answers = message
+        .addrs()
+        .iter()
+        .map(|addr| format!("\"{}\"", addr))
+        .collect::<Vec<_>>();
+
+    format!(
+        "\"client\":\"{}\",\"server\":\"{}\",\"name\":\"{}\",\"qtype\":{},\"rcode\":{},\"answers\":[{}],\"route\":\"{}\"",
+        src,
+        dst,
+        name,
+        qtype,
+        message.rcode(),
+        answers.join(","),
+        route
+    )
+}
+
+/// Returns the fields in JSON of a DNS response like `dns_fields`, without the name and the
+/// answers.
+fn dns_fields_redacted(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    message: &Message,
+    route: DnsRoute,
+) -> String {
+    let qtype = message.question().map_or(0, |question| question.qtype());
+
+    format!(
+        "\"client\":\"{}\",\"server\":\"{}\",\"qtype\":{},\"rcode\":{},\"route\":\"{}\"",
+        src,
+        dst,
+        qtype,
+        message.rcode(),
+        route
+    )
+}
+
+/// Returns a Frame Streams control frame of the type with the optional content type.
+fn fstrm_control(t: u32, content_type: Option<&str>) -> Vec<u8> {
+    let mut frame = t.to_be_bytes().to_vec();
+    if let Some(content_type) = content_type {
+        // Content type field
+        frame.extend_from_slice(&1u32.to_be_bytes());
+        frame.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+        frame.extend_from_slice(content_type.as_bytes());
+    }
+
+    // The escape sequence of control frames followed by the length
+    let mut buffer = 0u32.to_be_bytes().to_vec();
+    buffer.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&frame);
+
+    buffer
+}
+
+/// Returns a Frame Streams data frame of a dnstap message between the client and the server of
+/// the type, where the payload is the query or the response by the type, and is omitted if it is
+/// `None`.
+fn dnstap_frame(
+    t: u64,
+    client: SocketAddrV4,
+    server: SocketAddrV4,
+    query_time: Option<SystemTime>,
+    response_time: Option<SystemTime>,
+    payload: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut message = Vec::new();
+    put_uint(&mut message, 1, t);
+    // INET
+    put_uint(&mut message, 2, 1);
+    // UDP
+    put_uint(&mut message, 3, 1);
+    put_bytes(&mut message, 4, &client.ip().octets());
+    put_bytes(&mut message, 5, &server.ip().octets());
+    put_uint(&mut message, 6, client.port() as u64);
+    put_uint(&mut message, 7, server.port() as u64);
+    if let Some(time) = query_time {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_uint(&mut message, 8, time.as_secs());
+        put_fixed32(&mut message, 9, time.subsec_nanos());
+    }
+    match (t, payload) {
+        (_, None) => {}
+        (DNSTAP_CLIENT_QUERY, Some(payload)) => put_bytes(&mut message, 10, payload),
+        (_, Some(payload)) => put_bytes(&mut message, 14, payload),
+    }
+    if let Some(time) = response_time {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_uint(&mut message, 12, time.as_secs());
+        put_fixed32(&mut message, 13, time.subsec_nanos());
+    }
+
+    let mut dnstap = Vec::new();
+    put_bytes(&mut dnstap, 1, b"pcap2socks");
+    put_bytes(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+    put_bytes(&mut dnstap, 14, &message);
+    // MESSAGE
+    put_uint(&mut dnstap, 15, 1);
+
+    let mut buffer = (dnstap.len() as u32).to_be_bytes().to_vec();
+    buffer.extend_from_slice(&dnstap);
+
+    buffer
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn put_uint(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buffer, field << 3);
+    put_varint(buffer, value);
+}
+
+fn put_fixed32(buffer: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buffer, field << 3 | 5);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buffer, field << 3 | 2);
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+#[test]
+fn dnstap_frame_encode() {
+    let mut buffer = Vec::new();
+    put_varint(&mut buffer, 300);
+    assert_eq!(buffer, [0xac, 0x02]);
+
+    let frame = fstrm_control(FSTRM_CONTROL_START, Some(DNSTAP_CONTENT_TYPE));
+    assert_eq!(&frame[..12], &[0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 2]);
+
+    let frame = dnstap_frame(
+        DNSTAP_CLIENT_QUERY,
+        "10.6.0.2:50000".parse().unwrap(),
+        "1.1.1.1:53".parse().unwrap(),
+        None,
+        None,
+        Some(&[0xff; 3]),
+    );
+    assert_eq!(&frame[..4], &((frame.len() - 4) as u32).to_be_bytes());
+    // The query message
+    assert!(frame.windows(5).any(|w| w == [0x52, 3, 0xff, 0xff, 0xff]));
+    // The type of MESSAGE
+    assert_eq!(&frame[frame.len() - 2..], &[0x78, 1]);
+
+    // The message is omitted in the privacy mode
+    let redacted = dnstap_frame(
+        DNSTAP_CLIENT_QUERY,
+        "10.6.0.2:50000".parse().unwrap(),
+        "1.1.1.1:53".parse().unwrap(),
+        None,
+        None,
+        None,
+    );
+    assert_eq!(redacted.len(), frame.len() - 5);
+    assert!(!redacted.windows(2).any(|w| w == [0x52, 3]));
+}
+
+#[test]
+fn dns_fields_redact() {
+    let response = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0,
+        0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+    let message = Message::parse(&response).unwrap();
+    let src = "10.6.0.2:50000".parse().unwrap();
+    let dst = "1.1.1.1:53".parse().unwrap();
+
+    let fields = dns_fields(src, dst, &message, DnsRoute::Proxy);
+    assert!(fields.contains("example.com") && fields.contains("93.184.216.34"));
+    let fields = dns_fields_redacted(src, dst, &message, DnsRoute::Proxy);
+    assert!(!fields.contains("example") && !fields.contains("93.184.216.34"));
+    assert!(fields.contains("\"client\":\"10.6.0.2:50000\"") && fields.contains("\"qtype\":1"));
+}
+
+#[test]
+fn flow_table_close() {
+    let table = FlowTable::new();
+    let src: SocketAddrV4 = "10.6.0.1:50000".parse().unwrap();
+    let dst: SocketAddrV4 = "1.1.1.1:443".parse().unwrap();
+    let upstream: SocketAddr = "192.168.1.2:1080".parse().unwrap();
+
+    table.on_flow_created(LayerKinds::Tcp, src, dst);
+    table.on_flow_connected(LayerKinds::Tcp, src, dst, upstream);
+    table.on_bytes_sent(LayerKinds::Tcp, src, dst, 100);
+    table.on_bytes_received(LayerKinds::Tcp, dst, src, 200);
+    let flows = table.flows();
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].upstream(), Some(upstream));
+    assert_eq!((flows[0].sent(), flows[0].received()), (100, 200));
+    assert_eq!(flows[0].reason(), None);
+
+    // A flow failed to connect is listed without being created
+    let dst_refused: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    table.on_flow_closed(LayerKinds::Tcp, src, dst, CloseReason::RemoteFin);
+    table.on_flow_closed(LayerKinds::Tcp, src, dst_refused, CloseReason::SocksError);
+    let flows = table.flows();
+    assert_eq!(flows.len(), 2);
+    assert_eq!(flows[0].dst(), dst_refused);
+    assert_eq!(flows[0].reason(), Some(CloseReason::SocksError));
+    assert_eq!(flows[1].reason(), Some(CloseReason::RemoteFin));
+    assert_eq!(flows[1].sent(), 100);
+
+    for _ in 0..MAX_CLOSED_FLOWS {
+        table.on_flow_closed(LayerKinds::Udp, src, dst, CloseReason::Idle);
+    }
+    assert_eq!(table.flows().len(), MAX_CLOSED_FLOWS);
+}
@@ -0,0 +1,95 @@
+//! Support for looking up vendors of hardware addresses by their OUIs.
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the OUIs of a few vendors of game consoles, handhelds and single-board computers,
+/// which are the most common devices to be proxied. The table is compact on purpose and is not
+/// meant to be a full copy of the IEEE registry.
+#[cfg(feature = "oui")]
+const VENDORS: [([u8; 3], &str); 48] = [
+    ([0x00, 0x04, 0x1f], "Sony Interactive"),
+    ([0x00, 0x13, 0x15], "Sony Interactive"),
+    ([0x00, 0x15, 0xc1], "Sony Interactive"),
+    ([0x00, 0x19, 0xc5], "Sony Interactive"),
+    ([0x00, 0x1d, 0x0d], "Sony Interactive"),
+    ([0x00, 0x24, 0x8d], "Sony Interactive"),
+    ([0x00, 0xd9, 0xd1], "Sony Interactive"),
+    ([0x0c, 0xfe, 0x45], "Sony Interactive"),
+    ([0x28, 0x0d, 0xfc], "Sony Interactive"),
+    ([0x2c, 0xcc, 0x44], "Sony Interactive"),
+    ([0x70, 0x9e, 0x29], "Sony Interactive"),
+    ([0x78, 0xc8, 0x81], "Sony Interactive"),
+    ([0xa8, 0xe3, 0xee], "Sony Interactive"),
+    ([0xbc, 0x60, 0xa7], "Sony Interactive"),
+    ([0xc8, 0x63, 0xf1], "Sony Interactive"),
+    ([0xf8, 0x46, 0x1c], "Sony Interactive"),
+    ([0xfc, 0x0f, 0xe6], "Sony Interactive"),
+    ([0x00, 0x09, 0xbf], "Nintendo"),
+    ([0x00, 0x17, 0xab], "Nintendo"),
+    ([0x00, 0x19, 0x1d], "Nintendo"),
+    ([0x00, 0x1a, 0xe9], "Nintendo"),
+    ([0x00, 0x1b, 0xea], "Nintendo"),
+    ([0x00, 0x1e, 0x35], "Nintendo"),
+    ([0x00, 0x1f, 0x32], "Nintendo"),
+    ([0x04, 0x03, 0xd6], "Nintendo"),
+    ([0x40, 0xf4, 0x07], "Nintendo"),
+    ([0x58, 0xbd, 0xa3], "Nintendo"),
+    ([0x7c, 0xbb, 0x8a], "Nintendo"),
+    ([0x8c, 0xcd, 0xe8], "Nintendo"),
+    ([0x98, 0xb6, 0xe9], "Nintendo"),
+    ([0xdc, 0x68, 0xeb], "Nintendo"),
+    ([0xe0, 0xe7, 0x51], "Nintendo"),
+    ([0x00, 0x0d, 0x3a], "Microsoft"),
+    ([0x00, 0x12, 0x5a], "Microsoft"),
+    ([0x00, 0x15, 0x5d], "Microsoft"),
+    ([0x00, 0x1d, 0xd8], "Microsoft"),
+    ([0x00, 0x22, 0x48], "Microsoft"),
+    ([0x00, 0x25, 0xae], "Microsoft"),
+    ([0x00, 0x50, 0xf2], "Microsoft"),
+    ([0x28, 0x18, 0x78], "Microsoft"),
+    ([0x60, 0x45, 0xbd], "Microsoft"),
+    ([0x7c, 0x1e, 0x52], "Microsoft"),
+    ([0x7c, 0xed, 0x8d], "Microsoft"),
+    ([0x98, 0x5f, 0xd3], "Microsoft"),
+    ([0xc8, 0x3f, 0x26], "Microsoft"),
+    ([0xb8, 0x27, 0xeb], "Raspberry Pi"),
+    ([0xdc, 0xa6, 0x32], "Raspberry Pi"),
+    ([0xe4, 0x5f, 0x01], "Raspberry Pi"),
+];
+
+/// Returns the vendor of the hardware address by its OUI, or `None` if the vendor is not known.
+/// Locally administered addresses, which are randomized by many devices, never match.
+#[cfg(feature = "oui")]
+pub fn vendor(hardware_addr: HardwareAddr) -> Option<&'static str> {
+    if hardware_addr.0 & 0x02 != 0 {
+        return None;
+    }
+
+    let oui = [hardware_addr.0, hardware_addr.1, hardware_addr.2];
+    VENDORS
+        .iter()
+        .find(|(prefix, _)| *prefix == oui)
+        .map(|(_, vendor)| *vendor)
+}
+
+/// Returns the vendor of the hardware address by its OUI. Always returns `None` as the OUI table
+/// is not built in without the `oui` feature.
+#[cfg(not(feature = "oui"))]
+pub fn vendor(_hardware_addr: HardwareAddr) -> Option<&'static str> {
+    None
+}
+
+#[cfg(feature = "oui")]
+#[test]
+fn oui_vendor() {
+    assert_eq!(
+        vendor(HardwareAddr::new(0x00, 0xd9, 0xd1, 1, 2, 3)),
+        Some("Sony Interactive")
+    );
+    assert_eq!(
+        vendor(HardwareAddr::new(0x98, 0xb6, 0xe9, 1, 2, 3)),
+        Some("Nintendo")
+    );
+    assert_eq!(vendor(HardwareAddr::new(0x02, 0xd9, 0xd1, 1, 2, 3)), None);
+    assert_eq!(vendor(HardwareAddr::new(0x10, 0x00, 0x00, 1, 2, 3)), None);
+}
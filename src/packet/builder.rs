@@ -0,0 +1,381 @@
+//! Support for building packets layer by layer.
+
+use pnet::packet::tcp::{self, TcpFlags, TcpOption};
+use pnet::util::MacAddr;
+use std::cmp::min;
+use std::net::Ipv4Addr;
+
+use super::layer::arp::Arp;
+use super::layer::ethernet::Ethernet;
+use super::layer::ipv4::Ipv4;
+use super::layer::tcp::Tcp;
+use super::layer::{Layer, LayerKind, Layers};
+use super::Indicator;
+
+/// Represents the max size of TCP options.
+const MAX_TCP_OPTIONS_SIZE: usize = 40;
+
+#[derive(Clone, Debug)]
+enum Network {
+    Layer(Layers),
+    Ipv4(Ipv4Builder),
+}
+
+/// Represents a builder of Ethernet frames. The EtherType, the IPv4 protocol and the addresses of
+/// the TCP or UDP pseudo header are filled according to the composed layers, and lengths and
+/// checksums are computed in serializing the built `Indicator`.
+#[derive(Clone, Debug)]
+pub struct EthernetBuilder {
+    src: MacAddr,
+    dst: MacAddr,
+    network: Option<Network>,
+    transport: Option<Layers>,
+}
+
+impl EthernetBuilder {
+    /// Creates a new `EthernetBuilder`.
+    pub fn new(src: MacAddr, dst: MacAddr) -> EthernetBuilder {
+        EthernetBuilder {
+            src,
+            dst,
+            network: None,
+            transport: None,
+        }
+    }
+
+    /// Sets the ARP layer.
+    pub fn arp(self, arp: Arp) -> EthernetBuilder {
+        self.network(Layers::Arp(arp))
+    }
+
+    /// Sets the IPv4 layer.
+    pub fn ipv4(mut self, ipv4: Ipv4Builder) -> EthernetBuilder {
+        self.network = Some(Network::Ipv4(ipv4));
+        self
+    }
+
+    /// Sets the network layer which has been built.
+    pub fn network(mut self, network: Layers) -> EthernetBuilder {
+        self.network = Some(Network::Layer(network));
+        self
+    }
+
+    /// Sets the TCP layer.
+    pub fn tcp(self, tcp: TcpBuilder) -> EthernetBuilder {
+        self.transport(Layers::Tcp(tcp.build()))
+    }
+
+    /// Sets the transport layer which has been built.
+    pub fn transport(mut self, transport: Layers) -> EthernetBuilder {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Builds an `Indicator`. Returns `None` if the network layer is not set, or the layers
+    /// cannot be composed.
+    pub fn build(self) -> Option<Indicator> {
+        let network = match self.network? {
+            Network::Layer(network) => network,
+            Network::Ipv4(ipv4) => Layers::Ipv4(ipv4.build(self.transport.as_ref()?.kind())?),
+        };
+        let ethernet = Ethernet::new(network.kind(), self.src, self.dst)?;
+
+        // Set IPv4 layer for checksum
+        let mut transport = self.transport;
+        if let Layers::Ipv4(ref ipv4) = network {
+            match transport {
+                Some(Layers::Tcp(ref mut tcp)) => tcp.set_ipv4_layer(ipv4),
+                Some(Layers::Udp(ref mut udp)) => udp.set_ipv4_layer(ipv4),
+                _ => {}
+            }
+        }
+
+        Some(Indicator::new(
+            Layers::Ethernet(ethernet),
+            Some(network),
+            transport,
+        ))
+    }
+}
+
+/// Represents a builder of IPv4 layers.
+#[derive(Clone, Debug)]
+pub struct Ipv4Builder {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    identification: u16,
+    fragment: Option<(u16, bool)>,
+}
+
+impl Ipv4Builder {
+    /// Creates a new `Ipv4Builder`.
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr) -> Ipv4Builder {
+        Ipv4Builder {
+            src,
+            dst,
+            identification: 0,
+            fragment: None,
+        }
+    }
+
+    /// Sets the identification.
+    pub fn identification(mut self, identification: u16) -> Ipv4Builder {
+        self.identification = identification;
+        self
+    }
+
+    /// Sets the fragment offset in 8 Bytes, and if there are more fragments.
+    pub fn fragment(mut self, fragment_offset: u16, is_more: bool) -> Ipv4Builder {
+        self.fragment = Some((fragment_offset, is_more));
+        self
+    }
+
+    /// Builds an `Ipv4` carrying the given layer. Returns `None` if the layer cannot be carried.
+    pub fn build(self, t: LayerKind) -> Option<Ipv4> {
+        match self.fragment {
+            Some((fragment_offset, true)) => {
+                Ipv4::new_more_fragment(self.identification, t, fragment_offset, self.src, self.dst)
+            }
+            Some((fragment_offset, false)) => {
+                Ipv4::new_last_fragment(self.identification, t, fragment_offset, self.src, self.dst)
+            }
+            None => Ipv4::new(self.identification, t, self.src, self.dst),
+        }
+    }
+}
+
+/// Represents a builder of TCP layers. Options are padded and SACK blocks are trimmed to fit in
+/// the TCP header.
+#[derive(Clone, Debug)]
+pub struct TcpBuilder {
+    src: u16,
+    dst: u16,
+    sequence: u32,
+    acknowledgement: Option<u32>,
+    window: u16,
+    flags: u16,
+    mss: Option<u16>,
+    wscale: Option<u8>,
+    sack_perm: bool,
+    sacks: Vec<(u32, u32)>,
+    ts: Option<(u32, u32)>,
+}
+
+impl TcpBuilder {
+    /// Creates a new `TcpBuilder`.
+    pub fn new(src: u16, dst: u16) -> TcpBuilder {
+        TcpBuilder {
+            src,
+            dst,
+            sequence: 0,
+            acknowledgement: None,
+            window: 0,
+            flags: 0,
+            mss: None,
+            wscale: None,
+            sack_perm: false,
+            sacks: Vec::new(),
+            ts: None,
+        }
+    }
+
+    /// Sets the sequence.
+    pub fn sequence(mut self, sequence: u32) -> TcpBuilder {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Sets the ACK flag and the acknowledgement.
+    pub fn ack(mut self, acknowledgement: u32) -> TcpBuilder {
+        self.acknowledgement = Some(acknowledgement);
+        self
+    }
+
+    /// Sets the window.
+    pub fn window(mut self, window: u16) -> TcpBuilder {
+        self.window = window;
+        self
+    }
+
+    /// Sets the SYN flag.
+    pub fn syn(mut self) -> TcpBuilder {
+        self.flags |= TcpFlags::SYN;
+        self
+    }
+
+    /// Sets the FIN flag.
+    pub fn fin(mut self) -> TcpBuilder {
+        self.flags |= TcpFlags::FIN;
+        self
+    }
+
+    /// Sets the RST flag.
+    pub fn rst(mut self) -> TcpBuilder {
+        self.flags |= TcpFlags::RST;
+        self
+    }
+
+    /// Sets the MSS option.
+    pub fn mss(mut self, mss: u16) -> TcpBuilder {
+        self.mss = Some(mss);
+        self
+    }
+
+    /// Sets the window scale option.
+    pub fn wscale(mut self, wscale: u8) -> TcpBuilder {
+        self.wscale = Some(wscale);
+        self
+    }
+
+    /// Sets the SACK permitted option.
+    pub fn sack_perm(mut self) -> TcpBuilder {
+        self.sack_perm = true;
+        self
+    }
+
+    /// Sets the SACK blocks.
+    pub fn sacks(mut self, sacks: Vec<(u32, u32)>) -> TcpBuilder {
+        self.sacks = sacks;
+        self
+    }
+
+    /// Sets the timestamps option with the timestamp value and the timestamp echo reply.
+    pub fn ts(mut self, ts: u32, ts_ecr: u32) -> TcpBuilder {
+        self.ts = Some((ts, ts_ecr));
+        self
+    }
+
+    /// Builds a `Tcp`.
+    pub fn build(self) -> Tcp {
+        let mut options = Vec::new();
+        let mut size = 0;
+
+        // TCP options
+        if let Some(mss) = self.mss {
+            options.push(TcpOption::mss(mss));
+            size += 4;
+        }
+        if let Some(wscale) = self.wscale {
+            options.push(TcpOption::nop());
+            options.push(TcpOption::wscale(wscale));
+            size += 4;
+        }
+        match (self.sack_perm, self.ts) {
+            (true, Some(ts)) => {
+                options.push(TcpOption::sack_perm());
+                options.push(TcpOption::timestamp(ts.0, ts.1));
+                size += 12;
+            }
+            (true, None) => {
+                options.push(TcpOption::nop());
+                options.push(TcpOption::nop());
+                options.push(TcpOption::sack_perm());
+                size += 4;
+            }
+            (false, Some(ts)) => {
+                options.push(TcpOption::nop());
+                options.push(TcpOption::nop());
+                options.push(TcpOption::timestamp(ts.0, ts.1));
+                size += 12;
+            }
+            (false, None) => {}
+        }
+        // Trim sacks
+        let n = min(
+            self.sacks.len(),
+            MAX_TCP_OPTIONS_SIZE.saturating_sub(size + 4) / 8,
+        );
+        if n > 0 {
+            let mut vector = Vec::with_capacity(n * 2);
+            for sack in &self.sacks[..n] {
+                vector.push(sack.0);
+                vector.push(sack.1);
+            }
+
+            options.push(TcpOption::nop());
+            options.push(TcpOption::nop());
+            options.push(TcpOption::selective_ack(vector.as_slice()));
+            size += 4 + n * 8;
+        }
+
+        let mut flags = self.flags;
+        if self.acknowledgement.is_some() {
+            flags |= TcpFlags::ACK;
+        }
+        let d_tcp = tcp::Tcp {
+            source: self.src,
+            destination: self.dst,
+            sequence: self.sequence,
+            acknowledgement: self.acknowledgement.unwrap_or(0),
+            data_offset: 5 + (size / 4) as u8,
+            reserved: 0,
+            flags,
+            window: self.window,
+            checksum: 0,
+            urgent_ptr: 0,
+            options,
+            payload: vec![],
+        };
+
+        Tcp::from(d_tcp)
+    }
+}
+
+#[test]
+fn ethernet_builder_build() {
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::tcp::TcpPacket;
+    use pnet::packet::Packet;
+
+    let src: Ipv4Addr = "10.6.0.254".parse().unwrap();
+    let dst: Ipv4Addr = "10.6.0.1".parse().unwrap();
+    let indicator = EthernetBuilder::new(
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .ipv4(Ipv4Builder::new(src, dst).identification(1))
+    .tcp(
+        TcpBuilder::new(80, 50000)
+            .sequence(100)
+            .ack(200)
+            .window(65535)
+            .syn()
+            .mss(1460)
+            .wscale(7)
+            .sack_perm()
+            .ts(1, 2),
+    )
+    .build()
+    .unwrap();
+
+    let payload = [0u8; 8];
+    let mut buffer = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(buffer.as_mut_slice(), &payload)
+        .unwrap();
+
+    let parsed = Indicator::from(buffer.as_slice()).unwrap();
+    let tcp = parsed.tcp().unwrap();
+    assert_eq!(tcp.sequence(), 100);
+    assert_eq!(tcp.acknowledgement(), 200);
+    assert!(tcp.is_syn() && tcp.is_ack());
+    assert_eq!(tcp.mss(), Some(1460));
+    assert_eq!(tcp.wscale(), Some(7));
+    assert!(tcp.is_sack_perm());
+    assert_eq!(tcp.ts(), Some(1));
+
+    // Checksums
+    let ethernet_packet = EthernetPacket::new(buffer.as_slice()).unwrap();
+    let ipv4_packet = Ipv4Packet::new(ethernet_packet.payload()).unwrap();
+    assert_eq!(ipv4_packet.get_checksum(), ipv4::checksum(&ipv4_packet));
+    assert_eq!(
+        ipv4_packet.get_total_length() as usize,
+        ethernet_packet.payload().len()
+    );
+    let tcp_packet = TcpPacket::new(ipv4_packet.payload()).unwrap();
+    assert_eq!(
+        tcp_packet.get_checksum(),
+        tcp::ipv4_checksum(&tcp_packet, &src, &dst)
+    );
+}
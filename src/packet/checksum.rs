@@ -0,0 +1,64 @@
+//! Support for updating Internet checksums incrementally (RFC 1624).
+
+use std::net::Ipv4Addr;
+
+/// Returns the checksum updated for a 16-bit word of the checksummed data changed from `old` to
+/// `new`, which is `HC' = ~(~HC + ~m + m')` as in RFC 1624 equation 3.
+pub fn update_u16(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Returns the checksum updated for an IPv4 address of the checksummed data changed from `old` to
+/// `new`, like the one in an IPv4 header or a pseudo header.
+pub fn update_ipv4_addr(checksum: u16, old: Ipv4Addr, new: Ipv4Addr) -> u16 {
+    let old = old.octets();
+    let new = new.octets();
+
+    let checksum = update_u16(
+        checksum,
+        u16::from_be_bytes([old[0], old[1]]),
+        u16::from_be_bytes([new[0], new[1]]),
+    );
+    update_u16(
+        checksum,
+        u16::from_be_bytes([old[2], old[3]]),
+        u16::from_be_bytes([new[2], new[3]]),
+    )
+}
+
+#[test]
+fn checksum_update_ipv4_addr() {
+    use pnet::packet::ipv4::{self, MutableIpv4Packet};
+
+    let mut buffer = [0u8; 20];
+    let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+    packet.set_version(4);
+    packet.set_header_length(5);
+    packet.set_total_length(20);
+    packet.set_ttl(64);
+    packet.set_source(Ipv4Addr::new(192, 168, 1, 100));
+    packet.set_destination(Ipv4Addr::new(1, 1, 1, 1));
+    let checksum = ipv4::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+
+    for new in [
+        Ipv4Addr::new(10, 6, 0, 1),
+        Ipv4Addr::new(255, 255, 255, 255),
+    ]
+    .iter()
+    {
+        let old = packet.get_source();
+        packet.set_source(*new);
+        let checksum = update_ipv4_addr(packet.get_checksum(), old, *new);
+        assert_eq!(checksum, ipv4::checksum(&packet.to_immutable()));
+        packet.set_checksum(checksum);
+    }
+
+    // A word unchanged keeps the checksum
+    assert_eq!(update_u16(0x1234, 0xabcd, 0xabcd), 0x1234);
+}
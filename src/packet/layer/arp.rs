@@ -1,9 +1,9 @@
 //! Support for serializing and deserializing the ARP layer.
 
 use super::{Layer, LayerKind, LayerKinds};
-use pnet::datalink::MacAddr;
 use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
 use pnet::packet::ethernet::EtherTypes;
+use pnet::util::MacAddr;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
@@ -55,6 +55,24 @@ impl Arp {
         Arp::from(arp)
     }
 
+    /// Creates a `Arp` represents an ARP probe (RFC 5227), which has an unspecified sender IP
+    /// address.
+    pub fn new_probe(hardware_addr: MacAddr, ip_addr: Ipv4Addr) -> Arp {
+        let arp = arp::Arp {
+            hardware_type: ArpHardwareTypes::Ethernet,
+            protocol_type: EtherTypes::Ipv4,
+            hw_addr_len: 6,
+            proto_addr_len: 4,
+            operation: ArpOperations::Request,
+            sender_hw_addr: hardware_addr,
+            sender_proto_addr: Ipv4Addr::UNSPECIFIED,
+            target_hw_addr: MacAddr::zero(),
+            target_proto_addr: ip_addr,
+            payload: vec![],
+        };
+        Arp::from(arp)
+    }
+
     /// Creates an `Arp` according to the given `Arp`.
     pub fn from(arp: arp::Arp) -> Arp {
         Arp { layer: arp }
@@ -29,9 +29,15 @@ pub struct Icmpv4 {
 impl Icmpv4 {
     /// Creates a `Icmpv4` represents an ICMPv4 echo reply.
     pub fn new_echo_reply(identifier: u16, sequence_number: u16) -> Icmpv4 {
-        let mut payload = vec![0u8; 4];
+        Icmpv4::new_echo_reply_with_data(identifier, sequence_number, &[])
+    }
+
+    /// Creates a `Icmpv4` represents an ICMPv4 echo reply carrying the data of the echo request.
+    pub fn new_echo_reply_with_data(identifier: u16, sequence_number: u16, data: &[u8]) -> Icmpv4 {
+        let mut payload = vec![0u8; 4 + data.len()];
         &payload[..2].copy_from_slice(&identifier.to_ne_bytes());
-        &payload[2..].copy_from_slice(&sequence_number.to_ne_bytes());
+        &payload[2..4].copy_from_slice(&sequence_number.to_ne_bytes());
+        &payload[4..].copy_from_slice(data);
         let icmp = Icmp {
             icmp_type: IcmpTypes::EchoReply,
             icmp_code: echo_reply::IcmpCodes::NoCode,
@@ -101,7 +107,7 @@ impl Icmpv4 {
 
     /// Returns the identifier (NE) of the layer.
     pub fn identifier(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[0], self.layer.payload[1]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -111,7 +117,7 @@ impl Icmpv4 {
 
     /// Returns the sequence number (NE) of the layer.
     pub fn sequence_number(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -119,9 +125,18 @@ impl Icmpv4 {
         }
     }
 
+    /// Returns the data of the layer if it is an ICMPv4 echo request or reply.
+    pub fn data(&self) -> Option<&[u8]> {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
+            Some(&self.layer.payload[4..])
+        } else {
+            None
+        }
+    }
+
     /// Returns the next-hop MTU of the layer.
     pub fn next_hop_mtu(&self) -> Option<u16> {
-        if self.is_fragmentation_required_and_df_flag_set() {
+        if self.is_fragmentation_required_and_df_flag_set() && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_be_bytes(buffer))
         } else {
@@ -134,7 +149,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (ipv4, _) = self.parse_payload().unwrap();
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.src())
         } else {
             None
@@ -146,7 +161,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (ipv4, _) = self.parse_payload().unwrap();
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.dst())
         } else {
             None
@@ -158,7 +173,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (ipv4, _) = self.parse_payload().unwrap();
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.next_level_protocol())
         } else {
             None
@@ -170,7 +185,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (ipv4, _) = self.parse_payload().unwrap();
+            let (ipv4, _) = self.parse_payload()?;
             ipv4.next_level_layer_kind()
         } else {
             None
@@ -182,7 +197,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (_, transport) = self.parse_payload().unwrap();
+            let (_, transport) = self.parse_payload()?;
             match transport {
                 Some(transport) => match transport {
                     Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.src_ip_addr(), tcp.src())),
@@ -201,7 +216,7 @@ impl Icmpv4 {
         if self.is_destination_port_unreachable()
             || self.is_fragmentation_required_and_df_flag_set()
         {
-            let (_, transport) = self.parse_payload().unwrap();
+            let (_, transport) = self.parse_payload()?;
             match transport {
                 Some(transport) => match transport {
                     Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst())),
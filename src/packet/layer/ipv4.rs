@@ -7,9 +7,50 @@ use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Represents the default TTL in the sent packets.
+pub const TTL: u8 = 128;
+
+/// Represents the policy of the TTL of packets forwarded between sources.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TtlPolicy {
+    /// Represents the same TTL as the packets synthesized toward sources.
+    Fixed,
+    /// Represents the TTL of the original packets.
+    Preserve,
+    /// Represents the TTL of the original packets minus 1, like a router. Packets whose TTL runs
+    /// out are dropped.
+    Decrement,
+}
+
+impl Display for TtlPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            TtlPolicy::Fixed => "fixed",
+            TtlPolicy::Preserve => "preserve",
+            TtlPolicy::Decrement => "decrement",
+        };
+
+        write!(f, "{}", s)
+    }
+}
 
-/// Represents the TTL in the sent packets.
-const TTL: u8 = 128;
+impl FromStr for TtlPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(TtlPolicy::Fixed),
+            "preserve" => Ok(TtlPolicy::Preserve),
+            "decrement" => Ok(TtlPolicy::Decrement),
+            _ => Err(format!(
+                "invalid policy {}, please use fixed, preserve or decrement",
+                s
+            )),
+        }
+    }
+}
 
 /// Represents an IPv4 layer.
 #[derive(Clone, Debug)]
@@ -123,6 +164,16 @@ impl Ipv4 {
         self.layer.identification
     }
 
+    /// Returns the TTL of the layer.
+    pub fn ttl(&self) -> u8 {
+        self.layer.ttl
+    }
+
+    /// Sets the TTL of the layer.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.layer.ttl = ttl;
+    }
+
     /// Returns if more fragments are follows this layer.
     pub fn is_more_fragment(&self) -> bool {
         self.layer.flags & Ipv4Flags::MoreFragments != 0
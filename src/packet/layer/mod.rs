@@ -2,9 +2,10 @@
 
 use std::clone::Clone;
 use std::cmp::{Eq, PartialEq};
-use std::fmt::{self, Display, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::hash::Hash;
 use std::io;
+use std::sync::Arc;
 
 pub mod arp;
 pub mod ethernet;
@@ -29,6 +30,7 @@ impl Display for LayerKind {
                 LayerKinds::Icmpv4 => "ICMPv4",
                 LayerKinds::Tcp => "TCP",
                 LayerKinds::Udp => "UDP",
+                LayerKinds::Custom => "Custom",
                 _ => "unknown",
             }
         )
@@ -53,6 +55,8 @@ pub mod LayerKinds {
     pub const Tcp: LayerKind = LayerKind(4);
     /// Represents the layer kind of UDP.
     pub const Udp: LayerKind = LayerKind(5);
+    /// Represents the layer kind of custom layers.
+    pub const Custom: LayerKind = LayerKind(6);
 }
 
 /// Represents a layer.
@@ -75,6 +79,11 @@ pub trait Layer: Display {
     ) -> io::Result<usize>;
 }
 
+/// Represents a layer of a custom protocol which is parsed by a registered parser.
+pub trait CustomLayer: Layer + Debug + Send + Sync {}
+
+impl<T: Layer + Debug + Send + Sync> CustomLayer for T {}
+
 #[derive(Clone, Debug)]
 /// Enumeration of layers.
 pub enum Layers {
@@ -90,6 +99,8 @@ pub enum Layers {
     Tcp(tcp::Tcp),
     /// Represents the UDP layer.
     Udp(udp::Udp),
+    /// Represents a custom layer.
+    Custom(Arc<dyn CustomLayer>),
 }
 
 impl Display for Layers {
@@ -101,6 +112,7 @@ impl Display for Layers {
             Layers::Icmpv4(ref layer) => layer.fmt(f),
             Layers::Tcp(ref layer) => layer.fmt(f),
             Layers::Udp(ref layer) => layer.fmt(f),
+            Layers::Custom(ref layer) => Display::fmt(layer, f),
         }
     }
 }
@@ -114,6 +126,7 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.kind(),
             Layers::Tcp(ref layer) => layer.kind(),
             Layers::Udp(ref layer) => layer.kind(),
+            Layers::Custom(ref layer) => layer.kind(),
         }
     }
 
@@ -125,6 +138,7 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.len(),
             Layers::Tcp(ref layer) => layer.len(),
             Layers::Udp(ref layer) => layer.len(),
+            Layers::Custom(ref layer) => layer.len(),
         }
     }
 
@@ -136,6 +150,7 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize(buffer, n),
             Layers::Tcp(ref layer) => layer.serialize(buffer, n),
             Layers::Udp(ref layer) => layer.serialize(buffer, n),
+            Layers::Custom(ref layer) => layer.serialize(buffer, n),
         }
     }
 
@@ -152,6 +167,7 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Tcp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Udp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Custom(ref layer) => layer.serialize_with_payload(buffer, payload, n),
         }
     }
 }
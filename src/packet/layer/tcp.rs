@@ -347,7 +347,9 @@ impl Tcp {
                 TcpOptionNumbers::SACK => {
                     let mut vector = Vec::with_capacity(4);
 
-                    let pair_length = (buffer[1] as usize - 2) / 8;
+                    // The length is given by the source, which may exceed the option
+                    let length = min(buffer[1] as usize, buffer.len());
+                    let pair_length = length.checked_sub(2).unwrap_or(0) / 8;
                     for i in 0..pair_length {
                         let left = bytes_to_u32(&buffer[2 + 8 * i..2 + 8 * i + 4]);
                         let right = bytes_to_u32(&buffer[2 + 8 * i + 4..2 + 8 * i + 8]);
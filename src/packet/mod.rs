@@ -12,16 +12,54 @@ use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
 use std::time::Instant;
 
+pub mod builder;
+pub mod checksum;
 pub mod layer;
+pub mod template;
 use layer::arp::Arp;
 use layer::ethernet::Ethernet;
 use layer::icmpv4::Icmpv4;
 use layer::ipv4::Ipv4;
 use layer::tcp::Tcp;
 use layer::udp::Udp;
-use layer::{Layer, LayerKind, Layers};
+use layer::{CustomLayer, Layer, LayerKind, Layers};
+
+/// Represents a parser of a custom network layer. The argument is the payload of the Ethernet
+/// layer.
+pub type NetworkParser = Arc<dyn Fn(&[u8]) -> Option<Arc<dyn CustomLayer>> + Send + Sync>;
+
+/// Represents a parser of a custom transport layer. The arguments are the payload of the IPv4
+/// layer and the IPv4 layer.
+pub type TransportParser = Arc<dyn Fn(&[u8], &Ipv4) -> Option<Arc<dyn CustomLayer>> + Send + Sync>;
+
+/// Represents a registry of parsers of custom layers.
+#[derive(Clone, Default)]
+pub struct Parsers {
+    network: HashMap<u16, NetworkParser>,
+    transport: HashMap<u8, TransportParser>,
+}
+
+impl Parsers {
+    /// Creates a new empty `Parsers`.
+    pub fn new() -> Parsers {
+        Parsers::default()
+    }
+
+    /// Registers a parser of a custom network layer with the given EtherType. Built-in
+    /// EtherTypes like ARP and IPv4 cannot be overridden.
+    pub fn register_network(&mut self, ethertype: u16, parser: NetworkParser) {
+        self.network.insert(ethertype, parser);
+    }
+
+    /// Registers a parser of a custom transport layer with the given IP protocol. Built-in
+    /// protocols like ICMP, TCP and UDP cannot be overridden.
+    pub fn register_transport(&mut self, protocol: u8, parser: TransportParser) {
+        self.transport.insert(protocol, parser);
+    }
+}
 
 /// Represents a packet indicator.
 #[derive(Clone, Debug)]
@@ -43,6 +81,11 @@ impl Indicator {
 
     /// Creates a `Indicator` by the given Ethernet packet.
     pub fn parse(packet: &EthernetPacket) -> Indicator {
+        Indicator::parse_with(packet, None)
+    }
+
+    /// Creates a `Indicator` by the given Ethernet packet, with parsers of custom layers.
+    pub fn parse_with(packet: &EthernetPacket, parsers: Option<&Parsers>) -> Indicator {
         let mut transport = None;
 
         let link = Layers::Ethernet(Ethernet::parse(packet));
@@ -81,7 +124,14 @@ impl Indicator {
                                     None => None,
                                 }
                             }
-                            _ => None,
+                            protocol => match parsers
+                                .and_then(|parsers| parsers.transport.get(&protocol.0))
+                            {
+                                Some(parser) => {
+                                    parser(ipv4_packet.payload(), &ipv4).map(Layers::Custom)
+                                }
+                                None => None,
+                            },
                         };
                     }
 
@@ -89,7 +139,10 @@ impl Indicator {
                 }
                 None => None,
             },
-            _ => None,
+            ethertype => match parsers.and_then(|parsers| parsers.network.get(&ethertype.0)) {
+                Some(parser) => parser(packet.payload()).map(Layers::Custom),
+                None => None,
+            },
         };
 
         Indicator {
@@ -101,8 +154,13 @@ impl Indicator {
 
     /// Creates a `Indicator` by the given frame.
     pub fn from(frame: &[u8]) -> Option<Indicator> {
+        Indicator::from_with(frame, None)
+    }
+
+    /// Creates a `Indicator` by the given frame, with parsers of custom layers.
+    pub fn from_with(frame: &[u8], parsers: Option<&Parsers>) -> Option<Indicator> {
         match EthernetPacket::new(frame) {
-            Some(ref packet) => Some(Indicator::parse(packet)),
+            Some(ref packet) => Some(Indicator::parse_with(packet, parsers)),
             None => None,
         }
     }
@@ -139,10 +197,14 @@ impl Indicator {
                             udp.dst(),
                             udp.length(),
                         ),
+                        Layers::Custom(custom) => {
+                            format!("{}: {} -> {}", custom, ipv4.src(), ipv4.dst())
+                        }
                         _ => unreachable!(),
                     },
                     None => format!("{}", ipv4),
                 },
+                Layers::Custom(custom) => format!("{}", custom),
                 _ => unreachable!(),
             },
             None => match self.link() {
@@ -177,6 +239,7 @@ impl Indicator {
                 Some(network) => match network {
                     Layers::Arp(arp) => ethernet.len() + arp.len(),
                     Layers::Ipv4(ipv4) => ethernet.len() + ipv4.total_length() as usize,
+                    Layers::Custom(custom) => ethernet.len() + custom.len(),
                     _ => unreachable!(),
                 },
                 None => ethernet.len(),
@@ -185,6 +248,33 @@ impl Indicator {
         }
     }
 
+    /// Returns if the indicator is malformed in the given frame. Frames whose headers exceed the
+    /// frame or the IPv4 total length are always malformed. In the strict mode, truncated frames
+    /// and non-fragmented IPv4 packets whose transport layers cannot be parsed are also
+    /// malformed.
+    pub fn is_malformed(&self, frame: &[u8], is_strict: bool) -> bool {
+        let content_len = self.content_len();
+        if self.len() > frame.len() || self.len() > content_len {
+            return true;
+        }
+
+        if is_strict {
+            if content_len > frame.len() {
+                return true;
+            }
+            if let Some(ipv4) = self.ipv4() {
+                if !ipv4.is_fragment()
+                    && ipv4.next_level_layer_kind().is_some()
+                    && self.transport().is_none()
+                {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
     /// Serialize the indicator into a byte-array.
     pub fn serialize(&self, buffer: &mut [u8]) -> io::Result<usize> {
         let mut begin = 0;
@@ -561,3 +651,229 @@ fn defraggler_add() {
 
     assert_eq!(p, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
 }
+
+#[test]
+fn indicator_parse_with_custom() {
+    use layer::LayerKinds;
+
+    #[derive(Debug)]
+    struct Tunnel(u32);
+
+    impl Display for Tunnel {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "Tunnel: {}", self.0)
+        }
+    }
+
+    impl Layer for Tunnel {
+        fn kind(&self) -> LayerKind {
+            LayerKinds::Custom
+        }
+
+        fn len(&self) -> usize {
+            4
+        }
+
+        fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+            buffer[..4].copy_from_slice(&self.0.to_be_bytes());
+
+            Ok(4)
+        }
+
+        fn serialize_with_payload(
+            &self,
+            buffer: &mut [u8],
+            _: &[u8],
+            n: usize,
+        ) -> io::Result<usize> {
+            self.serialize(buffer, n)
+        }
+    }
+
+    let mut parsers = Parsers::new();
+    parsers.register_network(
+        0x88b5,
+        Arc::new(|payload: &[u8]| {
+            if payload.len() < 4 {
+                return None;
+            }
+            let id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            Some(Arc::new(Tunnel(id)) as Arc<dyn CustomLayer>)
+        }),
+    );
+
+    let mut frame = vec![0x11; 12];
+    frame.extend_from_slice(&[0x88, 0xb5, 0x00, 0x00, 0x00, 0x2a]);
+
+    let i = Indicator::from(frame.as_slice()).unwrap();
+    assert!(i.network().is_none());
+
+    let i = Indicator::from_with(frame.as_slice(), Some(&parsers)).unwrap();
+    assert_eq!(i.network_kind(), Some(LayerKinds::Custom));
+    assert_eq!(i.brief(), "Tunnel: 42");
+    assert_eq!(i.content_len(), 18);
+}
+
+#[test]
+fn indicator_is_malformed() {
+    let mut frame = vec![0x11; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x28, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 1, 1, 1, 1, 2, 2,
+        2, 2,
+    ]);
+
+    // Truncated
+    let i = Indicator::from(frame.as_slice()).unwrap();
+    assert!(i.transport().is_none());
+    assert!(!i.is_malformed(frame.as_slice(), false));
+    assert!(i.is_malformed(frame.as_slice(), true));
+
+    // Total length shorter than the header
+    frame[17] = 0x0a;
+    let i = Indicator::from(frame.as_slice()).unwrap();
+    assert!(i.is_malformed(frame.as_slice(), false));
+}
+
+#[cfg(test)]
+fn exercise_indicator(frame: &[u8]) {
+    use std::cmp::min;
+
+    let i = match Indicator::from(frame) {
+        Some(i) => i,
+        None => return,
+    };
+    i.brief();
+    for is_strict in &[false, true] {
+        if !i.is_malformed(frame, *is_strict) {
+            // The dispatch slices the frame by the indicator
+            assert!(i.len() <= min(i.content_len(), frame.len()));
+        }
+    }
+    if let Some(transport) = i.transport() {
+        match transport {
+            Layers::Icmpv4(icmpv4) => {
+                icmpv4.description();
+                icmpv4.identifier();
+                icmpv4.sequence_number();
+                icmpv4.data();
+                icmpv4.next_hop_mtu();
+                icmpv4.next_level_layer_kind();
+                icmpv4.src();
+                icmpv4.dst();
+            }
+            Layers::Tcp(tcp) => {
+                tcp.mss();
+                tcp.wscale();
+                tcp.sack();
+                tcp.ts();
+                tcp.ts_ecr();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn indicator_parse_truncated_and_random() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut ethernet = vec![0x11; 6];
+    ethernet.extend_from_slice(&[0x22; 6]);
+
+    let mut frames = Vec::new();
+    // TCP SYN with MSS, window scale and timestamps
+    let mut frame = ethernet.clone();
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 6, 0, 1, 1, 1,
+        1, 1,
+    ]);
+    frame.extend_from_slice(&[
+        0x03, 0xe8, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02, 0xff,
+        0xff, 0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+    ]);
+    frames.push(frame);
+    // TCP ACK with a SACK whose length exceeds the options
+    let mut frame = ethernet.clone();
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x34, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 6, 0, 1, 1, 1,
+        1, 1,
+    ]);
+    frame.extend_from_slice(&[
+        0x03, 0xe8, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x80, 0x10, 0xff,
+        0xff, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x05, 0xff, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0x00, 0x20,
+    ]);
+    frames.push(frame);
+    // UDP
+    let mut frame = ethernet.clone();
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 6, 0, 1, 1, 1,
+        1, 1,
+    ]);
+    frame.extend_from_slice(&[
+        0x13, 0x88, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+    ]);
+    frames.push(frame);
+    // ICMPv4 destination port unreachable
+    let mut frame = ethernet.clone();
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x38, 0x00, 0x00, 0x40, 0x00, 0x40, 0x01, 0x00, 0x00, 1, 1, 1, 1, 10, 6,
+        0, 1,
+    ]);
+    frame.extend_from_slice(&[0x03, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    frame.extend_from_slice(&[
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 6, 0, 1, 1, 1,
+        1, 1,
+    ]);
+    frame.extend_from_slice(&[0x13, 0x88, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00]);
+    frames.push(frame);
+    // ARP request
+    let mut frame = ethernet.clone();
+    frame.extend_from_slice(&[0x08, 0x06]);
+    frame.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01]);
+    frame.extend_from_slice(&[0x22; 6]);
+    frame.extend_from_slice(&[10, 6, 0, 1]);
+    frame.extend_from_slice(&[0x00; 6]);
+    frame.extend_from_slice(&[10, 6, 0, 2]);
+    frames.push(frame);
+
+    // Every truncation
+    for frame in &frames {
+        for i in 0..=frame.len() {
+            exercise_indicator(&frame[..i]);
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..10000 {
+        // Random bytes in a frame
+        let mut frame = frames[rng.gen_range(0..frames.len())].clone();
+        let size = rng.gen_range(1..4);
+        for _ in 0..size {
+            let i = rng.gen_range(0..frame.len());
+            frame[i] = rng.gen();
+        }
+        exercise_indicator(&frame);
+
+        // Random bytes after the Ethernet header
+        let mut frame = ethernet.clone();
+        let ether_type = match rng.gen() {
+            true => [0x08, 0x00],
+            false => [0x08, 0x06],
+        };
+        frame.extend_from_slice(&ether_type);
+        let size = rng.gen_range(0..80);
+        frame.extend((0..size).map(|_| rng.gen::<u8>()));
+        if size > 0 && frame[12] == 0x08 && frame[13] == 0x00 {
+            frame[14] = 0x45;
+        }
+        exercise_indicator(&frame);
+    }
+}
@@ -0,0 +1,178 @@
+//! Support for serializing small UDP packets from cached headers.
+
+use pnet::util::MacAddr;
+use std::io;
+
+use super::layer::Layers;
+use super::Indicator;
+
+/// Represents the size of the Ethernet, IPv4 and UDP headers without options.
+const HEADER_SIZE: usize = 42;
+const IPV4_BEGIN: usize = 14;
+const UDP_BEGIN: usize = 34;
+
+/// Represents the serialized headers of a UDP flow. Only the lengths, the IPv4 identification and
+/// the checksums are patched for each packet, and the checksums are updated from the sums of the
+/// fixed fields.
+#[derive(Clone, Copy, Debug)]
+pub struct UdpTemplate {
+    header: [u8; HEADER_SIZE],
+    dst: MacAddr,
+    ipv4_sum: u32,
+    udp_sum: u32,
+}
+
+impl UdpTemplate {
+    /// Creates a `UdpTemplate` from the `Indicator` of an Ethernet, IPv4 and UDP packet.
+    pub fn new(indicator: &Indicator) -> io::Result<UdpTemplate> {
+        let dst = match (indicator.link(), indicator.transport()) {
+            (Layers::Ethernet(ethernet), Some(Layers::Udp(_))) => ethernet.dst(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a UDP packet",
+                ))
+            }
+        };
+        if indicator.len() != HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IPv4 options not supported",
+            ));
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        indicator.serialize(&mut header)?;
+
+        // IPv4 header without the total length, the identification and the checksum
+        let ipv4 = &header[IPV4_BEGIN..UDP_BEGIN];
+        let ipv4_sum = sum(&ipv4[..2]) + sum(&ipv4[6..10]) + sum(&ipv4[12..]);
+        // Pseudo header and ports without the lengths and the checksum
+        let udp_sum =
+            sum(&ipv4[12..]) + u32::from(ipv4[9]) + sum(&header[UDP_BEGIN..UDP_BEGIN + 4]);
+
+        Ok(UdpTemplate {
+            header,
+            dst,
+            ipv4_sum,
+            udp_sum,
+        })
+    }
+
+    /// Returns the destination hardware address of the template.
+    pub fn dst(&self) -> MacAddr {
+        self.dst
+    }
+
+    /// Returns the TTL of the template.
+    pub fn ttl(&self) -> u8 {
+        self.header[IPV4_BEGIN + 8]
+    }
+
+    /// Returns the length of the headers.
+    pub fn header_len(&self) -> usize {
+        HEADER_SIZE
+    }
+
+    /// Serializes the packet with the payload and the IPv4 identification into the buffer.
+    pub fn serialize(
+        &self,
+        buffer: &mut [u8],
+        identification: u16,
+        payload: &[u8],
+    ) -> io::Result<usize> {
+        let size = HEADER_SIZE + payload.len();
+        if buffer.len() < size {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        if size - IPV4_BEGIN > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "length too big",
+            ));
+        }
+        buffer[..HEADER_SIZE].copy_from_slice(&self.header);
+        buffer[HEADER_SIZE..size].copy_from_slice(payload);
+
+        // IPv4
+        let total_length = (size - IPV4_BEGIN) as u16;
+        buffer[IPV4_BEGIN + 2..IPV4_BEGIN + 4].copy_from_slice(&total_length.to_be_bytes());
+        buffer[IPV4_BEGIN + 4..IPV4_BEGIN + 6].copy_from_slice(&identification.to_be_bytes());
+        let checksum = fold(self.ipv4_sum + u32::from(total_length) + u32::from(identification));
+        buffer[IPV4_BEGIN + 10..IPV4_BEGIN + 12].copy_from_slice(&checksum.to_be_bytes());
+
+        // UDP, whose length is counted in both the pseudo header and the header
+        let length = (size - UDP_BEGIN) as u16;
+        buffer[UDP_BEGIN + 4..UDP_BEGIN + 6].copy_from_slice(&length.to_be_bytes());
+        let checksum = match fold(self.udp_sum + 2 * u32::from(length) + sum(payload)) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        buffer[UDP_BEGIN + 6..UDP_BEGIN + 8].copy_from_slice(&checksum.to_be_bytes());
+
+        Ok(size)
+    }
+}
+
+/// Returns the sum of the 16-bit words, where an odd byte at the end is padded with zero.
+fn sum(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum = chunks
+        .by_ref()
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], chunk[1]])))
+        .sum::<u32>();
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+
+    sum
+}
+
+/// Returns the one's complement of the one's complement sum.
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[test]
+fn udp_template_serialize() {
+    use super::builder::EthernetBuilder;
+    use super::layer::ipv4::Ipv4;
+    use super::layer::udp::Udp;
+    use super::layer::LayerKinds;
+
+    let src = "10.6.0.254".parse().unwrap();
+    let dst = "10.6.0.1".parse().unwrap();
+    let build = |identification| {
+        EthernetBuilder::new(
+            "11:11:11:11:11:11".parse().unwrap(),
+            "22:22:22:22:22:22".parse().unwrap(),
+        )
+        .network(Layers::Ipv4(
+            Ipv4::new(identification, LayerKinds::Udp, src, dst).unwrap(),
+        ))
+        .transport(Layers::Udp(Udp::new(53, 50000)))
+        .build()
+        .unwrap()
+    };
+
+    let template = UdpTemplate::new(&build(0)).unwrap();
+    for (identification, size) in [(1, 0), (2, 1), (0xffff, 255)].iter() {
+        let payload = (0..*size).map(|i| i as u8).collect::<Vec<_>>();
+        let indicator = build(*identification);
+        let mut expected = vec![0u8; indicator.len() + payload.len()];
+        indicator
+            .serialize_with_payload(&mut expected, &payload)
+            .unwrap();
+
+        let mut buffer = vec![0u8; template.header_len() + payload.len()];
+        let n = template
+            .serialize(&mut buffer, *identification, &payload)
+            .unwrap();
+        assert_eq!(n, expected.len());
+        assert_eq!(buffer, expected);
+    }
+}
@@ -1,10 +1,36 @@
 //! Support for handling pcap interfaces.
 
+use log::{info, warn};
 use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::tcp::MutableTcpPacket;
+use pnet::packet::udp::MutableUdpPacket;
+use pnet::packet::{MutablePacket, Packet};
 use std::clone::Clone;
+#[cfg(feature = "capture")]
+use std::cmp::max;
+use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
-use std::io;
+#[cfg(feature = "capture")]
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::Ipv4Addr;
+#[cfg(feature = "capture")]
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+#[cfg(feature = "capture")]
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::oui;
+use crate::packet::checksum;
+use crate::packet::Indicator;
 
 #[cfg(windows)]
 use netifs;
@@ -27,6 +53,27 @@ pub type Receiver = Box<dyn DataLinkReceiver>;
 
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
+/// Represents the buffer size of pcap channels in machines of low memory.
+const SMALL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Represents the snapshot length of pcap dumps.
+const DUMP_SNAPLEN: u32 = 65535;
+/// Represents the size of pcap dumps over which a rotating dump rotates to a new file.
+#[cfg(feature = "capture")]
+const ROTATE_SIZE: u64 = 16 * 1024 * 1024;
+/// Represents the number of files kept by a rotating dump, including the current one.
+#[cfg(feature = "capture")]
+const ROTATE_FILES: usize = 4;
+
+/// Represents the default port of TZSP collectors.
+#[cfg(feature = "capture")]
+const TZSP_PORT: u16 = 37008;
+
+/// Represents the max time in milliseconds a replay waits for the next frame in each receiving.
+const REPLAY_MAX_WAIT: u64 = 20;
+
+/// Represents the timeout in milliseconds of each direction in the self-test.
+const SELF_TEST_TIMEOUT: u64 = 1000;
 
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -38,6 +85,7 @@ pub struct Interface {
     mtu: usize,
     is_up: bool,
     is_loopback: bool,
+    is_point_to_point: bool,
 }
 
 impl Interface {
@@ -51,11 +99,22 @@ impl Interface {
             mtu: 0,
             is_up: false,
             is_loopback: false,
+            is_point_to_point: false,
         }
     }
 
     /// Opens the network interface for sending and receiving data.
     pub fn open(&self) -> io::Result<(Sender, Receiver)> {
+        self.open_with(None)
+    }
+
+    /// Opens the network interface for sending and receiving data, where receiving times out if
+    /// no frame arrives in the duration, so the receiver wakes up on an idle network.
+    pub fn open_timeout(&self, read_timeout: Duration) -> io::Result<(Sender, Receiver)> {
+        self.open_with(Some(read_timeout))
+    }
+
+    fn open_with(&self, read_timeout: Option<Duration>) -> io::Result<(Sender, Receiver)> {
         let inters = datalink::interfaces();
         let inter = inters
             .into_iter()
@@ -65,10 +124,22 @@ impl Interface {
                 io::ErrorKind::NotFound,
                 "interface not found",
             ))?;
+        // Frames of point-to-point interfaces like PPPoE and TUN have no Ethernet header
+        if inter.is_point_to_point() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "point-to-point interface not supported, please use an Ethernet interface",
+            ));
+        }
 
+        let buffer_size = match crate::is_low_memory() {
+            true => SMALL_BUFFER_SIZE,
+            false => BUFFER_SIZE,
+        };
         let mut config = Config::default();
-        config.write_buffer_size = BUFFER_SIZE;
-        config.read_buffer_size = BUFFER_SIZE;
+        config.write_buffer_size = buffer_size;
+        config.read_buffer_size = buffer_size;
+        config.read_timeout = read_timeout;
         let channel = datalink::channel(&inter, config)?;
         let channel = match channel {
             Channel::Ethernet(tx, rx) => (tx, rx),
@@ -116,6 +187,116 @@ impl Interface {
     pub fn is_loopback(&self) -> bool {
         self.is_loopback
     }
+
+    /// Returns if the interface is a point-to-point interface, which has no Ethernet header.
+    pub fn is_point_to_point(&self) -> bool {
+        self.is_point_to_point
+    }
+
+    /// Tests the send and the receive halves opened from the interface by exchanging crafted ARP
+    /// requests with another channel of the interface, since a channel never captures frames sent
+    /// by itself. The receive half is returned if both directions work.
+    pub fn self_test(&self, tx: &mut Sender, mut rx: Receiver) -> io::Result<Receiver> {
+        let timeout = Duration::from_millis(SELF_TEST_TIMEOUT);
+        let (mut probe_tx, mut probe_rx) = self.open_with(Some(timeout))?;
+        let ip_addr = self.ip_addr().unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+        // Send
+        let nonce = MacAddr::from(rand::random::<[u8; 6]>());
+        send_self_test_frame(tx, self.hardware_addr, ip_addr, nonce)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match probe_rx.next() {
+                Ok(frame) => {
+                    if is_self_test_frame(frame, nonce) {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "frames sent are not seen on the interface",
+                ));
+            }
+        }
+
+        // Receive, in another thread since the receive half blocks without a timeout
+        let nonce = MacAddr::from(rand::random::<[u8; 6]>());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = loop {
+                match rx.next() {
+                    Ok(frame) => {
+                        if is_self_test_frame(frame, nonce) {
+                            break Ok(());
+                        }
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            let _ = sender.send((rx, result));
+        });
+        send_self_test_frame(&mut probe_tx, self.hardware_addr, ip_addr, nonce)?;
+        match receiver.recv_timeout(timeout) {
+            Ok((rx, Ok(()))) => Ok(rx),
+            Ok((_, Err(e))) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "frames on the interface are not captured",
+            )),
+        }
+    }
+}
+
+/// Sends an ARP request to the interface itself, which carries the nonce in its target hardware
+/// address, so it never reaches other hosts and is distinguished from other frames.
+fn send_self_test_frame(
+    tx: &mut Sender,
+    hardware_addr: MacAddr,
+    ip_addr: Ipv4Addr,
+    nonce: MacAddr,
+) -> io::Result<()> {
+    let size =
+        MutableEthernetPacket::minimum_packet_size() + MutableArpPacket::minimum_packet_size();
+    let mut buffer = vec![0u8; size];
+    let mut packet = MutableEthernetPacket::new(&mut buffer).unwrap();
+    packet.set_destination(hardware_addr);
+    packet.set_source(hardware_addr);
+    packet.set_ethertype(EtherTypes::Arp);
+    let mut arp_packet = MutableArpPacket::new(packet.payload_mut()).unwrap();
+    arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp_packet.set_protocol_type(EtherTypes::Ipv4);
+    arp_packet.set_hw_addr_len(6);
+    arp_packet.set_proto_addr_len(4);
+    arp_packet.set_operation(ArpOperations::Request);
+    arp_packet.set_sender_hw_addr(hardware_addr);
+    arp_packet.set_sender_proto_addr(ip_addr);
+    arp_packet.set_target_hw_addr(nonce);
+    arp_packet.set_target_proto_addr(ip_addr);
+
+    tx.send_to(&buffer, None).unwrap_or(Ok(()))
+}
+
+/// Returns if the frame is the ARP request of the self-test carrying the nonce.
+fn is_self_test_frame(frame: &[u8], nonce: MacAddr) -> bool {
+    let packet = match EthernetPacket::new(frame) {
+        Some(packet) => packet,
+        None => return false,
+    };
+    if packet.get_ethertype() != EtherTypes::Arp {
+        return false;
+    }
+
+    match ArpPacket::new(packet.payload()) {
+        Some(arp_packet) => {
+            arp_packet.get_operation() == ArpOperations::Request
+                && arp_packet.get_target_hw_addr() == nonce
+        }
+        None => false,
+    }
 }
 
 impl Display for Interface {
@@ -134,16 +315,19 @@ impl Display for Interface {
                 .join(", ")
         );
 
+        let hardware_addr = match oui::vendor(self.hardware_addr) {
+            Some(vendor) => format!("{} ({})", self.hardware_addr, vendor),
+            None => self.hardware_addr.to_string(),
+        };
+
         let mut flags = String::new();
         if self.is_loopback {
             flags = String::from(" (Loopback)");
+        } else if self.is_point_to_point {
+            flags = String::from(" (Point-to-Point)");
         }
 
-        write!(
-            f,
-            "{} [{}]{}: {}",
-            name, self.hardware_addr, flags, ip_addrs
-        )
+        write!(f, "{} [{}]{}: {}", name, hardware_addr, flags, ip_addrs)
     }
 }
 
@@ -183,6 +367,7 @@ pub fn interfaces() -> Vec<Interface> {
 
             i.is_up = inter.is_up();
             i.is_loopback = inter.is_loopback();
+            i.is_point_to_point = inter.is_point_to_point();
 
             Ok(i)
         })
@@ -258,3 +443,714 @@ impl DataLinkSender for BlackHole {
         Some(Ok(()))
     }
 }
+
+/// Represents a timestamp of a frame taken at the boundary of the pcap. It carries both the
+/// monotonic clock for computing latencies and the wall clock for dumps and logs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timestamp {
+    instant: Instant,
+    wall: Duration,
+}
+
+impl Timestamp {
+    /// Creates a new `Timestamp` of now.
+    pub fn now() -> Timestamp {
+        Timestamp {
+            instant: Instant::now(),
+            wall: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the instant of the monotonic clock.
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    /// Returns the amount of time of the wall clock since the Unix epoch.
+    pub fn wall(&self) -> Duration {
+        self.wall
+    }
+
+    /// Returns the amount of time elapsed from an earlier timestamp in the monotonic clock, or
+    /// zero if the timestamp is later than this one.
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        self.instant.saturating_duration_since(earlier.instant)
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06}",
+            self.wall.as_secs(),
+            self.wall.subsec_micros()
+        )
+    }
+}
+
+/// Represents a writer which dumps frames to a file in the pcap format.
+#[derive(Debug)]
+pub struct Dump {
+    file: BufWriter<File>,
+    size: u64,
+    is_privacy: bool,
+}
+
+impl Dump {
+    /// Creates a new `Dump` which writes to the file of the given path.
+    pub fn create(path: &str) -> io::Result<Dump> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // Global header
+        file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&DUMP_SNAPLEN.to_le_bytes())?;
+        // Ethernet
+        file.write_all(&1u32.to_le_bytes())?;
+        file.flush()?;
+
+        Ok(Dump {
+            file,
+            size: 24,
+            is_privacy: false,
+        })
+    }
+
+    /// Writes a frame to the dump.
+    pub fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_truncated(frame, DUMP_SNAPLEN as usize)
+    }
+
+    /// Sets if the dump is in the privacy mode. Only headers of frames will be written in the
+    /// privacy mode.
+    pub fn set_privacy(&mut self, is_privacy: bool) {
+        self.is_privacy = is_privacy;
+    }
+
+    /// Returns the size of the dump in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Writes a frame captured or sent at the given timestamp to the dump.
+    pub fn write_at(&mut self, frame: &[u8], timestamp: Timestamp) -> io::Result<()> {
+        self.write_truncated_at(frame, DUMP_SNAPLEN as usize, timestamp)
+    }
+
+    /// Writes a frame truncated to the given length to the dump.
+    pub fn write_truncated(&mut self, frame: &[u8], snaplen: usize) -> io::Result<()> {
+        self.write_truncated_at(frame, snaplen, Timestamp::now())
+    }
+
+    /// Writes a frame captured or sent at the given timestamp truncated to the given length to the
+    /// dump.
+    pub fn write_truncated_at(
+        &mut self,
+        frame: &[u8],
+        snaplen: usize,
+        timestamp: Timestamp,
+    ) -> io::Result<()> {
+        let timestamp = timestamp.wall();
+        let mut size = min(min(frame.len(), snaplen), DUMP_SNAPLEN as usize);
+        if self.is_privacy {
+            size = min(size, headers_len(frame));
+        }
+
+        // Record header
+        self.file
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.file
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(size as u32).to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        // Record data
+        self.file.write_all(&frame[..size])?;
+        self.size += 16 + size as u64;
+        self.file.flush()
+    }
+}
+
+/// Represents a dump which rotates to a new file once the current one grows over a size. The file
+/// is created on the first write, and the older files are renamed with suffixes like `.1`, where
+/// a larger suffix stands for an older file.
+#[cfg(feature = "capture")]
+#[derive(Debug)]
+pub struct RotatingDump {
+    path: String,
+    dump: Option<Dump>,
+    is_privacy: bool,
+}
+
+#[cfg(feature = "capture")]
+impl RotatingDump {
+    /// Creates a new `RotatingDump` which writes to the file of the given path.
+    pub fn new(path: &str) -> RotatingDump {
+        RotatingDump {
+            path: path.to_string(),
+            dump: None,
+            is_privacy: false,
+        }
+    }
+
+    /// Returns the path of the current file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Sets if the dump is in the privacy mode. Only headers of frames will be written in the
+    /// privacy mode.
+    pub fn set_privacy(&mut self, is_privacy: bool) {
+        self.is_privacy = is_privacy;
+    }
+
+    /// Writes a frame captured or sent at the given timestamp to the dump.
+    pub fn write_at(&mut self, frame: &[u8], timestamp: Timestamp) -> io::Result<()> {
+        if self
+            .dump
+            .as_ref()
+            .map_or(false, |dump| dump.size() >= ROTATE_SIZE)
+        {
+            self.dump = None;
+        }
+        if self.dump.is_none() {
+            // Files of the last run are also rotated
+            self.rotate()?;
+            let mut dump = Dump::create(&self.path)?;
+            dump.set_privacy(self.is_privacy);
+            self.dump = Some(dump);
+            info!("Capture to {}", self.path);
+        }
+
+        match &mut self.dump {
+            Some(dump) => dump.write_at(frame, timestamp),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        for i in (1..ROTATE_FILES).rev() {
+            let from = match i {
+                1 => self.path.clone(),
+                _ => format!("{}.{}", self.path, i - 1),
+            };
+            if let Err(e) = fs::rename(&from, format!("{}.{}", self.path, i)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the length of the headers of the frame, which excludes all the payload.
+pub fn headers_len(frame: &[u8]) -> usize {
+    match Indicator::from(frame) {
+        Some(indicator) => min(indicator.len(), frame.len()),
+        None => 0,
+    }
+}
+
+#[cfg(feature = "capture")]
+#[derive(Debug)]
+enum MirrorTarget {
+    Dump(Dump),
+    Tzsp(UdpSocket),
+}
+
+/// Represents a mirror which copies frames to a pcap file or a TZSP collector.
+#[cfg(feature = "capture")]
+#[derive(Debug)]
+pub struct Mirror {
+    target: MirrorTarget,
+    snaplen: usize,
+    sample: usize,
+    count: usize,
+    is_privacy: bool,
+}
+
+#[cfg(feature = "capture")]
+impl Mirror {
+    /// Creates a new `Mirror`. The target can be a path of a pcap file, or an address of a TZSP
+    /// collector like `tzsp://192.168.1.2:37008` or `udp://192.168.1.2`.
+    pub fn create(target: &str) -> io::Result<Mirror> {
+        let addr = ["tzsp://", "udp://"]
+            .iter()
+            .find(|scheme| target.starts_with(*scheme))
+            .map(|scheme| &target[scheme.len()..]);
+        let target = match addr {
+            Some(addr) => {
+                let addr = match addr.contains(':') {
+                    true => addr.to_socket_addrs()?,
+                    false => (addr, TZSP_PORT).to_socket_addrs()?,
+                }
+                .find(|addr| addr.is_ipv4())
+                .ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+
+                MirrorTarget::Tzsp(socket)
+            }
+            None => MirrorTarget::Dump(Dump::create(target)?),
+        };
+
+        Ok(Mirror {
+            target,
+            snaplen: DUMP_SNAPLEN as usize,
+            sample: 1,
+            count: 0,
+            is_privacy: false,
+        })
+    }
+
+    /// Sets the snapshot length. Frames longer than the length will be truncated, so only headers
+    /// can be mirrored.
+    pub fn set_snaplen(&mut self, snaplen: usize) {
+        self.snaplen = snaplen;
+    }
+
+    /// Sets the sampling rate. Only 1 frame will be mirrored in every given number of frames.
+    pub fn set_sample(&mut self, sample: usize) {
+        self.sample = max(sample, 1);
+    }
+
+    /// Sets if the mirror is in the privacy mode. Only headers of frames will be mirrored in the
+    /// privacy mode regardless of the snapshot length.
+    pub fn set_privacy(&mut self, is_privacy: bool) {
+        self.is_privacy = is_privacy;
+    }
+
+    /// Writes a frame to the mirror.
+    pub fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_at(frame, Timestamp::now())
+    }
+
+    /// Writes a frame captured or sent at the given timestamp to the mirror. The timestamp is
+    /// only kept in pcap files.
+    pub fn write_at(&mut self, frame: &[u8], timestamp: Timestamp) -> io::Result<()> {
+        // Sample
+        self.count = self.count.checked_add(1).unwrap_or(0);
+        if self.count % self.sample != 0 {
+            return Ok(());
+        }
+
+        let mut snaplen = self.snaplen;
+        if self.is_privacy {
+            snaplen = min(snaplen, headers_len(frame));
+        }
+
+        match &mut self.target {
+            MirrorTarget::Dump(dump) => dump.write_truncated_at(frame, snaplen, timestamp),
+            MirrorTarget::Tzsp(socket) => {
+                let size = min(frame.len(), snaplen);
+
+                // TZSP header, version 1, received tag list, Ethernet, and the end tag
+                let mut buffer = Vec::with_capacity(5 + size);
+                buffer.extend_from_slice(&[1, 0, 0, 1, 1]);
+                buffer.extend_from_slice(&frame[..size]);
+                socket.send(buffer.as_slice()).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Represents a send half which copies all the sent frames to a mirror.
+#[cfg(feature = "capture")]
+pub struct MirrorSender {
+    tx: Sender,
+    mirror: Arc<Mutex<Mirror>>,
+}
+
+#[cfg(feature = "capture")]
+impl MirrorSender {
+    /// Creates a new `MirrorSender`.
+    pub fn new(tx: Sender, mirror: Arc<Mutex<Mirror>>) -> MirrorSender {
+        MirrorSender { tx, mirror }
+    }
+}
+
+#[cfg(feature = "capture")]
+impl DataLinkSender for MirrorSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mirror = &self.mirror;
+        self.tx
+            .build_and_send(num_packets, packet_size, &mut |buffer| {
+                func(buffer);
+                if let Err(ref e) = mirror.lock().unwrap().write(buffer) {
+                    warn!("mirror frame: {}", e);
+                }
+            })
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        if let Err(ref e) = self.mirror.lock().unwrap().write(packet) {
+            warn!("mirror frame: {}", e);
+        }
+
+        self.tx.send_to(packet, dst)
+    }
+}
+
+/// Represents a receive half which replays frames from a pcap file in their original timing.
+/// Addresses of frames can be rewritten to match the current configuration.
+#[derive(Debug)]
+pub struct Replay {
+    file: BufReader<File>,
+    is_big_endian: bool,
+    is_nanosec: bool,
+    speed: f64,
+    hardware_addr: Option<HardwareAddr>,
+    ip_addrs: HashMap<Ipv4Addr, Ipv4Addr>,
+    start: Option<(Instant, Duration)>,
+    next: Option<(Duration, Vec<u8>)>,
+    frame: Vec<u8>,
+    is_finished: bool,
+}
+
+impl Replay {
+    /// Opens a pcap file of Ethernet frames for replaying.
+    pub fn open(path: &str) -> io::Result<Replay> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        // Global header
+        let mut header = [0u8; 24];
+        file.read_exact(&mut header)?;
+        let (is_big_endian, is_nanosec) =
+            match u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+                0xa1b2c3d4 => (false, false),
+                0xa1b23c4d => (false, true),
+                0xd4c3b2a1 => (true, false),
+                0x4d3cb2a1 => (true, true),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "not a pcap file",
+                    ))
+                }
+            };
+        let link_type = read_u32(&header[20..24], is_big_endian);
+        if link_type != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("link type {} not supported", link_type),
+            ));
+        }
+
+        Ok(Replay {
+            file,
+            is_big_endian,
+            is_nanosec,
+            speed: 1.0,
+            hardware_addr: None,
+            ip_addrs: HashMap::new(),
+            start: None,
+            next: None,
+            frame: Vec::new(),
+            is_finished: false,
+        })
+    }
+
+    /// Sets the speed multiplier of the original timing. Frames are replayed twice as fast with a
+    /// speed of `2`.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    /// Sets the hardware address which the destination of unicast frames will be rewritten to.
+    pub fn set_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.hardware_addr = Some(hardware_addr);
+    }
+
+    /// Adds a rewrite of the IPv4 address in both ARP and IPv4 packets. Checksums are recomputed
+    /// except for transport layers of fragments.
+    pub fn add_ip_addr_rewrite(&mut self, from: Ipv4Addr, to: Ipv4Addr) {
+        self.ip_addrs.insert(from, to);
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        // Record header
+        let mut header = [0u8; 16];
+        if let Err(e) = self.file.read_exact(&mut header) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        let secs = read_u32(&header[0..4], self.is_big_endian) as u64;
+        let frac = read_u32(&header[4..8], self.is_big_endian) as u64;
+        let size = read_u32(&header[8..12], self.is_big_endian) as usize;
+        if size > DUMP_SNAPLEN as usize * 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} Bytes too big", size),
+            ));
+        }
+        let timestamp = match self.is_nanosec {
+            true => Duration::from_secs(secs) + Duration::from_nanos(frac),
+            false => Duration::from_secs(secs) + Duration::from_micros(frac),
+        };
+
+        // Record data
+        let mut frame = vec![0u8; size];
+        if let Err(e) = self.file.read_exact(&mut frame) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+
+        Ok(Some((timestamp, frame)))
+    }
+
+    fn rewrite(&self, frame: &mut [u8]) {
+        let mut ethernet = match MutableEthernetPacket::new(frame) {
+            Some(ethernet) => ethernet,
+            None => return,
+        };
+        if let Some(hardware_addr) = self.hardware_addr {
+            // Keep broadcast and multicast frames
+            if ethernet.get_destination().0 & 1 == 0 {
+                ethernet.set_destination(hardware_addr);
+            }
+        }
+        if self.ip_addrs.is_empty() {
+            return;
+        }
+
+        let rewrite = |ip_addr: Ipv4Addr| *self.ip_addrs.get(&ip_addr).unwrap_or(&ip_addr);
+        match ethernet.get_ethertype() {
+            EtherTypes::Arp => {
+                if let Some(mut arp) = MutableArpPacket::new(ethernet.payload_mut()) {
+                    arp.set_sender_proto_addr(rewrite(arp.get_sender_proto_addr()));
+                    arp.set_target_proto_addr(rewrite(arp.get_target_proto_addr()));
+                }
+            }
+            EtherTypes::Ipv4 => {
+                let mut ipv4 = match MutableIpv4Packet::new(ethernet.payload_mut()) {
+                    Some(ipv4) => ipv4,
+                    None => return,
+                };
+                let (old_src, old_dst) = (ipv4.get_source(), ipv4.get_destination());
+                let (src, dst) = (rewrite(old_src), rewrite(old_dst));
+                if src == old_src && dst == old_dst {
+                    return;
+                }
+                // Update checksums incrementally (RFC 1624), since only addresses are changed
+                let update = |checksum: u16| {
+                    let checksum = checksum::update_ipv4_addr(checksum, old_src, src);
+                    checksum::update_ipv4_addr(checksum, old_dst, dst)
+                };
+                ipv4.set_source(src);
+                ipv4.set_destination(dst);
+                let checksum = update(ipv4.get_checksum());
+                ipv4.set_checksum(checksum);
+
+                // Only the first fragment carries the transport layer
+                if ipv4.get_fragment_offset() != 0 {
+                    return;
+                }
+                match ipv4.get_next_level_protocol() {
+                    IpNextHeaderProtocols::Tcp => {
+                        if let Some(mut tcp) = MutableTcpPacket::new(ipv4.payload_mut()) {
+                            let checksum = update(tcp.get_checksum());
+                            tcp.set_checksum(checksum);
+                        }
+                    }
+                    IpNextHeaderProtocols::Udp => {
+                        if let Some(mut udp) = MutableUdpPacket::new(ipv4.payload_mut()) {
+                            // Zero checksums are not computed
+                            if udp.get_checksum() != 0 {
+                                let checksum = match update(udp.get_checksum()) {
+                                    0 => 0xffff,
+                                    checksum => checksum,
+                                };
+                                udp.set_checksum(checksum);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DataLinkReceiver for Replay {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        if self.next.is_none() {
+            if self.is_finished {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            match self.read_record()? {
+                Some(record) => self.next = Some(record),
+                None => {
+                    self.is_finished = true;
+                    info!("Replay finished");
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+            }
+        }
+
+        // Timing
+        let timestamp = self.next.as_ref().unwrap().0;
+        let (start_instant, start_timestamp) =
+            *self.start.get_or_insert((Instant::now(), timestamp));
+        let offset = timestamp
+            .checked_sub(start_timestamp)
+            .unwrap_or_default()
+            .div_f64(self.speed);
+        let instant = start_instant + offset;
+        let now = Instant::now();
+        if instant > now {
+            let wait = instant - now;
+            if wait > Duration::from_millis(REPLAY_MAX_WAIT) {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            thread::sleep(wait);
+        }
+
+        let (_, mut frame) = self.next.take().unwrap();
+        self.rewrite(&mut frame);
+        self.frame = frame;
+
+        Ok(&self.frame)
+    }
+}
+
+fn read_u32(buffer: &[u8], is_big_endian: bool) -> u32 {
+    let bytes = [buffer[0], buffer[1], buffer[2], buffer[3]];
+    match is_big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    }
+}
+
+/// Represents a virtual pcap device in memory, where frames can be injected to its receive half
+/// and frames sent by its send half can be taken out.
+#[cfg(feature = "testing")]
+pub type Loopback = crate::stack::Device;
+
+#[test]
+fn timestamp_duration_since() {
+    let earlier = Timestamp::now();
+    let later = Timestamp {
+        instant: earlier.instant + Duration::from_millis(5),
+        wall: Duration::new(1609459200, 1_000),
+    };
+
+    assert_eq!(later.duration_since(earlier), Duration::from_millis(5));
+    assert_eq!(earlier.duration_since(later), Duration::from_secs(0));
+    assert_eq!(later.to_string(), "1609459200.000001");
+}
+
+#[test]
+fn replay_next() {
+    let path = std::env::temp_dir().join("pcap2socks_replay_next.pcap");
+    let path = path.to_str().unwrap();
+    let timestamp = Timestamp::now();
+    {
+        let mut dump = Dump::create(path).unwrap();
+        dump.write_at(&[0x10; 14], timestamp).unwrap();
+        dump.write_at(&[0xff; 14], timestamp).unwrap();
+    }
+
+    let mut replay = Replay::open(path).unwrap();
+    replay.set_hardware_addr(MacAddr(2, 2, 2, 2, 2, 2));
+    // Unicast destination is rewritten
+    assert_eq!(&replay.next().unwrap()[..6], &[2, 2, 2, 2, 2, 2]);
+    // Broadcast destination is kept
+    assert_eq!(&replay.next().unwrap()[..6], &[0xff; 6]);
+    assert_eq!(replay.next().unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn replay_rewrite_checksum() {
+    use crate::packet::builder::{EthernetBuilder, Ipv4Builder, TcpBuilder};
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ipv4::{self, Ipv4Packet};
+    use pnet::packet::tcp::{self, TcpPacket};
+    use pnet::packet::Packet;
+
+    let path = std::env::temp_dir().join("pcap2socks_replay_rewrite_checksum.pcap");
+    let path = path.to_str().unwrap();
+    let indicator = EthernetBuilder::new(
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .ipv4(Ipv4Builder::new(
+        Ipv4Addr::new(192, 168, 1, 100),
+        Ipv4Addr::new(1, 1, 1, 1),
+    ))
+    .tcp(TcpBuilder::new(50000, 443).sequence(100))
+    .build()
+    .unwrap();
+    let payload = [0xab; 7];
+    let mut frame = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut frame, &payload)
+        .unwrap();
+    {
+        let mut dump = Dump::create(path).unwrap();
+        dump.write_at(&frame, Timestamp::now()).unwrap();
+    }
+
+    let mut replay = Replay::open(path).unwrap();
+    replay.add_ip_addr_rewrite(Ipv4Addr::new(192, 168, 1, 100), Ipv4Addr::new(10, 6, 0, 1));
+    let frame = replay.next().unwrap();
+
+    // Checksums updated incrementally are the same with the recomputed ones
+    let ethernet = EthernetPacket::new(frame).unwrap();
+    let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+    assert_eq!(ipv4.get_source(), Ipv4Addr::new(10, 6, 0, 1));
+    assert_eq!(ipv4.get_checksum(), ipv4::checksum(&ipv4));
+    let tcp = TcpPacket::new(ipv4.payload()).unwrap();
+    assert_eq!(
+        tcp.get_checksum(),
+        tcp::ipv4_checksum(&tcp, &ipv4.get_source(), &ipv4.get_destination())
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(feature = "capture")]
+#[test]
+fn rotating_dump_rotate() {
+    let path = std::env::temp_dir().join("pcap2socks_rotating_dump_rotate.pcap");
+    let path = path.to_str().unwrap();
+    let rotated = format!("{}.1", path);
+    let _ = std::fs::remove_file(&rotated);
+    let timestamp = Timestamp::now();
+
+    let mut dump = RotatingDump::new(path);
+    dump.write_at(&[0x10; 14], timestamp).unwrap();
+    drop(dump);
+    assert_eq!(std::fs::metadata(path).unwrap().len(), 24 + 16 + 14);
+
+    // The file of the last run is rotated on the first write
+    let mut dump = RotatingDump::new(path);
+    dump.write_at(&[0x10; 14], timestamp).unwrap();
+    drop(dump);
+    assert_eq!(std::fs::metadata(&rotated).unwrap().len(), 24 + 16 + 14);
+
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(&rotated).unwrap();
+}
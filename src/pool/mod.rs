@@ -0,0 +1,110 @@
+//! Support for recycling buffers of frames and payloads.
+
+use std::fmt::{self, Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Represents the max number of idle buffers kept in a pool.
+pub const POOL_CAPACITY: usize = 256;
+/// Represents the max capacity of a buffer which will be recycled, larger buffers will be freed.
+pub const MAX_POOLED_SIZE: usize = 65536;
+
+/// Represents a pool of reusable buffers, which can be cloned and shared across threads.
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    /// Creates a new empty `BufferPool`.
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    /// Takes a zeroed buffer of the given size from the pool, or allocates one if the pool is
+    /// empty. The buffer returns to the pool when it is dropped.
+    pub fn get(&self, size: usize) -> PooledBuffer {
+        let mut buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer.resize(size, 0);
+
+        PooledBuffer {
+            buffer,
+            pool: Arc::clone(&self.buffers),
+        }
+    }
+
+    /// Returns the number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+impl Debug for BufferPool {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("idle", &self.idle())
+            .finish()
+    }
+}
+
+/// Represents a buffer taken from a `BufferPool`.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_slice()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buffer.as_mut_slice()
+    }
+}
+
+impl Debug for PooledBuffer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.buffer.len())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if self.buffer.capacity() > MAX_POOLED_SIZE {
+            return;
+        }
+
+        let mut buffers = self.pool.lock().unwrap();
+        if buffers.len() < POOL_CAPACITY {
+            buffers.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[test]
+fn buffer_pool_recycle() {
+    let pool = BufferPool::new();
+
+    let mut buffer = pool.get(4);
+    buffer[0] = 1;
+    drop(buffer);
+    assert_eq!(pool.idle(), 1);
+
+    // Recycled buffers are zeroed
+    let buffer = pool.get(8);
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(&buffer[..], &[0u8; 8]);
+    drop(buffer);
+
+    // Large buffers are not recycled
+    drop(pool.get(MAX_POOLED_SIZE + 1));
+    assert_eq!(pool.idle(), 0);
+}
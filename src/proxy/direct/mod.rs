@@ -0,0 +1,59 @@
+//! Support for connecting to destinations directly from the host without the proxy.
+
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+use tokio::io;
+use tokio::net::TcpStream;
+
+use super::stream::{DatagramRecvHalf, DatagramSendHalf, ProxyStream};
+use super::{ProxyTransport, TransportFuture};
+use crate::stats::LatencyStats;
+
+/// Represents a transport connecting to destinations directly from the host. Only TCP is
+/// supported, since a UDP association through the proxy serves all the destinations of a source
+/// port.
+#[derive(Clone, Debug)]
+pub struct DirectTransport {
+    latency: Arc<Mutex<LatencyStats>>,
+}
+
+impl DirectTransport {
+    /// Creates a new `DirectTransport`.
+    pub fn new() -> DirectTransport {
+        DirectTransport {
+            latency: Arc::new(Mutex::new(LatencyStats::new())),
+        }
+    }
+}
+
+impl Default for DirectTransport {
+    fn default() -> DirectTransport {
+        DirectTransport::new()
+    }
+}
+
+impl ProxyTransport for DirectTransport {
+    fn connect_tcp(&self, dst: SocketAddrV4) -> TransportFuture<'_, ProxyStream> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(dst).await?;
+
+            Ok(ProxyStream::Tcp(stream))
+        })
+    }
+
+    fn associate_udp(
+        &self,
+        _port: u16,
+    ) -> TransportFuture<'_, (DatagramRecvHalf, DatagramSendHalf, u16)> {
+        Box::pin(async move {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "UDP is not supported in the direct path",
+            ))
+        })
+    }
+
+    fn latency(&self) -> Arc<Mutex<LatencyStats>> {
+        Arc::clone(&self.latency)
+    }
+}
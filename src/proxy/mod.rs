@@ -1,23 +1,62 @@
 //! Support for handling proxies.
 
 use log::{debug, trace, warn};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
 use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
 use tokio::{self, io, time};
 
+use crate::qos::{self, FlowClass};
+use crate::stats::LatencyStats;
+
+mod direct;
+pub mod probe;
 mod socks;
-use socks::SocksSendHalf;
-use socks::{SocksAuth, SocksOption};
+mod stream;
+mod websocket;
+pub use direct::DirectTransport;
+pub use socks::is_socks_error;
+use socks::{SocksAuth, SocksOption, SocksTransport};
+use stream::ProxyWriteHalf;
+pub use stream::{DatagramRecvHalf, DatagramSendHalf, ProxyStream};
+#[cfg(feature = "tls")]
+pub(crate) use websocket::base64;
+pub use websocket::{WebSocketConfig, SECURE_WEBSOCKET_PORT, WEBSOCKET_PORT};
+
+/// Represents a future returned by a `ProxyTransport`.
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// Trait for transports to the upstream. A transport speaks its own protocol to the proxy, and
+/// hands streams and datagram associations to workers, which never depend on the protocol.
+pub trait ProxyTransport: Send + Sync {
+    /// Connects to the destination in TCP through the proxy.
+    fn connect_tcp(&self, dst: SocketAddrV4) -> TransportFuture<'_, ProxyStream>;
+
+    /// Associates a local UDP port, preferably the given port or any port if it is `0`, with the
+    /// proxy. Returns the receive half, the send half and the local port.
+    fn associate_udp(
+        &self,
+        port: u16,
+    ) -> TransportFuture<'_, (DatagramRecvHalf, DatagramSendHalf, u16)>;
+
+    /// Returns the latencies of the proxy, which are recorded by all workers of the proxy.
+    fn latency(&self) -> Arc<Mutex<LatencyStats>>;
+}
 
 /// Represents the configuration of the proxy.
+#[derive(Clone, Debug)]
 pub enum ProxyConfig {
     /// Represents the SOCKS proxy configuration.
-    Socks(SocketAddrV4, SocksOption),
+    Socks(SocksTransport),
 }
 
 impl ProxyConfig {
@@ -28,7 +67,7 @@ impl ProxyConfig {
         force_associate_bind_addr: bool,
         auth: Option<(String, String)>,
     ) -> ProxyConfig {
-        ProxyConfig::Socks(
+        ProxyConfig::Socks(SocksTransport::new(
             remote,
             SocksOption::new(
                 force_associate_remote,
@@ -38,7 +77,74 @@ impl ProxyConfig {
                     None => None,
                 },
             ),
-        )
+        ))
+    }
+
+    /// Sets the authentication of the proxy. The authentication is shared by all clones of the
+    /// configuration, and applies to new connections immediately, while existing connections and
+    /// associations are kept.
+    pub fn set_auth(&self, auth: Option<(String, String)>) {
+        match self {
+            ProxyConfig::Socks(transport) => transport
+                .options()
+                .set_auth(auth.map(|(username, password)| SocksAuth::new(username, password))),
+        }
+    }
+
+    /// Returns the IPv4 addresses connected to by the proxy, which should never be redirected.
+    pub fn upstream_addrs(&self) -> Vec<Ipv4Addr> {
+        match self {
+            ProxyConfig::Socks(transport) => transport.upstream_addrs(),
+        }
+    }
+
+    /// Sets all the addresses of the proxy, including IPv6 ones. The addresses will be raced in
+    /// connecting per Happy Eyeballs (RFC 8305).
+    pub fn set_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        match self {
+            ProxyConfig::Socks(transport) => transport.options_mut().set_addrs(addrs),
+        }
+    }
+
+    /// Sets the UDP relay. If the SOCKS server does not support UDP ASSOCIATE, datagrams will be
+    /// tunneled over a TCP connection to the UDP relay through the proxy.
+    pub fn set_udp_relay(&mut self, udp_relay: SocketAddrV4) {
+        match self {
+            ProxyConfig::Socks(transport) => transport.options_mut().set_udp_relay(udp_relay),
+        }
+    }
+
+    /// Sets the WebSocket server which tunnels the connections to the proxy, for networks only
+    /// allowing HTTP out. The proxy is spoken to in binary frames of WebSocket connections to the
+    /// server instead of being connected directly. UDP ASSOCIATE is skipped, so datagrams require
+    /// the UDP relay.
+    pub fn set_websocket(&mut self, websocket: WebSocketConfig) {
+        match self {
+            ProxyConfig::Socks(transport) => transport.options_mut().set_websocket(websocket),
+        }
+    }
+}
+
+impl ProxyTransport for ProxyConfig {
+    fn connect_tcp(&self, dst: SocketAddrV4) -> TransportFuture<'_, ProxyStream> {
+        match self {
+            ProxyConfig::Socks(transport) => transport.connect_tcp(dst),
+        }
+    }
+
+    fn associate_udp(
+        &self,
+        port: u16,
+    ) -> TransportFuture<'_, (DatagramRecvHalf, DatagramSendHalf, u16)> {
+        match self {
+            ProxyConfig::Socks(transport) => transport.associate_udp(port),
+        }
+    }
+
+    fn latency(&self) -> Arc<Mutex<LatencyStats>> {
+        match self {
+            ProxyConfig::Socks(transport) => transport.latency(),
+        }
     }
 }
 
@@ -56,6 +162,19 @@ pub trait ForwardStream: Send {
     /// Closes a stream connection.
     fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
 
+    /// Resets a stream connection whose connection to the proxy died, so the source may retry.
+    fn reset(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+
+    /// Reports a write of the given size to the proxy which has been pending for the given number
+    /// of `STALL_TIMEOUT`s, which may be stuck in a path MTU blackhole.
+    fn stall(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        size: usize,
+        n: usize,
+    ) -> io::Result<()>;
+
     /// Checks the stream.
     fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize>;
 }
@@ -72,13 +191,96 @@ const MAX_RECV_ZERO: usize = 3;
 
 /// Represents the interval of a tick.
 const TICK_INTERVAL: u64 = 500;
+/// Represents the time a write to the stream is pending before it is reported as stalled.
+const STALL_TIMEOUT: u64 = 2000;
+
+/// Represents the max size of a coalesced write to the stream.
+const MAX_BATCH_SIZE: usize = 65536;
+/// Represents the size of the receive buffer of a stream in machines of low memory.
+const SMALL_STREAM_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Enumeration of ways to batch writes of a stream to the proxy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Batching {
+    /// Represents coalescing bulk streams and flushing interactive streams by classification.
+    Auto,
+    /// Represents coalescing queued segments into one write and delaying small writes.
+    Coalesce,
+    /// Represents flushing each segment immediately without delay.
+    Flush,
+}
+
+impl Display for Batching {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            Batching::Auto => "auto",
+            Batching::Coalesce => "coalesce",
+            Batching::Flush => "flush",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Batching {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Batching::Auto),
+            "coalesce" => Ok(Batching::Coalesce),
+            "flush" => Ok(Batching::Flush),
+            _ => Err(format!("invalid batching {}", s)),
+        }
+    }
+}
+
+/// Enumeration of strategies to choose the local port bound for the UDP association of a source.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum UdpPortStrategy {
+    /// Represents binding an ephemeral port, or the one restored from the UDP port mappings.
+    Ephemeral,
+    /// Represents binding the same port as the source port, and an ephemeral port if the port is
+    /// not available.
+    Preserve,
+}
+
+impl Display for UdpPortStrategy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            UdpPortStrategy::Ephemeral => "ephemeral",
+            UdpPortStrategy::Preserve => "preserve",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for UdpPortStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ephemeral" => Ok(UdpPortStrategy::Ephemeral),
+            "preserve" => Ok(UdpPortStrategy::Preserve),
+            _ => Err(format!(
+                "invalid strategy {}, please use ephemeral or preserve",
+                s
+            )),
+        }
+    }
+}
 
 /// Represents a worker of a proxied TCP stream.
 pub struct StreamWorker {
     dst: SocketAddrV4,
+    upstream: Option<SocketAddr>,
     tx_tx: UnboundedSender<Vec<u8>>,
+    is_qos: Arc<AtomicBool>,
+    batching: Arc<AtomicU8>,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
+    is_reset: Arc<AtomicBool>,
     tx_close_tx: Sender<()>,
     rx_close_tx: Sender<()>,
 }
@@ -89,16 +291,40 @@ impl StreamWorker {
         tx: Arc<Mutex<dyn ForwardStream>>,
         src: SocketAddrV4,
         dst: SocketAddrV4,
-        proxy: &ProxyConfig,
+        proxy: &dyn ProxyTransport,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::connect_to(tx, src, dst, dst, proxy, None).await
+    }
+
+    /// Opens a new `StreamWorker` which connects to the target instead of the destination. If the
+    /// keep-alive is set, the connection to the proxy is probed by TCP keep-alive after it has
+    /// been idle for the keep-alive.
+    pub async fn connect_to(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        target: SocketAddrV4,
+        proxy: &dyn ProxyTransport,
+        keepalive: Option<Duration>,
     ) -> io::Result<StreamWorker> {
         let tx_cloned = Arc::clone(&tx);
+        let tx_stall = Arc::clone(&tx);
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(remote.clone(), dst, options).await?
+        let connect = proxy.connect_tcp(target);
+        #[cfg(feature = "tracing")]
+        let connect = tracing::Instrument::instrument(
+            connect,
+            tracing::trace_span!("connect", %src, dst = %target),
+        );
+        let stream = connect.await?;
+        if keepalive.is_some() {
+            if let Err(ref e) = stream.set_keepalive(keepalive) {
+                warn!("set keep-alive: {}: {} -> {}: {}", "TCP", src, target, e);
             }
-        };
-        let stream = stream.into_inner();
+        }
+        let upstream = stream.peer_addr().ok();
+        let latency = proxy.latency();
+        let connected = Instant::now();
         let (mut stream_rx, mut stream_tx) = stream.into_split();
 
         // Open
@@ -106,17 +332,26 @@ impl StreamWorker {
 
         let (tx_tx, mut tx_rx): (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>) =
             mpsc::unbounded_channel();
+        let is_qos = Arc::new(AtomicBool::new(false));
+        let is_qos_cloned = Arc::clone(&is_qos);
+        let batching = Arc::new(AtomicU8::new(batching_to_u8(None)));
+        let batching_cloned = Arc::clone(&batching);
         let is_tx_closed = Arc::new(AtomicBool::new(false));
         let is_tx_closed_cloned = Arc::clone(&is_tx_closed);
         let is_rx_closed = Arc::new(AtomicBool::new(false));
         let is_rx_closed_cloned = Arc::clone(&is_rx_closed);
+        let is_reset = Arc::new(AtomicBool::new(false));
+        let is_reset_cloned = Arc::clone(&is_reset);
         let (tx_close_tx, mut tx_close_rx) = mpsc::channel(1);
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
 
         // Send
         tokio::spawn(async move {
+            let mut sent: usize = 0;
+            let mut class = None;
+            let mut is_nodelay = None;
             loop {
-                let is_close;
+                let mut payload;
 
                 // Select
                 {
@@ -126,29 +361,91 @@ impl StreamWorker {
                     tokio::pin!(tx_rx_fut, tx_close_rx_fut);
 
                     tokio::select! {
-                        r = tx_rx_fut => match r {
-                            Some(payload) => {
-                                match stream_tx.write_all(payload.as_slice()).await {
-                                    Ok(_) => {
-                                        debug!(
-                                            "send to proxy: {}: {} -> {} ({} Bytes)",
-                                            "TCP", 0, dst, payload.len()
-                                        );
+                        r = tx_rx_fut => payload = r,
+                        _ = tx_close_rx_fut => payload = None
+                    }
+                }
 
-                                        is_close = false
+                let is_close = match payload {
+                    Some(ref mut payload) => {
+                        // QoS
+                        let batching = u8_to_batching(batching_cloned.load(Ordering::Relaxed));
+                        if is_qos_cloned.load(Ordering::Relaxed) || batching == Some(Batching::Auto)
+                        {
+                            sent = sent.checked_add(payload.len()).unwrap_or(usize::MAX);
+                            class = Some(qos::classify(sent));
+                        }
+
+                        // Batching
+                        let (next_is_nodelay, is_coalesce) = match (batching, class) {
+                            (Some(Batching::Coalesce), _) => (Some(false), true),
+                            (Some(Batching::Flush), _) => (Some(true), false),
+                            (_, Some(class)) => (
+                                Some(class == FlowClass::Interactive),
+                                batching.is_some() && class == FlowClass::Bulk,
+                            ),
+                            (_, None) => (None, false),
+                        };
+                        if next_is_nodelay.is_some() && is_nodelay != next_is_nodelay {
+                            let _ = stream_tx.set_nodelay(next_is_nodelay.unwrap());
+                            is_nodelay = next_is_nodelay;
+                        }
+                        if is_coalesce {
+                            // Coalesce segments which have been queued into one write
+                            while payload.len() < MAX_BATCH_SIZE {
+                                tokio::select! {
+                                    biased;
+                                    r = tx_rx.recv() => match r {
+                                        Some(next_payload) => payload.extend(next_payload),
+                                        None => break
                                     },
-                                    Err(ref e) => {
-                                        warn!("handle send: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                                    _ = async {} => break
+                                }
+                            }
+                        }
 
-                                        is_close = true
+                        // Report the write if it stalls
+                        let size = payload.len();
+                        let write_fut = stream_tx.write_all(payload.as_slice());
+                        tokio::pin!(write_fut);
+                        let mut stalls: usize = 0;
+                        let result = loop {
+                            match time::timeout(
+                                Duration::from_millis(STALL_TIMEOUT),
+                                &mut write_fut,
+                            )
+                            .await
+                            {
+                                Ok(result) => break result,
+                                Err(_) => {
+                                    stalls = stalls.checked_add(1).unwrap_or(usize::MAX);
+                                    if let Err(ref e) =
+                                        tx_stall.lock().unwrap().stall(dst, src, size, stalls)
+                                    {
+                                        warn!("handle stall: {}: {} -> {}: {}", "TCP", 0, dst, e);
                                     }
-                                };
+                                }
                             }
-                            None => is_close = true
-                        },
-                        _ = tx_close_rx_fut => is_close = true
+                        };
+
+                        match result {
+                            Ok(_) => {
+                                debug!(
+                                    "send to proxy: {}: {} -> {} ({} Bytes)",
+                                    "TCP", 0, dst, size
+                                );
+
+                                false
+                            }
+                            Err(ref e) => {
+                                warn!("handle send: {}: {} -> {}: {}", "TCP", 0, dst, e);
+
+                                true
+                            }
+                        }
                     }
-                }
+                    None => true,
+                };
 
                 if is_close {
                     // Close
@@ -156,13 +453,20 @@ impl StreamWorker {
                     trace!("close stream TX {} -> {}", 0, dst);
                     break;
                 }
+
+                // Yield to interactive flows
+                if class == Some(FlowClass::Bulk) {
+                    tokio::task::yield_now().await;
+                }
             }
         });
 
         // Receive
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; u16::MAX as usize];
+            let mut buffer = vec![0u8; stream_buffer_size()];
             let mut recv_zero: usize = 0;
+            let mut is_first = true;
+            let mut is_reset = false;
             loop {
                 let size;
 
@@ -199,6 +503,7 @@ impl StreamWorker {
 
                                 warn!("receive from proxy: {}: {} -> {}: {}", "TCP", dst, 0, e);
 
+                                is_reset = true;
                                 size = 0;
                             }
                         },
@@ -207,6 +512,17 @@ impl StreamWorker {
                 }
 
                 if size > 0 {
+                    // Latency
+                    if is_first {
+                        if let Some(upstream) = upstream {
+                            latency
+                                .lock()
+                                .unwrap()
+                                .add_first_byte(upstream, connected.elapsed());
+                        }
+                        is_first = false;
+                    }
+
                     // Loop until the data was transferred to the forwarder
                     let mut is_sent = false;
                     loop {
@@ -241,6 +557,17 @@ impl StreamWorker {
                             time::sleep(Duration::from_millis(QUEUE_FULL_WAIT)).await;
                         }
                     }
+                } else if is_reset {
+                    // Reset
+                    is_rx_closed_cloned.store(true, Ordering::Relaxed);
+                    is_reset_cloned.store(true, Ordering::Relaxed);
+                    trace!("reset stream RX {} -> {}", dst, 0);
+
+                    if let Err(ref e) = tx.lock().unwrap().reset(dst, src) {
+                        warn!("handle reset: {}: {} -> {}: {}", "TCP", dst, 0, e);
+                    }
+
+                    break;
                 } else {
                     // Close
                     is_rx_closed_cloned.store(true, Ordering::Relaxed);
@@ -273,14 +600,37 @@ impl StreamWorker {
 
         Ok(StreamWorker {
             dst,
+            upstream,
             tx_tx,
+            is_qos,
+            batching,
             is_tx_closed,
             is_rx_closed,
+            is_reset,
             tx_close_tx,
             rx_close_tx,
         })
     }
 
+    /// Returns the address of the proxy the worker is connected to.
+    pub fn upstream(&self) -> Option<SocketAddr> {
+        self.upstream
+    }
+
+    /// Sets if the worker should be classified for QoS. An interactive worker sends without
+    /// delay, and a bulk worker yields to others after each send.
+    pub fn set_qos(&mut self, is_qos: bool) {
+        self.is_qos.store(is_qos, Ordering::Relaxed);
+        trace!("set stream {} -> {} QoS to {}", 0, self.dst, is_qos);
+    }
+
+    /// Sets how the worker batches writes to the proxy, overriding the classification for QoS.
+    pub fn set_batching(&mut self, batching: Batching) {
+        self.batching
+            .store(batching_to_u8(Some(batching)), Ordering::Relaxed);
+        trace!("set stream {} -> {} batching to {}", 0, self.dst, batching);
+    }
+
     /// Sends data on the proxied stream in TCP to the destination.
     pub fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
         // Send
@@ -325,6 +675,11 @@ impl StreamWorker {
     pub fn is_rx_closed(&self) -> bool {
         self.is_rx_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns if the worker was reset because the connection to the proxy died.
+    pub fn is_reset(&self) -> bool {
+        self.is_reset.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for StreamWorker {
@@ -338,9 +693,10 @@ impl Drop for StreamWorker {
 /// not require the ownership of the sent payload, but have to wait until the payload was sent.
 pub struct StreamWorker2 {
     dst: SocketAddrV4,
-    stream_tx: Option<OwnedWriteHalf>,
+    stream_tx: Option<ProxyWriteHalf>,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
+    is_reset: Arc<AtomicBool>,
     rx_close_tx: Sender<()>,
 }
 
@@ -350,16 +706,18 @@ impl StreamWorker2 {
         tx: Arc<Mutex<dyn ForwardStream>>,
         src: SocketAddrV4,
         dst: SocketAddrV4,
-        proxy: &ProxyConfig,
+        proxy: &dyn ProxyTransport,
     ) -> io::Result<StreamWorker2> {
         let tx_cloned = Arc::clone(&tx);
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(remote.clone(), dst, options).await?
-            }
-        };
-        let stream = stream.into_inner();
+        let connect = proxy.connect_tcp(dst);
+        #[cfg(feature = "tracing")]
+        let connect =
+            tracing::Instrument::instrument(connect, tracing::trace_span!("connect", %src, %dst));
+        let stream = connect.await?;
+        let upstream = stream.peer_addr().ok();
+        let latency = proxy.latency();
+        let connected = Instant::now();
         let (mut stream_rx, stream_tx) = stream.into_split();
 
         // Open
@@ -368,12 +726,16 @@ impl StreamWorker2 {
         let is_tx_closed = Arc::new(AtomicBool::new(false));
         let is_rx_closed = Arc::new(AtomicBool::new(false));
         let is_rx_closed_cloned = Arc::clone(&is_rx_closed);
+        let is_reset = Arc::new(AtomicBool::new(false));
+        let is_reset_cloned = Arc::clone(&is_reset);
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
 
         // Receive
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; u16::MAX as usize];
+            let mut buffer = vec![0u8; stream_buffer_size()];
             let mut recv_zero: usize = 0;
+            let mut is_first = true;
+            let mut is_reset = false;
             loop {
                 let size;
 
@@ -410,6 +772,7 @@ impl StreamWorker2 {
 
                                 warn!("receive from proxy: {}: {} -> {}: {}", "TCP", dst, 0, e);
 
+                                is_reset = true;
                                 size = 0;
                             }
                         },
@@ -418,9 +781,31 @@ impl StreamWorker2 {
                 }
 
                 if size > 0 {
+                    // Latency
+                    if is_first {
+                        if let Some(upstream) = upstream {
+                            latency
+                                .lock()
+                                .unwrap()
+                                .add_first_byte(upstream, connected.elapsed());
+                        }
+                        is_first = false;
+                    }
+
                     if let Err(ref e) = tx.lock().unwrap().forward(dst, src, &buffer[..size]) {
                         warn!("handle receive: {}: {} -> {}: {}", "TCP", dst, 0, e);
                     }
+                } else if is_reset {
+                    // Reset
+                    is_rx_closed_cloned.store(true, Ordering::Relaxed);
+                    is_reset_cloned.store(true, Ordering::Relaxed);
+                    trace!("reset stream RX {} -> {}", dst, 0);
+
+                    if let Err(ref e) = tx.lock().unwrap().reset(dst, src) {
+                        warn!("handle reset: {}: {} -> {}: {}", "TCP", dst, 0, e);
+                    }
+
+                    break;
                 } else {
                     // Close
                     is_rx_closed_cloned.store(true, Ordering::Relaxed);
@@ -456,6 +841,7 @@ impl StreamWorker2 {
             stream_tx: Some(stream_tx),
             is_tx_closed,
             is_rx_closed,
+            is_reset,
             rx_close_tx,
         })
     }
@@ -514,6 +900,11 @@ impl StreamWorker2 {
     pub fn is_rx_closed(&self) -> bool {
         self.is_rx_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns if the worker was reset because the connection to the proxy died.
+    pub fn is_reset(&self) -> bool {
+        self.is_reset.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for StreamWorker2 {
@@ -534,9 +925,12 @@ pub struct DatagramWorker {
     src: Arc<AtomicU64>,
     local_port: u16,
     tx_tx: UnboundedSender<(Vec<u8>, SocketAddrV4)>,
+    keepalive: Arc<AtomicU64>,
     is_closed: Arc<AtomicBool>,
     close_tx: Sender<()>,
     close_tx2: Sender<()>,
+    #[cfg(unix)]
+    fds: Option<(RawFd, RawFd)>,
 }
 
 impl DatagramWorker {
@@ -544,12 +938,61 @@ impl DatagramWorker {
     pub async fn bind(
         tx: Arc<Mutex<dyn ForwardDatagram>>,
         src: SocketAddrV4,
-        proxy: &ProxyConfig,
+        proxy: &dyn ProxyTransport,
     ) -> io::Result<(DatagramWorker, u16)> {
-        let (mut socks_rx, mut socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
-        };
+        DatagramWorker::bind_to(tx, src, 0, proxy).await
+    }
+
+    /// Creates a new `DatagramWorker` which binds the given local port if it is available.
+    pub async fn bind_to(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        src: SocketAddrV4,
+        port: u16,
+        proxy: &dyn ProxyTransport,
+    ) -> io::Result<(DatagramWorker, u16)> {
+        let associate = proxy.associate_udp(port);
+        #[cfg(feature = "tracing")]
+        let associate =
+            tracing::Instrument::instrument(associate, tracing::trace_span!("associate", %src));
+        let (socks_rx, socks_tx, local_port) = associate.await?;
+
+        Ok((
+            DatagramWorker::spawn(tx, src, socks_rx, socks_tx, local_port),
+            local_port,
+        ))
+    }
+
+    /// Creates a new `DatagramWorker` from a SOCKS UDP association of the TCP connection it was
+    /// requested on and its UDP socket, like the ones handed over by another process.
+    pub fn from_std(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        src: SocketAddrV4,
+        stream: std::net::TcpStream,
+        socket: std::net::UdpSocket,
+    ) -> io::Result<(DatagramWorker, u16)> {
+        let (socks_rx, socks_tx, local_port) = socks::from_std(stream, socket)?;
+
+        Ok((
+            DatagramWorker::spawn(
+                tx,
+                src,
+                DatagramRecvHalf::Socks(socks_rx),
+                DatagramSendHalf::Socks(socks_tx),
+                local_port,
+            ),
+            local_port,
+        ))
+    }
 
+    fn spawn(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        src: SocketAddrV4,
+        mut socks_rx: DatagramRecvHalf,
+        mut socks_tx: DatagramSendHalf,
+        local_port: u16,
+    ) -> DatagramWorker {
+        #[cfg(unix)]
+        let fds = socks_rx.raw_fds();
         let (tx_tx, mut tx_rx): (
             UnboundedSender<(Vec<u8>, SocketAddrV4)>,
             UnboundedReceiver<(Vec<u8>, SocketAddrV4)>,
@@ -558,20 +1001,28 @@ impl DatagramWorker {
         let a_src_cloned = Arc::clone(&a_src);
         let is_closed = Arc::new(AtomicBool::new(false));
         let is_closed_cloned = Arc::clone(&is_closed);
+        let keepalive = Arc::new(AtomicU64::new(0));
+        let keepalive_cloned = Arc::clone(&keepalive);
         let (close_tx, mut close_rx) = mpsc::channel(1);
+        let close_tx_cloned = close_tx.clone();
         let (close_tx2, mut close_rx2) = mpsc::channel(1);
 
         // Send
         tokio::spawn(async move {
+            let mut last_sent = Instant::now();
+            let mut last_dst = None;
             loop {
                 let is_close;
 
                 // Select
                 {
+                    let interval = keepalive_cloned.load(Ordering::Relaxed);
+                    let wait = Duration::from_millis(interval).checked_sub(last_sent.elapsed());
                     let tx_rx_fut = tx_rx.recv();
                     let close_rx_fut = close_rx.recv();
+                    let keepalive_fut = time::sleep(wait.unwrap_or_default());
 
-                    tokio::pin!(tx_rx_fut, close_rx_fut);
+                    tokio::pin!(tx_rx_fut, close_rx_fut, keepalive_fut);
 
                     tokio::select! {
                         r = tx_rx_fut => match r {
@@ -587,10 +1038,26 @@ impl DatagramWorker {
                                         warn!("handle send: {}: {} -> {}: {}", "UDP", local_port, dst, e);
                                     }
                                 }
+                                last_sent = Instant::now();
+                                last_dst = Some(dst);
                                 is_close = false;
                             }
                             None => is_close = false
                         },
+                        _ = keepalive_fut, if interval > 0 && last_dst.is_some() => {
+                            // Keep the association alive with an empty datagram
+                            let dst = last_dst.unwrap();
+                            match socks_tx.send_to(&[], dst).await {
+                                Ok(_) => {
+                                    trace!("keep alive datagram {} -> {}", local_port, dst);
+                                },
+                                Err(ref e) => {
+                                    warn!("handle send: {}: {} -> {}: {}", "UDP", local_port, dst, e);
+                                }
+                            }
+                            last_sent = Instant::now();
+                            is_close = false;
+                        },
                         _ = close_rx_fut => is_close = true
                     }
                 }
@@ -670,6 +1137,9 @@ impl DatagramWorker {
                         u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed))
                     );
 
+                    // Release the local port in the send half
+                    let _ = close_tx_cloned.try_send(());
+
                     break;
                 }
             }
@@ -677,17 +1147,17 @@ impl DatagramWorker {
 
         trace!("create datagram {} = {}", src, local_port);
 
-        Ok((
-            DatagramWorker {
-                src: a_src,
-                local_port,
-                tx_tx,
-                is_closed,
-                close_tx,
-                close_tx2,
-            },
+        DatagramWorker {
+            src: a_src,
             local_port,
-        ))
+            tx_tx,
+            keepalive,
+            is_closed,
+            close_tx,
+            close_tx2,
+            #[cfg(unix)]
+            fds,
+        }
     }
 
     /// Sends data on the proxied datagram in UDP to the destination.
@@ -712,10 +1182,31 @@ impl DatagramWorker {
         u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
     }
 
-    /// Returns if the worker is closed.
+    /// Sets the interval of keep-alive datagrams in milliseconds. An empty datagram will be sent to
+    /// the last destination if nothing was sent during the interval, so middleboxes between
+    /// pcap2socks and the proxy do not expire the mapping of the association. 0 disables it.
+    pub fn set_keepalive(&mut self, interval: u64) {
+        self.keepalive.store(interval, Ordering::Relaxed);
+        trace!(
+            "set datagram {} keep-alive to {}",
+            self.local_port,
+            interval
+        );
+    }
+
+    /// Returns if the worker is closed, where the association was terminated by the proxy or the
+    /// connection to the proxy died.
     pub fn is_closed(&self) -> bool {
         self.is_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns the descriptors of the TCP connection the association was requested on and its UDP
+    /// socket, which stay open as long as the worker. Associations tunneled over TCP cannot be
+    /// handed over to another process, and return `None`.
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Option<(RawFd, RawFd)> {
+        self.fds
+    }
 }
 
 impl Drop for DatagramWorker {
@@ -732,7 +1223,7 @@ impl Drop for DatagramWorker {
 pub struct DatagramWorker2 {
     src: Arc<AtomicU64>,
     local_port: u16,
-    socks_tx: SocksSendHalf,
+    socks_tx: DatagramSendHalf,
     is_closed: Arc<AtomicBool>,
     close_tx: Sender<()>,
 }
@@ -742,11 +1233,13 @@ impl DatagramWorker2 {
     pub async fn bind(
         tx: Arc<Mutex<dyn ForwardDatagram>>,
         src: SocketAddrV4,
-        proxy: &ProxyConfig,
+        proxy: &dyn ProxyTransport,
     ) -> io::Result<(DatagramWorker2, u16)> {
-        let (mut socks_rx, socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
-        };
+        let associate = proxy.associate_udp(0);
+        #[cfg(feature = "tracing")]
+        let associate =
+            tracing::Instrument::instrument(associate, tracing::trace_span!("associate", %src));
+        let (mut socks_rx, socks_tx, local_port) = associate.await?;
 
         let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
         let a_src_cloned = Arc::clone(&a_src);
@@ -878,6 +1371,91 @@ impl Drop for DatagramWorker2 {
     }
 }
 
+fn stream_buffer_size() -> usize {
+    match crate::is_low_memory() {
+        true => SMALL_STREAM_BUFFER_SIZE,
+        false => u16::MAX as usize,
+    }
+}
+
+/// Represents the timeout of a DNS query over TCP.
+#[cfg(feature = "dns")]
+pub(crate) const DNS_TIMEOUT: u64 = 5000;
+
+/// Resolves a DNS query over TCP through the proxy.
+#[cfg(feature = "dns")]
+pub async fn resolve_tcp(
+    proxy: &dyn ProxyTransport,
+    server: SocketAddrV4,
+    query: &[u8],
+) -> io::Result<Vec<u8>> {
+    let fut = async {
+        let mut stream = BufStream::new(proxy.connect_tcp(server).await?);
+
+        exchange_tcp(&mut stream, server, query).await
+    };
+
+    match time::timeout(Duration::from_millis(DNS_TIMEOUT), fut).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    }
+}
+
+/// Exchanges a DNS query with the server over the stream, where messages are prefixed by their
+/// lengths as in DNS over TCP (RFC 7766).
+#[cfg(feature = "dns")]
+pub(crate) async fn exchange_tcp<S>(
+    stream: &mut S,
+    server: SocketAddrV4,
+    query: &[u8],
+) -> io::Result<Vec<u8>>
+where
+    S: io::AsyncRead + io::AsyncWrite + Unpin,
+{
+    // Query
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+    debug!(
+        "send to proxy: {}: {} -> {} ({} Bytes)",
+        "DNS",
+        0,
+        server,
+        query.len()
+    );
+
+    // Response
+    let size = stream.read_u16().await? as usize;
+    let mut response = vec![0u8; size];
+    stream.read_exact(&mut response).await?;
+    debug!(
+        "receive from proxy: {}: {} -> {} ({} Bytes)",
+        "DNS", server, 0, size
+    );
+
+    Ok(response)
+}
+
+fn batching_to_u8(batching: Option<Batching>) -> u8 {
+    match batching {
+        None => 0,
+        Some(Batching::Auto) => 1,
+        Some(Batching::Coalesce) => 2,
+        Some(Batching::Flush) => 3,
+    }
+}
+
+fn u8_to_batching(v: u8) -> Option<Batching> {
+    match v {
+        1 => Some(Batching::Auto),
+        2 => Some(Batching::Coalesce),
+        3 => Some(Batching::Flush),
+        _ => None,
+    }
+}
+
 fn socket_addr_v4_to_u64(addr: &SocketAddrV4) -> u64 {
     let ip = u32::from(addr.ip().clone());
 
@@ -891,3 +1469,90 @@ fn u64_to_socket_addr_v4(v: u64) -> SocketAddrV4 {
 
     SocketAddrV4::new(ip, port)
 }
+
+#[tokio::test]
+async fn stream_worker_reset() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[derive(Default)]
+    struct Resets {
+        resets: Vec<(SocketAddrV4, SocketAddrV4)>,
+    }
+
+    impl ForwardStream for Resets {
+        fn open(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn tick(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reset(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+            self.resets.push((dst, src));
+
+            Ok(())
+        }
+
+        fn stall(
+            &mut self,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            _: usize,
+            _: usize,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn check(&self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<usize> {
+            Ok(usize::MAX)
+        }
+    }
+
+    // A SOCKS5 server whose connection dies with a RST after the handshake
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).unwrap();
+        stream.write_all(&[5, 0]).unwrap();
+        stream.read_exact(&mut buffer).unwrap();
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let socket = socket2::Socket::from(stream);
+        socket.set_linger(Some(Duration::from_secs(0))).unwrap();
+    });
+
+    let tx = Arc::new(Mutex::new(Resets::default()));
+    let src = "10.6.0.1:1000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let config = ProxyConfig::new_socks(proxy, false, false, None);
+    let worker = StreamWorker::connect_to(tx.clone(), src, dst, dst, &config, None)
+        .await
+        .unwrap();
+    for _ in 0..50 {
+        if worker.is_reset() {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    assert!(worker.is_reset());
+    assert!(worker.is_rx_closed());
+    assert_eq!(tx.lock().unwrap().resets, vec![(dst, src)]);
+}
@@ -0,0 +1,261 @@
+//! Support for testing proxies without capturing.
+
+use log::{debug, trace};
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::ProxyTransport;
+
+/// Represents the timeout of waiting for data from the test endpoint.
+const PROBE_TIMEOUT: u64 = 2000;
+/// Represents the size of each write in testing the TCP throughput.
+const CHUNK_SIZE: usize = 16384;
+
+/// Represents the report of testing a proxy in TCP.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TcpReport {
+    handshake: Duration,
+    sent: usize,
+    upload: Duration,
+    received: usize,
+    download: Duration,
+}
+
+impl TcpReport {
+    /// Returns the time of connecting to the test endpoint through the proxy, including the SOCKS
+    /// handshake.
+    pub fn handshake(&self) -> Duration {
+        self.handshake
+    }
+
+    /// Returns the size of data sent to the test endpoint.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the upload throughput in bits per second.
+    pub fn upload_rate(&self) -> f64 {
+        rate(self.sent, self.upload)
+    }
+
+    /// Returns the size of data received from the test endpoint.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Returns the download throughput in bits per second.
+    pub fn download_rate(&self) -> f64 {
+        rate(self.received, self.download)
+    }
+}
+
+impl Display for TcpReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "handshake {:.0} ms, upload {} Bytes ({:.2} Mbps), download {} Bytes ({:.2} Mbps)",
+            self.handshake.as_secs_f64() * 1000.0,
+            self.sent,
+            self.upload_rate() / 1_000_000.0,
+            self.received,
+            self.download_rate() / 1_000_000.0
+        )
+    }
+}
+
+/// Represents the report of testing a proxy in UDP.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct UdpReport {
+    associate: Duration,
+    rtts: Vec<Option<Duration>>,
+}
+
+impl UdpReport {
+    /// Returns the time of UDP ASSOCIATE.
+    pub fn associate(&self) -> Duration {
+        self.associate
+    }
+
+    /// Returns the round-trip time of each datagram, or `None` if the datagram was lost.
+    pub fn rtts(&self) -> &[Option<Duration>] {
+        self.rtts.as_slice()
+    }
+
+    /// Returns the number of lost datagrams.
+    pub fn lost(&self) -> usize {
+        self.rtts.iter().filter(|rtt| rtt.is_none()).count()
+    }
+}
+
+impl Display for UdpReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let rtts = self.rtts.iter().filter_map(|rtt| *rtt).collect::<Vec<_>>();
+        write!(
+            f,
+            "associate {:.0} ms, {} datagrams ({} lost)",
+            self.associate.as_secs_f64() * 1000.0,
+            self.rtts.len(),
+            self.lost()
+        )?;
+        if !rtts.is_empty() {
+            let sum: Duration = rtts.iter().sum();
+            write!(
+                f,
+                ", RTT min {:.0} ms, avg {:.0} ms, max {:.0} ms",
+                rtts.iter().min().unwrap().as_secs_f64() * 1000.0,
+                sum.as_secs_f64() * 1000.0 / rtts.len() as f64,
+                rtts.iter().max().unwrap().as_secs_f64() * 1000.0
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn rate(size: usize, duration: Duration) -> f64 {
+    if duration.as_secs_f64() == 0.0 {
+        0.0
+    } else {
+        size as f64 * 8.0 / duration.as_secs_f64()
+    }
+}
+
+/// Tests if the proxy is reachable by connecting to it in TCP, and returns the time of connecting.
+pub async fn probe_reachable(addr: SocketAddr) -> io::Result<Duration> {
+    let instant = Instant::now();
+    match time::timeout(
+        Duration::from_millis(PROBE_TIMEOUT),
+        TcpStream::connect(addr),
+    )
+    .await
+    {
+        Ok(Ok(_)) => {
+            let duration = instant.elapsed();
+            trace!("probe {}: connect in {:?}", addr, duration);
+
+            Ok(duration)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    }
+}
+
+/// Tests the proxy in TCP. The given size of data will be sent to the test endpoint, and all the
+/// data replied will be received until the endpoint closes the connection or is idle for a while,
+/// so the endpoint should be an echo (RFC 862) or a discard (RFC 863) server.
+pub async fn probe_tcp(
+    proxy: &dyn ProxyTransport,
+    target: SocketAddrV4,
+    size: usize,
+) -> io::Result<TcpReport> {
+    // Handshake
+    let instant = Instant::now();
+    let stream = proxy.connect_tcp(target).await?;
+    let handshake = instant.elapsed();
+    trace!("probe TCP {}: handshake in {:?}", target, handshake);
+    let (mut stream_rx, mut stream_tx) = stream.into_split();
+
+    // Transfer
+    let instant = Instant::now();
+    let upload_fut = async {
+        let buffer = vec![0u8; CHUNK_SIZE];
+        let mut sent = 0;
+        while sent < size {
+            let n = std::cmp::min(CHUNK_SIZE, size - sent);
+            stream_tx.write_all(&buffer[..n]).await?;
+            sent += n;
+        }
+        stream_tx.shutdown().await?;
+
+        Ok::<_, io::Error>(instant.elapsed())
+    };
+    let download_fut = async {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let mut received = 0;
+        let mut download = Duration::default();
+        loop {
+            match time::timeout(
+                Duration::from_millis(PROBE_TIMEOUT),
+                stream_rx.read(&mut buffer),
+            )
+            .await
+            {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(n)) => {
+                    received += n;
+                    download = instant.elapsed();
+                }
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+
+        Ok((received, download))
+    };
+    let (upload, download) = tokio::join!(upload_fut, download_fut);
+    let upload = upload?;
+    let (received, download) = download?;
+    debug!(
+        "probe TCP {}: sent {} Bytes, received {} Bytes",
+        target, size, received
+    );
+
+    Ok(TcpReport {
+        handshake,
+        sent: size,
+        upload,
+        received,
+        download,
+    })
+}
+
+/// Tests the proxy in UDP. The given number of datagrams will be sent to the test endpoint one by
+/// one, and each should be echoed before the next is sent, so the endpoint should be an echo
+/// (RFC 862) server.
+pub async fn probe_udp(
+    proxy: &dyn ProxyTransport,
+    target: SocketAddrV4,
+    count: usize,
+) -> io::Result<UdpReport> {
+    // Associate
+    let instant = Instant::now();
+    let (mut socket_rx, mut socket_tx, _) = proxy.associate_udp(0).await?;
+    let associate = instant.elapsed();
+    trace!("probe UDP {}: associate in {:?}", target, associate);
+
+    // Ping
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let mut rtts = Vec::new();
+    for i in 0..count {
+        let payload = format!("pcap2socks probe {}", i);
+        let instant = Instant::now();
+        socket_tx.send_to(payload.as_bytes(), target).await?;
+
+        let deadline = instant + Duration::from_millis(PROBE_TIMEOUT);
+        let mut rtt = None;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match time::timeout(deadline - now, socket_rx.recv_from(&mut buffer)).await {
+                Ok(Ok((size, _))) => {
+                    // Drop late replies of previous datagrams
+                    if &buffer[..size] == payload.as_bytes() {
+                        rtt = Some(instant.elapsed());
+                        break;
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        debug!("probe UDP {}: datagram {} RTT {:?}", target, i, rtt);
+        rtts.push(rtt);
+    }
+
+    Ok(UdpReport { associate, rtts })
+}
@@ -1,9 +1,22 @@
 use async_socks5::{self, AddrKind, Auth};
-use log::trace;
+use log::{trace, warn};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
-use tokio::io::{self, BufStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufStream};
 use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time;
+
+use super::stream::{
+    DatagramRecvHalf, DatagramSendHalf, ProxyReadHalf, ProxyStream, ProxyWriteHalf,
+};
+use super::websocket::{self, WebSocketConfig};
+use super::{ProxyTransport, TransportFuture};
+use crate::stats::LatencyStats;
 
 /// Represents the username and the password of the authentication connecting to a SOCKS5 server.
 #[derive(Clone, Debug)]
@@ -24,7 +37,14 @@ impl SocksAuth {
 pub struct SocksOption {
     force_associate_remote: bool,
     force_associate_bind_addr: bool,
-    auth: Option<SocksAuth>,
+    /// Represents the authentication, shared by all clones of the options, so it can be rotated
+    /// without recreating workers.
+    auth: Arc<Mutex<Option<SocksAuth>>>,
+    addrs: Vec<SocketAddr>,
+    udp_relay: Option<SocketAddrV4>,
+    websocket: Option<WebSocketConfig>,
+    is_associate_unsupported: Arc<AtomicBool>,
+    latency: Arc<Mutex<LatencyStats>>,
 }
 
 impl SocksOption {
@@ -37,25 +57,137 @@ impl SocksOption {
         SocksOption {
             force_associate_remote,
             force_associate_bind_addr: force_associate_bind_addr,
-            auth,
+            auth: Arc::new(Mutex::new(auth)),
+            addrs: Vec::new(),
+            udp_relay: None,
+            websocket: None,
+            is_associate_unsupported: Arc::new(AtomicBool::new(false)),
+            latency: Arc::new(Mutex::new(LatencyStats::new())),
         }
     }
 
+    /// Sets the UDP relay which datagrams will be tunneled over TCP to if the SOCKS5 server does
+    /// not support UDP ASSOCIATE.
+    pub fn set_udp_relay(&mut self, udp_relay: SocketAddrV4) {
+        self.udp_relay = Some(udp_relay);
+    }
+
+    /// Sets the WebSocket server which tunnels the connections to the SOCKS5 server. The SOCKS5
+    /// server will not be connected directly, and datagrams can only be tunneled over TCP to the
+    /// UDP relay.
+    pub fn set_websocket(&mut self, websocket: WebSocketConfig) {
+        self.websocket = Some(websocket);
+    }
+
+    /// Sets all the addresses of the SOCKS5 server, which will be raced in connecting.
+    pub fn set_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.addrs = interleave(addrs);
+    }
+
+    /// Sets the authentication of the SOCKS5 server for all clones of the options. Only new
+    /// connections will authenticate with it.
+    pub fn set_auth(&self, auth: Option<SocksAuth>) {
+        *self.auth.lock().unwrap() = auth;
+    }
+
+    /// Returns the latencies of the SOCKS5 server, shared by all clones of the options.
+    pub fn latency(&self) -> Arc<Mutex<LatencyStats>> {
+        Arc::clone(&self.latency)
+    }
+
     fn auth(&self) -> Option<Auth> {
-        match self.auth {
+        match *self.auth.lock().unwrap() {
             Some(ref auth) => Some(Auth::new(auth.username.clone(), auth.password.clone())),
             None => None,
         }
     }
 }
 
+/// Represents a transport to a SOCKS5 server.
+#[derive(Clone, Debug)]
+pub struct SocksTransport {
+    remote: SocketAddrV4,
+    options: SocksOption,
+}
+
+impl SocksTransport {
+    /// Creates a `SocksTransport`.
+    pub fn new(remote: SocketAddrV4, options: SocksOption) -> SocksTransport {
+        SocksTransport { remote, options }
+    }
+
+    /// Returns the IPv4 addresses connected to by the transport, including the ones of the
+    /// SOCKS5 server, the UDP relay and the WebSocket server if it is not named by a hostname.
+    pub fn upstream_addrs(&self) -> Vec<Ipv4Addr> {
+        let mut addrs = vec![*self.remote.ip()];
+        for addr in self.options.addrs.iter() {
+            if let SocketAddr::V4(addr) = addr {
+                addrs.push(*addr.ip());
+            }
+        }
+        if let Some(udp_relay) = self.options.udp_relay {
+            addrs.push(*udp_relay.ip());
+        }
+        if let Some(ip_addr) = self
+            .options
+            .websocket
+            .as_ref()
+            .and_then(|websocket| websocket.ip_addr())
+        {
+            addrs.push(ip_addr);
+        }
+
+        addrs
+    }
+
+    /// Returns the options connecting to the SOCKS5 server.
+    pub fn options(&self) -> &SocksOption {
+        &self.options
+    }
+
+    /// Returns the options connecting to the SOCKS5 server.
+    pub fn options_mut(&mut self) -> &mut SocksOption {
+        &mut self.options
+    }
+}
+
+impl ProxyTransport for SocksTransport {
+    fn connect_tcp(&self, dst: SocketAddrV4) -> TransportFuture<'_, ProxyStream> {
+        Box::pin(async move {
+            let stream = connect(self.remote, dst, &self.options).await?;
+
+            Ok(stream.into_inner())
+        })
+    }
+
+    fn associate_udp(
+        &self,
+        port: u16,
+    ) -> TransportFuture<'_, (DatagramRecvHalf, DatagramSendHalf, u16)> {
+        Box::pin(async move {
+            let (rx, tx, local_port) = bind(self.remote, port, &self.options).await?;
+
+            Ok((
+                DatagramRecvHalf::Socks(rx),
+                DatagramSendHalf::Socks(tx),
+                local_port,
+            ))
+        })
+    }
+
+    fn latency(&self) -> Arc<Mutex<LatencyStats>> {
+        self.options.latency()
+    }
+}
+
 /// Connects to a target server through a SOCKS5 proxy.
 pub async fn connect(
     remote: SocketAddrV4,
     dst: SocketAddrV4,
     options: &SocksOption,
-) -> io::Result<BufStream<TcpStream>> {
-    let stream = TcpStream::connect(remote).await?;
+) -> io::Result<BufStream<ProxyStream>> {
+    let instant = Instant::now();
+    let stream = connect_remote(remote, options).await?;
     let mut stream = BufStream::new(stream);
     if let Err(e) = async_socks5::connect(&mut stream, dst, options.auth()).await {
         match e {
@@ -64,9 +196,113 @@ pub async fn connect(
         }
     }
 
+    // Latency
+    if let Ok(addr) = stream.get_ref().peer_addr() {
+        options
+            .latency
+            .lock()
+            .unwrap()
+            .add_connect(addr, instant.elapsed());
+    }
+
     Ok(stream)
 }
 
+/// Returns if the error is replied by the SOCKS5 server, rather than raised in connecting to it.
+pub fn is_socks_error(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |e| e.is::<async_socks5::Error>())
+}
+
+/// Represents the delay between connection attempts to addresses of the SOCKS5 server.
+const CONNECTION_ATTEMPT_DELAY: u64 = 250;
+
+/// Sorts addresses by interleaving the address families, starting with IPv6.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    v6.reverse();
+    v4.reverse();
+
+    let mut addrs = Vec::new();
+    while !v6.is_empty() || !v4.is_empty() {
+        if let Some(addr) = v6.pop() {
+            addrs.push(addr);
+        }
+        if let Some(addr) = v4.pop() {
+            addrs.push(addr);
+        }
+    }
+
+    addrs
+}
+
+/// Connects to the SOCKS5 server, or the WebSocket server tunneling to it. If there are multiple
+/// addresses of the SOCKS5 server, the connection attempts will be raced per Happy Eyeballs
+/// (RFC 8305), and the first established one wins.
+async fn connect_remote(remote: SocketAddrV4, options: &SocksOption) -> io::Result<ProxyStream> {
+    if let Some(ref config) = options.websocket {
+        return Ok(ProxyStream::WebSocket(websocket::connect(config).await?));
+    }
+    if options.addrs.len() <= 1 {
+        return Ok(ProxyStream::Tcp(TcpStream::connect(remote).await?));
+    }
+
+    let (tx, mut rx) = mpsc::channel(options.addrs.len());
+    let mut handles = Vec::new();
+    let mut next = 0;
+    let mut pending = 0;
+    let result = loop {
+        // Attempt
+        if next < options.addrs.len() {
+            let addr = options.addrs[next];
+            let tx = tx.clone();
+            handles.push(tokio::spawn(async move {
+                let result = TcpStream::connect(addr).await;
+                if let Err(ref e) = result {
+                    trace!("connect SOCKS5 server {}: {}", addr, e);
+                }
+                let _ = tx.send(result).await;
+            }));
+            next += 1;
+            pending += 1;
+        }
+
+        // Wait for an attempt, or the delay before the next attempt
+        let result = match next < options.addrs.len() {
+            true => {
+                match time::timeout(Duration::from_millis(CONNECTION_ATTEMPT_DELAY), rx.recv())
+                    .await
+                {
+                    Ok(result) => result,
+                    Err(_) => continue,
+                }
+            }
+            false => rx.recv().await,
+        };
+        match result {
+            Some(Ok(stream)) => break Ok(stream),
+            Some(Err(e)) => {
+                pending -= 1;
+                if pending == 0 && next >= options.addrs.len() {
+                    break Err(e);
+                }
+            }
+            None => unreachable!(),
+        }
+    };
+
+    // Cancel the other attempts
+    for handle in handles {
+        handle.abort();
+    }
+    if let Ok(ref stream) = result {
+        if let Ok(addr) = stream.peer_addr() {
+            trace!("connect SOCKS5 server {} in racing", addr);
+        }
+    }
+
+    result.map(ProxyStream::Tcp)
+}
+
 const RSV_SIZE: usize = 2;
 const FRAG_SIZE: usize = 1;
 const ATYP_SIZE: usize = 1;
@@ -76,92 +312,253 @@ const HEADER_SIZE: usize = RSV_SIZE + FRAG_SIZE + ATYP_SIZE + DST_ADDR_SIZE + DS
 
 const ATYP_IPV4: u8 = 1;
 
+/// Represents the size of the length in the framing of UDP over TCP.
+const LENGTH_SIZE: usize = 2;
+/// Represents the header size in the framing of UDP over TCP.
+const TCP_HEADER_SIZE: usize = DST_ADDR_SIZE + DST_PORT_SIZE;
+
+#[derive(Debug)]
+enum SendHalf {
+    Udp(Arc<UdpSocket>),
+    Tcp(ProxyWriteHalf),
+}
+
 /// Represents the send half of a SOCKS5 UDP client.
 #[derive(Debug)]
 pub struct SocksSendHalf {
-    stream: Arc<BufStream<TcpStream>>,
-    socket: Arc<UdpSocket>,
+    half: SendHalf,
+    /// Represents the buffer reused by datagrams, which grows to the largest datagram.
+    buffer: Vec<u8>,
 }
 
 impl SocksSendHalf {
     /// Creates a new `SocksSendHalf`.
-    pub fn new(stream: Arc<BufStream<TcpStream>>, socket: Arc<UdpSocket>) -> SocksSendHalf {
-        SocksSendHalf { stream, socket }
+    pub fn new(socket: Arc<UdpSocket>) -> SocksSendHalf {
+        SocksSendHalf {
+            half: SendHalf::Udp(socket),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Creates a new `SocksSendHalf` which tunnels datagrams over TCP.
+    pub fn new_tcp(stream: ProxyWriteHalf) -> SocksSendHalf {
+        SocksSendHalf {
+            half: SendHalf::Tcp(stream),
+            buffer: Vec::new(),
+        }
     }
 
     /// Sends data on the socket to the given address.
     pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
-        let mut buf = vec![0u8; HEADER_SIZE + payload.len()];
-        // RSV
-        // FRAG
-        // ATYP
-        buf[3] = ATYP_IPV4;
-        // DST.ADDR
-        &buf[4..8].copy_from_slice(&dst.ip().octets());
-        // DST.PORT
-        buf[8] = (dst.port() / 256) as u8;
-        buf[9] = (dst.port() % 256) as u8;
-        // Data
-        &buf[10..].copy_from_slice(payload);
+        let buf = &mut self.buffer;
+        match &mut self.half {
+            SendHalf::Udp(socket) => {
+                buf.clear();
+                buf.resize(HEADER_SIZE + payload.len(), 0);
+                // RSV
+                // FRAG
+                // ATYP
+                buf[3] = ATYP_IPV4;
+                // DST.ADDR
+                &buf[4..8].copy_from_slice(&dst.ip().octets());
+                // DST.PORT
+                buf[8] = (dst.port() / 256) as u8;
+                buf[9] = (dst.port() % 256) as u8;
+                // Data
+                &buf[10..].copy_from_slice(payload);
 
-        self.socket.send(buf.as_slice()).await
+                socket.send(buf.as_slice()).await
+            }
+            SendHalf::Tcp(stream) => {
+                let size = TCP_HEADER_SIZE + payload.len();
+                if size > u16::MAX as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "payload too large",
+                    ));
+                }
+
+                buf.clear();
+                buf.resize(LENGTH_SIZE + size, 0);
+                // Length
+                buf[0] = (size / 256) as u8;
+                buf[1] = (size % 256) as u8;
+                // DST.ADDR
+                &buf[2..6].copy_from_slice(&dst.ip().octets());
+                // DST.PORT
+                buf[6] = (dst.port() / 256) as u8;
+                buf[7] = (dst.port() % 256) as u8;
+                // Data
+                &buf[8..].copy_from_slice(payload);
+
+                stream.write_all(buf.as_slice()).await?;
+
+                Ok(buf.len())
+            }
+        }
     }
 }
 
+#[derive(Debug)]
+enum RecvHalf {
+    Udp(BufStream<ProxyStream>, Arc<UdpSocket>),
+    Tcp(BufReader<ProxyReadHalf>, UdpSocket),
+}
+
 /// Represents the receive half of a SOCKS5 UDP client.
 #[derive(Debug)]
 pub struct SocksRecvHalf {
-    stream: Arc<BufStream<TcpStream>>,
-    socket: Arc<UdpSocket>,
+    half: RecvHalf,
     buffer: Vec<u8>,
 }
 
 impl SocksRecvHalf {
-    /// Creates a new `SocksRecvHalf`.
-    pub fn new(stream: Arc<BufStream<TcpStream>>, socket: Arc<UdpSocket>) -> SocksRecvHalf {
+    /// Creates a new `SocksRecvHalf`. The stream is the TCP connection the UDP association was
+    /// requested on, and the association terminates when it closes.
+    pub fn new(stream: BufStream<ProxyStream>, socket: Arc<UdpSocket>) -> SocksRecvHalf {
+        SocksRecvHalf {
+            half: RecvHalf::Udp(stream, socket),
+            buffer: vec![0u8; u16::MAX as usize],
+        }
+    }
+
+    /// Creates a new `SocksRecvHalf` which tunnels datagrams over TCP. The socket reserves the
+    /// local port identifying the client.
+    pub fn new_tcp(stream: ProxyReadHalf, socket: UdpSocket) -> SocksRecvHalf {
         SocksRecvHalf {
-            stream,
-            socket,
+            half: RecvHalf::Tcp(BufReader::new(stream), socket),
             buffer: vec![0u8; u16::MAX as usize],
         }
     }
 
-    /// Receives a single datagram message on the socket.
+    /// Returns the descriptors of the TCP connection the UDP association was requested on and the
+    /// UDP socket, if the association is not tunneled.
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Option<(RawFd, RawFd)> {
+        match &self.half {
+            RecvHalf::Udp(stream, socket) => match stream.get_ref() {
+                ProxyStream::Tcp(stream) => Some((stream.as_raw_fd(), socket.as_raw_fd())),
+                ProxyStream::WebSocket(_) => None,
+            },
+            RecvHalf::Tcp(_, _) => None,
+        }
+    }
+
+    /// Receives a single datagram message on the socket. Returns a `ConnectionAborted` error if the
+    /// UDP association was terminated by the proxy.
     pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
-        let n = self.socket.recv(&mut self.buffer).await?;
-        // ATYP and address
-        match self.buffer[3] {
-            ATYP_IPV4 => {}
-            _ => unreachable!(),
-        }
-        let addr = SocketAddrV4::new(
-            Ipv4Addr::new(
-                self.buffer[4],
-                self.buffer[5],
-                self.buffer[6],
-                self.buffer[7],
-            ),
-            self.buffer[8] as u16 * 256 + self.buffer[9] as u16,
-        );
+        let (addr, range) = match &mut self.half {
+            RecvHalf::Udp(stream, socket) => {
+                // Watch the TCP connection of the association while receiving
+                let n = loop {
+                    let mut control = [0u8; 1];
+                    tokio::select! {
+                        r = socket.recv(&mut self.buffer) => break r?,
+                        r = stream.read(&mut control) => if r? == 0 {
+                            return Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "UDP association closed",
+                            ));
+                        }
+                    }
+                };
+                // ATYP and address
+                match self.buffer[3] {
+                    ATYP_IPV4 => {}
+                    _ => unreachable!(),
+                }
+
+                (parse_addr(&self.buffer[4..]), HEADER_SIZE..n)
+            }
+            RecvHalf::Tcp(stream, _) => {
+                let mut length = [0u8; LENGTH_SIZE];
+                stream.read_exact(&mut length).await?;
+                let n = length[0] as usize * 256 + length[1] as usize;
+                if n < TCP_HEADER_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "datagram too short",
+                    ));
+                }
+                stream.read_exact(&mut self.buffer[..n]).await?;
+
+                (parse_addr(&self.buffer), TCP_HEADER_SIZE..n)
+            }
+        };
         // Buffer
-        let size = n - HEADER_SIZE;
-        &buffer[..size].copy_from_slice(&self.buffer[HEADER_SIZE..n]);
+        let size = range.len();
+        &buffer[..size].copy_from_slice(&self.buffer[range]);
 
         Ok((size, addr))
     }
 }
 
-/// Binds a local address to a target server through a SOCKS5 proxy.
+fn parse_addr(buffer: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3]),
+        buffer[4] as u16 * 256 + buffer[5] as u16,
+    )
+}
+
+/// Binds a local UDP socket on the given port, or any port if the port is `0` or unavailable.
+async fn bind_socket(port: u16) -> io::Result<UdpSocket> {
+    if port != 0 {
+        match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(ref e) => trace!("bind UDP port {}: {}", port, e),
+        }
+    }
+
+    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await
+}
+
+/// Rebuilds a UDP association from the TCP connection it was requested on and its UDP socket,
+/// like the ones handed over by another process.
+pub fn from_std(
+    stream: std::net::TcpStream,
+    socket: std::net::UdpSocket,
+) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
+    stream.set_nonblocking(true)?;
+    socket.set_nonblocking(true)?;
+    let stream = BufStream::new(ProxyStream::Tcp(TcpStream::from_std(stream)?));
+    let socket = UdpSocket::from_std(socket)?;
+    let local_port = socket.local_addr()?.port();
+
+    let a_socket = Arc::new(socket);
+    let a_socket_cloned = Arc::clone(&a_socket);
+
+    Ok((
+        SocksRecvHalf::new(stream, a_socket),
+        SocksSendHalf::new(a_socket_cloned),
+        local_port,
+    ))
+}
+
+/// Binds a local address, preferably on the given port, to a target server through a SOCKS5
+/// proxy. If the SOCKS5 server does not support UDP ASSOCIATE or is tunneled in WebSocket, and a
+/// UDP relay is set, datagrams will be tunneled over TCP to the UDP relay instead.
 pub async fn bind(
     remote: SocketAddrV4,
+    port: u16,
     options: &SocksOption,
 ) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
+    if let Some(udp_relay) = options.udp_relay {
+        if options.websocket.is_some() || options.is_associate_unsupported.load(Ordering::Relaxed) {
+            return bind_tcp(remote, udp_relay, port, options).await;
+        }
+    }
+    if options.websocket.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "UDP ASSOCIATE is not supported over WebSocket",
+        ));
+    }
+
     // Connect
-    let stream = TcpStream::connect(remote).await?;
+    let stream = connect_remote(remote, options).await?;
     let stream = BufStream::new(stream);
 
-    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let socket = UdpSocket::bind(local).await?;
+    let socket = bind_socket(port).await?;
     let local_port = socket.local_addr().unwrap().port();
     let datagram = match async_socks5::SocksDatagram::associate::<SocketAddrV4>(
         stream,
@@ -174,7 +571,19 @@ pub async fn bind(
         Ok(datagram) => datagram,
         Err(e) => match e {
             async_socks5::Error::Io(e) => return Err(e),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            _ => match options.udp_relay {
+                Some(udp_relay) => {
+                    if !options
+                        .is_associate_unsupported
+                        .swap(true, Ordering::Relaxed)
+                    {
+                        warn!("UDP ASSOCIATE is not supported by the proxy: {}, tunnel UDP over TCP to {} with extra latency", e, udp_relay);
+                    }
+
+                    return bind_tcp(remote, udp_relay, port, options).await;
+                }
+                None => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            },
         },
     };
 
@@ -207,15 +616,174 @@ pub async fn bind(
         );
     }
 
-    let a_stream = Arc::new(stream);
-    let a_stream_cloned = Arc::clone(&a_stream);
-
     let a_socket = Arc::new(socket);
     let a_socket_cloned = Arc::clone(&a_socket);
 
     Ok((
-        SocksRecvHalf::new(a_stream, a_socket),
-        SocksSendHalf::new(a_stream_cloned, a_socket_cloned),
+        SocksRecvHalf::new(stream, a_socket),
+        SocksSendHalf::new(a_socket_cloned),
         local_port,
     ))
 }
+
+/// Binds a local address to a target server by tunneling datagrams over a TCP connection to the
+/// UDP relay through a SOCKS5 proxy.
+async fn bind_tcp(
+    remote: SocketAddrV4,
+    udp_relay: SocketAddrV4,
+    port: u16,
+    options: &SocksOption,
+) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
+    let stream = connect(remote, udp_relay, options).await?;
+    let (stream_rx, stream_tx) = stream.into_inner().into_split();
+
+    // Reserve a local port identifying the client
+    let socket = bind_socket(port).await?;
+    let local_port = socket.local_addr().unwrap().port();
+
+    trace!("tunnel UDP port {} over TCP to {}", local_port, udp_relay);
+
+    Ok((
+        SocksRecvHalf::new_tcp(stream_rx, socket),
+        SocksSendHalf::new_tcp(stream_tx),
+        local_port,
+    ))
+}
+
+#[test]
+fn interleave_addrs() {
+    let addrs = vec![
+        "10.0.0.1:1080".parse().unwrap(),
+        "10.0.0.2:1080".parse().unwrap(),
+        "10.0.0.3:1080".parse().unwrap(),
+        "[fd00::1]:1080".parse().unwrap(),
+    ];
+    let expected: Vec<SocketAddr> = vec![
+        "[fd00::1]:1080".parse().unwrap(),
+        "10.0.0.1:1080".parse().unwrap(),
+        "10.0.0.2:1080".parse().unwrap(),
+        "10.0.0.3:1080".parse().unwrap(),
+    ];
+    assert_eq!(interleave(addrs), expected);
+}
+
+#[tokio::test]
+async fn bind_udp_over_tcp() {
+    use tokio::net::TcpListener;
+
+    async fn accept(stream: &mut TcpStream) {
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).await.unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    tokio::spawn(async move {
+        // Command not supported
+        let (mut stream, _) = listener.accept().await.unwrap();
+        accept(&mut stream).await;
+        stream
+            .write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        // The UDP relay echoes datagrams
+        let (mut stream, _) = listener.accept().await.unwrap();
+        accept(&mut stream).await;
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut length = [0u8; LENGTH_SIZE];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut buffer = vec![0u8; length[0] as usize * 256 + length[1] as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        stream.write_all(&length).await.unwrap();
+        stream.write_all(&buffer).await.unwrap();
+    });
+
+    let mut options = SocksOption::new(false, false, None);
+    options.set_udp_relay("10.0.0.9:5000".parse().unwrap());
+    let (mut rx, mut tx, _) = bind(remote, 0, &options).await.unwrap();
+    assert!(options.is_associate_unsupported.load(Ordering::Relaxed));
+
+    let dst = "1.1.1.1:53".parse().unwrap();
+    tx.send_to(&[1, 2, 3], dst).await.unwrap();
+    let mut buffer = [0u8; 16];
+    let (size, addr) = rx.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..size], &[1, 2, 3]);
+    assert_eq!(addr, dst);
+}
+
+#[tokio::test]
+async fn bind_socket_preferred() {
+    let socket = bind_socket(0).await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    // The port is in use
+    let other = bind_socket(port).await.unwrap();
+    assert_ne!(other.local_addr().unwrap().port(), port);
+
+    // The port is available again
+    drop(socket);
+    let socket = bind_socket(port).await.unwrap();
+    assert_eq!(socket.local_addr().unwrap().port(), port);
+}
+
+#[tokio::test]
+async fn bind_association_closed() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_port = relay.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 10];
+        stream.read_exact(&mut buffer[..2]).await.unwrap();
+        let n = buffer[1] as usize;
+        stream.read_exact(&mut buffer[..n]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buffer).await.unwrap();
+        let port = relay_port.to_be_bytes();
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+            .await
+            .unwrap();
+
+        // Terminate the association
+        drop(stream);
+    });
+
+    let options = SocksOption::new(false, false, None);
+    let (mut rx, _, _) = bind(remote, 0, &options).await.unwrap();
+    let mut buffer = [0u8; 16];
+    let e = rx.recv_from(&mut buffer).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn socks_error() {
+    assert!(!is_socks_error(&io::Error::from(
+        io::ErrorKind::ConnectionRefused
+    )));
+    assert!(!is_socks_error(&io::Error::new(
+        io::ErrorKind::Other,
+        "UDP ASSOCIATE is not supported over WebSocket"
+    )));
+    assert!(is_socks_error(&io::Error::new(
+        io::ErrorKind::Other,
+        async_socks5::Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+    )));
+}
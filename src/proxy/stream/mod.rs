@@ -0,0 +1,244 @@
+//! Support for streams to the proxy over different transports.
+
+use socket2::Socket;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use super::socks::{SocksRecvHalf, SocksSendHalf};
+use super::websocket::{WebSocketReadHalf, WebSocketStream, WebSocketWriteHalf};
+
+/// Represents a stream to the proxy, either connected directly or tunneled in a WebSocket
+/// connection.
+#[derive(Debug)]
+pub enum ProxyStream {
+    /// Represents a TCP connection to the proxy.
+    Tcp(TcpStream),
+    /// Represents a WebSocket connection to a server tunneling to the proxy.
+    WebSocket(WebSocketStream),
+}
+
+impl ProxyStream {
+    /// Returns the remote address of the underlying TCP connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ProxyStream::Tcp(stream) => stream.peer_addr(),
+            ProxyStream::WebSocket(stream) => stream.peer_addr(),
+        }
+    }
+
+    /// Sets the TCP keep-alive of the underlying TCP connection, which probes the connection
+    /// after it has been idle for the given duration, or disables it if `None`.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        match self {
+            ProxyStream::Tcp(stream) => set_keepalive(stream, keepalive),
+            ProxyStream::WebSocket(stream) => stream.set_keepalive(keepalive),
+        }
+    }
+
+    /// Splits the stream into a read half and a write half.
+    pub fn into_split(self) -> (ProxyReadHalf, ProxyWriteHalf) {
+        match self {
+            ProxyStream::Tcp(stream) => {
+                let (rx, tx) = stream.into_split();
+
+                (ProxyReadHalf::Tcp(rx), ProxyWriteHalf::Tcp(tx))
+            }
+            ProxyStream::WebSocket(stream) => {
+                let (rx, tx) = stream.into_split();
+
+                (ProxyReadHalf::WebSocket(rx), ProxyWriteHalf::WebSocket(tx))
+            }
+        }
+    }
+}
+
+/// Sets the TCP keep-alive of a TCP connection, which probes the connection after it has been
+/// idle for the given duration, or disables it if `None`.
+pub(crate) fn set_keepalive(stream: &TcpStream, keepalive: Option<Duration>) -> io::Result<()> {
+    borrow_socket(stream).set_keepalive(keepalive)
+}
+
+/// Duplicates the socket of a TCP connection, which can set options of the connection after the
+/// connection is moved into a wrapper not exposing it, like a TLS stream.
+#[cfg(feature = "tls")]
+pub(crate) fn duplicate_socket(stream: &TcpStream) -> io::Result<Socket> {
+    borrow_socket(stream).try_clone()
+}
+
+/// Borrows the socket of a TCP connection.
+fn borrow_socket(stream: &TcpStream) -> ManuallyDrop<Socket> {
+    // The socket is borrowed from the stream, and must not be closed on drop
+    #[cfg(unix)]
+    let socket = {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        ManuallyDrop::new(unsafe { Socket::from_raw_fd(stream.as_raw_fd()) })
+    };
+    #[cfg(windows)]
+    let socket = {
+        use std::os::windows::io::{AsRawSocket, FromRawSocket};
+        ManuallyDrop::new(unsafe { Socket::from_raw_socket(stream.as_raw_socket()) })
+    };
+
+    socket
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::WebSocket(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::WebSocket(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::WebSocket(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::WebSocket(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Represents the read half of a `ProxyStream`.
+#[derive(Debug)]
+pub enum ProxyReadHalf {
+    Tcp(OwnedReadHalf),
+    WebSocket(WebSocketReadHalf),
+}
+
+impl AsyncRead for ProxyReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyReadHalf::Tcp(rx) => Pin::new(rx).poll_read(cx, buf),
+            ProxyReadHalf::WebSocket(rx) => Pin::new(rx).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Represents the write half of a `ProxyStream`.
+#[derive(Debug)]
+pub enum ProxyWriteHalf {
+    Tcp(OwnedWriteHalf),
+    WebSocket(WebSocketWriteHalf),
+}
+
+impl ProxyWriteHalf {
+    /// Sets the value of the `TCP_NODELAY` option of the underlying TCP connection.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            ProxyWriteHalf::Tcp(tx) => tx.as_ref().set_nodelay(nodelay),
+            ProxyWriteHalf::WebSocket(tx) => tx.set_nodelay(nodelay),
+        }
+    }
+
+    /// Destroys the write half without shutting down the underlying TCP connection.
+    pub fn forget(self) {
+        match self {
+            ProxyWriteHalf::Tcp(tx) => tx.forget(),
+            ProxyWriteHalf::WebSocket(tx) => tx.forget(),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ProxyWriteHalf::Tcp(tx) => Pin::new(tx).poll_write(cx, buf),
+            ProxyWriteHalf::WebSocket(tx) => Pin::new(tx).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyWriteHalf::Tcp(tx) => Pin::new(tx).poll_flush(cx),
+            ProxyWriteHalf::WebSocket(tx) => Pin::new(tx).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyWriteHalf::Tcp(tx) => Pin::new(tx).poll_shutdown(cx),
+            ProxyWriteHalf::WebSocket(tx) => Pin::new(tx).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Represents the receive half of a datagram association through the proxy.
+#[derive(Debug)]
+pub enum DatagramRecvHalf {
+    /// Represents the receive half of a SOCKS5 UDP client.
+    Socks(SocksRecvHalf),
+}
+
+impl DatagramRecvHalf {
+    /// Receives a single datagram, and returns its size and its source.
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        match self {
+            DatagramRecvHalf::Socks(rx) => rx.recv_from(buffer).await,
+        }
+    }
+
+    /// Returns the descriptors of the control connection and the socket of the association, if it
+    /// can be handed over to another process.
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Option<(RawFd, RawFd)> {
+        match self {
+            DatagramRecvHalf::Socks(rx) => rx.raw_fds(),
+        }
+    }
+}
+
+/// Represents the send half of a datagram association through the proxy.
+#[derive(Debug)]
+pub enum DatagramSendHalf {
+    /// Represents the send half of a SOCKS5 UDP client.
+    Socks(SocksSendHalf),
+}
+
+impl DatagramSendHalf {
+    /// Sends a single datagram to the given address.
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        match self {
+            DatagramSendHalf::Socks(tx) => tx.send_to(payload, dst).await,
+        }
+    }
+}
@@ -0,0 +1,856 @@
+//! Support for tunneling streams in WebSocket connections defined in RFC 6455.
+
+use log::trace;
+#[cfg(feature = "tls")]
+use socket2::Socket;
+use std::cmp::min;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use crate::tls::{CertPin, TlsConfig, TlsStream};
+
+/// Represents the default port of WebSocket.
+pub const WEBSOCKET_PORT: u16 = 80;
+/// Represents the default port of WebSocket over TLS.
+pub const SECURE_WEBSOCKET_PORT: u16 = 443;
+
+/// Represents the GUID appended to the key in computing the accept key.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Represents the max size of the response of the opening handshake.
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+const MAX_HEADER_SIZE: usize = 14;
+const MAX_CONTROL_PAYLOAD_SIZE: usize = 125;
+/// Represents the status code of a normal closure.
+const CLOSE_NORMAL: u16 = 1000;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Represents a WebSocket server which tunnels the streams in its connections to the proxy.
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    host: String,
+    port: u16,
+    path: String,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+impl WebSocketConfig {
+    /// Creates a new `WebSocketConfig`. The host is resolved in each connection, so servers
+    /// behind a CDN follow the changes of their addresses, and sent in the `Host` header with the
+    /// port, and the path is requested in the opening handshake.
+    pub fn new(host: String, port: u16, path: String) -> WebSocketConfig {
+        WebSocketConfig {
+            host,
+            port,
+            path,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Sets TLS of the connections to the server, which is `wss://`. The server is verified in
+    /// the TLS handshake before the opening handshake.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Sets the pins of the certificate of the server if the connections are over TLS.
+    #[cfg(feature = "tls")]
+    pub fn set_pins(&mut self, pins: Vec<CertPin>) {
+        if let Some(ref mut tls) = self.tls {
+            tls.set_pins(pins);
+        }
+    }
+
+    /// Returns the IPv4 address of the WebSocket server if its host is an IPv4 address rather
+    /// than a hostname.
+    pub fn ip_addr(&self) -> Option<Ipv4Addr> {
+        self.host.parse().ok()
+    }
+
+    /// Returns if the connections to the server are over TLS.
+    pub fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns the value of the `Host` header, where the port is left out if it is the default
+    /// one of the scheme.
+    fn authority(&self) -> String {
+        match (self.port, self.is_tls()) {
+            (WEBSOCKET_PORT, false) | (SECURE_WEBSOCKET_PORT, true) => self.host.clone(),
+            (port, _) => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+/// Connects to the WebSocket server, and upgrades the connection in the opening handshake.
+pub async fn connect(config: &WebSocketConfig) -> io::Result<WebSocketStream> {
+    let mut stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+
+    #[cfg(feature = "tls")]
+    if let Some(ref tls) = config.tls {
+        let socket = super::stream::duplicate_socket(&stream)?;
+        let mut stream = tls.connect(stream).await?;
+        handshake(&mut stream, config).await?;
+
+        return Ok(WebSocketStream::new_tls(stream, socket));
+    }
+
+    handshake(&mut stream, config).await?;
+
+    Ok(WebSocketStream::new(stream))
+}
+
+/// Upgrades the connection in the opening handshake.
+async fn handshake<S>(stream: &mut S, config: &WebSocketConfig) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = base64(&rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        config.path,
+        config.authority(),
+        key
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response byte by byte, so no frame after it will be consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handshake response too large",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    check_response(&String::from_utf8_lossy(&response), &key)?;
+
+    trace!("upgrade WebSocket connection to {}", config.authority());
+
+    Ok(())
+}
+
+/// Checks the response of the opening handshake against the key.
+fn check_response(response: &str, key: &str) -> io::Result<()> {
+    let mut lines = response.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("unexpected status {}", status),
+        ));
+    }
+
+    let accept = lines
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].trim(), line[i + 1..].trim()))
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+        .map(|(_, value)| value);
+    if accept != Some(accept_key(key).as_str()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "mismatched accept key",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the accept key the server answers to the key.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Represents a WebSocket connection carrying a stream in binary frames.
+#[derive(Debug)]
+pub struct WebSocketStream {
+    rx: WebSocketReadHalf,
+    tx: WebSocketWriteHalf,
+}
+
+impl WebSocketStream {
+    fn new(stream: TcpStream) -> WebSocketStream {
+        let (rx, tx) = stream.into_split();
+
+        WebSocketStream::from_split(ConnectionReadHalf::Tcp(rx), ConnectionWriteHalf::Tcp(tx))
+    }
+
+    #[cfg(feature = "tls")]
+    fn new_tls(stream: TlsStream<TcpStream>, socket: Socket) -> WebSocketStream {
+        let (rx, tx) = io::split(stream);
+
+        WebSocketStream::from_split(
+            ConnectionReadHalf::Tls(rx),
+            ConnectionWriteHalf::Tls(tx, socket),
+        )
+    }
+
+    fn from_split(rx: ConnectionReadHalf, tx: ConnectionWriteHalf) -> WebSocketStream {
+        let pong = Arc::new(Mutex::new(None));
+
+        WebSocketStream {
+            rx: WebSocketReadHalf::new(rx, Arc::clone(&pong)),
+            tx: WebSocketWriteHalf::new(tx, pong),
+        }
+    }
+
+    /// Returns the remote address of the underlying TCP connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tx.stream.peer_addr()
+    }
+
+    /// Sets the TCP keep-alive of the underlying TCP connection.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.tx.stream.set_keepalive(keepalive)
+    }
+
+    /// Splits the connection into a read half and a write half.
+    pub fn into_split(self) -> (WebSocketReadHalf, WebSocketWriteHalf) {
+        (self.rx, self.tx)
+    }
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().rx).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().tx).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().tx).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().tx).poll_shutdown(cx)
+    }
+}
+
+/// Represents the read half of a `WebSocketStream`. The payloads of data frames are read as the
+/// stream, and a close frame ends the stream.
+#[derive(Debug)]
+pub struct WebSocketReadHalf {
+    stream: ConnectionReadHalf,
+    header: [u8; MAX_HEADER_SIZE],
+    header_size: usize,
+    is_payload: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    offset: u64,
+    remaining: u64,
+    control: Vec<u8>,
+    pong: Arc<Mutex<Option<Vec<u8>>>>,
+    is_closed: bool,
+}
+
+impl WebSocketReadHalf {
+    fn new(stream: ConnectionReadHalf, pong: Arc<Mutex<Option<Vec<u8>>>>) -> WebSocketReadHalf {
+        WebSocketReadHalf {
+            stream,
+            header: [0u8; MAX_HEADER_SIZE],
+            header_size: 0,
+            is_payload: false,
+            opcode: 0,
+            mask: None,
+            offset: 0,
+            remaining: 0,
+            control: Vec::new(),
+            pong,
+            is_closed: false,
+        }
+    }
+
+    /// Parses the header which has been read completely.
+    fn parse_header(&mut self) -> io::Result<()> {
+        let header = &self.header[..self.header_size];
+        let opcode = header[0] & 0x0f;
+        let (length, i) = match header[1] & 0x7f {
+            126 => (u16::from_be_bytes([header[2], header[3]]) as u64, 4),
+            127 => {
+                let mut length = [0u8; 8];
+                length.copy_from_slice(&header[2..10]);
+
+                (u64::from_be_bytes(length), 10)
+            }
+            length => (length as u64, 2),
+        };
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {}
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if length > MAX_CONTROL_PAYLOAD_SIZE as u64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "control frame too large",
+                    ));
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown opcode {}", opcode),
+                ))
+            }
+        }
+        // Servers do not mask frames, but unmask them anyway
+        self.mask = match header[1] & 0x80 {
+            0 => None,
+            _ => Some([header[i], header[i + 1], header[i + 2], header[i + 3]]),
+        };
+
+        self.opcode = opcode;
+        self.offset = 0;
+        self.remaining = length;
+        self.control.clear();
+        self.header_size = 0;
+        self.is_payload = true;
+
+        Ok(())
+    }
+
+    /// Handles the control frame which has been read completely.
+    fn handle_control(&mut self) {
+        match self.opcode {
+            OPCODE_CLOSE => {
+                self.is_closed = true;
+                trace!("receive WebSocket close");
+            }
+            OPCODE_PING => *self.pong.lock().unwrap() = Some(self.control.clone()),
+            _ => {}
+        }
+    }
+}
+
+impl AsyncRead for WebSocketReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let half = self.get_mut();
+        loop {
+            if half.is_closed || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Header
+            if !half.is_payload {
+                let size = header_size(&half.header[..half.header_size]);
+                if half.header_size < size {
+                    let n = match poll_read_slice(
+                        &mut half.stream,
+                        cx,
+                        &mut half.header[half.header_size..size],
+                    ) {
+                        Poll::Ready(Ok(n)) => n,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    if n == 0 {
+                        // Closed without a close frame between frames
+                        if half.header_size == 0 {
+                            half.is_closed = true;
+                            continue;
+                        }
+                        return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                    }
+                    half.header_size += n;
+                    continue;
+                }
+
+                if let Err(e) = half.parse_header() {
+                    return Poll::Ready(Err(e));
+                }
+            }
+
+            // Payload
+            if half.opcode & OPCODE_CLOSE == 0 {
+                // Data
+                if half.remaining == 0 {
+                    half.is_payload = false;
+                    continue;
+                }
+
+                let size = min(half.remaining, buf.remaining() as u64) as usize;
+                let dst = buf.initialize_unfilled_to(size);
+                let n = match poll_read_slice(&mut half.stream, cx, dst) {
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
+                if let Some(mask) = half.mask {
+                    apply_mask(&mut dst[..n], mask, half.offset);
+                }
+                buf.advance(n);
+                half.offset += n as u64;
+                half.remaining -= n as u64;
+                if half.remaining == 0 {
+                    half.is_payload = false;
+                }
+
+                return Poll::Ready(Ok(()));
+            } else {
+                // Control
+                if half.remaining > 0 {
+                    let mut control = [0u8; MAX_CONTROL_PAYLOAD_SIZE];
+                    let n = match poll_read_slice(
+                        &mut half.stream,
+                        cx,
+                        &mut control[..half.remaining as usize],
+                    ) {
+                        Poll::Ready(Ok(n)) => n,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    if n == 0 {
+                        return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                    }
+                    if let Some(mask) = half.mask {
+                        apply_mask(&mut control[..n], mask, half.offset);
+                    }
+                    half.control.extend_from_slice(&control[..n]);
+                    half.offset += n as u64;
+                    half.remaining -= n as u64;
+                    continue;
+                }
+
+                half.is_payload = false;
+                half.handle_control();
+            }
+        }
+    }
+}
+
+/// Represents the write half of a `WebSocketStream`. Each write is sent in a masked binary frame.
+/// A frame is written completely before the write returns, so callers have to retry with the same
+/// data after `Poll::Pending`, like `write_all` does. Pings are answered before the next write.
+#[derive(Debug)]
+pub struct WebSocketWriteHalf {
+    stream: ConnectionWriteHalf,
+    buffer: Vec<u8>,
+    offset: usize,
+    accepted: usize,
+    pong: Arc<Mutex<Option<Vec<u8>>>>,
+    is_closing: bool,
+}
+
+impl WebSocketWriteHalf {
+    fn new(stream: ConnectionWriteHalf, pong: Arc<Mutex<Option<Vec<u8>>>>) -> WebSocketWriteHalf {
+        WebSocketWriteHalf {
+            stream,
+            buffer: Vec::new(),
+            offset: 0,
+            accepted: 0,
+            pong,
+            is_closing: false,
+        }
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option of the underlying TCP connection.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    /// Destroys the write half without shutting down the underlying TCP connection.
+    pub fn forget(self) {
+        self.stream.forget();
+    }
+
+    /// Writes the buffered frames to the underlying TCP connection.
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.offset < self.buffer.len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.buffer[self.offset..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)))
+                }
+                Poll::Ready(Ok(n)) => self.offset += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.buffer.clear();
+        self.offset = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocketWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let half = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if half.is_closing {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+
+        if half.buffer.is_empty() {
+            if let Some(payload) = half.pong.lock().unwrap().take() {
+                append_frame(&mut half.buffer, OPCODE_PONG, &payload);
+            }
+            append_frame(&mut half.buffer, OPCODE_BINARY, buf);
+            half.accepted = buf.len();
+        }
+
+        match half.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(min(half.accepted, buf.len()))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let half = self.get_mut();
+        match half.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut half.stream).poll_flush(cx),
+            poll => poll,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let half = self.get_mut();
+        if !half.is_closing {
+            match half.poll_write_buffer(cx) {
+                Poll::Ready(Ok(())) => {}
+                poll => return poll,
+            }
+            append_frame(&mut half.buffer, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+            half.is_closing = true;
+        }
+
+        match half.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut half.stream).poll_shutdown(cx),
+            poll => poll,
+        }
+    }
+}
+
+/// Represents the read half of the underlying connection of a `WebSocketStream`.
+#[derive(Debug)]
+enum ConnectionReadHalf {
+    Tcp(OwnedReadHalf),
+    #[cfg(feature = "tls")]
+    Tls(io::ReadHalf<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ConnectionReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionReadHalf::Tcp(rx) => Pin::new(rx).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ConnectionReadHalf::Tls(rx) => Pin::new(rx).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Represents the write half of the underlying connection of a `WebSocketStream`. The write half
+/// of a TLS connection keeps a duplicate of the socket for setting options of the connection.
+#[derive(Debug)]
+enum ConnectionWriteHalf {
+    Tcp(OwnedWriteHalf),
+    #[cfg(feature = "tls")]
+    Tls(io::WriteHalf<TlsStream<TcpStream>>, Socket),
+}
+
+impl ConnectionWriteHalf {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ConnectionWriteHalf::Tcp(tx) => tx.as_ref().peer_addr(),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(_, socket) => socket
+                .peer_addr()?
+                .as_std()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)),
+        }
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            ConnectionWriteHalf::Tcp(tx) => tx.as_ref().set_nodelay(nodelay),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(_, socket) => socket.set_nodelay(nodelay),
+        }
+    }
+
+    fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        match self {
+            ConnectionWriteHalf::Tcp(tx) => super::stream::set_keepalive(tx.as_ref(), keepalive),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(_, socket) => socket.set_keepalive(keepalive),
+        }
+    }
+
+    fn forget(self) {
+        match self {
+            ConnectionWriteHalf::Tcp(tx) => tx.forget(),
+            // Dropping the write half of a TLS connection never shuts down the connection
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(_, _) => {}
+        }
+    }
+}
+
+impl AsyncWrite for ConnectionWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ConnectionWriteHalf::Tcp(tx) => Pin::new(tx).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(tx, _) => Pin::new(tx).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionWriteHalf::Tcp(tx) => Pin::new(tx).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(tx, _) => Pin::new(tx).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ConnectionWriteHalf::Tcp(tx) => Pin::new(tx).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            ConnectionWriteHalf::Tls(tx, _) => Pin::new(tx).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Reads from the stream into the slice.
+fn poll_read_slice(
+    stream: &mut ConnectionReadHalf,
+    cx: &mut Context<'_>,
+    slice: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    let mut buf = ReadBuf::new(slice);
+    match Pin::new(stream).poll_read(cx, &mut buf) {
+        Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+/// Returns the size of the frame header, given the part of it which has been read.
+fn header_size(header: &[u8]) -> usize {
+    if header.len() < 2 {
+        return 2;
+    }
+
+    let length_size = match header[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_size = match header[1] & 0x80 {
+        0 => 0,
+        _ => 4,
+    };
+
+    2 + length_size + mask_size
+}
+
+/// Appends a masked frame in a single fragment to the buffer.
+fn append_frame(buffer: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    // FIN and opcode
+    buffer.push(0x80 | opcode);
+    // Mask and payload length
+    match payload.len() {
+        length if length < 126 => buffer.push(0x80 | length as u8),
+        length if length <= u16::MAX as usize => {
+            buffer.push(0x80 | 126);
+            buffer.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            buffer.push(0x80 | 127);
+            buffer.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    // Masking key
+    let mask = rand::random::<[u8; 4]>();
+    buffer.extend_from_slice(&mask);
+    // Payload
+    let i = buffer.len();
+    buffer.extend_from_slice(payload);
+    apply_mask(&mut buffer[i..], mask, 0);
+}
+
+/// Masks or unmasks the data at the offset of the payload.
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: u64) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[((offset + i as u64) % 4) as usize];
+    }
+}
+
+/// Returns the SHA-1 digest of the data, which is only used in the opening handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // Pad with a bit of 1, zeroes and the length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (x, y) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *x = x.wrapping_add(*y);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+/// Returns the data encoded in Base64 with paddings.
+pub(crate) fn base64(data: &[u8]) -> String {
+    let mut s = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => s.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char),
+                false => s.push('='),
+            }
+        }
+    }
+
+    s
+}
+
+#[test]
+fn websocket_accept_key() {
+    assert_eq!(
+        sha1(b"abc"),
+        [
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+            0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+        ]
+    );
+    assert_eq!(base64(b"pcap2socks"), "cGNhcDJzb2Nrcw==");
+    // The example in RFC 6455
+    let key = "dGhlIHNhbXBsZSBub25jZQ==";
+    assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert!(check_response(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nsec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+        key
+    )
+    .is_ok());
+    assert!(check_response("HTTP/1.1 403 Forbidden\r\n\r\n", key).is_err());
+
+    let mut frame = Vec::new();
+    append_frame(&mut frame, OPCODE_BINARY, &[1, 2, 3, 4, 5]);
+    assert_eq!(&frame[..2], &[0x82, 0x85]);
+    assert_eq!(header_size(&frame[..2]), 6);
+    let mask = [frame[2], frame[3], frame[4], frame[5]];
+    apply_mask(&mut frame[6..], mask, 0);
+    assert_eq!(&frame[6..], &[1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn websocket_config_authority() {
+    let config = WebSocketConfig::new(String::from("example.com"), 80, String::from("/socks"));
+    assert_eq!(config.authority(), "example.com");
+    assert!(config.ip_addr().is_none());
+    let config = WebSocketConfig::new(String::from("10.0.0.1"), 8080, String::from("/"));
+    assert_eq!(config.authority(), "10.0.0.1:8080");
+    assert_eq!(config.ip_addr(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    #[cfg(feature = "tls")]
+    {
+        let mut config = WebSocketConfig::new(String::from("example.com"), 443, String::from("/"));
+        config.set_tls(TlsConfig::new("example.com").unwrap());
+        assert!(config.is_tls());
+        assert_eq!(config.authority(), "example.com");
+    }
+}
@@ -0,0 +1,262 @@
+//! Support for prioritizing interactive flows over bulk flows.
+
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::SocketAddrV4;
+
+/// Represents the bytes transferred in a TCP connection before it is classified as bulk.
+pub const BULK_THRESHOLD: usize = 1024 * 1024;
+/// Represents the bytes a client of weight 1 can send in each round of the deficit round-robin.
+pub const QUANTUM: usize = 1514;
+
+/// Represents the class of a flow.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FlowClass {
+    /// Represents flows of small packets like UDP and short TCP connections, which are sensitive
+    /// to latency.
+    Interactive,
+    /// Represents long TCP connections of high throughput.
+    Bulk,
+}
+
+/// Represents a classifier which classifies TCP connections by the bytes transferred.
+#[derive(Debug, Default)]
+pub struct Classifier {
+    bytes: HashMap<(SocketAddrV4, SocketAddrV4), usize>,
+}
+
+impl Classifier {
+    /// Creates a new `Classifier`.
+    pub fn new() -> Classifier {
+        Classifier::default()
+    }
+
+    /// Adds bytes transferred in the TCP connection and returns its class.
+    pub fn add(&mut self, dst: SocketAddrV4, src: SocketAddrV4, n: usize) -> FlowClass {
+        let bytes = self.bytes.entry((src, dst)).or_insert(0);
+        *bytes = bytes.checked_add(n).unwrap_or(usize::MAX);
+
+        classify(*bytes)
+    }
+
+    /// Removes the TCP connection.
+    pub fn remove(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
+        self.bytes.remove(&(src, dst));
+    }
+}
+
+/// Returns the class of a TCP connection which has transferred the given bytes.
+pub fn classify(bytes: usize) -> FlowClass {
+    match bytes >= BULK_THRESHOLD {
+        true => FlowClass::Bulk,
+        false => FlowClass::Interactive,
+    }
+}
+
+/// Represents a queue of bulk flows which pops items of flows in turn.
+#[derive(Debug)]
+pub struct FairQueue<K, T> {
+    queues: HashMap<K, VecDeque<T>>,
+    order: VecDeque<K>,
+    len: usize,
+}
+
+impl<K: Clone + Eq + Hash, T> Default for FairQueue<K, T> {
+    fn default() -> FairQueue<K, T> {
+        FairQueue::new()
+    }
+}
+
+impl<K: Clone + Eq + Hash, T> FairQueue<K, T> {
+    /// Creates a new `FairQueue`.
+    pub fn new() -> FairQueue<K, T> {
+        FairQueue {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    /// Appends an item of the flow.
+    pub fn push(&mut self, key: K, item: T) {
+        let queue = self
+            .queues
+            .entry(key.clone())
+            .or_insert_with(|| VecDeque::new());
+        if queue.is_empty() {
+            self.order.push_back(key);
+        }
+        queue.push_back(item);
+        self.len += 1;
+    }
+
+    /// Pops an item of the next flow.
+    pub fn pop(&mut self) -> Option<T> {
+        let key = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&key).unwrap();
+        let item = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.order.push_back(key);
+        }
+        self.len -= 1;
+
+        item
+    }
+
+    /// Returns the item which will be popped next.
+    pub fn front(&self) -> Option<&T> {
+        let key = self.order.front()?;
+
+        self.queues.get(key).unwrap().front()
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns if the queue contains no item.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[derive(Debug)]
+struct Deficit<S, T> {
+    queue: FairQueue<S, (T, usize)>,
+    deficit: usize,
+    is_visited: bool,
+}
+
+/// Represents a queue of clients which pops items of clients in deficit round-robin by their
+/// weights, and pops items of flows in turn in each client.
+#[derive(Debug)]
+pub struct DeficitQueue<K, S, T> {
+    clients: HashMap<K, Deficit<S, T>>,
+    order: VecDeque<K>,
+    weights: HashMap<K, usize>,
+    len: usize,
+}
+
+impl<K: Clone + Eq + Hash, S: Clone + Eq + Hash, T> Default for DeficitQueue<K, S, T> {
+    fn default() -> DeficitQueue<K, S, T> {
+        DeficitQueue::new()
+    }
+}
+
+impl<K: Clone + Eq + Hash, S: Clone + Eq + Hash, T> DeficitQueue<K, S, T> {
+    /// Creates a new `DeficitQueue`.
+    pub fn new() -> DeficitQueue<K, S, T> {
+        DeficitQueue {
+            clients: HashMap::new(),
+            order: VecDeque::new(),
+            weights: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Sets the weight of the client. Clients are of weight 1 by default.
+    pub fn set_weight(&mut self, key: K, weight: usize) {
+        self.weights.insert(key, max(weight, 1));
+    }
+
+    /// Appends an item of the given size of the flow of the client.
+    pub fn push(&mut self, key: K, flow: S, item: T, size: usize) {
+        let client = self.clients.entry(key.clone()).or_insert_with(|| Deficit {
+            queue: FairQueue::new(),
+            deficit: 0,
+            is_visited: false,
+        });
+        if client.queue.is_empty() {
+            self.order.push_back(key);
+        }
+        client.queue.push(flow, (item, size));
+        self.len += 1;
+    }
+
+    /// Pops an item of the next client which has enough deficit.
+    pub fn pop(&mut self) -> Option<T> {
+        loop {
+            let key = self.order.front()?.clone();
+            let weight = *self.weights.get(&key).unwrap_or(&1);
+            let client = self.clients.get_mut(&key).unwrap();
+
+            // Add the quantum once in each round
+            if !client.is_visited {
+                client.deficit = client
+                    .deficit
+                    .checked_add(QUANTUM.checked_mul(weight).unwrap_or(usize::MAX))
+                    .unwrap_or(usize::MAX);
+                client.is_visited = true;
+            }
+
+            let size = client.queue.front().unwrap().1;
+            if size <= client.deficit {
+                let (item, _) = client.queue.pop().unwrap();
+                client.deficit -= size;
+                if client.queue.is_empty() {
+                    self.clients.remove(&key);
+                    self.order.pop_front();
+                }
+                self.len -= 1;
+
+                return Some(item);
+            }
+
+            // Move to the next client
+            client.is_visited = false;
+            self.order.pop_front();
+            self.order.push_back(key);
+        }
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns if the queue contains no item.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[test]
+fn fair_queue_pop() {
+    let mut queue = FairQueue::new();
+    queue.push(1, "a1");
+    queue.push(1, "a2");
+    queue.push(1, "a3");
+    queue.push(2, "b1");
+    assert_eq!(queue.len(), 4);
+    assert_eq!(queue.pop(), Some("a1"));
+    assert_eq!(queue.pop(), Some("b1"));
+    assert_eq!(queue.pop(), Some("a2"));
+    assert_eq!(queue.pop(), Some("a3"));
+    assert_eq!(queue.pop(), None);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn deficit_queue_pop() {
+    let mut queue = DeficitQueue::new();
+    queue.set_weight(1, 2);
+    for i in 0..4 {
+        queue.push(1, 0, ("a", i), QUANTUM);
+        queue.push(2, 0, ("b", i), QUANTUM);
+    }
+    assert_eq!(queue.len(), 8);
+    // Client 1 sends 2 items in each round for its weight
+    assert_eq!(queue.pop(), Some(("a", 0)));
+    assert_eq!(queue.pop(), Some(("a", 1)));
+    assert_eq!(queue.pop(), Some(("b", 0)));
+    assert_eq!(queue.pop(), Some(("a", 2)));
+    assert_eq!(queue.pop(), Some(("a", 3)));
+    assert_eq!(queue.pop(), Some(("b", 1)));
+    assert_eq!(queue.pop(), Some(("b", 2)));
+    assert_eq!(queue.pop(), Some(("b", 3)));
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,272 @@
+//! Support for the cryptography of QUIC initial packets, which is limited to what peeking into
+//! the initials of clients needs. Keys of initials are derived from the visible destination
+//! connection ID, so they are not secrets, and the authentication tags are not verified.
+
+/// Represents the initial hash values of SHA-256.
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+/// Represents the round constants of SHA-256.
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+const SHA256_BLOCK_SIZE: usize = 64;
+
+/// Represents the S-box of AES.
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+/// Represents the round constants of the key expansion of AES-128.
+const AES_RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+pub const AES_BLOCK_SIZE: usize = 16;
+
+/// Returns the SHA-256 digest of the data.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h = SHA256_H;
+
+    // Pad with a bit of 1, zeroes and the length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % SHA256_BLOCK_SIZE != SHA256_BLOCK_SIZE - 8 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(SHA256_BLOCK_SIZE) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip(v.iter()) {
+            *x = x.wrapping_add(*y);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, x) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+
+    digest
+}
+
+/// Returns the HMAC-SHA256 of the data with the key.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; SHA256_BLOCK_SIZE];
+    if key.len() > SHA256_BLOCK_SIZE {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>();
+    inner.extend_from_slice(data);
+    let mut outer = block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>();
+    outer.extend_from_slice(&sha256(&inner));
+
+    sha256(&outer)
+}
+
+/// Returns the pseudorandom key of HKDF-Extract with SHA-256.
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    hmac_sha256(salt, ikm)
+}
+
+/// Returns the output of HKDF-Expand-Label of TLS 1.3 with SHA-256 and an empty context.
+pub fn hkdf_expand_label(secret: &[u8], label: &str, len: usize) -> Vec<u8> {
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+
+    // HKDF-Expand
+    let mut output = Vec::with_capacity(len);
+    let mut t: Vec<u8> = Vec::new();
+    let mut i = 1u8;
+    while output.len() < len {
+        let mut data = t.clone();
+        data.extend_from_slice(&info);
+        data.push(i);
+        t = hmac_sha256(secret, &data).to_vec();
+        output.extend_from_slice(&t);
+        i = i.wrapping_add(1);
+    }
+    output.truncate(len);
+
+    output
+}
+
+/// Represents the encryption of AES-128, which is all both the header protection and the
+/// counter mode of AES-128-GCM need.
+pub struct Aes128 {
+    round_keys: [[u8; AES_BLOCK_SIZE]; 11],
+}
+
+impl Aes128 {
+    /// Creates a new `Aes128` with the key.
+    pub fn new(key: &[u8; AES_BLOCK_SIZE]) -> Aes128 {
+        let mut round_keys = [[0u8; AES_BLOCK_SIZE]; 11];
+        round_keys[0] = *key;
+        for i in 1..11 {
+            let prev = round_keys[i - 1];
+            let mut word = [
+                AES_SBOX[prev[13] as usize] ^ AES_RCON[i - 1],
+                AES_SBOX[prev[14] as usize],
+                AES_SBOX[prev[15] as usize],
+                AES_SBOX[prev[12] as usize],
+            ];
+            for j in 0..4 {
+                for k in 0..4 {
+                    word[k] ^= prev[j * 4 + k];
+                    round_keys[i][j * 4 + k] = word[k];
+                }
+            }
+        }
+
+        Aes128 { round_keys }
+    }
+
+    /// Encrypts the block in place.
+    pub fn encrypt_block(&self, block: &mut [u8; AES_BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..11 {
+            // SubBytes
+            for b in block.iter_mut() {
+                *b = AES_SBOX[*b as usize];
+            }
+            // ShiftRows
+            let state = *block;
+            for c in 0..4 {
+                for r in 0..4 {
+                    block[c * 4 + r] = state[((c + r) % 4) * 4 + r];
+                }
+            }
+            // MixColumns
+            if round != 10 {
+                for c in 0..4 {
+                    let col = [
+                        block[c * 4],
+                        block[c * 4 + 1],
+                        block[c * 4 + 2],
+                        block[c * 4 + 3],
+                    ];
+                    let all = col[0] ^ col[1] ^ col[2] ^ col[3];
+                    for r in 0..4 {
+                        block[c * 4 + r] = col[r] ^ all ^ xtime(col[r] ^ col[(r + 1) % 4]);
+                    }
+                }
+            }
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+}
+
+fn add_round_key(block: &mut [u8; AES_BLOCK_SIZE], round_key: &[u8; AES_BLOCK_SIZE]) {
+    for (b, k) in block.iter_mut().zip(round_key.iter()) {
+        *b ^= k;
+    }
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// Decrypts the ciphertext of AES-128-GCM without the authentication tag, with the key and the
+/// nonce of 12 Bytes. The authentication tag is not verified.
+pub fn aes128_gcm_decrypt(aes: &Aes128, nonce: &[u8; 12], ciphertext: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    // The counter starts at 2, since 1 is of the authentication tag
+    for (i, chunk) in ciphertext.chunks(AES_BLOCK_SIZE).enumerate() {
+        let mut block = [0u8; AES_BLOCK_SIZE];
+        block[..12].copy_from_slice(nonce);
+        block[12..].copy_from_slice(&(i as u32).wrapping_add(2).to_be_bytes());
+        aes.encrypt_block(&mut block);
+        plaintext.extend(chunk.iter().zip(block.iter()).map(|(c, k)| c ^ k));
+    }
+
+    plaintext
+}
+
+#[test]
+fn crypto_sha256_aes128() {
+    assert_eq!(
+        sha256(b"abc")[..],
+        [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad
+        ][..]
+    );
+
+    let mut block = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    let mut key = [0u8; AES_BLOCK_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    Aes128::new(&key).encrypt_block(&mut block);
+    assert_eq!(
+        block,
+        [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+            0xc5, 0x5a
+        ]
+    );
+}
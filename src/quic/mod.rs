@@ -0,0 +1,395 @@
+//! Support for recognizing QUIC packets.
+
+mod crypto;
+
+use std::fmt::{self, Display, Formatter};
+
+use self::crypto::{Aes128, AES_BLOCK_SIZE};
+
+/// Represents the version 1 of QUIC.
+pub const VERSION_1: u32 = 0x00000001;
+
+/// Represents the salt of initial secrets of QUIC version 1.
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const MAX_CID_SIZE: usize = 20;
+const TAG_SIZE: usize = 16;
+/// Represents the offset of the sample of the header protection from the packet number.
+const SAMPLE_OFFSET: usize = 4;
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+
+/// Parses the long header of a QUIC packet, and returns the version, the destination connection
+/// ID and the source connection ID. Returns `None` if the buffer is not a long header packet.
+pub fn long_header(buffer: &[u8]) -> Option<(u32, &[u8], &[u8])> {
+    if buffer.len() < 7 || buffer[0] & 0xc0 != 0xc0 {
+        return None;
+    }
+    let version = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+    // Version negotiation
+    if version == 0 {
+        return None;
+    }
+
+    let dcid_len = buffer[5] as usize;
+    if dcid_len > MAX_CID_SIZE || buffer.len() < 7 + dcid_len {
+        return None;
+    }
+    let dcid = &buffer[6..6 + dcid_len];
+    let scid_len = buffer[6 + dcid_len] as usize;
+    if scid_len > MAX_CID_SIZE || buffer.len() < 7 + dcid_len + scid_len {
+        return None;
+    }
+    let scid = &buffer[7 + dcid_len..7 + dcid_len + scid_len];
+
+    Some((version, dcid, scid))
+}
+
+/// Returns if the buffer may be a QUIC packet of the short header, which carries the destination
+/// connection ID of a length unknown to others.
+pub fn is_short_header(buffer: &[u8]) -> bool {
+    buffer.len() > 1 + TAG_SIZE && buffer[0] & 0xc0 == 0x40
+}
+
+/// Represents a QUIC initial packet from a client, with the server name and the application
+/// protocols in its ClientHello if they can be parsed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Initial {
+    version: u32,
+    dcid: Vec<u8>,
+    scid: Vec<u8>,
+    sni: Option<String>,
+    alpn: Vec<String>,
+}
+
+impl Initial {
+    /// Parses a QUIC initial packet from a client. Returns `None` if the buffer is not an initial
+    /// packet. The ClientHello is only parsed in QUIC version 1 and as far as it is carried in
+    /// this packet.
+    pub fn parse(buffer: &[u8]) -> Option<Initial> {
+        let (version, dcid, scid) = long_header(buffer)?;
+        // Packet type of initials in QUIC version 1
+        if version != VERSION_1 || (buffer[0] >> 4) & 0x03 != 0 {
+            return None;
+        }
+
+        let mut initial = Initial {
+            version,
+            dcid: dcid.to_vec(),
+            scid: scid.to_vec(),
+            sni: None,
+            alpn: Vec::new(),
+        };
+        if let Some(crypto) = decrypt_crypto(buffer, 7 + dcid.len() + scid.len(), dcid) {
+            parse_client_hello(&crypto, &mut initial);
+        }
+
+        Some(initial)
+    }
+
+    /// Returns the version.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the destination connection ID, which is chosen by the client.
+    pub fn dcid(&self) -> &[u8] {
+        &self.dcid
+    }
+
+    /// Returns the source connection ID.
+    pub fn scid(&self) -> &[u8] {
+        &self.scid
+    }
+
+    /// Returns the server name in the ClientHello.
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// Returns the application protocols in the ClientHello, like `h3`.
+    pub fn alpn(&self) -> &[String] {
+        &self.alpn
+    }
+}
+
+impl Display for Initial {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "QUIC initial")?;
+        if let Some(ref sni) = self.sni {
+            write!(f, " of {}", sni)?;
+        }
+        if !self.alpn.is_empty() {
+            write!(f, " ({})", self.alpn.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a variable-length integer, and returns the integer and the position after it.
+fn read_varint(buffer: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let first = *buffer.get(pos)?;
+    let len = 1 << (first >> 6);
+    if buffer.len() < pos + len {
+        return None;
+    }
+
+    let mut value = (first & 0x3f) as u64;
+    for b in &buffer[pos + 1..pos + len] {
+        value = (value << 8) | *b as u64;
+    }
+
+    Some((value, pos + len))
+}
+
+/// Returns the key, the IV and the key of the header protection of initials of the client,
+/// which are derived from the destination connection ID chosen by the client.
+fn client_keys(dcid: &[u8]) -> ([u8; AES_BLOCK_SIZE], [u8; 12], [u8; AES_BLOCK_SIZE]) {
+    let initial_secret = crypto::hkdf_extract(&INITIAL_SALT_V1, dcid);
+    let secret = crypto::hkdf_expand_label(&initial_secret, "client in", 32);
+    let mut key = [0u8; AES_BLOCK_SIZE];
+    key.copy_from_slice(&crypto::hkdf_expand_label(
+        &secret,
+        "quic key",
+        AES_BLOCK_SIZE,
+    ));
+    let mut iv = [0u8; 12];
+    iv.copy_from_slice(&crypto::hkdf_expand_label(&secret, "quic iv", 12));
+    let mut hp = [0u8; AES_BLOCK_SIZE];
+    hp.copy_from_slice(&crypto::hkdf_expand_label(
+        &secret,
+        "quic hp",
+        AES_BLOCK_SIZE,
+    ));
+
+    (key, iv, hp)
+}
+
+/// Removes the protection of the initial packet, and returns the data of CRYPTO frames in it
+/// from the offset 0 as far as they are contiguous.
+fn decrypt_crypto(buffer: &[u8], pos: usize, dcid: &[u8]) -> Option<Vec<u8>> {
+    // Token and length
+    let (token_len, pos) = read_varint(buffer, pos)?;
+    let pos = pos.checked_add(token_len as usize)?;
+    let (len, pn_offset) = read_varint(buffer, pos)?;
+    let end = pn_offset.checked_add(len as usize)?;
+    if end > buffer.len() || pn_offset + SAMPLE_OFFSET + AES_BLOCK_SIZE > end {
+        return None;
+    }
+
+    let (key, iv, hp) = client_keys(dcid);
+
+    // Header protection
+    let mut mask = [0u8; AES_BLOCK_SIZE];
+    mask.copy_from_slice(
+        &buffer[pn_offset + SAMPLE_OFFSET..pn_offset + SAMPLE_OFFSET + AES_BLOCK_SIZE],
+    );
+    Aes128::new(&hp).encrypt_block(&mut mask);
+    let first = buffer[0] ^ (mask[0] & 0x0f);
+    let pn_len = (first & 0x03) as usize + 1;
+    if pn_offset + pn_len + TAG_SIZE > end {
+        return None;
+    }
+    let mut nonce = iv;
+    for i in 0..pn_len {
+        nonce[12 - pn_len + i] ^= buffer[pn_offset + i] ^ mask[1 + i];
+    }
+
+    let plaintext = crypto::aes128_gcm_decrypt(
+        &Aes128::new(&key),
+        &nonce,
+        &buffer[pn_offset + pn_len..end - TAG_SIZE],
+    );
+
+    // Frames, where CRYPTO frames may be out of order
+    let mut fragments = Vec::new();
+    let mut pos = 0;
+    while pos < plaintext.len() {
+        let (frame_type, next) = read_varint(&plaintext, pos)?;
+        pos = next;
+        match frame_type {
+            FRAME_PADDING | FRAME_PING => {}
+            FRAME_ACK | FRAME_ACK_ECN => {
+                let (_, next) = read_varint(&plaintext, pos)?;
+                let (_, next) = read_varint(&plaintext, next)?;
+                let (count, next) = read_varint(&plaintext, next)?;
+                let (_, mut next) = read_varint(&plaintext, next)?;
+                for _ in 0..count.saturating_mul(2) {
+                    next = read_varint(&plaintext, next)?.1;
+                }
+                if frame_type == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        next = read_varint(&plaintext, next)?.1;
+                    }
+                }
+                pos = next;
+            }
+            FRAME_CRYPTO => {
+                let (offset, next) = read_varint(&plaintext, pos)?;
+                let (len, next) = read_varint(&plaintext, next)?;
+                let end = next.checked_add(len as usize)?;
+                if end > plaintext.len() {
+                    return None;
+                }
+                fragments.push((offset as usize, &plaintext[next..end]));
+                pos = end;
+            }
+            // Other frames are not allowed in initials of clients
+            _ => break,
+        }
+    }
+    fragments.sort_by_key(|(offset, _)| *offset);
+
+    let mut crypto = Vec::new();
+    for (offset, data) in fragments {
+        if offset > crypto.len() {
+            break;
+        }
+        if offset + data.len() > crypto.len() {
+            crypto.extend_from_slice(&data[crypto.len() - offset..]);
+        }
+    }
+
+    Some(crypto)
+}
+
+/// Parses the ClientHello as far as it is complete, and sets the server name and the application
+/// protocols of the initial.
+fn parse_client_hello(buffer: &[u8], initial: &mut Initial) {
+    if buffer.len() < 4 || buffer[0] != HANDSHAKE_CLIENT_HELLO {
+        return;
+    }
+
+    // Version and random
+    let mut pos = 4 + 2 + 32;
+    // Session ID
+    pos += 1 + *buffer.get(pos).unwrap_or(&0) as usize;
+    // Cipher suites
+    pos += 2 + match buffer.get(pos..pos + 2) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => return,
+    };
+    // Compression methods
+    pos += 1 + match buffer.get(pos) {
+        Some(len) => *len as usize,
+        None => return,
+    };
+    // Extensions
+    pos += 2;
+    while let Some(header) = buffer.get(pos..pos + 4) {
+        let extension_type = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let data = match buffer.get(pos + 4..pos + 4 + len) {
+            Some(data) => data,
+            None => return,
+        };
+        match extension_type {
+            // The first name of the type host name
+            EXTENSION_SERVER_NAME if data.len() >= 5 && data[2] == 0 => {
+                let name_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+                if let Some(name) = data.get(5..5 + name_len) {
+                    initial.sni = Some(String::from_utf8_lossy(name).to_lowercase());
+                }
+            }
+            EXTENSION_ALPN => {
+                let mut i = 2;
+                while let Some(&len) = data.get(i) {
+                    match data.get(i + 1..i + 1 + len as usize) {
+                        Some(protocol) => initial
+                            .alpn
+                            .push(String::from_utf8_lossy(protocol).to_string()),
+                        None => break,
+                    }
+                    i += 1 + len as usize;
+                }
+            }
+            _ => {}
+        }
+        pos += 4 + len;
+    }
+}
+
+#[test]
+fn initial_parse() {
+    // Keys in RFC 9001 appendix A.1
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let (key, iv, hp) = client_keys(&dcid);
+    assert_eq!(
+        key,
+        [
+            0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef, 0xcb, 0xe3, 0xb1,
+            0xa2, 0x2d
+        ]
+    );
+    assert_eq!(
+        iv,
+        [0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c]
+    );
+    assert_eq!(
+        hp,
+        [
+            0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad,
+            0xed, 0xd2
+        ]
+    );
+
+    // ClientHello with SNI and ALPN, split in 2 CRYPTO frames out of order
+    let mut extensions = vec![0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b];
+    extensions.extend_from_slice(b"example.com");
+    extensions.extend_from_slice(&[0x00, 0x10, 0x00, 0x05, 0x00, 0x03, 0x02]);
+    extensions.extend_from_slice(b"h3");
+    let mut hello = vec![HANDSHAKE_CLIENT_HELLO, 0x00, 0x00, 0x00, 0x03, 0x03];
+    hello.extend_from_slice(&[0u8; 32]);
+    hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+    let hello_len = (hello.len() - 4) as u16;
+    hello[2..4].copy_from_slice(&hello_len.to_be_bytes());
+    let mut frames = vec![FRAME_CRYPTO as u8, 0x14, 0x40, hello.len() as u8 - 20];
+    frames.extend_from_slice(&hello[20..]);
+    frames.extend_from_slice(&[FRAME_CRYPTO as u8, 0x00, 0x14]);
+    frames.extend_from_slice(&hello[..20]);
+    frames.extend_from_slice(&[0u8; 64]);
+
+    // Protect the packet of the packet number 2 in 2 Bytes
+    let mut packet = vec![0xc1, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+    packet.extend_from_slice(&dcid);
+    packet.extend_from_slice(&[0x00, 0x00]);
+    let len = (2 + frames.len() + TAG_SIZE) as u16 | 0x4000;
+    packet.extend_from_slice(&len.to_be_bytes());
+    let pn_offset = packet.len();
+    let mut nonce = iv;
+    nonce[11] ^= 2;
+    packet.extend_from_slice(&[0x00, 0x02]);
+    packet.extend_from_slice(&crypto::aes128_gcm_decrypt(
+        &Aes128::new(&key),
+        &nonce,
+        &frames,
+    ));
+    packet.extend_from_slice(&[0u8; TAG_SIZE]);
+    let mut mask = [0u8; AES_BLOCK_SIZE];
+    mask.copy_from_slice(&packet[pn_offset + 4..pn_offset + 4 + AES_BLOCK_SIZE]);
+    Aes128::new(&hp).encrypt_block(&mut mask);
+    packet[0] ^= mask[0] & 0x0f;
+    packet[pn_offset] ^= mask[1];
+    packet[pn_offset + 1] ^= mask[2];
+
+    let initial = Initial::parse(&packet).unwrap();
+    assert_eq!(initial.dcid(), &dcid);
+    assert_eq!(initial.sni(), Some("example.com"));
+    assert_eq!(initial.alpn(), &[String::from("h3")]);
+    assert_eq!(long_header(&packet).unwrap().1, &dcid);
+
+    // Short header
+    assert!(Initial::parse(&[0x40; 64]).is_none());
+    assert!(is_short_header(&[0x40; 64]));
+}
@@ -0,0 +1,364 @@
+//! Support for accounting bytes of sources against quotas.
+
+use log::{info, trace, warn};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::observer::FlowObserver;
+use crate::packet::layer::LayerKind;
+
+/// Represents the period after which the usage of quotas is reset.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum QuotaPeriod {
+    /// Resets at 00:00 UTC every day.
+    Day,
+    /// Resets at 00:00 UTC every Monday.
+    Week,
+    /// Resets at 00:00 UTC on the first day of every month.
+    Month,
+}
+
+impl QuotaPeriod {
+    /// Returns the index of the period of the given time in seconds since the Unix epoch.
+    fn index(&self, secs: u64) -> u64 {
+        let days = secs / 86400;
+        match self {
+            QuotaPeriod::Day => days,
+            // 1970-01-01 is a Thursday
+            QuotaPeriod::Week => (days + 3) / 7,
+            QuotaPeriod::Month => {
+                let (year, month) = civil_from_days(days);
+                year * 12 + month - 1
+            }
+        }
+    }
+}
+
+impl Display for QuotaPeriod {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            QuotaPeriod::Day => "day",
+            QuotaPeriod::Week => "week",
+            QuotaPeriod::Month => "month",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Returns the year and the month of the given days since the Unix epoch.
+fn civil_from_days(days: u64) -> (u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month)
+}
+
+/// Represents a quota of bytes of each source in a period, like `50GB/month`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Quota {
+    limit: u64,
+    period: QuotaPeriod,
+}
+
+impl Quota {
+    /// Creates a new `Quota`.
+    pub fn new(limit: u64, period: QuotaPeriod) -> Quota {
+        Quota { limit, period }
+    }
+
+    /// Returns the limit of bytes in a period.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Returns the period.
+    pub fn period(&self) -> QuotaPeriod {
+        self.period
+    }
+}
+
+impl Display for Quota {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} Bytes/{}", self.limit, self.period)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.split('/').collect::<Vec<_>>();
+        if v.len() != 2 {
+            return Err(String::from("expect LIMIT/PERIOD"));
+        }
+
+        let limit = v[0].trim();
+        let i = limit
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(limit.len());
+        let value = limit[..i]
+            .parse::<u64>()
+            .map_err(|e| format!("invalid limit {}: {}", v[0], e))?;
+        let unit = match limit[i..].trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" => 1024,
+            "M" | "MB" => 1024 * 1024,
+            "G" | "GB" => 1024 * 1024 * 1024,
+            "T" | "TB" => 1024 * 1024 * 1024 * 1024,
+            _ => return Err(format!("invalid unit of limit {}", v[0])),
+        };
+        let limit = value
+            .checked_mul(unit)
+            .ok_or_else(|| format!("limit {} too big", v[0]))?;
+
+        let period = match v[1].trim().to_ascii_lowercase().as_str() {
+            "day" | "daily" => QuotaPeriod::Day,
+            "week" | "weekly" => QuotaPeriod::Week,
+            "month" | "monthly" => QuotaPeriod::Month,
+            _ => return Err(format!("invalid period {}", v[1])),
+        };
+
+        Ok(Quota { limit, period })
+    }
+}
+
+/// Represents the policy of new flows of sources which have used up their quotas.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum QuotaPolicy {
+    /// Represents resetting new TCP connections and dropping new UDP flows.
+    Reject,
+    /// Represents connecting new TCP connections directly from the host without the proxy. New
+    /// UDP flows are still dropped, since the direct path only supports TCP.
+    Direct,
+}
+
+impl Display for QuotaPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            QuotaPolicy::Reject => "reject",
+            QuotaPolicy::Direct => "direct",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(QuotaPolicy::Reject),
+            "direct" => Ok(QuotaPolicy::Direct),
+            _ => Err(format!("invalid policy {}, please use reject or direct", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QuotaUsages {
+    period: u64,
+    usages: HashMap<Ipv4Addr, u64>,
+    is_dirty: bool,
+}
+
+/// Represents an observer which counts bytes relayed from and to each source against a quota.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quota: Quota,
+    path: Option<String>,
+    usages: Mutex<QuotaUsages>,
+}
+
+impl QuotaTracker {
+    /// Creates a new `QuotaTracker`.
+    pub fn new(quota: Quota) -> QuotaTracker {
+        QuotaTracker {
+            quota,
+            path: None,
+            usages: Mutex::new(QuotaUsages {
+                period: quota.period.index(now()),
+                usages: HashMap::new(),
+                is_dirty: false,
+            }),
+        }
+    }
+
+    /// Creates a new `QuotaTracker` which saves the usages to the file of the given path, and
+    /// restores the usages from it if they are in the current period.
+    pub fn open(quota: Quota, path: &str) -> io::Result<QuotaTracker> {
+        let mut tracker = QuotaTracker::new(quota);
+        tracker.path = Some(path.to_string());
+
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(tracker);
+                }
+                return Err(e);
+            }
+        };
+        let mut lines = s.lines();
+        let period = lines
+            .next()
+            .and_then(|line| line.trim().parse::<u64>().ok());
+        {
+            let mut usages = tracker.usages.lock().unwrap();
+            if period == Some(usages.period) {
+                for line in lines {
+                    let v = line.split_whitespace().collect::<Vec<_>>();
+                    if v.len() != 2 {
+                        continue;
+                    }
+                    if let (Ok(ip_addr), Ok(usage)) = (v[0].parse(), v[1].parse()) {
+                        usages.usages.insert(ip_addr, usage);
+                    }
+                }
+            }
+        }
+
+        Ok(tracker)
+    }
+
+    /// Returns the quota.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Returns if the source has used up its quota in the current period.
+    pub fn is_exceeded(&self, ip_addr: Ipv4Addr) -> bool {
+        let mut usages = self.usages.lock().unwrap();
+        self.reset_if_expired(&mut usages);
+
+        *usages.usages.get(&ip_addr).unwrap_or(&0) >= self.quota.limit
+    }
+
+    /// Returns the usages of all the sources in the current period.
+    pub fn usages(&self) -> Vec<(Ipv4Addr, u64)> {
+        let mut usages = self.usages.lock().unwrap();
+        self.reset_if_expired(&mut usages);
+
+        let mut usages = usages
+            .usages
+            .iter()
+            .map(|(ip_addr, usage)| (*ip_addr, *usage))
+            .collect::<Vec<_>>();
+        usages.sort();
+
+        usages
+    }
+
+    /// Saves the usages to the file if they have changed.
+    pub fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let s = {
+            let mut usages = self.usages.lock().unwrap();
+            if !usages.is_dirty {
+                return;
+            }
+            usages.is_dirty = false;
+
+            let mut s = format!("{}\n", usages.period);
+            for (ip_addr, usage) in usages.usages.iter() {
+                s.push_str(&format!("{} {}\n", ip_addr, usage));
+            }
+
+            s
+        };
+
+        // Write to a temporary file and rename it to avoid a partial file
+        let tmp_path = format!("{}.tmp", path);
+        if let Err(ref e) = fs::write(&tmp_path, s).and_then(|_| fs::rename(&tmp_path, path)) {
+            warn!("save quota usages: {}", e);
+        }
+    }
+
+    fn add(&self, ip_addr: Ipv4Addr, n: usize) {
+        let mut usages = self.usages.lock().unwrap();
+        self.reset_if_expired(&mut usages);
+
+        let usage = usages.usages.entry(ip_addr).or_insert(0);
+        let is_exceeded = *usage >= self.quota.limit;
+        *usage = usage.checked_add(n as u64).unwrap_or(u64::MAX);
+        if !is_exceeded && *usage >= self.quota.limit {
+            info!("Device {} used up its quota of {}", ip_addr, self.quota);
+        }
+        usages.is_dirty = true;
+    }
+
+    fn reset_if_expired(&self, usages: &mut QuotaUsages) {
+        let period = self.quota.period.index(now());
+        if period != usages.period {
+            usages.period = period;
+            usages.usages.clear();
+            usages.is_dirty = true;
+            trace!("reset quota usages");
+        }
+    }
+}
+
+impl FlowObserver for QuotaTracker {
+    fn on_bytes_sent(&self, _kind: LayerKind, src: SocketAddrV4, _dst: SocketAddrV4, n: usize) {
+        self.add(*src.ip(), n);
+    }
+
+    fn on_bytes_received(&self, _kind: LayerKind, _dst: SocketAddrV4, src: SocketAddrV4, n: usize) {
+        self.add(*src.ip(), n);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[test]
+fn quota_from_str() {
+    assert_eq!(
+        "50GB/month".parse(),
+        Ok(Quota::new(50 * 1024 * 1024 * 1024, QuotaPeriod::Month))
+    );
+    assert_eq!(
+        "512 MB/day".parse(),
+        Ok(Quota::new(512 * 1024 * 1024, QuotaPeriod::Day))
+    );
+    assert!("50GB".parse::<Quota>().is_err());
+    assert!("50XB/month".parse::<Quota>().is_err());
+    assert!("50GB/year".parse::<Quota>().is_err());
+
+    // 2020-02-29 and 2020-03-01
+    assert_eq!(QuotaPeriod::Month.index(1582934400), 2020 * 12 + 1);
+    assert_eq!(QuotaPeriod::Month.index(1583020800), 2020 * 12 + 2);
+    // 2021-01-03 is a Sunday and 2021-01-04 is a Monday
+    assert_eq!(
+        QuotaPeriod::Week.index(1609632000) + 1,
+        QuotaPeriod::Week.index(1609718400)
+    );
+}
+
+#[test]
+fn quota_policy_from_str() {
+    assert_eq!("reject".parse(), Ok(QuotaPolicy::Reject));
+    assert_eq!("Direct".parse(), Ok(QuotaPolicy::Direct));
+    assert!("proxy".parse::<QuotaPolicy>().is_err());
+    assert_eq!(QuotaPolicy::Direct.to_string(), "direct");
+}
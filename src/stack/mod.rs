@@ -0,0 +1,292 @@
+//! Support for the user-space network stack without pcap.
+//!
+//! This module gathers the parsers and serializers of layers, the defragmentation and the TCP
+//! state machine which depend on neither pcap devices nor the asynchronous runtime, so they can
+//! be used standalone in other projects, tests and fuzzers. It also provides `Stack`, which runs
+//! the redirector over a `Device` in memory, so the redirector can be embedded behind any source
+//! of Ethernet frames, like a TAP device or the network of a virtual machine.
+
+use ipnetwork::Ipv4Network;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::collections::VecDeque;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::pcap::{HardwareAddr, Receiver, Sender};
+use crate::{Forwarder, Redirector};
+
+pub use crate::packet::layer::arp::Arp;
+pub use crate::packet::layer::ethernet::Ethernet;
+pub use crate::packet::layer::icmpv4::Icmpv4;
+pub use crate::packet::layer::ipv4::Ipv4;
+pub use crate::packet::layer::tcp::Tcp;
+pub use crate::packet::layer::udp::Udp;
+pub use crate::packet::layer::{CustomLayer, Layer, LayerKind, LayerKinds, Layers};
+pub use crate::packet::{Defraggler, Fragmentation, Indicator, Parsers};
+pub use crate::tcp::{TcpCc, TcpCcAlgorithms, TcpRxState, TcpTxState, Timer};
+
+/// Represents the default MTU of a `Stack`.
+const DEFAULT_MTU: usize = 1500;
+
+/// Represents the max time in milliseconds the receive half of a device waits for a frame in each
+/// receiving.
+const READ_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Default)]
+struct DeviceQueues {
+    inbound: VecDeque<Vec<u8>>,
+    outbound: VecDeque<Vec<u8>>,
+}
+
+/// Represents a virtual device in memory, where frames can be injected to its receive half and
+/// frames sent by its send half can be taken out.
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    queues: Arc<(Mutex<DeviceQueues>, Condvar)>,
+}
+
+impl Device {
+    /// Constructs a new `Device`.
+    pub fn new() -> Device {
+        Device::default()
+    }
+
+    /// Opens the virtual device for sending and receiving data.
+    pub fn open(&self) -> (Sender, Receiver) {
+        (
+            Box::new(DeviceSender {
+                queues: Arc::clone(&self.queues),
+            }),
+            Box::new(DeviceReceiver {
+                queues: Arc::clone(&self.queues),
+                frame: Vec::new(),
+            }),
+        )
+    }
+
+    /// Injects a frame which will be received from the receive half.
+    pub fn inject(&self, frame: &[u8]) {
+        let (queues, cvar) = &*self.queues;
+        queues.lock().unwrap().inbound.push_back(frame.to_vec());
+        cvar.notify_one();
+    }
+
+    /// Takes the earliest frame sent by the send half.
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.queues.0.lock().unwrap().outbound.pop_front()
+    }
+
+    /// Takes all the frames sent by the send half.
+    pub fn take_all(&self) -> Vec<Vec<u8>> {
+        self.queues.0.lock().unwrap().outbound.drain(..).collect()
+    }
+}
+
+/// Represents the send half of a `Device`.
+#[derive(Debug)]
+struct DeviceSender {
+    queues: Arc<(Mutex<DeviceQueues>, Condvar)>,
+}
+
+impl DataLinkSender for DeviceSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0u8; packet_size];
+            func(&mut frame);
+            self.queues.0.lock().unwrap().outbound.push_back(frame);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        self.queues
+            .0
+            .lock()
+            .unwrap()
+            .outbound
+            .push_back(packet.to_vec());
+
+        Some(Ok(()))
+    }
+}
+
+/// Represents the receive half of a `Device`. It returns a `TimedOut` error if there is no frame
+/// injected in `READ_TIMEOUT`, just like a pcap device with a read timeout.
+#[derive(Debug)]
+struct DeviceReceiver {
+    queues: Arc<(Mutex<DeviceQueues>, Condvar)>,
+    frame: Vec<u8>,
+}
+
+impl DataLinkReceiver for DeviceReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let (queues, cvar) = &*self.queues;
+        let mut queues = queues.lock().unwrap();
+        if queues.inbound.is_empty() {
+            queues = cvar
+                .wait_timeout(queues, Duration::from_millis(READ_TIMEOUT))
+                .unwrap()
+                .0;
+        }
+        match queues.inbound.pop_front() {
+            Some(frame) => self.frame = frame,
+            None => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+
+        Ok(&self.frame)
+    }
+}
+
+/// Represents a redirector running over a `Device`. Frames from sources are injected into the
+/// device, and frames to sources are taken out of it.
+pub struct Stack {
+    device: Device,
+    forwarder: Arc<Mutex<Forwarder>>,
+    redirector: Redirector,
+    rx: Receiver,
+}
+
+impl Stack {
+    /// Creates a new `Stack` of the configuration, which has the given hardware address and IP
+    /// address. The interface of the configuration is ignored, and the MTU defaults to 1500.
+    pub fn new(config: &Config, hardware_addr: HardwareAddr, ip_addr: Ipv4Addr) -> Stack {
+        let device = Device::new();
+        let (tx, rx) = device.open();
+        let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
+        let src = config
+            .src
+            .unwrap_or_else(|| Ipv4Network::new(Ipv4Addr::BROADCAST, 32).unwrap());
+
+        let forwarder = Arc::new(Mutex::new(Forwarder::new(tx, mtu, hardware_addr, ip_addr)));
+        let redirector = Redirector::new(
+            Arc::clone(&forwarder),
+            src,
+            ip_addr,
+            config.publish,
+            config.proxy(),
+        );
+
+        Stack {
+            device,
+            forwarder,
+            redirector,
+            rx,
+        }
+    }
+
+    /// Returns the device of the stack.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Returns the forwarder of the stack, which can be tuned before running.
+    pub fn forwarder(&self) -> Arc<Mutex<Forwarder>> {
+        Arc::clone(&self.forwarder)
+    }
+
+    /// Returns the redirector of the stack, which can be tuned before running.
+    pub fn redirector_mut(&mut self) -> &mut Redirector {
+        &mut self.redirector
+    }
+
+    /// Runs the stack until `is_running` is cleared, or forever if it is not given.
+    pub async fn run(&mut self, is_running: Option<Arc<AtomicBool>>) -> io::Result<()> {
+        self.redirector
+            .open_monitored(&mut self.rx, is_running, None, None)
+            .await
+    }
+}
+
+#[test]
+fn device_inject_take() {
+    let device = Device::new();
+    let (mut tx, mut rx) = device.open();
+
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    device.inject(&[1, 2, 3]);
+    assert_eq!(rx.next().unwrap(), &[1, 2, 3]);
+
+    tx.build_and_send(1, 2, &mut |buffer| buffer.copy_from_slice(&[4, 5]))
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.take(), Some(vec![4, 5]));
+    assert!(device.take().is_none());
+}
+
+#[tokio::test]
+async fn stack_arp_reply() {
+    use crate::packet::builder::EthernetBuilder;
+    use pnet::packet::arp::{self, ArpHardwareTypes, ArpOperations};
+    use pnet::packet::ethernet::EtherTypes;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    let local_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+    let gw_ip_addr = Ipv4Addr::new(10, 6, 0, 254);
+
+    let mut config = Config::new("127.0.0.1:1080".parse().unwrap());
+    config.src = Some(Ipv4Network::new(src_ip_addr, 32).unwrap());
+    config.publish = Some(gw_ip_addr);
+    let mut stack = Stack::new(&config, local_hardware_addr, Ipv4Addr::new(192, 168, 1, 2));
+
+    // The source asks for the published address
+    let arp = Arp::from(arp::Arp {
+        hardware_type: ArpHardwareTypes::Ethernet,
+        protocol_type: EtherTypes::Ipv4,
+        hw_addr_len: 6,
+        proto_addr_len: 4,
+        operation: ArpOperations::Request,
+        sender_hw_addr: src_hardware_addr,
+        sender_proto_addr: src_ip_addr,
+        target_hw_addr: HardwareAddr::zero(),
+        target_proto_addr: gw_ip_addr,
+        payload: vec![],
+    });
+    let indicator = EthernetBuilder::new(src_hardware_addr, HardwareAddr::broadcast())
+        .arp(arp)
+        .build()
+        .unwrap();
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    stack.device().inject(&frame);
+
+    let is_running = Arc::new(AtomicBool::new(true));
+    let is_running_cloned = Arc::clone(&is_running);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        is_running_cloned.store(false, Ordering::Relaxed);
+    });
+    stack.run(Some(is_running)).await.unwrap();
+
+    let arps = stack
+        .device()
+        .take_all()
+        .iter()
+        .map(|frame| {
+            let indicator = Indicator::from(frame).unwrap();
+            let arp = indicator.arp().unwrap();
+
+            (arp.src_hardware_addr(), arp.src(), arp.dst())
+        })
+        .collect::<Vec<_>>();
+    // Publish, and reply the source
+    assert_eq!(
+        arps,
+        vec![
+            (local_hardware_addr, gw_ip_addr, gw_ip_addr),
+            (local_hardware_addr, gw_ip_addr, src_ip_addr)
+        ]
+    );
+}
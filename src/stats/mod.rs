@@ -0,0 +1,626 @@
+//! Support for measuring statistics of connections.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use crate::oui;
+use crate::pcap::HardwareAddr;
+
+/// Represents the weight of a new sample in the smoothed RTT of a source.
+const SRTT_ALPHA: f64 = 1.0 / 8.0;
+
+/// Represents the number of sub-buckets in each power of 2 in a histogram. Values are recorded
+/// with a relative error of at most 1 / `SUB_BUCKETS`.
+const SUB_BUCKETS: u64 = 16;
+/// Represents the quantiles exported of a histogram.
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Represents the statistics of a TCP connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlowStats {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    srtt: Option<f64>,
+    sent: usize,
+    retrans: usize,
+}
+
+impl FlowStats {
+    /// Creates a new `FlowStats`.
+    pub fn new(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        srtt: Option<f64>,
+        sent: usize,
+        retrans: usize,
+    ) -> FlowStats {
+        FlowStats {
+            src,
+            dst,
+            srtt,
+            sent,
+            retrans,
+        }
+    }
+
+    /// Returns the source of the TCP connection.
+    pub fn src(&self) -> SocketAddrV4 {
+        self.src
+    }
+
+    /// Returns the destination of the TCP connection.
+    pub fn dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Returns the smoothed RTT in seconds of the TCP connection.
+    pub fn srtt(&self) -> Option<f64> {
+        self.srtt
+    }
+
+    /// Returns the size of the payload sent to the source of the TCP connection.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the size of the payload retransmitted to the source of the TCP connection.
+    pub fn retrans(&self) -> usize {
+        self.retrans
+    }
+
+    /// Returns the retransmission rate of the TCP connection.
+    pub fn retrans_rate(&self) -> f64 {
+        retrans_rate(self.sent, self.retrans)
+    }
+}
+
+impl Display for FlowStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {}: SRTT {}, sent {} Bytes, retransmitted {} Bytes ({:.2}%)",
+            self.dst,
+            self.src,
+            srtt_to_string(self.srtt),
+            self.sent,
+            self.retrans,
+            self.retrans_rate() * 100.0
+        )
+    }
+}
+
+/// Represents the statistics of a source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientStats {
+    ip_addr: Ipv4Addr,
+    hardware_addr: Option<HardwareAddr>,
+    flows: usize,
+    active: usize,
+    srtt: Option<f64>,
+    sent: usize,
+    retrans: usize,
+}
+
+impl ClientStats {
+    /// Creates a new `ClientStats`.
+    pub fn new(ip_addr: Ipv4Addr) -> ClientStats {
+        ClientStats {
+            ip_addr,
+            hardware_addr: None,
+            flows: 0,
+            active: 0,
+            srtt: None,
+            sent: 0,
+            retrans: 0,
+        }
+    }
+
+    /// Adds the statistics of a TCP connection to the source.
+    pub fn add_flow(&mut self, flow: &FlowStats, is_active: bool) {
+        self.flows = self.flows.checked_add(1).unwrap_or(usize::MAX);
+        if is_active {
+            self.active = self.active.checked_add(1).unwrap_or(usize::MAX);
+        }
+        if let Some(srtt) = flow.srtt() {
+            self.srtt = match self.srtt {
+                Some(prev_srtt) => Some((1.0 - SRTT_ALPHA) * prev_srtt + SRTT_ALPHA * srtt),
+                None => Some(srtt),
+            };
+        }
+        self.sent = self.sent.checked_add(flow.sent()).unwrap_or(usize::MAX);
+        self.retrans = self
+            .retrans
+            .checked_add(flow.retrans())
+            .unwrap_or(usize::MAX);
+    }
+
+    /// Sets the hardware address of the source.
+    pub fn set_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.hardware_addr = Some(hardware_addr);
+    }
+
+    /// Returns the IP address of the source.
+    pub fn ip_addr(&self) -> Ipv4Addr {
+        self.ip_addr
+    }
+
+    /// Returns the hardware address of the source.
+    pub fn hardware_addr(&self) -> Option<HardwareAddr> {
+        self.hardware_addr
+    }
+
+    /// Returns the vendor of the source by its hardware address.
+    pub fn vendor(&self) -> Option<&'static str> {
+        self.hardware_addr.and_then(oui::vendor)
+    }
+
+    /// Returns the number of TCP connections of the source.
+    pub fn flows(&self) -> usize {
+        self.flows
+    }
+
+    /// Returns the number of active TCP connections of the source.
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Returns the smoothed RTT in seconds of the source.
+    pub fn srtt(&self) -> Option<f64> {
+        self.srtt
+    }
+
+    /// Returns the size of the payload sent to the source.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the size of the payload retransmitted to the source.
+    pub fn retrans(&self) -> usize {
+        self.retrans
+    }
+
+    /// Returns the retransmission rate of the source.
+    pub fn retrans_rate(&self) -> f64 {
+        retrans_rate(self.sent, self.retrans)
+    }
+}
+
+impl Display for ClientStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.ip_addr)?;
+        if let Some(vendor) = self.vendor() {
+            write!(f, " ({})", vendor)?;
+        }
+        write!(
+            f,
+            ": {} connections ({} active), SRTT {}, sent {} Bytes, retransmitted {} Bytes ({:.2}%)",
+            self.flows,
+            self.active,
+            srtt_to_string(self.srtt),
+            self.sent,
+            self.retrans,
+            self.retrans_rate() * 100.0
+        )
+    }
+}
+
+/// Represents a histogram of latencies in microseconds. Like an HDR histogram, values are
+/// recorded in buckets whose width grows with the magnitude, so a fixed relative precision is
+/// kept from microseconds to minutes in a small memory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    /// Creates a new `Histogram`.
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    /// Records a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let value = latency.as_micros().min(u64::MAX as u128) as u64;
+
+        let index = bucket_index(value);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.count = self.count.checked_add(1).unwrap_or(u64::MAX);
+        self.sum = self.sum.checked_add(value).unwrap_or(u64::MAX);
+        self.max = self.max.max(value);
+    }
+
+    /// Returns the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of recorded latencies in seconds.
+    pub fn sum(&self) -> f64 {
+        self.sum as f64 / 1_000_000.0
+    }
+
+    /// Returns the latency in seconds at the quantile, or `None` if no latency is recorded.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let target = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(bucket_value(index).min(self.max) as f64 / 1_000_000.0);
+            }
+        }
+
+        Some(self.max as f64 / 1_000_000.0)
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    // Values are exact below 2 * `SUB_BUCKETS`
+    if value < 2 * SUB_BUCKETS {
+        return value as usize;
+    }
+
+    let exp = 63 - value.leading_zeros() as u64;
+    let shift = exp - SUB_BUCKETS.trailing_zeros() as u64;
+    let sub = (value >> shift) - SUB_BUCKETS;
+
+    (2 * SUB_BUCKETS + (shift - 1) * SUB_BUCKETS + sub) as usize
+}
+
+/// Returns the highest value of the bucket.
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * SUB_BUCKETS {
+        return index;
+    }
+
+    let shift = (index - 2 * SUB_BUCKETS) / SUB_BUCKETS + 1;
+    let sub = (index - 2 * SUB_BUCKETS) % SUB_BUCKETS + SUB_BUCKETS;
+    let value = ((sub + 1) as u128) << shift;
+
+    (value - 1).min(u64::MAX as u128) as u64
+}
+
+/// Represents the latencies of an upstream proxy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamStats {
+    addr: SocketAddr,
+    connect: Histogram,
+    first_byte: Histogram,
+}
+
+impl UpstreamStats {
+    /// Creates a new `UpstreamStats`.
+    pub fn new(addr: SocketAddr) -> UpstreamStats {
+        UpstreamStats {
+            addr,
+            connect: Histogram::new(),
+            first_byte: Histogram::new(),
+        }
+    }
+
+    /// Returns the address of the upstream proxy.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the histogram of latencies connecting to destinations through the upstream proxy,
+    /// including the SOCKS handshake.
+    pub fn connect(&self) -> &Histogram {
+        &self.connect
+    }
+
+    /// Returns the histogram of latencies from connected to the first byte received in TCP
+    /// connections through the upstream proxy.
+    pub fn first_byte(&self) -> &Histogram {
+        &self.first_byte
+    }
+}
+
+impl Display for UpstreamStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: connect {} ({} samples), first byte {} ({} samples)",
+            self.addr,
+            quantiles_to_string(&self.connect),
+            self.connect.count(),
+            quantiles_to_string(&self.first_byte),
+            self.first_byte.count()
+        )
+    }
+}
+
+/// Represents the latencies of all upstream proxies.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyStats {
+    upstreams: HashMap<SocketAddr, UpstreamStats>,
+}
+
+impl LatencyStats {
+    /// Creates a new `LatencyStats`.
+    pub fn new() -> LatencyStats {
+        LatencyStats::default()
+    }
+
+    fn upstream(&mut self, addr: SocketAddr) -> &mut UpstreamStats {
+        self.upstreams
+            .entry(addr)
+            .or_insert_with(|| UpstreamStats::new(addr))
+    }
+
+    /// Records a latency connecting to a destination through the upstream proxy.
+    pub fn add_connect(&mut self, addr: SocketAddr, latency: Duration) {
+        self.upstream(addr).connect.record(latency);
+    }
+
+    /// Records a latency from connected to the first byte received through the upstream proxy.
+    pub fn add_first_byte(&mut self, addr: SocketAddr, latency: Duration) {
+        self.upstream(addr).first_byte.record(latency);
+    }
+
+    /// Returns the latencies of each upstream proxy, sorted by the address.
+    pub fn upstreams(&self) -> Vec<UpstreamStats> {
+        let mut upstreams = self.upstreams.values().cloned().collect::<Vec<_>>();
+        upstreams.sort_by_key(|stats| stats.addr());
+
+        upstreams
+    }
+
+    /// Returns the latencies in the Prometheus text exposition format, as summaries labeled with
+    /// the upstream proxy.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        let upstreams = self.upstreams();
+        for (name, help) in &[
+            (
+                "pcap2socks_upstream_connect_seconds",
+                "Latency connecting to destinations through the upstream proxy",
+            ),
+            (
+                "pcap2socks_upstream_first_byte_seconds",
+                "Latency from connected to the first byte received through the upstream proxy",
+            ),
+        ] {
+            let _ = writeln!(s, "# HELP {} {}", name, help);
+            let _ = writeln!(s, "# TYPE {} summary", name);
+            for upstream in upstreams.iter() {
+                let histogram = match *name {
+                    "pcap2socks_upstream_connect_seconds" => upstream.connect(),
+                    _ => upstream.first_byte(),
+                };
+                for q in QUANTILES.iter() {
+                    if let Some(value) = histogram.quantile(*q) {
+                        let _ = writeln!(
+                            s,
+                            "{}{{upstream=\"{}\",quantile=\"{}\"}} {}",
+                            name,
+                            upstream.addr(),
+                            q,
+                            value
+                        );
+                    }
+                }
+                let _ = writeln!(
+                    s,
+                    "{}_sum{{upstream=\"{}\"}} {}",
+                    name,
+                    upstream.addr(),
+                    histogram.sum()
+                );
+                let _ = writeln!(
+                    s,
+                    "{}_count{{upstream=\"{}\"}} {}",
+                    name,
+                    upstream.addr(),
+                    histogram.count()
+                );
+            }
+        }
+
+        s
+    }
+}
+
+/// Enumeration of stages of a frame in the hot path.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Stage {
+    /// Represents parsing a captured frame.
+    Parse,
+    /// Represents filtering a parsed frame.
+    Filter,
+    /// Represents dispatching a frame to its handler, including forwarding to the proxy and
+    /// injecting the replies it triggers.
+    Dispatch,
+    /// Represents building and injecting a frame to a source.
+    Inject,
+}
+
+/// Represents all the stages in the order of the hot path.
+const STAGES: [Stage; 4] = [Stage::Parse, Stage::Filter, Stage::Dispatch, Stage::Inject];
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            Stage::Parse => "parse",
+            Stage::Filter => "filter",
+            Stage::Dispatch => "dispatch",
+            Stage::Inject => "inject",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Represents the latencies of each stage in the hot path, which attribute the time processing
+/// frames to the stages.
+#[derive(Clone, Debug, Default)]
+pub struct StageStats {
+    stages: HashMap<Stage, Histogram>,
+}
+
+impl StageStats {
+    /// Creates a new `StageStats`.
+    pub fn new() -> StageStats {
+        StageStats::default()
+    }
+
+    /// Records a latency of the stage.
+    pub fn record(&mut self, stage: Stage, latency: Duration) {
+        self.stages.entry(stage).or_default().record(latency);
+    }
+
+    /// Returns the histogram of latencies of the stage.
+    pub fn stage(&self, stage: Stage) -> Option<&Histogram> {
+        self.stages.get(&stage)
+    }
+
+    /// Returns the latencies in the Prometheus text exposition format, as summaries labeled with
+    /// the stage.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        let name = "pcap2socks_stage_seconds";
+        let _ = writeln!(s, "# HELP {} Latency of each stage in the hot path", name);
+        let _ = writeln!(s, "# TYPE {} summary", name);
+        for stage in STAGES.iter() {
+            let histogram = match self.stage(*stage) {
+                Some(histogram) => histogram,
+                None => continue,
+            };
+            for q in QUANTILES.iter() {
+                if let Some(value) = histogram.quantile(*q) {
+                    let _ = writeln!(
+                        s,
+                        "{}{{stage=\"{}\",quantile=\"{}\"}} {}",
+                        name, stage, q, value
+                    );
+                }
+            }
+            let _ = writeln!(s, "{}_sum{{stage=\"{}\"}} {}", name, stage, histogram.sum());
+            let _ = writeln!(
+                s,
+                "{}_count{{stage=\"{}\"}} {}",
+                name,
+                stage,
+                histogram.count()
+            );
+        }
+
+        s
+    }
+}
+
+impl Display for StageStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let stages = STAGES
+            .iter()
+            .filter_map(|stage| {
+                self.stage(*stage).map(|histogram| {
+                    format!(
+                        "{} {}",
+                        stage,
+                        QUANTILES
+                            .iter()
+                            .map(|q| format!(
+                                "p{} {}",
+                                q * 100.0,
+                                micros_to_string(histogram.quantile(*q))
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", stages.join("; "))
+    }
+}
+
+fn micros_to_string(latency: Option<f64>) -> String {
+    match latency {
+        Some(latency) => format!("{:.0} µs", latency * 1_000_000.0),
+        None => String::from("unknown"),
+    }
+}
+
+fn retrans_rate(sent: usize, retrans: usize) -> f64 {
+    if sent == 0 {
+        0.0
+    } else {
+        retrans as f64 / sent as f64
+    }
+}
+
+fn srtt_to_string(srtt: Option<f64>) -> String {
+    match srtt {
+        Some(srtt) => format!("{:.0} ms", srtt * 1000.0),
+        None => String::from("unknown"),
+    }
+}
+
+fn quantiles_to_string(histogram: &Histogram) -> String {
+    QUANTILES
+        .iter()
+        .map(|q| format!("p{} {}", q * 100.0, srtt_to_string(histogram.quantile(*q))))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[test]
+fn client_stats_add_flow() {
+    let ip_addr = Ipv4Addr::new(10, 6, 0, 1);
+    let src = SocketAddrV4::new(ip_addr, 1000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+
+    let mut stats = ClientStats::new(ip_addr);
+    stats.add_flow(&FlowStats::new(src, dst, Some(0.1), 1000, 100), false);
+    stats.add_flow(&FlowStats::new(src, dst, None, 1000, 0), true);
+    assert_eq!(stats.flows(), 2);
+    assert_eq!(stats.active(), 1);
+    assert_eq!(stats.srtt(), Some(0.1));
+    assert_eq!(stats.sent(), 2000);
+    assert!((stats.retrans_rate() - 0.05).abs() < f64::EPSILON);
+}
+
+#[test]
+fn histogram_quantile() {
+    let mut histogram = Histogram::new();
+    for i in 1..=100 {
+        histogram.record(Duration::from_millis(i));
+    }
+    assert_eq!(histogram.count(), 100);
+    assert!((histogram.sum() - 5.05).abs() < 1e-9);
+    for (q, expected) in &[(0.5, 0.05), (0.9, 0.09), (0.99, 0.099)] {
+        let value = histogram.quantile(*q).unwrap();
+        assert!(value >= *expected && value <= expected * (1.0 + 1.0 / SUB_BUCKETS as f64));
+    }
+    assert_eq!(Histogram::new().quantile(0.5), None);
+}
+
+#[test]
+fn stage_stats_to_prometheus() {
+    let mut stages = StageStats::new();
+    stages.record(Stage::Parse, Duration::from_micros(10));
+    stages.record(Stage::Inject, Duration::from_micros(20));
+    let s = stages.to_prometheus();
+    assert!(s.contains("pcap2socks_stage_seconds{stage=\"parse\",quantile=\"0.5\"} 0.00001\n"));
+    assert!(s.contains("pcap2socks_stage_seconds_count{stage=\"inject\"} 1\n"));
+    assert!(!s.contains("stage=\"dispatch\""));
+    assert_eq!(
+        stages.to_string(),
+        "parse p50 10 µs, p90 10 µs, p99 10 µs; inject p50 20 µs, p90 20 µs, p99 20 µs"
+    );
+}
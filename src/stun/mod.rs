@@ -0,0 +1,154 @@
+//! Support for recognizing STUN messages.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+const HEADER_SIZE: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112a442;
+const METHOD_BINDING: u16 = 0x0001;
+const CLASS_MASK: u16 = 0x0110;
+const CLASS_REQUEST: u16 = 0x0000;
+const CLASS_SUCCESS_RESPONSE: u16 = 0x0100;
+const CLASS_ERROR_RESPONSE: u16 = 0x0110;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+
+/// Represents a STUN message.
+#[derive(Clone, Debug)]
+pub struct Message {
+    message_type: u16,
+    transaction_id: [u8; 12],
+    mapped_addr: Option<SocketAddrV4>,
+}
+
+impl Message {
+    /// Parses a STUN message from the given buffer. Returns `None` if the buffer is not a STUN
+    /// message defined in RFC 5389.
+    pub fn parse(buffer: &[u8]) -> Option<Message> {
+        if buffer.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let message_type = u16::from_be_bytes([buffer[0], buffer[1]]);
+        let length = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        let magic_cookie = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        // The most significant 2 bits are zeroes, and attributes are padded to 4 Bytes
+        if message_type & 0xc000 != 0
+            || magic_cookie != MAGIC_COOKIE
+            || length % 4 != 0
+            || HEADER_SIZE + length != buffer.len()
+        {
+            return None;
+        }
+
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buffer[8..HEADER_SIZE]);
+
+        // Attributes, where XOR-MAPPED-ADDRESS takes precedence over MAPPED-ADDRESS
+        let mut mapped_addr = None;
+        let mut i = HEADER_SIZE;
+        while i + 4 <= buffer.len() {
+            let attribute_type = u16::from_be_bytes([buffer[i], buffer[i + 1]]);
+            let attribute_length = u16::from_be_bytes([buffer[i + 2], buffer[i + 3]]) as usize;
+            let value = &buffer[i + 4..buffer.len().min(i + 4 + attribute_length)];
+            match attribute_type {
+                ATTRIBUTE_XOR_MAPPED_ADDRESS => {
+                    if let Some(addr) = parse_addr(value) {
+                        let cookie = MAGIC_COOKIE.to_be_bytes();
+                        let port = addr.port() ^ u16::from_be_bytes([cookie[0], cookie[1]]);
+                        let ip_addr = u32::from(*addr.ip()) ^ MAGIC_COOKIE;
+                        mapped_addr = Some(SocketAddrV4::new(Ipv4Addr::from(ip_addr), port));
+                    }
+                }
+                ATTRIBUTE_MAPPED_ADDRESS => {
+                    if mapped_addr.is_none() {
+                        mapped_addr = parse_addr(value);
+                    }
+                }
+                _ => {}
+            }
+            // Padded to 4 Bytes
+            i += 4 + (attribute_length + 3) / 4 * 4;
+        }
+
+        Some(Message {
+            message_type,
+            transaction_id,
+            mapped_addr,
+        })
+    }
+
+    fn method(&self) -> u16 {
+        self.message_type & !CLASS_MASK
+    }
+
+    fn class(&self) -> u16 {
+        self.message_type & CLASS_MASK
+    }
+
+    /// Returns if the message is a binding request.
+    pub fn is_binding_request(&self) -> bool {
+        self.method() == METHOD_BINDING && self.class() == CLASS_REQUEST
+    }
+
+    /// Returns if the message is a binding response, either succeeded or failed.
+    pub fn is_binding_response(&self) -> bool {
+        self.method() == METHOD_BINDING
+            && (self.class() == CLASS_SUCCESS_RESPONSE || self.class() == CLASS_ERROR_RESPONSE)
+    }
+
+    /// Returns the transaction ID.
+    pub fn transaction_id(&self) -> &[u8] {
+        &self.transaction_id
+    }
+
+    /// Returns the IPv4 address in the XOR-MAPPED-ADDRESS or MAPPED-ADDRESS attribute, which is
+    /// the external mapping of the requester seen by the server.
+    pub fn mapped_addr(&self) -> Option<SocketAddrV4> {
+        self.mapped_addr
+    }
+}
+
+/// Parses the value of MAPPED-ADDRESS. Returns `None` if the address is not an IPv4 address.
+fn parse_addr(value: &[u8]) -> Option<SocketAddrV4> {
+    if value.len() < 8 || value[1] != FAMILY_IPV4 {
+        return None;
+    }
+
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip_addr = Ipv4Addr::new(value[4], value[5], value[6], value[7]);
+
+    Some(SocketAddrV4::new(ip_addr, port))
+}
+
+#[test]
+fn message_parse() {
+    let mut buffer = vec![
+        0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    ];
+    let message = Message::parse(&buffer).unwrap();
+    assert!(message.is_binding_request());
+    assert!(!message.is_binding_response());
+    assert_eq!(message.transaction_id(), &buffer[8..20]);
+
+    // Binding success response with an XOR-MAPPED-ADDRESS
+    buffer[1] = 0x01;
+    buffer[0] = 0x01;
+    buffer[3] = 0x0c;
+    buffer.extend_from_slice(&[
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    ]);
+    let message = Message::parse(&buffer).unwrap();
+    assert!(!message.is_binding_request());
+    assert!(message.is_binding_response());
+    assert_eq!(
+        message.mapped_addr(),
+        Some("192.0.2.1:32853".parse().unwrap())
+    );
+
+    // Length mismatched
+    buffer.pop();
+    assert!(Message::parse(&buffer).is_none());
+    // DNS query
+    assert!(Message::parse(&[0u8; 32]).is_none());
+}
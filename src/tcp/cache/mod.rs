@@ -5,7 +5,7 @@ use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::{self, Display};
 use std::io::{Error, ErrorKind, Result};
 use std::ops::Bound::Included;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::Timer;
 
@@ -114,6 +114,12 @@ impl Queue {
 
     /// Invalidates queue to the certain sequence and returns the RTT.
     pub fn invalidate_to(&mut self, sequence: u32) -> Option<Duration> {
+        self.invalidate_to_at(sequence, Instant::now())
+    }
+
+    /// Invalidates queue to the certain sequence by an acknowledgement received at the given
+    /// instant, and returns the RTT measured to the instant.
+    pub fn invalidate_to_at(&mut self, sequence: u32, instant: Instant) -> Option<Duration> {
         let size = sequence
             .checked_sub(self.sequence)
             .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
@@ -151,7 +157,7 @@ impl Queue {
                     if !timer.is_timedout() {
                         // Choose the largest RTT
                         if rtt.is_none() {
-                            rtt = Some(timer.elapsed());
+                            rtt = Some(timer.elapsed_to(instant));
 
                             // Rollback on retransmission
                             if let Some(retrans) = self.retrans {
@@ -4,9 +4,11 @@ use log::trace;
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::fmt::{self, Display};
+use std::io;
 use std::net::SocketAddrV4;
 use std::time::{Duration, Instant};
-use tokio::io;
+
+use crate::stats::FlowStats;
 
 mod cache;
 use cache::{Queue, Window};
@@ -27,15 +29,33 @@ impl Timer {
         }
     }
 
+    /// Creates a new `Timer` which started at the given instant.
+    pub fn new_at(instant: Instant, timeout: u64) -> Timer {
+        Timer {
+            instant,
+            timeout: Duration::from_millis(timeout),
+        }
+    }
+
     /// Returns the amount of time elapsed since this timer was created.
     pub fn elapsed(&self) -> Duration {
         self.instant.elapsed()
     }
 
+    /// Returns the amount of time elapsed from the creation of this timer to the given instant.
+    pub fn elapsed_to(&self, instant: Instant) -> Duration {
+        instant.saturating_duration_since(self.instant)
+    }
+
     /// Returns if the timer is timed out.
     pub fn is_timedout(&self) -> bool {
         self.instant.elapsed() > self.timeout
     }
+
+    /// Returns the timeout of the timer.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
 }
 
 /// Represents the max distance of `u32` values between packets in an `u32` window.
@@ -48,12 +68,16 @@ pub enum TcpCcAlgorithms {
     Tahoe,
     /// Represents the TCP Reno congestion control algorithm.
     Reno,
+    /// Represents the TCP NewReno congestion control algorithm.
+    NewReno,
     /// Represents the TCP CUBIC congestion control algorithm.
     Cubic,
 }
 
 /// Represents the initial slow start threshold rate for congestion window in a TCP connection.
 const INITIAL_SSTHRESH_RATE: usize = 100;
+/// Represents the threshold of TCP ACK duplicates before trigger a fast retransmission.
+const DUPLICATES_THRESHOLD: usize = 3;
 
 /// Trait for TCP congestion control.
 pub trait TcpCc: Send + Sync {
@@ -66,8 +90,23 @@ pub trait TcpCc: Send + Sync {
     /// Indicates a TCP timed out event.
     fn timedout(&mut self);
 
-    /// Indicates a TCP fast retransmission event.
-    fn fast_retransmission(&mut self);
+    /// Indicates a TCP fast retransmission event, where the recovery point is the sequence next to
+    /// the highest sequence sent.
+    fn fast_retransmission(&mut self, recover: u32);
+
+    /// Indicates a TCP ACK event to the given sequence in fast recovery. Returns if the ACK is a
+    /// partial acknowledgement which does not cover the recovery point, after which the first
+    /// unacknowledged segment should be retransmitted.
+    fn ack_recovery(&mut self, _sequence: u32, size: usize) -> bool {
+        self.ack(size);
+
+        false
+    }
+
+    /// Returns if the TCP connection is in fast recovery.
+    fn is_recovering(&self) -> bool {
+        false
+    }
 
     /// Returns the congestion window of the TCP connection.
     fn cwnd(&self) -> usize;
@@ -175,7 +214,7 @@ impl TcpCc for TcpTahoeCcState {
         self.cwnd_count = 0;
     }
 
-    fn fast_retransmission(&mut self) {
+    fn fast_retransmission(&mut self, _: u32) {
         self.timedout();
     }
 
@@ -296,7 +335,7 @@ impl TcpCc for TcpRenoCcState {
         self.cwnd_count = 0;
     }
 
-    fn fast_retransmission(&mut self) {
+    fn fast_retransmission(&mut self, _: u32) {
         self.update_ssthresh();
         self.set_cwnd(self.ssthresh);
         self.cwnd_count = 0;
@@ -317,6 +356,199 @@ impl Display for TcpRenoCcState {
     }
 }
 
+/// Represents the TCP NewReno congestion control state of a TCP connection.
+#[derive(Clone, Debug)]
+pub struct TcpNewRenoCcState {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    cwnd_count: usize,
+    recover: Option<u32>,
+}
+
+impl TcpNewRenoCcState {
+    /// Creates a new `TcpNewRenoCcState`.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpNewRenoCcState {
+        TcpNewRenoCcState {
+            src,
+            dst,
+            mss,
+            cwnd: mss,
+            ssthresh: mss.checked_mul(INITIAL_SSTHRESH_RATE).unwrap_or(usize::MAX),
+            cwnd_count: 0,
+            recover: None,
+        }
+    }
+
+    fn set_cwnd(&mut self, cwnd: usize) {
+        self.cwnd = max(self.mss, cwnd);
+        trace!(
+            "set TCP congestion window of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.cwnd
+        );
+    }
+
+    fn update_ssthresh(&mut self) {
+        self.ssthresh = max(self.cwnd / 2, self.mss.checked_mul(2).unwrap_or(usize::MAX));
+        trace!(
+            "update TCP slow start threshold of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.ssthresh
+        );
+    }
+
+    fn set_recover(&mut self, recover: u32) {
+        self.recover = Some(recover);
+        trace!(
+            "set TCP fast recovery of {} -> {} to {}",
+            self.dst,
+            self.src,
+            recover
+        );
+    }
+
+    fn clear_recover(&mut self) {
+        self.recover = None;
+        trace!("clear TCP fast recovery of {} -> {}", self.dst, self.src);
+    }
+
+    fn slow_start(&mut self, size: usize) -> usize {
+        let remain = self.ssthresh - self.cwnd;
+        let delta = min(remain, size);
+
+        self.set_cwnd(self.cwnd.checked_add(delta).unwrap_or(usize::MAX));
+
+        size - delta
+    }
+
+    fn congestion_control(&mut self, size: usize) {
+        let remain = self.cwnd - self.cwnd_count;
+
+        let size_mod = size % self.cwnd;
+        self.cwnd_count = self
+            .cwnd_count
+            .checked_add(size_mod)
+            .unwrap_or_else(|| size_mod - (self.cwnd - self.cwnd_count))
+            % self.cwnd;
+
+        if size >= remain {
+            let size_remain = size - remain;
+
+            let n = (size_remain / self.cwnd)
+                .checked_add(1)
+                .unwrap_or(usize::MAX);
+            self.set_cwnd(
+                self.cwnd
+                    .checked_add(self.mss.checked_mul(n).unwrap_or(usize::MAX))
+                    .unwrap_or(usize::MAX),
+            )
+        }
+    }
+}
+
+impl TcpCc for TcpNewRenoCcState {
+    fn ack(&mut self, size: usize) {
+        let mut size = size;
+        // Slow start
+        if self.cwnd < self.ssthresh {
+            size = self.slow_start(size);
+        }
+
+        // Congestion avoidance
+        if size > 0 {
+            self.congestion_control(size);
+        }
+    }
+
+    fn ack_rtt(&mut self, size: usize, _: f64) {
+        self.ack(size);
+    }
+
+    fn timedout(&mut self) {
+        if self.recover.is_some() {
+            self.clear_recover();
+        }
+        self.update_ssthresh();
+        self.set_cwnd(self.mss);
+        self.cwnd_count = 0;
+    }
+
+    fn fast_retransmission(&mut self, recover: u32) {
+        match self.recover {
+            Some(_) => {
+                // Already in fast recovery, inflate the window by one segment
+                self.set_cwnd(self.cwnd.checked_add(self.mss).unwrap_or(usize::MAX));
+            }
+            None => {
+                self.update_ssthresh();
+                self.set_cwnd(
+                    self.ssthresh
+                        .checked_add(self.mss.checked_mul(DUPLICATES_THRESHOLD).unwrap_or(0))
+                        .unwrap_or(usize::MAX),
+                );
+                self.cwnd_count = 0;
+                self.set_recover(recover);
+            }
+        }
+    }
+
+    fn ack_recovery(&mut self, sequence: u32, size: usize) -> bool {
+        let recover = match self.recover {
+            Some(recover) => recover,
+            None => {
+                self.ack(size);
+
+                return false;
+            }
+        };
+
+        if sequence
+            .checked_sub(recover)
+            .unwrap_or_else(|| sequence + (u32::MAX - recover)) as usize
+            <= MAX_U32_WINDOW_SIZE
+        {
+            // Full acknowledgement, deflate the window
+            self.clear_recover();
+            self.set_cwnd(self.ssthresh);
+
+            false
+        } else {
+            // Partial acknowledgement, deflate the window by the amount acknowledged, and inflate
+            // it by one segment if at least one segment is acknowledged (RFC 6582)
+            let mut cwnd = self.cwnd.checked_sub(size).unwrap_or(0);
+            if size >= self.mss {
+                cwnd = cwnd.checked_add(self.mss).unwrap_or(usize::MAX);
+            }
+            self.set_cwnd(cwnd);
+
+            true
+        }
+    }
+
+    fn is_recovering(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+}
+
+impl Display for TcpNewRenoCcState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TCP NewReno State: {} -> {}, cwnd = {}, ssthresh = {}",
+            self.dst, self.src, self.cwnd, self.ssthresh
+        )
+    }
+}
+
 const CC_CUBIC_C: f64 = 0.4;
 const CC_CUBIC_BETA: f64 = 0.7;
 
@@ -513,7 +745,7 @@ impl TcpCc for TcpCubicCcState {
         self.cwnd_count = 0;
     }
 
-    fn fast_retransmission(&mut self) {
+    fn fast_retransmission(&mut self, _: u32) {
         self.update();
         self.update_ssthresh();
         self.set_cwnd((self.cwnd as f64 * CC_CUBIC_BETA) as usize);
@@ -557,7 +789,7 @@ const RTO_BETA: f64 = 1.0 / 4.0;
 /// Represents if the congestion control is enabled.
 const ENABLE_CC: bool = true;
 /// Represents the congestion control algorithm.
-const CC_ALGORITHM: TcpCcAlgorithms = TcpCcAlgorithms::Reno;
+const CC_ALGORITHM: TcpCcAlgorithms = TcpCcAlgorithms::NewReno;
 
 /// Represents the TX state of a TCP connection.
 pub struct TcpTxState {
@@ -575,12 +807,16 @@ pub struct TcpTxState {
     cache_syn: Option<Instant>,
     cache_fin: Option<Timer>,
     cache_fin_retrans: bool,
+    persist: Option<Timer>,
     queue: VecDeque<u8>,
     queue_fin: bool,
     rto: u64,
     srtt: Option<f64>,
     rttvar: Option<f64>,
     cc: Option<Box<dyn TcpCc>>,
+    sent: usize,
+    received: usize,
+    retrans: usize,
 }
 
 impl TcpTxState {
@@ -614,6 +850,7 @@ impl TcpTxState {
             cache_syn: None,
             cache_fin: None,
             cache_fin_retrans: true,
+            persist: None,
             queue: VecDeque::new(),
             queue_fin: false,
             rto: INITIAL_RTO,
@@ -623,10 +860,16 @@ impl TcpTxState {
                 true => match CC_ALGORITHM {
                     TcpCcAlgorithms::Tahoe => Some(Box::new(TcpTahoeCcState::new(src, dst, mss))),
                     TcpCcAlgorithms::Reno => Some(Box::new(TcpRenoCcState::new(src, dst, mss))),
+                    TcpCcAlgorithms::NewReno => {
+                        Some(Box::new(TcpNewRenoCcState::new(src, dst, mss)))
+                    }
                     TcpCcAlgorithms::Cubic => Some(Box::new(TcpCubicCcState::new(src, dst, mss))),
                 },
                 false => None,
             },
+            sent: 0,
+            received: 0,
+            retrans: 0,
         }
     }
 
@@ -639,6 +882,10 @@ impl TcpTxState {
             self.src,
             window
         );
+
+        if window > 0 {
+            self.clear_persist_timer();
+        }
     }
 
     /// Adds sequence to the TCP connection.
@@ -697,19 +944,29 @@ impl TcpTxState {
         }
     }
 
-    /// Acknowledges to the given sequence of the TCP connection.
-    pub fn acknowledge(&mut self, sequence: u32) {
+    /// Acknowledges to the given sequence of the TCP connection. Returns if the acknowledgement is
+    /// a partial acknowledgement in fast recovery.
+    pub fn acknowledge(&mut self, sequence: u32) -> bool {
+        self.acknowledge_at(sequence, Instant::now())
+    }
+
+    /// Acknowledges to the given sequence of the TCP connection by an acknowledgement received at
+    /// the given instant, which RTTs are measured to. Returns if the acknowledgement is a partial
+    /// acknowledgement in fast recovery, after which the first unacknowledged segment should be
+    /// retransmitted.
+    pub fn acknowledge_at(&mut self, sequence: u32, instant: Instant) -> bool {
         let mut rtt = None;
+        let mut is_partial = false;
 
         // SYN
-        if let Some(instant) = self.cache_syn {
+        if let Some(syn_instant) = self.cache_syn {
             let send_next = self.sequence;
             if sequence
                 .checked_sub(send_next)
                 .unwrap_or_else(|| sequence + (u32::MAX - send_next)) as usize
                 <= MAX_U32_WINDOW_SIZE
             {
-                rtt = Some(instant.elapsed());
+                rtt = Some(instant.saturating_duration_since(syn_instant));
 
                 self.cache_syn = None;
                 trace!("acknowledge TCP SYN of {} -> {}", self.dst, self.src);
@@ -725,7 +982,7 @@ impl TcpTxState {
             .unwrap_or_else(|| sequence + (u32::MAX - self.cache.sequence()));
         if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
             // Invalidate cache
-            let cache_rtt = self.cache.invalidate_to(sequence);
+            let cache_rtt = self.cache.invalidate_to_at(sequence, instant);
             if rtt.is_none() {
                 rtt = cache_rtt;
             }
@@ -738,9 +995,13 @@ impl TcpTxState {
 
             // Congestion control
             if let Some(cc) = &mut self.cc {
-                match self.srtt {
-                    Some(srtt) => cc.ack_rtt(sub_sequence as usize, srtt),
-                    None => cc.ack(sub_sequence as usize),
+                if cc.is_recovering() {
+                    is_partial = cc.ack_recovery(sequence, sub_sequence as usize);
+                } else {
+                    match self.srtt {
+                        Some(srtt) => cc.ack_rtt(sub_sequence as usize, srtt),
+                        None => cc.ack(sub_sequence as usize),
+                    }
                 }
             }
         }
@@ -754,7 +1015,7 @@ impl TcpTxState {
                 <= MAX_U32_WINDOW_SIZE
             {
                 if rtt.is_none() && !self.cache_fin_retrans && !timer.is_timedout() {
-                    rtt = Some(timer.elapsed());
+                    rtt = Some(timer.elapsed_to(instant));
                 }
 
                 self.cache_fin = None;
@@ -770,6 +1031,8 @@ impl TcpTxState {
         if let Some(rtt) = rtt {
             self.update_rto(rtt);
         }
+
+        is_partial
     }
 
     /// Updates the TCP SYN timer of the TCP connection.
@@ -787,6 +1050,35 @@ impl TcpTxState {
         trace!("update TCP FIN timer of {} -> {}", self.dst, self.src);
     }
 
+    /// Updates the TCP persist timer of the TCP connection. The timer starts from the RTO and is
+    /// doubled on each update until the max RTO.
+    pub fn update_persist_timer(&mut self) {
+        let timeout = match self.persist {
+            Some(timer) => min(
+                (timer.timeout().as_millis() as u64)
+                    .checked_mul(2)
+                    .unwrap_or(u64::MAX),
+                MAX_RTO,
+            ),
+            None => self.rto,
+        };
+        self.persist = Some(Timer::new(timeout));
+        trace!(
+            "update TCP persist timer of {} -> {} to {}",
+            self.dst,
+            self.src,
+            timeout
+        );
+    }
+
+    /// Clears the TCP persist timer of the TCP connection.
+    pub fn clear_persist_timer(&mut self) {
+        if self.persist.is_some() {
+            self.persist = None;
+            trace!("clear TCP persist timer of {} -> {}", self.dst, self.src);
+        }
+    }
+
     /// Set the TCP delayed ACK to the cache of the TCP connection.
     pub fn set_delayed_ack(&mut self) {
         self.delayed_ack = true;
@@ -859,6 +1151,39 @@ impl TcpTxState {
         );
     }
 
+    /// Adds the size of the payload sent to the TCP connection.
+    pub fn add_sent(&mut self, n: usize) {
+        self.sent = self.sent.checked_add(n).unwrap_or(usize::MAX);
+        trace!(
+            "add TCP sent of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.sent
+        );
+    }
+
+    /// Adds the size of the payload received from the source of the TCP connection.
+    pub fn add_received(&mut self, n: usize) {
+        self.received = self.received.checked_add(n).unwrap_or(usize::MAX);
+        trace!(
+            "add TCP received of {} -> {} to {}",
+            self.src,
+            self.dst,
+            self.received
+        );
+    }
+
+    /// Adds the size of the payload retransmitted to the TCP connection.
+    pub fn add_retrans(&mut self, n: usize) {
+        self.retrans = self.retrans.checked_add(n).unwrap_or(usize::MAX);
+        trace!(
+            "add TCP retransmitted of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.retrans
+        );
+    }
+
     fn set_rto(&mut self, rto: u64) {
         if ENABLE_RTO_COMPUTE {
             let rto = min(MAX_RTO, max(MIN_RTO, rto));
@@ -972,6 +1297,11 @@ impl TcpTxState {
         &mut self.cache
     }
 
+    /// Returns the TCP persist timer of the TCP connection.
+    pub fn persist(&self) -> Option<Timer> {
+        self.persist
+    }
+
     /// Returns the TCP SYN in the cache of the TCP connection.
     pub fn cache_syn(&self) -> Option<Instant> {
         self.cache_syn
@@ -1007,6 +1337,31 @@ impl TcpTxState {
         max(MIN_RTO, self.rto.checked_mul(2).unwrap_or(MAX_RTO))
     }
 
+    /// Returns the smoothed RTT in seconds of the TCP connection.
+    pub fn srtt(&self) -> Option<f64> {
+        self.srtt
+    }
+
+    /// Returns the size of the payload sent of the TCP connection, excluding retransmissions.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the size of the payload received from the source of the TCP connection.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Returns the size of the payload retransmitted of the TCP connection.
+    pub fn retrans(&self) -> usize {
+        self.retrans
+    }
+
+    /// Returns the statistics of the TCP connection.
+    pub fn stats(&self) -> FlowStats {
+        FlowStats::new(self.src, self.dst, self.srtt, self.sent, self.retrans)
+    }
+
     /// Returns the congestion control state of the TCP connection.
     pub fn cc(&self) -> &Option<Box<dyn TcpCc>> {
         &self.cc
@@ -1039,6 +1394,7 @@ impl Display for TcpTxState {
 pub struct TcpRxState {
     src: SocketAddrV4,
     dst: SocketAddrV4,
+    syn_sequence: u32,
     recv_next: u32,
     acknowledgement: u32,
     duplicate: usize,
@@ -1065,6 +1421,7 @@ impl TcpRxState {
         TcpRxState {
             src,
             dst,
+            syn_sequence: sequence,
             recv_next,
             acknowledgement: 0,
             duplicate: 0,
@@ -1150,6 +1507,30 @@ impl TcpRxState {
         self.cache.append(sequence, payload)
     }
 
+    /// Trims the bytes of the payload which have been delivered, like the ones in a retransmission
+    /// of the source. Returns the sequence and the payload remaining, or `None` if all the bytes
+    /// have been delivered.
+    pub fn trim_delivered<'a>(&self, sequence: u32, payload: &'a [u8]) -> Option<(u32, &'a [u8])> {
+        let delivered = self.recv_next.wrapping_sub(sequence) as usize;
+        if delivered == 0 || delivered > MAX_U32_WINDOW_SIZE {
+            // In order or out of order after the receive next
+            return Some((sequence, payload));
+        }
+        if delivered >= payload.len() {
+            return None;
+        }
+
+        trace!(
+            "trim {} Bytes delivered of TCP {} -> {} at {}",
+            delivered,
+            self.src,
+            self.dst,
+            sequence
+        );
+
+        Some((self.recv_next, &payload[delivered..]))
+    }
+
     /// Sets the TCP FIN sequence of the TCP connection.
     pub fn set_fin_sequence(&mut self, sequence: u32) {
         self.fin_sequence = Some(sequence);
@@ -1167,6 +1548,11 @@ impl TcpRxState {
         trace!("admit TCP FIN of {} -> {}", self.src, self.dst);
     }
 
+    /// Returns the sequence in the TCP SYN packet of the TCP connection.
+    pub fn syn_sequence(&self) -> u32 {
+        self.syn_sequence
+    }
+
     /// Returns the receive next of the TCP connection.
     pub fn recv_next(&self) -> u32 {
         self.recv_next
@@ -1208,3 +1594,112 @@ impl Display for TcpRxState {
         write!(f, "TCP RX State: {} -> {}", self.src, self.dst)
     }
 }
+
+#[test]
+fn new_reno_fast_recovery() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 1000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let mut cc = TcpNewRenoCcState::new(src, dst, 1000);
+    cc.ack(19000);
+    assert_eq!(cc.cwnd(), 20000);
+
+    // Enter fast recovery with 20000 Bytes in flight from sequence 0
+    cc.fast_retransmission(20000);
+    assert!(cc.is_recovering());
+    assert_eq!(cc.cwnd(), 13000);
+
+    // Partial acknowledgement
+    assert!(cc.ack_recovery(5000, 5000));
+    assert!(cc.is_recovering());
+    assert_eq!(cc.cwnd(), 9000);
+
+    // Partial acknowledgement less than a segment
+    assert!(cc.ack_recovery(5500, 500));
+    assert!(cc.is_recovering());
+    assert_eq!(cc.cwnd(), 8500);
+
+    // Full acknowledgement
+    assert!(!cc.ack_recovery(20000, 14500));
+    assert!(!cc.is_recovering());
+    assert_eq!(cc.cwnd(), 10000);
+}
+
+#[test]
+fn new_reno_fast_recovery_wrapping() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 1000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let mut cc = TcpNewRenoCcState::new(src, dst, 1000);
+    cc.ack(19000);
+
+    // The recovery point wraps around
+    cc.fast_retransmission(10000);
+    assert!(cc.ack_recovery(u32::MAX - 1000, 5000));
+    assert!(cc.ack_recovery(5000, 6000));
+    assert!(!cc.ack_recovery(10000, 5000));
+    assert!(!cc.is_recovering());
+}
+
+#[test]
+fn tcp_rx_state_trim_delivered() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 1000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let payload = [0u8, 1, 2, 3, 4, 5, 6, 7];
+
+    // The receive next is at 0
+    let mut state = TcpRxState::new(src, dst, u32::MAX, 0, false);
+    assert_eq!(state.trim_delivered(0, &payload), Some((0, &payload[..])));
+    assert_eq!(state.trim_delivered(16, &payload), Some((16, &payload[..])));
+
+    state.add_recv_next(4);
+    assert_eq!(state.trim_delivered(0, &payload), Some((4, &payload[4..])));
+    assert_eq!(state.trim_delivered(0, &payload[..4]), None);
+    // Wrap around
+    assert_eq!(
+        state.trim_delivered(u32::MAX - 1, &payload),
+        Some((4, &payload[6..]))
+    );
+}
+
+#[test]
+fn tcp_tx_state_persist_timer() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 1000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let mut state = TcpTxState::new(src, dst, 0, 0, 0, None, false, None, 1460);
+    assert!(state.persist().is_none());
+
+    // Back off exponentially until the max RTO
+    state.update_persist_timer();
+    assert_eq!(
+        state.persist().unwrap().timeout(),
+        Duration::from_millis(INITIAL_RTO)
+    );
+    state.update_persist_timer();
+    assert_eq!(
+        state.persist().unwrap().timeout(),
+        Duration::from_millis(INITIAL_RTO * 2)
+    );
+    for _ in 0..16 {
+        state.update_persist_timer();
+    }
+    assert_eq!(
+        state.persist().unwrap().timeout(),
+        Duration::from_millis(MAX_RTO)
+    );
+
+    state.set_src_window(0);
+    assert!(state.persist().is_some());
+    state.set_src_window(1460);
+    assert!(state.persist().is_none());
+}
+
+#[test]
+fn tcp_tx_state_received() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 1000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let mut state = TcpTxState::new(src, dst, 0, 0, 65535, None, false, None, 1460);
+    state.add_sent(4096);
+    state.add_received(100);
+    state.add_received(200);
+    assert_eq!(state.sent(), 4096);
+    assert_eq!(state.received(), 300);
+}
@@ -0,0 +1,344 @@
+//! Support for TLS connections to upstream servers.
+
+use ring::digest::{self, SHA256};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use crate::proxy::base64;
+
+pub use tokio_rustls::client::TlsStream;
+
+/// Represents a pin of the certificate of a server.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CertPin {
+    /// Represents the SHA-256 fingerprint of the certificate.
+    Certificate([u8; 32]),
+    /// Represents the SHA-256 hash of the SubjectPublicKeyInfo of the certificate, which is kept
+    /// over renewals of the certificate with the same key.
+    PublicKey([u8; 32]),
+}
+
+impl CertPin {
+    /// Returns if the certificate in DER matches the pin.
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            CertPin::Certificate(hash) => sha256(cert) == *hash,
+            CertPin::PublicKey(hash) => match spki(cert) {
+                Some(spki) => sha256(spki) == *hash,
+                None => false,
+            },
+        }
+    }
+}
+
+impl Display for CertPin {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CertPin::Certificate(hash) => write!(
+                f,
+                "{}",
+                hash.iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            ),
+            CertPin::PublicKey(hash) => write!(f, "sha256/{}", base64(hash)),
+        }
+    }
+}
+
+impl FromStr for CertPin {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid pin {}, please use sha256/BASE64 of the public key or the SHA-256 fingerprint of the certificate",
+                s
+            )
+        };
+
+        let mut hash = [0u8; 32];
+        match s.strip_prefix("sha256/") {
+            Some(encoded) => {
+                let decoded = base64_decode(encoded).ok_or_else(err)?;
+                if decoded.len() != hash.len() {
+                    return Err(err());
+                }
+                hash.copy_from_slice(&decoded);
+
+                Ok(CertPin::PublicKey(hash))
+            }
+            None => {
+                let hex = s.replace(':', "");
+                if hex.len() != hash.len() * 2 || !hex.is_ascii() {
+                    return Err(err());
+                }
+                for (i, b) in hash.iter_mut().enumerate() {
+                    *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| err())?;
+                }
+
+                Ok(CertPin::Certificate(hash))
+            }
+        }
+    }
+}
+
+/// Represents a verifier accepting servers by the pins of their certificates instead of the root
+/// certificates. The signatures of the handshake are still verified by the certificate.
+struct PinVerifier {
+    pins: Vec<CertPin>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        _: &RootCertStore,
+        presented_certs: &[Certificate],
+        _: DNSNameRef,
+        _: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        match self.pins.iter().any(|pin| pin.matches(&cert.0)) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(TLSError::General(String::from(
+                "certificate does not match the pins",
+            ))),
+        }
+    }
+}
+
+/// Represents the configuration of TLS connections to a server. The server is verified by its
+/// name against the Mozilla root certificates, or by the pins of its certificate if set.
+#[derive(Clone)]
+pub struct TlsConfig {
+    name: String,
+    pins: Vec<CertPin>,
+    connector: TlsConnector,
+}
+
+impl TlsConfig {
+    /// Creates a new `TlsConfig` of the server name, which must be a hostname rather than an
+    /// IP address.
+    pub fn new(name: &str) -> io::Result<TlsConfig> {
+        if DNSNameRef::try_from_ascii_str(name).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid server name {}", name),
+            ));
+        }
+
+        Ok(TlsConfig {
+            name: String::from(name),
+            pins: Vec::new(),
+            connector: connector(&[]),
+        })
+    }
+
+    /// Sets the pins of the certificate of the server. If any pin is set, the server is only
+    /// accepted if its certificate matches one of the pins, regardless of the root certificates
+    /// and the name, so self-signed certificates of private servers can be pinned. Setting
+    /// multiple pins allows rotating to a backup key.
+    pub fn set_pins(&mut self, pins: Vec<CertPin>) {
+        self.connector = connector(&pins);
+        self.pins = pins;
+    }
+
+    /// Returns the server name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the pins of the certificate of the server.
+    pub fn pins(&self) -> &[CertPin] {
+        &self.pins
+    }
+
+    /// Performs the TLS handshake over the stream, and verifies the server.
+    pub async fn connect<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // The name has been checked in constructing
+        let name = DNSNameRef::try_from_ascii_str(&self.name).unwrap();
+
+        self.connector.connect(name, stream).await
+    }
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("name", &self.name)
+            .field("pins", &self.pins)
+            .finish()
+    }
+}
+
+/// Returns a connector verifying servers by the pins, or by the root certificates if there is no
+/// pin.
+fn connector(pins: &[CertPin]) -> TlsConnector {
+    let mut config = ClientConfig::new();
+    match pins.is_empty() {
+        true => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        false => config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinVerifier {
+                pins: pins.to_vec(),
+            })),
+    }
+
+    TlsConnector::from(Arc::new(config))
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(digest::digest(&SHA256, data).as_ref());
+
+    hash
+}
+
+/// Returns the tag, the size of the header and the total size of the DER element at the start of
+/// the data.
+fn der_element(data: &[u8]) -> Option<(u8, usize, usize)> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (length, header_size) = match first {
+        0..=0x7f => (first, 2),
+        0x81..=0x84 => {
+            let n = first & 0x7f;
+            let length = data
+                .get(2..2 + n)?
+                .iter()
+                .fold(0usize, |length, b| (length << 8) | *b as usize);
+
+            (length, 2 + n)
+        }
+        _ => return None,
+    };
+    let size = header_size.checked_add(length)?;
+    if size > data.len() {
+        return None;
+    }
+
+    Some((tag, header_size, size))
+}
+
+/// Returns the SubjectPublicKeyInfo in DER of the certificate in DER (RFC 5280).
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate and TBSCertificate
+    let (tag, header_size, size) = der_element(cert)?;
+    if tag != 0x30 {
+        return None;
+    }
+    let cert = &cert[header_size..size];
+    let (tag, header_size, size) = der_element(cert)?;
+    if tag != 0x30 {
+        return None;
+    }
+    let mut tbs = &cert[header_size..size];
+
+    // Skip the version, the serial number, the signature, the issuer, the validity and the
+    // subject
+    if tbs.first() == Some(&0xa0) {
+        tbs = &tbs[der_element(tbs)?.2..];
+    }
+    for _ in 0..5 {
+        tbs = &tbs[der_element(tbs)?.2..];
+    }
+
+    let (tag, _, size) = der_element(tbs)?;
+    match tag {
+        0x30 => Some(&tbs[..size]),
+        _ => None,
+    }
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut data = Vec::new();
+    let mut n = 0u32;
+    for (i, c) in s.bytes().enumerate() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        } as u32;
+        n = n << 6 | value;
+        if i % 4 == 3 {
+            data.extend_from_slice(&n.to_be_bytes()[1..]);
+            n = 0;
+        }
+    }
+    match s.len() % 4 {
+        0 => {}
+        2 => data.push((n >> 4) as u8),
+        3 => data.extend_from_slice(&((n >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+
+    Some(data)
+}
+
+#[test]
+fn tls_config_name() {
+    assert_eq!(TlsConfig::new("dns.google").unwrap().name(), "dns.google");
+    assert!(TlsConfig::new("dns google").is_err());
+}
+
+#[test]
+fn tls_cert_pin() {
+    // A certificate of a structure only, with a SubjectPublicKeyInfo of an empty algorithm and
+    // key
+    let spki_der = [0x30, 0x05, 0x30, 0x00, 0x03, 0x01, 0x00];
+    let mut tbs = vec![
+        0xa0, 0x03, 0x02, 0x01, 0x02, // Version
+        0x02, 0x01, 0x01, // Serial number
+        0x30, 0x00, // Signature
+        0x30, 0x00, // Issuer
+        0x30, 0x00, // Validity
+        0x30, 0x00, // Subject
+    ];
+    tbs.extend_from_slice(&spki_der);
+    let mut cert = vec![0x30, tbs.len() as u8 + 2 + 5, 0x30, tbs.len() as u8];
+    cert.extend_from_slice(&tbs);
+    cert.extend_from_slice(&[0x30, 0x00, 0x03, 0x01, 0x00]);
+    assert_eq!(spki(&cert), Some(&spki_der[..]));
+    assert_eq!(spki(&cert[..cert.len() - 1]), None);
+
+    let spki_pin = CertPin::PublicKey(sha256(&spki_der));
+    let cert_pin = CertPin::Certificate(sha256(&cert));
+    assert!(spki_pin.matches(&cert));
+    assert!(cert_pin.matches(&cert));
+    assert!(!CertPin::PublicKey([0u8; 32]).matches(&cert));
+    assert_eq!(spki_pin.to_string().parse(), Ok(spki_pin));
+    assert_eq!(cert_pin.to_string().parse(), Ok(cert_pin));
+    assert_eq!(
+        cert_pin.to_string().replace(':', "").to_lowercase().parse(),
+        Ok(cert_pin)
+    );
+
+    assert_eq!(
+        base64_decode("cGNhcDJzb2Nrcw=="),
+        Some(b"pcap2socks".to_vec())
+    );
+    assert!("sha256/cGNhcDJzb2Nrcw==".parse::<CertPin>().is_err());
+    assert!("AB:CD".parse::<CertPin>().is_err());
+
+    let mut config = TlsConfig::new("relay.example.com").unwrap();
+    config.set_pins(vec![spki_pin]);
+    assert_eq!(config.pins(), &[spki_pin]);
+}
@@ -0,0 +1,111 @@
+//! Support for recognizing encrypted UDP tunnels and IPv6 tunnels over IPv4.
+
+use std::fmt::{self, Display, Formatter};
+
+/// Represents the port of IPsec NAT traversal.
+pub const IPSEC_NAT_T_PORT: u16 = 4500;
+/// Represents the port of Teredo servers.
+pub const TEREDO_PORT: u16 = 3544;
+
+const WIREGUARD_HANDSHAKE_INITIATION: u8 = 1;
+const WIREGUARD_HANDSHAKE_RESPONSE: u8 = 2;
+const WIREGUARD_HANDSHAKE_INITIATION_SIZE: usize = 148;
+const WIREGUARD_HANDSHAKE_RESPONSE_SIZE: usize = 92;
+
+const IPV6_HEADER_SIZE: usize = 40;
+const TEREDO_ORIGIN_INDICATION_SIZE: usize = 8;
+
+/// Represents the kind of an encrypted UDP tunnel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TunnelKind {
+    /// Represents WireGuard.
+    WireGuard,
+    /// Represents IKE and ESP of IPsec encapsulated in UDP for NAT traversal.
+    IpsecNatT,
+    /// Represents Teredo, which tunnels IPv6 over UDP for hosts behind NATs, like Xbox consoles.
+    Teredo,
+}
+
+impl Display for TunnelKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            TunnelKind::WireGuard => "WireGuard",
+            TunnelKind::IpsecNatT => "IPsec NAT-T",
+            TunnelKind::Teredo => "Teredo",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Recognizes the tunnel of a UDP datagram from a source to the destination port. WireGuard is
+/// recognized by its handshake messages, which are of fixed sizes, IPsec NAT-T is recognized by
+/// its port, and Teredo is recognized by the port of its servers, or by an encapsulated IPv6
+/// packet to its peers. Returns `None` if the datagram is not known to be of a tunnel.
+pub fn recognize(dst_port: u16, payload: &[u8]) -> Option<TunnelKind> {
+    match dst_port {
+        IPSEC_NAT_T_PORT => return Some(TunnelKind::IpsecNatT),
+        TEREDO_PORT => return Some(TunnelKind::Teredo),
+        _ => {}
+    }
+    if is_teredo(payload) {
+        return Some(TunnelKind::Teredo);
+    }
+
+    // Type and 3 reserved zeroes
+    if payload.len() >= 4 && payload[1..4] == [0, 0, 0] {
+        match (payload[0], payload.len()) {
+            (WIREGUARD_HANDSHAKE_INITIATION, WIREGUARD_HANDSHAKE_INITIATION_SIZE)
+            | (WIREGUARD_HANDSHAKE_RESPONSE, WIREGUARD_HANDSHAKE_RESPONSE_SIZE) => {
+                return Some(TunnelKind::WireGuard)
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Returns if the payload is an IPv6 packet encapsulated by Teredo, whose version is 6 and whose
+/// payload length matches the rest of the payload, optionally after an origin indication.
+fn is_teredo(payload: &[u8]) -> bool {
+    // Origin indication
+    let payload = match payload {
+        [0, 0, ..] if payload.len() >= TEREDO_ORIGIN_INDICATION_SIZE => {
+            &payload[TEREDO_ORIGIN_INDICATION_SIZE..]
+        }
+        _ => payload,
+    };
+    if payload.len() < IPV6_HEADER_SIZE || payload[0] >> 4 != 6 {
+        return false;
+    }
+    let payload_length = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+
+    payload_length == payload.len() - IPV6_HEADER_SIZE
+}
+
+#[test]
+fn tunnel_recognize() {
+    let mut initiation = vec![0u8; WIREGUARD_HANDSHAKE_INITIATION_SIZE];
+    initiation[0] = WIREGUARD_HANDSHAKE_INITIATION;
+    assert_eq!(recognize(51820, &initiation), Some(TunnelKind::WireGuard));
+    initiation[1] = 1;
+    assert_eq!(recognize(51820, &initiation), None);
+    assert_eq!(recognize(51820, &[4, 0, 0, 0, 1, 2, 3, 4]), None);
+    assert_eq!(
+        recognize(IPSEC_NAT_T_PORT, &[0xff]),
+        Some(TunnelKind::IpsecNatT)
+    );
+
+    // IPv6 packet with 8 bytes of payload
+    let mut ipv6 = vec![0u8; IPV6_HEADER_SIZE + 8];
+    ipv6[0] = 0x60;
+    ipv6[5] = 8;
+    assert_eq!(recognize(50000, &ipv6), Some(TunnelKind::Teredo));
+    let mut indicated = vec![0u8; TEREDO_ORIGIN_INDICATION_SIZE];
+    indicated.extend_from_slice(&ipv6);
+    assert_eq!(recognize(50000, &indicated), Some(TunnelKind::Teredo));
+    ipv6.push(0);
+    assert_eq!(recognize(50000, &ipv6), None);
+    assert_eq!(recognize(TEREDO_PORT, &[0xff]), Some(TunnelKind::Teredo));
+}
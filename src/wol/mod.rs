@@ -0,0 +1,75 @@
+//! Support for recognizing and building Wake-on-LAN magic packets.
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the port of Wake-on-LAN, which is the discard port.
+pub const WOL_PORT: u16 = 9;
+
+const SYNC_STREAM_SIZE: usize = 6;
+const REPETITIONS: usize = 16;
+const MAGIC_PACKET_SIZE: usize = SYNC_STREAM_SIZE + REPETITIONS * 6;
+
+/// Builds the magic packet of the hardware address, which is a synchronization stream of 6
+/// Bytes of `0xFF` followed by 16 repetitions of the hardware address.
+pub fn magic_packet(hardware_addr: HardwareAddr) -> Vec<u8> {
+    let addr = [
+        hardware_addr.0,
+        hardware_addr.1,
+        hardware_addr.2,
+        hardware_addr.3,
+        hardware_addr.4,
+        hardware_addr.5,
+    ];
+
+    let mut buffer = vec![0xFF; SYNC_STREAM_SIZE];
+    for _ in 0..REPETITIONS {
+        buffer.extend_from_slice(&addr);
+    }
+
+    buffer
+}
+
+/// Parses a magic packet from the given buffer anywhere in it, which may be followed by a
+/// SecureOn password, and returns the hardware address to wake. Returns `None` if the buffer is
+/// not a magic packet.
+pub fn parse_magic_packet(buffer: &[u8]) -> Option<HardwareAddr> {
+    if buffer.len() < MAGIC_PACKET_SIZE {
+        return None;
+    }
+
+    (0..=buffer.len() - MAGIC_PACKET_SIZE).find_map(|i| {
+        let packet = &buffer[i..i + MAGIC_PACKET_SIZE];
+        let (sync, addrs) = packet.split_at(SYNC_STREAM_SIZE);
+        if sync.iter().any(|b| *b != 0xFF) {
+            return None;
+        }
+        let addr = &addrs[..6];
+        if *addr == [0xFF; 6] || addrs.chunks(6).any(|chunk| chunk != addr) {
+            return None;
+        }
+
+        Some(HardwareAddr::new(
+            addr[0], addr[1], addr[2], addr[3], addr[4], addr[5],
+        ))
+    })
+}
+
+#[test]
+fn wol_parse_magic_packet() {
+    let hardware_addr = HardwareAddr::new(0x00, 0xd9, 0xd1, 1, 2, 3);
+    let packet = magic_packet(hardware_addr);
+    assert_eq!(packet.len(), MAGIC_PACKET_SIZE);
+    assert_eq!(parse_magic_packet(&packet), Some(hardware_addr));
+
+    // Prefixed and followed by a SecureOn password
+    let mut buffer = vec![0u8; 4];
+    buffer.extend_from_slice(&packet);
+    buffer.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+    assert_eq!(parse_magic_packet(&buffer), Some(hardware_addr));
+
+    // Broken repetitions
+    let mut buffer = packet;
+    buffer[MAGIC_PACKET_SIZE - 1] = 0;
+    assert_eq!(parse_magic_packet(&buffer), None);
+    assert_eq!(parse_magic_packet(&[0xFF; 128]), None);
+}